@@ -13,7 +13,17 @@ fn main() {
         .create_disk_image(&bios_path)
         .unwrap();
 
+    // the file the runner passes through fw_cfg, for the kernel tests to read back: 5000 bytes, more than a page so
+    // the kernel reads it in a few DMA requests, where byte n is n % 253
+    let fw_cfg_test_path = out_dir.join("fwcfg-test.bin");
+    let contents: Vec<u8> = (0..5000).map(|n| (n % 253) as u8).collect();
+    std::fs::write(&fw_cfg_test_path, contents).unwrap();
+
     // pass the artifacts as env variables
     println!("cargo:rustc-env=KERNEL_PATH={}", kernel.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
+    println!(
+        "cargo:rustc-env=FW_CFG_TEST_PATH={}",
+        fw_cfg_test_path.display()
+    );
 }
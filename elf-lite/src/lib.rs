@@ -0,0 +1,11 @@
+//! A small parser for 64-bit little-endian x86_64 ELF executables.
+//!
+//! It has no dependency on the kernel (only `core` and `alloc`), so it can be unit-tested on the host with
+//! `cargo test -p elf-lite`. The kernel uses it through `user::elf_parser` and `user::elf_structure`.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod parser;
+pub mod structure;
@@ -0,0 +1,490 @@
+use core::{fmt, ptr::read_unaligned};
+
+use alloc::{string::String, vec::Vec};
+
+use crate::structure::*;
+
+/// Errors returned when parsing or loading an ELF file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file is smaller than the ELF header.
+    TooSmall,
+    /// The file doesn't start with the ELF magic number.
+    BadMagic,
+    /// The file is not a 64-bit ELF file.
+    UnsupportedClass,
+    /// The file is not little-endian.
+    UnsupportedEndian,
+    /// The ELF version is not 1.
+    UnsupportedVersion,
+    /// The file is not an executable (or a position-independent one).
+    NotExecutable,
+    /// The file is not for x86_64.
+    WrongMachine,
+    /// e_phentsize is smaller than a program header.
+    BadPhentsize,
+    /// e_shentsize is smaller than a section header.
+    BadShentsize,
+    /// The program header table lies outside of the file.
+    PhTableOutOfBounds,
+    /// The program header doesn't exist or lies outside of the file.
+    PhOutOfBounds { index: usize },
+    /// The section header doesn't exist or lies outside of the file.
+    ShOutOfBounds { index: usize },
+    /// e_shstrndx is SHN_UNDEF, SHN_XINDEX (not supported) or doesn't name a section inside the file.
+    BadShstrndx,
+    /// The name of a section lies outside of the section header string table, or isn't NUL-terminated UTF-8.
+    BadSectionName,
+    /// The segment's data lies outside of the file, or the segment lies outside of userspace.
+    SegmentOutOfBounds { index: usize },
+    /// The segment overlaps another segment.
+    OverlappingSegment { index: usize },
+    /// p_align is not a power of two, or p_vaddr and p_offset are not congruent modulo p_align.
+    MisalignedSegment { index: usize },
+    /// The segment's data in the file overlaps another segment's data.
+    OverlappingFileRange { index: usize },
+    /// The segment is (or shares a page with a segment that is) both writable and executable.
+    WriteExecute { index: usize },
+    /// The file needs a dynamic linker, which is not supported.
+    NeedsInterpreter,
+    /// The dynamic segment or the relocation table it points to is malformed.
+    BadDynamic,
+    /// A relocation has a type other than R_X86_64_RELATIVE, or applies outside of the segments.
+    UnsupportedRelocation { r_type: u32 },
+    /// PT_GNU_STACK asks for an executable stack, which the loader doesn't allow.
+    ExecutableStack,
+    /// The PT_TLS template lies outside of the file, or has an unsupported alignment.
+    BadTls,
+    /// The arguments and environment don't fit on the user stack.
+    ArgumentsTooLarge,
+    /// Memory for the task could not be mapped.
+    OomMapping,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::TooSmall => write!(f, "file is smaller than the ELF header"),
+            ElfError::BadMagic => write!(f, "bad ELF magic number"),
+            ElfError::UnsupportedClass => write!(f, "not a 64-bit ELF file"),
+            ElfError::UnsupportedEndian => write!(f, "not a little-endian ELF file"),
+            ElfError::UnsupportedVersion => write!(f, "unsupported ELF version"),
+            ElfError::NotExecutable => write!(f, "not an executable"),
+            ElfError::WrongMachine => write!(f, "not an x86_64 executable"),
+            ElfError::BadPhentsize => write!(f, "program header entries are too small"),
+            ElfError::BadShentsize => write!(f, "section header entries are too small"),
+            ElfError::PhTableOutOfBounds => write!(f, "program header table out of bounds"),
+            ElfError::PhOutOfBounds { index } => write!(f, "program header {index} out of bounds"),
+            ElfError::ShOutOfBounds { index } => write!(f, "section header {index} out of bounds"),
+            ElfError::BadShstrndx => write!(f, "bad section header string table index"),
+            ElfError::BadSectionName => write!(f, "bad section name"),
+            ElfError::SegmentOutOfBounds { index } => write!(f, "segment {index} out of bounds"),
+            ElfError::OverlappingSegment { index } => {
+                write!(f, "segment {index} overlaps another segment")
+            }
+            ElfError::MisalignedSegment { index } => {
+                write!(f, "segment {index} is misaligned")
+            }
+            ElfError::OverlappingFileRange { index } => {
+                write!(f, "data of segment {index} overlaps another segment's data")
+            }
+            ElfError::WriteExecute { index } => {
+                write!(f, "segment {index} is writable and executable")
+            }
+            ElfError::NeedsInterpreter => {
+                write!(f, "dynamically linked executables are not supported")
+            }
+            ElfError::BadDynamic => write!(f, "malformed dynamic segment"),
+            ElfError::UnsupportedRelocation { r_type } => {
+                write!(f, "unsupported relocation of type {r_type}")
+            }
+            ElfError::ExecutableStack => write!(f, "executable stack requested"),
+            ElfError::BadTls => write!(f, "malformed TLS segment"),
+            ElfError::ArgumentsTooLarge => write!(f, "arguments don't fit on the user stack"),
+            ElfError::OomMapping => write!(f, "out of memory while mapping the task"),
+        }
+    }
+}
+
+// The buffer can be at any alignment (e.g. a file inside a ramdisk), so headers are copied out with unaligned reads
+// instead of being referenced in place.
+pub struct ElfParser<'a> {
+    buf: &'a [u8],
+    header: ElfHeader,
+}
+
+impl<'a> ElfParser<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, ElfError> {
+        easy_assert(buf.len() >= size_of::<ElfHeader>(), ElfError::TooSmall)?;
+
+        let header = unsafe { read_unaligned(buf.as_ptr() as *const ElfHeader) };
+        let parser = Self { buf, header };
+
+        // Check ELF magic number
+        easy_assert(&header.e_ident[0..4] == b"\x7FELF", ElfError::BadMagic)?;
+        // Only support 64-bit, little-endian, version 1 ELF
+        easy_assert(header.e_ident[4] == 2, ElfError::UnsupportedClass)?;
+        easy_assert(header.e_ident[5] == 1, ElfError::UnsupportedEndian)?;
+        easy_assert(header.e_ident[6] == 1, ElfError::UnsupportedVersion)?;
+
+        // Only support x86_64 executable files, position-independent ones included
+        easy_assert(
+            header.e_type == ElfType::Executable || header.e_type == ElfType::SharedObject,
+            ElfError::NotExecutable,
+        )?;
+        easy_assert(
+            header.e_machine == ElfMachine::x86_64,
+            ElfError::WrongMachine,
+        )?;
+
+        // Entries may be larger than the structs we know (the rest is ignored), but not smaller.
+        easy_assert(
+            header.e_phnum == 0 || header.e_phentsize as usize >= size_of::<ElfProgramHeader>(),
+            ElfError::BadPhentsize,
+        )?;
+        easy_assert(
+            header.e_shnum == 0 || header.e_shentsize as usize >= size_of::<ElfSectionHeader>(),
+            ElfError::BadShentsize,
+        )?;
+
+        // Reject malformed segments now, before anything is mapped.
+        parser.validate_program_headers()?;
+
+        Ok(parser)
+    }
+
+    // Check that the program header table fits in the file, and that the file data of each PT_LOAD segment fits in
+    // the file, is congruent to its address modulo p_align, and doesn't overlap the data of another segment.
+    fn validate_program_headers(&self) -> Result<(), ElfError> {
+        let header = &self.header;
+        let table_size = (header.e_phnum as usize)
+            .checked_mul(header.e_phentsize as usize)
+            .ok_or(ElfError::PhTableOutOfBounds)?;
+        add_within_bounds(header.e_phoff as usize, table_size, self.buf.len())
+            .ok_or(ElfError::PhTableOutOfBounds)?;
+
+        // (start, end, index) of the file data of each segment
+        let mut file_ranges = Vec::new();
+        for i in 0..header.e_phnum as usize {
+            let ph = self.get_program_header(i)?;
+            if ph.p_type != ElfProgramHeaderType::Load {
+                continue;
+            }
+
+            let offset = ph.p_offset as usize;
+            let file_size = ph.p_filesz as usize;
+            let end = add_within_bounds(offset, file_size, self.buf.len())
+                .ok_or(ElfError::SegmentOutOfBounds { index: i })?;
+
+            let align = ph.p_align as usize;
+            if align > 1
+                && (!align.is_power_of_two() || ph.p_vaddr as usize % align != offset % align)
+            {
+                return Err(ElfError::MisalignedSegment { index: i });
+            }
+
+            // Segments without file data (only BSS) may have any offset.
+            if file_size != 0 {
+                file_ranges.push((offset, end, i));
+            }
+        }
+
+        // Sorted by start, two ranges overlap only if neighbouring ranges do.
+        file_ranges.sort_unstable();
+        for pair in file_ranges.windows(2) {
+            let ((_, prev_end, prev_index), (start, _, index)) = (pair[0], pair[1]);
+            if start < prev_end {
+                return Err(ElfError::OverlappingFileRange {
+                    index: index.max(prev_index),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_buf(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// Check if this is a position-independent executable, which can be loaded at any base address.
+    pub fn is_position_independent(&self) -> bool {
+        self.header.e_type == ElfType::SharedObject
+    }
+
+    pub fn get_header(&self) -> &ElfHeader {
+        &self.header
+    }
+
+    pub fn get_program_header(&self, index: usize) -> Result<ElfProgramHeader, ElfError> {
+        let header = self.get_header();
+        let error = ElfError::PhOutOfBounds { index };
+        easy_assert(index < header.e_phnum as usize, error)?;
+
+        let ph_offset = (header.e_phoff as usize)
+            .checked_add(index * header.e_phentsize as usize)
+            .ok_or(error)?;
+        add_within_bounds(ph_offset, size_of::<ElfProgramHeader>(), self.buf.len()).ok_or(error)?;
+
+        let ph =
+            unsafe { read_unaligned(self.buf.as_ptr().add(ph_offset) as *const ElfProgramHeader) };
+        Ok(ph)
+    }
+
+    pub fn get_section_header(&self, index: usize) -> Result<ElfSectionHeader, ElfError> {
+        let header = self.get_header();
+        let error = ElfError::ShOutOfBounds { index };
+        easy_assert(index < header.e_shnum as usize, error)?;
+
+        let sh_offset = (header.e_shoff as usize)
+            .checked_add(index * header.e_shentsize as usize)
+            .ok_or(error)?;
+        add_within_bounds(sh_offset, size_of::<ElfSectionHeader>(), self.buf.len()).ok_or(error)?;
+
+        let sh =
+            unsafe { read_unaligned(self.buf.as_ptr().add(sh_offset) as *const ElfSectionHeader) };
+        Ok(sh)
+    }
+
+    /// Get the first program header of the given type, if any.
+    pub fn find_program_header(
+        &self,
+        p_type: ElfProgramHeaderType,
+    ) -> Result<Option<ElfProgramHeader>, ElfError> {
+        for i in 0..self.header.e_phnum as usize {
+            let ph = self.get_program_header(i)?;
+            if ph.p_type == p_type {
+                return Ok(Some(ph));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the contents of a section, or None if they lie outside of the file.
+    pub fn get_section_data(&self, sh: &ElfSectionHeader) -> Option<&'a [u8]> {
+        let offset = sh.sh_offset as usize;
+        let end = add_within_bounds(offset, sh.sh_size as usize, self.buf.len())?;
+        Some(&self.buf[offset..end])
+    }
+
+    /// Get the NUL-terminated string at offset in the string table section strtab.
+    pub fn get_string(&self, strtab: &ElfSectionHeader, offset: usize) -> Option<&'a str> {
+        c_str(self.get_section_data(strtab)?, offset)
+    }
+
+    /// Get the contents of the section header string table, which holds the section names.
+    pub fn get_shstrtab(&self) -> Result<&'a [u8], ElfError> {
+        let index = self.header.e_shstrndx;
+        easy_assert(
+            index != SHN_UNDEF && index != SHN_XINDEX,
+            ElfError::BadShstrndx,
+        )?;
+
+        let shstrtab = self
+            .get_section_header(index as usize)
+            .map_err(|_| ElfError::BadShstrndx)?;
+        self.get_section_data(&shstrtab)
+            .ok_or(ElfError::BadShstrndx)
+    }
+
+    /// Get the name of a section.
+    pub fn section_name(&self, sh: &ElfSectionHeader) -> Result<&'a str, ElfError> {
+        c_str(self.get_shstrtab()?, sh.sh_name as usize).ok_or(ElfError::BadSectionName)
+    }
+
+    /// Iterate over the section headers, with their names.
+    pub fn sections(
+        &self,
+    ) -> impl Iterator<Item = Result<(&'a str, ElfSectionHeader), ElfError>> + '_ {
+        (0..self.header.e_shnum as usize).map(|i| {
+            let sh = self.get_section_header(i)?;
+            Ok((self.section_name(&sh)?, sh))
+        })
+    }
+
+    /// Find a section by its name (e.g. ".symtab"). Sections that can't be read are skipped.
+    pub fn get_section_by_name(&self, name: &str) -> Option<ElfSectionHeader> {
+        self.sections()
+            .filter_map(Result::ok)
+            .find(|&(section_name, _)| section_name == name)
+            .map(|(_, sh)| sh)
+    }
+
+    /// Iterate over the entries of the symbol table, with their names.
+    /// Stripped files (and malformed symbol tables) have no symbols.
+    pub fn symbols(&self) -> impl Iterator<Item = (ElfSymbol, &'a str)> + '_ {
+        let symtab = self
+            .get_section_by_name(".symtab")
+            .filter(|sh| sh.sh_type == ElfSectionHeaderType::Symtab);
+        let strtab = symtab.and_then(|sh| self.get_section_header(sh.sh_link as usize).ok());
+        let data = symtab.and_then(|sh| self.get_section_data(&sh));
+        // Entries may be larger than ElfSymbol, as with program headers.
+        let entsize = symtab.map_or(0, |sh| sh.sh_entsize as usize);
+
+        let count = match (data, strtab) {
+            (Some(data), Some(_)) if entsize >= size_of::<ElfSymbol>() => data.len() / entsize,
+            _ => 0,
+        };
+        (0..count).filter_map(move |i| {
+            let entry = &data?[i * entsize..];
+            let sym = unsafe { read_unaligned(entry.as_ptr() as *const ElfSymbol) };
+            let name = self.get_string(strtab.as_ref()?, sym.st_name as usize)?;
+            Some((sym, name))
+        })
+    }
+
+    /// Collect the function symbols of the file, offset by the load base, to symbolize addresses of the loaded task.
+    pub fn symbol_table(&self, base: usize) -> SymbolTable {
+        let mut symbols: Vec<Symbol> = self
+            .symbols()
+            .filter(|(sym, _)| sym.st_type() == ElfSymbolType::Func && sym.st_size != 0)
+            .map(|(sym, name)| Symbol {
+                start: base.wrapping_add(sym.st_value as usize),
+                size: sym.st_size as usize,
+                name: String::from(name),
+            })
+            .collect();
+        symbols.sort_unstable_by_key(|symbol| symbol.start);
+
+        SymbolTable { symbols }
+    }
+
+    /// Get the RELA relocations listed in the dynamic segment. Files without one have no relocations.
+    /// PT_INTERP and DT_NEEDED are rejected, as nothing could resolve them.
+    pub fn relocations(&self) -> Result<impl Iterator<Item = ElfRela> + '_, ElfError> {
+        let (mut rela, mut rela_size, mut rela_ent) = (0, 0, size_of::<ElfRela>());
+
+        for i in 0..self.header.e_phnum as usize {
+            let ph = self.get_program_header(i)?;
+            if ph.p_type == ElfProgramHeaderType::Interp {
+                return Err(ElfError::NeedsInterpreter);
+            }
+            if ph.p_type != ElfProgramHeaderType::Dynamic {
+                continue;
+            }
+
+            let offset = ph.p_offset as usize;
+            let size = ph.p_filesz as usize;
+            add_within_bounds(offset, size, self.buf.len()).ok_or(ElfError::BadDynamic)?;
+
+            for entry in (offset..offset + size / size_of::<ElfDynamic>() * size_of::<ElfDynamic>())
+                .step_by(size_of::<ElfDynamic>())
+            {
+                let dynamic =
+                    unsafe { read_unaligned(self.buf.as_ptr().add(entry) as *const ElfDynamic) };
+                match dynamic.d_tag {
+                    ElfDynamicTag::Null => break,
+                    ElfDynamicTag::Needed => return Err(ElfError::NeedsInterpreter),
+                    ElfDynamicTag::Rela => rela = dynamic.d_val as usize,
+                    ElfDynamicTag::Relasz => rela_size = dynamic.d_val as usize,
+                    ElfDynamicTag::Relaent => rela_ent = dynamic.d_val as usize,
+                    _ => {}
+                }
+            }
+        }
+
+        easy_assert(rela_ent >= size_of::<ElfRela>(), ElfError::BadDynamic)?;
+        let count = rela_size / rela_ent;
+        // The table is given by its address, find it in the file.
+        let table = match count {
+            0 => 0,
+            _ => self
+                .vaddr_to_offset(rela, rela_size)
+                .ok_or(ElfError::BadDynamic)?,
+        };
+
+        Ok((0..count).map(move |i| unsafe {
+            read_unaligned(self.buf.as_ptr().add(table + i * rela_ent) as *const ElfRela)
+        }))
+    }
+
+    /// Get the address of the program header table once loaded (before adding the load base), if it is loaded.
+    pub fn phdr_vaddr(&self) -> Option<usize> {
+        let program_headers =
+            || (0..self.header.e_phnum as usize).filter_map(|i| self.get_program_header(i).ok());
+        let phoff = self.header.e_phoff;
+
+        // Prefer PT_PHDR, otherwise find the segment whose file data holds the table.
+        program_headers()
+            .find(|ph| ph.p_type == ElfProgramHeaderType::Phdr)
+            .map(|ph| ph.p_vaddr as usize)
+            .or_else(|| {
+                program_headers().find_map(|ph| {
+                    let delta = phoff.checked_sub(ph.p_offset)?;
+                    (ph.p_type == ElfProgramHeaderType::Load && delta < ph.p_filesz)
+                        .then(|| (ph.p_vaddr + delta) as usize)
+                })
+            })
+    }
+
+    // Find the file offset of [vaddr, vaddr + len), which must lie within the file data of a single segment.
+    fn vaddr_to_offset(&self, vaddr: usize, len: usize) -> Option<usize> {
+        (0..self.header.e_phnum as usize).find_map(|i| {
+            let ph = self.get_program_header(i).ok()?;
+            if ph.p_type != ElfProgramHeaderType::Load {
+                return None;
+            }
+            let delta = vaddr.checked_sub(ph.p_vaddr as usize)?;
+            let end = delta.checked_add(len)?;
+            if end > ph.p_filesz as usize {
+                return None;
+            }
+            let offset = (ph.p_offset as usize).checked_add(delta)?;
+            add_within_bounds(offset, len, self.buf.len())?;
+            Some(offset)
+        })
+    }
+}
+
+/// The function symbols of a loaded executable, sorted by address. Used to name the function a task crashed in.
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+#[derive(Debug, Clone)]
+struct Symbol {
+    start: usize,
+    size: usize,
+    name: String,
+}
+
+impl SymbolTable {
+    /// Find the function containing addr, and the offset of addr within it.
+    pub fn resolve_symbol(&self, addr: usize) -> Option<(&str, usize)> {
+        // The last function starting at or before addr.
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.start <= addr)
+            .checked_sub(1)?;
+        let symbol = &self.symbols[index];
+
+        let offset = addr - symbol.start;
+        (offset < symbol.size).then_some((symbol.name.as_str(), offset))
+    }
+
+    /// Write addr, followed by the function containing it if it is known, e.g. "0x401042 (<main+0x42>)".
+    pub fn format_addr(&self, addr: usize, out: &mut impl fmt::Write) -> fmt::Result {
+        match self.resolve_symbol(addr) {
+            Some((name, offset)) => write!(out, "{addr:#x} (<{name}+{offset:#x}>)"),
+            None => write!(out, "{addr:#x}"),
+        }
+    }
+}
+
+// Get the NUL-terminated string at offset in a string table.
+fn c_str(strtab: &[u8], offset: usize) -> Option<&str> {
+    let data = strtab.get(offset..)?;
+    let len = data.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&data[..len]).ok()
+}
+
+/// Check if a + b <= upper_bound, returning Some(sum) if so, None otherwise.
+fn add_within_bounds(a: usize, b: usize, upper_bound: usize) -> Option<usize> {
+    let sum = a.checked_add(b)?;
+    if sum <= upper_bound { Some(sum) } else { None }
+}
+
+fn easy_assert(cond: bool, error: ElfError) -> Result<(), ElfError> {
+    if cond { Ok(()) } else { Err(error) }
+}
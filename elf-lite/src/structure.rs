@@ -0,0 +1,207 @@
+// These structs mirror the on-disk layout. ElfParser reads them with unaligned reads, so the file can be at any offset.
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ElfHeader {
+    pub e_ident: [u8; 16],     // Magic number and other info
+    pub e_type: ElfType,       // Object file type
+    pub e_machine: ElfMachine, // Architecture
+    pub e_version: u32,        // Object file version
+    pub e_entry: u64,          // Entry point virtual address
+    pub e_phoff: u64,          // Program header table file offset
+    pub e_shoff: u64,          // Section header table file offset
+    pub e_flags: u32,          // Processor-specific flags
+    pub e_ehsize: u16,         // ELF header size in bytes
+    pub e_phentsize: u16,      // Program header table entry size
+    pub e_phnum: u16,          // Program header table entry count
+    pub e_shentsize: u16,      // Section header table entry size
+    pub e_shnum: u16,          // Section header table entry count
+    pub e_shstrndx: u16,       // Section header string table index
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ElfProgramHeader {
+    pub p_type: ElfProgramHeaderType, // Segment type
+    pub p_flags: u32,                 // Segment flags
+    pub p_offset: u64,                // Segment file offset
+    pub p_vaddr: u64,                 // Segment virtual address
+    pub p_paddr: u64,                 // Segment physical address
+    pub p_filesz: u64,                // Segment size in file
+    pub p_memsz: u64,                 // Segment size in memory
+    pub p_align: u64,                 // Segment alignment
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ElfSectionHeader {
+    pub sh_name: u32,                  // Section name (string table index)
+    pub sh_type: ElfSectionHeaderType, // Section type
+    pub sh_flags: u64,                 // Section flags
+    pub sh_addr: u64,                  // Section virtual address at execution
+    pub sh_offset: u64,                // Section file offset
+    pub sh_size: u64,                  // Section size in bytes
+    pub sh_link: u32,                  // Link to another section
+    pub sh_info: u32,                  // Additional section information
+    pub sh_addralign: u64,             // Section alignment
+    pub sh_entsize: u64,               // Entry size if section holds a table
+}
+
+// Section header flags
+pub const SHF_WRITE: u64 = 0x1; // Writable at execution
+pub const SHF_ALLOC: u64 = 0x2; // Occupies memory at execution
+pub const SHF_EXECINSTR: u64 = 0x4; // Executable
+
+// Special section indices
+pub const SHN_UNDEF: u16 = 0; // No section
+pub const SHN_XINDEX: u16 = 0xffff; // The real index is stored elsewhere (in the first section header)
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ElfSymbol {
+    pub st_name: u32,  // Symbol name (string table index)
+    pub st_info: u8,   // Symbol type (low 4 bits) and binding (high 4 bits)
+    pub st_other: u8,  // Symbol visibility
+    pub st_shndx: u16, // Section index
+    pub st_value: u64, // Symbol value
+    pub st_size: u64,  // Symbol size
+}
+
+impl ElfSymbol {
+    pub fn st_type(&self) -> ElfSymbolType {
+        ElfSymbolType(self.st_info & 0xf)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ElfDynamic {
+    pub d_tag: ElfDynamicTag, // Entry type
+    pub d_val: u64,           // Integer value or address
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ElfRela {
+    pub r_offset: u64, // Address to apply the relocation at
+    pub r_info: u64,   // Relocation type (low 32 bits) and symbol index (high 32 bits)
+    pub r_addend: i64, // Constant addend
+}
+
+impl ElfRela {
+    pub fn r_type(&self) -> ElfRelocationType {
+        ElfRelocationType(self.r_info as u32)
+    }
+}
+
+// Create a "enum" where only some variants have names, but all bit patterns are still valid
+macro_rules! open_enum {
+    ($(#[$attr:meta])* $vis:vis struct $name:ident($type:ty) {
+        $($var_name:ident = $var_value:expr),* $(,)?
+    }) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(transparent)]
+        $vis struct $name(pub $type);
+
+        impl $name {
+            $(
+                #[allow(non_upper_case_globals)]
+                pub const $var_name: Self = Self($var_value);
+            )*
+        }
+    };
+}
+
+open_enum! {
+    pub struct ElfType(u16) {
+        None = 0,
+        Relocatable = 1,
+        Executable = 2,
+        SharedObject = 3,
+        Core = 4,
+    }
+}
+
+open_enum! {
+    pub struct ElfMachine(u16) {
+        None = 0,
+        Sparc = 0x2,
+        x86 = 0x3,
+        MIPS = 0x8,
+        PowerPC = 0x14,
+        ARM = 0x28,
+        Sparc64 = 0x2b,
+        IA64 = 0x32,
+        x86_64 = 0x3e,
+        AArch64 = 0xb7,
+        RiscV = 0xf3,
+    }
+}
+
+open_enum! {
+    pub struct ElfProgramHeaderType(u32) {
+        Null = 0,
+        Load = 1,
+        Dynamic = 2,
+        Interp = 3,
+        Note = 4,
+        Shlib = 5,
+        Phdr = 6,
+        Tls = 7,
+        GnuStack = 0x6474e551,
+    }
+}
+
+open_enum! {
+    pub struct ElfSectionHeaderType(u32) {
+        Null = 0,
+        Progbits = 1,
+        Symtab = 2,
+        Strtab = 3,
+        Rela = 4,
+        Hash = 5,
+        Dynamic = 6,
+        Note = 7,
+        Nobits = 8,
+        Rel = 9,
+        Shlib = 10,
+        Dynsym = 11,
+        InitArray = 14,
+        FiniArray = 15,
+        PreinitArray = 16,
+        Group = 17,
+        SymtabShndx = 18,
+    }
+}
+
+open_enum! {
+    pub struct ElfSymbolType(u8) {
+        NoType = 0,
+        Object = 1,
+        Func = 2,
+        Section = 3,
+        File = 4,
+        Common = 5,
+        Tls = 6,
+    }
+}
+
+open_enum! {
+    pub struct ElfDynamicTag(i64) {
+        Null = 0,
+        Needed = 1,
+        Rela = 7,
+        Relasz = 8,
+        Relaent = 9,
+        Rel = 17,
+    }
+}
+
+open_enum! {
+    pub struct ElfRelocationType(u32) {
+        None = 0,
+        R64 = 1,
+        Relative = 8,
+    }
+}
@@ -0,0 +1,500 @@
+//! Host tests for the ELF parser, against the user test programs and corrupted copies of them.
+
+use std::mem::offset_of;
+
+use elf_lite::{
+    parser::{ElfError, ElfParser},
+    structure::*,
+};
+
+const TEST: &[u8] = include_bytes!("../../tests/test");
+const BSS: &[u8] = include_bytes!("../../tests/bss");
+const BSS_FILL: &[u8] = include_bytes!("../../tests/bss_fill");
+const CRASH: &[u8] = include_bytes!("../../tests/crash");
+const PIE: &[u8] = include_bytes!("../../tests/pie");
+const SHARED_PAGE: &[u8] = include_bytes!("../../tests/shared_page");
+const UNALIGNED: &[u8] = include_bytes!("../../tests/unaligned");
+
+const FIXTURES: [&[u8]; 13] = [
+    TEST,
+    include_bytes!("../../tests/stack_overflow"),
+    BSS,
+    include_bytes!("../../tests/write_text"),
+    UNALIGNED,
+    SHARED_PAGE,
+    include_bytes!("../../tests/maps"),
+    PIE,
+    CRASH,
+    include_bytes!("../../tests/args"),
+    include_bytes!("../../tests/tls"),
+    BSS_FILL,
+    include_bytes!("../../tests/hello"),
+];
+
+// Overwrite a little-endian field of the file at offset.
+fn set<const N: usize>(elf: &mut [u8], offset: usize, bytes: [u8; N]) {
+    elf[offset..offset + N].copy_from_slice(&bytes);
+}
+
+fn set_header_u16(elf: &mut [u8], field: usize, value: u16) {
+    set(elf, field, value.to_le_bytes());
+}
+
+fn set_header_u64(elf: &mut [u8], field: usize, value: u64) {
+    set(elf, field, value.to_le_bytes());
+}
+
+// Overwrite a field of program header index, assuming the table is where the header says.
+fn set_ph_u64(elf: &mut [u8], index: usize, field: usize, value: u64) {
+    let header = *ElfParser::parse(TEST).unwrap().get_header();
+    let offset = header.e_phoff as usize + index * header.e_phentsize as usize + field;
+    set(elf, offset, value.to_le_bytes());
+}
+
+fn parse_corrupted(elf: &[u8], corrupt: impl FnOnce(&mut Vec<u8>)) -> Result<(), ElfError> {
+    let mut elf = elf.to_vec();
+    corrupt(&mut elf);
+    ElfParser::parse(&elf).map(|_| ())
+}
+
+#[test]
+fn parses_every_fixture() {
+    for elf in FIXTURES {
+        let parser = ElfParser::parse(elf).unwrap();
+        assert_eq!(parser.get_header().e_machine, ElfMachine::x86_64);
+    }
+}
+
+#[test]
+fn reads_the_program_headers() {
+    let parser = ElfParser::parse(TEST).unwrap();
+    let loads: Vec<_> = (0..parser.get_header().e_phnum as usize)
+        .map(|i| parser.get_program_header(i).unwrap())
+        .filter(|ph| ph.p_type == ElfProgramHeaderType::Load)
+        .map(|ph| (ph.p_vaddr, ph.p_offset, ph.p_flags))
+        .collect();
+    assert_eq!(
+        loads,
+        [
+            (0x400000, 0x0, 0x4),
+            (0x401000, 0x1000, 0x5),
+            (0x402000, 0x2000, 0x4)
+        ]
+    );
+}
+
+#[test]
+fn finds_program_headers_by_type() {
+    let parser = ElfParser::parse(TEST).unwrap();
+    assert!(
+        parser
+            .find_program_header(ElfProgramHeaderType::GnuStack)
+            .unwrap()
+            .is_some()
+    );
+    assert!(
+        parser
+            .find_program_header(ElfProgramHeaderType::Interp)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn finds_the_loaded_program_headers() {
+    // The program headers are in the first segment, at offset 0x40.
+    let parser = ElfParser::parse(TEST).unwrap();
+    assert_eq!(parser.phdr_vaddr(), Some(0x400040));
+}
+
+#[test]
+fn position_independence() {
+    assert!(ElfParser::parse(PIE).unwrap().is_position_independent());
+    assert!(!ElfParser::parse(TEST).unwrap().is_position_independent());
+}
+
+#[test]
+fn parses_at_any_alignment() {
+    let mut buf = vec![0u8; TEST.len() + 1];
+    buf[1..].copy_from_slice(TEST);
+    let parser = ElfParser::parse(&buf[1..]).unwrap();
+    assert_eq!(
+        parser.get_header().e_entry,
+        ElfParser::parse(TEST).unwrap().get_header().e_entry
+    );
+    assert!(parser.get_section_by_name(".text").is_some());
+}
+
+#[test]
+fn too_small() {
+    assert_eq!(
+        parse_corrupted(TEST, |elf| elf.truncate(32)),
+        Err(ElfError::TooSmall)
+    );
+    assert_eq!(ElfParser::parse(&[]).err(), Some(ElfError::TooSmall));
+}
+
+#[test]
+fn bad_magic() {
+    assert_eq!(
+        parse_corrupted(TEST, |elf| elf[1] = b'X'),
+        Err(ElfError::BadMagic)
+    );
+}
+
+#[test]
+fn unsupported_class() {
+    assert_eq!(
+        parse_corrupted(TEST, |elf| elf[4] = 1),
+        Err(ElfError::UnsupportedClass)
+    );
+}
+
+#[test]
+fn unsupported_endian() {
+    assert_eq!(
+        parse_corrupted(TEST, |elf| elf[5] = 2),
+        Err(ElfError::UnsupportedEndian)
+    );
+}
+
+#[test]
+fn unsupported_version() {
+    assert_eq!(
+        parse_corrupted(TEST, |elf| elf[6] = 0),
+        Err(ElfError::UnsupportedVersion)
+    );
+}
+
+#[test]
+fn not_executable() {
+    let corrupt = |elf: &mut Vec<u8>| {
+        set_header_u16(elf, offset_of!(ElfHeader, e_type), ElfType::Relocatable.0)
+    };
+    assert_eq!(parse_corrupted(TEST, corrupt), Err(ElfError::NotExecutable));
+}
+
+#[test]
+fn wrong_machine() {
+    let corrupt = |elf: &mut Vec<u8>| {
+        set_header_u16(elf, offset_of!(ElfHeader, e_machine), ElfMachine::ARM.0)
+    };
+    assert_eq!(parse_corrupted(TEST, corrupt), Err(ElfError::WrongMachine));
+}
+
+#[test]
+fn bad_entry_sizes() {
+    let corrupt = |elf: &mut Vec<u8>| set_header_u16(elf, offset_of!(ElfHeader, e_phentsize), 32);
+    assert_eq!(parse_corrupted(TEST, corrupt), Err(ElfError::BadPhentsize));
+    let corrupt = |elf: &mut Vec<u8>| set_header_u16(elf, offset_of!(ElfHeader, e_shentsize), 32);
+    assert_eq!(parse_corrupted(TEST, corrupt), Err(ElfError::BadShentsize));
+}
+
+#[test]
+fn program_header_table_out_of_bounds() {
+    let corrupt = |elf: &mut Vec<u8>| {
+        let len = elf.len() as u64;
+        set_header_u64(elf, offset_of!(ElfHeader, e_phoff), len - 8)
+    };
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::PhTableOutOfBounds)
+    );
+
+    let corrupt =
+        |elf: &mut Vec<u8>| set_header_u64(elf, offset_of!(ElfHeader, e_phoff), u64::MAX - 8);
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::PhTableOutOfBounds)
+    );
+
+    let corrupt = |elf: &mut Vec<u8>| set_header_u16(elf, offset_of!(ElfHeader, e_phnum), u16::MAX);
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::PhTableOutOfBounds)
+    );
+}
+
+#[test]
+fn empty_program_header_table_out_of_bounds() {
+    let corrupt = |elf: &mut Vec<u8>| {
+        let len = elf.len() as u64;
+        set_header_u16(elf, offset_of!(ElfHeader, e_phnum), 0);
+        set_header_u64(elf, offset_of!(ElfHeader, e_phoff), len + 1);
+    };
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::PhTableOutOfBounds)
+    );
+}
+
+#[test]
+fn program_header_index_out_of_range() {
+    let parser = ElfParser::parse(TEST).unwrap();
+    let phnum = parser.get_header().e_phnum as usize;
+    assert_eq!(
+        parser.get_program_header(phnum).err(),
+        Some(ElfError::PhOutOfBounds { index: phnum })
+    );
+    let shnum = parser.get_header().e_shnum as usize;
+    assert_eq!(
+        parser.get_section_header(shnum).err(),
+        Some(ElfError::ShOutOfBounds { index: shnum })
+    );
+}
+
+#[test]
+fn segment_out_of_bounds() {
+    let corrupt = |elf: &mut Vec<u8>| {
+        set_ph_u64(elf, 1, offset_of!(ElfProgramHeader, p_offset), u64::MAX - 8)
+    };
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::SegmentOutOfBounds { index: 1 })
+    );
+
+    let corrupt =
+        |elf: &mut Vec<u8>| set_ph_u64(elf, 2, offset_of!(ElfProgramHeader, p_filesz), 0x100000);
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::SegmentOutOfBounds { index: 2 })
+    );
+}
+
+#[test]
+fn misaligned_segment() {
+    let corrupt =
+        |elf: &mut Vec<u8>| set_ph_u64(elf, 1, offset_of!(ElfProgramHeader, p_vaddr), 0x401010);
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::MisalignedSegment { index: 1 })
+    );
+
+    let corrupt =
+        |elf: &mut Vec<u8>| set_ph_u64(elf, 1, offset_of!(ElfProgramHeader, p_align), 0x30);
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::MisalignedSegment { index: 1 })
+    );
+
+    // Alignments of 0 and 1 mean no constraint.
+    for align in [0, 1] {
+        let corrupt = |elf: &mut Vec<u8>| {
+            set_ph_u64(elf, 1, offset_of!(ElfProgramHeader, p_align), align);
+            set_ph_u64(elf, 1, offset_of!(ElfProgramHeader, p_vaddr), 0x401010);
+        };
+        assert_eq!(parse_corrupted(TEST, corrupt), Ok(()));
+    }
+}
+
+#[test]
+fn segments_with_unaligned_addresses() {
+    // The data segment of this program starts at 0x404123, which is congruent to its offset.
+    assert!(ElfParser::parse(UNALIGNED).is_ok());
+    // The segments of this program share pages, with 16 byte alignment.
+    assert!(ElfParser::parse(SHARED_PAGE).is_ok());
+}
+
+#[test]
+fn overlapping_file_range() {
+    let corrupt =
+        |elf: &mut Vec<u8>| set_ph_u64(elf, 2, offset_of!(ElfProgramHeader, p_offset), 0x1000);
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::OverlappingFileRange { index: 2 })
+    );
+
+    let corrupt =
+        |elf: &mut Vec<u8>| set_ph_u64(elf, 1, offset_of!(ElfProgramHeader, p_filesz), 0x1001);
+    assert_eq!(
+        parse_corrupted(TEST, corrupt),
+        Err(ElfError::OverlappingFileRange { index: 2 })
+    );
+}
+
+#[test]
+fn segments_without_file_data_may_share_offsets() {
+    // The BSS segment of this program has offset 0, like the first segment.
+    assert!(ElfParser::parse(BSS).is_ok());
+}
+
+#[test]
+fn finds_sections_by_name() {
+    let parser = ElfParser::parse(TEST).unwrap();
+    let text = parser.get_section_by_name(".text").unwrap();
+    assert_eq!(text.sh_type, ElfSectionHeaderType::Progbits);
+    assert_eq!(
+        text.sh_flags & (SHF_ALLOC | SHF_EXECINSTR | SHF_WRITE),
+        SHF_ALLOC | SHF_EXECINSTR
+    );
+    let entry = parser.get_header().e_entry;
+    assert!(text.sh_addr <= entry && entry < text.sh_addr + text.sh_size);
+    assert!(parser.get_section_by_name(".nonexistent").is_none());
+}
+
+#[test]
+fn data_and_bss_sections() {
+    let parser = ElfParser::parse(BSS_FILL).unwrap();
+    let data = parser.get_section_by_name(".data").unwrap();
+    assert_eq!(
+        data.sh_flags & (SHF_ALLOC | SHF_EXECINSTR | SHF_WRITE),
+        SHF_ALLOC | SHF_WRITE
+    );
+    assert!(
+        parser
+            .get_section_data(&data)
+            .unwrap()
+            .iter()
+            .all(|&byte| byte == 0x5a)
+    );
+    let bss = parser.get_section_by_name(".bss").unwrap();
+    assert_eq!(bss.sh_type, ElfSectionHeaderType::Nobits);
+}
+
+#[test]
+fn section_names() {
+    let parser = ElfParser::parse(TEST).unwrap();
+    let names: Vec<_> = parser
+        .sections()
+        .map(|section| section.unwrap().0)
+        .collect();
+    assert_eq!(names[0], "");
+    for name in [".text", ".rodata", ".symtab", ".strtab", ".shstrtab"] {
+        assert!(names.contains(&name), "{name} missing from {names:?}");
+    }
+    assert!(parser.get_shstrtab().unwrap().starts_with(b"\0"));
+}
+
+#[test]
+fn bad_shstrndx() {
+    for shstrndx in [SHN_UNDEF, SHN_XINDEX, 1000] {
+        let mut elf = TEST.to_vec();
+        set_header_u16(&mut elf, offset_of!(ElfHeader, e_shstrndx), shstrndx);
+        let parser = ElfParser::parse(&elf).unwrap();
+        assert_eq!(parser.get_shstrtab().err(), Some(ElfError::BadShstrndx));
+        assert_eq!(
+            parser.sections().last().unwrap().err(),
+            Some(ElfError::BadShstrndx)
+        );
+        assert!(parser.get_section_by_name(".text").is_none());
+    }
+}
+
+#[test]
+fn bad_section_name() {
+    let mut elf = TEST.to_vec();
+    let header = *ElfParser::parse(TEST).unwrap().get_header();
+    // Section 2 is .text
+    let offset = header.e_shoff as usize + 2 * header.e_shentsize as usize;
+    set(
+        &mut elf,
+        offset + offset_of!(ElfSectionHeader, sh_name),
+        u32::MAX.to_le_bytes(),
+    );
+
+    let parser = ElfParser::parse(&elf).unwrap();
+    let sh = parser.get_section_header(2).unwrap();
+    assert_eq!(parser.section_name(&sh), Err(ElfError::BadSectionName));
+    assert!(parser.get_section_by_name(".text").is_none());
+    assert!(parser.get_section_by_name(".rodata").is_some());
+}
+
+#[test]
+fn symbols() {
+    let parser = ElfParser::parse(CRASH).unwrap();
+    let crash_here = parser
+        .symbols()
+        .find(|(_, name)| *name == "crash_here")
+        .map(|(sym, _)| sym)
+        .unwrap();
+    assert_eq!(crash_here.st_type(), ElfSymbolType::Func);
+    assert_eq!((crash_here.st_value, crash_here.st_size), (0x401000, 18));
+}
+
+#[test]
+fn resolves_symbols() {
+    let symbols = ElfParser::parse(CRASH).unwrap().symbol_table(0);
+    assert_eq!(symbols.resolve_symbol(0x401009), Some(("crash_here", 0x9)));
+    assert_eq!(symbols.resolve_symbol(0x401012), Some(("_start", 0)));
+    assert_eq!(symbols.resolve_symbol(0x400fff), None);
+    assert_eq!(symbols.resolve_symbol(0x401012 + 58), None);
+
+    let mut out = String::new();
+    symbols.format_addr(0x401009, &mut out).unwrap();
+    assert_eq!(out, "0x401009 (<crash_here+0x9>)");
+}
+
+#[test]
+fn resolves_symbols_with_load_base() {
+    let symbols = ElfParser::parse(PIE).unwrap().symbol_table(0x10000);
+    assert_eq!(symbols.resolve_symbol(0x11000), Some(("_start", 0)));
+    assert_eq!(symbols.resolve_symbol(0x1000), None);
+}
+
+#[test]
+fn stripped_files_have_no_symbols() {
+    let mut elf = CRASH.to_vec();
+    set_header_u16(&mut elf, offset_of!(ElfHeader, e_shnum), 0);
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(parser.symbols().count(), 0);
+
+    let mut out = String::new();
+    parser
+        .symbol_table(0)
+        .format_addr(0x401009, &mut out)
+        .unwrap();
+    assert_eq!(out, "0x401009");
+}
+
+#[test]
+fn relocations() {
+    let parser = ElfParser::parse(PIE).unwrap();
+    let relocations: Vec<_> = parser.relocations().unwrap().collect();
+    assert_eq!(relocations.len(), 1);
+    assert_eq!(relocations[0].r_type(), ElfRelocationType::Relative);
+    assert_eq!(
+        (relocations[0].r_offset, relocations[0].r_addend),
+        (0x4030, 0x4000)
+    );
+
+    // Executables linked at a fixed address have no dynamic segment.
+    assert_eq!(
+        ElfParser::parse(TEST)
+            .unwrap()
+            .relocations()
+            .unwrap()
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn needs_interpreter() {
+    let mut elf = PIE.to_vec();
+    let parser = ElfParser::parse(PIE).unwrap();
+    let header = parser.get_header();
+    let index = (0..header.e_phnum as usize)
+        .find(|&i| parser.get_program_header(i).unwrap().p_type == ElfProgramHeaderType::Dynamic)
+        .unwrap();
+    let offset = header.e_phoff as usize + index * header.e_phentsize as usize;
+    set(
+        &mut elf,
+        offset,
+        ElfProgramHeaderType::Interp.0.to_le_bytes(),
+    );
+
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(parser.relocations().err(), Some(ElfError::NeedsInterpreter));
+}
+
+#[test]
+fn error_messages() {
+    assert_eq!(
+        ElfError::PhOutOfBounds { index: 3 }.to_string(),
+        "program header 3 out of bounds"
+    );
+    assert_eq!(
+        ElfError::UnsupportedRelocation { r_type: 1 }.to_string(),
+        "unsupported relocation of type 1"
+    );
+}
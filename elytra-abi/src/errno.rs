@@ -0,0 +1,29 @@
+//! Error numbers, as in Linux.
+//!
+//! A syscall that fails returns its error number negated, in -MAX_ERRNO..=-1. Results are never in that range.
+
+/// Largest error number.
+pub const MAX_ERRNO: usize = 4095;
+
+pub const EPERM: usize = 1; // Operation not permitted
+pub const ENOENT: usize = 2; // No such file or directory (or program)
+pub const ESRCH: usize = 3; // No such task
+pub const EINTR: usize = 4; // Interrupted by a signal
+pub const E2BIG: usize = 7; // Argument list too long
+pub const ENOEXEC: usize = 8; // Exec format error
+pub const EBADF: usize = 9; // Bad file descriptor
+pub const ECHILD: usize = 10; // No child to wait for
+pub const EAGAIN: usize = 11; // Try again (the futex word changed)
+pub const ENOMEM: usize = 12; // Out of memory
+pub const EFAULT: usize = 14; // Bad address
+pub const ENODEV: usize = 19; // No such device
+pub const EINVAL: usize = 22; // Invalid argument
+pub const EMFILE: usize = 24; // Too many open files
+pub const EPIPE: usize = 32; // Broken pipe
+pub const ENOSYS: usize = 38; // Function not implemented
+pub const ETIMEDOUT: usize = 110; // Timed out
+
+/// Check if the value a syscall returned is an error.
+pub fn is_error(ret: isize) -> bool {
+    (-(MAX_ERRNO as isize)..0).contains(&ret)
+}
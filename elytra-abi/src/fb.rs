@@ -0,0 +1,20 @@
+//! The framebuffer, as user programs see it through sys_fb_info and sys_fb_blit.
+
+// Pixel formats in FbInfo.
+pub const FB_FORMAT_RGB: u32 = 0; // Red, green and blue bytes, then padding up to bytes_per_pixel
+pub const FB_FORMAT_BGR: u32 = 1; // Blue, green and red bytes, then padding up to bytes_per_pixel
+pub const FB_FORMAT_GRAY: u32 = 2; // One intensity byte
+pub const FB_FORMAT_UNKNOWN: u32 = 3;
+
+/// Geometry and pixel format of the framebuffer.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FbInfo {
+    pub width: u32,  // In pixels
+    pub height: u32, // In pixels
+    pub stride: u32, // Pixels from the start of one row to the start of the next
+    pub bytes_per_pixel: u32,
+    pub format: u32, // One of FB_FORMAT_*
+}
+
+const _: () = assert!(size_of::<FbInfo>() == 20);
@@ -0,0 +1,18 @@
+//! The interface between the kernel and user programs: syscall numbers, error numbers, flags and the layout of
+//! structs passed through syscalls.
+//!
+//! Both sides build against this crate, so they can't disagree on a number. It has no dependencies (only `core`).
+//! User programs call syscalls through `syscall`, which the kernel doesn't use.
+
+#![no_std]
+
+pub mod errno;
+pub mod fb;
+pub mod mm;
+pub mod nr;
+pub mod power;
+pub mod signal;
+pub mod syscall;
+pub mod sysinfo;
+pub mod task;
+pub mod time;
@@ -0,0 +1,7 @@
+//! Flags for memory syscalls.
+
+/// mmap: the region is writable. Regions are always readable.
+pub const PROT_WRITE: usize = 0x2;
+
+/// mmap: the region stays shared with forked children, instead of being copied on write.
+pub const MAP_SHARED: usize = 0x1;
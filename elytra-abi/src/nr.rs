@@ -0,0 +1,40 @@
+//! Syscall numbers, passed in rax.
+
+pub const SYS_EXIT: usize = 0;
+pub const SYS_YIELD: usize = 1;
+pub const SYS_BRK: usize = 2;
+pub const SYS_MMAP: usize = 3;
+pub const SYS_WRITE: usize = 4;
+pub const SYS_MAPS: usize = 5;
+pub const SYS_SLEEP_MS: usize = 6;
+pub const SYS_SET_PRIORITY: usize = 7;
+pub const SYS_WAIT_TICK: usize = 8;
+pub const SYS_SPAWN: usize = 9;
+pub const SYS_WAIT: usize = 10;
+pub const SYS_FORK: usize = 11;
+pub const SYS_EXEC: usize = 12;
+pub const SYS_TASK_STATS: usize = 13;
+pub const SYS_GETPID: usize = 14;
+pub const SYS_READ: usize = 15;
+pub const SYS_GETPPID: usize = 16;
+pub const SYS_TRACE_ME: usize = 17;
+pub const SYS_CLOCK_GETTIME: usize = 18;
+pub const SYS_SLEEP_NS: usize = 19;
+pub const SYS_FB_INFO: usize = 20;
+pub const SYS_FB_BLIT: usize = 21;
+pub const SYS_PIPE: usize = 22;
+pub const SYS_CLOSE: usize = 23;
+pub const SYS_FUTEX_WAIT: usize = 24;
+pub const SYS_FUTEX_WAKE: usize = 25;
+pub const SYS_KILL: usize = 26;
+pub const SYS_SIGACTION: usize = 27;
+pub const SYS_SIGRETURN: usize = 28;
+pub const SYS_GETRANDOM: usize = 29;
+pub const SYS_SYSINFO: usize = 30;
+pub const SYS_DMESG: usize = 31;
+pub const SYS_REBOOT: usize = 32;
+pub const SYS_SET_FS_BASE: usize = 33;
+pub const SYS_GET_FS_BASE: usize = 34;
+
+/// Number of syscall numbers. Every number below this has a handler.
+pub const SYSCALL_COUNT: usize = 35;
@@ -0,0 +1,9 @@
+//! Arguments of sys_reboot.
+
+/// The first argument of sys_reboot, so that a stray call with some other number does nothing.
+pub const REBOOT_MAGIC: usize = 0xfee1_dead;
+
+/// Reset the machine.
+pub const REBOOT_CMD_RESTART: usize = 1;
+/// Turn the machine off.
+pub const REBOOT_CMD_POWER_OFF: usize = 2;
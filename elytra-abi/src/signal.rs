@@ -0,0 +1,48 @@
+//! Signals, and the frame the kernel saves on the user stack to run a signal handler.
+//!
+//! sys_kill() sends a signal to a task. A signal without a handler terminates the task, with SIGNAL_EXIT_BASE plus the
+//! signal number as its exit code. sys_sigaction() sets a handler for any signal but SIGKILL.
+//!
+//! Handlers run when the task returns from a syscall. The kernel saves a SigContext on the user stack, and calls the
+//! handler with the signal number. The handler returns into the restorer given to sys_sigaction(), which must call
+//! sys_sigreturn() with the stack pointer at the SigContext. The task then returns from the interrupted syscall, with
+//! everything as it was. A task that never makes a syscall can still be terminated, but its handlers don't run until
+//! it makes one.
+//!
+//! A signal interrupts a blocking syscall, which fails with EINTR. The signal is blocked while its handler runs.
+
+pub const SIGINT: usize = 2; // Interrupt
+pub const SIGKILL: usize = 9; // Kill, can't have a handler
+pub const SIGUSR1: usize = 10; // For the program to use
+pub const SIGUSR2: usize = 12; // For the program to use
+pub const SIGTERM: usize = 15; // Termination
+
+/// Signal numbers are below this. sys_kill() with signal 0 only checks that the task exists.
+pub const NSIG: usize = 32;
+
+/// A task terminated by a signal exits with this plus the signal number.
+pub const SIGNAL_EXIT_BASE: i32 = 128;
+
+/// Size of the area below the stack pointer that functions may use without moving it, which the kernel skips over
+/// before it saves a SigContext.
+pub const RED_ZONE_SIZE: usize = 128;
+
+/// The registers of a task that a signal interrupted, as saved on its stack while the handler runs.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct SigContext {
+    pub fpu: [u8; 512], // x87 FPU and SSE registers, in the FXSAVE format
+    pub rax: u64,       // Result of the interrupted syscall
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub blocked: u64, // Signals that were blocked before the handler ran, one bit per signal number
+}
+
+const _: () = assert!(size_of::<SigContext>() == 608);
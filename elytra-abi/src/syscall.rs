@@ -0,0 +1,326 @@
+//! Syscall wrappers for user programs.
+//!
+//! The syscall number goes in rax, and the arguments in rdi, rsi, rdx, r10, r8 and r9. The result comes back in rax,
+//! as a negated error number if the syscall failed (see errno). The kernel clears the argument registers on return,
+//! and the SYSCALL instruction itself clobbers rcx and r11.
+//!
+//! The sys_* wrappers return the raw result. Pointers are passed to the kernel as they are, and are never dereferenced
+//! here, so a bad one just makes the syscall fail with EFAULT.
+
+use core::arch::asm;
+
+use crate::{fb::FbInfo, nr::*, sysinfo::SysInfo, task::TaskInfo, time::Timespec};
+
+/// Call syscall num without arguments.
+///
+/// # Safety
+/// The syscall must not break any invariant of the program, e.g. by unmapping memory it uses.
+#[inline(always)]
+pub unsafe fn syscall0(num: usize) -> isize {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") num => ret,
+            lateout("rdi") _, lateout("rsi") _, lateout("rdx") _,
+            lateout("r10") _, lateout("r8") _, lateout("r9") _,
+            lateout("rcx") _, lateout("r11") _,
+            options(nostack)
+        )
+    };
+    ret
+}
+
+/// Call syscall num with one argument.
+///
+/// # Safety
+/// See syscall0.
+#[inline(always)]
+pub unsafe fn syscall1(num: usize, arg1: usize) -> isize {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") num => ret,
+            inlateout("rdi") arg1 => _, lateout("rsi") _, lateout("rdx") _,
+            lateout("r10") _, lateout("r8") _, lateout("r9") _,
+            lateout("rcx") _, lateout("r11") _,
+            options(nostack)
+        )
+    };
+    ret
+}
+
+/// Call syscall num with two arguments.
+///
+/// # Safety
+/// See syscall0.
+#[inline(always)]
+pub unsafe fn syscall2(num: usize, arg1: usize, arg2: usize) -> isize {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") num => ret,
+            inlateout("rdi") arg1 => _, inlateout("rsi") arg2 => _, lateout("rdx") _,
+            lateout("r10") _, lateout("r8") _, lateout("r9") _,
+            lateout("rcx") _, lateout("r11") _,
+            options(nostack)
+        )
+    };
+    ret
+}
+
+/// Call syscall num with three arguments.
+///
+/// # Safety
+/// See syscall0.
+#[inline(always)]
+pub unsafe fn syscall3(num: usize, arg1: usize, arg2: usize, arg3: usize) -> isize {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") num => ret,
+            inlateout("rdi") arg1 => _, inlateout("rsi") arg2 => _, inlateout("rdx") arg3 => _,
+            lateout("r10") _, lateout("r8") _, lateout("r9") _,
+            lateout("rcx") _, lateout("r11") _,
+            options(nostack)
+        )
+    };
+    ret
+}
+
+/// Call syscall num with four arguments.
+///
+/// # Safety
+/// See syscall0.
+#[inline(always)]
+pub unsafe fn syscall4(num: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> isize {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") num => ret,
+            inlateout("rdi") arg1 => _, inlateout("rsi") arg2 => _, inlateout("rdx") arg3 => _,
+            inlateout("r10") arg4 => _, lateout("r8") _, lateout("r9") _,
+            lateout("rcx") _, lateout("r11") _,
+            options(nostack)
+        )
+    };
+    ret
+}
+
+/// Call syscall num with five arguments.
+///
+/// # Safety
+/// See syscall0.
+#[inline(always)]
+pub unsafe fn syscall5(
+    num: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+) -> isize {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") num => ret,
+            inlateout("rdi") arg1 => _, inlateout("rsi") arg2 => _, inlateout("rdx") arg3 => _,
+            inlateout("r10") arg4 => _, inlateout("r8") arg5 => _, lateout("r9") _,
+            lateout("rcx") _, lateout("r11") _,
+            options(nostack)
+        )
+    };
+    ret
+}
+
+/// Call syscall num with six arguments.
+///
+/// # Safety
+/// See syscall0.
+#[inline(always)]
+pub unsafe fn syscall6(
+    num: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+    arg6: usize,
+) -> isize {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") num => ret,
+            inlateout("rdi") arg1 => _, inlateout("rsi") arg2 => _, inlateout("rdx") arg3 => _,
+            inlateout("r10") arg4 => _, inlateout("r8") arg5 => _, inlateout("r9") arg6 => _,
+            lateout("rcx") _, lateout("r11") _,
+            options(nostack)
+        )
+    };
+    ret
+}
+
+/// Exit the current task with code.
+pub fn sys_exit(code: i32) -> ! {
+    unsafe { syscall1(SYS_EXIT, code as usize) };
+    unreachable!("exit returned");
+}
+
+/// Give up the rest of the timeslice.
+pub fn sys_yield() -> isize {
+    unsafe { syscall0(SYS_YIELD) }
+}
+
+/// Write up to len bytes from buf to fd. Returns the number of bytes written.
+pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
+    unsafe { syscall3(SYS_WRITE, fd, buf as usize, len) }
+}
+
+/// Read up to len bytes from fd into buf, blocking until at least one byte is there. Returns the number of bytes read.
+pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
+    unsafe { syscall3(SYS_READ, fd, buf as usize, len) }
+}
+
+/// Map an anonymous region of len bytes, with PROT_* flags in prot and MAP_* flags in flags. Returns its address.
+pub fn sys_mmap(len: usize, prot: usize, flags: usize) -> isize {
+    unsafe { syscall3(SYS_MMAP, len, prot, flags) }
+}
+
+/// Block while the 32-bit word at addr holds expected, until sys_futex_wake() is called on it, or for at most
+/// timeout_ns nanoseconds if it isn't 0. Fails with EAGAIN if the word holds another value, with ETIMEDOUT if the
+/// timeout ran out, and with EINTR if a signal arrived. The task may also wake up for no reason, so check the word
+/// again.
+pub fn sys_futex_wait(addr: *const u32, expected: u32, timeout_ns: u64) -> isize {
+    unsafe { syscall3(SYS_FUTEX_WAIT, addr as usize, expected as usize, timeout_ns as usize) }
+}
+
+/// Wake up to count tasks blocked in sys_futex_wait() on the word at addr. Returns the number of tasks woken up.
+pub fn sys_futex_wake(addr: *const u32, count: usize) -> isize {
+    unsafe { syscall2(SYS_FUTEX_WAKE, addr as usize, count) }
+}
+
+/// Create a pipe, and store the descriptors of its read end in fds[0] and its write end in fds[1]. Reads from an empty
+/// pipe block until there is data, or return 0 once every write end is closed. Writes to a full pipe block until there
+/// is room, and fail with EPIPE once every read end is closed.
+pub fn sys_pipe(fds: &mut [i32; 2]) -> isize {
+    unsafe { syscall1(SYS_PIPE, fds.as_mut_ptr() as usize) }
+}
+
+/// Close the file descriptor.
+pub fn sys_close(fd: usize) -> isize {
+    unsafe { syscall1(SYS_CLOSE, fd) }
+}
+
+/// Fill buf with up to len random bytes. Returns the number of bytes, which is less than len if it is over 256.
+pub fn sys_getrandom(buf: *mut u8, len: usize) -> isize {
+    unsafe { syscall2(SYS_GETRANDOM, buf as usize, len) }
+}
+
+/// Send the signal to the task with the id. Signal 0 only checks that the task exists.
+pub fn sys_kill(id: usize, sig: usize) -> isize {
+    unsafe { syscall2(SYS_KILL, id, sig) }
+}
+
+/// Run handler when the signal arrives, or terminate the task if it is None. The handler returns into restorer, which
+/// must call SYS_SIGRETURN right away, with the stack pointer it was entered with (see signal).
+pub fn sys_sigaction(
+    sig: usize,
+    handler: Option<extern "C" fn(usize)>,
+    restorer: unsafe extern "C" fn() -> !,
+) -> isize {
+    let handler = handler.map_or(0, |handler| handler as usize);
+    unsafe { syscall3(SYS_SIGACTION, sig, handler, restorer as usize) }
+}
+
+/// Sleep for at least ms milliseconds.
+pub fn sys_sleep_ms(ms: usize) -> isize {
+    unsafe { syscall1(SYS_SLEEP_MS, ms) }
+}
+
+/// Start the program with the name as a child of the current task, with SPAWN_* flags. argv is a null-terminated array
+/// of NUL-terminated arguments, or null to pass just the name. Returns the id of the child.
+pub fn sys_spawn(name: &str, flags: usize, argv: *const *const u8) -> isize {
+    unsafe { syscall4(SYS_SPAWN, name.as_ptr() as usize, name.len(), flags, argv as usize) }
+}
+
+/// Sleep for at least ns nanoseconds.
+pub fn sys_sleep_ns(ns: u64) -> isize {
+    unsafe { syscall1(SYS_SLEEP_NS, ns as usize) }
+}
+
+/// Store the time of the clock (a CLOCK_* constant) in time.
+pub fn sys_clock_gettime(clock: usize, time: *mut Timespec) -> isize {
+    unsafe { syscall2(SYS_CLOCK_GETTIME, clock, time as usize) }
+}
+
+/// Store the geometry and pixel format of the framebuffer in info.
+pub fn sys_fb_info(info: *mut FbInfo) -> isize {
+    unsafe { syscall1(SYS_FB_INFO, info as usize) }
+}
+
+/// Draw the w x h rectangle of pixels at buf to (x, y) on the framebuffer. buf holds the rows one after another, each
+/// w * bytes_per_pixel bytes long, in the pixel format of the framebuffer.
+pub fn sys_fb_blit(x: usize, y: usize, w: usize, h: usize, buf: *const u8) -> isize {
+    unsafe { syscall5(SYS_FB_BLIT, x, y, w, h, buf as usize) }
+}
+
+/// Create a copy of the current task. Returns 0 in the child, and the id of the child in the parent.
+pub fn sys_fork() -> isize {
+    unsafe { syscall0(SYS_FORK) }
+}
+
+/// Wait for the child with the id to exit, and store its exit code in status if it isn't null. Returns the id.
+pub fn sys_wait(id: usize, status: *mut i32) -> isize {
+    unsafe { syscall2(SYS_WAIT, id, status as usize) }
+}
+
+/// Id of the current task.
+pub fn sys_getpid() -> isize {
+    unsafe { syscall0(SYS_GETPID) }
+}
+
+/// Id of the parent of the current task, or 0 if it has none.
+pub fn sys_getppid() -> isize {
+    unsafe { syscall0(SYS_GETPPID) }
+}
+
+/// Turn tracing of the syscalls of the current task on or off. The kernel prints a line for each traced syscall.
+pub fn sys_trace_me(enable: bool) -> isize {
+    unsafe { syscall1(SYS_TRACE_ME, enable as usize) }
+}
+
+/// Copy a snapshot of up to tasks.len() tasks into tasks. Returns the number of tasks, which may be more.
+pub fn sys_task_stats(tasks: &mut [TaskInfo]) -> isize {
+    unsafe { syscall2(SYS_TASK_STATS, tasks.as_mut_ptr() as usize, tasks.len()) }
+}
+
+/// Store a snapshot of the memory and tasks of the system in info.
+pub fn sys_sysinfo(info: *mut SysInfo) -> isize {
+    unsafe { syscall1(SYS_SYSINFO, info as usize) }
+}
+
+/// Copy the newest whole lines of the kernel log that fit into buf, as text. Returns the number of bytes.
+pub fn sys_dmesg(buf: *mut u8, len: usize) -> isize {
+    unsafe { syscall2(SYS_DMESG, buf as usize, len) }
+}
+
+/// Reset the machine, or turn it off, as cmd says (see power). magic must be REBOOT_MAGIC. Only returns on failure.
+pub fn sys_reboot(magic: usize, cmd: usize) -> isize {
+    unsafe { syscall2(SYS_REBOOT, magic, cmd) }
+}
+
+/// Set the fs base of the current task, which thread-local storage is reached through. base must be a user address.
+pub fn sys_set_fs_base(base: usize) -> isize {
+    unsafe { syscall1(SYS_SET_FS_BASE, base) }
+}
+
+/// The fs base of the current task.
+pub fn sys_get_fs_base() -> isize {
+    unsafe { syscall0(SYS_GET_FS_BASE) }
+}
@@ -0,0 +1,16 @@
+//! System-wide statistics, as returned by sys_sysinfo.
+
+/// A snapshot of the memory and tasks of the system.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SysInfo {
+    pub total_pages: u64,      // Pages of physical memory the kernel allocates from
+    pub free_pages: u64,       // Pages not allocated, never more than total_pages
+    pub slab_bytes: u64,       // Bytes of small kernel objects allocated from the slab caches
+    pub num_tasks: u64,        // Tasks that haven't exited
+    pub uptime_ns: u64,        // Time since boot, as CLOCK_MONOTONIC
+    pub context_switches: u64, // Times the CPU was switched to a task
+}
+
+// User programs written in C lay it out by hand.
+const _: () = assert!(size_of::<SysInfo>() == 48);
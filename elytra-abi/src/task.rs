@@ -0,0 +1,51 @@
+//! Task information, as returned by sys_task_stats, and flags for starting tasks.
+
+/// Longest task name, in bytes. Longer names are truncated.
+pub const TASK_NAME_LEN: usize = 16;
+
+/// spawn: trace the syscalls of the new task, as sys_trace_me does.
+pub const SPAWN_TRACE: usize = 0x1;
+
+// States in TaskInfo.
+pub const STATE_RUNNING: u32 = 0;
+pub const STATE_READY: u32 = 1;
+pub const STATE_SLEEPING: u32 = 2;
+pub const STATE_BLOCKED: u32 = 3;
+pub const STATE_EXITED: u32 = 4;
+
+/// A snapshot of a task.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub state: u32,
+    pub priority: u32,
+    pub runtime_ms: u64,
+    pub voluntary_switches: u64,
+    pub preemptions: u64,
+    pub name: [u8; TASK_NAME_LEN], // Truncated, and NUL-padded
+}
+
+// The header of the C programs, tests/elytra_abi.h, lays it out by hand.
+const _: () = assert!(size_of::<TaskInfo>() == 56);
+
+impl TaskInfo {
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(TASK_NAME_LEN);
+        str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            STATE_RUNNING => "running",
+            STATE_READY => "ready",
+            STATE_SLEEPING => "sleeping",
+            STATE_BLOCKED => "blocked",
+            _ => "exited",
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Clocks, and the time structs of time syscalls.
+
+/// Time since 1970-01-01 00:00:00 UTC, from the RTC at boot.
+pub const CLOCK_REALTIME: usize = 0;
+/// Time since boot, which never goes back.
+pub const CLOCK_MONOTONIC: usize = 1;
+
+/// A point in time, or a duration.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timespec {
+    pub secs: u64,
+    pub nanos: u64, // Always below 1_000_000_000
+}
+
+const _: () = assert!(size_of::<Timespec>() == 16);
+
+impl Timespec {
+    pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+    pub fn from_nanos(nanos: u64) -> Self {
+        Timespec {
+            secs: nanos / Self::NANOS_PER_SEC,
+            nanos: nanos % Self::NANOS_PER_SEC,
+        }
+    }
+
+    /// The time in nanoseconds, saturating at u64::MAX.
+    pub fn as_nanos(&self) -> u64 {
+        self.secs
+            .saturating_mul(Self::NANOS_PER_SEC)
+            .saturating_add(self.nanos)
+    }
+}
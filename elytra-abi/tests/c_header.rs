@@ -0,0 +1,304 @@
+//! Generates tests/elytra_abi.h, the header the C test programs include, from this crate, and checks that the one in
+//! the tree is up to date. After changing the crate, regenerate it with:
+//!
+//! UPDATE_C_HEADER=1 cargo test -p elytra-abi
+//!
+//! The constants are taken from the `pub const` items of the modules, with their trailing comments. Structs are laid
+//! out by hand, and the header asserts their sizes and offsets as this crate has them, so a C program no longer
+//! compiles against a struct that changed. The syscall wrappers mirror those of the syscall module.
+
+use std::{env, fmt::Write, fs, mem::offset_of, path::Path};
+
+use elytra_abi::task::TaskInfo;
+
+// Modules whose constants the header defines, in order.
+const MODULES: [&str; 8] = ["nr", "errno", "mm", "task", "signal", "time", "power", "fb"];
+
+const PREAMBLE: &str = "\
+// The interface between the kernel and user programs, for the test programs written in C.
+//
+// Generated from the elytra-abi crate by elytra-abi/tests/c_header.rs, don't edit it by hand. After changing the
+// crate, regenerate it with: UPDATE_C_HEADER=1 cargo test -p elytra-abi
+
+#ifndef ELYTRA_ABI_H
+#define ELYTRA_ABI_H
+";
+
+const SYSCALLS: &str = "\
+// Always inlined, so that a program only has the syscall instructions it makes, even without optimizations.
+#define ABI_FN static inline __attribute__((always_inline))
+
+// Call syscall num. The syscall number goes in rax, and the arguments in rdi, rsi, rdx, r10, r8 and r9. The result
+// comes back in rax, as a negated error number if the syscall failed. The kernel clears the argument registers on
+// return, so they are all clobbered, and the SYSCALL instruction itself clobbers rcx and r11.
+ABI_FN long syscall0(long num)
+{
+    long ret;
+    __asm__ volatile(
+        \"syscall\"
+        : \"=a\"(ret)
+        : \"a\"(num)
+        : \"rcx\", \"r11\", \"rdi\", \"rsi\", \"rdx\", \"r8\", \"r9\", \"r10\", \"memory\");
+    return ret;
+}
+
+ABI_FN long syscall1(long num, long arg1)
+{
+    long ret;
+    __asm__ volatile(
+        \"syscall\"
+        : \"=a\"(ret), \"+D\"(arg1)
+        : \"a\"(num)
+        : \"rcx\", \"r11\", \"rsi\", \"rdx\", \"r8\", \"r9\", \"r10\", \"memory\");
+    return ret;
+}
+
+ABI_FN long syscall2(long num, long arg1, long arg2)
+{
+    long ret;
+    __asm__ volatile(
+        \"syscall\"
+        : \"=a\"(ret), \"+D\"(arg1), \"+S\"(arg2)
+        : \"a\"(num)
+        : \"rcx\", \"r11\", \"rdx\", \"r8\", \"r9\", \"r10\", \"memory\");
+    return ret;
+}
+
+ABI_FN long syscall3(long num, long arg1, long arg2, long arg3)
+{
+    long ret;
+    __asm__ volatile(
+        \"syscall\"
+        : \"=a\"(ret), \"+D\"(arg1), \"+S\"(arg2), \"+d\"(arg3)
+        : \"a\"(num)
+        : \"rcx\", \"r11\", \"r8\", \"r9\", \"r10\", \"memory\");
+    return ret;
+}
+
+ABI_FN long syscall4(long num, long arg1, long arg2, long arg3, long arg4)
+{
+    long ret;
+    register long r10 __asm__(\"r10\") = arg4;
+    __asm__ volatile(
+        \"syscall\"
+        : \"=a\"(ret), \"+D\"(arg1), \"+S\"(arg2), \"+d\"(arg3), \"+r\"(r10)
+        : \"a\"(num)
+        : \"rcx\", \"r11\", \"r8\", \"r9\", \"memory\");
+    return ret;
+}
+
+ABI_FN long syscall5(long num, long arg1, long arg2, long arg3, long arg4, long arg5)
+{
+    long ret;
+    register long r10 __asm__(\"r10\") = arg4;
+    register long r8 __asm__(\"r8\") = arg5;
+    __asm__ volatile(
+        \"syscall\"
+        : \"=a\"(ret), \"+D\"(arg1), \"+S\"(arg2), \"+d\"(arg3), \"+r\"(r10), \"+r\"(r8)
+        : \"a\"(num)
+        : \"rcx\", \"r11\", \"r9\", \"memory\");
+    return ret;
+}
+
+ABI_FN long syscall6(long num, long arg1, long arg2, long arg3, long arg4, long arg5, long arg6)
+{
+    long ret;
+    register long r10 __asm__(\"r10\") = arg4;
+    register long r8 __asm__(\"r8\") = arg5;
+    register long r9 __asm__(\"r9\") = arg6;
+    __asm__ volatile(
+        \"syscall\"
+        : \"=a\"(ret), \"+D\"(arg1), \"+S\"(arg2), \"+d\"(arg3), \"+r\"(r10), \"+r\"(r8), \"+r\"(r9)
+        : \"a\"(num)
+        : \"rcx\", \"r11\", \"memory\");
+    return ret;
+}
+
+// Wrappers for the syscalls the C programs make. They return the raw result.
+
+ABI_FN __attribute__((noreturn)) void sys_exit(long code)
+{
+    syscall1(SYS_EXIT, code);
+    __builtin_unreachable();
+}
+
+ABI_FN long sys_yield(void)
+{
+    return syscall0(SYS_YIELD);
+}
+
+// Move the end of the heap to addr, or just return it if addr is 0. Returns the new end.
+ABI_FN long sys_brk(void *addr)
+{
+    return syscall1(SYS_BRK, (long)addr);
+}
+
+ABI_FN long sys_write(long fd, const void *buf, long len)
+{
+    return syscall3(SYS_WRITE, fd, (long)buf, len);
+}
+
+// Write the memory map of the task to buf, truncated to len bytes. Returns its length.
+ABI_FN long sys_maps(char *buf, long len)
+{
+    return syscall2(SYS_MAPS, (long)buf, len);
+}
+
+ABI_FN long sys_sleep_ms(long ms)
+{
+    return syscall1(SYS_SLEEP_MS, ms);
+}
+
+ABI_FN long sys_set_priority(long priority)
+{
+    return syscall1(SYS_SET_PRIORITY, priority);
+}
+
+// Block until the next timer tick. Returns its number.
+ABI_FN long sys_wait_tick(void)
+{
+    return syscall0(SYS_WAIT_TICK);
+}
+
+// Start the program called name as a child, with SPAWN_* flags and a NULL-terminated argv, or none if argv is NULL.
+// Returns its id.
+ABI_FN long sys_spawn(const char *name, long len, long flags, const char *const *argv)
+{
+    return syscall4(SYS_SPAWN, (long)name, len, flags, (long)argv);
+}
+
+// Wait for the child with the id to exit, and store its exit code in status. Returns the id.
+ABI_FN long sys_wait(long id, int *status)
+{
+    return syscall2(SYS_WAIT, id, (long)status);
+}
+
+// Returns the id of the child in the parent, and 0 in the child.
+ABI_FN long sys_fork(void)
+{
+    return syscall0(SYS_FORK);
+}
+
+// Replace the program of the task with the one called name. Only returns if that failed.
+ABI_FN long sys_exec(const char *name, long len, const char *const *argv)
+{
+    return syscall3(SYS_EXEC, (long)name, len, (long)argv);
+}
+
+// Fill tasks with up to count tasks. Returns the number of tasks, even those that didn't fit.
+ABI_FN long sys_task_stats(struct task_info *tasks, long count)
+{
+    return syscall2(SYS_TASK_STATS, (long)tasks, count);
+}
+
+#endif
+";
+
+// Define every `pub const` of the module, under the first line of its doc comment.
+fn write_constants(header: &mut String, module: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("src/{module}.rs"));
+    let source = fs::read_to_string(path).unwrap();
+
+    let title = source
+        .lines()
+        .find_map(|line| line.strip_prefix("//! "))
+        .unwrap();
+    writeln!(header, "\n// {title}").unwrap();
+
+    for line in source.lines() {
+        let Some(item) = line.strip_prefix("pub const ") else {
+            continue;
+        };
+        let (item, comment) = match item.split_once(" // ") {
+            Some((item, comment)) => (item, Some(comment)),
+            None => (item, None),
+        };
+        let (name, value) = item.split_once(':').unwrap();
+        let value = value
+            .split_once('=')
+            .unwrap()
+            .1
+            .trim()
+            .trim_end_matches(';');
+        write!(header, "#define {} {}", name, value.replace('_', "")).unwrap();
+        match comment {
+            Some(comment) => writeln!(header, " // {comment}").unwrap(),
+            None => writeln!(header).unwrap(),
+        }
+    }
+}
+
+// Lay out TaskInfo, and assert its size and offsets.
+fn write_structs(header: &mut String) {
+    let fields = [
+        ("unsigned long", "id", offset_of!(TaskInfo, id)),
+        ("unsigned int", "state", offset_of!(TaskInfo, state)),
+        ("unsigned int", "priority", offset_of!(TaskInfo, priority)),
+        (
+            "unsigned long",
+            "runtime_ms",
+            offset_of!(TaskInfo, runtime_ms),
+        ),
+        (
+            "unsigned long",
+            "voluntary_switches",
+            offset_of!(TaskInfo, voluntary_switches),
+        ),
+        (
+            "unsigned long",
+            "preemptions",
+            offset_of!(TaskInfo, preemptions),
+        ),
+        ("char", "name[TASK_NAME_LEN]", offset_of!(TaskInfo, name)),
+    ];
+
+    writeln!(
+        header,
+        "\n// elytra_abi::task::TaskInfo\nstruct task_info\n{{"
+    )
+    .unwrap();
+    for (ty, name, _) in fields {
+        writeln!(header, "    {ty} {name};").unwrap();
+    }
+    writeln!(header, "}};").unwrap();
+    writeln!(
+        header,
+        "_Static_assert(sizeof(struct task_info) == {}, \"struct task_info changed\");",
+        size_of::<TaskInfo>()
+    )
+    .unwrap();
+    for (_, name, offset) in fields {
+        let name = name.split('[').next().unwrap();
+        writeln!(
+            header,
+            "_Static_assert(__builtin_offsetof(struct task_info, {name}) == {offset}, \"struct task_info changed\");"
+        )
+        .unwrap();
+    }
+}
+
+fn generate() -> String {
+    let mut header = String::from(PREAMBLE);
+    for module in MODULES {
+        write_constants(&mut header, module);
+    }
+    write_structs(&mut header);
+    header.push('\n');
+    header.push_str(SYSCALLS);
+    header
+}
+
+#[test]
+fn c_header_is_up_to_date() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/elytra_abi.h");
+    let header = generate();
+    if env::var_os("UPDATE_C_HEADER").is_some() {
+        fs::write(&path, header).unwrap();
+        return;
+    }
+    let current = fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        current == header,
+        "tests/elytra_abi.h is out of date, regenerate it with UPDATE_C_HEADER=1 cargo test -p elytra-abi"
+    );
+}
@@ -0,0 +1,20 @@
+use std::{env, fs, path::PathBuf};
+
+// Embeds the console font: the PSF1 or PSF2 file the ELYTRA_FONT environment variable names, as a path from the root
+// of the repository. Without it, the font is empty, and the console draws with its built-in font.
+fn main() {
+    println!("cargo:rerun-if-env-changed=ELYTRA_FONT");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let font_path = out_dir.join("font.psf");
+
+    match env::var_os("ELYTRA_FONT").filter(|font| !font.is_empty()) {
+        Some(font) => {
+            let root = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("..");
+            let font = root.join(font);
+            println!("cargo:rerun-if-changed={}", font.display());
+            fs::copy(&font, &font_path)
+                .unwrap_or_else(|err| panic!("Can't read the font {}: {}", font.display(), err));
+        }
+        None => fs::write(&font_path, []).unwrap(),
+    }
+}
@@ -0,0 +1,395 @@
+//! Just enough ACPI to find the interrupt controllers: the RSDP the bootloader found leads to the RSDT or XSDT, which
+//! lists the MADT. The MADT describes the I/O APICs, and the interrupt source overrides that say where the ISA IRQs
+//! go when they don't map one to one to global system interrupts (GSIs). The FADT gives the index of the century
+//! register of the RTC, if there is one, and the PM1 control blocks that put the machine to sleep. The sleep type of
+//! S5, soft off, is in the \_S5 object of the DSDT, which is AML; rather than running it, the kernel looks for the
+//! package it is defined as, the way most small kernels do.
+//!
+//! Tables are mapped through the MMIO window while they are read, as the firmware may have put them outside the memory
+//! the direct map covers.
+
+use alloc::vec::Vec;
+
+use crate::{
+    mem::{map_mmio, unmap_mmio},
+    printlnk,
+};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+// Offsets in the FADT of the address of the DSDT, of the ports of the PM1a and PM1b control blocks (0 if there is
+// none), and of the index of the century register of the RTC (0 if it has none)
+const FADT_DSDT: usize = 40;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+const FADT_CENTURY: usize = 108;
+
+// AML opcodes of the definition of \_S5: Name(_S5_, Package(n) { SLP_TYPa, SLP_TYPb, ... })
+const AML_NAME: u8 = 0x08;
+const AML_PACKAGE: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+
+// Length of the header every system description table starts with
+const SDT_HEADER_LEN: usize = 36;
+// Length of the ACPI 1.0 RSDP, which the checksum covers
+const RSDP_V1_LEN: usize = 20;
+// Length of the ACPI 2.0 RSDP, with the address of the XSDT
+const RSDP_V2_LEN: usize = 36;
+
+// Types of the MADT entries the kernel uses
+const MADT_IO_APIC: u8 = 1;
+const MADT_SOURCE_OVERRIDE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,       // The bootloader didn't find the RSDP
+    BadChecksum,  // The bytes of a table don't add up to 0
+    BadSignature, // A table isn't the one it should be
+    Truncated,    // A table or entry is shorter than its fields
+    NoTable,      // The RSDT or XSDT doesn't list the table
+}
+
+/// An I/O APIC, from the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub addr: u32,     // Physical address of its registers
+    pub gsi_base: u32, // The GSI of its first redirection entry
+}
+
+/// An interrupt source override, from the MADT: the ISA IRQ source is GSI gsi, with the polarity and trigger mode of
+/// the flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl SourceOverride {
+    const POLARITY: u16 = 0b11;
+    const TRIGGER: u16 = 0b11 << 2;
+
+    /// Whether the interrupt is active low, or None if it conforms to the bus (active high for ISA).
+    pub fn active_low(&self) -> Option<bool> {
+        match self.flags & Self::POLARITY {
+            0b01 => Some(false),
+            0b11 => Some(true),
+            _ => None,
+        }
+    }
+
+    /// Whether the interrupt is level triggered, or None if it conforms to the bus (edge triggered for ISA).
+    pub fn level_triggered(&self) -> Option<bool> {
+        match (self.flags & Self::TRIGGER) >> 2 {
+            0b01 => Some(false),
+            0b11 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// What the kernel takes from the MADT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_addr: u32,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<SourceOverride>,
+}
+
+impl Madt {
+    /// The override of the ISA IRQ, if it has one.
+    pub fn source_override(&self, irq: u8) -> Option<&SourceOverride> {
+        self.overrides.iter().find(|entry| entry.source == irq)
+    }
+}
+
+/// What the kernel needs to enter S5, soft off: write SLP_TYPa with SLP_EN to the PM1a control block, and SLP_TYPb
+/// to the PM1b one if there is one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoftOff {
+    pub pm1a_control: u16,
+    pub pm1b_control: Option<u16>,
+    pub sleep_type_a: u8,
+    pub sleep_type_b: u8,
+}
+
+// The MADT, once init() found it.
+static mut MADT: Option<Madt> = None;
+
+/// The MADT, or None if init() didn't find one.
+pub fn madt() -> Option<&'static Madt> {
+    unsafe { MADT.as_ref() }
+}
+
+// The index of the century register of the RTC, once init() found it in the FADT.
+static mut CENTURY_REGISTER: Option<u8> = None;
+
+/// The index of the century register of the RTC, or None if the FADT doesn't give one.
+pub fn century_register() -> Option<u8> {
+    unsafe { CENTURY_REGISTER }
+}
+
+// How to enter S5, once init() found it in the FADT and the DSDT.
+static mut SOFT_OFF: Option<SoftOff> = None;
+
+/// How to enter S5, or None if the FADT or the DSDT doesn't say.
+pub fn soft_off() -> Option<SoftOff> {
+    unsafe { SOFT_OFF }
+}
+
+/// Whether the bytes add up to 0, as every ACPI table does.
+pub fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Get the index of the century register of the RTC from a FADT, header included, or None if it has none.
+pub fn parse_fadt_century(table: &[u8]) -> Result<Option<u8>, AcpiError> {
+    if table.len() < SDT_HEADER_LEN {
+        return Err(AcpiError::Truncated);
+    }
+    if &table[..4] != FADT_SIGNATURE {
+        return Err(AcpiError::BadSignature);
+    }
+    // ACPI 1.0 FADTs may end before the field
+    Ok(table.get(FADT_CENTURY).copied().filter(|&index| index != 0))
+}
+
+/// Get the address of the DSDT and the ports of the PM1a and PM1b control blocks from a FADT, header included. Ports
+/// of 0 mean there is no block. The FADT is checked as parse_fadt_century() does.
+pub fn parse_fadt_pm1(table: &[u8]) -> Result<(usize, u16, u16), AcpiError> {
+    parse_fadt_century(table)?;
+    if table.len() < FADT_PM1B_CONTROL + 4 {
+        return Err(AcpiError::Truncated);
+    }
+    // The ports are 32-bit fields, but the I/O space is 16-bit
+    Ok((
+        read_u32(table, FADT_DSDT) as usize,
+        read_u32(table, FADT_PM1A_CONTROL) as u16,
+        read_u32(table, FADT_PM1B_CONTROL) as u16,
+    ))
+}
+
+/// Find the sleep types of S5, SLP_TYPa and SLP_TYPb, in the AML of a DSDT, or None if it doesn't define \_S5 as a
+/// package of at least two integers.
+pub fn parse_s5(dsdt: &[u8]) -> Option<(u8, u8)> {
+    let name = dsdt.windows(4).enumerate().find_map(|(i, window)| {
+        // The name is defined at the root, with or without the root prefix
+        let defined = i >= 1 && dsdt[i - 1] == AML_NAME
+            || i >= 2 && dsdt[i - 2] == AML_NAME && dsdt[i - 1] == b'\\';
+        (window == b"_S5_" && defined).then_some(i + 4)
+    })?;
+
+    let mut aml = dsdt.get(name..)?;
+    if *aml.first()? != AML_PACKAGE {
+        return None;
+    }
+    // The package length takes 1 to 4 bytes, as the top bits of the first say, then comes the number of elements
+    let length_bytes = (*aml.get(1)? >> 6) as usize + 1;
+    let elements = *aml.get(1 + length_bytes)?;
+    if elements < 2 {
+        return None;
+    }
+    aml = &aml[2 + length_bytes..];
+
+    let mut integer = || {
+        let (value, len) = match *aml.first()? {
+            AML_BYTE_PREFIX => (*aml.get(1)?, 2),
+            value @ (AML_ZERO | AML_ONE) => (value, 1),
+            _ => return None,
+        };
+        aml = &aml[len..];
+        Some(value)
+    };
+    Some((integer()?, integer()?))
+}
+
+/// Parse a MADT, header included.
+pub fn parse_madt(table: &[u8]) -> Result<Madt, AcpiError> {
+    if table.len() < SDT_HEADER_LEN + 8 {
+        return Err(AcpiError::Truncated);
+    }
+    if &table[..4] != MADT_SIGNATURE {
+        return Err(AcpiError::BadSignature);
+    }
+    if !checksum_ok(table) {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    let mut madt = Madt {
+        local_apic_addr: read_u32(table, SDT_HEADER_LEN),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut entries = &table[SDT_HEADER_LEN + 8..];
+    while !entries.is_empty() {
+        let [kind, len, ..] = *entries else {
+            return Err(AcpiError::Truncated);
+        };
+        let len = len as usize;
+        if len < 2 || len > entries.len() {
+            return Err(AcpiError::Truncated);
+        }
+        let entry = &entries[..len];
+
+        match kind {
+            MADT_IO_APIC if len >= 12 => madt.io_apics.push(IoApicEntry {
+                id: entry[2],
+                addr: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            }),
+            MADT_SOURCE_OVERRIDE if len >= 10 => madt.overrides.push(SourceOverride {
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: u16::from_le_bytes([entry[8], entry[9]]),
+            }),
+            MADT_IO_APIC | MADT_SOURCE_OVERRIDE => return Err(AcpiError::Truncated),
+            _ => {}
+        }
+        entries = &entries[len..];
+    }
+    Ok(madt)
+}
+
+/// Find the MADT and the FADT from the RSDP the bootloader found, and keep what the kernel uses of them.
+///
+/// # Safety
+/// Must be called once, after the MMIO window is up.
+pub unsafe fn init(rsdp_addr: Option<u64>) {
+    match unsafe { with_table(rsdp_addr, MADT_SIGNATURE, parse_madt) } {
+        Ok(madt) => {
+            printlnk!(
+                "MADT: {} I/O APICs, {} interrupt source overrides",
+                madt.io_apics.len(),
+                madt.overrides.len()
+            );
+            unsafe { MADT = Some(madt) };
+        }
+        Err(err) => printlnk!("No MADT: {:?}", err),
+    }
+
+    match unsafe { with_table(rsdp_addr, FADT_SIGNATURE, parse_fadt_century) } {
+        Ok(century) => unsafe { CENTURY_REGISTER = century },
+        Err(err) => printlnk!("No FADT: {:?}", err),
+    }
+
+    match unsafe { find_soft_off(rsdp_addr) } {
+        Ok(soft_off) => unsafe { SOFT_OFF = Some(soft_off) },
+        Err(err) => printlnk!("No ACPI power off: {:?}", err),
+    }
+}
+
+// Read how to enter S5 from the FADT and the DSDT it points to.
+unsafe fn find_soft_off(rsdp_addr: Option<u64>) -> Result<SoftOff, AcpiError> {
+    let (dsdt_addr, pm1a_control, pm1b_control) =
+        unsafe { with_table(rsdp_addr, FADT_SIGNATURE, parse_fadt_pm1) }?;
+    if pm1a_control == 0 || dsdt_addr == 0 {
+        return Err(AcpiError::NoTable);
+    }
+
+    let dsdt = unsafe { map_table(dsdt_addr) }?;
+    let s5 = parse_s5(&dsdt[SDT_HEADER_LEN..]);
+    unsafe { unmap(dsdt) };
+    let (sleep_type_a, sleep_type_b) = s5.ok_or(AcpiError::NoTable)?;
+
+    Ok(SoftOff {
+        pm1a_control,
+        pm1b_control: (pm1b_control != 0).then_some(pm1b_control),
+        sleep_type_a,
+        sleep_type_b,
+    })
+}
+
+// Find the table with the signature through the RSDT or XSDT, and call f with it while it is mapped.
+unsafe fn with_table<R>(
+    rsdp_addr: Option<u64>,
+    signature: &[u8; 4],
+    f: impl FnOnce(&[u8]) -> Result<R, AcpiError>,
+) -> Result<R, AcpiError> {
+    let rsdp_addr = rsdp_addr.ok_or(AcpiError::NoRsdp)? as usize;
+    let rsdp = unsafe { map(rsdp_addr, RSDP_V2_LEN) };
+    let root = rsdp_root(rsdp);
+    unsafe { unmap(rsdp) };
+    let (root_addr, entry_len) = root?;
+
+    let root = unsafe { map_table(root_addr) }?;
+    let table_addr = root[SDT_HEADER_LEN..]
+        .chunks_exact(entry_len)
+        .map(|entry| match entry_len {
+            8 => u64::from_le_bytes(entry.try_into().unwrap()) as usize,
+            _ => read_u32(entry, 0) as usize,
+        })
+        .find(|&addr| {
+            let header = unsafe { map(addr, SDT_HEADER_LEN) };
+            let found = &header[..4] == signature;
+            unsafe { unmap(header) };
+            found
+        });
+    unsafe { unmap(root) };
+
+    let table = unsafe { map_table(table_addr.ok_or(AcpiError::NoTable)?) }?;
+    let result = f(table);
+    unsafe { unmap(table) };
+    result
+}
+
+// The address of the RSDT or XSDT, and the length of its entries.
+fn rsdp_root(rsdp: &[u8]) -> Result<(usize, usize), AcpiError> {
+    if &rsdp[..8] != RSDP_SIGNATURE {
+        return Err(AcpiError::BadSignature);
+    }
+    if !checksum_ok(&rsdp[..RSDP_V1_LEN]) {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    // ACPI 2.0 and later have an XSDT, with 64-bit addresses
+    if rsdp[15] >= 2 {
+        if !checksum_ok(rsdp) {
+            return Err(AcpiError::BadChecksum);
+        }
+        Ok((
+            u64::from_le_bytes(rsdp[24..32].try_into().unwrap()) as usize,
+            8,
+        ))
+    } else {
+        Ok((read_u32(rsdp, 16) as usize, 4))
+    }
+}
+
+// Map a table, as long as its header says it is, and check its checksum.
+unsafe fn map_table(addr: usize) -> Result<&'static [u8], AcpiError> {
+    let header = unsafe { map(addr, SDT_HEADER_LEN) };
+    let len = read_u32(header, 4) as usize;
+    unsafe { unmap(header) };
+    if len < SDT_HEADER_LEN {
+        return Err(AcpiError::Truncated);
+    }
+
+    let table = unsafe { map(addr, len) };
+    if !checksum_ok(table) {
+        unsafe { unmap(table) };
+        return Err(AcpiError::BadChecksum);
+    }
+    Ok(table)
+}
+
+// Map len bytes of physical memory.
+unsafe fn map(addr: usize, len: usize) -> &'static [u8] {
+    let ptr = unsafe { map_mmio(addr, len) };
+    assert!(!ptr.is_null(), "Failed to map an ACPI table");
+    unsafe { core::slice::from_raw_parts(ptr, len) }
+}
+
+// Unmap what map() mapped.
+unsafe fn unmap(bytes: &[u8]) {
+    unsafe { unmap_mmio(bytes.as_ptr() as *mut u8, bytes.len()) };
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
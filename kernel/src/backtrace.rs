@@ -0,0 +1,176 @@
+//! Backtraces of the kernel stack, printed when the kernel panics or runs into an exception.
+//!
+//! The kernel is built with frame pointers (see the profiles in the root Cargo.toml), so each function saves the frame
+//! pointer of its caller at [rbp], and has its return address at [rbp + 8]. walk() follows that chain up the stack.
+//! The chain may be broken by the very bug that is being reported, so every frame pointer is checked before it is
+//! read, and the walk ends at the first one that doesn't look right, rather than faulting.
+//!
+//! Return addresses are named with the function symbols of the kernel's own ELF file, which the bootloader leaves in
+//! memory.
+
+use core::{arch::asm, fmt, slice};
+
+use bootloader_api::BootInfo;
+
+use crate::{
+    consts::{KERNEL_OFFSET, USERSPACE_LIMIT},
+    helper::p2v,
+    mem::paging::{get_active_page_directory, resolve_virt_addr},
+    printlnk,
+    user::elf_parser::{ElfParser, SymbolTable},
+};
+
+/// Frames walked at most.
+pub const MAX_DEPTH: usize = 32;
+
+// Largest distance between two frame pointers of a chain. No frame is larger than a stack.
+const MAX_FRAME_SIZE: usize = 0x10000;
+
+// Function symbols of the kernel, offset by where it is loaded. None until init(), or if they couldn't be read.
+static mut KERNEL_SYMBOLS: Option<SymbolTable> = None;
+
+/// Read the function symbols of the kernel, from the ELF file the bootloader loaded it from.
+///
+/// # Safety
+/// Must be called once, after the heap is set up and before anything runs that may print a backtrace concurrently.
+pub unsafe fn init(boot_info: &BootInfo) {
+    let file = unsafe {
+        slice::from_raw_parts(
+            p2v(boot_info.kernel_addr as usize) as *const u8,
+            boot_info.kernel_len as usize,
+        )
+    };
+
+    match ElfParser::parse(file) {
+        Ok(parser) => {
+            let symbols = parser.symbol_table(boot_info.kernel_image_offset as usize);
+            unsafe { KERNEL_SYMBOLS = Some(symbols) };
+        }
+        Err(err) => printlnk!("Backtraces will not be symbolized: {:?}", err),
+    }
+}
+
+/// Find the kernel function containing addr, and the offset of addr within it. The name is mangled.
+pub fn resolve_symbol(addr: usize) -> Option<(&'static str, usize)> {
+    unsafe { KERNEL_SYMBOLS.as_ref() }?.resolve_symbol(addr)
+}
+
+/// Call f with the return addresses on the kernel stack, innermost first, by following the chain of frame pointers
+/// up from the caller. Addresses outside the kernel image (e.g. the error code, in the frame of an exception handler)
+/// are skipped.
+#[inline(never)]
+pub fn walk(f: impl FnMut(usize)) {
+    let rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    walk_from(rbp, f);
+}
+
+/// Call f with the return addresses of the chain of frames starting at rbp, as walk() does.
+pub fn walk_from(mut rbp: usize, mut f: impl FnMut(usize)) {
+    let page_directory = unsafe { get_active_page_directory() };
+    let mapped = |addr: usize| unsafe { resolve_virt_addr(page_directory, addr) }.is_some();
+
+    for _ in 0..MAX_DEPTH {
+        // The frame must be on a kernel stack, and both of its words readable
+        if rbp < USERSPACE_LIMIT
+            || !rbp.is_multiple_of(8)
+            || !mapped(rbp)
+            || !mapped(rbp.wrapping_add(8))
+        {
+            break;
+        }
+
+        let (next, ret) = unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+        if ret >= KERNEL_OFFSET {
+            f(ret);
+        }
+
+        // Stacks grow down, so the frame of the caller is above, and on the same stack
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Print a backtrace of the kernel stack, from the caller up.
+pub fn print() {
+    printlnk!("Backtrace:");
+    walk(|ret| printlnk!("  {}", Symbolized(ret)));
+}
+
+/// Formats a kernel address, followed by the function containing it if it is known, e.g.
+/// "0xffffffff80012345 (<kernel::test::test+0x45>)".
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match resolve_symbol(self.0) {
+            Some((name, offset)) => {
+                write!(f, "{:#x} (<{}+{:#x}>)", self.0, Demangled(name), offset)
+            }
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// Formats a mangled symbol name as a Rust path, e.g. "_RNvNtCs1a2b3c_6kernel4test4test" as "kernel::test::test".
+/// Only plain paths are demangled, with closures as {closure}. Other names (generic instances, trait methods, and
+/// anything not mangled by Rust) are written as they are.
+pub struct Demangled<'a>(pub &'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.0.strip_prefix("_R");
+        // Parse once without writing anything, so nothing is written for a name that turns out not to be a plain path
+        if path
+            .and_then(|path| demangle_path(path, &mut |_| Ok(())))
+            .is_none()
+        {
+            return f.write_str(self.0);
+        }
+
+        // It parsed the first time, so it can only fail to write
+        demangle_path(path.unwrap(), &mut |part| f.write_str(part))
+            .map(|_| ())
+            .ok_or(fmt::Error)
+    }
+}
+
+// Write the path at the start of s, and return what follows it (e.g. the crate that instantiated it).
+fn demangle_path<'a>(s: &'a str, out: &mut dyn FnMut(&str) -> fmt::Result) -> Option<&'a str> {
+    match s.as_bytes().first()? {
+        // Crate root
+        b'C' => {
+            let (name, rest) = identifier(&s[1..])?;
+            out(name).ok()?;
+            Some(rest)
+        }
+        // Nested path: namespace, parent path, name
+        b'N' => {
+            let namespace = *s.as_bytes().get(1)?;
+            let rest = demangle_path(s.get(2..)?, out)?;
+            let (name, rest) = identifier(rest)?;
+            out("::").ok()?;
+            out(if namespace == b'C' { "{closure}" } else { name }).ok()?;
+            Some(rest)
+        }
+        _ => None,
+    }
+}
+
+// Split the identifier at the start of s from the rest: an optional disambiguator ("s", a base-62 number and "_"), the
+// length of the name, and the name, separated from the length by "_" if it starts with a digit or "_".
+fn identifier(s: &str) -> Option<(&str, &str)> {
+    let s = match s.strip_prefix('s') {
+        Some(rest) => &rest[rest.find('_')? + 1..],
+        None => s,
+    };
+
+    // Punycode names start with "u" rather than their length, so they aren't demangled
+    let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+    let len: usize = s[..digits].parse().ok()?;
+    let s = &s[digits..];
+    let s = s.strip_prefix('_').unwrap_or(s);
+    Some((s.get(..len)?, s.get(len..)?))
+}
@@ -0,0 +1,157 @@
+//! The disk, for reading sectors.
+//!
+//! init() takes the first virtio-blk device PCI found, if any, and routes its interrupt to the vector of its IRQ line
+//! on the PIC. read_sectors() splits a read into requests of at most a page, and runs them one at a time: it waits for
+//! the disk to be free, hands it the request, and waits for the interrupt that says it is done. A task blocks on the
+//! wait queue meanwhile. Without a task, e.g. in the tests or at boot, the CPU halts until an interrupt instead; the
+//! timer raises one every tick, so a lost interrupt only costs time.
+
+pub mod virtio_blk;
+
+use core::arch::asm;
+
+use crate::{
+    idt::{self, PIC_OFFSET, without_interrupt},
+    ioapic,
+    irq::{self, IrqContext},
+    log_warn, pci, printlnk,
+    user::{
+        sched::{self, wait_queue::WaitQueue},
+        signal,
+    },
+};
+use virtio_blk::{MAX_REQUEST_SECTORS, VirtioBlk};
+
+/// Size of a sector, in bytes.
+pub const SECTOR_SIZE: usize = 512;
+
+// Number of IRQ lines of the PIC. A device on a higher line has to be polled.
+const PIC_IRQS: u8 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    NoDisk,
+    OutOfRange,  // Past the end of the disk
+    SmallBuffer, // Shorter than the sectors
+    Io,          // The disk failed the request
+}
+
+// The disk, once init() found it. Only used with interrupts disabled.
+static mut DISK: Option<VirtioBlk> = None;
+// Whether a request is in flight.
+static mut BUSY: bool = false;
+// Whether the interrupt of the disk is routed, so tasks can wait for it.
+static mut INTERRUPTS: bool = false;
+
+/// Tasks waiting for the disk to be free, or for their request to complete. Woken up by the interrupt of the disk,
+/// and whenever a request finishes.
+pub static mut WAITERS: WaitQueue = WaitQueue::new();
+
+/// Set up the first virtio-blk device, and route its interrupt.
+///
+/// # Safety
+/// Must be called once, with interrupts disabled, after pci::init() and ioapic::init().
+pub unsafe fn init() {
+    let Some(device) = pci::find_by_id(virtio_blk::VENDOR, virtio_blk::DEVICE).next() else {
+        printlnk!("No disk");
+        return;
+    };
+    let Some(disk) = (unsafe { VirtioBlk::new(device) }) else {
+        log_warn!(
+            "Failed to set up the virtio-blk device at {}",
+            device.address
+        );
+        return;
+    };
+    printlnk!(
+        "Disk {}: {} sectors{}",
+        device.address,
+        disk.capacity(),
+        if disk.read_only() { ", read only" } else { "" }
+    );
+
+    let line = device.interrupt_line;
+    if device.interrupt_pin != 0 && line < PIC_IRQS {
+        let vector = PIC_OFFSET as usize + line as usize;
+        irq::register_handler(vector, interrupt_handler).unwrap();
+        if ioapic::enabled() {
+            ioapic::route_isa_irq(line, vector).unwrap();
+        } else {
+            idt::unmask_pic_irq(line);
+        }
+        unsafe { INTERRUPTS = true };
+    }
+    unsafe { DISK = Some(disk) };
+}
+
+/// Size of the disk in sectors, or None without one.
+pub fn capacity() -> Option<u64> {
+    without_interrupt(|| unsafe { DISK.as_ref().map(VirtioBlk::capacity) })
+}
+
+/// Read count sectors from lba on into the start of buf.
+pub fn read_sectors(lba: u64, count: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+    let capacity = capacity().ok_or(BlockError::NoDisk)?;
+    if lba
+        .checked_add(count as u64)
+        .is_none_or(|end| end > capacity)
+    {
+        return Err(BlockError::OutOfRange);
+    }
+    let len = count
+        .checked_mul(SECTOR_SIZE)
+        .ok_or(BlockError::OutOfRange)?;
+    if buf.len() < len {
+        return Err(BlockError::SmallBuffer);
+    }
+
+    for (i, chunk) in buf[..len]
+        .chunks_mut(MAX_REQUEST_SECTORS * SECTOR_SIZE)
+        .enumerate()
+    {
+        let sector = lba + (i * MAX_REQUEST_SECTORS) as u64;
+        read_request(sector, chunk)?;
+    }
+    Ok(())
+}
+
+// Run one request, of the sectors that fill buf.
+fn read_request(sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    without_interrupt(|| unsafe {
+        wait_for(|| !BUSY);
+        BUSY = true;
+
+        let disk = DISK.as_mut().unwrap();
+        disk.start_read(sector, buf.len() / SECTOR_SIZE);
+        wait_for(|| DISK.as_ref().unwrap().read_done());
+        let result = DISK.as_mut().unwrap().finish_read(buf);
+
+        BUSY = false;
+        WAITERS.wake_all();
+        result
+    })
+}
+
+// Wait until done returns true. Interrupts must be disabled, so none comes between the check and the wait. Reads
+// aren't cut short by signals; a task with one pending, which wait() returns to right away, yields instead.
+fn wait_for(mut done: impl FnMut() -> bool) {
+    while !done() {
+        if unsafe { INTERRUPTS && sched::CURRENT_TASK.is_some() } {
+            if signal::pending() {
+                unsafe { sched::yield_task() };
+            } else {
+                unsafe { WAITERS.wait() };
+            }
+        } else {
+            // sti only takes effect after hlt starts, so an interrupt can't slip in before it
+            unsafe { asm!("sti", "hlt", "cli", options(nomem, nostack)) };
+        }
+    }
+}
+
+fn interrupt_handler(_: &mut IrqContext) {
+    let disk = unsafe { DISK.as_ref() };
+    if disk.is_some_and(VirtioBlk::acknowledge_interrupt) {
+        unsafe { WAITERS.wake_all() };
+    }
+}
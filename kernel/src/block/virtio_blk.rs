@@ -0,0 +1,264 @@
+//! Legacy virtio-blk, the disk of QEMU's -device virtio-blk-pci, for reading.
+//!
+//! The legacy interface is a set of registers in I/O BAR 0. The driver resets the device, accepts none of its
+//! features, and gives it one virtqueue, in physically contiguous pages as the legacy interface wants it: the
+//! descriptor table and the available ring, then the used ring from the next page on. Two more pages hold the request
+//! header and status byte, and the data, which is copied out to the caller.
+//!
+//! A read is a chain of three descriptors: the header, which the device reads, then the data and the status byte,
+//! which it writes. Only one request is in flight at a time, so the chain is always descriptors 0 to 2. The device
+//! puts the chain on the used ring when it is done, and raises its interrupt.
+
+use core::{
+    mem::size_of,
+    ptr,
+    sync::atomic::{Ordering, fence},
+};
+
+use super::{BlockError, SECTOR_SIZE};
+use crate::{
+    consts::PAGE_SIZE,
+    helper::{align_up, v2p},
+    io::port::{Port, PortValue},
+    mem::buddy,
+    pci::{self, Bar, PciDevice},
+};
+
+/// The IDs of a transitional virtio-blk device, which has the legacy interface.
+pub const VENDOR: u16 = 0x1af4;
+pub const DEVICE: u16 = 0x1001;
+
+/// Sectors one request reads, the size of the data page.
+pub const MAX_REQUEST_SECTORS: usize = PAGE_SIZE / SECTOR_SIZE;
+
+// Registers, as offsets from BAR 0
+const DEVICE_FEATURES: u16 = 0x00;
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08; // Page number of the virtqueue
+const QUEUE_SIZE: u16 = 0x0c;
+const QUEUE_SELECT: u16 = 0x0e;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13; // Cleared by reading it
+const CAPACITY: u16 = 0x14; // In sectors, 64 bits
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FAILED: u8 = 1 << 7;
+
+const ISR_QUEUE: u8 = 1 << 0; // The used ring changed
+
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+
+// Descriptor flags
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1; // The device writes the buffer
+
+const REQUEST_READ: u32 = 0;
+const REQUEST_STATUS_OK: u8 = 0;
+
+// Size of each part of the virtqueue
+const DESCRIPTOR_SIZE: usize = 16;
+const RING_HEADER_SIZE: usize = 4; // Flags and index, then the ring
+const USED_ELEMENT_SIZE: usize = 8;
+
+// The request page: the header, then the status byte
+const STATUS_OFFSET: usize = 16;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Bytes the virtqueue of queue_size descriptors takes up.
+pub fn queue_bytes(queue_size: usize) -> usize {
+    let used = RING_HEADER_SIZE + USED_ELEMENT_SIZE * queue_size + 2; // Ends with the available event index
+    used_ring_offset(queue_size) + align_up(used, PAGE_SIZE)
+}
+
+// Where the used ring starts: on the page after the descriptor table and the available ring, which ends with the used
+// event index.
+fn used_ring_offset(queue_size: usize) -> usize {
+    align_up(
+        DESCRIPTOR_SIZE * queue_size + RING_HEADER_SIZE + 2 * queue_size + 2,
+        PAGE_SIZE,
+    )
+}
+
+/// A virtio-blk device, set up with its virtqueue.
+#[derive(Debug)]
+pub struct VirtioBlk {
+    io_base: u16,
+    queue_size: u16,
+    memory: *mut u8, // The virtqueue, then the request page and the data page
+    used_index: u16, // Of the next used element to come
+    capacity: u64,
+    read_only: bool,
+}
+
+impl VirtioBlk {
+    /// Set up the device. Returns None if its BAR 0 isn't an I/O BAR, or the device fails.
+    ///
+    /// # Safety
+    /// Must be called once per device, before it is used.
+    pub unsafe fn new(device: &PciDevice) -> Option<Self> {
+        let Some(Bar::Io { port, .. }) = device.bars[0] else {
+            return None;
+        };
+        unsafe { pci::enable(device.address) };
+
+        let mut disk = VirtioBlk {
+            io_base: port,
+            queue_size: 0,
+            memory: ptr::null_mut(),
+            used_index: 0,
+            capacity: 0,
+            read_only: false,
+        };
+        disk.write(DEVICE_STATUS, 0u8); // Reset
+        disk.write(DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        disk.write(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        disk.read_only = disk.read::<u32>(DEVICE_FEATURES) & FEATURE_READ_ONLY != 0;
+        disk.write(GUEST_FEATURES, 0u32);
+
+        disk.write(QUEUE_SELECT, 0u16);
+        disk.queue_size = disk.read(QUEUE_SIZE);
+        if disk.queue_size < 3 {
+            disk.write(DEVICE_STATUS, STATUS_FAILED);
+            return None;
+        }
+        let pages = queue_bytes(disk.queue_size as usize) / PAGE_SIZE + 2;
+        disk.memory = unsafe { buddy::alloc_pages_panic(pages) };
+        unsafe { ptr::write_bytes(disk.memory, 0, pages * PAGE_SIZE) };
+        disk.write(
+            QUEUE_ADDRESS,
+            (v2p(disk.memory as usize) / PAGE_SIZE) as u32,
+        );
+
+        disk.capacity =
+            disk.read::<u32>(CAPACITY) as u64 | (disk.read::<u32>(CAPACITY + 4) as u64) << 32;
+        disk.write(
+            DEVICE_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+        Some(disk)
+    }
+
+    /// Size of the disk, in sectors.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Hand the device a read of count sectors from sector on, at most MAX_REQUEST_SECTORS. The request must be
+    /// finished with finish_read() before the next one starts.
+    pub fn start_read(&mut self, sector: u64, count: usize) {
+        assert!(count <= MAX_REQUEST_SECTORS);
+        let request = self.request_page();
+        let data = self.data_page();
+        unsafe {
+            ptr::write_volatile(
+                request as *mut RequestHeader,
+                RequestHeader {
+                    kind: REQUEST_READ,
+                    reserved: 0,
+                    sector,
+                },
+            );
+            ptr::write_volatile(request.add(STATUS_OFFSET), 0xff);
+
+            let chain = [
+                (request, size_of::<RequestHeader>(), 0),
+                (data, count * SECTOR_SIZE, DESCRIPTOR_WRITE),
+                (request.add(STATUS_OFFSET), 1, DESCRIPTOR_WRITE),
+            ];
+            let descriptors = self.memory as *mut Descriptor;
+            for (i, &(buffer, len, flags)) in chain.iter().enumerate() {
+                let last = i == chain.len() - 1;
+                ptr::write_volatile(
+                    descriptors.add(i),
+                    Descriptor {
+                        addr: v2p(buffer as usize) as u64,
+                        len: len as u32,
+                        flags: flags | if last { 0 } else { DESCRIPTOR_NEXT },
+                        next: if last { 0 } else { i as u16 + 1 },
+                    },
+                );
+            }
+
+            // Put the head of the chain on the available ring, then move its index past it
+            let available = self.available_ring();
+            let index = ptr::read_volatile(available.add(1));
+            ptr::write_volatile(available.add(2 + (index % self.queue_size) as usize), 0);
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(available.add(1), index.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+        self.write(QUEUE_NOTIFY, 0u16);
+    }
+
+    /// Whether the device finished the request.
+    pub fn read_done(&self) -> bool {
+        let index = unsafe { ptr::read_volatile(self.used_ring().add(1)) };
+        fence(Ordering::SeqCst);
+        index != self.used_index
+    }
+
+    /// Copy the sectors the finished request read into buf.
+    pub fn finish_read(&mut self, buf: &mut [u8]) -> Result<(), BlockError> {
+        assert!(self.read_done());
+        self.used_index = self.used_index.wrapping_add(1);
+        let status = unsafe { ptr::read_volatile(self.request_page().add(STATUS_OFFSET)) };
+        if status != REQUEST_STATUS_OK {
+            return Err(BlockError::Io);
+        }
+        unsafe { ptr::copy_nonoverlapping(self.data_page(), buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Acknowledge the interrupt of the device. Returns whether it was for the used ring.
+    pub fn acknowledge_interrupt(&self) -> bool {
+        self.read::<u8>(ISR_STATUS) & ISR_QUEUE != 0
+    }
+
+    // The available ring, as u16: the flags, the index, then the ring.
+    fn available_ring(&self) -> *mut u16 {
+        unsafe { self.memory.add(DESCRIPTOR_SIZE * self.queue_size as usize) as *mut u16 }
+    }
+
+    // The used ring, as u16: the flags, the index, then the ring.
+    fn used_ring(&self) -> *mut u16 {
+        unsafe { self.memory.add(used_ring_offset(self.queue_size as usize)) as *mut u16 }
+    }
+
+    fn request_page(&self) -> *mut u8 {
+        unsafe { self.memory.add(queue_bytes(self.queue_size as usize)) }
+    }
+
+    fn data_page(&self) -> *mut u8 {
+        unsafe { self.request_page().add(PAGE_SIZE) }
+    }
+
+    fn read<T: PortValue>(&self, register: u16) -> T {
+        unsafe { Port::new(self.io_base + register) }.read()
+    }
+
+    fn write<T: PortValue>(&self, register: u16, value: T) {
+        unsafe { Port::new(self.io_base + register) }.write(value)
+    }
+}
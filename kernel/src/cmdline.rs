@@ -0,0 +1,58 @@
+//! Kernel command line.
+//!
+//! The bootloader doesn't pass a command line, so it is given when building the kernel, in the KERNEL_CMDLINE
+//! environment variable. Options are separated by spaces, and are either a name or name=value.
+//!
+//! Under QEMU, more options can come from the fw_cfg file opt/elytra/cmdline, which load_fw_cfg() reads once fw_cfg is
+//! up. They go after the built-in ones, so they win. Options read before then, e.g. console and loglevel, only come
+//! from the built-in command line.
+
+use alloc::string::String;
+
+use crate::{io::fwcfg, printlnk};
+
+/// The fw_cfg file with the options to add to the command line.
+pub const FW_CFG_CMDLINE: &str = "opt/elytra/cmdline";
+
+/// The command line the kernel was built with.
+pub const CMDLINE: &str = match option_env!("KERNEL_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+// The options load_fw_cfg() read
+static mut FW_CFG_OPTIONS: &str = "";
+
+/// Add the options in the fw_cfg file FW_CFG_CMDLINE, if there is one.
+///
+/// # Safety
+/// Must be called once, after fwcfg::init(), before any other task runs.
+pub unsafe fn load_fw_cfg() {
+    let Some(options) = fwcfg::read_file(FW_CFG_CMDLINE) else {
+        return;
+    };
+    let options = String::from_utf8_lossy(&options).into_owned().leak();
+    printlnk!("Command line from fw_cfg: {}", options.trim());
+    unsafe { FW_CFG_OPTIONS = options };
+}
+
+/// The options that came from fw_cfg, or "" if none did.
+pub fn fw_cfg_options() -> &'static str {
+    unsafe { FW_CFG_OPTIONS }
+}
+
+/// Find the value of the option with the given name in cmdline. Options without a value have an empty one.
+/// If an option is given more than once, the last one wins.
+pub fn find_option<'a>(cmdline: &'a str, name: &str) -> Option<&'a str> {
+    cmdline
+        .split_ascii_whitespace()
+        .rev()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+        .find(|&(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+/// Find the value of the option with the given name in the kernel command line, and the options from fw_cfg.
+pub fn option(name: &str) -> Option<&'static str> {
+    find_option(fw_cfg_options(), name).or_else(|| find_option(CMDLINE, name))
+}
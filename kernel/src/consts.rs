@@ -8,3 +8,19 @@ pub const PHYS_MEM_OFFSET: usize = 0xffff800000000000;
 pub const KERNEL_OFFSET: usize = 0xffffffff80000000;
 
 pub const PAGE_SIZE: usize = 4096;
+
+/// Size of a huge page mapped by a P2 entry (2 MiB).
+pub const HUGE_PAGE_SIZE: usize = 512 * PAGE_SIZE;
+
+/// Size of a giant page mapped by a P3 entry (1 GiB).
+pub const GIANT_PAGE_SIZE: usize = 512 * HUGE_PAGE_SIZE;
+
+/// Start of the range where the bootloader places its own mappings (boot info, framebuffer, etc.).
+/// It is kept away from the direct mapping, so the direct mapping can be rebuilt after boot.
+pub const BOOTLOADER_DYNAMIC_START: usize = 0xffffa00000000000;
+
+/// Base address of the kernel virtual window used for MMIO mappings
+pub const MMIO_BASE: usize = 0xffffc00000000000;
+
+/// Size of the MMIO window
+pub const MMIO_SIZE: usize = 256 * 1024 * 1024;
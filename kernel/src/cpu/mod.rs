@@ -0,0 +1,75 @@
+//! Features of the CPU the kernel picks between, and the registers that go with them.
+//!
+//! CPUID is slow, and traps to the hypervisor in a VM, so init() asks it once and features() hands out the answers.
+
+use core::arch::{asm, x86_64::__cpuid_count};
+
+use crate::{
+    msr::{IA32_TSC_AUX, write_msr},
+    user::sched::cpu::this_cpu_id,
+};
+
+pub mod segment_base;
+pub mod tsc;
+
+const CPUID_7_EBX_FSGSBASE: u32 = 1 << 0;
+const CPUID_80000001_EDX_RDTSCP: u32 = 1 << 27;
+const CPUID_80000007_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+const CR4_FSGSBASE: usize = 1 << 16; // RDFSBASE and friends are enabled, in user mode too
+
+/// What init() found the CPU has, and turned on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    /// RDFSBASE, WRFSBASE, RDGSBASE and WRGSBASE, which read and write the FS and GS bases without an MSR.
+    pub fsgsbase: bool,
+    /// RDTSCP, which reads the TSC with IA32_TSC_AUX.
+    pub rdtscp: bool,
+    /// The TSC counts at the same rate in every P-, C- and T-state, so it measures time.
+    pub invariant_tsc: bool,
+}
+
+static mut FEATURES: CpuFeatures = CpuFeatures {
+    fsgsbase: false,
+    rdtscp: false,
+    invariant_tsc: false,
+};
+
+/// Look for the features of the CPU, and turn on the ones the kernel uses.
+///
+/// # Safety
+/// Must be called once, before anything calls features().
+pub unsafe fn init() {
+    let fsgsbase =
+        __cpuid_count(0, 0).eax >= 7 && __cpuid_count(7, 0).ebx & CPUID_7_EBX_FSGSBASE != 0;
+    if fsgsbase {
+        unsafe {
+            let cr4: usize;
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+            asm!("mov cr4, {}", in(reg) cr4 | CR4_FSGSBASE, options(nostack, preserves_flags));
+        }
+    }
+
+    let max_extended = __cpuid_count(0x80000000, 0).eax;
+    let rdtscp = max_extended >= 0x80000001
+        && __cpuid_count(0x80000001, 0).edx & CPUID_80000001_EDX_RDTSCP != 0;
+    let invariant_tsc = max_extended >= 0x80000007
+        && __cpuid_count(0x80000007, 0).edx & CPUID_80000007_EDX_INVARIANT_TSC != 0;
+    // RDTSCP tells which CPU it ran on
+    if rdtscp {
+        write_msr(IA32_TSC_AUX, this_cpu_id() as u64);
+    }
+
+    unsafe {
+        FEATURES = CpuFeatures {
+            fsgsbase,
+            rdtscp,
+            invariant_tsc,
+        }
+    };
+}
+
+/// The features init() found.
+pub fn features() -> CpuFeatures {
+    unsafe { FEATURES }
+}
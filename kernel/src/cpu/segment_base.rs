@@ -0,0 +1,90 @@
+//! The FS and GS bases, and the kernel GS base swapgs trades GS base with.
+//!
+//! With FSGSBASE, the bases are read and written with RDFSBASE and friends, which are much faster than the MSRs. Only
+//! the kernel GS base is always an MSR. FSGSBASE lets user mode write its FS and GS bases too, so the context switch
+//! saves the FS base of the old task rather than trusting what the task last asked for.
+//!
+//! The kernel keeps the GS base of user mode loaded, and never uses GS itself. swapgs only ever comes in pairs that
+//! run with interrupts disabled: syscall_entry() swaps the SyscallScratch of the CPU in to find the kernel stack, and
+//! the context switch does the same to set it. Interrupt and exception handlers never swap, so they work the same
+//! whether they come from user or kernel mode. NMIs and machine checks can land between the swaps of a pair, and don't
+//! use GS either.
+
+use core::arch::asm;
+
+use crate::{
+    consts::USERSPACE_LIMIT,
+    cpu,
+    msr::{IA32_FS_BASE, IA32_GS_BASE, IA32_KERNEL_GS_BASE, read_msr, write_msr},
+};
+
+/// Whether the address can be a segment base. A base that isn't canonical raises #GP when written.
+pub fn is_canonical(base: u64) -> bool {
+    let high = base >> 47;
+    high == 0 || high == (1 << 17) - 1
+}
+
+/// Whether the address is one user mode may use as a segment base.
+pub fn is_user_base(base: u64) -> bool {
+    base < USERSPACE_LIMIT as u64
+}
+
+pub fn read_fs_base() -> u64 {
+    if cpu::features().fsgsbase {
+        let base;
+        unsafe { asm!("rdfsbase {}", out(reg) base, options(nomem, nostack, preserves_flags)) };
+        base
+    } else {
+        read_msr(IA32_FS_BASE)
+    }
+}
+
+/// Set the FS base, which must be canonical.
+pub fn write_fs_base(base: u64) {
+    assert!(is_canonical(base), "non-canonical FS base {:#x}", base);
+    if cpu::features().fsgsbase {
+        unsafe { asm!("wrfsbase {}", in(reg) base, options(nomem, nostack, preserves_flags)) };
+    } else {
+        write_msr(IA32_FS_BASE, base);
+    }
+}
+
+/// The GS base, which is the one of user mode outside of a swapgs pair.
+pub fn read_gs_base() -> u64 {
+    if cpu::features().fsgsbase {
+        let base;
+        unsafe { asm!("rdgsbase {}", out(reg) base, options(nomem, nostack, preserves_flags)) };
+        base
+    } else {
+        read_msr(IA32_GS_BASE)
+    }
+}
+
+/// Set the GS base, which must be canonical.
+pub fn write_gs_base(base: u64) {
+    assert!(is_canonical(base), "non-canonical GS base {:#x}", base);
+    if cpu::features().fsgsbase {
+        unsafe { asm!("wrgsbase {}", in(reg) base, options(nomem, nostack, preserves_flags)) };
+    } else {
+        write_msr(IA32_GS_BASE, base);
+    }
+}
+
+/// The kernel GS base, which is the SyscallScratch of this CPU outside of a swapgs pair.
+pub fn read_kernel_gs_base() -> u64 {
+    read_msr(IA32_KERNEL_GS_BASE)
+}
+
+/// Set the kernel GS base, which must be canonical.
+///
+/// # Safety
+/// syscall_entry() and the context switch find the SyscallScratch of the CPU through the kernel GS base, so it must
+/// point to one that lives for as long as it is set.
+pub unsafe fn write_kernel_gs_base(base: u64) {
+    assert!(
+        is_canonical(base),
+        "non-canonical kernel GS base {:#x}",
+        base
+    );
+    write_msr(IA32_KERNEL_GS_BASE, base);
+}
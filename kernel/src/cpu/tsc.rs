@@ -0,0 +1,65 @@
+//! The time stamp counter, which counts cycles at a fixed rate on modern CPUs.
+//!
+//! rdtsc alone can be reordered with the instructions around it, so a stretch of code measured with it may be
+//! partly outside the measurement. serialized_rdtsc() and measure_cycles() fence the read on both sides.
+//!
+//! The TSC only measures time if it is invariant, i.e. keeps its rate through frequency changes and sleep states,
+//! which CpuFeatures::invariant_tsc says. It is calibrated against the PIT in time.
+
+use core::arch::asm;
+
+use crate::cpu;
+
+/// Read the time stamp counter.
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags))
+    };
+    (high as u64) << 32 | low as u64
+}
+
+/// Read the time stamp counter, after every earlier instruction has finished and before any later one starts.
+pub fn serialized_rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!(
+            "lfence",
+            "rdtsc",
+            "lfence",
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+    (high as u64) << 32 | low as u64
+}
+
+/// Read the time stamp counter once every earlier instruction has finished, with IA32_TSC_AUX, which cpu::init() sets
+/// to the id of the CPU. Panics if the CPU doesn't have RDTSCP (see CpuFeatures::rdtscp).
+pub fn rdtscp() -> (u64, u32) {
+    assert!(cpu::features().rdtscp, "the CPU doesn't have RDTSCP");
+    let low: u32;
+    let high: u32;
+    let aux: u32;
+    unsafe {
+        asm!(
+            "rdtscp",
+            out("eax") low,
+            out("edx") high,
+            out("ecx") aux,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+    ((high as u64) << 32 | low as u64, aux)
+}
+
+/// Run f, and return how many cycles it took. The reads of the counter are fenced, so none of f is left out, and
+/// nothing else is counted.
+pub fn measure_cycles(f: impl FnOnce()) -> u64 {
+    let start = serialized_rdtsc();
+    f();
+    serialized_rdtsc() - start
+}
@@ -0,0 +1,211 @@
+//! Breakpoints: int3, and the hardware breakpoints of the debug registers.
+//!
+//! Both are only reported, and execution goes on, so they can be left in code as trace points. int3 raises a
+//! breakpoint exception (#BP) after it runs. A hardware breakpoint raises a debug exception (#DB): before the
+//! instruction at its address runs, or after an access to the bytes it watches. DR6 tells the handler which one fired,
+//! or that it was a single step (TF).
+//!
+//! The debug registers aren't switched with tasks, so a hardware breakpoint fires whichever task hits its address.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    backtrace::Symbolized,
+    idt::without_interrupt,
+    isr::{InterruptStackFrame, print_rip},
+    printlnk,
+    user::sched,
+};
+
+/// Number of hardware breakpoints (DR0 to DR3).
+pub const HW_BREAKPOINTS: usize = 4;
+
+// DR6: which hardware breakpoint fired (one bit each), and single-stepping
+const DR6_TRIGGERED: usize = 0xf;
+const DR6_SINGLE_STEP: usize = 1 << 14;
+// DR6 with no condition set. The reserved bits read as ones.
+const DR6_CLEAR: usize = 0xffff0ff0;
+
+// RFLAGS.RF: the instruction at rip runs without firing its instruction breakpoint again
+const RFLAGS_RESUME: usize = 1 << 16;
+
+/// What a hardware breakpoint fires on. Data breakpoints watch 1, 2, 4 or 8 bytes, aligned to their length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    Execute,          // Running the instruction at the address
+    Write(usize),     // Writing any of the bytes
+    ReadWrite(usize), // Reading or writing any of the bytes
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointError {
+    InvalidLength, // Not 1, 2, 4 or 8 bytes
+    Misaligned,    // The address isn't aligned to the length
+    NoFreeSlot,    // All the debug registers are in use
+    NotSet,        // The slot has no breakpoint to clear
+}
+
+// Number of times each hardware breakpoint fired, and int3 ran, for statistics.
+static HITS: [AtomicUsize; HW_BREAKPOINTS] = [const { AtomicUsize::new(0) }; HW_BREAKPOINTS];
+static BREAKPOINTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set a hardware breakpoint in a free debug register, and return its slot.
+pub fn set_hw_breakpoint(addr: usize, kind: BreakpointKind) -> Result<usize, BreakpointError> {
+    // DR7 encodes the access in two bits (R/W), and the length in two more (LEN)
+    let (access, len) = match kind {
+        BreakpointKind::Execute => (0b00, 1),
+        BreakpointKind::Write(len) => (0b01, len),
+        BreakpointKind::ReadWrite(len) => (0b11, len),
+    };
+    let len_bits = match len {
+        1 => 0b00,
+        2 => 0b01,
+        4 => 0b11,
+        8 => 0b10,
+        _ => return Err(BreakpointError::InvalidLength),
+    };
+    if !addr.is_multiple_of(len) {
+        return Err(BreakpointError::Misaligned);
+    }
+
+    without_interrupt(|| {
+        let dr7 = read_dr7();
+        let slot = (0..HW_BREAKPOINTS)
+            .find(|&slot| dr7 & enable_bit(slot) == 0)
+            .ok_or(BreakpointError::NoFreeSlot)?;
+
+        let condition_shift = 16 + slot * 4;
+        let dr7 = (dr7 & !(0b1111 << condition_shift))
+            | ((access | len_bits << 2) << condition_shift)
+            | enable_bit(slot);
+        write_address_register(slot, addr);
+        write_dr7(dr7);
+        Ok(slot)
+    })
+}
+
+/// Clear the hardware breakpoint in the slot.
+pub fn clear_hw_breakpoint(slot: usize) -> Result<(), BreakpointError> {
+    if slot >= HW_BREAKPOINTS {
+        return Err(BreakpointError::NotSet);
+    }
+
+    without_interrupt(|| {
+        let dr7 = read_dr7();
+        if dr7 & enable_bit(slot) == 0 {
+            return Err(BreakpointError::NotSet);
+        }
+        write_dr7(dr7 & !enable_bit(slot));
+        Ok(())
+    })
+}
+
+/// Get the number of times the hardware breakpoint in the slot fired.
+pub fn hits(slot: usize) -> usize {
+    HITS.get(slot)
+        .map_or(0, |hits| hits.load(Ordering::Relaxed))
+}
+
+/// Get the number of times int3 ran.
+pub fn breakpoint_count() -> usize {
+    BREAKPOINTS.load(Ordering::Relaxed)
+}
+
+/// Report a breakpoint exception (int3). The frame points after the int3, where execution resumes.
+pub fn handle_breakpoint(frame: &InterruptStackFrame) {
+    BREAKPOINTS.fetch_add(1, Ordering::Relaxed);
+    report("Breakpoint", frame);
+}
+
+/// Report a debug exception, clear DR6, and resume.
+///
+/// # Safety
+/// Must only be called from the debug exception handler, with the frame the CPU pushed, as RFLAGS in it may be
+/// changed for the return.
+pub unsafe fn handle_debug(frame: &mut InterruptStackFrame) {
+    let dr6 = read_dr6();
+    write_dr6(DR6_CLEAR);
+
+    for slot in (0..HW_BREAKPOINTS).filter(|&slot| dr6 & DR6_TRIGGERED & (1 << slot) != 0) {
+        HITS[slot].fetch_add(1, Ordering::Relaxed);
+        printlnk!(
+            "Hardware breakpoint {} fired, at {:#x}",
+            slot,
+            read_address_register(slot)
+        );
+    }
+    if dr6 & DR6_SINGLE_STEP != 0 {
+        printlnk!("Single step");
+    }
+    report("Debug exception", frame);
+
+    // An instruction breakpoint fires before its instruction runs, so it would fire again on return. Data breakpoints
+    // and single steps fire after, and ignore RF.
+    unsafe { (&raw mut frame.flags).write_volatile(frame.flags | RFLAGS_RESUME) };
+}
+
+// Print where an exception that is only reported happened, and in which task if it came from user mode.
+fn report(what: &str, frame: &InterruptStackFrame) {
+    if frame.is_user_mode() {
+        sched::with_current_task(|task| {
+            printlnk!("{} in task {} ({})", what, task.id, task.name());
+            print_rip(task, frame.ip);
+        });
+    } else {
+        printlnk!("{} in the kernel, rip = {}", what, Symbolized(frame.ip));
+    }
+}
+
+// The local enable bit of the slot in DR7. Breakpoints are only ever enabled locally.
+fn enable_bit(slot: usize) -> usize {
+    1 << (slot * 2)
+}
+
+fn read_dr6() -> usize {
+    let value: usize;
+    unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+fn write_dr6(value: usize) {
+    unsafe { asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+}
+
+fn read_dr7() -> usize {
+    let value: usize;
+    unsafe { asm!("mov {}, dr7", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+// Not nomem, so memory accesses aren't moved across enabling or disabling a breakpoint
+fn write_dr7(value: usize) {
+    unsafe { asm!("mov dr7, {}", in(reg) value, options(nostack, preserves_flags)) };
+}
+
+fn read_address_register(slot: usize) -> usize {
+    let value: usize;
+    unsafe {
+        match slot {
+            0 => asm!("mov {}, dr0", out(reg) value, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov {}, dr1", out(reg) value, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov {}, dr2", out(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => asm!("mov {}, dr3", out(reg) value, options(nomem, nostack, preserves_flags)),
+        }
+    }
+    value
+}
+
+// Only called with the breakpoint of the slot disabled, so it doesn't fire on a half-updated slot.
+fn write_address_register(slot: usize, addr: usize) {
+    unsafe {
+        match slot {
+            0 => asm!("mov dr0, {}", in(reg) addr, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov dr1, {}", in(reg) addr, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov dr2, {}", in(reg) addr, options(nomem, nostack, preserves_flags)),
+            _ => asm!("mov dr3, {}", in(reg) addr, options(nomem, nostack, preserves_flags)),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! The exception table: instructions in the kernel that are allowed to fault, and where each goes on when it does.
+//!
+//! An instruction that may fault, e.g. rdmsr of an MSR the CPU may not have, gets an entry from extable_entry! in
+//! the same asm!. The general protection fault handler looks the rip of a fault in the kernel up here before reporting
+//! it, and resumes at the fixup of the entry it finds, which tells the code around it that the instruction failed.
+//!
+//! The assembler keeps the entries in the extable section, which the linker brackets with __start_extable and
+//! __stop_extable. Both addresses of an entry are offsets from the entry, so the table needs no relocations.
+
+use core::slice;
+
+/// An entry of the exception table, for asm!: the instruction at the label fault may fault, and goes on at the label
+/// fixup if it does, e.g. `extable_entry!("2b", "3f")`.
+#[macro_export]
+macro_rules! extable_entry {
+    ($fault:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection extable, \"aR\"\n",
+            ".balign 4\n",
+            ".long ",
+            $fault,
+            " - .\n",
+            ".long ",
+            $fixup,
+            " - .\n",
+            ".popsection\n",
+        )
+    };
+}
+
+#[repr(C)]
+struct Entry {
+    fault: i32, // From the address of this field
+    fixup: i32, // From the address of this field
+}
+
+impl Entry {
+    fn fault(&self) -> usize {
+        (&raw const self.fault as usize).wrapping_add_signed(self.fault as isize)
+    }
+
+    fn fixup(&self) -> usize {
+        (&raw const self.fixup as usize).wrapping_add_signed(self.fixup as isize)
+    }
+}
+
+unsafe extern "C" {
+    static __start_extable: Entry;
+    static __stop_extable: Entry;
+}
+
+fn entries() -> &'static [Entry] {
+    let start = &raw const __start_extable;
+    let stop = &raw const __stop_extable;
+    unsafe { slice::from_raw_parts(start, stop.offset_from(start) as usize) }
+}
+
+/// Where the instruction at ip goes on if it faults, if it is in the table.
+pub fn fixup(ip: usize) -> Option<usize> {
+    entries()
+        .iter()
+        .find(|entry| entry.fault() == ip)
+        .map(Entry::fixup)
+}
+
+/// Number of instructions in the table.
+pub fn len() -> usize {
+    entries().len()
+}
@@ -0,0 +1,117 @@
+//! x87 FPU and SSE state.
+//!
+//! User code uses SSE freely, so the state is saved with FXSAVE and restored with FXRSTOR on every context switch.
+//! The kernel is built for a soft-float target and never touches the FPU or SSE registers itself, so syscalls and
+//! interrupts leave the user state alone.
+
+use core::arch::asm;
+
+const CR0_MP: usize = 1 << 1; // Monitor coprocessor
+const CR0_EM: usize = 1 << 2; // x87 emulation
+const CR0_TS: usize = 1 << 3; // Task switched
+const CR4_OSFXSR: usize = 1 << 9; // FXSAVE, FXRSTOR and SSE instructions are enabled
+const CR4_OSXMMEXCPT: usize = 1 << 10; // Unmasked SSE exceptions raise #XM
+
+pub const FXSAVE_AREA_SIZE: usize = 512;
+
+// Offsets into the FXSAVE area.
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+const MXCSR_MASK_OFFSET: usize = 28;
+
+// MXCSR bits the CPU supports, if FXSAVE stores 0 as the mask
+const DEFAULT_MXCSR_MASK: u32 = 0xffbf;
+
+pub const DEFAULT_FCW: u16 = 0x37f; // All x87 exceptions masked, 64-bit precision, round to nearest
+pub const DEFAULT_MXCSR: u32 = 0x1f80; // All SSE exceptions masked, round to nearest
+
+/// Enable the FPU and SSE for user mode.
+pub fn init() {
+    unsafe {
+        let mut cr0: usize;
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        cr0 = (cr0 | CR0_MP) & !(CR0_EM | CR0_TS);
+        asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+
+        let mut cr4: usize;
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+        asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+/// The x87 FPU and SSE registers of a task, in the FXSAVE format.
+#[repr(C, align(16))]
+#[derive(Debug, Clone)]
+pub struct FpuState([u8; FXSAVE_AREA_SIZE]);
+
+impl FpuState {
+    /// The state a new task starts with, which is the state after FNINIT with the default MXCSR.
+    pub fn new() -> Self {
+        let mut state = FpuState([0; FXSAVE_AREA_SIZE]);
+        state.0[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&DEFAULT_FCW.to_ne_bytes());
+        state.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_ne_bytes());
+        state
+    }
+
+    /// Save the current FPU and SSE registers into a new state.
+    pub fn current() -> Self {
+        let mut state = FpuState([0; FXSAVE_AREA_SIZE]);
+        state.save();
+        state
+    }
+
+    /// A state from an FXSAVE area that user mode may have written. MXCSR bits the CPU doesn't support are cleared, as
+    /// FXRSTOR would fault on them.
+    pub fn from_user(area: &[u8; FXSAVE_AREA_SIZE]) -> Self {
+        let current = Self::current();
+        let mask = match u32::from_ne_bytes(
+            current.0[MXCSR_MASK_OFFSET..MXCSR_MASK_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        ) {
+            0 => DEFAULT_MXCSR_MASK,
+            mask => mask,
+        };
+
+        let mut state = FpuState(*area);
+        let mxcsr = state.mxcsr() & mask;
+        state.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&mxcsr.to_ne_bytes());
+        state
+    }
+
+    /// The state in the FXSAVE format.
+    pub fn as_bytes(&self) -> &[u8; FXSAVE_AREA_SIZE] {
+        &self.0
+    }
+
+    pub fn fcw(&self) -> u16 {
+        u16::from_ne_bytes(self.0[FCW_OFFSET..FCW_OFFSET + 2].try_into().unwrap())
+    }
+
+    pub fn mxcsr(&self) -> u32 {
+        u32::from_ne_bytes(self.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].try_into().unwrap())
+    }
+
+    /// Save the current FPU and SSE registers into this state.
+    pub fn save(&mut self) {
+        unsafe {
+            asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags))
+        };
+    }
+
+    /// Load the FPU and SSE registers from this state.
+    pub fn restore(&self) {
+        unsafe {
+            asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(readonly, nostack, preserves_flags))
+        };
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
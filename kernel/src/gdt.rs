@@ -1,6 +1,6 @@
 use core::{arch::asm, mem::MaybeUninit};
 
-use arbitrary_int::{u4, u20};
+use arbitrary_int::{u2, u4, u20};
 use bitbybit::bitfield;
 
 #[bitfield(u64)]
@@ -18,21 +18,181 @@ struct Entry {
     flags: u4,
 }
 
-// We will define 7 entries in the GDT:
-// 0: Null segment
-// 1: Kernel code segment
-// 2: Kernel data segment
-// 3: User data segment
-// 4: User code segment
-// 5: Task State Segment (lower half)
-// 6: Task State Segment (higher half)
+/// The access byte of a descriptor.
+#[bitfield(u8)]
+struct Access {
+    // What the segment is: the TYPE_ bits for code and data, or a SYSTEM_ type
+    #[bits(0..=3, rw)]
+    kind: u4,
 
-const SIZE_OF_GDT: usize = 7;
+    // S: set for code and data, clear for system descriptors
+    #[bit(4, rw)]
+    code_or_data: bool,
 
-pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
-pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
-pub const USER_DATA_SELECTOR: u16 = 0x18 | 0x03;
-pub const USER_CODE_SELECTOR: u16 = 0x20 | 0x03;
+    #[bits(5..=6, rw)]
+    dpl: u2,
+
+    #[bit(7, rw)]
+    present: bool,
+}
+
+/// The flags nibble of a descriptor.
+#[bitfield(u8)]
+struct Flags {
+    // L: a 64-bit code segment
+    #[bit(1, rw)]
+    long_mode: bool,
+
+    // D/B: a 32-bit segment, which must be clear with L
+    #[bit(2, rw)]
+    default_size: bool,
+
+    // G: the limit is in pages
+    #[bit(3, rw)]
+    granularity: bool,
+}
+
+// Type bits of code and data segments. The CPU sets ACCESSED on first use, so it is set already to save it a write.
+const TYPE_ACCESSED: u8 = 1 << 0;
+const TYPE_READABLE_WRITABLE: u8 = 1 << 1; // Readable for code, writable for data
+const TYPE_CODE: u8 = 1 << 3;
+
+// Type of an available 64-bit TSS
+const SYSTEM_TSS: u8 = 0b1001;
+
+const fn entry(access: Access, flags: Flags) -> Entry {
+    Entry::ZERO
+        .with_access(access.raw_value())
+        .with_flags(u4::new(flags.raw_value()))
+}
+
+/// A code or data segment descriptor. In long mode, their base and limit are ignored.
+#[derive(Clone, Copy)]
+pub struct SegmentDescriptor(Entry);
+
+impl SegmentDescriptor {
+    pub const fn kernel_code() -> Self {
+        Self::code(0)
+    }
+
+    pub const fn kernel_data() -> Self {
+        Self::data(0)
+    }
+
+    pub const fn user_code() -> Self {
+        Self::code(3)
+    }
+
+    pub const fn user_data() -> Self {
+        Self::data(3)
+    }
+
+    const fn code(dpl: u8) -> Self {
+        let kind = TYPE_CODE | TYPE_READABLE_WRITABLE | TYPE_ACCESSED;
+        Self(entry(
+            Self::access(kind, dpl),
+            Flags::ZERO.with_long_mode(true),
+        ))
+    }
+
+    const fn data(dpl: u8) -> Self {
+        let kind = TYPE_READABLE_WRITABLE | TYPE_ACCESSED;
+        Self(entry(Self::access(kind, dpl), Flags::ZERO))
+    }
+
+    const fn access(kind: u8, dpl: u8) -> Access {
+        Access::ZERO
+            .with_kind(u4::new(kind))
+            .with_code_or_data(true)
+            .with_dpl(u2::new(dpl))
+            .with_present(true)
+    }
+
+    pub const fn raw(&self) -> u64 {
+        self.0.raw_value()
+    }
+}
+
+/// A system descriptor, which takes two entries in long mode: the low one is laid out as a segment descriptor, and
+/// the high one holds the top half of the base.
+#[derive(Clone, Copy)]
+pub struct SystemDescriptor {
+    low: Entry,
+    high: Entry,
+}
+
+impl SystemDescriptor {
+    /// The descriptor of an available TSS at base, with limit one less than its size in bytes.
+    pub const fn tss(base: u64, limit: u32) -> Self {
+        let access = Access::ZERO
+            .with_kind(u4::new(SYSTEM_TSS))
+            .with_present(true);
+        SystemDescriptor {
+            low: entry(access, Flags::ZERO)
+                .with_base(base as u32)
+                .with_limit(u20::new(limit)),
+            high: Entry::new_with_raw_value(base >> 32),
+        }
+    }
+
+    /// The low entry, then the high one.
+    pub const fn raw(&self) -> [u64; 2] {
+        [self.low.raw_value(), self.high.raw_value()]
+    }
+}
+
+// The builders give the same descriptors as the access bytes and flags that were written out before them
+const _: () = {
+    assert!(SegmentDescriptor::kernel_code().raw() == 0x0020_9b00_0000_0000);
+    assert!(SegmentDescriptor::kernel_data().raw() == 0x0000_9300_0000_0000);
+    assert!(SegmentDescriptor::user_data().raw() == 0x0000_f300_0000_0000);
+    assert!(SegmentDescriptor::user_code().raw() == 0x0020_fb00_0000_0000);
+    let tss = SystemDescriptor::tss(0xffff_8000_1234_5678, 0x67).raw();
+    assert!(tss[0] == 0x1200_8934_5678_0067 && tss[1] == 0xffff_8000);
+};
+
+// Indices of the entries of the GDT. The TSS takes two.
+const NULL_INDEX: usize = 0;
+const KERNEL_CODE_INDEX: usize = 1;
+const KERNEL_DATA_INDEX: usize = 2;
+const USER_DATA_INDEX: usize = 3;
+const USER_CODE_INDEX: usize = 4;
+const TSS_INDEX: usize = 5;
+
+const SIZE_OF_GDT: usize = TSS_INDEX + 2;
+
+// What each entry is, for reports that name a selector.
+const ENTRY_NAMES: [&str; SIZE_OF_GDT] = [
+    "null",
+    "kernel code",
+    "kernel data",
+    "user data",
+    "user code",
+    "TSS, lower half",
+    "TSS, upper half",
+];
+
+/// Name the GDT entry at the index, e.g. "user code" for 4.
+pub fn entry_name(index: usize) -> Option<&'static str> {
+    ENTRY_NAMES.get(index).copied()
+}
+
+// The selector of the entry at the index, with the requested privilege level.
+const fn selector(index: usize, rpl: u16) -> u16 {
+    (index as u16) << 3 | rpl
+}
+
+pub const KERNEL_CODE_SELECTOR: u16 = selector(KERNEL_CODE_INDEX, 0);
+pub const KERNEL_DATA_SELECTOR: u16 = selector(KERNEL_DATA_INDEX, 0);
+pub const USER_DATA_SELECTOR: u16 = selector(USER_DATA_INDEX, 3);
+pub const USER_CODE_SELECTOR: u16 = selector(USER_CODE_INDEX, 3);
+pub const TSS_SELECTOR: u16 = selector(TSS_INDEX, 0);
+
+/// The selector IA32_STAR gives sysret. It loads SS from the entry after it, and CS from the one after that, so user
+/// data must come right before user code. syscall loads CS from KERNEL_CODE_SELECTOR, and SS from the entry after it.
+pub const SYSRET_BASE_SELECTOR: u16 = selector(USER_DATA_INDEX - 1, 0);
+const _: () =
+    assert!(USER_CODE_INDEX == USER_DATA_INDEX + 1 && KERNEL_DATA_INDEX == KERNEL_CODE_INDEX + 1);
 
 #[repr(C)]
 struct Gdt([Entry; SIZE_OF_GDT]);
@@ -73,35 +233,40 @@ pub struct Tss {
 
 pub static mut TSS: Tss = unsafe { MaybeUninit::zeroed().assume_init() };
 
+/// IST index of the stack of the double fault handler. Double faults are mostly kernel stack overflows, and the stack
+/// that overflowed can't take the interrupt frame.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+/// IST index of the stack of the NMI handler. An NMI can't be masked, so it may arrive right after syscall, while the
+/// kernel still runs on the user stack.
+pub const NMI_IST: u8 = 2;
+
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+static mut NMI_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+
 pub unsafe fn init() {
     // Setup gdt
 
     let gdt = unsafe { &mut GDT };
-    // Null segment
-    gdt.0[0] = Entry::ZERO;
-    // Kernel code segment
-    gdt.0[1] = Entry::ZERO
-        .with_access(0b10011011)
-        .with_flags(u4::new(0b0010));
-    // Kernel data segment
-    gdt.0[2] = Entry::ZERO
-        .with_access(0b10010011)
-        .with_flags(u4::new(0b0000));
-    // User data segment
-    gdt.0[3] = Entry::ZERO
-        .with_access(0b11110011)
-        .with_flags(u4::new(0b0000));
-    // User code segment
-    gdt.0[4] = Entry::ZERO
-        .with_access(0b11111011)
-        .with_flags(u4::new(0b0010));
-    // Task State Segment
-    gdt.0[5] = Entry::ZERO
-        .with_base(&raw const TSS as u32)
-        .with_limit(u20::new(size_of::<Tss>() as u32 - 1))
-        .with_access(0b10001001)
-        .with_flags(u4::new(0b0000));
-    gdt.0[6] = Entry::new_with_raw_value(&raw const TSS as u64 >> 32);
+    let tss = SystemDescriptor::tss(&raw const TSS as u64, size_of::<Tss>() as u32 - 1);
+    gdt.0[NULL_INDEX] = Entry::ZERO;
+    gdt.0[KERNEL_CODE_INDEX] = SegmentDescriptor::kernel_code().0;
+    gdt.0[KERNEL_DATA_INDEX] = SegmentDescriptor::kernel_data().0;
+    gdt.0[USER_DATA_INDEX] = SegmentDescriptor::user_data().0;
+    gdt.0[USER_CODE_INDEX] = SegmentDescriptor::user_code().0;
+    gdt.0[TSS_INDEX] = tss.low;
+    gdt.0[TSS_INDEX + 1] = tss.high;
+
+    // Interrupt stacks, which grow down from the end
+    unsafe {
+        TSS.ist1 = (&raw const DOUBLE_FAULT_STACK as usize + IST_STACK_SIZE) as u64;
+        TSS.ist2 = (&raw const NMI_STACK as usize + IST_STACK_SIZE) as u64;
+    }
 
     // Setup gdtr
 
@@ -118,6 +283,6 @@ pub unsafe fn init() {
         );
 
         // Load tss
-        asm!("mov ax, 0x28", "ltr ax", out("ax") _, options(nostack, preserves_flags));
+        asm!("ltr {0:x}", in(reg) TSS_SELECTOR, options(nostack, preserves_flags));
     }
 }
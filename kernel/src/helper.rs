@@ -1,6 +1,14 @@
 use core::arch::asm;
 
-use crate::{consts, printlnk};
+use crate::{consts, io::port::PortWriteOnly, printlnk};
+
+// Port of the isa-debug-exit device, which the runner adds to QEMU.
+const QEMU_EXIT: PortWriteOnly<u8> = unsafe { PortWriteOnly::new(0xf4) };
+
+/// QEMU exits with (QEMU_EXIT_DOUBLE_FAULT << 1) | 1 after a double fault, in kernels built with test-double-fault.
+pub const QEMU_EXIT_DOUBLE_FAULT: u8 = 0x10;
+/// QEMU exits with (QEMU_EXIT_PANIC << 1) | 1 after a panic, in kernels built with test-panic-locked.
+pub const QEMU_EXIT_PANIC: u8 = 0x11;
 
 /// Halt and Catch Fire.
 pub fn hcf() -> ! {
@@ -10,6 +18,12 @@ pub fn hcf() -> ! {
     }
 }
 
+/// Exit QEMU, with (code << 1) | 1 as its exit status. Returns if there is no isa-debug-exit device, e.g. on real
+/// hardware.
+pub fn exit_qemu(code: u8) {
+    QEMU_EXIT.write(code);
+}
+
 /// Convert a physical address to a virtual address (in the direct mapping).
 pub fn p2v(addr: usize) -> usize {
     addr + consts::PHYS_MEM_OFFSET
@@ -46,3 +60,28 @@ pub fn add_within_bounds(a: usize, b: usize, upper_bound: usize) -> Option<usize
     let sum = a.checked_add(b)?;
     if sum <= upper_bound { Some(sum) } else { None }
 }
+
+// From: https://users.rust-lang.org/t/can-i-conveniently-compile-bytes-into-a-rust-program-with-a-specific-alignment/24049/2
+#[doc(hidden)]
+#[repr(C)] // guarantee 'bytes' comes after '_align'
+pub struct AlignedAs<Align, Bytes: ?Sized> {
+    pub _align: [Align; 0],
+    pub bytes: Bytes,
+}
+
+/// include_bytes!, aligned as the type, e.g. for a file that is read in place as u32 or u64.
+#[macro_export]
+macro_rules! include_bytes_align_as {
+    ($align_ty:ty, $path:expr) => {{
+        // const block expression to encapsulate the static
+        use $crate::helper::AlignedAs;
+
+        // this assignment is made possible by CoerceUnsized
+        static ALIGNED: &AlignedAs<$align_ty, [u8]> = &AlignedAs {
+            _align: [],
+            bytes: *include_bytes!($path),
+        };
+
+        &ALIGNED.bytes
+    }};
+}
@@ -1,10 +1,14 @@
-use core::arch::asm;
+use core::{arch::asm, marker::PhantomData};
 
 use arbitrary_int::{u2, u3};
 use bitbybit::{bitenum, bitfield};
 use pic8259::ChainedPics;
 
-use crate::{gdt::KERNEL_CODE_SELECTOR, isr};
+use crate::{
+    gdt::{DOUBLE_FAULT_IST, KERNEL_CODE_SELECTOR, NMI_IST},
+    io::serial,
+    ioapic, irq, isr, nmi,
+};
 
 #[bitenum(u4)]
 #[allow(dead_code)]
@@ -54,6 +58,12 @@ static mut IDTR: Idtr = Idtr {
 pub const PIC_OFFSET: u8 = 0x20;
 pub static mut PICS: ChainedPics = unsafe { ChainedPics::new_contiguous(PIC_OFFSET) };
 
+// Vectors of the IRQs of the PIC the kernel uses.
+pub const TIMER_VECTOR: usize = PIC_OFFSET as usize; // IRQ 0, the PIT
+pub const KEYBOARD_VECTOR: usize = PIC_OFFSET as usize + 1; // IRQ 1
+pub const COM2_VECTOR: usize = PIC_OFFSET as usize + 3; // IRQ 3
+pub const COM1_VECTOR: usize = PIC_OFFSET as usize + 4; // IRQ 4
+
 fn to_entry(func: *const ()) -> Entry {
     Entry::ZERO
         .with_offset(func as u64)
@@ -70,13 +80,14 @@ pub unsafe fn init() {
     let idt = unsafe { &mut IDT };
     idt.0[0] = to_entry(isr::isr_0 as *const ());
     idt.0[1] = to_entry(isr::isr_1 as *const ());
-    idt.0[2] = to_entry(isr::isr_2 as *const ());
-    idt.0[3] = to_entry(isr::isr_3 as *const ());
+    idt.0[2] = to_entry(nmi::nmi_entry as *const ()).with_ist(u3::new(NMI_IST));
+    // User code may run int3 too
+    idt.0[3] = to_entry(isr::isr_3 as *const ()).with_dpl(u2::new(3));
     idt.0[4] = to_entry(isr::isr_4 as *const ());
     idt.0[5] = to_entry(isr::isr_5 as *const ());
     idt.0[6] = to_entry(isr::isr_6 as *const ());
     idt.0[7] = to_entry(isr::isr_7 as *const ());
-    idt.0[8] = to_entry(isr::isr_8 as *const ());
+    idt.0[8] = to_entry(isr::isr_8 as *const ()).with_ist(u3::new(DOUBLE_FAULT_IST));
     idt.0[9] = to_entry(isr::isr_9 as *const ());
     idt.0[10] = to_entry(isr::isr_10 as *const ());
     idt.0[11] = to_entry(isr::isr_11 as *const ());
@@ -91,8 +102,16 @@ pub unsafe fn init() {
     idt.0[20] = to_entry(isr::isr_20 as *const ());
     idt.0[21] = to_entry(isr::isr_21 as *const ());
 
-    idt.0[0x20] = to_entry(isr::pic_timer_handler as *const ());
-    idt.0[0x21] = to_entry(isr::pic_keyboard_handler as *const ());
+    // Everything past the exceptions goes through irq, where drivers register their handlers. The reserved exceptions
+    // do too, so a stray interrupt on them is reported rather than turned into a general protection fault.
+    for (vector, entry) in idt
+        .0
+        .iter_mut()
+        .enumerate()
+        .skip(irq::FIRST_RESERVED_VECTOR)
+    {
+        *entry = to_entry(irq::stub(vector));
+    }
 
     // Setup idtr
 
@@ -110,11 +129,60 @@ pub unsafe fn init() {
         );
     }
 
-    // Setup PICs
+    // Setup PICs. IRQ 0 is left to the PIT, which unmasks it once it is started.
+    irq::register_handler(KEYBOARD_VECTOR, isr::pic_keyboard_handler).unwrap();
+    // Only the console receives, so only its IRQ is unmasked
+    let (serial_irq, serial_vector) = console_irq();
+    irq::register_handler(serial_vector, isr::pic_serial_handler).unwrap();
     unsafe {
         PICS.initialize();
-        PICS.write_masks(0b11111100, 0b11111111);
+        PICS.write_masks(!(1 << serial_irq | 0b10), 0b11111111); // Keyboard and the console
+    }
+}
+
+// The ISA IRQ of the serial console, and its vector.
+fn console_irq() -> (u8, usize) {
+    if serial::console_port() == serial::COM2 {
+        (3, COM2_VECTOR)
+    } else {
+        (4, COM1_VECTOR)
+    }
+}
+
+/// Route the keyboard and serial console IRQs through the I/O APIC, to the vectors they have on the PIC, and disable
+/// the PIC. Does nothing without an I/O APIC.
+pub fn route_device_irqs() {
+    if !ioapic::enabled() {
+        return;
     }
+
+    let (serial_irq, serial_vector) = console_irq();
+    for (irq, vector) in [(1, KEYBOARD_VECTOR), (serial_irq, serial_vector)] {
+        ioapic::route_isa_irq(irq, vector).unwrap();
+    }
+    without_interrupt(|| unsafe { PICS.disable() });
+}
+
+/// Mask an IRQ of the PIC, e.g. when another interrupt controller takes over its device.
+pub fn mask_pic_irq(irq: u8) {
+    without_interrupt(|| unsafe {
+        let [primary, secondary] = PICS.read_masks();
+        match irq {
+            0..8 => PICS.write_masks(primary | 1 << irq, secondary),
+            _ => PICS.write_masks(primary, secondary | 1 << (irq - 8)),
+        }
+    });
+}
+
+/// Unmask an IRQ of the PIC, and the cascade to the secondary PIC if the IRQ is on it.
+pub fn unmask_pic_irq(irq: u8) {
+    without_interrupt(|| unsafe {
+        let [primary, secondary] = PICS.read_masks();
+        match irq {
+            0..8 => PICS.write_masks(primary & !(1 << irq), secondary),
+            _ => PICS.write_masks(primary & !(1 << 2), secondary & !(1 << (irq - 8))),
+        }
+    });
 }
 
 pub fn enable_interrupt() {
@@ -129,26 +197,39 @@ pub fn disable_interrupt() {
     }
 }
 
-pub fn without_interrupt<F, R>(f: F) -> R
-where
-    F: FnOnce() -> R,
-{
-    unsafe {
-        let rflags: usize;
-        asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags));
-
-        let was_enabled = (rflags & (1 << 9)) != 0;
+/// Keeps interrupts disabled while it lives. When dropped, it enables them again only if they were enabled when it was
+/// taken, so guards nest, and one taken with interrupts disabled (e.g. in an interrupt handler) leaves them disabled.
+/// Guards must be dropped in the reverse order they were taken, as they are at the end of their scopes.
+pub struct InterruptGuard {
+    was_enabled: bool,
+    _not_send: PhantomData<*const ()>, // IF belongs to the CPU the guard was taken on
+}
 
-        if was_enabled {
-            disable_interrupt();
+impl InterruptGuard {
+    /// Disable interrupts until the guard is dropped.
+    pub fn disable() -> Self {
+        let was_enabled = irq::are_enabled();
+        disable_interrupt();
+        Self {
+            was_enabled,
+            _not_send: PhantomData,
         }
+    }
+}
 
-        let result = f();
-
-        if was_enabled {
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
             enable_interrupt();
         }
-
-        result
     }
 }
+
+/// Run f with interrupts disabled, and leave them as they were before.
+pub fn without_interrupt<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = InterruptGuard::disable();
+    f()
+}
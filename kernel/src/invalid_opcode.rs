@@ -0,0 +1,210 @@
+//! The invalid opcode (#UD) handler.
+//!
+//! #UD covers many mistakes: executing data, code for another mode, or an extension the kernel hasn't enabled (e.g.
+//! AVX, as XCR0 is never set). The handler reads the bytes at rip and runs them through a small classifier, which
+//! looks at the prefixes and the opcode, and names the likely cause. A fault from user mode then kills the task, once
+//! the handler returns. One in the kernel is a bug, and panics.
+
+use core::{arch::asm, fmt};
+
+use crate::{
+    backtrace::Symbolized,
+    isr::{self, InterruptStackFrame, fetch_instruction},
+    printlnk,
+};
+
+const CR4_OSFXSR: usize = 1 << 9; // SSE instructions are enabled
+const CR4_OSXSAVE: usize = 1 << 18; // XGETBV, and the extensions XCR0 enables
+
+const XCR0_AVX: u64 = 0b110; // SSE and AVX (YMM) state
+const XCR0_AVX512: u64 = 0b111 << 5; // Opmask, and the upper halves of the ZMM registers
+
+// Legacy prefixes: LOCK, REP, segment overrides, operand and address size.
+const LOCK_PREFIX: u8 = 0xf0;
+const PREFIXES: [u8; 11] = [
+    LOCK_PREFIX,
+    0xf2,
+    0xf3,
+    0x2e,
+    0x36,
+    0x3e,
+    0x26,
+    0x64,
+    0x65,
+    0x66,
+    0x67,
+];
+
+// One-byte opcodes that only exist outside 64-bit mode (push/pop of segment registers, BCD arithmetic, pusha/popa,
+// into, far jumps and calls with an immediate address).
+const INVALID_IN_64_BIT: [u8; 16] = [
+    0x06, 0x07, 0x0e, 0x16, 0x17, 0x1e, 0x1f, 0x27, 0x2f, 0x37, 0x3f, 0x60, 0x61, 0x9a, 0xce, 0xea,
+];
+
+/// The extensions the kernel has enabled, which decide whether an instruction using them is valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    pub sse: bool,
+    pub avx: bool,
+    pub avx512: bool,
+}
+
+impl Extensions {
+    /// Read the extensions enabled on this CPU from CR4 and XCR0.
+    pub fn current() -> Self {
+        let cr4: usize;
+        unsafe { asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags)) };
+
+        // XGETBV is itself invalid without OSXSAVE
+        let xcr0 = if cr4 & CR4_OSXSAVE != 0 {
+            let (low, high): (u32, u32);
+            unsafe {
+                asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags))
+            };
+            ((high as u64) << 32) | low as u64
+        } else {
+            0
+        };
+
+        Self {
+            sse: cr4 & CR4_OSFXSR != 0,
+            avx: xcr0 & XCR0_AVX == XCR0_AVX,
+            avx512: xcr0 & XCR0_AVX512 == XCR0_AVX512,
+        }
+    }
+}
+
+/// The likely cause of an invalid opcode, as far as the classifier can tell from the bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    Ud2,                // UD0, UD1 or UD2, which are invalid on purpose
+    AvxNotEnabled,      // A VEX encoded (AVX) instruction
+    Avx512NotEnabled,   // An EVEX encoded (AVX-512) instruction
+    SseNotEnabled,      // An SSE instruction
+    Unsupported,        // An extension that is enabled, so the CPU doesn't have the instruction
+    LockOnRegister, // A LOCK prefix on an instruction that could be locked, but with a register destination
+    LockNotAllowed, // A LOCK prefix on an instruction that can't be locked
+    InvalidIn64BitMode, // An opcode that only exists in 16 and 32-bit mode
+    Unknown,        // None of the above, often data executed as code
+}
+
+/// Suggests what went wrong.
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Cause::Ud2 => {
+                "a deliberately invalid instruction (ud2), e.g. an abort or unreachable code"
+            }
+            Cause::AvxNotEnabled => "an AVX instruction, but the AVX extension is not enabled",
+            Cause::Avx512NotEnabled => {
+                "an AVX-512 instruction, but the AVX-512 extension is not enabled"
+            }
+            Cause::SseNotEnabled => "an SSE instruction, but the SSE extension is not enabled",
+            Cause::Unsupported => {
+                "an instruction of an enabled extension that this CPU doesn't support"
+            }
+            Cause::LockOnRegister => "a LOCK prefix on an instruction with a register destination",
+            Cause::LockNotAllowed => "a LOCK prefix on an instruction that can't be locked",
+            Cause::InvalidIn64BitMode => {
+                "an instruction that doesn't exist in 64-bit mode, e.g. 32-bit code"
+            }
+            Cause::Unknown => {
+                "unknown, maybe data executed as code, or an instruction this CPU doesn't support"
+            }
+        })
+    }
+}
+
+/// Guess why the instruction in bytes is invalid, with the extensions that are enabled.
+pub fn classify(bytes: &[u8], extensions: Extensions) -> Cause {
+    let prefixes = bytes
+        .iter()
+        .take_while(|byte| PREFIXES.contains(byte))
+        .count();
+    let lock = bytes[..prefixes].contains(&LOCK_PREFIX);
+    // A REX prefix comes last, right before the opcode
+    let rest = &bytes[prefixes..];
+    let rest = match rest.first() {
+        Some(0x40..=0x4f) => &rest[1..],
+        _ => rest,
+    };
+
+    match rest {
+        [0x0f, 0x0b | 0xb9 | 0xff, ..] => Cause::Ud2,
+        [0xc4 | 0xc5, ..] if !extensions.avx => Cause::AvxNotEnabled,
+        [0x62, ..] if !extensions.avx512 => Cause::Avx512NotEnabled,
+        [0xc4 | 0xc5 | 0x62, ..] => Cause::Unsupported,
+        [0x0f, opcode, ..] if is_sse(*opcode) => {
+            if extensions.sse {
+                Cause::Unsupported
+            } else {
+                Cause::SseNotEnabled
+            }
+        }
+        [opcode, ..] if INVALID_IN_64_BIT.contains(opcode) => Cause::InvalidIn64BitMode,
+        _ if lock => match lockable_modrm(rest) {
+            Some(modrm) if modrm >> 6 == 0b11 => Cause::LockOnRegister,
+            Some(_) => Cause::Unknown,
+            None => Cause::LockNotAllowed,
+        },
+        _ => Cause::Unknown,
+    }
+}
+
+// Whether the opcode following 0x0f belongs to SSE (or MMX, which uses the same encodings with other prefixes).
+fn is_sse(opcode: u8) -> bool {
+    matches!(opcode, 0x10..=0x17 | 0x28..=0x2f | 0x50..=0x7f | 0xc2..=0xc6 | 0xd0..=0xfe)
+}
+
+// The ModRM byte of an instruction that can take a LOCK prefix, or None if it can't. The ones that can all write to a
+// ModRM operand, which must be in memory.
+fn lockable_modrm(instruction: &[u8]) -> Option<u8> {
+    let (lockable, modrm) = match *instruction {
+        // add, or, adc, sbb, and, sub and xor to r/m, the immediate group, and xchg
+        [opcode, modrm, ..] if opcode <= 0x31 && opcode & 0b110 == 0 => (true, modrm),
+        [0x80..=0x83 | 0x86 | 0x87, modrm, ..] => (true, modrm),
+        // not and neg, inc and dec
+        [0xf6 | 0xf7, modrm, ..] => (matches!((modrm >> 3) & 0b111, 2 | 3), modrm),
+        [0xfe | 0xff, modrm, ..] => (matches!((modrm >> 3) & 0b111, 0 | 1), modrm),
+        // bts, btr and btc, cmpxchg, xadd, and cmpxchg8b/16b
+        [
+            0x0f,
+            0xab | 0xb3 | 0xbb | 0xba | 0xb0 | 0xb1 | 0xc0 | 0xc1,
+            modrm,
+            ..,
+        ] => (true, modrm),
+        [0x0f, 0xc7, modrm, ..] => ((modrm >> 3) & 0b111 == 1, modrm),
+        _ => (false, 0),
+    };
+    lockable.then_some(modrm)
+}
+
+// The cause of the last invalid opcode, for the tests.
+static mut LAST_CAUSE: Option<Cause> = None;
+
+/// Get the likely cause of the last invalid opcode.
+pub fn last_cause() -> Option<Cause> {
+    unsafe { LAST_CAUSE }
+}
+
+/// Report an invalid opcode, with its bytes and likely cause, then have the current task killed, or panic.
+///
+/// # Safety
+/// Must only be called from the invalid opcode handler, in interrupt context.
+pub unsafe fn handle(frame: &InterruptStackFrame) {
+    let instruction = fetch_instruction(frame);
+    let cause = classify(instruction.as_bytes(), Extensions::current());
+    unsafe { LAST_CAUSE = Some(cause) };
+
+    printlnk!("Invalid opcode, bytes at rip: {}", instruction);
+    printlnk!("Likely cause: {}", cause);
+
+    if frame.is_user_mode() {
+        unsafe { isr::handle_user_exception(6, frame, None) };
+        return;
+    }
+    panic!(
+        "Invalid opcode in the kernel, rip = {}",
+        Symbolized(frame.ip)
+    );
+}
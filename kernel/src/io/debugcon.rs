@@ -0,0 +1,56 @@
+//! The debug console of QEMU and Bochs: every byte written to port 0xE9 goes to the host, with nothing to set up and
+//! nothing to wait for.
+//!
+//! That makes it the one output that works from the first instruction of the kernel, and the last one to rely on when
+//! everything else is broken. The console writes to it as well as to the serial port and the framebuffer, and the boot
+//! stages are marked on it before the serial port is up. Reading the port gives 0xE9 when the device is there, so
+//! nothing is written to the port of some other device on real hardware.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use super::port::Port;
+
+/// The port of the debug console.
+pub const PORT: u16 = 0xE9;
+
+const CONSOLE: Port<u8> = unsafe { Port::new(PORT) };
+
+// Whether the device is there, once it was probed
+const UNKNOWN: u8 = 0;
+const PRESENT: u8 = 1;
+const ABSENT: u8 = 2;
+static STATE: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+/// Whether there is a debug console. Probed on the first call.
+pub fn present() -> bool {
+    match STATE.load(Ordering::Relaxed) {
+        UNKNOWN => {
+            let present = CONSOLE.read() == PORT as u8;
+            STATE.store(if present { PRESENT } else { ABSENT }, Ordering::Relaxed);
+            present
+        }
+        state => state == PRESENT,
+    }
+}
+
+/// Writes to the debug console, or nowhere if there is none.
+pub struct DebugCon;
+
+impl fmt::Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if present() {
+            for byte in s.bytes() {
+                CONSOLE.write(byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write to the debug console. Never blocks, and takes no lock, so it can be used from anywhere.
+pub fn print(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut DebugCon, args);
+}
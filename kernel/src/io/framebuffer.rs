@@ -1,179 +0,0 @@
-use bootloader_api::info::{FrameBufferInfo, PixelFormat};
-use core::{fmt, ptr};
-use font_constants::BACKUP_CHAR;
-use noto_sans_mono_bitmap::{
-    FontWeight, RasterHeight, RasterizedChar, get_raster, get_raster_width,
-};
-
-/// Additional vertical space between lines
-const LINE_SPACING: usize = 2;
-/// Additional horizontal space between characters.
-const LETTER_SPACING: usize = 0;
-
-/// Padding from the border. Prevent that font is too close to border.
-const BORDER_PADDING: usize = 1;
-
-/// Constants for the usage of the [`noto_sans_mono_bitmap`] crate.
-mod font_constants {
-    use super::*;
-
-    /// Height of each char raster. The font size is ~0.84% of this. Thus, this is the line height that
-    /// enables multiple characters to be side-by-side and appear optically in one line in a natural way.
-    pub const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size20;
-
-    /// The width of each single symbol of the mono space font.
-    pub const CHAR_RASTER_WIDTH: usize = get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
-
-    /// Backup character if a desired symbol is not available by the font.
-    /// The '�' character requires the feature "unicode-specials".
-    pub const BACKUP_CHAR: char = '�';
-
-    pub const FONT_WEIGHT: FontWeight = FontWeight::Regular;
-}
-
-/// Returns the raster of the given char or the raster of [`font_constants::BACKUP_CHAR`].
-fn get_char_raster(c: char) -> RasterizedChar {
-    fn get(c: char) -> Option<RasterizedChar> {
-        get_raster(
-            c,
-            font_constants::FONT_WEIGHT,
-            font_constants::CHAR_RASTER_HEIGHT,
-        )
-    }
-    get(c).unwrap_or_else(|| get(BACKUP_CHAR).expect("Should get raster of backup char."))
-}
-
-/// Allows logging text to a pixel-based framebuffer.
-pub struct FrameBufferWriter {
-    framebuffer: &'static mut [u8],
-    info: FrameBufferInfo,
-    x_pos: usize,
-    y_pos: usize,
-}
-
-impl FrameBufferWriter {
-    /// Creates a new logger that uses the given framebuffer.
-    pub fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
-        let mut logger = Self {
-            framebuffer,
-            info,
-            x_pos: 0,
-            y_pos: 0,
-        };
-        logger.clear();
-        logger
-    }
-
-    fn newline(&mut self) {
-        self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
-        self.carriage_return()
-    }
-
-    fn carriage_return(&mut self) {
-        self.x_pos = BORDER_PADDING;
-    }
-
-    /// Erases all text on the screen. Resets `self.x_pos` and `self.y_pos`.
-    pub fn clear(&mut self) {
-        self.x_pos = BORDER_PADDING;
-        self.y_pos = BORDER_PADDING;
-        self.framebuffer.fill(0);
-    }
-
-    pub fn scroll_up(&mut self) {
-        let line_height = font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
-        let stride_bytes = self.info.stride * self.info.bytes_per_pixel;
-
-        // Move all lines up by one line
-        for y in 0..(self.info.height - line_height) {
-            let dest_offset = y * stride_bytes;
-            let src_offset = (y + line_height) * stride_bytes;
-            self.framebuffer
-                .copy_within(src_offset..(src_offset + stride_bytes), dest_offset);
-        }
-
-        // Clear the last line
-        let start_clear = (self.info.height - line_height) * stride_bytes;
-        self.framebuffer[start_clear..].fill(0);
-
-        self.y_pos -= line_height;
-    }
-
-    fn width(&self) -> usize {
-        self.info.width
-    }
-
-    fn height(&self) -> usize {
-        self.info.height
-    }
-
-    /// Writes a single char to the framebuffer. Takes care of special control characters, such as
-    /// newlines and carriage returns.
-    fn write_char(&mut self, c: char) {
-        match c {
-            '\n' => self.newline(),
-            '\r' => self.carriage_return(),
-            c => {
-                let new_xpos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
-                if new_xpos >= self.width() {
-                    self.newline();
-                }
-                while self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() >= self.height() {
-                    self.scroll_up();
-                }
-                self.write_rendered_char(get_char_raster(c));
-            }
-        }
-    }
-
-    /// Prints a rendered char into the framebuffer.
-    /// Updates `self.x_pos`.
-    fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
-        for (y, row) in rendered_char.raster().iter().enumerate() {
-            for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(self.x_pos + x, self.y_pos + y, *byte);
-            }
-        }
-        self.x_pos += rendered_char.width() + LETTER_SPACING;
-    }
-
-    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
-        let pixel_offset = y * self.info.stride + x;
-        let color = match self.info.pixel_format {
-            // PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
-            // PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
-            // PixelFormat::U8 => [if intensity > 200 { 0xf } else { 0 }, 0, 0, 0],
-            PixelFormat::Rgb => [intensity, intensity, intensity, 0],
-            PixelFormat::Bgr => [intensity, intensity, intensity, 0],
-            PixelFormat::U8 => [
-                if intensity > 200 { 0xf } else { 0 },
-                if intensity > 200 { 0xf } else { 0 },
-                if intensity > 200 { 0xf } else { 0 },
-                0,
-            ],
-            other => {
-                // set a supported (but invalid) pixel format before panicking to avoid a double
-                // panic; it might not be readable though
-                self.info.pixel_format = PixelFormat::Rgb;
-                panic!("pixel format {:?} not supported in logger", other)
-            }
-        };
-        let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * bytes_per_pixel;
-        self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
-            .copy_from_slice(&color[..bytes_per_pixel]);
-        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
-    }
-}
-
-unsafe impl Send for FrameBufferWriter {}
-unsafe impl Sync for FrameBufferWriter {}
-
-impl fmt::Write for FrameBufferWriter {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            self.write_char(c);
-        }
-        Ok(())
-    }
-}
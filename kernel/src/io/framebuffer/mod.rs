@@ -0,0 +1,473 @@
+//! The framebuffer console.
+//!
+//! Text is drawn with the font picked when the kernel is built: the PSF font in the ELYTRA_FONT environment variable,
+//! which the build script embeds, or else the anti-aliased font of the noto-sans-mono-bitmap crate.
+
+pub mod psf;
+
+use alloc::{string::String, vec, vec::Vec};
+use bootloader_api::info::{FrameBufferInfo, PixelFormat};
+use core::{fmt, mem, ops::Range, ptr};
+use font_constants::BACKUP_CHAR;
+use noto_sans_mono_bitmap::{
+    FontWeight, RasterHeight, RasterizedChar, get_raster, get_raster_width,
+};
+
+use crate::{include_bytes_align_as, mem::buddy::SIZE_OF_MAX_ORDER};
+use psf::{PsfError, PsfFont};
+
+// The font ELYTRA_FONT picked, or nothing
+static EMBEDDED_FONT: &[u8] = include_bytes_align_as!(u32, concat!(env!("OUT_DIR"), "/font.psf"));
+
+/// Additional vertical space between lines
+const LINE_SPACING: usize = 2;
+/// Additional horizontal space between characters.
+const LETTER_SPACING: usize = 0;
+
+/// Padding from the border. Prevent that font is too close to border.
+const BORDER_PADDING: usize = 1;
+
+/// Constants for the usage of the [`noto_sans_mono_bitmap`] crate.
+mod font_constants {
+    use super::*;
+
+    /// Height of each char raster. The font size is ~0.84% of this. Thus, this is the line height that
+    /// enables multiple characters to be side-by-side and appear optically in one line in a natural way.
+    pub const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size20;
+
+    /// The width of each single symbol of the mono space font.
+    pub const CHAR_RASTER_WIDTH: usize = get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
+
+    /// Backup character if a desired symbol is not available by the font.
+    /// The '�' character requires the feature "unicode-specials".
+    pub const BACKUP_CHAR: char = '�';
+
+    pub const FONT_WEIGHT: FontWeight = FontWeight::Regular;
+}
+
+/// Returns the raster of the given char or the raster of [`font_constants::BACKUP_CHAR`].
+fn get_char_raster(c: char) -> RasterizedChar {
+    fn get(c: char) -> Option<RasterizedChar> {
+        get_raster(
+            c,
+            font_constants::FONT_WEIGHT,
+            font_constants::CHAR_RASTER_HEIGHT,
+        )
+    }
+    get(c).unwrap_or_else(|| get(BACKUP_CHAR).expect("Should get raster of backup char."))
+}
+
+/// Tab stops are this many columns apart.
+const TAB_WIDTH: usize = 8;
+
+// Glyphs the atlas keeps. Any more are drawn a pixel at a time.
+const ATLAS_GLYPHS: usize = 256;
+
+/// A font to draw text with.
+#[derive(Debug, Clone, Copy)]
+pub enum Font {
+    /// The font of the noto-sans-mono-bitmap crate.
+    Noto,
+    Psf(PsfFont<'static>),
+}
+
+impl Font {
+    /// The font picked at build time: the PSF font ELYTRA_FONT names, or Noto if it is unset. Fails if the font
+    /// isn't a PSF font.
+    pub fn embedded() -> Result<Font, PsfError> {
+        if EMBEDDED_FONT.is_empty() {
+            return Ok(Font::Noto);
+        }
+        PsfFont::parse(EMBEDDED_FONT).map(Font::Psf)
+    }
+
+    /// Width of every char, in pixels.
+    pub fn width(&self) -> usize {
+        match self {
+            Font::Noto => font_constants::CHAR_RASTER_WIDTH,
+            Font::Psf(font) => font.width(),
+        }
+    }
+
+    /// Height of every char, in pixels.
+    pub fn height(&self) -> usize {
+        match self {
+            Font::Noto => font_constants::CHAR_RASTER_HEIGHT.val(),
+            Font::Psf(font) => font.height(),
+        }
+    }
+
+    // Call pixel with the position and intensity of each pixel of c, or of the fallback char if the font has none.
+    fn draw(&self, c: char, mut pixel: impl FnMut(usize, usize, u8)) {
+        match self {
+            Font::Noto => {
+                for (y, row) in get_char_raster(c).raster().iter().enumerate() {
+                    for (x, intensity) in row.iter().enumerate() {
+                        pixel(x, y, *intensity);
+                    }
+                }
+            }
+            Font::Psf(font) => {
+                let glyph = font.glyph_or_fallback(c);
+                for y in 0..glyph.height() {
+                    for x in 0..glyph.width() {
+                        pixel(x, y, if glyph.is_set(x, y) { 0xff } else { 0 });
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The color of a pixel of text in the pixel format, as bytes, or None if the format isn't supported.
+fn color(pixel_format: PixelFormat, intensity: u8) -> Option<[u8; 4]> {
+    match pixel_format {
+        // PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
+        // PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
+        // PixelFormat::U8 => [if intensity > 200 { 0xf } else { 0 }, 0, 0, 0],
+        PixelFormat::Rgb => Some([intensity, intensity, intensity, 0]),
+        PixelFormat::Bgr => Some([intensity, intensity, intensity, 0]),
+        PixelFormat::U8 => Some([
+            if intensity > 200 { 0xf } else { 0 },
+            if intensity > 200 { 0xf } else { 0 },
+            if intensity > 200 { 0xf } else { 0 },
+            0,
+        ]),
+        _ => None,
+    }
+}
+
+/// Glyphs drawn once in the pixel format of the framebuffer, so drawing them again is a copy of each row.
+struct GlyphAtlas {
+    slots: Vec<(char, usize)>, // Sorted by char
+    pixels: Vec<u8>,           // The glyph of each slot, in the order they were drawn
+    glyph_bytes: usize,
+}
+
+impl GlyphAtlas {
+    fn new(font: &Font, bytes_per_pixel: usize) -> Self {
+        let glyph_bytes = font.width() * font.height() * bytes_per_pixel;
+        GlyphAtlas {
+            slots: Vec::with_capacity(ATLAS_GLYPHS),
+            pixels: vec![0; ATLAS_GLYPHS * glyph_bytes],
+            glyph_bytes,
+        }
+    }
+
+    // The pixels of the glyph of c, drawn first if it isn't in the atlas yet. None if the atlas is full. Doesn't
+    // allocate, so it can be used while printing anything.
+    fn glyph(&mut self, c: char, font: &Font, info: &FrameBufferInfo) -> Option<&[u8]> {
+        let slot = match self.slots.binary_search_by_key(&c, |&(c, _)| c) {
+            Ok(i) => self.slots[i].1,
+            Err(_) if self.slots.len() == ATLAS_GLYPHS => return None,
+            Err(i) => {
+                let slot = self.slots.len();
+                self.slots.insert(i, (c, slot));
+                let pixels =
+                    &mut self.pixels[slot * self.glyph_bytes..(slot + 1) * self.glyph_bytes];
+                let bytes_per_pixel = info.bytes_per_pixel;
+                font.draw(c, |x, y, intensity| {
+                    let offset = (y * font.width() + x) * bytes_per_pixel;
+                    let color = color(info.pixel_format, intensity).unwrap();
+                    pixels[offset..offset + bytes_per_pixel]
+                        .copy_from_slice(&color[..bytes_per_pixel]);
+                });
+                slot
+            }
+        };
+        Some(&self.pixels[slot * self.glyph_bytes..(slot + 1) * self.glyph_bytes])
+    }
+}
+
+/// Allows logging text to a pixel-based framebuffer, as a console of text rows and columns.
+///
+/// The framebuffer is slow to read, so scrolling it in place is expensive. Once the heap is up, text is drawn to a back
+/// buffer in normal memory instead, and the rows that changed are copied to the framebuffer after every write. The
+/// text of every cell is kept next to the back buffer, and the glyphs drawn are kept in an atlas.
+pub struct FrameBufferWriter {
+    framebuffer: &'static mut [u8],
+    info: FrameBufferInfo,
+    font: Font,
+    row_height: usize,   // With the spacing below
+    column_width: usize, // With the spacing after
+    back_buffer: Option<Vec<u8>>,
+    atlas: Option<GlyphAtlas>,
+    cells: Vec<char>,
+    rows: usize,
+    columns: usize,
+    row: usize,
+    column: usize,
+    // Pixel rows of the back buffer that the framebuffer doesn't have yet
+    dirty: Range<usize>,
+}
+
+impl FrameBufferWriter {
+    /// Creates a new logger that uses the given framebuffer, and draws text with the font.
+    pub fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo, font: Font) -> Self {
+        let row_height = font.height() + LINE_SPACING;
+        let column_width = font.width() + LETTER_SPACING;
+        let mut logger = Self {
+            framebuffer,
+            info,
+            font,
+            row_height,
+            column_width,
+            back_buffer: None,
+            atlas: None,
+            cells: Vec::new(),
+            rows: (info.height.saturating_sub(2 * BORDER_PADDING) / row_height).max(1),
+            columns: (info.width.saturating_sub(2 * BORDER_PADDING) / column_width).max(1),
+            row: 0,
+            column: 0,
+            dirty: 0..0,
+        };
+        logger.clear();
+        logger
+    }
+
+    /// Draw to a back buffer from now on, and keep the text. Needs the heap. Returns false if the framebuffer is too
+    /// large to allocate one, in which case text is still drawn straight to the framebuffer.
+    pub fn enable_back_buffer(&mut self) -> bool {
+        if self.back_buffer.is_some() {
+            return true;
+        }
+        if self.framebuffer.len() > SIZE_OF_MAX_ORDER {
+            return false;
+        }
+
+        // Start from what is on the screen, as the text before now isn't known
+        self.back_buffer = Some(self.framebuffer.to_vec());
+        self.cells = vec![' '; self.rows * self.columns];
+        let atlas_bytes =
+            ATLAS_GLYPHS * self.font.width() * self.font.height() * self.info.bytes_per_pixel;
+        if color(self.info.pixel_format, 0).is_some() && atlas_bytes <= SIZE_OF_MAX_ORDER {
+            self.atlas = Some(GlyphAtlas::new(&self.font, self.info.bytes_per_pixel));
+        }
+        true
+    }
+
+    /// The back buffer, in the pixel format of the framebuffer, or None if text is drawn straight to the framebuffer.
+    pub fn back_buffer(&self) -> Option<&[u8]> {
+        self.back_buffer.as_deref()
+    }
+
+    /// The font text is drawn with.
+    pub fn font(&self) -> Font {
+        self.font
+    }
+
+    /// Number of text rows on the screen.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Number of text columns on the screen.
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// The row and column the next character goes to. After a full line, the column is one past the last, until the
+    /// next character wraps.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    /// The text of a row, without trailing spaces, or None if there is no back buffer to keep it.
+    pub fn row_text(&self, row: usize) -> Option<String> {
+        let cells = self
+            .cells
+            .get(row * self.columns..(row + 1) * self.columns)?;
+        Some(cells.iter().collect::<String>().trim_end().into())
+    }
+
+    fn newline(&mut self) {
+        self.carriage_return();
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll_up();
+        }
+    }
+
+    fn carriage_return(&mut self) {
+        self.column = 0;
+    }
+
+    /// Erases all text on the screen, and moves the cursor to the top left.
+    pub fn clear(&mut self) {
+        self.row = 0;
+        self.column = 0;
+        self.pixels().fill(0);
+        self.cells.fill(' ');
+        self.mark_dirty(0..self.info.height);
+        self.flush();
+    }
+
+    /// Moves every text row up by one, and clears the last one. The cursor stays on its row.
+    pub fn scroll_up(&mut self) {
+        let stride_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let top = BORDER_PADDING * stride_bytes;
+        let row_bytes = self.row_height * stride_bytes;
+        let last_row = top + (self.rows - 1) * row_bytes;
+        let bottom = top + self.rows * row_bytes;
+
+        let pixels = self.pixels();
+        pixels.copy_within(top + row_bytes..bottom, top);
+        pixels[last_row..bottom].fill(0);
+
+        if !self.cells.is_empty() {
+            self.cells.copy_within(self.columns.., 0);
+            let last = (self.rows - 1) * self.columns;
+            self.cells[last..].fill(' ');
+        }
+        self.mark_dirty(BORDER_PADDING..BORDER_PADDING + self.rows * self.row_height);
+    }
+
+    /// Copies the rows of the back buffer that changed to the framebuffer.
+    pub fn flush(&mut self) {
+        let dirty = mem::replace(&mut self.dirty, 0..0);
+        let Some(back_buffer) = &self.back_buffer else {
+            return;
+        };
+
+        let stride_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let bytes = dirty.start * stride_bytes..(dirty.end * stride_bytes).min(back_buffer.len());
+        if bytes.is_empty() {
+            return;
+        }
+        self.framebuffer[bytes.clone()].copy_from_slice(&back_buffer[bytes.clone()]);
+        let _ = unsafe { ptr::read_volatile(&self.framebuffer[bytes.start]) };
+    }
+
+    /// Geometry and pixel format of the framebuffer.
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
+
+    /// Copy pixels, in the pixel format of the framebuffer, to the row y starting at column x.
+    /// Panics if they don't fit in the row.
+    pub fn write_row(&mut self, x: usize, y: usize, pixels: &[u8]) {
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        assert!(y < self.info.height && pixels.len().is_multiple_of(bytes_per_pixel));
+        assert!(x + pixels.len() / bytes_per_pixel <= self.info.width);
+
+        let offset = (y * self.info.stride + x) * bytes_per_pixel;
+        self.framebuffer[offset..offset + pixels.len()].copy_from_slice(pixels);
+        if let Some(back_buffer) = &mut self.back_buffer {
+            back_buffer[offset..offset + pixels.len()].copy_from_slice(pixels);
+        }
+    }
+
+    // The pixels text is drawn to: the back buffer, or the framebuffer if there is none.
+    fn pixels(&mut self) -> &mut [u8] {
+        match &mut self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.framebuffer,
+        }
+    }
+
+    // Remember that the pixel rows changed, for the next flush.
+    fn mark_dirty(&mut self, rows: Range<usize>) {
+        if self.dirty.is_empty() {
+            self.dirty = rows;
+        } else {
+            self.dirty = self.dirty.start.min(rows.start)..self.dirty.end.max(rows.end);
+        }
+    }
+
+    /// Writes a single char to the framebuffer. Takes care of control characters: newlines, carriage returns, tabs
+    /// and backspaces. Lines longer than the screen wrap.
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.carriage_return(),
+            '\t' => {
+                let stop = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                if stop >= self.columns {
+                    self.newline();
+                } else {
+                    while self.column < stop {
+                        self.put_char(' ');
+                    }
+                }
+            }
+            // Backspace erases the character before the cursor
+            '\x08' => {
+                if self.column > 0 {
+                    self.column = self.column.min(self.columns) - 1;
+                    self.put_char(' ');
+                    self.column -= 1;
+                }
+            }
+            c => {
+                if self.column >= self.columns {
+                    self.newline();
+                }
+                self.put_char(c);
+            }
+        }
+    }
+
+    // Draws the char in the cell of the cursor, and moves the cursor right.
+    fn put_char(&mut self, c: char) {
+        if let Some(cell) = self.cells.get_mut(self.row * self.columns + self.column) {
+            *cell = c;
+        }
+
+        let x = BORDER_PADDING + self.column * self.column_width;
+        let y = BORDER_PADDING + self.row * self.row_height;
+        self.write_rendered_char(x, y, c);
+        self.mark_dirty(y..y + self.row_height);
+        self.column += 1;
+    }
+
+    /// Prints the glyph of c into the cell with its top left corner at (x, y): a copy from the atlas, or else a pixel
+    /// at a time.
+    fn write_rendered_char(&mut self, x: usize, y: usize, c: char) {
+        let info = self.info;
+        if let (Some(atlas), Some(back_buffer)) = (&mut self.atlas, &mut self.back_buffer)
+            && let Some(glyph) = atlas.glyph(c, &self.font, &info)
+        {
+            let glyph_row = self.font.width() * info.bytes_per_pixel;
+            for (dy, row) in glyph.chunks_exact(glyph_row).enumerate() {
+                let offset = ((y + dy) * info.stride + x) * info.bytes_per_pixel;
+                back_buffer[offset..offset + glyph_row].copy_from_slice(row);
+            }
+            return;
+        }
+
+        let font = self.font;
+        font.draw(c, |dx, dy, intensity| {
+            self.write_pixel(x + dx, y + dy, intensity)
+        });
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        let pixel_offset = y * self.info.stride + x;
+        let Some(color) = color(self.info.pixel_format, intensity) else {
+            // set a supported (but invalid) pixel format before panicking to avoid a double
+            // panic; it might not be readable though
+            let other = mem::replace(&mut self.info.pixel_format, PixelFormat::Rgb);
+            panic!("pixel format {:?} not supported in logger", other)
+        };
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let byte_offset = pixel_offset * bytes_per_pixel;
+        let pixels = self.pixels();
+        pixels[byte_offset..(byte_offset + bytes_per_pixel)]
+            .copy_from_slice(&color[..bytes_per_pixel]);
+        let _ = unsafe { ptr::read_volatile(&pixels[byte_offset]) };
+    }
+}
+
+unsafe impl Send for FrameBufferWriter {}
+unsafe impl Sync for FrameBufferWriter {}
+
+impl fmt::Write for FrameBufferWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+        self.flush();
+        Ok(())
+    }
+}
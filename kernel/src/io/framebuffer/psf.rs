@@ -0,0 +1,243 @@
+//! PC Screen Fonts, the bitmap fonts of the Linux console, in either version.
+//!
+//! A PSF1 font has 256 or 512 glyphs, 8 pixels wide. A PSF2 font has any number of glyphs of any size, and its header
+//! says where they start. Each glyph is a bitmap of rows, each padded to whole bytes, with the leftmost pixel in the
+//! top bit.
+//!
+//! The glyphs may be followed by a Unicode table, which lists the chars each glyph draws, glyph by glyph: u16 values
+//! ending with 0xffff in PSF1, and UTF-8 ending with 0xff in PSF2. A glyph can also draw sequences of chars, e.g. a
+//! letter and a combining accent, which come after a 0xfffe or 0xfe separator, and are skipped. A font without a table
+//! draws the char with the number of each glyph.
+
+use core::str;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_MODE_512: u8 = 1 << 0; // 512 glyphs rather than 256
+const PSF1_MODE_HAS_TABLE: u8 = 1 << 1;
+const PSF1_MODE_HAS_SEQUENCES: u8 = 1 << 2; // Also has a table
+const PSF1_SEPARATOR: u16 = 0xfffe;
+const PSF1_END: u16 = 0xffff;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_HAS_TABLE: u32 = 1 << 0;
+const PSF2_SEPARATOR: u8 = 0xfe;
+const PSF2_END: u8 = 0xff;
+
+// Chars drawn in place of one the font has no glyph for, the first it has
+const FALLBACK_CHARS: [char; 3] = ['\u{fffd}', '?', ' '];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    BadMagic,
+    BadHeader, // Sizes that don't make sense
+    Truncated, // Shorter than its glyphs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Table {
+    Psf1,
+    Psf2,
+}
+
+/// A PSF font, read in place from its bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct PsfFont<'a> {
+    glyphs: &'a [u8],
+    glyph_count: usize,
+    glyph_bytes: usize,
+    width: usize,
+    height: usize,
+    table: Option<(Table, &'a [u8])>,
+}
+
+/// The bitmap of one glyph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Glyph<'a> {
+    bitmap: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl<'a> PsfFont<'a> {
+    /// Read the header of a PSF1 or PSF2 font, and check that its glyphs are all there.
+    pub fn parse(data: &'a [u8]) -> Result<Self, PsfError> {
+        if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)
+        } else {
+            Err(PsfError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(data: &'a [u8]) -> Result<Self, PsfError> {
+        let (mode, height) = match data {
+            [_, _, mode, height, ..] => (*mode, *height as usize),
+            _ => return Err(PsfError::Truncated),
+        };
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let table =
+            (mode & (PSF1_MODE_HAS_TABLE | PSF1_MODE_HAS_SEQUENCES) != 0).then_some(Table::Psf1);
+        Self::new(
+            data,
+            PSF1_HEADER_SIZE,
+            glyph_count,
+            height,
+            8,
+            height,
+            table,
+        )
+    }
+
+    fn parse_psf2(data: &'a [u8]) -> Result<Self, PsfError> {
+        if data.len() < PSF2_HEADER_SIZE {
+            return Err(PsfError::Truncated);
+        }
+        let field =
+            |i: usize| u32::from_le_bytes(data[4 * i..4 * i + 4].try_into().unwrap()) as usize;
+        let (header_size, flags, glyph_count) = (field(2), field(3) as u32, field(4));
+        let (glyph_bytes, height, width) = (field(5), field(6), field(7));
+        if header_size < PSF2_HEADER_SIZE || glyph_bytes != width.div_ceil(8) * height {
+            return Err(PsfError::BadHeader);
+        }
+        let table = (flags & PSF2_HAS_TABLE != 0).then_some(Table::Psf2);
+        Self::new(
+            data,
+            header_size,
+            glyph_count,
+            glyph_bytes,
+            width,
+            height,
+            table,
+        )
+    }
+
+    fn new(
+        data: &'a [u8],
+        start: usize,
+        glyph_count: usize,
+        glyph_bytes: usize,
+        width: usize,
+        height: usize,
+        table: Option<Table>,
+    ) -> Result<Self, PsfError> {
+        if glyph_count == 0 || width == 0 || height == 0 {
+            return Err(PsfError::BadHeader);
+        }
+        let end = glyph_count
+            .checked_mul(glyph_bytes)
+            .and_then(|len| len.checked_add(start))
+            .ok_or(PsfError::BadHeader)?;
+        if data.len() < end {
+            return Err(PsfError::Truncated);
+        }
+        Ok(PsfFont {
+            glyphs: &data[start..end],
+            glyph_count,
+            glyph_bytes,
+            width,
+            height,
+            table: table.map(|kind| (kind, &data[end..])),
+        })
+    }
+
+    /// Width of every glyph, in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height of every glyph, in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+
+    pub fn has_unicode_table(&self) -> bool {
+        self.table.is_some()
+    }
+
+    /// The glyph with the number.
+    pub fn glyph(&self, index: usize) -> Option<Glyph<'a>> {
+        if index >= self.glyph_count {
+            return None;
+        }
+        Some(Glyph {
+            bitmap: &self.glyphs[index * self.glyph_bytes..(index + 1) * self.glyph_bytes],
+            width: self.width,
+            height: self.height,
+        })
+    }
+
+    /// The number of the glyph that draws c, from the Unicode table if the font has one. The table is searched from
+    /// the start every time, so callers that draw a lot of text should keep what they find.
+    pub fn glyph_index(&self, c: char) -> Option<usize> {
+        let Some((kind, table)) = self.table else {
+            return Some(c as usize).filter(|&index| index < self.glyph_count);
+        };
+        match kind {
+            Table::Psf1 => psf1_lookup(table, c),
+            Table::Psf2 => psf2_lookup(table, c),
+        }
+        .filter(|&index| index < self.glyph_count)
+    }
+
+    /// The glyph that draws c, or the first of U+FFFD, '?' and ' ' the font has, or else its first glyph.
+    pub fn glyph_or_fallback(&self, c: char) -> Glyph<'a> {
+        let index = self
+            .glyph_index(c)
+            .or_else(|| FALLBACK_CHARS.iter().find_map(|&c| self.glyph_index(c)))
+            .unwrap_or(0);
+        self.glyph(index).unwrap()
+    }
+}
+
+// Find c in a PSF1 table. The table may be cut short, in which case the glyphs after it draw nothing.
+fn psf1_lookup(table: &[u8], c: char) -> Option<usize> {
+    let mut index = 0;
+    let mut in_sequence = false;
+    for &value in table.as_chunks::<2>().0 {
+        match u16::from_le_bytes(value) {
+            PSF1_END => {
+                index += 1;
+                in_sequence = false;
+            }
+            PSF1_SEPARATOR => in_sequence = true,
+            value if !in_sequence && value as u32 == c as u32 => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Find c in a PSF2 table. Bad UTF-8 in the entry of a glyph is skipped.
+fn psf2_lookup(table: &[u8], c: char) -> Option<usize> {
+    for (index, entry) in table.split(|&byte| byte == PSF2_END).enumerate() {
+        // Only the chars before the first separator are drawn alone
+        let chars = entry.split(|&byte| byte == PSF2_SEPARATOR).next().unwrap();
+        if str::from_utf8(chars).is_ok_and(|chars| chars.contains(c)) {
+            return Some(index);
+        }
+    }
+    None
+}
+
+impl Glyph<'_> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Whether the pixel at (x, y) is drawn.
+    pub fn is_set(&self, x: usize, y: usize) -> bool {
+        let row_bytes = self.width.div_ceil(8);
+        self.bitmap[y * row_bytes + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
@@ -0,0 +1,224 @@
+//! QEMU's firmware configuration device, fw_cfg, which passes files to the guest: `-fw_cfg name=opt/...,file=...`.
+//!
+//! Each item has a 16-bit key. Writing the key to the selector port selects the item, and its bytes are then read one
+//! at a time from the data port. Newer QEMUs also have a DMA interface: the physical address of a request, which says
+//! what to read and where to, is written to the DMA address register, and QEMU has done the request by the time the
+//! write returns. Files are items too, found by name in the file directory, which is item FILE_DIR.
+//!
+//! Items are read with interrupts disabled, as the selected item and the offset in it are global. DMA goes through a
+//! page the kernel keeps for it, a page at a time.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{Ordering, fence},
+};
+
+use crate::{
+    consts::PAGE_SIZE,
+    helper::v2p,
+    idt::without_interrupt,
+    io::port::{Port, PortWriteOnly},
+    mem::buddy,
+    printlnk,
+};
+
+const SELECTOR: PortWriteOnly<u16> = unsafe { PortWriteOnly::new(0x510) };
+const DATA: Port<u8> = unsafe { Port::new(0x511) };
+// The DMA address register, big endian: the high half, then the low half, which starts the request when written
+const DMA_ADDRESS_HIGH: PortWriteOnly<u32> = unsafe { PortWriteOnly::new(0x514) };
+const DMA_ADDRESS_LOW: PortWriteOnly<u32> = unsafe { PortWriteOnly::new(0x518) };
+
+// Keys of the items every fw_cfg device has
+const KEY_SIGNATURE: u16 = 0x0000;
+const KEY_ID: u16 = 0x0001; // Feature bits
+const KEY_FILE_DIR: u16 = 0x0019;
+
+const SIGNATURE: &[u8; 4] = b"QEMU";
+const FEATURE_DMA: u32 = 1 << 1;
+
+// Bits of the control field of a DMA request. The device clears them all once it is done, but ERROR.
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_READ: u32 = 1 << 1;
+const DMA_SELECT: u32 = 1 << 3; // Select the item in the top 16 bits first
+
+// An entry of the file directory, after its count: size, key, 2 reserved bytes, then the name padded with NULs, all
+// big endian
+const FILE_ENTRY_SIZE: usize = 64;
+const FILE_NAME_OFFSET: usize = 8;
+
+/// A file fw_cfg has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FwCfgFile {
+    pub name: String,
+    pub size: usize,
+    pub key: u16,
+}
+
+// A DMA request, big endian.
+#[repr(C)]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+#[derive(Debug)]
+struct FwCfg {
+    // With DMA, the request, then the page data is read to
+    dma_pages: Option<*mut u8>,
+}
+
+// The device, once init() found it. Only used with interrupts disabled.
+static mut FW_CFG: Option<FwCfg> = None;
+
+/// Look for the fw_cfg device, and for whether it has the DMA interface.
+///
+/// # Safety
+/// Must be called once, after the buddy allocator is up.
+pub unsafe fn init() {
+    let mut signature = [0; 4];
+    read_ports(KEY_SIGNATURE, &mut signature);
+    if &signature != SIGNATURE {
+        return;
+    }
+
+    let mut id = [0; 4];
+    read_ports(KEY_ID, &mut id);
+    let dma = u32::from_le_bytes(id) & FEATURE_DMA != 0;
+    let dma_pages = dma.then(|| unsafe { buddy::alloc_pages_panic(2) });
+    unsafe { FW_CFG = Some(FwCfg { dma_pages }) };
+    printlnk!(
+        "fw_cfg: {} files{}",
+        files().len(),
+        if dma { ", DMA" } else { "" }
+    );
+}
+
+/// Whether QEMU's fw_cfg device is there.
+pub fn present() -> bool {
+    without_interrupt(|| unsafe { FW_CFG.is_some() })
+}
+
+/// Whether the fw_cfg device has the DMA interface.
+pub fn has_dma() -> bool {
+    without_interrupt(|| unsafe {
+        FW_CFG
+            .as_ref()
+            .is_some_and(|fw_cfg| fw_cfg.dma_pages.is_some())
+    })
+}
+
+/// Parse the file directory: a big endian count of files, then an entry for each. Entries past the end are dropped.
+pub fn parse_directory(directory: &[u8]) -> Vec<FwCfgFile> {
+    let Some(count) = directory.first_chunk::<4>() else {
+        return Vec::new();
+    };
+    directory[4..]
+        .as_chunks::<FILE_ENTRY_SIZE>()
+        .0
+        .iter()
+        .take(u32::from_be_bytes(*count) as usize)
+        .map(|entry| {
+            let name = &entry[FILE_NAME_OFFSET..];
+            let len = name
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(name.len());
+            FwCfgFile {
+                name: String::from_utf8_lossy(&name[..len]).into(),
+                size: u32::from_be_bytes(entry[0..4].try_into().unwrap()) as usize,
+                key: u16::from_be_bytes([entry[4], entry[5]]),
+            }
+        })
+        .collect()
+}
+
+/// The files fw_cfg has, or none without the device.
+pub fn files() -> Vec<FwCfgFile> {
+    let Some(count) = read_item(KEY_FILE_DIR, 4, true) else {
+        return Vec::new();
+    };
+    let count = u32::from_be_bytes(count.try_into().unwrap()) as usize;
+    let directory = read_item(KEY_FILE_DIR, 4 + count * FILE_ENTRY_SIZE, true).unwrap();
+    parse_directory(&directory)
+}
+
+/// Read the file with the name, e.g. "opt/elytra/init", through DMA if the device has it.
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    let file = files().into_iter().find(|file| file.name == name)?;
+    read_item(file.key, file.size, true)
+}
+
+/// Read the file with the name through the data port, even if the device has DMA.
+pub fn read_file_ports(name: &str) -> Option<Vec<u8>> {
+    let file = files().into_iter().find(|file| file.name == name)?;
+    read_item(file.key, file.size, false)
+}
+
+// Read the first len bytes of the item, through DMA if dma is set and the device has it. None without the device, or
+// if DMA fails.
+fn read_item(key: u16, len: usize, dma: bool) -> Option<Vec<u8>> {
+    let mut buf = vec![0; len];
+    without_interrupt(|| {
+        let fw_cfg = unsafe { FW_CFG.as_ref() }?;
+        match fw_cfg.dma_pages.filter(|_| dma) {
+            Some(pages) => read_dma(pages, key, &mut buf).then_some(()),
+            None => {
+                read_ports(key, &mut buf);
+                Some(())
+            }
+        }
+    })?;
+    Some(buf)
+}
+
+// Fill buf from the start of the item, a byte at a time.
+fn read_ports(key: u16, buf: &mut [u8]) {
+    SELECTOR.write(key);
+    for byte in buf {
+        *byte = DATA.read();
+    }
+}
+
+// Fill buf from the start of the item, a page at a time through the DMA pages. Returns false if the device failed.
+fn read_dma(pages: *mut u8, key: u16, buf: &mut [u8]) -> bool {
+    let request = pages as *mut DmaAccess;
+    let data = unsafe { pages.add(PAGE_SIZE) };
+    for (i, chunk) in buf.chunks_mut(PAGE_SIZE).enumerate() {
+        // The first request selects the item, and each one goes on where the last one stopped
+        let select = if i == 0 {
+            (key as u32) << 16 | DMA_SELECT
+        } else {
+            0
+        };
+        unsafe {
+            ptr::write_volatile(
+                request,
+                DmaAccess {
+                    control: (select | DMA_READ).to_be(),
+                    length: (chunk.len() as u32).to_be(),
+                    address: (v2p(data as usize) as u64).to_be(),
+                },
+            );
+        }
+        fence(Ordering::SeqCst);
+
+        let address = v2p(request as usize) as u64;
+        DMA_ADDRESS_HIGH.write(((address >> 32) as u32).to_be());
+        DMA_ADDRESS_LOW.write((address as u32).to_be());
+        let control = loop {
+            let control =
+                u32::from_be(unsafe { ptr::read_volatile(&raw const (*request).control) });
+            if control & !DMA_ERROR == 0 {
+                break control;
+            }
+        };
+        fence(Ordering::SeqCst);
+        if control & DMA_ERROR != 0 {
+            return false;
+        }
+        unsafe { ptr::copy_nonoverlapping(data, chunk.as_mut_ptr(), chunk.len()) };
+    }
+    true
+}
@@ -0,0 +1,318 @@
+//! The kernel log: a ring buffer of the lines the kernel printed, each with a level and the time since boot.
+//!
+//! Every printk goes to the log as well as the console, from the first one on, so lines printed before the framebuffer
+//! was up can still be read back with read(), dump() or sys_dmesg. Lines are stored as records of a header and the
+//! text. When the ring is full, the oldest records are dropped whole to make room, and a record never ends up half
+//! overwritten. Text printed without a newline is held until the line ends, so a line printed in pieces is one record.
+//!
+//! Only lines at or below the console level reach the console. The loglevel option of the kernel command line sets
+//! it, to a name or a number: error (0), warn (1), info (2, the default) or debug (3).
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{idt::without_interrupt, time};
+
+/// Size of the kernel log, in bytes.
+pub const LOG_SIZE: usize = 64 * 1024;
+/// The longest line a record holds. Longer lines are split.
+pub const MAX_LINE: usize = 256;
+
+// A record is the length of the text (u16), the level, a reserved byte and the timestamp (u64), then the text
+const HEADER_LEN: usize = 12;
+
+const NS_PER_US: u64 = 1000;
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2, // printk
+    Debug = 3,
+}
+
+impl Level {
+    const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+    /// Parse a level from its name or number, e.g. "warn" or "1".
+    pub fn parse(value: &str) -> Option<Level> {
+        Self::ALL
+            .into_iter()
+            .find(|level| value == level.name() || value.parse() == Ok(*level as u8))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    fn from_u8(value: u8) -> Level {
+        Self::ALL[(value as usize).min(Self::ALL.len() - 1)]
+    }
+}
+
+/// A line of the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub level: Level,
+    pub timestamp_ns: u64, // As time::monotonic_ns()
+    pub text: &'a str,     // Without the newline
+}
+
+/// Writes the record as a line, e.g. "[    1.234567] warn: text". Info lines have no level.
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] ",
+            self.timestamp_ns / NS_PER_SEC,
+            self.timestamp_ns % NS_PER_SEC / NS_PER_US
+        )?;
+        if self.level != Level::Info {
+            write!(f, "{}: ", self.level.name())?;
+        }
+        writeln!(f, "{}", self.text)
+    }
+}
+
+/// A ring buffer of N bytes of records.
+pub struct Ring<const N: usize> {
+    bytes: [u8; N],
+    // Positions of the oldest record, and of where the next one goes. They only grow, and index bytes modulo N.
+    first: usize,
+    next: usize,
+    dropped: usize,
+}
+
+impl<const N: usize> Default for Ring<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Ring<N> {
+    pub const fn new() -> Self {
+        const { assert!(N >= HEADER_LEN + MAX_LINE) };
+        Ring {
+            bytes: [0; N],
+            first: 0,
+            next: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Append a record, dropping the oldest ones until it fits. Text past MAX_LINE is cut off.
+    pub fn push(&mut self, level: Level, timestamp_ns: u64, text: &str) {
+        let mut text_len = text.len().min(MAX_LINE);
+        while !text.is_char_boundary(text_len) {
+            text_len -= 1;
+        }
+        let len = HEADER_LEN + text_len;
+
+        while self.next + len - self.first > N {
+            self.first += HEADER_LEN + self.text_len(self.first);
+            self.dropped += 1;
+        }
+
+        let mut header = [0; HEADER_LEN];
+        header[..2].copy_from_slice(&(text_len as u16).to_le_bytes());
+        header[2] = level as u8;
+        header[4..].copy_from_slice(&timestamp_ns.to_le_bytes());
+        self.write_at(self.next, &header);
+        self.write_at(self.next + HEADER_LEN, &text.as_bytes()[..text_len]);
+        self.next += len;
+    }
+
+    /// Call f with each record, oldest first.
+    pub fn for_each(&self, mut f: impl FnMut(Record)) {
+        let mut pos = self.first;
+        let mut text = [0; MAX_LINE];
+        while pos < self.next {
+            let mut header = [0; HEADER_LEN];
+            self.read_at(pos, &mut header);
+            let text_len = self.text_len(pos);
+            self.read_at(pos + HEADER_LEN, &mut text[..text_len]);
+
+            f(Record {
+                level: Level::from_u8(header[2]),
+                timestamp_ns: u64::from_le_bytes(header[4..].try_into().unwrap()),
+                text: core::str::from_utf8(&text[..text_len]).unwrap_or("<bad utf-8>"),
+            });
+            pos += HEADER_LEN + text_len;
+        }
+    }
+
+    /// Bytes the records take up.
+    pub fn len(&self) -> usize {
+        self.next - self.first
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of records dropped to make room for newer ones.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    fn text_len(&self, pos: usize) -> usize {
+        let mut len = [0; 2];
+        self.read_at(pos, &mut len);
+        u16::from_le_bytes(len) as usize
+    }
+
+    fn write_at(&mut self, pos: usize, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.bytes[(pos + i) % N] = byte;
+        }
+    }
+
+    fn read_at(&self, pos: usize, bytes: &mut [u8]) {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.bytes[(pos + i) % N];
+        }
+    }
+}
+
+// The ring, and the line being printed until it ends.
+struct Log {
+    ring: Ring<LOG_SIZE>,
+    line: [u8; MAX_LINE],
+    line_len: usize,
+    line_level: Level,
+    line_timestamp_ns: u64,
+}
+
+impl Log {
+    fn end_line(&mut self) {
+        // The line only ever gets whole characters
+        let text = core::str::from_utf8(&self.line[..self.line_len]).unwrap_or_default();
+        self.ring
+            .push(self.line_level, self.line_timestamp_ns, text);
+        self.line_len = 0;
+    }
+}
+
+impl Write for Log {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            if character == '\n' {
+                self.end_line();
+                continue;
+            }
+            if self.line_len + character.len_utf8() > MAX_LINE {
+                self.end_line();
+            }
+            character.encode_utf8(&mut self.line[self.line_len..]);
+            self.line_len += character.len_utf8();
+        }
+        Ok(())
+    }
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    ring: Ring::new(),
+    line: [0; MAX_LINE],
+    line_len: 0,
+    line_level: Level::Info,
+    line_timestamp_ns: 0,
+});
+
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// The most verbose level that reaches the console.
+pub fn console_level() -> Level {
+    Level::from_u8(CONSOLE_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_console_level(level: Level) {
+    CONSOLE_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Append text to the log. A line takes the level and the time of its first piece.
+pub fn log(level: Level, args: fmt::Arguments) {
+    without_interrupt(|| {
+        let mut log = LOG.lock();
+        if log.line_len == 0 {
+            log.line_level = level;
+            log.line_timestamp_ns = time::monotonic_ns();
+        }
+        log.write_fmt(args).unwrap();
+    });
+}
+
+/// The newest whole lines of the log that fit in max_len bytes, formatted as Record does.
+pub fn read(max_len: usize) -> String {
+    let mut text = String::new();
+    let mut starts = Vec::new();
+    without_interrupt(|| {
+        LOG.lock().ring.for_each(|record| {
+            starts.push(text.len());
+            write!(text, "{}", record).unwrap();
+        })
+    });
+
+    let start = starts
+        .into_iter()
+        .find(|&start| text.len() - start <= max_len)
+        .unwrap_or(text.len());
+    text.split_off(start)
+}
+
+/// Unlock the log if it is held, for output::force_print(). Returns whether it was.
+///
+/// # Safety
+/// Whoever held the lock must never run again, or must not mind the log changing under it.
+pub unsafe fn break_lock() -> bool {
+    if !LOG.is_locked() {
+        return false;
+    }
+    unsafe { LOG.force_unlock() };
+    true
+}
+
+/// Print the whole log to the console, whatever the console level.
+pub fn dump() {
+    super::output::print_console(format_args!("{}", read(usize::MAX)));
+}
+
+/// Number of lines dropped from the log to make room for newer ones.
+pub fn dropped() -> usize {
+    without_interrupt(|| LOG.lock().ring.dropped())
+}
+
+#[macro_export]
+macro_rules! log_err {
+    ($($arg:tt)*) => ($crate::io::output::_log(
+        $crate::io::klog::Level::Error,
+        format_args!("{}\n", format_args!($($arg)*)),
+    ));
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::io::output::_log(
+        $crate::io::klog::Level::Warn,
+        format_args!("{}\n", format_args!($($arg)*)),
+    ));
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::io::output::_log(
+        $crate::io::klog::Level::Debug,
+        format_args!("{}\n", format_args!($($arg)*)),
+    ));
+}
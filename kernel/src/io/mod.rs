@@ -1,4 +1,9 @@
+pub mod debugcon;
 pub mod framebuffer;
+pub mod fwcfg;
+pub mod klog;
 pub mod output;
+pub mod pit;
 pub mod port;
+pub mod rtc;
 pub mod serial;
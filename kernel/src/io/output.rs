@@ -1,31 +1,107 @@
-use core::fmt::{self, Write};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use bootloader_api::BootInfo;
 use spin::Mutex;
 
 use crate::{
+    cmdline,
     idt::without_interrupt,
-    io::{framebuffer::FrameBufferWriter, serial::Serial},
+    io::{
+        debugcon,
+        framebuffer::{Font, FrameBufferWriter},
+        klog::{self, Level},
+        serial::{self, COM1, DEFAULT_BAUD, Serial, SerialError},
+    },
+    log_warn,
 };
 
 static SERIAL: Mutex<Option<Serial>> = Mutex::new(None);
 static FRAMEBUFFER: Mutex<Option<FrameBufferWriter>> = Mutex::new(None);
 
+// Set once force_print() broke a lock of the console, so what was being printed then may be cut off.
+static POISONED: AtomicBool = AtomicBool::new(false);
+
 pub fn init(boot_info: &mut BootInfo) {
-    // Initialize serial port
-    *SERIAL.lock() = Some(Serial::new().unwrap());
+    // Initialize the serial port of the console, COM1 unless the command line picks another
+    let option = cmdline::option("console");
+    let port = option.and_then(serial::parse_console).unwrap_or(COM1);
+    let console = if cfg!(feature = "test-no-serial") {
+        Err(SerialError::Faulty)
+    } else {
+        Serial::new(port, DEFAULT_BAUD)
+    };
+    let serial_error = match console {
+        Ok(console) => {
+            serial::set_console(&console);
+            *SERIAL.lock() = Some(console);
+            None
+        }
+        Err(err) => Some(err),
+    };
 
-    // Initialize framebuffer writer, if available
+    // Initialize framebuffer writer, if available, with the font picked at build time
+    let font = Font::embedded();
     if let Some(framebuffer) = boot_info.framebuffer.take() {
         let info = framebuffer.info();
         let buffer = framebuffer.into_buffer();
-        *FRAMEBUFFER.lock() = Some(FrameBufferWriter::new(buffer, info));
+        *FRAMEBUFFER.lock() = Some(FrameBufferWriter::new(
+            buffer,
+            info,
+            font.unwrap_or(Font::Noto),
+        ));
+    }
+
+    // Without it, the console is the framebuffer and the debug console
+    if let Some(err) = serial_error {
+        log_warn!("No serial console at {:#x}: {:?}", port, err);
+    }
+    if let Err(err) = font {
+        log_warn!(
+            "Ignoring the embedded font, which isn't a PSF font: {:?}",
+            err
+        );
+    }
+    if let Some(value) = option.filter(|value| serial::parse_console(value).is_none()) {
+        log_warn!("Ignoring bad console option: {}", value);
+    }
+
+    if let Some(value) = cmdline::option("loglevel") {
+        match Level::parse(value) {
+            Some(level) => klog::set_console_level(level),
+            None => log_warn!("Ignoring bad log level option: {}", value),
+        }
     }
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
+/// Have the framebuffer console draw to a back buffer, once the heap is up.
+pub fn init_back_buffer() {
+    let Some(enabled) = with_framebuffer(|framebuffer| framebuffer.enable_back_buffer()) else {
+        return;
+    };
+    if !enabled {
+        log_warn!("The framebuffer is too large for a back buffer, drawing to it directly");
+    }
+}
+
+/// Call f with the serial port of the console. Nothing can be printed while f runs.
+pub fn with_serial<R>(f: impl FnOnce(&mut Serial) -> R) -> Option<R> {
+    without_interrupt(|| SERIAL.lock().as_mut().map(f))
+}
+
+/// Call f with the framebuffer, or return None if there is none. Nothing can be printed while f runs.
+pub fn with_framebuffer<R>(f: impl FnOnce(&mut FrameBufferWriter) -> R) -> Option<R> {
+    without_interrupt(|| FRAMEBUFFER.lock().as_mut().map(f))
+}
+
+/// Print to the console only, leaving the kernel log alone.
+pub fn print_console(args: fmt::Arguments) {
     without_interrupt(|| {
+        // First to the debug console, which needs no lock, in case printing anywhere else goes wrong
+        debugcon::print(args);
+
         // Print to serial, if available
         if let Some(serial) = SERIAL.lock().as_mut() {
             serial.write_fmt(args).unwrap();
@@ -38,6 +114,61 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Print from the panic and NMI handlers, which may have stopped a print halfway, with the console locked. Locks of
+/// the console and the kernel log that are held are broken, and the console is marked poisoned, with a "[lock busted]"
+/// note before the text. The text is logged as an error, and printed whatever the console level.
+pub fn force_print(args: fmt::Arguments) {
+    without_interrupt(|| {
+        let busted = unsafe { break_lock(&SERIAL) | break_lock(&FRAMEBUFFER) | klog::break_lock() };
+        if busted {
+            POISONED.store(true, Ordering::Relaxed);
+            klog::log(Level::Error, format_args!("[lock busted] "));
+            print_console(format_args!("[lock busted] "));
+        }
+        klog::log(Level::Error, args);
+        print_console(args);
+    });
+}
+
+/// Whether force_print() ever broke a lock of the console.
+pub fn poisoned() -> bool {
+    POISONED.load(Ordering::Relaxed)
+}
+
+// Unlock the mutex if it is held. Returns whether it was. The holder must never run again, or must not mind.
+unsafe fn break_lock<T>(mutex: &Mutex<T>) -> bool {
+    if !mutex.is_locked() {
+        return false;
+    }
+    unsafe { mutex.force_unlock() };
+    true
+}
+
+/// Writes to the console with printk, for functions that format into any fmt::Write.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        _print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    _log(Level::Info, args);
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    without_interrupt(|| {
+        klog::log(level, args);
+        if level <= klog::console_level() {
+            print_console(args);
+        }
+    });
+}
+
 #[macro_export]
 macro_rules! printk {
     ($($arg:tt)*) => ($crate::io::output::_print(format_args!($($arg)*)));
@@ -0,0 +1,147 @@
+//! The 8253/8254 PIT (Programmable Interval Timer), which keeps the system tick.
+//!
+//! Channel 0 runs as a rate generator at the frequency init() is given, and raises IRQ 0 on every tick, which is the
+//! only IRQ of the PIC the PIT unmasks. The timer interrupt calls tick(), which counts the tick and runs the callbacks
+//! registered with register_tick_callback(), in the order they were registered. Once the local APIC drives the tick,
+//! IRQ 0 is masked again, but channel 0 keeps counting, so delay_ms() still works.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    idt::{self, TIMER_VECTOR, without_interrupt},
+    io::port::{Port, PortWriteOnly},
+    irq, isr,
+};
+
+pub const PIT_FREQUENCY: usize = 1193182; // Input clock of the PIT, in Hz
+
+/// Number of tick callbacks that can be registered.
+pub const MAX_TICK_CALLBACKS: usize = 8;
+
+const PIT_CHANNEL_0: Port<u8> = unsafe { Port::new(0x40) };
+const PIT_COMMAND: PortWriteOnly<u8> = unsafe { PortWriteOnly::new(0x43) };
+const PIT_LATCH_CHANNEL_0: u8 = 0x00; // Latch the count of channel 0, so both bytes are read from the same count
+const PIT_RATE_GENERATOR: u8 = 0x34; // Channel 0, access mode lobyte/hibyte, mode 2 (rate generator), binary
+
+// The largest divisor, written as 0, which is what the firmware leaves channel 0 counting from
+const MAX_DIVISOR: usize = 0x10000;
+
+/// Called on every tick, from the timer interrupt.
+pub type TickCallback = unsafe fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PitError {
+    TooManyCallbacks,
+    NotRegistered,
+}
+
+// Number of ticks since init().
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// What channel 0 counts down from.
+static DIVISOR: AtomicUsize = AtomicUsize::new(MAX_DIVISOR);
+
+// Only locked with interrupts disabled, as the timer interrupt locks it too.
+static TICK_CALLBACKS: Mutex<[Option<TickCallback>; MAX_TICK_CALLBACKS]> =
+    Mutex::new([None; MAX_TICK_CALLBACKS]);
+
+/// Start channel 0 as a rate generator, firing hz times per second, and unmask IRQ 0.
+///
+/// # Safety
+/// Must be called once, with interrupts disabled, after the IDT is set up.
+pub unsafe fn init(hz: usize) {
+    let divisor = PIT_FREQUENCY / hz;
+    assert!(
+        (1..=MAX_DIVISOR).contains(&divisor),
+        "The PIT can't tick at {} Hz",
+        hz
+    );
+
+    PIT_COMMAND.write(PIT_RATE_GENERATOR);
+    PIT_CHANNEL_0.write(divisor as u8); // A divisor of 0x10000 is written as 0
+    PIT_CHANNEL_0.write((divisor >> 8) as u8);
+    DIVISOR.store(divisor, Ordering::Relaxed);
+
+    irq::register_handler(TIMER_VECTOR, isr::timer_handler).unwrap();
+    idt::unmask_pic_irq(0);
+}
+
+/// Ticks per second, as channel 0 is set up to fire.
+pub fn frequency() -> usize {
+    PIT_FREQUENCY / DIVISOR.load(Ordering::Relaxed)
+}
+
+/// Number of ticks since init(), whether the PIT or the local APIC drives them.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Run callback on every tick, after the callbacks registered before it.
+pub fn register_tick_callback(callback: TickCallback) -> Result<(), PitError> {
+    without_interrupt(|| {
+        let mut callbacks = TICK_CALLBACKS.lock();
+        let slot = callbacks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(PitError::TooManyCallbacks)?;
+        *slot = Some(callback);
+        Ok(())
+    })
+}
+
+/// Stop running callback on every tick.
+pub fn unregister_tick_callback(callback: TickCallback) -> Result<(), PitError> {
+    without_interrupt(|| {
+        let mut callbacks = TICK_CALLBACKS.lock();
+        let slot = callbacks
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|registered| ptr::fn_addr_eq(registered, callback)))
+            .ok_or(PitError::NotRegistered)?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Count a tick, and run the tick callbacks.
+///
+/// # Safety
+/// Must only be called from the timer interrupt.
+pub(crate) unsafe fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    // Copied out, so a callback may register others
+    let callbacks = *TICK_CALLBACKS.lock();
+    for callback in callbacks.into_iter().flatten() {
+        unsafe { callback() };
+    }
+}
+
+// Read the current count of channel 0, which counts down from the divisor once per PIT clock, then starts over.
+fn read_count() -> u16 {
+    without_interrupt(|| {
+        PIT_COMMAND.write(PIT_LATCH_CHANNEL_0);
+        let low = PIT_CHANNEL_0.read();
+        let high = PIT_CHANNEL_0.read();
+        u16::from_le_bytes([low, high])
+    })
+}
+
+/// Busy-wait for ms milliseconds by polling the count of channel 0. Unlike waiting for ticks, this works with
+/// interrupts disabled, e.g. early in boot, and before init().
+pub fn delay_ms(ms: usize) {
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    let target = ms * PIT_FREQUENCY / 1000;
+
+    let mut elapsed = 0;
+    let mut last = read_count() as usize;
+    while elapsed < target {
+        let count = read_count() as usize;
+        // The count goes down, and starts over from the divisor when it reaches 0
+        elapsed += (last + divisor - count) % divisor;
+        last = count;
+    }
+}
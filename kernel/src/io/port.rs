@@ -1,4 +1,13 @@
-use core::arch::asm;
+//! x86 I/O ports.
+//!
+//! The in and out functions access a port directly. Drivers rather keep a Port for each register, which is unsafe to
+//! create, for the port number and value width to be checked once, and is then safe to read and write.
+//! PortReadOnly and PortWriteOnly are for registers that only go one way.
+
+use core::{arch::asm, marker::PhantomData};
+
+// Port of the POST code display, which nothing reads, so writing to it only takes time
+const POST_CODE: u16 = 0x80;
 
 pub unsafe fn inb(port: u16) -> u8 {
     let value: u8;
@@ -23,3 +32,195 @@ pub unsafe fn outb(port: u16, value: u8) {
         );
     }
 }
+
+/// Read 16 bits from the port.
+///
+/// # Safety
+/// Reading the port must not break memory safety, e.g. by making a device write to memory.
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    unsafe {
+        asm!(
+            "in ax, dx",
+            in("dx") port,
+            out("ax") value,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    value
+}
+
+/// Write 16 bits to the port.
+///
+/// # Safety
+/// Writing the port must not break memory safety, e.g. by making a device write to memory.
+pub unsafe fn outw(port: u16, value: u16) {
+    unsafe {
+        asm!(
+            "out dx, ax",
+            in("dx") port,
+            in("ax") value,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+}
+
+/// Read 32 bits from the port.
+///
+/// # Safety
+/// Reading the port must not break memory safety, e.g. by making a device write to memory.
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    unsafe {
+        asm!(
+            "in eax, dx",
+            in("dx") port,
+            out("eax") value,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    value
+}
+
+/// Write 32 bits to the port.
+///
+/// # Safety
+/// Writing the port must not break memory safety, e.g. by making a device write to memory.
+pub unsafe fn outl(port: u16, value: u32) {
+    unsafe {
+        asm!(
+            "out dx, eax",
+            in("dx") port,
+            in("eax") value,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+}
+
+/// Wait about a microsecond, for old devices that need time between accesses, e.g. the PIC while it is programmed.
+pub fn io_wait() {
+    unsafe { outb(POST_CODE, 0) };
+}
+
+/// A width a port can be accessed with: u8, u16 or u32.
+pub trait PortValue: Copy {
+    /// # Safety
+    /// As inb(), inw() or inl().
+    unsafe fn read_from(port: u16) -> Self;
+
+    /// # Safety
+    /// As outb(), outw() or outl().
+    unsafe fn write_to(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    unsafe fn read_from(port: u16) -> Self {
+        unsafe { inb(port) }
+    }
+
+    unsafe fn write_to(port: u16, value: Self) {
+        unsafe { outb(port, value) }
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_from(port: u16) -> Self {
+        unsafe { inw(port) }
+    }
+
+    unsafe fn write_to(port: u16, value: Self) {
+        unsafe { outw(port, value) }
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_from(port: u16) -> Self {
+        unsafe { inl(port) }
+    }
+
+    unsafe fn write_to(port: u16, value: Self) {
+        unsafe { outl(port, value) }
+    }
+}
+
+/// A port that is read and written as T.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T: PortValue> {
+    port: u16,
+    value: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    /// # Safety
+    /// Reading and writing T at the port must never break memory safety, e.g. by making a device write to memory.
+    pub const unsafe fn new(port: u16) -> Self {
+        Port {
+            port,
+            value: PhantomData,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn read(&self) -> T {
+        unsafe { T::read_from(self.port) }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { T::write_to(self.port, value) }
+    }
+}
+
+/// A port that is only read, as T.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortReadOnly<T: PortValue> {
+    port: u16,
+    value: PhantomData<T>,
+}
+
+impl<T: PortValue> PortReadOnly<T> {
+    /// # Safety
+    /// Reading T at the port must never break memory safety.
+    pub const unsafe fn new(port: u16) -> Self {
+        PortReadOnly {
+            port,
+            value: PhantomData,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn read(&self) -> T {
+        unsafe { T::read_from(self.port) }
+    }
+}
+
+/// A port that is only written, as T.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortWriteOnly<T: PortValue> {
+    port: u16,
+    value: PhantomData<T>,
+}
+
+impl<T: PortValue> PortWriteOnly<T> {
+    /// # Safety
+    /// Writing T at the port must never break memory safety.
+    pub const unsafe fn new(port: u16) -> Self {
+        PortWriteOnly {
+            port,
+            value: PhantomData,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { T::write_to(self.port, value) }
+    }
+}
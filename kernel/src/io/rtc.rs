@@ -0,0 +1,185 @@
+//! The MC146818 real time clock in the CMOS, for the calendar time.
+//!
+//! The RTC is only read once, at boot. After that the wall clock is the boot time plus the monotonic clock, which is
+//! finer than the one second the RTC counts in, and never jumps when the RTC updates.
+//!
+//! Its registers change while the RTC updates, once a second, so a read waits until no update is in progress, and is
+//! repeated until two in a row agree. The values are BCD or binary, and the hour is 12 or 24-hour, as status register
+//! B says. The year has two digits; the century comes from the century register the FADT names, or is taken to be
+//! 2000.
+
+use core::fmt;
+
+use super::port::{Port, io_wait};
+use crate::{acpi, idt::without_interrupt, time};
+
+const CMOS_INDEX: Port<u8> = unsafe { Port::new(0x70) };
+const CMOS_DATA: Port<u8> = unsafe { Port::new(0x71) };
+const NMI_DISABLE: u8 = 1 << 7; // In the index port, kept as it was
+
+// Registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+/// Status register B: hours are 0 to 23, rather than 1 to 12 with a PM bit.
+pub const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status register B: values are binary, rather than BCD.
+pub const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7; // In 12-hour mode
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// A date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8, // 1 to 12
+    pub day: u8,   // 1 to 31
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC. Dates before it aren't supported.
+    pub fn to_unix_secs(&self) -> u64 {
+        // Days since 1970-01-01 of the civil date, counting years from March so the leap day comes last
+        let (year, month) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        secs.max(0) as u64
+    }
+}
+
+/// Writes the time as "2024-03-05 14:07:09 UTC".
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The time registers of the RTC, as read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawTime {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8,
+    pub day: u8,
+    pub month: u8,
+    pub year: u8,
+    pub century: Option<u8>,
+}
+
+/// Decode the registers, in the formats status register B gives.
+pub fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let value = |byte: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            byte
+        } else {
+            (byte >> 4) * 10 + (byte & 0x0f)
+        }
+    };
+
+    let mut hour = value(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, and 12 PM noon
+        hour %= 12;
+        if raw.hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = raw.century.map_or(20, value) as u16;
+
+    DateTime {
+        year: century * 100 + value(raw.year) as u16,
+        month: value(raw.month),
+        day: value(raw.day),
+        hour,
+        minute: value(raw.minute),
+        second: value(raw.second),
+    }
+}
+
+// The wall clock at boot, in Unix seconds, and the monotonic clock when it was read.
+static mut BOOT_TIME: Option<DateTime> = None;
+static mut BOOT_UNIX_SECS: u64 = 0;
+static mut BOOT_MONOTONIC_NS: u64 = 0;
+
+/// Read the date and time from the RTC.
+pub fn read() -> DateTime {
+    let century = acpi::century_register();
+    without_interrupt(|| {
+        let mut last = read_raw(century);
+        loop {
+            let raw = read_raw(century);
+            if raw == last {
+                return decode(raw, read_register(STATUS_B));
+            }
+            last = raw;
+        }
+    })
+}
+
+/// Read the RTC, and remember it as the boot time the wall clock counts from.
+pub fn init() {
+    let now = read();
+    unsafe {
+        BOOT_MONOTONIC_NS = time::monotonic_ns();
+        BOOT_UNIX_SECS = now.to_unix_secs();
+        BOOT_TIME = Some(now);
+    }
+}
+
+/// The date and time the RTC had at boot, or None before init().
+pub fn boot_time() -> Option<DateTime> {
+    unsafe { BOOT_TIME }
+}
+
+/// Nanoseconds since 1970-01-01 00:00:00 UTC: the boot time plus the monotonic clock since.
+pub fn realtime_ns() -> u64 {
+    unsafe { BOOT_UNIX_SECS * NS_PER_SEC + time::monotonic_ns().saturating_sub(BOOT_MONOTONIC_NS) }
+}
+
+// Read the time registers once no update is in progress.
+fn read_raw(century: Option<u8>) -> RawTime {
+    while read_register(STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: century.map(read_register),
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    let nmi = CMOS_INDEX.read() & NMI_DISABLE;
+    CMOS_INDEX.write(register | nmi);
+    io_wait();
+    CMOS_DATA.read()
+}
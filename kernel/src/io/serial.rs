@@ -1,52 +1,167 @@
-use core::fmt;
+//! 16550 UART serial ports.
+//!
+//! The console is COM1 unless the console option of the kernel command line picks COM2 (console=com2). Its port is
+//! kept here, for the free functions that print or receive without a Serial at hand.
 
-use super::port::*;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU16, Ordering},
+};
 
-const PORT: u16 = 0x3F8; // COM1
+use super::{debugcon, port::Port};
+use crate::{idt::without_interrupt, user::sched::wait_queue::WaitQueue};
 
-pub struct Serial;
+/// Base port of the first UART.
+pub const COM1: u16 = 0x3F8;
+/// Base port of the second UART.
+pub const COM2: u16 = 0x2F8;
+
+/// Baud rate of the console.
+pub const DEFAULT_BAUD: u32 = 38400;
+
+// The UART divides this rate by its divisor to get the baud rate
+const UART_CLOCK: u32 = 115200;
+
+// Registers, as offsets from the base port. With DLAB set, the first two are the divisor instead.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+// Line status bits
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5; // Room for another byte
+const TRANSMITTER_IDLE: u8 = 1 << 6; // Every byte has been sent
+
+// Base port of the console.
+static CONSOLE_PORT: AtomicU16 = AtomicU16::new(COM1);
+
+// Bytes received but not read yet. Bytes that arrive while the buffer is full are dropped.
+const RX_BUFFER_SIZE: usize = 256;
+static mut RX_BUFFER: [u8; RX_BUFFER_SIZE] = [0; RX_BUFFER_SIZE];
+static mut RX_HEAD: usize = 0; // Index of the oldest byte
+static mut RX_LEN: usize = 0;
+
+/// Tasks waiting for input. Woken up whenever bytes are received.
+pub static mut RX_WAITERS: WaitQueue = WaitQueue::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    InvalidBaud, // The UART can't run at the baud rate
+    Faulty, // The loopback test didn't get its byte back, e.g. because there is no UART at the port
+}
+
+/// A UART, by its base port.
+#[derive(Debug)]
+pub struct Serial {
+    port: u16,
+}
 
 impl Serial {
-    pub fn new() -> Result<Self, ()> {
+    /// Set up the UART at the base port for the baud rate, with 8 bits, no parity and one stop bit, and interrupts
+    /// when data is received. Fails if a loopback test doesn't get its byte back.
+    pub fn new(port: u16, baud: u32) -> Result<Self, SerialError> {
+        let [divisor_low, divisor_high] = divisor(baud)?.to_le_bytes();
+        let serial = Serial { port };
+
         unsafe {
-            outb(PORT + 1, 0x00); // Disable all interrupts
-            outb(PORT + 3, 0x80); // Enable DLAB (set baud rate divisor)
-            outb(PORT, 0x03); // Set divisor to 3 (lo byte) 38400 baud
-            outb(PORT + 1, 0x00); //                  (hi byte)
-            outb(PORT + 3, 0x03); // 8 bits, no parity, one stop bit
-            outb(PORT + 2, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
-            outb(PORT + 4, 0x0B); // IRQs enabled, RTS/DSR set
-            outb(PORT + 4, 0x1E); // Set in loopback mode, test the serial chip
-            outb(PORT, 0xAE); // Test serial chip (send byte 0xAE and check if serial returns same byte)
+            serial.write_register(INTERRUPT_ENABLE, 0x00); // Disable all interrupts
+            serial.write_register(LINE_CONTROL, 0x80); // Enable DLAB (set baud rate divisor)
+            serial.write_register(DATA, divisor_low);
+            serial.write_register(INTERRUPT_ENABLE, divisor_high);
+            serial.write_register(LINE_CONTROL, 0x03); // 8 bits, no parity, one stop bit
+            serial.write_register(FIFO_CONTROL, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
+            serial.write_register(MODEM_CONTROL, 0x0B); // IRQs enabled, RTS/DSR set
+            serial.write_register(MODEM_CONTROL, 0x1E); // Set in loopback mode, test the serial chip
+            serial.write_register(DATA, 0xAE); // Test serial chip (send 0xAE and check if serial returns same byte)
 
             // Check if serial is faulty (i.e: not same byte as sent)
-            if inb(PORT) != 0xAE {
-                return Err(());
+            if serial.read_register(DATA) != 0xAE {
+                return Err(SerialError::Faulty);
             }
 
             // If serial is not faulty set it in normal operation mode
             // (not-loopback with IRQs enabled and OUT#1 and OUT#2 bits enabled)
-            outb(PORT + 4, 0x0F);
-            Ok(Serial)
+            serial.write_register(MODEM_CONTROL, 0x0F);
+            serial.write_register(INTERRUPT_ENABLE, 0x01); // Interrupt when data is received
         }
+        Ok(serial)
+    }
+
+    /// The base port of the UART.
+    pub fn port(&self) -> u16 {
+        self.port
     }
 
     pub fn can_read(&self) -> bool {
-        unsafe { (inb(PORT + 5) & 1) != 0 }
+        self.line_status() & DATA_READY != 0
     }
 
     pub fn read_u8(&self) -> u8 {
         while !self.can_read() {}
-        unsafe { inb(PORT) }
+        unsafe { self.read_register(DATA) }
     }
 
     pub fn can_write(&self) -> bool {
-        unsafe { (inb(PORT + 5) & 0x20) != 0 }
+        self.line_status() & TRANSMIT_EMPTY != 0
     }
 
     pub fn write_u8(&self, val: u8) {
         while !self.can_write() {}
-        unsafe { outb(PORT, val) }
+        unsafe { self.write_register(DATA, val) }
+    }
+
+    /// Wait until every byte written has been sent.
+    pub fn flush(&self) {
+        while self.line_status() & TRANSMITTER_IDLE == 0 {}
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.read_register(LINE_STATUS) }
+    }
+
+    unsafe fn read_register(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.port + register) }.read()
+    }
+
+    unsafe fn write_register(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.port + register) }.write(value)
+    }
+}
+
+/// The divisor of the UART for the baud rate, which must divide its clock evenly.
+pub fn divisor(baud: u32) -> Result<u16, SerialError> {
+    if baud == 0 || !UART_CLOCK.is_multiple_of(baud) {
+        return Err(SerialError::InvalidBaud);
+    }
+    Ok((UART_CLOCK / baud) as u16)
+}
+
+/// The base port named by the value of the console option, "com1" or "com2".
+pub fn parse_console(value: &str) -> Option<u16> {
+    match value {
+        "com1" => Some(COM1),
+        "com2" => Some(COM2),
+        _ => None,
+    }
+}
+
+/// The base port of the console.
+pub fn console_port() -> u16 {
+    CONSOLE_PORT.load(Ordering::Relaxed)
+}
+
+/// Make the UART the console, once it is set up.
+pub fn set_console(serial: &Serial) {
+    CONSOLE_PORT.store(serial.port, Ordering::Relaxed);
+}
+
+// The console, for the free functions. It is set up by output::init(), before anything prints.
+fn console() -> Serial {
+    Serial {
+        port: console_port(),
     }
 }
 
@@ -61,3 +176,55 @@ impl fmt::Write for Serial {
         Ok(())
     }
 }
+
+/// Write straight to the serial port and the debug console, without the output lock, for handlers that may interrupt
+/// a printk (e.g. the MCE handler). The text may be mixed with the output of the printk it interrupted.
+pub fn emergency_print(args: fmt::Arguments) {
+    debugcon::print(args);
+    let _ = fmt::Write::write_fmt(&mut console(), args);
+}
+
+/// Wait until the console has sent every byte written to it, e.g. before the kernel halts.
+pub fn flush() {
+    console().flush();
+}
+
+/// Called by the serial interrupt. Moves every byte the console received to the receive buffer.
+pub fn handle_interrupt() {
+    let console = console();
+    while console.can_read() {
+        receive(&[console.read_u8()]);
+    }
+}
+
+/// Add bytes to the receive buffer as if they came from the serial port, and wake up the tasks waiting for input.
+pub fn receive(bytes: &[u8]) {
+    without_interrupt(|| unsafe {
+        for &byte in bytes {
+            if RX_LEN == RX_BUFFER_SIZE {
+                break;
+            }
+            RX_BUFFER[(RX_HEAD + RX_LEN) % RX_BUFFER_SIZE] = byte;
+            RX_LEN += 1;
+        }
+        RX_WAITERS.wake_all();
+    });
+}
+
+/// Move up to buf.len() received bytes into buf, without waiting. Returns the number of bytes moved.
+pub fn read_received(buf: &mut [u8]) -> usize {
+    without_interrupt(|| unsafe {
+        let count = buf.len().min(RX_LEN);
+        for byte in &mut buf[..count] {
+            *byte = RX_BUFFER[RX_HEAD];
+            RX_HEAD = (RX_HEAD + 1) % RX_BUFFER_SIZE;
+        }
+        RX_LEN -= count;
+        count
+    })
+}
+
+/// Number of received bytes that are not read yet.
+pub fn received_len() -> usize {
+    without_interrupt(|| unsafe { RX_LEN })
+}
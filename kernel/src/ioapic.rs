@@ -0,0 +1,232 @@
+//! I/O APICs, which route device interrupts to the local APICs.
+//!
+//! Each I/O APIC has a redirection entry per global system interrupt (GSI) from its base on, which names the vector,
+//! the destination local APIC, and the trigger mode and polarity of the line. init() maps the I/O APICs the MADT lists
+//! and masks every entry, then drivers ask for their line with route() or route_isa_irq(). ISA IRQs map to the GSI of
+//! the same number, unless the MADT overrides it (on QEMU, the PIT moves to GSI 2).
+//!
+//! The EOI of a routed vector goes to the local APIC, which broadcasts it to the I/O APICs, so a level triggered line
+//! can raise its next interrupt.
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use crate::{
+    acpi::{self, IoApicEntry},
+    idt::without_interrupt,
+    irq, lapic,
+    mem::map_mmio,
+    printlnk,
+};
+
+// Registers, accessed by writing their index to IOREGSEL, then reading or writing IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const REGISTERS_LEN: usize = 0x20;
+
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10; // Two registers per entry, low half first
+
+// Redirection entry bits
+pub const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+pub const ENTRY_LEVEL: u64 = 1 << 15;
+pub const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_DESTINATION_SHIFT: u64 = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    NoIoApic,      // No I/O APIC handles the GSI
+    InvalidVector, // A CPU exception, or past the end of the IDT
+}
+
+// A mapped I/O APIC.
+struct IoApic {
+    registers: *mut u8,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.registers.add(IOREGSEL) as *mut u32, register);
+            ptr::read_volatile(self.registers.add(IOWIN) as *const u32)
+        }
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.registers.add(IOREGSEL) as *mut u32, register);
+            ptr::write_volatile(self.registers.add(IOWIN) as *mut u32, value);
+        }
+    }
+
+    unsafe fn read_entry(&self, index: u32) -> u64 {
+        let register = IOREDTBL + 2 * index;
+        unsafe { (self.read(register) as u64) | ((self.read(register + 1) as u64) << 32) }
+    }
+
+    unsafe fn write_entry(&self, index: u32, entry: u64) {
+        let register = IOREDTBL + 2 * index;
+        // Masked while it changes, so the line never fires with half an entry
+        unsafe {
+            self.write(register, ENTRY_MASKED as u32);
+            self.write(register + 1, (entry >> 32) as u32);
+            self.write(register, entry as u32);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+}
+
+// The I/O APICs, once init() mapped them. Only changed with interrupts disabled.
+static mut IO_APICS: Vec<IoApic> = Vec::new();
+
+/// Whether device interrupts are routed through I/O APICs.
+pub fn enabled() -> bool {
+    unsafe { !IO_APICS.is_empty() }
+}
+
+/// Build a redirection entry.
+pub fn redirection_entry(
+    vector: u8,
+    dest_apic_id: u8,
+    trigger: Trigger,
+    polarity: Polarity,
+) -> u64 {
+    let mut entry = vector as u64 | (dest_apic_id as u64) << ENTRY_DESTINATION_SHIFT;
+    if trigger == Trigger::Level {
+        entry |= ENTRY_LEVEL;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    entry
+}
+
+/// The GSI, trigger mode and polarity of an ISA IRQ: the IRQ itself, edge triggered and active high, unless the
+/// MADT overrides them.
+pub fn isa_irq(madt: Option<&acpi::Madt>, irq: u8) -> (u32, Trigger, Polarity) {
+    let Some(entry) = madt.and_then(|madt| madt.source_override(irq)) else {
+        return (irq as u32, Trigger::Edge, Polarity::ActiveHigh);
+    };
+    let trigger = match entry.level_triggered() {
+        Some(true) => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+    let polarity = match entry.active_low() {
+        Some(true) => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    (entry.gsi, trigger, polarity)
+}
+
+/// Map the I/O APICs the MADT lists, and mask all their entries. Does nothing without the local APIC, which they
+/// deliver to, or a MADT.
+///
+/// # Safety
+/// Must be called once, with interrupts disabled, after lapic::init() and acpi::init().
+pub unsafe fn init() {
+    let (true, Some(madt)) = (lapic::enabled(), acpi::madt()) else {
+        printlnk!("No I/O APIC, device interrupts come through the PIC");
+        return;
+    };
+
+    for &IoApicEntry { id, addr, gsi_base } in &madt.io_apics {
+        let registers = unsafe { map_mmio(addr as usize, REGISTERS_LEN) };
+        assert!(!registers.is_null(), "Failed to map an I/O APIC");
+
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((unsafe { io_apic.read(IOAPICVER) } >> 16) & 0xff) + 1;
+        for index in 0..io_apic.entries {
+            unsafe { io_apic.write_entry(index, ENTRY_MASKED) };
+        }
+
+        printlnk!(
+            "I/O APIC {}: GSIs {} to {}",
+            id,
+            gsi_base,
+            gsi_base + io_apic.entries - 1
+        );
+        unsafe { IO_APICS.push(io_apic) };
+    }
+}
+
+/// Route the GSI to the vector of the local APIC dest_apic_id, and unmask it. The vector gets its EOI from the local
+/// APIC from now on.
+pub fn route(
+    gsi: u32,
+    vector: usize,
+    dest_apic_id: u8,
+    trigger: Trigger,
+    polarity: Polarity,
+) -> Result<(), IoApicError> {
+    let vector_u8 = u8::try_from(vector).map_err(|_| IoApicError::InvalidVector)?;
+    with_entry(gsi, |_, _| ())?;
+    irq::set_lapic_eoi(vector).map_err(|_| IoApicError::InvalidVector)?;
+
+    let entry = redirection_entry(vector_u8, dest_apic_id, trigger, polarity);
+    with_entry(gsi, |io_apic, index| unsafe {
+        io_apic.write_entry(index, entry)
+    })
+}
+
+/// Route the ISA IRQ to the vector of this CPU, following the overrides of the MADT. Returns the GSI it uses.
+pub fn route_isa_irq(irq: u8, vector: usize) -> Result<u32, IoApicError> {
+    let (gsi, trigger, polarity) = isa_irq(acpi::madt(), irq);
+    let dest = lapic::id().ok_or(IoApicError::NoIoApic)? as u8;
+    route(gsi, vector, dest, trigger, polarity)?;
+    Ok(gsi)
+}
+
+/// Stop the GSI from raising interrupts.
+pub fn mask(gsi: u32) -> Result<(), IoApicError> {
+    set_masked(gsi, true)
+}
+
+/// Let the GSI raise interrupts again.
+pub fn unmask(gsi: u32) -> Result<(), IoApicError> {
+    set_masked(gsi, false)
+}
+
+/// The redirection entry of the GSI.
+pub fn entry(gsi: u32) -> Result<u64, IoApicError> {
+    with_entry(gsi, |io_apic, index| unsafe { io_apic.read_entry(index) })
+}
+
+fn set_masked(gsi: u32, masked: bool) -> Result<(), IoApicError> {
+    with_entry(gsi, |io_apic, index| unsafe {
+        let entry = io_apic.read_entry(index) & !ENTRY_MASKED;
+        io_apic.write_entry(index, entry | if masked { ENTRY_MASKED } else { 0 });
+    })
+}
+
+// Call f with the I/O APIC that handles the GSI, and the index of its entry.
+fn with_entry<R>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> R) -> Result<R, IoApicError> {
+    without_interrupt(|| {
+        let io_apics = unsafe { &IO_APICS };
+        let io_apic = io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(gsi))
+            .ok_or(IoApicError::NoIoApic)?;
+        Ok(f(io_apic, gsi - io_apic.gsi_base))
+    })
+}
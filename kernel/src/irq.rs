@@ -0,0 +1,435 @@
+//! Interrupts from devices (vectors 32 to 255), dispatched to handlers that drivers register at run time.
+//!
+//! Every vector has a stub that pushes its vector number and jumps to irq_entry(). That saves the registers a handler
+//! may clobber into an IrqContext, and calls dispatch(), which runs the handler registered for the vector, then sends
+//! the EOI. Vectors without a handler are stray: they are counted as spurious, the first few are reported, and
+//! execution goes on. The exception vectors the CPU reserves (22 to 31) go through the same stubs, and are always
+//! stray, so every vector of the IDT is present. The CPUs the kernel runs on never raise those, so only int reaches
+//! them, which pushes no error code.
+//!
+//! The PIC raises vectors PIC_OFFSET to PIC_OFFSET + 15, which need an EOI sent to it. Vectors the local APIC raises
+//! are marked with set_lapic_eoi(), and get theirs written to the local APIC instead. Every other vector can only be
+//! raised with int, or is a spurious interrupt of the local APIC, and needs none.
+//!
+//! Every interrupt and exception handler runs in interrupt context (see InterruptContext), where it must not block,
+//! switch tasks or allocate, as it may have interrupted anything. What has to be done anyway is deferred until the
+//! handler leaves interrupt context on its way back to user mode.
+
+use core::{
+    arch::{asm, naked_asm},
+    fmt::{self, Write},
+    marker::PhantomData,
+    mem::offset_of,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    backtrace,
+    idt::{PIC_OFFSET, PICS, without_interrupt},
+    io::output::Console,
+    isr::InterruptStackFrame,
+    lapic, printlnk,
+    user::sched::{
+        self,
+        cpu::{MAX_CPUS, this_cpu_id},
+    },
+};
+
+/// The first vector that isn't a CPU exception.
+pub const FIRST_VECTOR: usize = 32;
+
+/// The first of the exception vectors the CPU reserves, which have no handler of their own.
+pub const FIRST_RESERVED_VECTOR: usize = 22;
+
+/// Number of vectors in the IDT.
+pub const VECTOR_COUNT: usize = 256;
+
+// RFLAGS.IF: interrupts are enabled
+const RFLAGS_IF: usize = 1 << 9;
+
+// Number of vectors the PIC raises, starting at PIC_OFFSET.
+const PIC_VECTORS: usize = 16;
+
+/// Everything irq_entry() saves on the stack, from the lowest address up. Callee-saved registers are left to the
+/// handler, which follows System V.
+#[repr(C)]
+#[derive(Debug)]
+pub struct IrqContext {
+    eoi_sent: usize, // Set once the EOI is sent, so it isn't sent twice
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rdx: usize,
+    pub rcx: usize,
+    pub rax: usize,
+    pub vector: usize,
+    pub frame: InterruptStackFrame,
+}
+
+impl IrqContext {
+    /// Send the EOI now, rather than after the handler returns. A handler that switches tasks must do this first, as
+    /// the PIC raises nothing of lower priority until it gets the EOI.
+    pub fn end_of_interrupt(&mut self) {
+        if self.eoi_sent == 0 {
+            self.eoi_sent = 1;
+            send_eoi(self.vector);
+        }
+    }
+}
+
+/// The interrupt controller that needs the EOI of a vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eoi {
+    Pic,
+    Lapic,
+    None, // Raised with int, or a spurious interrupt
+}
+
+/// A handler of a vector. It runs with interrupts disabled, in interrupt context.
+pub type IrqHandler = fn(&mut IrqContext);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidVector,     // A CPU exception, or past the end of the IDT
+    AlreadyRegistered, // The vector has a handler already
+    NotRegistered,     // The vector has no handler to unregister
+}
+
+// The handler of each vector from FIRST_VECTOR on. Only locked with interrupts disabled, so dispatch() never finds it
+// locked by the code it interrupted.
+static HANDLERS: Mutex<[Option<IrqHandler>; VECTOR_COUNT - FIRST_VECTOR]> =
+    Mutex::new([None; VECTOR_COUNT - FIRST_VECTOR]);
+
+// Stray interrupts reported for each vector. Later ones are only counted, so a stuck line doesn't flood the output.
+const STRAY_REPORTS: usize = 3;
+
+// Number of interrupts each vector has received through the stubs, for statistics.
+static COUNTS: [AtomicUsize; VECTOR_COUNT] = [const { AtomicUsize::new(0) }; VECTOR_COUNT];
+
+// Vectors that need their EOI written to the local APIC.
+static LAPIC_EOI: [AtomicBool; VECTOR_COUNT] = [const { AtomicBool::new(false) }; VECTOR_COUNT];
+
+// Number of interrupts on vectors without a handler, for statistics.
+static SPURIOUS: AtomicUsize = AtomicUsize::new(0);
+
+// Number of interrupt and exception handlers each CPU is nested in.
+static NESTING: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+// Number of times assert_not_in_interrupt() found the CPU in interrupt context, for the tests.
+static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Interrupt context, which every interrupt and exception handler enters when it starts, and leaves with exit() on its
+/// way out. Work a handler can't do in it (switching to another task, or killing the task after an exception) is
+/// deferred until then.
+///
+/// Dropping it leaves interrupt context without doing the deferred work, which is what the NMI handler does, as it
+/// runs on a stack of its own.
+#[must_use]
+pub struct InterruptContext {
+    // Must be left on the CPU it was entered on
+    _not_send: PhantomData<*const ()>,
+}
+
+impl InterruptContext {
+    /// Enter interrupt context, for a handler that is starting.
+    pub fn enter() -> Self {
+        NESTING[this_cpu_id()].fetch_add(1, Ordering::Relaxed);
+        InterruptContext {
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Leave interrupt context, for the handler that is about to return with frame. If it returns to user mode, the
+    /// deferred work is done now (see sched::return_to_user()), which may switch to another task, or never return.
+    ///
+    /// # Safety
+    /// Must be called with interrupts disabled, on the kernel stack of the current task.
+    pub unsafe fn exit(self, frame: &InterruptStackFrame) {
+        drop(self);
+        if frame.is_user_mode() && !in_interrupt() {
+            unsafe { sched::return_to_user() };
+        }
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        NESTING[this_cpu_id()].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether this CPU is running an interrupt or exception handler.
+pub fn in_interrupt() -> bool {
+    interrupt_nesting() != 0
+}
+
+/// Number of interrupt and exception handlers this CPU is nested in.
+pub fn interrupt_nesting() -> usize {
+    NESTING[this_cpu_id()].load(Ordering::Relaxed)
+}
+
+/// Check that this CPU isn't in interrupt context, before doing what (e.g. "yield_task()") that must not be done
+/// there. In debug builds, a violation is reported with its caller and a backtrace, and panics, unless the kernel is
+/// built with the test-interrupt-context feature, where the tests make them on purpose.
+#[track_caller]
+pub fn assert_not_in_interrupt(what: &str) {
+    if !cfg!(debug_assertions) || !in_interrupt() {
+        return;
+    }
+
+    VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+    printlnk!(
+        "{} in interrupt context (nesting {}), at {}",
+        what,
+        interrupt_nesting(),
+        Location::caller()
+    );
+    backtrace::print();
+    if !cfg!(feature = "test-interrupt-context") {
+        panic!("{} in interrupt context", what);
+    }
+}
+
+/// Get the number of times assert_not_in_interrupt() found the CPU in interrupt context.
+pub fn violations() -> usize {
+    VIOLATIONS.load(Ordering::Relaxed)
+}
+
+/// Set the handler of the vector, which must not have one.
+pub fn register_handler(vector: usize, handler: IrqHandler) -> Result<(), IrqError> {
+    let index = index(vector)?;
+    without_interrupt(|| {
+        let slot = &mut HANDLERS.lock()[index];
+        if slot.is_some() {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+/// Remove the handler of the vector. Interrupts on it are spurious from then on.
+pub fn unregister_handler(vector: usize) -> Result<(), IrqError> {
+    let index = index(vector)?;
+    without_interrupt(|| {
+        HANDLERS.lock()[index]
+            .take()
+            .map(|_| ())
+            .ok_or(IrqError::NotRegistered)
+    })
+}
+
+/// Send the EOI of the vector to the local APIC, rather than the PIC, as the local APIC raises it.
+pub fn set_lapic_eoi(vector: usize) -> Result<(), IrqError> {
+    index(vector)?;
+    LAPIC_EOI[vector].store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The interrupt controller the EOI of the vector goes to.
+pub fn eoi_kind(vector: usize) -> Eoi {
+    if LAPIC_EOI
+        .get(vector)
+        .is_some_and(|lapic| lapic.load(Ordering::Relaxed))
+    {
+        Eoi::Lapic
+    } else if (PIC_OFFSET as usize..PIC_OFFSET as usize + PIC_VECTORS).contains(&vector) {
+        Eoi::Pic
+    } else {
+        Eoi::None
+    }
+}
+
+/// Whether interrupts are enabled on this CPU (RFLAGS.IF).
+pub fn are_enabled() -> bool {
+    let rflags: usize;
+    unsafe { asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
+    rflags & RFLAGS_IF != 0
+}
+
+/// Get the handler of the vector, if it has one.
+pub fn handler(vector: usize) -> Option<IrqHandler> {
+    let index = index(vector).ok()?;
+    without_interrupt(|| HANDLERS.lock()[index])
+}
+
+/// Get the number of interrupts the vector has received. Exceptions with their own handlers aren't counted.
+pub fn interrupt_count(vector: usize) -> usize {
+    COUNTS
+        .get(vector)
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Get the number of interrupts on vectors without a handler.
+pub fn spurious_count() -> usize {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Print the number of interrupts of every vector that received any, and whether it has a handler.
+pub fn print_stats() {
+    format_stats(&mut Console).unwrap();
+}
+
+/// Write the interrupt counts, as print_stats() prints them.
+pub fn format_stats(out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "Interrupts per vector:")?;
+    for vector in FIRST_RESERVED_VECTOR..VECTOR_COUNT {
+        let count = interrupt_count(vector);
+        if count != 0 {
+            let kind = if handler(vector).is_some() {
+                "handled"
+            } else {
+                "stray"
+            };
+            writeln!(out, "  {:#04x}: {} ({})", vector, count, kind)?;
+        }
+    }
+    writeln!(out, "Spurious: {}", spurious_count())
+}
+
+/// Address of the stub of the vector, for its IDT entry. Only the vectors from FIRST_RESERVED_VECTOR on have one.
+pub fn stub(vector: usize) -> *const () {
+    assert!((FIRST_RESERVED_VECTOR..VECTOR_COUNT).contains(&vector));
+    STUBS[vector / 16 - 1][vector % 16] as *const ()
+}
+
+fn index(vector: usize) -> Result<usize, IrqError> {
+    if (FIRST_VECTOR..VECTOR_COUNT).contains(&vector) {
+        Ok(vector - FIRST_VECTOR)
+    } else {
+        Err(IrqError::InvalidVector)
+    }
+}
+
+fn send_eoi(vector: usize) {
+    match eoi_kind(vector) {
+        Eoi::Pic => unsafe { PICS.notify_end_of_interrupt(vector as u8) },
+        Eoi::Lapic => lapic::eoi(),
+        Eoi::None => {}
+    }
+}
+
+// Called by irq_entry() with interrupts disabled.
+extern "C" fn dispatch(context: &mut IrqContext) {
+    let interrupt = InterruptContext::enter();
+    let vector = context.vector;
+    let count = COUNTS[vector].fetch_add(1, Ordering::Relaxed) + 1;
+
+    // The lock is released before the handler runs, as it may switch tasks
+    let handler = index(vector).ok().and_then(|index| HANDLERS.lock()[index]);
+    match handler {
+        Some(handler) => handler(context),
+        None => {
+            SPURIOUS.fetch_add(1, Ordering::Relaxed);
+            if count <= STRAY_REPORTS {
+                let later = if count == STRAY_REPORTS {
+                    ", later ones are only counted"
+                } else {
+                    ""
+                };
+                printlnk!("Stray interrupt on vector {:#x}{}", vector, later);
+            }
+        }
+    }
+
+    context.end_of_interrupt();
+    unsafe { interrupt.exit(&context.frame) };
+}
+
+// Push the vector number, where the error code of an exception would be, and go to irq_entry().
+#[unsafe(naked)]
+unsafe extern "C" fn irq_stub<const VECTOR: usize>() -> ! {
+    naked_asm!(
+        "push {vector}",
+        "jmp {entry}",
+        vector = const VECTOR,
+        entry = sym irq_entry,
+    )
+}
+
+// The common part of the stubs. The CPU aligns the stack to 16 bytes before it pushes the InterruptStackFrame.
+#[unsafe(naked)]
+unsafe extern "C" fn irq_entry() -> ! {
+    naked_asm!(
+        // Save the caller-saved registers, and make room for eoi_sent, which keeps the stack 16-byte aligned
+        "push rax",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push 0",
+
+        "cld",          // The interrupted code may have set the direction flag
+        "mov rdi, rsp", // First argument: pointer to the IrqContext
+        "call {dispatch}",
+
+        "add rsp, 8",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rax",
+        "add rsp, 8", // The vector number
+        "iretq",
+        dispatch = sym dispatch,
+    )
+}
+
+const _: () = assert!(offset_of!(IrqContext, frame) == 11 * 8);
+
+// One row of 16 stubs, for the vectors from 16 * row on.
+macro_rules! stub_row {
+    ($row:literal) => {
+        [
+            irq_stub::<{ $row * 16 }>,
+            irq_stub::<{ $row * 16 + 1 }>,
+            irq_stub::<{ $row * 16 + 2 }>,
+            irq_stub::<{ $row * 16 + 3 }>,
+            irq_stub::<{ $row * 16 + 4 }>,
+            irq_stub::<{ $row * 16 + 5 }>,
+            irq_stub::<{ $row * 16 + 6 }>,
+            irq_stub::<{ $row * 16 + 7 }>,
+            irq_stub::<{ $row * 16 + 8 }>,
+            irq_stub::<{ $row * 16 + 9 }>,
+            irq_stub::<{ $row * 16 + 10 }>,
+            irq_stub::<{ $row * 16 + 11 }>,
+            irq_stub::<{ $row * 16 + 12 }>,
+            irq_stub::<{ $row * 16 + 13 }>,
+            irq_stub::<{ $row * 16 + 14 }>,
+            irq_stub::<{ $row * 16 + 15 }>,
+        ]
+    };
+}
+
+// The stubs of the vectors from 16 on, 16 per row. Those of the exceptions before FIRST_RESERVED_VECTOR are unused.
+static STUBS: [[unsafe extern "C" fn() -> !; 16]; VECTOR_COUNT / 16 - 1] = [
+    stub_row!(1),
+    stub_row!(2),
+    stub_row!(3),
+    stub_row!(4),
+    stub_row!(5),
+    stub_row!(6),
+    stub_row!(7),
+    stub_row!(8),
+    stub_row!(9),
+    stub_row!(10),
+    stub_row!(11),
+    stub_row!(12),
+    stub_row!(13),
+    stub_row!(14),
+    stub_row!(15),
+];
@@ -1,6 +1,25 @@
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts::Us104Key};
-
-use crate::{helper, idt::PICS, io::port::inb, printk, printlnk};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1, layouts::Us104Key};
+
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count},
+    },
+    fmt,
+};
+
+use crate::{
+    backtrace::{self, Symbolized},
+    consts::{PAGE_SIZE, USERSPACE_LIMIT},
+    debug, extable, gdt, helper, invalid_opcode,
+    io::{pit, port::PortReadOnly, serial},
+    irq::{InterruptContext, IrqContext},
+    mce,
+    mem::paging::{get_active_page_directory, resolve_virt_addr},
+    msr::{IA32_S_CET, IA32_U_CET, read_msr},
+    page_fault, printk, printlnk,
+    user::{address_space::AddressSpace, sched, task::Task, uaccess::copy_from_user},
+};
 
 // Interrupts are enabled for most of the time in the kernel.
 // For code that should not be interrupted (e.g. context switch), use cli/sti instructions.
@@ -70,136 +89,519 @@ fn print_info_with_err(num: usize, frame: &InterruptStackFrame, err_code: usize)
     );
 }
 
-pub(super) unsafe extern "x86-interrupt" fn isr_0(frame: InterruptStackFrame) {
-    print_info(0, &frame);
-    helper::hcf();
+// Handle a CPU exception that has no handler of its own, in interrupt context.
+unsafe fn handle_exception(num: usize, frame: &InterruptStackFrame, err_code: Option<usize>) {
+    let context = InterruptContext::enter();
+    unsafe {
+        report_exception(num, frame, err_code);
+        context.exit(frame);
+    }
 }
 
-pub(super) unsafe extern "x86-interrupt" fn isr_1(frame: InterruptStackFrame) {
-    print_info(1, &frame);
+// Report a CPU exception. One from user mode only kills the task that caused it, once the handler returns. One in the
+// kernel halts the machine, after printing what is known about it.
+unsafe fn report_exception(num: usize, frame: &InterruptStackFrame, err_code: Option<usize>) {
+    if frame.is_user_mode() {
+        unsafe { handle_user_exception(num, frame, err_code) };
+        return;
+    }
+
+    match err_code {
+        Some(err_code) => print_info_with_err(num, frame, err_code),
+        None => print_info(num, frame),
+    }
+    printlnk!("rip = {}", Symbolized(frame.ip));
+    backtrace::print();
     helper::hcf();
 }
 
-pub(super) unsafe extern "x86-interrupt" fn isr_2(frame: InterruptStackFrame) {
-    print_info(2, &frame);
-    helper::hcf();
+/// Report a CPU exception the current task caused in user mode (e.g. ud2, or a division by zero), and have the task
+/// killed once the handler leaves interrupt context, so the next task goes on. The report names the exception and the
+/// task, and shows where it was.
+///
+/// # Safety
+/// Must only be called from an exception handler in interrupt context, for an exception that came from user mode, as
+/// checked with InterruptStackFrame::is_user_mode().
+pub unsafe fn handle_user_exception(
+    num: usize,
+    frame: &InterruptStackFrame,
+    err_code: Option<usize>,
+) {
+    sched::with_current_task(|task| {
+        printlnk!(
+            "{} in task {} ({})",
+            INTERRUPT_NAMES[num],
+            task.id,
+            task.name()
+        );
+        print_rip(task, frame.ip);
+        printk!("rsp = {:#x}, rflags = {:#x}", frame.sp, frame.flags);
+        match err_code {
+            Some(err_code) => printlnk!(", error code = {:#x}", err_code),
+            None => printlnk!(),
+        }
+        printlnk!(
+            "Killing task {} ({}) after an exception",
+            task.id,
+            task.name()
+        );
+    });
+    sched::kill_on_return();
+}
+
+pub(super) unsafe extern "x86-interrupt" fn isr_0(frame: InterruptStackFrame) {
+    unsafe { handle_exception(0, &frame, None) };
+}
+
+// Breakpoints are only reported, and execution goes on
+pub(super) unsafe extern "x86-interrupt" fn isr_1(mut frame: InterruptStackFrame) {
+    let context = InterruptContext::enter();
+    unsafe {
+        debug::handle_debug(&mut frame);
+        context.exit(&frame);
+    }
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_3(frame: InterruptStackFrame) {
-    print_info(3, &frame);
-    helper::hcf();
+    let context = InterruptContext::enter();
+    debug::handle_breakpoint(&frame);
+    unsafe { context.exit(&frame) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_4(frame: InterruptStackFrame) {
-    print_info(4, &frame);
-    helper::hcf();
+    unsafe { handle_exception(4, &frame, None) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_5(frame: InterruptStackFrame) {
-    print_info(5, &frame);
-    helper::hcf();
+    unsafe { handle_exception(5, &frame, None) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_6(frame: InterruptStackFrame) {
-    print_info(6, &frame);
-    helper::hcf();
+    let context = InterruptContext::enter();
+    unsafe {
+        invalid_opcode::handle(&frame);
+        context.exit(&frame);
+    }
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_7(frame: InterruptStackFrame) {
-    print_info(7, &frame);
-    helper::hcf();
+    unsafe { handle_exception(7, &frame, None) };
 }
 
+// Runs on its own stack (see gdt::DOUBLE_FAULT_IST), so a kernel stack overflow is reported instead of escalating to a
+// triple fault, which resets the machine.
 pub(super) unsafe extern "x86-interrupt" fn isr_8(frame: InterruptStackFrame, err_code: usize) {
+    // Never left, as the machine halts
+    let _context = InterruptContext::enter();
     print_info_with_err(8, &frame, err_code);
+    // A page fault the CPU couldn't push a frame for, usually on the guard page below a stack, is the likely cause
+    printlnk!(
+        "rsp before the fault: {:#x}, last page fault at: {:#x}",
+        frame.sp,
+        page_fault::read_cr2()
+    );
+
+    // Not through with_current_task(), as the task may be borrowed
+    if let Some(task) = unsafe { sched::CURRENT_TASK.as_ref() } {
+        let task = unsafe { &*task.get() };
+        printlnk!("Current task: {} ({})", task.id, task.name());
+    }
+
+    #[cfg(feature = "test-double-fault")]
+    helper::exit_qemu(helper::QEMU_EXIT_DOUBLE_FAULT);
     helper::hcf();
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_9(frame: InterruptStackFrame) {
-    print_info(9, &frame);
-    helper::hcf();
+    unsafe { handle_exception(9, &frame, None) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_10(frame: InterruptStackFrame, err_code: usize) {
-    print_info_with_err(10, &frame, err_code);
-    helper::hcf();
+    unsafe { handle_exception(10, &frame, Some(err_code)) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_11(frame: InterruptStackFrame, err_code: usize) {
-    print_info_with_err(11, &frame, err_code);
-    helper::hcf();
+    unsafe { handle_exception(11, &frame, Some(err_code)) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_12(frame: InterruptStackFrame, err_code: usize) {
-    print_info_with_err(12, &frame, err_code);
-    helper::hcf();
+    unsafe { handle_exception(12, &frame, Some(err_code)) };
 }
 
-pub(super) unsafe extern "x86-interrupt" fn isr_13(frame: InterruptStackFrame, err_code: usize) {
-    print_info_with_err(13, &frame, err_code);
-    helper::hcf();
+pub(super) unsafe extern "x86-interrupt" fn isr_13(
+    mut frame: InterruptStackFrame,
+    err_code: usize,
+) {
+    let context = InterruptContext::enter();
+    // An instruction the kernel expects to fault goes on at its fixup, e.g. read_msr_safe() of a missing MSR
+    if !frame.is_user_mode()
+        && let Some(fixup) = extable::fixup(frame.ip)
+    {
+        unsafe {
+            (&raw mut frame.ip).write_volatile(fixup);
+            context.exit(&frame);
+        }
+        return;
+    }
+
+    let diagnosis = diagnose_gpf(&frame, err_code);
+    printlnk!("{}", diagnosis);
+    unsafe {
+        LAST_GPF = Some(diagnosis);
+        report_exception(13, &frame, Some(err_code));
+        context.exit(&frame);
+    }
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_14(frame: InterruptStackFrame, err_code: usize) {
-    print_info_with_err(14, &frame, err_code);
-    helper::hcf();
+    unsafe { page_fault::handle(&frame, err_code) };
+}
+
+// Writes formatted text straight to the kernel output, so nothing is allocated.
+struct OutputWriter;
+
+impl fmt::Write for OutputWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        printk!("{}", s);
+        Ok(())
+    }
+}
+
+// Print the instruction pointer of a task that is about to be killed, with the function it was in if known.
+pub(crate) fn print_rip(task: &Task, ip: usize) {
+    printk!("rip = ");
+    let _ = task.symbols.format_addr(ip, &mut OutputWriter);
+    printlnk!();
+}
+
+// Print the regions of a task that is about to be killed.
+pub(crate) fn print_maps(task: &Task) {
+    printlnk!("Memory map of task {}:", task.id);
+    let _ = task.addr_space.format_maps(&mut OutputWriter);
+}
+
+/// The bytes at an instruction pointer. An x86 instruction is 15 bytes long at most, but fewer are read when the next
+/// page isn't mapped, and none at all when the page of the instruction pointer isn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionBytes {
+    bytes: [u8; Self::MAX_LEN],
+    len: usize,
+}
+
+impl InstructionBytes {
+    pub const MAX_LEN: usize = 15;
+
+    const EMPTY: Self = Self {
+        bytes: [0; Self::MAX_LEN],
+        len: 0,
+    };
+
+    /// Read the bytes at ip in the kernel, as far as they are mapped.
+    pub fn from_kernel(ip: usize) -> Self {
+        let page_directory = unsafe { get_active_page_directory() };
+        let mut instruction = Self::EMPTY;
+        for (i, byte) in instruction.bytes.iter_mut().enumerate() {
+            let addr = ip.wrapping_add(i);
+            if addr < USERSPACE_LIMIT
+                || unsafe { resolve_virt_addr(page_directory, addr) }.is_none()
+            {
+                break;
+            }
+            *byte = unsafe { *(addr as *const u8) };
+            instruction.len += 1;
+        }
+        instruction
+    }
+
+    /// Read the bytes at ip in the address space of a task, through uaccess, as far as they are mapped.
+    pub fn from_user(addr_space: &mut AddressSpace, ip: usize) -> Self {
+        let mut instruction = Self::EMPTY;
+        // Up to the end of the page first, which is mapped if the instruction could be fetched at all
+        let first = (PAGE_SIZE - ip % PAGE_SIZE).min(Self::MAX_LEN);
+        if copy_from_user(addr_space, &mut instruction.bytes[..first], ip).is_err() {
+            return Self::EMPTY;
+        }
+        instruction.len = first;
+        if copy_from_user(addr_space, &mut instruction.bytes[first..], ip + first).is_ok() {
+            instruction.len = Self::MAX_LEN;
+        }
+        instruction
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Hex bytes separated by spaces, e.g. "0f 30 c3".
+impl fmt::Display for InstructionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.len == 0 {
+            return write!(f, "(not mapped)");
+        }
+        for (i, byte) in self.as_bytes().iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the instruction at the instruction pointer of the frame, from the current task if it came from user mode.
+pub fn fetch_instruction(frame: &InterruptStackFrame) -> InstructionBytes {
+    if frame.is_user_mode() {
+        sched::with_current_task(|task| InstructionBytes::from_user(&mut task.addr_space, frame.ip))
+    } else {
+        InstructionBytes::from_kernel(frame.ip)
+    }
+}
+
+/// The error code of an exception caused by a segment selector or an IDT vector, such as a general protection fault.
+/// It is zero if no selector caused the exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorError(pub usize);
+
+/// The table a SelectorError refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl SelectorError {
+    pub const EXTERNAL: usize = 1 << 0; // An event outside the program (e.g. an interrupt) caused the exception
+
+    pub fn external(self) -> bool {
+        self.0 & Self::EXTERNAL != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// The index of the entry in its table: a selector shifted right by 3, or a vector.
+    pub fn index(self) -> usize {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+/// Names the entry, e.g. "GDT entry 4 (user code)", or "no selector".
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "no selector");
+        }
+        let index = self.index();
+        match self.table() {
+            DescriptorTable::Gdt => match gdt::entry_name(index) {
+                Some(name) => write!(f, "GDT entry {} ({})", index, name),
+                None => write!(f, "GDT entry {}, past the end of the GDT", index),
+            },
+            DescriptorTable::Idt => match INTERRUPT_NAMES.get(index) {
+                Some(name) => write!(f, "IDT vector {} ({})", index, name),
+                None => write!(f, "IDT vector {}", index),
+            },
+            DescriptorTable::Ldt => write!(f, "LDT entry {}, but there is no LDT", index),
+        }?;
+        if self.external() {
+            write!(f, ", from an external event")?;
+        }
+        Ok(())
+    }
+}
+
+// A segment register, named by its GDT entry, e.g. "0x23 (user code, ring 3)".
+struct Selector(usize);
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = gdt::entry_name(self.0 >> 3).unwrap_or("past the end of the GDT");
+        write!(f, "{:#x} ({}, ring {})", self.0, name, self.0 & 0b11)
+    }
+}
+
+/// What diagnose_gpf() found out about a general protection fault.
+#[derive(Debug, Clone, Copy)]
+pub struct GpfDiagnosis {
+    pub error: SelectorError,
+    pub cs: usize,
+    pub ss: usize,
+    pub instruction: InstructionBytes,
+}
+
+impl fmt::Display for GpfDiagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "General protection fault, error code {:#x}: {}",
+            self.error.0, self.error
+        )?;
+        writeln!(f, "cs = {}, ss = {}", Selector(self.cs), Selector(self.ss))?;
+        write!(f, "Instruction bytes at rip: {}", self.instruction)
+    }
+}
+
+// The last general protection fault, for the tests.
+static mut LAST_GPF: Option<GpfDiagnosis> = None;
+
+/// Decode the error code of a general protection fault, and read the instruction that caused it.
+pub fn diagnose_gpf(frame: &InterruptStackFrame, err_code: usize) -> GpfDiagnosis {
+    GpfDiagnosis {
+        error: SelectorError(err_code),
+        cs: frame.cs,
+        ss: frame.ss,
+        instruction: fetch_instruction(frame),
+    }
+}
+
+/// Get the diagnosis of the last general protection fault.
+pub fn last_gpf() -> Option<GpfDiagnosis> {
+    unsafe { LAST_GPF }
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_15(frame: InterruptStackFrame) {
-    print_info(15, &frame);
-    helper::hcf();
+    unsafe { handle_exception(15, &frame, None) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_16(frame: InterruptStackFrame) {
-    print_info(16, &frame);
-    helper::hcf();
+    unsafe { handle_exception(16, &frame, None) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_17(frame: InterruptStackFrame, err_code: usize) {
-    print_info_with_err(17, &frame, err_code);
-    helper::hcf();
+    unsafe { handle_exception(17, &frame, Some(err_code)) };
 }
 
+// Machine checks are not caused by the running code, so they halt even from user mode
 pub(super) unsafe extern "x86-interrupt" fn isr_18(frame: InterruptStackFrame) {
-    print_info(18, &frame);
-    helper::hcf();
+    unsafe { mce::handle(&frame) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_19(frame: InterruptStackFrame) {
-    print_info(19, &frame);
-    helper::hcf();
+    unsafe { handle_exception(19, &frame, None) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_20(frame: InterruptStackFrame) {
-    print_info(20, &frame);
-    helper::hcf();
+    unsafe { handle_exception(20, &frame, None) };
 }
 
 pub(super) unsafe extern "x86-interrupt" fn isr_21(frame: InterruptStackFrame, err_code: usize) {
-    print_info_with_err(21, &frame, err_code);
-    helper::hcf();
+    let context = InterruptContext::enter();
+    printlnk!(
+        "Control protection violation: {}",
+        ControlProtectionError(err_code)
+    );
+    print_cet_state();
+    unsafe {
+        report_exception(21, &frame, Some(err_code));
+        context.exit(&frame);
+    }
+}
+
+/// The error code of a control protection exception (#CP). CET raises it when a return address doesn't match the
+/// shadow stack, or an indirect branch doesn't land on an ENDBRANCH.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlProtectionError(pub usize);
+
+impl ControlProtectionError {
+    pub const ENCLAVE: usize = 1 << 15; // Raised in an SGX enclave
+
+    pub const NEAR_RET: usize = 1;
+    pub const FAR_RET_IRET: usize = 2;
+    pub const ENDBRANCH: usize = 3;
+    pub const RSTORSSP: usize = 4;
+    pub const SETSSBSY: usize = 5;
+
+    /// What was violated, one of the constants above.
+    pub fn kind(self) -> usize {
+        self.0 & 0x7fff
+    }
+
+    pub fn in_enclave(self) -> bool {
+        self.0 & Self::ENCLAVE != 0
+    }
+}
+
+/// Explains the violation, e.g. "an indirect call or jump didn't land on an ENDBRANCH".
+impl fmt::Display for ControlProtectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind() {
+            Self::NEAR_RET => write!(f, "a near return address doesn't match the shadow stack"),
+            Self::FAR_RET_IRET => write!(
+                f,
+                "a far return or iret address doesn't match the shadow stack"
+            ),
+            Self::ENDBRANCH => write!(f, "an indirect call or jump didn't land on an ENDBRANCH"),
+            Self::RSTORSSP => write!(f, "rstorssp found no valid restore token"),
+            Self::SETSSBSY => write!(f, "setssbsy found the shadow stack token busy"),
+            kind => write!(f, "unknown violation {}", kind),
+        }?;
+        if self.in_enclave() {
+            write!(f, ", in an enclave")?;
+        }
+        Ok(())
+    }
 }
 
-// --- Interrupt by PICs ---
+// CPUID leaf 7: shadow stacks and indirect branch tracking
+const CPUID_7_ECX_CET_SS: u32 = 1 << 7;
+const CPUID_7_EDX_CET_IBT: u32 = 1 << 20;
+
+const CR4_CET: usize = 1 << 23;
+
+// Print whether CET is enabled, and how for user and supervisor mode. The kernel never enables it, so a #CP means
+// something else did. Its MSRs only exist if the CPU has CET.
+fn print_cet_state() {
+    let leaf = __cpuid_count(7, 0);
+    let supported = __cpuid(0).eax >= 7
+        && (leaf.ecx & CPUID_7_ECX_CET_SS != 0 || leaf.edx & CPUID_7_EDX_CET_IBT != 0);
+    if !supported {
+        printlnk!("CET is not supported");
+        return;
+    }
+
+    let cr4: usize;
+    unsafe { asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags)) };
+    printlnk!(
+        "CR4.CET = {}, IA32_U_CET = {:#x}, IA32_S_CET = {:#x}",
+        cr4 & CR4_CET != 0,
+        read_msr(IA32_U_CET),
+        read_msr(IA32_S_CET)
+    );
+}
 
-// Vector: 0x20
-pub(super) unsafe extern "x86-interrupt" fn pic_timer_handler(_: InterruptStackFrame) {
-    printk!(".");
+// --- Interrupt by PICs, registered with irq ---
 
-    unsafe { PICS.notify_end_of_interrupt(0x20) };
+// Vector: 0x20 (the PIT), or 0xF0 (the timer of the local APIC) once that drives the tick
+// Counts the tick, and runs the tick callbacks (see time::init()).
+// A reschedule the timer asks for happens once the interrupt leaves interrupt context, and only on the way back to user
+// mode, so we never switch away from kernel code in the middle of something.
+pub(super) fn timer_handler(_: &mut IrqContext) {
+    unsafe { pit::tick() };
 }
 
+// Data port of the keyboard controller, where the scancodes arrive
+const KEYBOARD_DATA: PortReadOnly<u8> = unsafe { PortReadOnly::new(0x60) };
+
 static mut KEYBOARD: Keyboard<Us104Key, ScancodeSet1> =
     Keyboard::new(ScancodeSet1::new(), Us104Key, HandleControl::Ignore);
 
 // Vector: 0x21
-pub(super) unsafe extern "x86-interrupt" fn pic_keyboard_handler(_: InterruptStackFrame) {
-    let scancode = unsafe { inb(0x60) };
+pub(super) fn pic_keyboard_handler(_: &mut IrqContext) {
+    let scancode = KEYBOARD_DATA.read();
 
     let keyboard = unsafe { &mut KEYBOARD };
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
+                // F12 dumps the task table, for debugging
+                DecodedKey::RawKey(KeyCode::F12) => sched::dump_tasks(),
                 DecodedKey::RawKey(key) => {
                     printk!("{:?}", key);
                 }
@@ -209,6 +611,9 @@ pub(super) unsafe extern "x86-interrupt" fn pic_keyboard_handler(_: InterruptSta
             }
         }
     }
+}
 
-    unsafe { PICS.notify_end_of_interrupt(0x21) };
+// Vector: 0x24 (COM1) or 0x23 (COM2), whichever UART is the console
+pub(super) fn pic_serial_handler(_: &mut IrqContext) {
+    serial::handle_interrupt();
 }
@@ -0,0 +1,154 @@
+//! The local APIC, the interrupt controller of each CPU, and its timer.
+//!
+//! Once the heap and the MMIO window are up, init() maps the registers of the local APIC and enables it. Its timer is
+//! calibrated against the PIT with pit::delay_ms(), then fires periodically at TIMER_HZ, and drives the scheduler tick
+//! in place of the PIT, whose IRQ is masked at the PIC. Device IRQs move to the I/O APIC if there is one (see ioapic).
+//!
+//! Vectors the local APIC raises need an EOI written to it instead of the PIC, which irq::dispatch() sends for the
+//! vectors given to irq::set_lapic_eoi(). Spurious interrupts need none.
+//!
+//! The nolapic option of the kernel command line keeps the PIT and PIC path.
+
+use core::{
+    arch::x86_64::__cpuid,
+    ptr::{self, null_mut},
+};
+
+use crate::{
+    cmdline,
+    consts::PAGE_SIZE,
+    idt,
+    io::pit,
+    irq, isr,
+    mem::map_mmio,
+    msr::{IA32_APIC_BASE, read_msr, write_msr},
+    printlnk,
+    time::MS_PER_TICK,
+};
+
+/// Vector of the timer of the local APIC.
+pub const TIMER_VECTOR: usize = 0xF0;
+/// Vector of spurious interrupts, which the local APIC raises when an interrupt goes away before it is delivered.
+pub const SPURIOUS_VECTOR: usize = 0xFF;
+
+// CPUID leaf 1: the CPU has a local APIC
+const CPUID_1_EDX_APIC: u32 = 1 << 9;
+
+// IA32_APIC_BASE: the local APIC is enabled, and the physical address of its registers
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR: u64 = 0xF_FFFF_F000;
+
+// Registers, as offsets from the base
+const ID: usize = 0x20;
+const VERSION: usize = 0x30;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xB0;
+const SPURIOUS: usize = 0xF0;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8; // Software enable of the local APIC
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+// How long the timer is measured against the PIT.
+const CALIBRATION_MS: usize = 10;
+
+// The registers, once they are mapped and the local APIC is enabled.
+static mut REGISTERS: *mut u8 = null_mut();
+
+// Timer counts per millisecond, with the divider at 16.
+static mut TIMER_PER_MS: u32 = 0;
+
+/// Whether the CPU has a local APIC.
+pub fn supported() -> bool {
+    __cpuid(1).edx & CPUID_1_EDX_APIC != 0
+}
+
+/// Whether the local APIC is enabled, and its timer drives the tick.
+pub fn enabled() -> bool {
+    unsafe { !REGISTERS.is_null() }
+}
+
+/// Timer counts per millisecond, measured against the PIT, or None if the local APIC isn't enabled.
+pub fn timer_per_ms() -> Option<u32> {
+    enabled().then_some(unsafe { TIMER_PER_MS })
+}
+
+/// Id of the local APIC of this CPU, or None if it isn't enabled.
+pub fn id() -> Option<u32> {
+    enabled().then(|| unsafe { read(ID) } >> 24)
+}
+
+/// Signal the end of an interrupt the local APIC delivered.
+pub fn eoi() {
+    if enabled() {
+        unsafe { write(EOI, 0) };
+    }
+}
+
+/// Enable the local APIC, and move the tick from the PIT to its timer. Does nothing with the nolapic option, or if
+/// the CPU has no local APIC.
+///
+/// # Safety
+/// Must be called once, with interrupts disabled, after the PIT is started and the MMIO window is up.
+pub unsafe fn init() {
+    if cmdline::option("nolapic").is_some() {
+        printlnk!("Local APIC disabled on the command line, the PIT drives the tick");
+        return;
+    }
+    if !supported() {
+        printlnk!("No local APIC, the PIT drives the tick");
+        return;
+    }
+
+    let base = read_msr(IA32_APIC_BASE);
+    write_msr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+    let registers = unsafe { map_mmio((base & APIC_BASE_ADDR) as usize, PAGE_SIZE) };
+    assert!(!registers.is_null(), "Failed to map the local APIC");
+
+    unsafe {
+        REGISTERS = registers;
+        write(TASK_PRIORITY, 0); // Accept every interrupt
+        write(SPURIOUS, SPURIOUS_VECTOR as u32 | SPURIOUS_ENABLE);
+
+        // Count down from the largest count, masked, for a while the PIT measures
+        write(TIMER_DIVIDE, TIMER_DIVIDE_16);
+        write(LVT_TIMER, LVT_MASKED);
+        write(TIMER_INITIAL_COUNT, u32::MAX);
+        pit::delay_ms(CALIBRATION_MS);
+        let elapsed = u32::MAX - read(TIMER_CURRENT_COUNT);
+        TIMER_PER_MS = (elapsed / CALIBRATION_MS as u32).max(1);
+    }
+
+    irq::register_handler(TIMER_VECTOR, isr::timer_handler).unwrap();
+    irq::register_handler(SPURIOUS_VECTOR, spurious_handler).unwrap();
+    irq::set_lapic_eoi(TIMER_VECTOR).unwrap();
+
+    unsafe {
+        write(LVT_TIMER, TIMER_VECTOR as u32 | LVT_TIMER_PERIODIC);
+        write(TIMER_INITIAL_COUNT, TIMER_PER_MS * MS_PER_TICK as u32);
+    }
+    idt::mask_pic_irq(0);
+
+    printlnk!(
+        "Local APIC {} enabled, version {:#x}, timer at {} counts per ms",
+        id().unwrap(),
+        unsafe { read(VERSION) } & 0xff,
+        unsafe { TIMER_PER_MS }
+    );
+}
+
+// Vector: 0xFF
+fn spurious_handler(_: &mut irq::IrqContext) {}
+
+unsafe fn read(register: usize) -> u32 {
+    unsafe { ptr::read_volatile(REGISTERS.add(register) as *const u32) }
+}
+
+unsafe fn write(register: usize, value: u32) {
+    unsafe { ptr::write_volatile(REGISTERS.add(register) as *mut u32, value) }
+}
@@ -9,25 +9,73 @@ extern crate alloc;
 use bootloader_api::{BootInfo, BootloaderConfig, config::Mapping, entry_point};
 use core::panic::PanicInfo;
 
-use crate::consts::{KERNEL_OFFSET, PHYS_MEM_OFFSET};
+use crate::{
+    consts::{BOOTLOADER_DYNAMIC_START, KERNEL_OFFSET, PHYS_MEM_OFFSET},
+    io::{output::force_print, serial},
+};
 
+pub mod acpi;
+pub mod backtrace;
+pub mod block;
+pub mod cmdline;
 pub mod consts;
+pub mod cpu;
+pub mod debug;
+pub mod extable;
+pub mod fpu;
 pub mod gdt;
 pub mod helper;
 pub mod idt;
+pub mod invalid_opcode;
 pub mod io;
+pub mod ioapic;
+pub mod irq;
 pub mod isr;
+pub mod lapic;
+pub mod mce;
 pub mod mem;
 pub mod msr;
+pub mod nmi;
+pub mod page_fault;
+pub mod pci;
+pub mod power;
 pub mod primitives;
+pub mod rand;
+pub mod shell;
 pub mod startup;
 pub mod test;
+pub mod time;
 pub mod user;
 
+// Set by the first panic. A panic while handling it (e.g. while printing the backtrace) only prints its message.
+static mut PANICKING: bool = false;
+
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    printlnk!("Kernel panic!\n{:#?}", info);
+    // The panic may have happened while printing, with the console locked
+    force_print(format_args!("Kernel panic!\n{:#?}\n", info));
+    if unsafe { PANICKING } {
+        serial::flush();
+        helper::hcf();
+    }
+    unsafe { PANICKING = true };
+
+    backtrace::print();
+
+    // Not through with_current_task(), as the panic may have happened while the task was borrowed
+    if let Some(task) = unsafe { user::sched::CURRENT_TASK.as_ref() } {
+        let task = unsafe { &*task.get() };
+        force_print(format_args!(
+            "Current task: {} ({})\n",
+            task.id,
+            task.name()
+        ));
+    }
+
+    serial::flush();
+    #[cfg(feature = "test-panic-locked")]
+    helper::exit_qemu(helper::QEMU_EXIT_PANIC);
     helper::hcf();
 }
 
@@ -42,6 +90,6 @@ pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::FixedAddress(PHYS_MEM_OFFSET as u64));
     config.mappings.kernel_base = Mapping::FixedAddress(KERNEL_OFFSET as u64);
-    config.mappings.dynamic_range_start = Some(PHYS_MEM_OFFSET as u64);
+    config.mappings.dynamic_range_start = Some(BOOTLOADER_DYNAMIC_START as u64);
     config
 };
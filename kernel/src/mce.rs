@@ -0,0 +1,271 @@
+//! Machine checks (#MC): hardware errors the CPU found, e.g. in a cache or the memory controller.
+//!
+//! Each error is logged in one of the banks of the machine check architecture (MCA), as a status MSR with an address
+//! and more information next to it. The handler prints every valid record, then halts, as the error may have corrupted
+//! anything, and machine checks aren't caused by the running code anyway. Like an NMI, a machine check may arrive in
+//! the middle of a printk, so the handler prints straight to the serial port.
+//!
+//! Not every CPU has MCA, and reading the MSRs of a missing bank raises #GP, so every read is guarded by CPUID and the
+//! bank count of IA32_MCG_CAP.
+
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    fmt,
+};
+
+use crate::{
+    helper,
+    io::serial::emergency_print,
+    irq::InterruptContext,
+    isr::InterruptStackFrame,
+    msr::{IA32_MCG_CAP, IA32_MCG_STATUS, ia32_mc_addr, ia32_mc_misc, ia32_mc_status, read_msr},
+    printlnk,
+};
+
+// CPUID leaf 1: the machine check exception, and the machine check architecture
+const CPUID_1_EDX_MCE: u32 = 1 << 7;
+const CPUID_1_EDX_MCA: u32 = 1 << 14;
+
+const CR4_MCE: usize = 1 << 6; // Machine checks raise #MC, rather than shutting the machine down
+
+// IA32_MCG_CAP: the number of banks
+const MCG_CAP_COUNT: u64 = 0xff;
+
+/// IA32_MCG_STATUS, the state of the CPU when the machine check was raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McgStatus(pub u64);
+
+impl McgStatus {
+    pub const RIPV: u64 = 1 << 0; // Execution can restart at the rip of the frame
+    pub const EIPV: u64 = 1 << 1; // The rip of the frame is where the error happened
+    pub const MCIP: u64 = 1 << 2; // A machine check is in progress
+
+    pub fn restartable(self) -> bool {
+        self.0 & Self::RIPV != 0
+    }
+
+    pub fn error_at_rip(self) -> bool {
+        self.0 & Self::EIPV != 0
+    }
+
+    pub fn in_progress(self) -> bool {
+        self.0 & Self::MCIP != 0
+    }
+}
+
+/// IA32_MCi_STATUS, the error logged in a bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankStatus(pub u64);
+
+impl BankStatus {
+    pub const VAL: u64 = 1 << 63; // The bank holds an error
+    pub const OVER: u64 = 1 << 62; // Another error came while this one was still logged
+    pub const UC: u64 = 1 << 61; // The hardware couldn't correct the error
+    pub const EN: u64 = 1 << 60; // The error was enabled to raise #MC
+    pub const MISCV: u64 = 1 << 59; // IA32_MCi_MISC is valid
+    pub const ADDRV: u64 = 1 << 58; // IA32_MCi_ADDR is valid
+    pub const PCC: u64 = 1 << 57; // The processor context may be corrupt
+
+    pub fn valid(self) -> bool {
+        self.0 & Self::VAL != 0
+    }
+
+    pub fn overflow(self) -> bool {
+        self.0 & Self::OVER != 0
+    }
+
+    pub fn uncorrected(self) -> bool {
+        self.0 & Self::UC != 0
+    }
+
+    pub fn context_corrupt(self) -> bool {
+        self.0 & Self::PCC != 0
+    }
+
+    pub fn addr_valid(self) -> bool {
+        self.0 & Self::ADDRV != 0
+    }
+
+    pub fn misc_valid(self) -> bool {
+        self.0 & Self::MISCV != 0
+    }
+
+    /// The architectural MCA error code.
+    pub fn error_code(self) -> u16 {
+        self.0 as u16
+    }
+
+    /// The model specific error code, which only the manual of the CPU explains.
+    pub fn model_code(self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+}
+
+/// Describes the error, e.g. "uncorrected memory controller error (0x009f), processor context corrupt".
+impl fmt::Display for BankStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let corrected = if self.uncorrected() {
+            "uncorrected"
+        } else {
+            "corrected"
+        };
+        write!(
+            f,
+            "{} {} ({:#06x}), model specific code {:#06x}",
+            corrected,
+            error_kind(self.error_code()),
+            self.error_code(),
+            self.model_code()
+        )?;
+        if self.context_corrupt() {
+            write!(f, ", processor context corrupt")?;
+        }
+        if self.overflow() {
+            write!(f, ", later errors were lost")?;
+        }
+        Ok(())
+    }
+}
+
+/// Name the kind of an MCA error code: one of the simple codes, or the class of a compound one. Bit 12 of compound
+/// codes only says whether corrected errors are filtered, and is ignored.
+pub fn error_kind(code: u16) -> &'static str {
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified error",
+        0x0002 => "microcode ROM parity error",
+        0x0003 => "external error",
+        0x0004 => "FRC error",
+        0x0005 => "internal parity error",
+        0x0006 => "SMM handler code access violation",
+        0x0400..=0x07ff => "internal error",
+        _ => {
+            let code = code & !0x1000;
+            if code & 0xf800 == 0x0800 {
+                "bus or interconnect error"
+            } else if code & 0xff00 == 0x0100 {
+                "cache hierarchy error"
+            } else if code & 0xff80 == 0x0080 {
+                "memory controller error"
+            } else if code & 0xfff0 == 0x0010 {
+                "TLB error"
+            } else if code & 0xfffc == 0x000c {
+                "generic cache hierarchy error"
+            } else {
+                "unknown error"
+            }
+        }
+    }
+}
+
+/// An error logged in a bank, with its address and extra information when the bank has them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorRecord {
+    pub bank: usize,
+    pub status: BankStatus,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+/// Writes the record on one line, e.g. "Bank 4: corrected cache hierarchy error (0x0135), ..., address 0x1234".
+impl fmt::Display for ErrorRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bank {}: {}", self.bank, self.status)?;
+        if let Some(addr) = self.addr {
+            write!(f, ", address {:#x}", addr)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {:#x}", misc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the CPU has the machine check exception and architecture, and so the MSRs read here.
+pub fn supported() -> bool {
+    let edx = __cpuid(1).edx;
+    edx & CPUID_1_EDX_MCE != 0 && edx & CPUID_1_EDX_MCA != 0
+}
+
+/// Number of banks, or None if the CPU has no MCA.
+pub fn bank_count() -> Option<usize> {
+    supported().then(|| (read_msr(IA32_MCG_CAP) & MCG_CAP_COUNT) as usize)
+}
+
+/// Read IA32_MCG_STATUS, or None if the CPU has no MCA.
+pub fn mcg_status() -> Option<McgStatus> {
+    supported().then(|| McgStatus(read_msr(IA32_MCG_STATUS)))
+}
+
+/// Call f with the error of every bank that holds a valid one. Returns the number of errors. Nothing is allocated, so
+/// this can run in the machine check handler.
+pub fn for_each_error(mut f: impl FnMut(&ErrorRecord)) -> usize {
+    let mut count = 0;
+    for bank in 0..bank_count().unwrap_or(0) {
+        let status = BankStatus(read_msr(ia32_mc_status(bank)));
+        if !status.valid() {
+            continue;
+        }
+
+        let record = ErrorRecord {
+            bank,
+            status,
+            addr: status.addr_valid().then(|| read_msr(ia32_mc_addr(bank))),
+            misc: status.misc_valid().then(|| read_msr(ia32_mc_misc(bank))),
+        };
+        f(&record);
+        count += 1;
+    }
+    count
+}
+
+/// Have machine checks raise #MC, if the CPU has them, and report the errors the banks logged before the kernel
+/// started (e.g. the ones that caused a reset).
+pub fn init() {
+    let Some(banks) = bank_count() else {
+        printlnk!("Machine checks are not supported");
+        return;
+    };
+
+    unsafe {
+        let mut cr4: usize;
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        asm!("mov cr4, {}", in(reg) cr4 | CR4_MCE, options(nostack, preserves_flags));
+    }
+
+    let errors = for_each_error(|record| printlnk!("Machine check error before boot: {}", record));
+    printlnk!(
+        "Machine checks enabled, {} banks, {} errors logged",
+        banks,
+        errors
+    );
+}
+
+/// Report a machine check, with every error the banks logged, and halt.
+///
+/// # Safety
+/// Must only be called from the machine check handler.
+pub unsafe fn handle(frame: &InterruptStackFrame) -> ! {
+    // Never left, as the machine halts
+    let _context = InterruptContext::enter();
+
+    let print = |args: fmt::Arguments| emergency_print(format_args!("{}\n", args));
+    print(format_args!(
+        "Machine check at rip = {:#x}, cs = {:#x}, rsp = {:#x}",
+        frame.ip, frame.cs, frame.sp
+    ));
+    if let Some(status) = mcg_status() {
+        print(format_args!(
+            "IA32_MCG_STATUS = {:#x}: restartable: {}, error at rip: {}, in progress: {}",
+            status.0,
+            status.restartable(),
+            status.error_at_rip(),
+            status.in_progress()
+        ));
+    }
+    if for_each_error(|record| print(format_args!("{}", record))) == 0 {
+        print(format_args!("No bank logged an error"));
+    }
+
+    helper::hcf();
+}
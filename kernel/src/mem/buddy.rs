@@ -6,6 +6,7 @@ use spin::Mutex;
 use crate::{
     consts::PAGE_SIZE,
     helper::{align_up, log2_ceil, log2_floor},
+    idt::without_interrupt,
     primitives::DoublyListHead,
 };
 
@@ -21,6 +22,7 @@ struct Bucket {
 pub struct BuddyAllocator {
     memory: *mut [u8],
     used: usize,
+    allocated_pages: usize, // Number of pages currently handed out, for statistics
     buckets: [Bucket; MAX_ORDER + 1],
 }
 
@@ -61,6 +63,7 @@ pub unsafe fn init(memory: *mut [u8]) {
 
     allocator.memory = ptr::slice_from_raw_parts_mut(final_ptr, final_len);
     allocator.used = 0;
+    allocator.allocated_pages = 0;
 }
 
 impl BuddyAllocator {
@@ -181,16 +184,49 @@ impl BuddyAllocator {
     }
 }
 
+// The allocator is locked with interrupts disabled, so an interrupt handler never finds it locked by the code it
+// interrupted.
 pub unsafe fn alloc_pages_order(order: usize) -> *mut u8 {
-    let mut allocator = BUDDY_ALLOCATOR.lock();
+    without_interrupt(|| {
+        let mut allocator = BUDDY_ALLOCATOR.lock();
 
-    unsafe { allocator.alloc_pages_order(order) }
+        let page = unsafe { allocator.alloc_pages_order(order) };
+        if !page.is_null() {
+            allocator.allocated_pages += 1 << order;
+        }
+        page
+    })
 }
 
 pub unsafe fn free_pages_order(page: *mut u8, order: usize) {
-    let mut allocator = BUDDY_ALLOCATOR.lock();
+    without_interrupt(|| {
+        let mut allocator = BUDDY_ALLOCATOR.lock();
+
+        allocator.allocated_pages -= 1 << order;
+        unsafe { allocator.free_pages_order(page, order) }
+    })
+}
+
+/// Get the number of pages currently allocated.
+pub fn allocated_pages() -> usize {
+    without_interrupt(|| BUDDY_ALLOCATOR.lock().allocated_pages)
+}
+
+/// Get the number of pages the buddy allocator manages, and the number of those that are free, taken together so the
+/// free pages never outnumber the managed ones.
+pub fn page_counts() -> (usize, usize) {
+    without_interrupt(|| {
+        let allocator = BUDDY_ALLOCATOR.lock();
+
+        // Only whole blocks of the max order are ever handed out
+        let total = (allocator.memory.len() / SIZE_OF_MAX_ORDER) << MAX_ORDER;
+        (total, total - allocator.allocated_pages)
+    })
+}
 
-    unsafe { allocator.free_pages_order(page, order) }
+/// Get the memory managed by the buddy allocator.
+pub fn managed_memory() -> *mut [u8] {
+    without_interrupt(|| BUDDY_ALLOCATOR.lock().memory)
 }
 
 #[inline]
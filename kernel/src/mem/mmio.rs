@@ -0,0 +1,110 @@
+use core::ptr::null_mut;
+
+use bitvec::{BitArr, array::BitArray, order::Lsb0};
+use spin::Mutex;
+
+use crate::{
+    consts::{MMIO_BASE, MMIO_SIZE, PAGE_SIZE},
+    helper::align_down,
+    mem::paging::{KERNEL_ADDRESS_SPACE, MapFlags},
+};
+
+const MMIO_PAGES: usize = MMIO_SIZE / PAGE_SIZE;
+
+// Tracks which pages of the MMIO window are in use. MMIO mappings are rare, so a first-fit search is good enough.
+struct MmioAllocator {
+    used: BitArr!(for MMIO_PAGES, in u64, Lsb0),
+}
+
+static MMIO_ALLOCATOR: Mutex<MmioAllocator> = Mutex::new(MmioAllocator {
+    used: BitArray::ZERO,
+});
+
+impl MmioAllocator {
+    // Allocate num_pages contiguous pages, returning the index of the first page.
+    fn alloc(&mut self, num_pages: usize) -> Option<usize> {
+        let mut run = 0;
+        for i in 0..MMIO_PAGES {
+            if self.used[i] {
+                run = 0;
+                continue;
+            }
+
+            run += 1;
+            if run == num_pages {
+                let first = i + 1 - num_pages;
+                self.used[first..=i].fill(true);
+                return Some(first);
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, first: usize, num_pages: usize) {
+        debug_assert!(self.used[first..first + num_pages].all());
+        self.used[first..first + num_pages].fill(false);
+    }
+}
+
+/// Flags used by `map_mmio`: uncached, not executable.
+pub const MMIO_FLAGS: MapFlags = MapFlags {
+    writable: true,
+    executable: false,
+    write_through: false,
+    cache_disable: true,
+};
+
+/// Map the physical range [phys_addr, phys_addr + len) uncached into the MMIO window.
+/// Returns a pointer to phys_addr, or null if the window is full.
+///
+/// # Safety
+/// The range must be device memory (or memory nothing else uses), as writes through the pointer go straight to it.
+/// The kernel address space must be set up.
+pub unsafe fn map_mmio(phys_addr: usize, len: usize) -> *mut u8 {
+    unsafe { map_mmio_with_flags(phys_addr, len, MMIO_FLAGS) }
+}
+
+/// Same as `map_mmio`, but with custom flags (e.g. write-through).
+///
+/// # Safety
+/// See `map_mmio`. The flags must suit the memory, e.g. RAM the rest of the kernel maps cached must not be mapped
+/// uncached.
+pub unsafe fn map_mmio_with_flags(phys_addr: usize, len: usize, flags: MapFlags) -> *mut u8 {
+    let phys_start = align_down(phys_addr, PAGE_SIZE);
+    let num_pages = (phys_addr - phys_start + len).div_ceil(PAGE_SIZE);
+
+    let Some(first) = MMIO_ALLOCATOR.lock().alloc(num_pages) else {
+        return null_mut();
+    };
+
+    let virt_start = MMIO_BASE + first * PAGE_SIZE;
+    for i in 0..num_pages {
+        unsafe {
+            KERNEL_ADDRESS_SPACE.map_kernel_page(
+                virt_start + i * PAGE_SIZE,
+                phys_start + i * PAGE_SIZE,
+                flags,
+            )
+        };
+    }
+
+    (virt_start + (phys_addr - phys_start)) as *mut u8
+}
+
+/// Unmap a range previously mapped with `map_mmio`.
+///
+/// # Safety
+/// ptr and len must be the same as when mapping, and nothing may use the mapping afterwards.
+pub unsafe fn unmap_mmio(ptr: *mut u8, len: usize) {
+    let virt_start = align_down(ptr as usize, PAGE_SIZE);
+    let num_pages = (ptr as usize - virt_start + len).div_ceil(PAGE_SIZE);
+    assert!(virt_start >= MMIO_BASE && virt_start + num_pages * PAGE_SIZE <= MMIO_BASE + MMIO_SIZE);
+
+    for i in 0..num_pages {
+        unsafe { KERNEL_ADDRESS_SPACE.unmap_kernel_page(virt_start + i * PAGE_SIZE) };
+    }
+
+    MMIO_ALLOCATOR
+        .lock()
+        .free((virt_start - MMIO_BASE) / PAGE_SIZE, num_pages);
+}
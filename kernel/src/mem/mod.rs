@@ -1,3 +1,40 @@
 pub mod buddy;
-pub mod page_table;
+pub mod mmio;
+pub mod page_meta;
+pub mod paging;
 pub mod slab;
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+pub use mmio::{map_mmio, unmap_mmio};
+
+// The physical memory the bootloader reported as RAM, sorted and with adjacent ranges merged.
+static mut RAM: Vec<Range<usize>> = Vec::new();
+
+/// Remember the physical ranges that are RAM, for is_ram(). Called once the heap is up.
+pub fn set_ram(ranges: impl IntoIterator<Item = Range<usize>>) {
+    let mut ranges: Vec<Range<usize>> = ranges
+        .into_iter()
+        .filter(|range| !range.is_empty())
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    unsafe { RAM = merged };
+}
+
+/// Whether the physical range is all RAM, which the direct map can be read through without touching a device.
+pub fn is_ram(range: Range<usize>) -> bool {
+    let ram = unsafe { &RAM };
+    range.is_empty()
+        || ram
+            .iter()
+            .any(|ram| ram.start <= range.start && range.end <= ram.end)
+}
@@ -0,0 +1,82 @@
+//! Per physical page metadata.
+//!
+//! There is one entry for every page managed by the buddy allocator. For now, it only holds a reference count,
+//! which lets several address spaces map the same physical page.
+
+use core::ptr::{self, null_mut};
+
+use crate::{
+    consts::PAGE_SIZE,
+    mem::buddy::{self, alloc_pages_panic, free_pages_order},
+};
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct PageMeta {
+    pub refcount: u32,
+}
+
+static mut PAGE_META: *mut [PageMeta] = ptr::slice_from_raw_parts_mut(null_mut(), 0);
+
+/// Allocate the page metadata array.
+///
+/// # Safety
+/// Must be called once, right after the buddy allocator is initialized, and before any other function here.
+pub unsafe fn init() {
+    let memory = buddy::managed_memory();
+    let num_pages = memory.len() / PAGE_SIZE;
+    let size = num_pages * size_of::<PageMeta>();
+
+    unsafe {
+        let array = alloc_pages_panic(size.div_ceil(PAGE_SIZE)) as *mut PageMeta;
+        array.write_bytes(0, num_pages);
+        PAGE_META = ptr::slice_from_raw_parts_mut(array, num_pages);
+    }
+}
+
+// Get the metadata of a page (virtual address in the direct mapping).
+unsafe fn page_meta(page: *mut u8) -> *mut PageMeta {
+    let memory = buddy::managed_memory();
+    let index = (page as usize - memory.addr()) / PAGE_SIZE;
+
+    unsafe {
+        assert!(index < PAGE_META.len());
+        (PAGE_META as *mut PageMeta).add(index)
+    }
+}
+
+/// Get the reference count of a page.
+///
+/// # Safety
+/// page must be the direct mapping address of a page the buddy allocator manages, and init() must have been called.
+pub unsafe fn page_refcount(page: *mut u8) -> u32 {
+    unsafe { (*page_meta(page)).refcount }
+}
+
+/// Take a reference to a page. Freshly allocated pages start with a reference count of zero.
+///
+/// # Safety
+/// See `page_refcount`. The page must be allocated, and the reference must be dropped with `put_page`.
+pub unsafe fn get_page(page: *mut u8) {
+    unsafe { (*page_meta(page)).refcount += 1 };
+}
+
+/// Drop a reference to a page. The page is returned to the buddy allocator when the last reference is dropped.
+///
+/// # Safety
+/// See `page_refcount`. The caller must own the reference it drops, and must not use the page afterwards unless it
+/// holds another one.
+pub unsafe fn put_page(page: *mut u8) {
+    unsafe {
+        let meta = page_meta(page);
+        assert!(
+            (*meta).refcount > 0,
+            "put_page on a page without references"
+        );
+
+        (*meta).refcount -= 1;
+        if (*meta).refcount == 0 {
+            free_pages_order(page, 0);
+        }
+    }
+}
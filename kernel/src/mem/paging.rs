@@ -0,0 +1,674 @@
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    ptr::null_mut,
+};
+
+use arbitrary_int::{traits::Integer, u3, u4, u7, u9, u11, u12, u40};
+use bitbybit::bitfield;
+
+use crate::{
+    consts::{GIANT_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE, PHYS_MEM_OFFSET, USERSPACE_LIMIT},
+    helper::{p2v, v2p},
+    mem::buddy::{self, alloc_pages_panic, free_pages},
+    printlnk,
+};
+
+// The official x86-64 names for these structures are complicated, so we use simpler names here.
+// Top 3 page table levels: Page Directory (P4, P3, P2)
+// Bottom page table level: Page Table (P1)
+
+#[bitfield(u64)]
+pub struct PageDirectoryEntry {
+    #[bit(0, rw)]
+    present: bool,
+    #[bit(1, rw)]
+    writable: bool,
+    #[bit(2, rw)]
+    user_accessible: bool,
+    #[bit(3, rw)]
+    write_through: bool,
+    #[bit(4, rw)]
+    cache_disable: bool,
+    #[bit(5, rw)]
+    accessed: bool,
+    // Only meaningful in entries that map a page (huge pages), ignored otherwise.
+    #[bit(6, rw)]
+    dirty: bool,
+    #[bit(7, rw)]
+    page_size: bool,
+
+    #[bits(8..=11, rw)]
+    available_low: u4,
+
+    #[bits(12..=51, rw)]
+    inner_addr: u40,
+
+    #[bits(52..=62, rw)]
+    available_high: u11,
+
+    #[bit(63, rw)]
+    execute_disable: bool,
+}
+
+#[bitfield(u64)]
+pub struct PageTableEntry {
+    #[bit(0, rw)]
+    present: bool,
+    #[bit(1, rw)]
+    writable: bool,
+    #[bit(2, rw)]
+    user_accessible: bool,
+    #[bit(3, rw)]
+    write_through: bool,
+    #[bit(4, rw)]
+    cache_disable: bool,
+    #[bit(5, rw)]
+    accessed: bool,
+    #[bit(6, rw)]
+    dirty: bool,
+    #[bit(7, rw)]
+    page_attribute_table: bool,
+    #[bit(8, rw)]
+    global: bool,
+
+    #[bits(9..=11, rw)]
+    available_low: u3,
+
+    #[bits(12..=51, rw)]
+    inner_addr: u40,
+
+    #[bits(52..=58, rw)]
+    available_high: u7,
+
+    #[bits(59..=62, rw)]
+    protection_key: u4,
+
+    #[bit(63, rw)]
+    execute_disable: bool,
+}
+
+impl PageDirectoryEntry {
+    #[inline]
+    pub const fn addr(&self) -> u64 {
+        self.inner_addr().value() << 12
+    }
+
+    #[inline]
+    pub fn with_addr(&self, addr: u64) -> Self {
+        self.with_inner_addr(u40::masked_new(addr >> 12))
+    }
+
+    #[inline]
+    pub fn set_addr(&mut self, addr: u64) {
+        self.set_inner_addr(u40::masked_new(addr >> 12));
+    }
+}
+
+impl PageTableEntry {
+    #[inline]
+    pub const fn addr(&self) -> u64 {
+        self.inner_addr().value() << 12
+    }
+
+    #[inline]
+    pub fn with_addr(&self, addr: u64) -> Self {
+        self.with_inner_addr(u40::masked_new(addr >> 12))
+    }
+
+    #[inline]
+    pub fn set_addr(&mut self, addr: u64) {
+        self.set_inner_addr(u40::masked_new(addr >> 12));
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct PageDirectory(pub [PageDirectoryEntry; 512]);
+
+#[repr(C, align(4096))]
+pub struct PageTable(pub [PageTableEntry; 512]);
+
+#[bitfield(u64)]
+pub struct VirtAddr {
+    #[bits(0..=11, r)]
+    offset: u12,
+    #[bits(12..=20, r)]
+    p1_index: u9,
+    #[bits(21..=29, r)]
+    p2_index: u9,
+    #[bits(30..=38, r)]
+    p3_index: u9,
+    #[bits(39..=47, r)]
+    p4_index: u9,
+}
+
+/// Result of resolving a virtual address, along with the permissions of the translation.
+/// Permissions are the AND of the permissions along the walk, e.g. a P4 entry without the user bit
+/// makes the whole translation kernel-only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolved {
+    pub phys: usize,
+    pub writable: bool,
+    pub user: bool,
+    pub nx: bool,
+}
+
+impl Resolved {
+    fn combine(&mut self, entry: PageDirectoryEntry) {
+        self.writable &= entry.writable();
+        self.user &= entry.user_accessible();
+        self.nx |= entry.execute_disable();
+    }
+}
+
+/// Resolve a virtual address into a physical address given the P4 page directory, along with its permissions.
+/// 2 MiB and 1 GiB pages (P2 and P3 entries with page_size set) are supported.
+///
+/// # Safety
+/// p4_table must point to a valid, live P4 page directory, whose tables are reachable through the direct mapping.
+pub unsafe fn resolve(p4_table: *mut PageDirectory, virt_addr: usize) -> Option<Resolved> {
+    let virt_addr = VirtAddr::new_with_raw_value(virt_addr as u64);
+    let mut resolved = Resolved {
+        phys: 0,
+        writable: true,
+        user: true,
+        nx: false,
+    };
+
+    unsafe {
+        let p4_entry = (*p4_table).0[virt_addr.p4_index().as_usize()];
+        if !p4_entry.present() {
+            return None;
+        }
+        resolved.combine(p4_entry);
+
+        let p3_table = p2v(p4_entry.addr() as usize) as *mut PageDirectory;
+        let p3_entry = (*p3_table).0[virt_addr.p3_index().as_usize()];
+        if !p3_entry.present() {
+            return None;
+        }
+        resolved.combine(p3_entry);
+        if p3_entry.page_size() {
+            // 1 GiB page, the walk stops here
+            let base = p3_entry.addr() as usize & !(GIANT_PAGE_SIZE - 1);
+            resolved.phys = base + (virt_addr.raw_value() as usize & (GIANT_PAGE_SIZE - 1));
+            return Some(resolved);
+        }
+
+        let p2_table = p2v(p3_entry.addr() as usize) as *mut PageDirectory;
+        let p2_entry = (*p2_table).0[virt_addr.p2_index().as_usize()];
+        if !p2_entry.present() {
+            return None;
+        }
+        resolved.combine(p2_entry);
+        if p2_entry.page_size() {
+            // Huge page, the walk stops here
+            let base = p2_entry.addr() as usize & !(HUGE_PAGE_SIZE - 1);
+            resolved.phys = base + (virt_addr.raw_value() as usize & (HUGE_PAGE_SIZE - 1));
+            return Some(resolved);
+        }
+
+        let p1_table = p2v(p2_entry.addr() as usize) as *mut PageTable;
+        let p1_entry = (*p1_table).0[virt_addr.p1_index().as_usize()];
+        if !p1_entry.present() {
+            return None;
+        }
+        resolved.writable &= p1_entry.writable();
+        resolved.user &= p1_entry.user_accessible();
+        resolved.nx |= p1_entry.execute_disable();
+
+        resolved.phys = p1_entry.addr() as usize + virt_addr.offset().as_usize();
+        Some(resolved)
+    }
+}
+
+/// Resolve a virtual address into a physical address given the P4 page directory.
+/// Page entry permissions are ignored.
+///
+/// # Safety
+/// See `resolve`.
+pub unsafe fn resolve_virt_addr(p4_table: *mut PageDirectory, virt_addr: usize) -> Option<usize> {
+    unsafe { resolve(p4_table, virt_addr) }.map(|resolved| resolved.phys)
+}
+
+// Effective permissions of a mapping, combined over all levels of the hierarchy.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct MappingFlags {
+    writable: bool,
+    executable: bool,
+    user_accessible: bool,
+    global: bool,
+}
+
+// A contiguous run of mappings with identical permissions and page size.
+struct MappingRun {
+    virt_start: usize,
+    virt_end: usize,
+    phys_start: usize,
+    page_size: usize,
+    flags: MappingFlags,
+    // Runs in the direct mapping are collapsed regardless of permissions and page size.
+    direct_map: bool,
+}
+
+impl MappingRun {
+    fn try_extend(&mut self, other: &MappingRun) -> bool {
+        let contiguous = self.virt_end == other.virt_start
+            && self.phys_start + (self.virt_end - self.virt_start) == other.phys_start;
+        if !contiguous {
+            return false;
+        }
+        if !(self.direct_map && other.direct_map)
+            && (self.page_size != other.page_size || self.flags != other.flags)
+        {
+            return false;
+        }
+
+        self.virt_end = other.virt_end;
+        true
+    }
+
+    fn print(&self) {
+        let phys_end = self.phys_start + (self.virt_end - self.virt_start);
+        if self.direct_map {
+            printlnk!(
+                "{:#018x}-{:#018x} -> {:#x}-{:#x} (direct map)",
+                self.virt_start,
+                self.virt_end,
+                self.phys_start,
+                phys_end
+            );
+            return;
+        }
+
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        let page_size = match self.page_size {
+            PAGE_SIZE => "4K",
+            HUGE_PAGE_SIZE => "2M",
+            _ => "1G",
+        };
+        printlnk!(
+            "{:#018x}-{:#018x} -> {:#x}-{:#x} {}{}{}{} {}",
+            self.virt_start,
+            self.virt_end,
+            self.phys_start,
+            phys_end,
+            flag(self.flags.writable, 'W'),
+            flag(self.flags.executable, 'X'),
+            flag(self.flags.user_accessible, 'U'),
+            flag(self.flags.global, 'G'),
+            page_size
+        );
+    }
+}
+
+// Walk the page table at the given level (4 = P4, 1 = P1) and call f for every leaf mapping overlapping [start, end).
+// P1 entries are read as PageDirectoryEntry, the bits used here are the same in both layouts.
+unsafe fn walk_leaves(
+    table: *const PageDirectory,
+    level: usize,
+    base: usize,
+    start: usize,
+    end: usize,
+    parent_flags: MappingFlags,
+    f: &mut impl FnMut(MappingRun),
+) {
+    let entry_size = PAGE_SIZE << (9 * (level - 1));
+
+    for index in 0..512 {
+        let mut virt = base + index * entry_size;
+        if level == 4 && index >= 256 {
+            // Sign extend to get a canonical address
+            virt |= 0xffff_0000_0000_0000;
+        }
+        if virt >= end || virt + (entry_size - 1) < start {
+            continue;
+        }
+
+        let entry = unsafe { (*table).0[index] };
+        if !entry.present() {
+            continue;
+        }
+
+        let flags = MappingFlags {
+            writable: parent_flags.writable && entry.writable(),
+            executable: parent_flags.executable && !entry.execute_disable(),
+            user_accessible: parent_flags.user_accessible && entry.user_accessible(),
+            // Bit 8 is the global bit in leaf entries
+            global: entry.raw_value() & (1 << 8) != 0,
+        };
+
+        if level == 1 || (level <= 3 && entry.page_size()) {
+            let phys = entry.addr() as usize & !(entry_size - 1);
+            f(MappingRun {
+                virt_start: virt,
+                virt_end: virt + entry_size,
+                phys_start: phys,
+                page_size: entry_size,
+                flags,
+                direct_map: virt >= PHYS_MEM_OFFSET && virt - PHYS_MEM_OFFSET == phys,
+            });
+        } else {
+            let next = p2v(entry.addr() as usize) as *const PageDirectory;
+            unsafe { walk_leaves(next, level - 1, virt, start, end, flags, f) };
+        }
+    }
+}
+
+/// Print the mappings in the virtual range [start, end) given the P4 page directory.
+/// One line is printed per contiguous run of mappings with identical permissions and page size.
+/// The direct mapping of physical memory is summarized so the output stays short.
+///
+/// # Safety
+/// See `resolve`. The page tables must not change during the walk.
+pub unsafe fn dump_range(p4_table: *mut PageDirectory, start: usize, end: usize) {
+    let all = MappingFlags {
+        writable: true,
+        executable: true,
+        user_accessible: true,
+        global: false,
+    };
+
+    let mut current: Option<MappingRun> = None;
+    unsafe {
+        walk_leaves(p4_table, 4, 0, start, end, all, &mut |run| {
+            if let Some(current) = &mut current
+                && current.try_extend(&run)
+            {
+                return;
+            }
+            if let Some(previous) = current.replace(run) {
+                previous.print();
+            }
+        });
+    }
+    if let Some(current) = current {
+        current.print();
+    }
+}
+
+/// Invalidate the TLB entry of the page containing the given virtual address.
+///
+/// # Safety
+/// Only the TLB of the current CPU is flushed, and only for the active page tables. The caller must make sure no
+/// stale entry for the page remains in use elsewhere.
+pub unsafe fn flush_tlb_page(addr: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags)) };
+}
+
+/// Get the (virtual) address of the active P4 page directory.
+pub unsafe fn get_active_page_directory() -> *mut PageDirectory {
+    let p4_table: usize;
+    unsafe { asm!("mov {}, cr3", out(reg) p4_table, options(nomem, nostack, preserves_flags)) };
+    p2v(p4_table) as *mut PageDirectory
+}
+
+/// Set the active P4 page directory (virtual address).
+pub unsafe fn set_active_page_directory(addr: *const PageDirectory) {
+    let phys_addr = v2p(addr as usize);
+    unsafe { asm!("mov cr3, {}", in(reg) phys_addr, options(nomem, nostack, preserves_flags)) };
+}
+
+/// Flags of a kernel mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapFlags {
+    pub writable: bool,
+    pub executable: bool,
+    pub write_through: bool,
+    pub cache_disable: bool,
+}
+
+impl MapFlags {
+    /// Readable and writable, not executable, cacheable.
+    pub const DATA: MapFlags = MapFlags {
+        writable: true,
+        executable: false,
+        write_through: false,
+        cache_disable: false,
+    };
+}
+
+/// Handle to the kernel page tables.
+///
+/// The upper half (P4 entries 256..512) is shared by every address space. All P3 tables of the upper half are
+/// allocated at boot, so the P4 entries never change afterwards and kernel mappings made later are visible in every task.
+pub struct KernelAddressSpace {
+    p4_table: *mut PageDirectory,
+}
+
+pub static mut KERNEL_ADDRESS_SPACE: KernelAddressSpace = KernelAddressSpace {
+    p4_table: null_mut(),
+};
+
+impl KernelAddressSpace {
+    /// Capture the active page tables (set up by the bootloader) as the kernel page tables.
+    ///
+    /// # Safety
+    /// Must be called once at boot, before any other method, while the bootloader's page tables are still active.
+    pub unsafe fn init(&mut self) {
+        unsafe { self.p4_table = get_active_page_directory() };
+    }
+
+    /// Get the P4 page table pointer.
+    pub fn p4_table(&self) -> *mut PageDirectory {
+        assert!(!self.p4_table.is_null());
+        self.p4_table
+    }
+
+    /// Unmap all lower half memory.
+    /// The rust-osdev bootloader leaves some junk in the lower half memory, so we have to unmap it ourselves.
+    /// https://github.com/rust-osdev/bootloader/issues/470
+    ///
+    /// # Safety
+    /// Nothing may use lower half memory afterwards, and the kernel page tables must be the active ones.
+    pub unsafe fn unmap_lower_half(&mut self) {
+        unsafe {
+            let p4_table = self.p4_table();
+            for i in 0..256 {
+                // Each entry maps 512 GiB, so unmapping the first 256 entries unmaps the first 128 TiB.
+                (*p4_table).0[i] = PageDirectoryEntry::ZERO;
+            }
+
+            // Flush the TLB by reloading CR3.
+            set_active_page_directory(p4_table);
+        }
+    }
+
+    /// Rebuild the direct mapping of physical memory in kernel-owned page tables, covering [0, phys_mem_end).
+    /// 1 GiB pages are used if the CPU supports them, 2 MiB pages otherwise. The old P4 table and the old tables of
+    /// the direct mapping are freed if the buddy allocator owns them. Returns how many were freed.
+    ///
+    /// # Safety
+    /// Must be called once the buddy allocator is ready, and before `populate_upper_half`. No other CPU may use the
+    /// direct map yet, and phys_mem_end must cover all RAM, as memory past it is no longer mapped.
+    pub unsafe fn remap_direct_map(&mut self, phys_mem_end: usize) -> usize {
+        // CPUID.80000001H:EDX[26] reports support for 1 GiB pages.
+        let giant_pages = __cpuid(0x80000001).edx & (1 << 26) != 0;
+
+        unsafe {
+            let old_p4_table = self.p4_table();
+            let p4_table = alloc_pages_panic(1) as *mut PageDirectory;
+            p4_table.copy_from_nonoverlapping(old_p4_table, 1);
+
+            let first_index = VirtAddr::new_with_raw_value(PHYS_MEM_OFFSET as u64)
+                .p4_index()
+                .as_usize();
+            let num_p4_entries = phys_mem_end.div_ceil(512 * GIANT_PAGE_SIZE);
+            for i in first_index..first_index + num_p4_entries {
+                (*p4_table).0[i] = PageDirectoryEntry::ZERO;
+            }
+
+            let entry = PageDirectoryEntry::ZERO
+                .with_present(true)
+                .with_writable(true)
+                .with_execute_disable(true)
+                .with_page_size(true);
+
+            if giant_pages {
+                for phys_addr in (0..phys_mem_end).step_by(GIANT_PAGE_SIZE) {
+                    let virt = VirtAddr::new_with_raw_value(p2v(phys_addr) as u64);
+                    let p3_table = get_or_create_table(p4_table, virt.p4_index().as_usize());
+                    (*p3_table).0[virt.p3_index().as_usize()] = entry.with_addr(phys_addr as u64);
+                }
+            } else {
+                for phys_addr in (0..phys_mem_end).step_by(HUGE_PAGE_SIZE) {
+                    let virt = VirtAddr::new_with_raw_value(p2v(phys_addr) as u64);
+                    let p3_table = get_or_create_table(p4_table, virt.p4_index().as_usize());
+                    let p2_table = get_or_create_table(p3_table, virt.p3_index().as_usize());
+                    (*p2_table).0[virt.p2_index().as_usize()] = entry.with_addr(phys_addr as u64);
+                }
+            }
+
+            set_active_page_directory(p4_table);
+            self.p4_table = p4_table;
+
+            // Nothing uses the old tables since the reload. Those the bootloader built lie outside the buddy
+            // allocator's memory, and stay reserved; the others were allocated by the kernel and are given back.
+            let mut freed = 0;
+            for i in first_index..first_index + num_p4_entries {
+                freed += free_owned_tables((*old_p4_table).0[i], 3);
+            }
+            freed += free_owned_table(old_p4_table);
+            freed
+        }
+    }
+
+    /// Allocate the P3 tables for every upper half P4 entry that is not present yet.
+    ///
+    /// # Safety
+    /// Must be called once the buddy allocator is ready, and before any user address space is created.
+    pub unsafe fn populate_upper_half(&mut self) {
+        unsafe {
+            let p4_table = self.p4_table();
+            for i in 256..512 {
+                get_or_create_table(p4_table, i);
+            }
+        }
+    }
+
+    /// Resolve a virtual address to a physical address.
+    pub fn resolve(&self, virt_addr: usize) -> Option<Resolved> {
+        unsafe { resolve(self.p4_table(), virt_addr) }
+    }
+
+    /// Switch to the kernel page tables.
+    ///
+    /// # Safety
+    /// The current code, stack and data must not live in the lower half, which the kernel page tables don't map.
+    pub unsafe fn switch_to_this(&self) {
+        unsafe { set_active_page_directory(self.p4_table()) };
+    }
+
+    /// Map a kernel virtual page (aligned to PAGE_SIZE) to a physical page.
+    /// Missing page tables are allocated. Panics if the page is inside a huge page.
+    ///
+    /// # Safety
+    /// The virtual page must not be in use, and the flags must suit the physical page, e.g. RAM must not be mapped
+    /// uncached. `populate_upper_half` must have been called.
+    pub unsafe fn map_kernel_page(&mut self, virt_addr: usize, phys_addr: usize, flags: MapFlags) {
+        assert!(virt_addr >= USERSPACE_LIMIT && virt_addr.is_multiple_of(PAGE_SIZE));
+
+        let virt = VirtAddr::new_with_raw_value(virt_addr as u64);
+        unsafe {
+            let p4_table = self.p4_table();
+            // The P3 table must already exist, otherwise the mapping wouldn't be shared.
+            assert!((*p4_table).0[virt.p4_index().as_usize()].present());
+
+            let p3_table = get_or_create_table(p4_table, virt.p4_index().as_usize());
+            let p2_table = get_or_create_table(p3_table, virt.p3_index().as_usize());
+            let p1_table =
+                get_or_create_table(p2_table, virt.p2_index().as_usize()) as *mut PageTable;
+
+            (*p1_table).0[virt.p1_index().as_usize()] = PageTableEntry::ZERO
+                .with_present(true)
+                .with_writable(flags.writable)
+                .with_write_through(flags.write_through)
+                .with_cache_disable(flags.cache_disable)
+                .with_execute_disable(!flags.executable)
+                .with_addr(phys_addr as u64);
+
+            flush_tlb_page(virt_addr);
+        }
+    }
+
+    /// Unmap a kernel virtual page previously mapped with `map_kernel_page`.
+    /// Page tables are kept around, as they may be reused later.
+    ///
+    /// # Safety
+    /// Nothing may use the page afterwards. Only the TLB of the current CPU is flushed.
+    pub unsafe fn unmap_kernel_page(&mut self, virt_addr: usize) {
+        let virt = VirtAddr::new_with_raw_value(virt_addr as u64);
+        unsafe {
+            let Some(p3_table) = get_table(self.p4_table(), virt.p4_index().as_usize()) else {
+                return;
+            };
+            let Some(p2_table) = get_table(p3_table, virt.p3_index().as_usize()) else {
+                return;
+            };
+            let Some(p1_table) = get_table(p2_table, virt.p2_index().as_usize()) else {
+                return;
+            };
+
+            (*(p1_table as *mut PageTable)).0[virt.p1_index().as_usize()] = PageTableEntry::ZERO;
+
+            flush_tlb_page(virt_addr);
+        }
+    }
+}
+
+// Free the table the entry points to, a P3 table at level 3 down to a P1 table at level 1, and the tables below it, if
+// the buddy allocator owns them. Returns how many were freed.
+unsafe fn free_owned_tables(entry: PageDirectoryEntry, level: usize) -> usize {
+    if !entry.present() || entry.page_size() {
+        return 0;
+    }
+    let table = p2v(entry.addr() as usize) as *mut PageDirectory;
+    let mut freed = 0;
+    if level > 1 {
+        for i in 0..512 {
+            freed += unsafe { free_owned_tables((*table).0[i], level - 1) };
+        }
+    }
+    freed + unsafe { free_owned_table(table) }
+}
+
+// Free the table if the buddy allocator owns it. Returns 1 if it was freed.
+unsafe fn free_owned_table(table: *mut PageDirectory) -> usize {
+    let memory = buddy::managed_memory();
+    let start = memory as *mut u8 as usize;
+    if !(start..start + memory.len()).contains(&(table as usize)) {
+        return 0;
+    }
+    unsafe { free_pages(table as *mut u8, 1) };
+    1
+}
+
+// Get the next level table of an entry, or None if the entry is not present.
+unsafe fn get_table(table: *mut PageDirectory, index: usize) -> Option<*mut PageDirectory> {
+    let entry = unsafe { (*table).0[index] };
+    if !entry.present() {
+        return None;
+    }
+
+    // Never descend into a huge page
+    assert!(!entry.page_size());
+    Some(p2v(entry.addr() as usize) as *mut PageDirectory)
+}
+
+// Get the next level table of an entry, allocating it if the entry is not present.
+// Kernel page tables are never freed.
+unsafe fn get_or_create_table(table: *mut PageDirectory, index: usize) -> *mut PageDirectory {
+    unsafe {
+        if let Some(next) = get_table(table, index) {
+            return next;
+        }
+
+        let new_table = alloc_pages_panic(1) as *mut PageDirectory;
+        new_table.write_bytes(0, 1);
+        (*table).0[index] = PageDirectoryEntry::ZERO
+            .with_present(true)
+            .with_writable(true)
+            .with_addr(v2p(new_table as usize) as u64);
+
+        new_table
+    }
+}
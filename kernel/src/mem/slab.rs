@@ -15,6 +15,8 @@ use spin::Mutex;
 use crate::{
     consts::PAGE_SIZE,
     helper::log2_floor,
+    idt::without_interrupt,
+    irq,
     mem::buddy::{alloc_pages_order, calculate_order, free_pages_order},
     primitives::SinglyListHead,
 };
@@ -41,6 +43,9 @@ impl Cache {
 struct SlabAllocator {
     // Caches MUST be sorted by obj_size in ascending order
     caches: [Cache; 8],
+
+    allocated_objects: usize, // Number of objects allocated from the caches, for statistics
+    allocated_bytes: usize,   // Total size of those objects, for statistics
 }
 
 impl SlabAllocator {
@@ -56,6 +61,8 @@ impl SlabAllocator {
                 Cache::new(1024, 2), // 1024 bytes, 2 page per slab
                 Cache::new(2048, 2), // 2048 bytes, 2 page per slab
             ],
+            allocated_objects: 0,
+            allocated_bytes: 0,
         }
     }
 
@@ -82,9 +89,12 @@ impl SlabAllocator {
         if let Some(cache) = cache {
             // Allocate from the slab allocator.
 
+            let obj_size = cache.obj_size;
             let obj = unsafe { cache.freelist.pop() };
             if !obj.is_null() {
                 // Found a free object. Return it directly.
+                self.allocated_objects += 1;
+                self.allocated_bytes += obj_size;
                 obj as *mut u8
             } else {
                 // No free object, allocate a new slab.
@@ -109,6 +119,8 @@ impl SlabAllocator {
 
                 // Pop one object to return.
                 let obj = unsafe { cache.freelist.pop() };
+                self.allocated_objects += 1;
+                self.allocated_bytes += obj_size;
                 obj as *mut u8
             }
         } else {
@@ -125,7 +137,10 @@ impl SlabAllocator {
         if let Some(cache) = cache {
             // Free to the slab allocator.
 
-            unsafe { cache.freelist.insert_after(ptr as *mut _) }
+            let obj_size = cache.obj_size;
+            unsafe { cache.freelist.insert_after(ptr as *mut _) };
+            self.allocated_objects -= 1;
+            self.allocated_bytes -= obj_size;
         } else {
             // Free to the buddy allocator.
 
@@ -164,25 +179,38 @@ pub struct SlabAllocatorWrapper(Mutex<SlabAllocator>);
 
 unsafe impl GlobalAlloc for SlabAllocatorWrapper {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        irq::assert_not_in_interrupt("Heap allocation");
         let size = max(layout.size(), layout.align());
 
-        unsafe { self.0.lock().alloc(size) }
+        without_interrupt(|| unsafe { self.0.lock().alloc(size) })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let size = max(layout.size(), layout.align());
 
-        unsafe { self.0.lock().dealloc(ptr, size) }
+        without_interrupt(|| unsafe { self.0.lock().dealloc(ptr, size) })
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        irq::assert_not_in_interrupt("Heap allocation");
         let old_size = max(layout.size(), layout.align());
         let new_size = max(new_size, layout.align());
 
-        unsafe { self.0.lock().realloc(ptr, old_size, new_size) }
+        without_interrupt(|| unsafe { self.0.lock().realloc(ptr, old_size, new_size) })
     }
 }
 
 #[global_allocator]
 pub static SLAB_ALLOCATOR: SlabAllocatorWrapper =
     SlabAllocatorWrapper(Mutex::new(SlabAllocator::new()));
+
+/// Get the number of objects currently allocated from the slab caches.
+/// Larger allocations come straight from the buddy allocator, and are counted by buddy::allocated_pages().
+pub fn allocated_objects() -> usize {
+    without_interrupt(|| SLAB_ALLOCATOR.0.lock().allocated_objects)
+}
+
+/// Get the number of bytes currently allocated from the slab caches, counting each object at the size of its cache.
+pub fn allocated_bytes() -> usize {
+    without_interrupt(|| SLAB_ALLOCATOR.0.lock().allocated_bytes)
+}
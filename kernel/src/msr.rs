@@ -2,11 +2,49 @@
 
 use core::arch::asm;
 
+use crate::extable_entry;
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
 pub const IA32_EFER: u32 = 0xC0000080;
 pub const IA32_STAR: u32 = 0xC0000081;
 pub const IA32_LSTAR: u32 = 0xC0000082;
 pub const IA32_CSTAR: u32 = 0xC0000083;
 pub const IA32_FMASK: u32 = 0xC0000084;
+pub const IA32_FS_BASE: u32 = 0xC0000100;
+pub const IA32_GS_BASE: u32 = 0xC0000101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+pub const IA32_TSC_AUX: u32 = 0xC0000103;
+
+// Machine check architecture. Each bank has four MSRs, from IA32_MC0_CTL on.
+pub const IA32_MCG_CAP: u32 = 0x179;
+pub const IA32_MCG_STATUS: u32 = 0x17A;
+pub const IA32_MC0_CTL: u32 = 0x400;
+
+// Control-flow Enforcement Technology (CET), for user and supervisor mode.
+pub const IA32_U_CET: u32 = 0x6A0;
+pub const IA32_S_CET: u32 = 0x6A2;
+
+// The status MSR of a machine check bank.
+pub const fn ia32_mc_status(bank: usize) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + 1
+}
+
+// The address MSR of a machine check bank.
+pub const fn ia32_mc_addr(bank: usize) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + 2
+}
+
+// The miscellaneous information MSR of a machine check bank.
+pub const fn ia32_mc_misc(bank: usize) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + 3
+}
+
+/// Why an MSR couldn't be read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrError {
+    /// The CPU raised #GP: it doesn't have the MSR, or the MSR doesn't take the value.
+    GeneralProtection,
+}
 
 // Reads the value of the specified MSR.
 pub fn read_msr(msr: u32) -> u64 {
@@ -36,3 +74,52 @@ pub fn write_msr(msr: u32, value: u64) {
         );
     }
 }
+
+/// Read the MSR, or fail if the CPU doesn't have it, rather than halting on the #GP.
+pub fn read_msr_safe(msr: u32) -> Result<u64, MsrError> {
+    let low: u32;
+    let high: u32;
+    let faulted: u32;
+    unsafe {
+        asm!(
+            "xor {faulted:e}, {faulted:e}",
+            "2: rdmsr",
+            "jmp 3f",
+            "4: mov {faulted:e}, 1",
+            "3:",
+            extable_entry!("2b", "4b"),
+            faulted = out(reg) faulted,
+            in("ecx") msr,
+            out("eax") low,
+            out("edx") high,
+        );
+    }
+    match faulted {
+        0 => Ok(((high as u64) << 32) | (low as u64)),
+        _ => Err(MsrError::GeneralProtection),
+    }
+}
+
+/// Write the value to the MSR, or fail if the CPU doesn't have it or it doesn't take the value, rather than halting
+/// on the #GP.
+pub fn write_msr_safe(msr: u32, value: u64) -> Result<(), MsrError> {
+    let faulted: u32;
+    unsafe {
+        asm!(
+            "xor {faulted:e}, {faulted:e}",
+            "2: wrmsr",
+            "jmp 3f",
+            "4: mov {faulted:e}, 1",
+            "3:",
+            extable_entry!("2b", "4b"),
+            faulted = out(reg) faulted,
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+        );
+    }
+    match faulted {
+        0 => Ok(()),
+        _ => Err(MsrError::GeneralProtection),
+    }
+}
@@ -0,0 +1,150 @@
+//! The NMI handler.
+//!
+//! An NMI can't be masked, so it may arrive anywhere: in another interrupt handler, right after syscall while the
+//! kernel still runs on the user stack, or in the middle of a printk with the output lock held. So it runs on its own
+//! stack (see gdt::NMI_IST), takes no locks, and prints straight to the serial port. It dumps the state of the machine
+//! and returns, unless the nmi_panic option of the kernel command line is set.
+//!
+//! Nothing raises NMIs yet, but the runner can inject one through the QEMU monitor (--nmi-after).
+
+use core::{
+    arch::{asm, naked_asm},
+    fmt::Arguments,
+    mem::offset_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cmdline, io::output::force_print, irq::InterruptContext, isr::InterruptStackFrame,
+    page_fault::read_cr2, startup, user::sched,
+};
+
+/// The general registers of the interrupted code, as nmi_entry() saves them, from the lowest address up.
+#[repr(C)]
+#[derive(Debug)]
+pub struct NmiContext {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rbp: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rdx: usize,
+    pub rcx: usize,
+    pub rbx: usize,
+    pub rax: usize,
+    pub frame: InterruptStackFrame,
+}
+
+// Number of NMIs received, for statistics.
+static NMI_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the number of NMIs received.
+pub fn nmi_count() -> usize {
+    NMI_COUNT.load(Ordering::Relaxed)
+}
+
+// Called by nmi_entry(). Interrupts are disabled, and further NMIs are held until the iretq.
+extern "C" fn handle(context: &NmiContext) {
+    // Left without the deferred work, as a task switch can't happen on the NMI stack
+    let _interrupt = InterruptContext::enter();
+    let count = NMI_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    let cr3: usize;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+
+    let print = |args: Arguments| force_print(format_args!("{}\n", args));
+    let frame = &context.frame;
+    print(format_args!("NMI {} received", count));
+    print(format_args!(
+        "rip = {:#x}, cs = {:#x}, rsp = {:#x}, ss = {:#x}, rflags = {:#x}",
+        frame.ip, frame.cs, frame.sp, frame.ss, frame.flags
+    ));
+    print(format_args!(
+        "rax = {:#x}, rbx = {:#x}, rcx = {:#x}, rdx = {:#x}",
+        context.rax, context.rbx, context.rcx, context.rdx
+    ));
+    print(format_args!(
+        "rsi = {:#x}, rdi = {:#x}, rbp = {:#x}, r8 = {:#x}",
+        context.rsi, context.rdi, context.rbp, context.r8
+    ));
+    print(format_args!(
+        "r9 = {:#x}, r10 = {:#x}, r11 = {:#x}, r12 = {:#x}",
+        context.r9, context.r10, context.r11, context.r12
+    ));
+    print(format_args!(
+        "r13 = {:#x}, r14 = {:#x}, r15 = {:#x}",
+        context.r13, context.r14, context.r15
+    ));
+    print(format_args!("cr2 = {:#x}, cr3 = {:#x}", read_cr2(), cr3));
+
+    // Not through with_current_task(), as the task may be borrowed
+    let task_id = unsafe { sched::CURRENT_TASK.as_ref() }.map(|task| unsafe { (*task.get()).id });
+    match task_id {
+        Some(id) => print(format_args!("Current task: {}", id)),
+        None => print(format_args!("No current task")),
+    }
+    print(format_args!(
+        "Last completed boot stage: {}",
+        startup::boot_stage()
+    ));
+
+    if cmdline::option("nmi_panic").is_some() {
+        panic!("NMI received, and nmi_panic is set");
+    }
+}
+
+/// The entry of the NMI handler, for its IDT entry.
+///
+/// # Safety
+/// Must only be run by the CPU, for an NMI.
+#[unsafe(naked)]
+pub unsafe extern "C" fn nmi_entry() -> ! {
+    naked_asm!(
+        // The CPU aligns the stack to 16 bytes before it pushes the 5 words of the InterruptStackFrame, so after the 15
+        // registers, it is aligned again for the call
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+
+        "cld",          // The interrupted code may have set the direction flag
+        "mov rdi, rsp", // First argument: pointer to the NmiContext
+        "call {handle}",
+
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+        handle = sym handle,
+    )
+}
+
+const _: () = assert!(offset_of!(NmiContext, frame) == 15 * 8);
@@ -0,0 +1,226 @@
+//! The page fault handler.
+//!
+//! A fault from user mode is first offered to a chain of recognizers, each of which handles one kind of fault that is
+//! part of normal operation: lazy pages, copy-on-write pages and running into a stack guard. The first one that
+//! recognizes the fault decides what happens to it. A fault none of them recognizes kills the task, with a report of
+//! what it did wrong, and the scheduler goes on with the other tasks. So does a fault there is no free page to resolve.
+//!
+//! Recognizers run once the handler has left interrupt context, on behalf of the task, as they may allocate pages and
+//! page tables.
+//!
+//! uaccess never dereferences user pointers, so there is no kernel code that is expected to fault. A fault in the
+//! kernel is a bug: it is reported with a backtrace, then the kernel panics.
+
+use core::{arch::asm, fmt};
+
+use crate::{
+    backtrace::{self, Symbolized},
+    consts::USERSPACE_LIMIT,
+    irq::InterruptContext,
+    isr::{InterruptStackFrame, print_maps, print_rip},
+    printlnk,
+    user::{address_space::MapError, sched, task::Task},
+};
+
+/// The error code the CPU pushes for a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(pub usize);
+
+impl PageFaultError {
+    pub const PRESENT: usize = 1 << 0; // The page was present, so the access broke its permissions
+    pub const WRITE: usize = 1 << 1; // The access was a write
+    pub const USER: usize = 1 << 2; // The access came from user mode
+    pub const RESERVED: usize = 1 << 3; // A paging entry had a reserved bit set
+    pub const INSTRUCTION_FETCH: usize = 1 << 4; // The access was an instruction fetch
+
+    pub fn present(self) -> bool {
+        self.0 & Self::PRESENT != 0
+    }
+
+    pub fn write(self) -> bool {
+        self.0 & Self::WRITE != 0
+    }
+
+    pub fn user(self) -> bool {
+        self.0 & Self::USER != 0
+    }
+
+    pub fn reserved(self) -> bool {
+        self.0 & Self::RESERVED != 0
+    }
+
+    pub fn instruction_fetch(self) -> bool {
+        self.0 & Self::INSTRUCTION_FETCH != 0
+    }
+}
+
+/// Describes the fault, e.g. "user write to a present page".
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = if self.user() { "user" } else { "kernel" };
+        let access = if self.instruction_fetch() {
+            "instruction fetch from"
+        } else if self.write() {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if self.present() {
+            "a present page"
+        } else {
+            "a page that is not present"
+        };
+        write!(f, "{} {} {}", mode, access, page)?;
+        if self.reserved() {
+            write!(f, ", with a reserved bit set in its paging entry")?;
+        }
+        Ok(())
+    }
+}
+
+/// A page fault: the address that was accessed, and how.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    pub addr: usize, // From CR2
+    pub error: PageFaultError,
+}
+
+// What a recognizer decided to do with a fault.
+enum Outcome {
+    Resolved, // The page is mapped now, and the access can be retried
+    Kill,     // The task must die, and the recognizer has said why
+}
+
+// Looks at a fault of the current task, and returns None if it isn't the kind of fault it handles.
+type Recognizer = fn(&mut Task, &PageFault, &InterruptStackFrame) -> Option<Outcome>;
+
+// Tried in order. The first one that recognizes a fault decides what happens to it.
+const RECOGNIZERS: [Recognizer; 3] = [lazy_page, cow_page, stack_guard];
+
+/// Handle the page fault of the interrupt frame, with the error code the CPU pushed. Returns if the access can be
+/// retried, and otherwise kills the current task or panics.
+///
+/// # Safety
+/// Must only be called from the page fault handler, which must not have entered interrupt context.
+pub unsafe fn handle(frame: &InterruptStackFrame, err_code: usize) {
+    let context = InterruptContext::enter();
+    let fault = PageFault {
+        addr: read_cr2(),
+        error: PageFaultError(err_code),
+    };
+
+    // A reserved bit means the page tables themselves are broken, whoever ran into them
+    if !frame.is_user_mode() || fault.error.reserved() {
+        kernel_fault(frame, &fault);
+    }
+    unsafe { context.exit(frame) };
+
+    let outcome = sched::with_current_task(|task| {
+        let outcome = RECOGNIZERS
+            .iter()
+            .find_map(|recognize| recognize(task, &fault, frame));
+        if outcome.is_none() {
+            report_user_fault(task, frame, &fault);
+        }
+        outcome
+    });
+
+    match outcome {
+        Some(Outcome::Resolved) => {}
+        Some(Outcome::Kill) | None => unsafe { sched::kill_task() },
+    }
+}
+
+// A not-present fault inside a lazy region is resolved by mapping the page.
+fn lazy_page(task: &mut Task, fault: &PageFault, _: &InterruptStackFrame) -> Option<Outcome> {
+    if fault.error.present() {
+        return None;
+    }
+    let resolved = task
+        .addr_space
+        .handle_lazy_fault(fault.addr, fault.error.write());
+    resolved_or_out_of_memory(task, fault, resolved)
+}
+
+// A write to a present, read-only page of a COW region is resolved by copying the page.
+fn cow_page(task: &mut Task, fault: &PageFault, _: &InterruptStackFrame) -> Option<Outcome> {
+    if !fault.error.present() || !fault.error.write() {
+        return None;
+    }
+    let resolved = task.addr_space.handle_cow_fault(fault.addr);
+    resolved_or_out_of_memory(task, fault, resolved)
+}
+
+// What to do with a fault a recognizer tried to resolve. The task is killed if there was no page to resolve it with.
+fn resolved_or_out_of_memory(
+    task: &Task,
+    fault: &PageFault,
+    resolved: Result<bool, MapError>,
+) -> Option<Outcome> {
+    match resolved {
+        Ok(resolved) => resolved.then_some(Outcome::Resolved),
+        Err(_) => {
+            printlnk!(
+                "Out of memory in task {} ({}): no page for the fault at {:#x}",
+                task.id,
+                task.name(),
+                fault.addr
+            );
+            Some(Outcome::Kill)
+        }
+    }
+}
+
+// A fault inside a guard region means the task ran off the end of its stack.
+fn stack_guard(task: &mut Task, fault: &PageFault, frame: &InterruptStackFrame) -> Option<Outcome> {
+    task.addr_space.guard_region_at(fault.addr)?;
+
+    printlnk!(
+        "Stack overflow in task {} ({}): faulting address {:#x}, rsp {:#x}",
+        task.id,
+        task.name(),
+        fault.addr,
+        frame.sp
+    );
+    print_maps(task);
+    Some(Outcome::Kill)
+}
+
+// Report a fault of a user task that no recognizer handled, before the task is killed.
+fn report_user_fault(task: &Task, frame: &InterruptStackFrame, fault: &PageFault) {
+    printlnk!(
+        "Page fault in task {} ({}) at {:#x}: {}",
+        task.id,
+        task.name(),
+        fault.addr,
+        fault.error
+    );
+    print_rip(task, frame.ip);
+    printlnk!("rsp = {:#x}", frame.sp);
+    print_maps(task);
+    task.addr_space.dump();
+    printlnk!(
+        "Killing task {} ({}) after an unhandled page fault",
+        task.id,
+        task.name()
+    );
+}
+
+// Report a fault in the kernel, and panic.
+fn kernel_fault(frame: &InterruptStackFrame, fault: &PageFault) -> ! {
+    printlnk!("Page fault at {:#x}: {}", fault.addr, fault.error);
+    printlnk!("rip = {}, rsp = {:#x}", Symbolized(frame.ip), frame.sp);
+    if fault.addr < USERSPACE_LIMIT && !fault.error.user() {
+        printlnk!("The kernel accessed a user address, which it must only do through uaccess");
+    }
+    backtrace::print();
+
+    panic!("Page fault in the kernel at {:#x}", fault.addr);
+}
+
+/// The address of the last page fault.
+pub fn read_cr2() -> usize {
+    let addr: usize;
+    unsafe { asm!("mov {}, cr2", out(reg) addr, options(nomem, nostack, preserves_flags)) };
+    addr
+}
@@ -0,0 +1,436 @@
+//! PCI devices, for drivers to find.
+//!
+//! Configuration space is accessed through mechanism 1: the bus, device, function and register go to CONFIG_ADDRESS,
+//! then the register is read or written at CONFIG_DATA. init() scans every device of bus 0, and of the buses behind
+//! each PCI-to-PCI bridge it finds, and keeps what it read of each function. A function that isn't there reads as
+//! vendor 0xffff.
+//!
+//! The size of a BAR is found by writing all ones to it and reading back which bits stuck, with the decoding of the
+//! device turned off meanwhile so it never answers at the wrong address.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{idt::without_interrupt, io::port::Port, log_debug, printlnk};
+
+const CONFIG_ADDRESS: Port<u32> = unsafe { Port::new(0xCF8) };
+const CONFIG_DATA: Port<u32> = unsafe { Port::new(0xCFC) };
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// Registers of the configuration space, each 32 bits
+const ID: u8 = 0x00; // Vendor, then device
+const COMMAND: u8 = 0x04; // Command, then status
+const CLASS: u8 = 0x08; // Revision, programming interface, subclass, class
+const HEADER: u8 = 0x0c; // Cache line size, latency timer, header type, BIST
+const BAR0: u8 = 0x10;
+const BRIDGE_BUSES: u8 = 0x18; // Primary, secondary and subordinate bus of a PCI-to-PCI bridge
+const INTERRUPT: u8 = 0x3c; // Interrupt line, then pin
+
+const NO_VENDOR: u16 = 0xffff;
+
+// Command bits
+const COMMAND_IO: u32 = 1 << 0;
+const COMMAND_MEMORY: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+// Header type
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+pub const HEADER_GENERAL: u8 = 0x00;
+pub const HEADER_PCI_BRIDGE: u8 = 0x01;
+
+// BAR bits
+const BAR_IO: u64 = 1 << 0;
+const BAR_IO_ADDR_MASK: u64 = !0x3;
+const BAR_MEMORY_64: u64 = 0x2 << 1;
+const BAR_MEMORY_TYPE_MASK: u64 = 0x3 << 1;
+const BAR_PREFETCHABLE: u64 = 1 << 3;
+const BAR_MEMORY_ADDR_MASK: u64 = !0xf;
+
+pub const MAX_DEVICES: u8 = 32; // Per bus
+pub const MAX_FUNCTIONS: u8 = 8; // Per device
+pub const MAX_BARS: usize = 6; // Of a general device; a PCI-to-PCI bridge has the first two
+
+// Classes and subclasses
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const CLASS_NETWORK: u8 = 0x02;
+pub const CLASS_DISPLAY: u8 = 0x03;
+pub const CLASS_BRIDGE: u8 = 0x06;
+pub const SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+pub const SUBCLASS_ISA_BRIDGE: u8 = 0x01;
+pub const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+/// The location of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,   // Below MAX_DEVICES
+    pub function: u8, // Below MAX_FUNCTIONS
+}
+
+impl Address {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Address {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// What to write to CONFIG_ADDRESS to access the 32-bit register at offset.
+    pub fn config_address(self, offset: u8) -> u32 {
+        CONFIG_ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset as u32 & 0xfc)
+    }
+}
+
+/// Writes the address as bus:device.function, e.g. "00:1f.3".
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{:x}",
+            self.bus, self.device, self.function
+        )
+    }
+}
+
+/// A base address register, with the range it decodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        addr: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64: bool, // Takes the next BAR too, for the upper half
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+/// Writes the BAR as e.g. "memory 0xfebf0000, 4096 bytes" or "I/O 0xc000, 32 bytes".
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                addr,
+                size,
+                prefetchable,
+                is_64,
+            } => {
+                write!(f, "memory {:#x}, {} bytes", addr, size)?;
+                if is_64 {
+                    f.write_str(", 64-bit")?;
+                }
+                if prefetchable {
+                    f.write_str(", prefetchable")?;
+                }
+                Ok(())
+            }
+            Bar::Io { port, size } => write!(f, "I/O {:#x}, {} bytes", port, size),
+        }
+    }
+}
+
+/// Decode a BAR from the value it held, and the value read back after writing all ones to it. The upper half of a
+/// 64-bit BAR goes in the upper 32 bits of both. Returns None for a BAR the device doesn't implement.
+pub fn decode_bar(value: u64, ones: u64) -> Option<Bar> {
+    if value & BAR_IO != 0 {
+        // The upper 16 bits of an I/O BAR may read as zeros instead of sticking
+        let mask = (ones & BAR_IO_ADDR_MASK) as u16;
+        if mask == 0 {
+            return None;
+        }
+        return Some(Bar::Io {
+            port: (value & BAR_IO_ADDR_MASK) as u16,
+            size: (!mask).wrapping_add(1),
+        });
+    }
+
+    let is_64 = value & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_64;
+    let mut mask = ones & BAR_MEMORY_ADDR_MASK;
+    let mut addr = value & BAR_MEMORY_ADDR_MASK;
+    if !is_64 {
+        mask = (mask as u32) as u64 | 0xffff_ffff_0000_0000;
+        addr &= 0xffff_ffff;
+    }
+    if mask == 0xffff_ffff_0000_0000 || mask == 0 {
+        return None;
+    }
+    Some(Bar::Memory {
+        addr,
+        size: (!mask).wrapping_add(1),
+        prefetchable: value & BAR_PREFETCHABLE != 0,
+        is_64,
+    })
+}
+
+/// What the scan read of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: Address,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,               // Without the multi-function bit
+    pub bars: [Option<Bar>; MAX_BARS], // The BAR after a 64-bit one is None
+    pub interrupt_line: u8,            // The IRQ the firmware routed the pin to, or 0xff for none
+    pub interrupt_pin: u8,             // 1 to 4 for INTA# to INTD#, or 0 for none
+}
+
+/// Writes the summary line of the device, e.g. "00:01.0 8086:7000 class 06.01.00, bridge".
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}, {}",
+            self.address,
+            self.vendor,
+            self.device,
+            self.class,
+            self.subclass,
+            self.prog_if,
+            class_name(self.class)
+        )?;
+        if (1..=4).contains(&self.interrupt_pin) {
+            write!(
+                f,
+                ", INT{}# IRQ {}",
+                (b'A' + self.interrupt_pin - 1) as char,
+                self.interrupt_line
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of a base class, e.g. "mass storage controller" for CLASS_MASS_STORAGE.
+pub fn class_name(class: u8) -> &'static str {
+    match class {
+        0x00 => "unclassified",
+        CLASS_MASS_STORAGE => "mass storage controller",
+        CLASS_NETWORK => "network controller",
+        CLASS_DISPLAY => "display controller",
+        0x04 => "multimedia controller",
+        0x05 => "memory controller",
+        CLASS_BRIDGE => "bridge",
+        0x07 => "communication controller",
+        0x08 => "system peripheral",
+        0x09 => "input device controller",
+        0x0c => "serial bus controller",
+        _ => "other",
+    }
+}
+
+/// Read the 32-bit register at offset, which is rounded down to a multiple of 4.
+pub fn read_config(address: Address, offset: u8) -> u32 {
+    without_interrupt(|| {
+        CONFIG_ADDRESS.write(address.config_address(offset));
+        CONFIG_DATA.read()
+    })
+}
+
+/// Write the 32-bit register at offset, which is rounded down to a multiple of 4.
+///
+/// # Safety
+/// The write must not break memory safety, e.g. by moving a BAR over memory in use, or letting a device write to
+/// memory.
+pub unsafe fn write_config(address: Address, offset: u8, value: u32) {
+    without_interrupt(|| {
+        CONFIG_ADDRESS.write(address.config_address(offset));
+        CONFIG_DATA.write(value);
+    })
+}
+
+/// Turn on the decoding of the I/O and memory BARs of the function, and let it access memory.
+///
+/// # Safety
+/// The function must only access the memory its driver hands it, as one fresh from reset does.
+pub unsafe fn enable(address: Address) {
+    let command = read_config(address, COMMAND) & 0xffff;
+    unsafe {
+        write_config(
+            address,
+            COMMAND,
+            command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+        )
+    };
+}
+
+// The devices, once init() scanned them. Never changed after that.
+static mut DEVICES: Vec<PciDevice> = Vec::new();
+
+/// Every function init() found, in the order it found them.
+pub fn devices() -> &'static [PciDevice] {
+    unsafe { &DEVICES }
+}
+
+/// The functions of the class and subclass, e.g. CLASS_BRIDGE and SUBCLASS_ISA_BRIDGE.
+pub fn find_by_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.class == class && device.subclass == subclass)
+}
+
+/// The functions with the vendor and device ID.
+pub fn find_by_id(vendor: u16, device: u16) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |found| found.vendor == vendor && found.device == device)
+}
+
+/// Scan the buses for devices, and print a line for each.
+///
+/// # Safety
+/// Must be called once, before any driver uses devices().
+pub unsafe fn init() {
+    let mut scan = Scan {
+        devices: Vec::new(),
+        scanned: [false; 256],
+    };
+    // A multi-function host bridge means several host controllers, each with the bus of its function number
+    let host = Address::new(0, 0, 0);
+    if header_type(host) & HEADER_MULTI_FUNCTION == 0 {
+        scan.bus(0);
+    } else {
+        for function in 0..MAX_FUNCTIONS {
+            if vendor(Address::new(0, 0, function)) != NO_VENDOR {
+                scan.bus(function);
+            }
+        }
+    }
+
+    for device in &scan.devices {
+        printlnk!("PCI {}", device);
+        for (i, bar) in device.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                log_debug!("PCI {} BAR{}: {}", device.address, i, bar);
+            }
+        }
+    }
+    unsafe { DEVICES = scan.devices };
+}
+
+// A scan of the buses, remembering which were scanned so a misconfigured bridge can't loop it.
+struct Scan {
+    devices: Vec<PciDevice>,
+    scanned: [bool; 256],
+}
+
+impl Scan {
+    fn bus(&mut self, bus: u8) {
+        if core::mem::replace(&mut self.scanned[bus as usize], true) {
+            return;
+        }
+        for device in 0..MAX_DEVICES {
+            let address = Address::new(bus, device, 0);
+            if vendor(address) == NO_VENDOR {
+                continue;
+            }
+            self.function(address);
+            if header_type(address) & HEADER_MULTI_FUNCTION != 0 {
+                for function in 1..MAX_FUNCTIONS {
+                    let address = Address::new(bus, device, function);
+                    if vendor(address) != NO_VENDOR {
+                        self.function(address);
+                    }
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, address: Address) {
+        let device = read_device(address);
+        self.devices.push(device);
+        if device.header_type == HEADER_PCI_BRIDGE {
+            let secondary = (read_config(address, BRIDGE_BUSES) >> 8) as u8;
+            self.bus(secondary);
+        }
+    }
+}
+
+fn vendor(address: Address) -> u16 {
+    read_config(address, ID) as u16
+}
+
+fn header_type(address: Address) -> u8 {
+    (read_config(address, HEADER) >> 16) as u8
+}
+
+fn read_device(address: Address) -> PciDevice {
+    let id = read_config(address, ID);
+    let class = read_config(address, CLASS);
+    let header_type = header_type(address) & HEADER_TYPE_MASK;
+    let interrupt = read_config(address, INTERRUPT);
+
+    let mut bars = [None; MAX_BARS];
+    let bar_count = match header_type {
+        HEADER_GENERAL => MAX_BARS,
+        HEADER_PCI_BRIDGE => 2,
+        _ => 0, // A CardBus bridge has none
+    };
+    let mut index = 0;
+    while index < bar_count {
+        bars[index] = unsafe { probe_bar(address, index, bar_count) };
+        index += match bars[index] {
+            Some(Bar::Memory { is_64: true, .. }) => 2,
+            _ => 1,
+        };
+    }
+
+    PciDevice {
+        address,
+        vendor: id as u16,
+        device: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type,
+        bars,
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+    }
+}
+
+// Size the BAR with the write-ones trick, then put it back as it was. Only during the scan, before any driver uses
+// the device.
+unsafe fn probe_bar(address: Address, index: usize, bar_count: usize) -> Option<Bar> {
+    let offset = BAR0 + 4 * index as u8;
+    without_interrupt(|| unsafe {
+        let command = read_config(address, COMMAND);
+        write_config(address, COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+        let low = read_config(address, offset);
+        write_config(address, offset, u32::MAX);
+        let low_ones = read_config(address, offset);
+        write_config(address, offset, low);
+
+        let is_64 = low as u64 & (BAR_IO | BAR_MEMORY_TYPE_MASK) == BAR_MEMORY_64;
+        let (high, high_ones) = if is_64 && index + 1 < bar_count {
+            let high = read_config(address, offset + 4);
+            write_config(address, offset + 4, u32::MAX);
+            let high_ones = read_config(address, offset + 4);
+            write_config(address, offset + 4, high);
+            (high, high_ones)
+        } else {
+            (0, 0)
+        };
+
+        // Writing ones to the status bits would clear them, so only the command goes back
+        write_config(address, COMMAND, command & 0xffff);
+
+        decode_bar(
+            (high as u64) << 32 | low as u64,
+            (high_ones as u64) << 32 | low_ones as u64,
+        )
+    })
+}
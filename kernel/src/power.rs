@@ -0,0 +1,100 @@
+//! Rebooting and powering off the machine.
+//!
+//! Both print a banner and wait for the serial port to send it first, with interrupts disabled from then on.
+//!
+//! reboot() pulses the reset line of the CPU through the keyboard controller. If the machine is still running after
+//! that, it triple faults: with an empty IDT, the breakpoint it raises can't be delivered, and neither can the double
+//! fault that follows.
+//!
+//! poweroff() enters ACPI S5 through the PM1 control blocks, when acpi::init() found how. If that doesn't work, it
+//! tries the ports QEMU and Bochs turn off at without ACPI, then halts.
+
+use core::arch::asm;
+
+use crate::{
+    acpi,
+    idt::disable_interrupt,
+    io::{
+        pit,
+        port::{PortReadOnly, PortWriteOnly, outw},
+        serial,
+    },
+    printlnk,
+};
+
+// Status and command port of the keyboard controller, and the command that pulses the reset line of the CPU
+const KBC_STATUS: PortReadOnly<u8> = unsafe { PortReadOnly::new(0x64) };
+const KBC_COMMAND: PortWriteOnly<u8> = unsafe { PortWriteOnly::new(0x64) };
+const KBC_INPUT_FULL: u8 = 1 << 1; // The controller hasn't taken the last command yet
+const KBC_RESET: u8 = 0xFE;
+
+// The PM1 control block of QEMU's i440FX machine, and of older Bochs, written to without ACPI
+const QEMU_PM1_CONTROL: u16 = 0x604;
+const BOCHS_PM1_CONTROL: u16 = 0xB004;
+
+// Bits of the PM1 control blocks
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+// How long to wait for each way to take effect before trying the next
+const RESET_WAIT_MS: usize = 100;
+
+/// What to write to a PM1 control block to enter the sleep state of the sleep type, e.g. SLP_TYPa of S5.
+pub fn pm1_control(sleep_type: u8) -> u16 {
+    ((sleep_type as u16 & 0x7) << SLEEP_TYPE_SHIFT) | SLEEP_ENABLE
+}
+
+/// Reset the machine.
+pub fn reboot() -> ! {
+    shutdown("Rebooting");
+
+    for _ in 0..1000 {
+        if KBC_STATUS.read() & KBC_INPUT_FULL == 0 {
+            break;
+        }
+        pit::delay_ms(1);
+    }
+    KBC_COMMAND.write(KBC_RESET);
+    pit::delay_ms(RESET_WAIT_MS);
+
+    printlnk!("The keyboard controller didn't reset the machine, triple faulting");
+    serial::flush();
+    let empty_idt = [0u16; 5]; // A limit of 0, and a base
+    unsafe { asm!("lidt [{}]", "int3", in(reg) &empty_idt, options(noreturn)) }
+}
+
+/// Turn the machine off.
+pub fn poweroff() -> ! {
+    shutdown("Powering off");
+
+    if let Some(soft_off) = acpi::soft_off() {
+        unsafe {
+            outw(soft_off.pm1a_control, pm1_control(soft_off.sleep_type_a));
+            if let Some(port) = soft_off.pm1b_control {
+                outw(port, pm1_control(soft_off.sleep_type_b));
+            }
+        }
+        pit::delay_ms(RESET_WAIT_MS);
+        printlnk!("ACPI didn't power off the machine");
+    }
+
+    // Sleep type 0 is S5 on both
+    unsafe {
+        outw(QEMU_PM1_CONTROL, pm1_control(0));
+        outw(BOCHS_PM1_CONTROL, pm1_control(0));
+    }
+    pit::delay_ms(RESET_WAIT_MS);
+
+    printlnk!("Failed to power off, halting");
+    serial::flush();
+    loop {
+        unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)) };
+    }
+}
+
+// Print the banner, and let the serial port send everything before the machine goes.
+fn shutdown(what: &str) {
+    disable_interrupt();
+    printlnk!("{}...", what);
+    serial::flush();
+}
@@ -0,0 +1,226 @@
+//! Random numbers, for the kernel and for user space (sys_getrandom).
+//!
+//! Output comes from a ChaCha20 DRBG. It is seeded at boot from RDSEED or RDRAND when CPUID advertises them, and
+//! always from the jitter of the time stamp counter too, so machines without the instructions still get a seed no two
+//! boots share. Every fill() mixes in a fresh RDRAND value if there is one, then replaces the key with output of its
+//! own, so earlier output can't be recovered from the state.
+
+use core::arch::{asm, x86_64::__cpuid};
+
+use crate::{cpu::tsc, idt::without_interrupt, printlnk};
+
+// CPUID feature bits
+const CPUID_1_ECX_RDRAND: u32 = 1 << 30;
+const CPUID_7_EBX_RDSEED: u32 = 1 << 18;
+
+// Times RDRAND is retried when it has no random number ready, as Intel recommends. RDSEED runs out more often, so it
+// gets more tries, with a pause in between.
+const RDRAND_RETRIES: usize = 10;
+const RDSEED_RETRIES: usize = 100;
+
+// Time stamp counter samples folded into the seed.
+const JITTER_SAMPLES: usize = 256;
+
+// "expand 32-byte k", the first row of every ChaCha block
+const CHACHA_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/// Size of a ChaCha20 block, in bytes.
+pub const CHACHA_BLOCK_SIZE: usize = 64;
+
+/// Where the seed came from, besides the jitter of the time stamp counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Rdseed,
+    Rdrand,
+    Jitter, // No instruction, the jitter alone
+}
+
+// The DRBG. Only touched with interrupts disabled.
+struct Drbg {
+    key: [u32; 8],
+    counter: u32, // Block counter, since the last rekey()
+}
+
+static mut DRBG: Drbg = Drbg {
+    key: [0; 8],
+    counter: 0,
+};
+
+static mut HAS_RDRAND: bool = false;
+static mut SOURCE: Source = Source::Jitter;
+
+/// Seed the DRBG. Must be called before fill().
+pub fn init() {
+    let rdrand = __cpuid(1).ecx & CPUID_1_ECX_RDRAND != 0;
+    let rdseed = __cpuid(0).eax >= 7 && __cpuid(7).ebx & CPUID_7_EBX_RDSEED != 0;
+
+    // RDSEED gives true random numbers, while RDRAND gives the output of a DRBG, so RDSEED is tried first
+    let mut seed = [0u64; 4];
+    let mut source = Source::Jitter;
+    for word in &mut seed {
+        if let Some(value) = rdseed.then(rdseed64).flatten() {
+            *word = value;
+            source = Source::Rdseed;
+        } else if let Some(value) = rdrand.then(rdrand64).flatten() {
+            *word = value;
+            if source == Source::Jitter {
+                source = Source::Rdrand;
+            }
+        }
+    }
+    for (i, sample) in jitter_samples().enumerate() {
+        let word = &mut seed[i % seed.len()];
+        *word = word.rotate_left(7) ^ sample;
+    }
+
+    without_interrupt(|| unsafe {
+        HAS_RDRAND = rdrand;
+        SOURCE = source;
+        DRBG.mix(&seed);
+        DRBG.rekey();
+    });
+    printlnk!("Random numbers seeded from {:?}", source);
+}
+
+/// Where the seed came from.
+pub fn source() -> Source {
+    unsafe { SOURCE }
+}
+
+/// Fill buf with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    let fresh = unsafe { HAS_RDRAND }.then(rdrand64).flatten();
+
+    without_interrupt(|| unsafe {
+        if let Some(value) = fresh {
+            DRBG.mix(&[value]);
+        }
+        for chunk in buf.chunks_mut(CHACHA_BLOCK_SIZE) {
+            let block = DRBG.next_block();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        DRBG.rekey();
+    });
+}
+
+/// A random u64.
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes);
+    u64::from_ne_bytes(bytes)
+}
+
+impl Drbg {
+    // XOR words into the key. The next block spreads them over all of its output.
+    fn mix(&mut self, words: &[u64]) {
+        for (i, word) in words.iter().enumerate() {
+            let index = (i * 2) % self.key.len();
+            self.key[index] ^= *word as u32;
+            self.key[index + 1] ^= (*word >> 32) as u32;
+        }
+    }
+
+    fn next_block(&mut self) -> [u8; CHACHA_BLOCK_SIZE] {
+        let nonce = [0; 3];
+        let block = chacha20_block(&self.key, self.counter, &nonce);
+        self.counter = self.counter.wrapping_add(1);
+        block
+    }
+
+    // Replace the key with the next block, so the output so far can't be computed from the state anymore.
+    fn rekey(&mut self) {
+        let block = self.next_block();
+        for (word, bytes) in self.key.iter_mut().zip(block.as_chunks::<4>().0) {
+            *word = u32::from_le_bytes(*bytes);
+        }
+        self.counter = 0;
+    }
+}
+
+/// The ChaCha20 block function of RFC 8439: a block of key stream for the key, block counter and nonce.
+pub fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; CHACHA_BLOCK_SIZE] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+
+    let mut state = input;
+    for _ in 0..10 {
+        // Column rounds, then diagonal rounds
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; CHACHA_BLOCK_SIZE];
+    for (i, bytes) in block.as_chunks_mut::<4>().0.iter_mut().enumerate() {
+        bytes.copy_from_slice(&state[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    block
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// A random number from RDRAND, or None if it had none ready after RDRAND_RETRIES tries. CF tells if the value is valid,
+// as the instruction returns 0 when its buffer is empty. The CPU must support RDRAND.
+fn rdrand64() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let valid: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) valid, options(nomem, nostack))
+        };
+        if valid != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// A random seed from RDSEED, or None if it had none ready after RDSEED_RETRIES tries. The CPU must support RDSEED.
+fn rdseed64() -> Option<u64> {
+    for _ in 0..RDSEED_RETRIES {
+        let value: u64;
+        let valid: u8;
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) valid, options(nomem, nostack))
+        };
+        if valid != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+// How long each of JITTER_SAMPLES short stretches of work takes, in time stamp counter cycles. The low bits vary with
+// caches, interrupts and the timing of the machine.
+fn jitter_samples() -> impl Iterator<Item = u64> {
+    let mut last = tsc::serialized_rdtsc();
+    let mut work = last;
+    (0..JITTER_SAMPLES).map(move |_| {
+        for _ in 0..(last & 0xf) {
+            work =
+                core::hint::black_box(work.rotate_left(13) ^ work.wrapping_mul(0x9e3779b97f4a7c15));
+        }
+        let now = tsc::serialized_rdtsc();
+        let sample = now.wrapping_sub(last) ^ work;
+        last = now;
+        sample
+    })
+}
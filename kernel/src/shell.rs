@@ -0,0 +1,277 @@
+//! A debug shell on the serial console, for looking around a running kernel.
+//!
+//! It only starts with the shell=on option of the kernel command line, once the tests are done, so automated runs
+//! never wait for input. It runs as a kernel task, which blocks until a line arrives, runs it, and prints the result.
+//! The task exits with the exit command, and the kernel halts after it.
+//!
+//! peek and poke take physical addresses, and go through the direct map. They refuse any range that isn't all RAM, as
+//! the direct map also covers the holes where devices sit, and reading a device register can change its state.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use crate::{
+    cmdline,
+    helper::p2v,
+    idt::without_interrupt,
+    io::{
+        klog,
+        output::{Console, print_console},
+        serial,
+    },
+    irq, mem, power, printk,
+    user::{
+        sched::{self, TASK_TABLE, TaskRef, stats},
+        task::Task,
+    },
+};
+
+/// The longest line the shell reads. The rest of a longer line is dropped.
+pub const MAX_LINE: usize = 128;
+/// The most bytes one peek dumps.
+pub const MAX_PEEK: usize = 256;
+
+const PROMPT: &str = "> ";
+
+const HELP: &str = "\
+help                  list the commands
+mem                   memory usage of the page allocator and the slab caches
+ps                    list the tasks
+maps <pid>            list the mappings of a task
+peek <addr> <len>     dump len bytes of physical memory, at most 256
+poke <addr> <byte>    write a byte of physical memory
+dmesg                 print the kernel log
+irqstats              count the interrupts of each vector
+reboot                reset the machine
+poweroff              turn the machine off
+exit                  leave the shell
+Numbers are decimal, or hexadecimal with 0x.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Mem,
+    Ps,
+    Maps { pid: usize },
+    Peek { addr: usize, len: usize },
+    Poke { addr: usize, value: u8 },
+    Dmesg,
+    IrqStats,
+    Reboot,
+    PowerOff,
+    Exit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellError {
+    UnknownCommand,
+    MissingArgument,
+    ExtraArgument,
+    BadNumber, // Not a number, or too large for what it is
+    TooLong,   // A peek of more than MAX_PEEK bytes
+    NotRam,    // A peek or poke outside RAM
+    NoSuchTask,
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ShellError::UnknownCommand => "unknown command, try help",
+            ShellError::MissingArgument => "missing argument",
+            ShellError::ExtraArgument => "too many arguments",
+            ShellError::BadNumber => "bad number",
+            ShellError::TooLong => "too long, peek at most 256 bytes",
+            ShellError::NotRam => "not RAM",
+            ShellError::NoSuchTask => "no such task",
+        })
+    }
+}
+
+/// Parse a number, in decimal or in hexadecimal with 0x.
+pub fn parse_number(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parse a line. Returns None for an empty one.
+pub fn parse_command(line: &str) -> Result<Option<Command>, ShellError> {
+    let mut words = line.split_ascii_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+    let mut number = || {
+        let word = words.next().ok_or(ShellError::MissingArgument)?;
+        parse_number(word).ok_or(ShellError::BadNumber)
+    };
+
+    let command = match name {
+        "help" => Command::Help,
+        "mem" => Command::Mem,
+        "ps" => Command::Ps,
+        "maps" => Command::Maps { pid: number()? },
+        "peek" => Command::Peek {
+            addr: number()?,
+            len: number()?,
+        },
+        "poke" => Command::Poke {
+            addr: number()?,
+            value: u8::try_from(number()?).map_err(|_| ShellError::BadNumber)?,
+        },
+        "dmesg" => Command::Dmesg,
+        "irqstats" => Command::IrqStats,
+        "reboot" => Command::Reboot,
+        "poweroff" => Command::PowerOff,
+        "exit" => Command::Exit,
+        _ => return Err(ShellError::UnknownCommand),
+    };
+    if words.next().is_some() {
+        return Err(ShellError::ExtraArgument);
+    }
+    Ok(Some(command))
+}
+
+/// Run a command, and write what it prints to out. reboot, poweroff and exit don't return.
+pub fn execute(command: Command, out: &mut impl Write) -> Result<(), ShellError> {
+    match command {
+        Command::Help => out.write_str(HELP).unwrap(),
+        Command::Mem => {
+            let info = stats::system_info();
+            writeln!(
+                out,
+                "pages: {} total, {} free, {} allocated",
+                info.total_pages,
+                info.free_pages,
+                mem::buddy::allocated_pages()
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "slab: {} objects, {} bytes",
+                mem::slab::allocated_objects(),
+                info.slab_bytes
+            )
+            .unwrap();
+        }
+        Command::Ps => stats::format_tasks(out).unwrap(),
+        Command::Maps { pid } => {
+            // Formatted into a string first, so the task table isn't held while printing
+            let maps = without_interrupt(|| {
+                let task = unsafe { TASK_TABLE.get(&pid) }?;
+                let mut maps = String::new();
+                unsafe { (*task.get()).addr_space.format_maps(&mut maps) }.unwrap();
+                Some(maps)
+            });
+            out.write_str(&maps.ok_or(ShellError::NoSuchTask)?).unwrap();
+        }
+        Command::Peek { addr, len } => {
+            if len > MAX_PEEK {
+                return Err(ShellError::TooLong);
+            }
+            let bytes = ram(addr, len)?;
+            hex_dump(addr, bytes, out).unwrap();
+        }
+        Command::Poke { addr, value } => ram(addr, 1)?[0] = value,
+        Command::Dmesg => out.write_str(&klog::read(usize::MAX)).unwrap(),
+        Command::IrqStats => irq::format_stats(out).unwrap(),
+        Command::Reboot => power::reboot(),
+        Command::PowerOff => power::poweroff(),
+        Command::Exit => unsafe { sched::exit_current(0) },
+    }
+    Ok(())
+}
+
+/// Write the bytes as lines of 16, each with the address of its first byte, e.g.
+/// "0x00001000: 48 65 6c 6c 6f                                   |Hello|".
+pub fn hex_dump(addr: usize, bytes: &[u8], out: &mut impl Write) -> fmt::Result {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        write!(out, "{:#010x}:", addr + i * 16)?;
+        for byte in line {
+            write!(out, " {:02x}", byte)?;
+        }
+        write!(out, "{:width$} |", "", width = (16 - line.len()) * 3)?;
+        for &byte in line {
+            let printable = byte.is_ascii_graphic() || byte == b' ';
+            out.write_char(if printable { byte as char } else { '.' })?;
+        }
+        writeln!(out, "|")?;
+    }
+    Ok(())
+}
+
+/// Start the shell if the shell=on option is set, and return once it exits.
+pub fn run_if_enabled() {
+    if cmdline::option("shell") != Some("on") {
+        return;
+    }
+    unsafe {
+        sched::add_new_task(TaskRef::new(Task::create_kernel_task(shell_main, "shell")));
+        sched::begin_scheduler();
+    }
+}
+
+// The physical range, through the direct map, if it is all RAM.
+fn ram(addr: usize, len: usize) -> Result<&'static mut [u8], ShellError> {
+    let end = addr.checked_add(len).ok_or(ShellError::NotRam)?;
+    if !mem::is_ram(addr..end) {
+        return Err(ShellError::NotRam);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(p2v(addr) as *mut u8, len) })
+}
+
+fn shell_main() -> ! {
+    printk!("Debug shell, type help for the commands\n");
+    let mut line = String::new();
+    loop {
+        print_console(format_args!("{}", PROMPT));
+        read_line(&mut line);
+        match parse_command(&line) {
+            Ok(Some(command)) => {
+                if let Err(err) = execute(command, &mut Console) {
+                    printk!("{}\n", err);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => printk!("{}\n", err),
+        }
+    }
+}
+
+// Read a line from the serial console into line, echoing it to the console but not the kernel log. Backspace deletes,
+// and the line ends at CR or LF.
+fn read_line(line: &mut String) {
+    line.clear();
+    loop {
+        let byte = read_byte();
+        match byte {
+            b'\r' | b'\n' => {
+                print_console(format_args!("\n"));
+                return;
+            }
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    print_console(format_args!("\x08 \x08"));
+                }
+            }
+            b' '..=b'~' if line.len() < MAX_LINE => {
+                line.push(byte as char);
+                print_console(format_args!("{}", byte as char));
+            }
+            _ => {}
+        }
+    }
+}
+
+// Wait for a byte from the serial console.
+fn read_byte() -> u8 {
+    let mut byte = [0];
+    without_interrupt(|| {
+        // Interrupts stay disabled between the check and wait(), so no input is missed in between
+        while serial::read_received(&mut byte) == 0 {
+            unsafe { serial::RX_WAITERS.wait() };
+        }
+    });
+    byte[0]
+}
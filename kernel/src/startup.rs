@@ -1,63 +1,205 @@
-use core::ptr::slice_from_raw_parts_mut;
+use core::{
+    ptr::slice_from_raw_parts_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use bootloader_api::{BootInfo, info::MemoryRegionKind};
 
 use crate::{
-    gdt,
+    acpi, backtrace, block, cmdline, cpu, fpu, gdt,
     helper::{self, p2v},
     idt::{self, enable_interrupt},
-    io::output,
-    mem::{
-        buddy,
-        page_table::{self, PageDirectoryEntry},
-    },
-    printlnk, test,
-    user::{address_space::KERNEL_P4_TABLE, syscall},
+    io::{debugcon, fwcfg, output, rtc},
+    ioapic, lapic, log_debug, log_warn, mce,
+    mem::{self, buddy, page_meta, paging::KERNEL_ADDRESS_SPACE},
+    pci, power, printlnk, rand, shell, test, time,
+    user::{programs, sched, syscall},
 };
 
+/// The fw_cfg file with a user program to make available as FW_CFG_INIT_PROGRAM, e.g. for programs to spawn.
+pub const FW_CFG_INIT: &str = "opt/elytra/init";
+/// The name of the program in FW_CFG_INIT. It isn't "init", which the tests register for their own.
+pub const FW_CFG_INIT_PROGRAM: &str = "fwcfg-init";
+
+// The stages of the boot, in order. The NMI handler reports the last one that completed.
+const BOOT_STAGES: [&str; 20] = [
+    "none",
+    "output",
+    "paging",
+    "cpu",
+    "gdt",
+    "idt",
+    "fpu",
+    "rand",
+    "memory",
+    "syscall",
+    "time",
+    "lapic",
+    "ioapic",
+    "rtc",
+    "pci",
+    "block",
+    "fwcfg",
+    "cmdline",
+    "interrupts",
+    "tests",
+];
+
+// Index in BOOT_STAGES of the last stage that completed.
+static BOOT_STAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// Name the last stage of the boot that completed, e.g. "memory".
+pub fn boot_stage() -> &'static str {
+    BOOT_STAGES[BOOT_STAGE.load(Ordering::Relaxed)]
+}
+
+// Also marked on the debug console, which works before the serial port is up.
+fn completed(stage: &str) {
+    let index = BOOT_STAGES.iter().position(|&name| name == stage).unwrap();
+    BOOT_STAGE.store(index, Ordering::Relaxed);
+    debugcon::print(format_args!("Boot stage completed: {}\n", stage));
+}
+
 pub(crate) fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     init(boot_info);
 
     test::test();
+    completed("tests");
+
+    shell::run_if_enabled();
 
+    halt();
+}
+
+// Halt the CPU, or power off or reboot the machine as the halt option of the kernel command line says.
+fn halt() -> ! {
+    match cmdline::option("halt") {
+        Some("poweroff") => power::poweroff(),
+        Some("reboot") => power::reboot(),
+        Some(value) => log_warn!("Ignoring bad halt option: {}", value),
+        None => {}
+    }
     helper::hcf();
 }
 
 // Initialize the kernel.
 fn init(boot_info: &'static mut BootInfo) {
+    debugcon::print(format_args!("Elytra OS starting\n"));
     unsafe {
         output::init(boot_info);
+        completed("output");
         init_mem_paging();
+        completed("paging");
+        cpu::init();
+        completed("cpu");
         gdt::init();
+        completed("gdt");
         idt::init();
+        mce::init();
+        completed("idt");
+        fpu::init();
+        completed("fpu");
+        rand::init();
+        completed("rand");
 
         init_buddy_allocator(boot_info);
+        page_meta::init();
+        init_direct_map(boot_info);
+        backtrace::init(boot_info);
+        KERNEL_ADDRESS_SPACE.populate_upper_half();
+        output::init_back_buffer();
+        completed("memory");
 
         syscall::init();
+        completed("syscall");
+        time::init();
+        completed("time");
+        lapic::init();
+        completed("lapic");
+        acpi::init(boot_info.rsdp_addr.into_option());
+        ioapic::init();
+        idt::route_device_irqs();
+        completed("ioapic");
+        rtc::init();
+        printlnk!("Elytra OS, booted {}", rtc::boot_time().unwrap());
+        completed("rtc");
+        pci::init();
+        completed("pci");
+        block::init();
+        completed("block");
+        fwcfg::init();
+        cmdline::load_fw_cfg();
+        load_fw_cfg_init();
+        completed("fwcfg");
+        apply_cmdline();
+        completed("cmdline");
 
         enable_interrupt();
+        completed("interrupts");
     }
 }
 
-// Set KERNEL_P4_TABLE and unmap all lower half memory.
-// The rust-osdev bootloader leaves some junk in the lower half memory, so we have to unmap it ourselves.
-// https://github.com/rust-osdev/bootloader/issues/470
-fn init_mem_paging() {
-    unsafe {
-        let p4_table = page_table::get_active_page_directory();
+// Make the user program in the fw_cfg file FW_CFG_INIT, if there is one, available as FW_CFG_INIT_PROGRAM.
+fn load_fw_cfg_init() {
+    if let Some(elf) = fwcfg::read_file(FW_CFG_INIT) {
+        printlnk!(
+            "Loaded {} from fw_cfg: {} bytes",
+            FW_CFG_INIT_PROGRAM,
+            elf.len()
+        );
+        programs::register(FW_CFG_INIT_PROGRAM, elf.leak());
+    }
+}
 
-        for i in 0..256 {
-            // Each entry maps 512 GiB, so unmapping the first 256 entries unmaps the first 128 TiB.
-            (*p4_table).0[i] = PageDirectoryEntry::ZERO;
+// Apply the options of the kernel command line.
+fn apply_cmdline() {
+    if let Some(value) = cmdline::option("timeslice") {
+        match sched::parse_time_slices(value) {
+            Some(slices) => sched::set_time_slices(slices),
+            None => log_warn!("Ignoring bad time slice option: {}", value),
         }
+    }
 
-        // Flush the TLB by reloading CR3.
-        page_table::set_active_page_directory(p4_table);
+    if cmdline::option("strace").is_some() {
+        unsafe { syscall::trace::TRACE_SYSCALLS = true };
+    }
+}
 
-        KERNEL_P4_TABLE = p4_table;
+// Capture the kernel page tables and unmap all lower half memory.
+fn init_mem_paging() {
+    unsafe {
+        KERNEL_ADDRESS_SPACE.init();
+        KERNEL_ADDRESS_SPACE.unmap_lower_half();
     }
 }
 
+// Rebuild the direct mapping with large pages, covering all physical memory reported by the bootloader.
+fn init_direct_map(boot_info: &BootInfo) {
+    let phys_mem_end = boot_info
+        .memory_regions
+        .iter()
+        .map(|region| region.end as usize)
+        .max()
+        .unwrap();
+
+    let freed = unsafe { KERNEL_ADDRESS_SPACE.remap_direct_map(phys_mem_end) };
+    log_debug!("Direct map rebuilt, {} old page tables freed", freed);
+
+    // The direct map also covers the holes between regions, where devices may sit
+    mem::set_ram(
+        boot_info
+            .memory_regions
+            .iter()
+            .filter(|region| {
+                matches!(
+                    region.kind,
+                    MemoryRegionKind::Usable | MemoryRegionKind::Bootloader
+                )
+            })
+            .map(|region| region.start as usize..region.end as usize),
+    );
+}
+
 fn init_buddy_allocator(boot_info: &BootInfo) {
     let biggest_region = boot_info
         .memory_regions
@@ -1,23 +1,151 @@
-use core::cell::UnsafeCell;
+use core::{
+    arch::asm,
+    fmt,
+    ptr::{self, null_mut},
+};
 
-use alloc::{boxed::Box, rc::Rc, vec};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use arbitrary_int::traits::Integer;
+use bootloader_api::info::{FrameBufferInfo, PixelFormat};
+use elytra_abi::{
+    nr,
+    power::{REBOOT_CMD_POWER_OFF, REBOOT_MAGIC},
+    time::Timespec,
+};
 
 use crate::{
-    consts::PAGE_SIZE,
-    helper::p2v,
+    acpi::{self, AcpiError, IoApicEntry},
+    backtrace,
+    block::{self, BlockError, SECTOR_SIZE},
+    cmdline,
+    consts::{HUGE_PAGE_SIZE, PAGE_SIZE},
+    cpu::{features as cpu_features, segment_base, tsc},
+    debug::{self, BreakpointError, BreakpointKind},
+    extable,
+    fpu::{self, FpuState},
+    gdt::{
+        self, KERNEL_CODE_SELECTOR, SegmentDescriptor, SystemDescriptor, TSS_SELECTOR,
+        USER_CODE_SELECTOR,
+    },
+    helper::{p2v, v2p},
+    idt::{self, InterruptGuard, disable_interrupt, enable_interrupt, without_interrupt},
+    include_bytes_align_as,
+    invalid_opcode::{self, Cause, Extensions},
+    io::{
+        debugcon,
+        framebuffer::{
+            Font, FrameBufferWriter,
+            psf::{PsfError, PsfFont},
+        },
+        fwcfg::{self, FwCfgFile},
+        klog::{self, Level, MAX_LINE, Record, Ring},
+        output, pit,
+        port::{Port, PortReadOnly, PortWriteOnly, inb, inl, inw, io_wait, outb, outl},
+        rtc::{self, DateTime, RawTime},
+        serial::{self, Serial, SerialError},
+    },
+    ioapic::{self, IoApicError, Polarity, Trigger},
+    irq::{self, Eoi},
+    isr::{
+        self, ControlProtectionError, DescriptorTable, InstructionBytes, InterruptStackFrame,
+        SelectorError,
+    },
+    lapic, log_debug,
+    mce::{self, BankStatus, ErrorRecord},
     mem::{
-        buddy,
-        page_table::{get_active_page_directory, resolve_virt_addr, set_active_page_directory},
+        self, buddy, page_meta,
+        paging::{
+            KERNEL_ADDRESS_SPACE, MapFlags, PageDirectory, VirtAddr, get_active_page_directory,
+            resolve_virt_addr, set_active_page_directory,
+        },
+        slab,
+    },
+    msr::{
+        IA32_EFER, IA32_FS_BASE, IA32_KERNEL_GS_BASE, MsrError, read_msr, read_msr_safe,
+        write_msr_safe,
     },
-    printlnk,
+    nmi,
+    page_fault::PageFaultError,
+    pci::{self, Bar, CLASS_BRIDGE, SUBCLASS_HOST_BRIDGE, SUBCLASS_ISA_BRIDGE},
+    power, printk, printlnk, rand,
+    shell::{self, Command, ShellError},
+    startup, time,
     user::{
-        address_space::{AddressSpace, KERNEL_P4_TABLE},
-        elf_parser::ElfParser,
-        sched,
-        task::Task,
+        address_space::{AddressSpace, MMAP_WINDOW_END, MapError, PIE_LOAD_BASE},
+        elf_parser::{ElfError, ElfParser},
+        elf_structure::{
+            ElfHeader, ElfMachine, ElfProgramHeader, ElfProgramHeaderType, ElfSectionHeader,
+            ElfSectionHeaderType, ElfType, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHN_UNDEF,
+            SHN_XINDEX,
+        },
+        fd::{self, FdTable, File},
+        programs,
+        sched::{
+            self, TaskRef,
+            cpu::{self, ALL_CPUS, CPUS},
+            stats::{self, TaskStats},
+            task_ref,
+            wait_queue::WaitQueue,
+        },
+        signal,
+        syscall::{
+            self, SyscallArgs, dispatch,
+            errno::{self, Errno},
+            trace,
+        },
+        task::{
+            ALLOW_EXECUTABLE_STACK, AT_ENTRY, AT_PAGESZ, AT_PHDR, AT_PHNUM, KernelStack, Task,
+            USER_STACK_SIZE, USER_STACK_TOP, USER_STACK_VADDR, task_name,
+        },
+        uaccess,
     },
 };
 
+const ELF_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/test");
+const STACK_OVERFLOW_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/stack_overflow");
+const BSS_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/bss");
+const WRITE_TEXT_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/write_text");
+const UNALIGNED_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/unaligned");
+const SHARED_PAGE_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/shared_page");
+const MAPS_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/maps");
+const PIE_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/pie");
+const CRASH_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/crash");
+const ARGS_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/args");
+const TLS_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/tls");
+const BSS_FILL_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/bss_fill");
+const SPIN_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/spin");
+const SLEEP_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/sleep");
+const LATENCY_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/latency");
+const TICK_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/tick");
+const EXIT42_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/exit42");
+const WAIT_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/wait");
+const FORK_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/fork");
+const EXEC_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/exec");
+const FPU_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/fpu");
+const PS_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/ps");
+const CHURN_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/churn");
+const YIELD_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/yield");
+const NOSYS_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/nosys");
+const HELLO_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/hello");
+const ECHO_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/echo");
+const IDS_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/ids");
+const STRACE_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/strace");
+const STORM_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/storm");
+const CLOCK_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/clock");
+const INIT_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/init");
+const GRADIENT_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/gradient");
+const PIPE_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/pipe");
+const MUTEX_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/mutex");
+const SIGNAL_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/signal");
+const RANDOM_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/random");
+const SYSINFO_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/sysinfo");
+const DMESG_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/dmesg");
+const NULL_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/null");
+const EXCEPTIONS_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/exceptions");
+const GPF_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/gpf");
+const AVX_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/avx");
+const FSBASE_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/fsbase");
+
 // Run test.
 pub fn test() {
     printlnk!("Here is a number: {}", 42);
@@ -25,9 +153,120 @@ pub fn test() {
     test_buddy_alloc();
     test_slab_alloc();
     test_paging();
+    test_direct_map();
     test_address_space();
+    test_huge_pages();
+    test_fragmented_segment();
+    test_protect_region();
+    test_check_user_range();
+    test_uaccess();
+    test_region_maps();
+    test_remove_region();
+    test_heap();
+    test_map_anonymous();
+    test_stack_guard();
+    test_initial_stack();
+    test_gnu_stack();
+    test_tls();
+    test_lazy_regions();
+    test_bss_zero_fill();
+    test_write_xor_execute();
+    test_unaligned_segment();
+    test_segments_sharing_page();
+    test_position_independent();
+    test_elf_errors();
+    test_sections();
+    test_symbols();
+    test_elf_misaligned();
+    test_accessed_scan();
+    test_shared_pages();
+    test_cow_fork();
+    test_shared_fork();
+    test_kernel_mappings_shared();
+    test_mmio();
+
+    test_task_reaping();
+    test_kernel_tasks();
+    test_task_refs();
+    test_task_names();
+    test_cpu_placement();
+    test_preemption();
+    test_time_slices();
+    test_sleep();
+    test_idle_task();
+    test_priorities();
+    test_wait_queue();
+    test_wait();
+    test_fork();
+    test_exec();
+    test_fpu();
+    test_task_stats();
+    test_syscall_dispatch();
+    test_errno();
+    test_strace();
+    test_syscall_storm();
+    test_segment_base();
+    test_clock();
+    test_delay();
+    test_pit();
+    test_tsc();
+    test_init();
+    test_framebuffer();
+    test_framebuffer_console();
+    test_psf_font();
+    test_write();
+    test_serial_ports();
+    test_debugcon();
+    test_port_io();
+    test_read();
+    test_interruptible_read();
+    test_pipe();
+    test_timed_wait();
+    test_futex();
+    test_signals();
+    test_rand();
+    test_sysinfo();
+    test_klog();
+    test_shell();
+    test_page_fault();
+    test_user_exceptions();
+    test_gpf();
+    test_safe_msr();
+    test_invalid_opcode();
+    test_machine_check();
+    test_gdt();
+    test_irq();
+    test_lapic();
+    test_ioapic();
+    test_rtc();
+    test_pci();
+    test_block();
+    test_fw_cfg();
+    test_power();
+    test_interrupt_guard();
+    test_interrupt_context();
+    test_nmi();
+    test_backtrace();
+    test_breakpoints();
 
+    #[cfg(feature = "test-double-fault")]
+    test_double_fault();
+    #[cfg(feature = "test-panic-locked")]
+    test_panic_locked();
+    test_spawn_churn();
     test_scheduler();
+
+    irq::print_stats();
+
+    // Benchmarks are slow, so they only run with the bench option of the kernel command line
+    if cmdline::option("bench").is_some() {
+        bench_context_switch();
+    }
+
+    // Leave a gradient on the screen, for the --display mode of the runner
+    if cmdline::option("display").is_some() {
+        assert_eq!(run_as_child("gradient"), Some(0));
+    }
 }
 
 fn test_buddy_alloc() {
@@ -111,12 +350,39 @@ fn test_paging() {
     }
 }
 
+fn test_direct_map() {
+    let page = unsafe { buddy::alloc_pages_panic(1) };
+    let virt_addr = VirtAddr::new_with_raw_value(page as u64);
+
+    // The direct mapping is made of 1 GiB pages, or 2 MiB pages if they are not supported.
+    unsafe {
+        let p4_table = KERNEL_ADDRESS_SPACE.p4_table();
+        let p3_table = p2v((*p4_table).0[virt_addr.p4_index().as_usize()].addr() as usize)
+            as *mut PageDirectory;
+        let p3_entry = (*p3_table).0[virt_addr.p3_index().as_usize()];
+        if !p3_entry.page_size() {
+            let p2_table = p2v(p3_entry.addr() as usize) as *mut PageDirectory;
+            assert!((*p2_table).0[virt_addr.p2_index().as_usize()].page_size());
+        }
+    }
+
+    let resolved = unsafe { KERNEL_ADDRESS_SPACE.resolve(page as usize + 0x123) }.unwrap();
+    assert_eq!(resolved.phys, v2p(page as usize) + 0x123);
+    assert!(resolved.writable && !resolved.user && resolved.nx);
+
+    unsafe { buddy::free_pages(page, 1) };
+
+    printlnk!("Direct mapping uses large pages");
+}
+
 fn test_address_space() {
     let mut address_space = AddressSpace::new();
 
     address_space.map_kernel_pages();
+    // This region is writable and executable, which has to be allowed explicitly.
+    address_space.set_allow_write_execute(true);
     address_space
-        .add_virt_region(0x400000, 4 * PAGE_SIZE, true, true)
+        .add_virt_region(0x400000, 4 * PAGE_SIZE, true, true, false)
         .unwrap();
 
     unsafe {
@@ -124,51 +390,4426 @@ fn test_address_space() {
 
         *(0x403000 as *mut usize) = 0xCAFEBABE;
 
-        set_active_page_directory(KERNEL_P4_TABLE);
+        KERNEL_ADDRESS_SPACE.switch_to_this();
     }
 }
 
-fn test_scheduler() {
-    // From: https://users.rust-lang.org/t/can-i-conveniently-compile-bytes-into-a-rust-program-with-a-specific-alignment/24049/2
-    #[repr(C)] // guarantee 'bytes' comes after '_align'
-    struct AlignedAs<Align, Bytes: ?Sized> {
-        pub _align: [Align; 0],
-        pub bytes: Bytes,
+fn test_huge_pages() {
+    let mut address_space = AddressSpace::new();
+
+    // Each huge page is allocated separately, so the region can be larger than a single buddy block.
+    const LEN: usize = 8 * 1024 * 1024; // 8 MiB, four huge pages
+    let start = 0x40000000;
+    address_space
+        .add_virt_region(start, LEN, true, false, false)
+        .unwrap();
+
+    // Walk the page tables directly, the P2 entries should be huge pages.
+    let mut huge_pages = [0; LEN / HUGE_PAGE_SIZE];
+    unsafe {
+        let virt_addr = VirtAddr::new_with_raw_value(start as u64);
+        let p4_table = address_space.p4_table();
+        let p3_table = p2v((*p4_table).0[virt_addr.p4_index().as_usize()].addr() as usize)
+            as *mut PageDirectory;
+        let p2_table = p2v((*p3_table).0[virt_addr.p3_index().as_usize()].addr() as usize)
+            as *mut PageDirectory;
+
+        for (i, huge_page) in huge_pages.iter_mut().enumerate() {
+            let p2_entry = (*p2_table).0[virt_addr.p2_index().as_usize() + i];
+            assert!(p2_entry.present() && p2_entry.page_size());
+            *huge_page = p2_entry.addr() as usize;
+        }
+    }
+
+    // Resolving addresses inside the huge pages should still work.
+    for offset in [0, 0x1234, 3 * HUGE_PAGE_SIZE + 0x5678, LEN - 1] {
+        assert_eq!(
+            address_space.resolve_virt_addr(start + offset),
+            Some(huge_pages[offset / HUGE_PAGE_SIZE] + offset % HUGE_PAGE_SIZE)
+        );
     }
 
-    macro_rules! include_bytes_align_as {
-        ($align_ty:ty, $path:literal) => {{
-            // const block expression to encapsulate the static
-            use AlignedAs;
+    // A region that is not 2 MiB aligned falls back to 4 KiB pages.
+    let unaligned = start + LEN + PAGE_SIZE;
+    address_space
+        .add_virt_region(unaligned, HUGE_PAGE_SIZE, true, false, false)
+        .unwrap();
+    let (_, page_size) = unsafe { address_space.leaf_entry(unaligned) }.unwrap();
+    assert_eq!(page_size, PAGE_SIZE);
 
-            // this assignment is made possible by CoerceUnsized
-            static ALIGNED: &AlignedAs<$align_ty, [u8]> = &AlignedAs {
-                _align: [],
-                bytes: *include_bytes!($path),
-            };
+    printlnk!("Huge pages mapped and resolved correctly");
+}
 
-            &ALIGNED.bytes
-        }};
+// Allocate every free page, then free 3 pages out of every 4, so no free block is larger than 2 pages.
+// Returns a list (linked through the pages themselves) of the pages still held.
+fn fragment_memory() -> *mut u8 {
+    let mut all_pages: *mut u8 = null_mut();
+    loop {
+        let page = unsafe { buddy::alloc_pages_order(0) };
+        if page.is_null() {
+            break;
+        }
+        unsafe { *(page as *mut *mut u8) = all_pages };
+        all_pages = page;
     }
 
-    const ELF_BINARY: &[u8] = include_bytes_align_as!(u64, "../../tests/test");
+    let mut held_pages: *mut u8 = null_mut();
+    while !all_pages.is_null() {
+        let page = all_pages;
+        all_pages = unsafe { *(page as *mut *mut u8) };
 
-    let parser = ElfParser::parse(ELF_BINARY).unwrap();
+        if (page as usize / PAGE_SIZE).is_multiple_of(4) {
+            unsafe { *(page as *mut *mut u8) = held_pages };
+            held_pages = page;
+        } else {
+            unsafe { buddy::free_pages_order(page, 0) };
+        }
+    }
+    held_pages
+}
 
-    // Create tasks
-    let task1 = Task::create_task_from_elf(&parser).unwrap();
-    let task2 = Task::create_task_from_elf(&parser).unwrap();
+fn release_fragmented_memory(mut held_pages: *mut u8) {
+    while !held_pages.is_null() {
+        let page = held_pages;
+        held_pages = unsafe { *(page as *mut *mut u8) };
+        unsafe { buddy::free_pages_order(page, 0) };
+    }
+}
+
+const SYNTHETIC_DATA_OFFSET: usize = 0x1000;
 
-    // Move tasks to heap
-    let task1 = Rc::new(UnsafeCell::new(task1));
-    let task2 = Rc::new(UnsafeCell::new(task2));
+// Build an ELF file with a single zeroed segment of size bytes at vaddr, with the given p_flags.
+// The segment data starts at SYNTHETIC_DATA_OFFSET in the file.
+fn synthetic_elf(vaddr: usize, size: usize, flags: u32) -> Vec<u8> {
+    synthetic_elf_segments(&[(vaddr, size, flags)])
+}
 
+// Build an ELF file with zeroed segments given as (vaddr, size, p_flags).
+// The segment data is laid out back to back from SYNTHETIC_DATA_OFFSET in the file.
+fn synthetic_elf_segments(segments: &[(usize, usize, u32)]) -> Vec<u8> {
+    let data_size: usize = segments.iter().map(|&(_, size, _)| size).sum();
+    let mut elf = vec![0u8; SYNTHETIC_DATA_OFFSET + data_size];
     unsafe {
-        // Add tasks to scheduler
-        sched::add_new_task(task1);
-        sched::add_new_task(task2);
+        let header = &mut *(elf.as_mut_ptr() as *mut ElfHeader);
+        header.e_ident[0..7].copy_from_slice(b"\x7FELF\x02\x01\x01");
+        header.e_type = ElfType::Executable;
+        header.e_machine = ElfMachine::x86_64;
+        header.e_phoff = size_of::<ElfHeader>() as u64;
+        header.e_phentsize = size_of::<ElfProgramHeader>() as u16;
+        header.e_phnum = segments.len() as u16;
 
-        // Begin scheduler (task1 should run first)
-        sched::begin_scheduler();
+        let mut offset = SYNTHETIC_DATA_OFFSET;
+        for (i, &(vaddr, size, flags)) in segments.iter().enumerate() {
+            let ph = &mut *(elf
+                .as_mut_ptr()
+                .add(size_of::<ElfHeader>() + i * size_of::<ElfProgramHeader>())
+                as *mut ElfProgramHeader);
+            ph.p_type = ElfProgramHeaderType::Load;
+            ph.p_flags = flags;
+            ph.p_offset = offset as u64;
+            ph.p_vaddr = vaddr as u64;
+            ph.p_filesz = size as u64;
+            ph.p_memsz = size as u64;
+            offset += size;
+        }
+    }
+    elf
+}
+
+fn test_fragmented_segment() {
+    const SEGMENT_VADDR: usize = 0x400000;
+    const SEGMENT_SIZE: usize = 3 * 1024 * 1024;
+
+    // Build a synthetic ELF file with a single read-only 3 MiB segment.
+    let mut elf = synthetic_elf(SEGMENT_VADDR, SEGMENT_SIZE, 0x4);
+    for (i, byte) in elf[SYNTHETIC_DATA_OFFSET..].iter_mut().enumerate() {
+        *byte = (i / PAGE_SIZE) as u8;
+    }
+    let parser = ElfParser::parse(&elf).unwrap();
+
+    // Physical memory has no contiguous block larger than 8 KiB from here on.
+    let held_pages = fragment_memory();
+
+    let mut address_space = AddressSpace::new();
+    address_space.map_elf_segments(&parser).unwrap();
+
+    for page in 0..SEGMENT_SIZE / PAGE_SIZE {
+        let phys_addr = address_space
+            .resolve_virt_addr(SEGMENT_VADDR + page * PAGE_SIZE)
+            .unwrap();
+        assert_eq!(unsafe { *(p2v(phys_addr) as *const u8) }, page as u8);
+    }
+
+    drop(address_space);
+    release_fragmented_memory(held_pages);
+
+    printlnk!("Large segment loaded into fragmented memory");
+}
+
+fn test_protect_region() {
+    let mut address_space = AddressSpace::new();
+    address_space.map_kernel_pages();
+
+    // A region backed by a huge page, followed by a region of small pages.
+    let start = 0x40000000;
+    address_space
+        .add_virt_region(start, HUGE_PAGE_SIZE, true, false, false)
+        .unwrap();
+    address_space
+        .add_virt_region(start + HUGE_PAGE_SIZE, 4 * PAGE_SIZE, true, false, false)
+        .unwrap();
+
+    // Make a range spanning the end of the huge page and the start of the second region read-only.
+    let protect_start = start + HUGE_PAGE_SIZE - 2 * PAGE_SIZE;
+    address_space
+        .protect_region(protect_start, 4 * PAGE_SIZE, false, false)
+        .unwrap();
+
+    // Ranges not fully covered by regions are rejected.
+    assert_eq!(
+        address_space.protect_region(start + HUGE_PAGE_SIZE, 8 * PAGE_SIZE, false, false),
+        Err(MapError::NotMapped)
+    );
+
+    let regions = address_space.virt_regions();
+    assert_eq!(regions.len(), 4);
+    assert!(regions.iter().all(|region| region.writable
+        == !(region.start >= protect_start && region.end() <= protect_start + 4 * PAGE_SIZE)));
+
+    unsafe {
+        set_active_page_directory(address_space.p4_table());
+
+        // Pages outside the range stay writable.
+        *((protect_start - PAGE_SIZE) as *mut usize) = 0xCAFEBABE;
+        *((protect_start + 4 * PAGE_SIZE) as *mut usize) = 0xCAFEBABE;
+
+        KERNEL_ADDRESS_SPACE.switch_to_this();
+    }
+
+    // Check the (now split) page table entries.
+    for page in 0..8 {
+        let addr = protect_start - 2 * PAGE_SIZE + page * PAGE_SIZE;
+        let writable = !(protect_start..protect_start + 4 * PAGE_SIZE).contains(&addr);
+        let (entry, page_size) = unsafe { address_space.leaf_entry(addr) }.unwrap();
+        assert_eq!(page_size, PAGE_SIZE);
+        assert_eq!(unsafe { (*entry).writable() }, writable);
+    }
+
+    // Runs with identical permissions are collapsed, so this prints a handful of lines.
+    address_space.dump();
+
+    // The bss program writes a byte of its BSS and exits. Once the page is made read-only, the write faults instead,
+    // and the task is killed.
+    programs::register("bss", BSS_BINARY);
+    assert_eq!(run_as_child("bss"), Some(0));
+    assert_eq!(run_as_child_with("bss", protect_bss_write), Some(-1));
+    assert!(unsafe { sched::TASK_TABLE.is_empty() });
+
+    printlnk!("Region permissions changed correctly");
+}
+
+// Map the page of the BSS the bss program writes to, as its write fault would, then make it read-only.
+fn protect_bss_write(task: &mut Task) {
+    // The byte big[12345], in the 1 MiB array at 0x403000
+    const WRITE_ADDR: usize = 0x403000 + 12345;
+
+    let addr_space = &mut task.addr_space;
+    assert_eq!(addr_space.handle_lazy_fault(WRITE_ADDR, true), Ok(true));
+    addr_space
+        .protect_region(WRITE_ADDR, 1, false, false)
+        .unwrap();
+    let (entry, _) = unsafe { addr_space.leaf_entry(WRITE_ADDR) }.unwrap();
+    assert!(!unsafe { (*entry).writable() });
+}
+
+fn test_remove_region() {
+    let mut address_space = AddressSpace::new();
+    address_space.map_kernel_pages();
+
+    let start = 0x400000;
+    let len = 8 * PAGE_SIZE;
+
+    // The first round allocates the page tables, which are kept after removal.
+    address_space
+        .add_virt_region(start, len, true, false, false)
+        .unwrap();
+    address_space.remove_virt_region(start).unwrap();
+    assert!(address_space.virt_regions().is_empty());
+    assert_eq!(address_space.resolve_virt_addr(start), None);
+
+    // Re-map the same range, the backing pages are recycled.
+    let before = buddy::allocated_pages();
+    address_space
+        .add_virt_region(start, len, true, false, false)
+        .unwrap();
+    assert_eq!(buddy::allocated_pages(), before + len / PAGE_SIZE);
+    address_space.remove_virt_region(start).unwrap();
+    assert_eq!(buddy::allocated_pages(), before);
+    assert_eq!(
+        address_space.remove_virt_region(start),
+        Err(MapError::NotMapped)
+    );
+
+    // Removing the middle of a region splits it.
+    address_space
+        .add_virt_region(start, len, true, false, false)
+        .unwrap();
+    address_space
+        .remove_range(start + 2 * PAGE_SIZE, 4 * PAGE_SIZE)
+        .unwrap();
+    assert_eq!(address_space.virt_regions().len(), 2);
+    assert_eq!(buddy::allocated_pages(), before + 4);
+    assert!(address_space.check_region_no_overlap(start + 2 * PAGE_SIZE, 4 * PAGE_SIZE));
+    assert!(!address_space.check_region_no_overlap(start + PAGE_SIZE, 2 * PAGE_SIZE));
+    for page in 0..8 {
+        let mapped = address_space
+            .resolve_virt_addr(start + page * PAGE_SIZE)
+            .is_some();
+        assert_eq!(mapped, !(2..6).contains(&page));
+    }
+
+    // Removing a part of a huge page splits it.
+    let huge_start = 0x40000000;
+    address_space
+        .add_virt_region(huge_start, HUGE_PAGE_SIZE, true, false, false)
+        .unwrap();
+    address_space
+        .remove_range(huge_start + PAGE_SIZE, HUGE_PAGE_SIZE - PAGE_SIZE)
+        .unwrap();
+    let (_, page_size) = unsafe { address_space.leaf_entry(huge_start) }.unwrap();
+    assert_eq!(page_size, PAGE_SIZE);
+    assert_eq!(
+        address_space.resolve_virt_addr(huge_start + PAGE_SIZE),
+        None
+    );
+
+    printlnk!("Regions removed correctly");
+}
+
+fn test_heap() {
+    let parser = ElfParser::parse(ELF_BINARY).unwrap();
+    let mut task = Task::create_task_from_elf(&parser).unwrap();
+    let addr_space = &mut task.addr_space;
+
+    let (heap_base, heap_end) = addr_space.heap();
+    assert_eq!(heap_base, heap_end);
+    assert!(heap_base.is_multiple_of(PAGE_SIZE));
+
+    // Growing maps zeroed pages, and is accounted against the address space.
+    let mapped_pages = addr_space.mapped_pages();
+    assert_eq!(
+        addr_space.grow_heap(heap_base + 3 * PAGE_SIZE - 1),
+        Ok(heap_base + 3 * PAGE_SIZE - 1)
+    );
+    assert_eq!(addr_space.mapped_pages(), mapped_pages + 3);
+    assert!(addr_space.check_user_range(heap_base, 3 * PAGE_SIZE, true));
+    let phys_addr = addr_space
+        .resolve_virt_addr(heap_base + 2 * PAGE_SIZE)
+        .unwrap();
+    assert_eq!(unsafe { *(p2v(phys_addr) as *const usize) }, 0);
+
+    // The heap can't run into the stack.
+    assert_eq!(
+        addr_space.grow_heap(USER_STACK_VADDR + PAGE_SIZE),
+        Err(MapError::Overlap)
+    );
+    assert_eq!(
+        addr_space.heap(),
+        (heap_base, heap_base + 3 * PAGE_SIZE - 1)
+    );
+
+    // Shrinking unmaps the pages beyond the new end.
+    assert_eq!(
+        addr_space.grow_heap(heap_base + PAGE_SIZE),
+        Ok(heap_base + PAGE_SIZE)
+    );
+    assert_eq!(addr_space.mapped_pages(), mapped_pages + 1);
+    assert!(addr_space.resolve_virt_addr(heap_base).is_some());
+    assert!(
+        addr_space
+            .resolve_virt_addr(heap_base + PAGE_SIZE)
+            .is_none()
+    );
+    assert_eq!(
+        addr_space.grow_heap(heap_base - 1),
+        Err(MapError::InvalidRange)
+    );
+
+    // Growing past free memory fails, and takes no pages
+    let allocated = buddy::allocated_pages();
+    let (_, free) = buddy::page_counts();
+    assert_eq!(
+        addr_space.grow_heap(heap_base + PAGE_SIZE + free * PAGE_SIZE),
+        Err(MapError::OutOfMemory)
+    );
+    assert_eq!(addr_space.heap(), (heap_base, heap_base + PAGE_SIZE));
+    assert_eq!(addr_space.mapped_pages(), mapped_pages + 1);
+    assert_eq!(buddy::allocated_pages(), allocated);
+
+    printlnk!("Heap grown and shrunk correctly");
+}
+
+fn test_map_anonymous() {
+    let mut address_space = AddressSpace::new();
+
+    // Mappings are placed from the top of the window down, without overlapping each other.
+    let first = address_space.map_anonymous(3 * PAGE_SIZE, true).unwrap();
+    assert_eq!(first, MMAP_WINDOW_END - 3 * PAGE_SIZE);
+    let second = address_space.map_anonymous(PAGE_SIZE, false).unwrap();
+    assert_eq!(second, first - PAGE_SIZE);
+    assert!(address_space.check_user_range(first, 3 * PAGE_SIZE, true));
+    assert!(!address_space.check_user_range(second, PAGE_SIZE, true));
+
+    // Holes left by removed regions are reused.
+    address_space.remove_virt_region(first).unwrap();
+    assert_eq!(
+        address_space.find_free_region(2 * PAGE_SIZE, PAGE_SIZE),
+        Some(MMAP_WINDOW_END - 2 * PAGE_SIZE)
+    );
+    assert_eq!(
+        address_space.find_free_region(PAGE_SIZE, HUGE_PAGE_SIZE),
+        Some(MMAP_WINDOW_END - HUGE_PAGE_SIZE)
+    );
+
+    // Invalid lengths and exhaustion are errors.
+    assert_eq!(
+        address_space.map_anonymous(0, true),
+        Err(MapError::InvalidRange)
+    );
+    assert_eq!(
+        address_space.find_free_region(usize::MAX - 1, PAGE_SIZE),
+        None
+    );
+    assert_eq!(
+        address_space.find_free_region(MMAP_WINDOW_END, PAGE_SIZE),
+        None
+    );
+    assert_eq!(
+        address_space.map_anonymous(usize::MAX, true),
+        Err(MapError::NoSpace)
+    );
+
+    // A mapping larger than free memory fails up front, and takes no pages
+    let allocated = buddy::allocated_pages();
+    let (_, free) = buddy::page_counts();
+    assert_eq!(
+        address_space.map_anonymous(free * PAGE_SIZE, true),
+        Err(MapError::OutOfMemory)
+    );
+    assert_eq!(buddy::allocated_pages(), allocated);
+    assert_eq!(address_space.mapped_pages(), 1);
+
+    printlnk!("Anonymous regions placed correctly");
+}
+
+fn test_stack_guard() {
+    let parser = ElfParser::parse(ELF_BINARY).unwrap();
+    let mut task = Task::create_task_from_elf(&parser).unwrap();
+    let addr_space = &mut task.addr_space;
+
+    // The page below the stack is reserved but not mapped.
+    let guard = USER_STACK_VADDR - PAGE_SIZE;
+    assert!(addr_space.guard_region_at(guard).is_some());
+    assert!(addr_space.guard_region_at(USER_STACK_VADDR).is_none());
+    assert_eq!(addr_space.resolve_virt_addr(guard), None);
+    assert_eq!(
+        addr_space.add_virt_region(guard, PAGE_SIZE, true, false, false),
+        Err(MapError::Overlap)
+    );
+
+    printlnk!("Stack guard page reserved");
+}
+
+fn test_initial_stack() {
+    let mut task = Task::spawn(ARGS_BINARY, &["args", "echo this"]).unwrap();
+    let sp = unsafe { (*task.kernel_stack.peek::<InterruptStackFrame>()).sp };
+    assert_eq!(sp % 16, 0);
+
+    let addr_space = &mut task.addr_space;
+    let mut read_word = |addr: usize| {
+        let mut word = [0; 8];
+        uaccess::copy_from_user(addr_space, &mut word, addr).unwrap();
+        usize::from_le_bytes(word)
+    };
+    let words: Vec<usize> = (0..16).map(|i| read_word(sp + i * 8)).collect();
+
+    // argc, argv, NULL, (no envp), NULL, then the auxiliary vector
+    assert_eq!(words[0], 2);
+    assert_eq!((words[3], words[4]), (0, 0));
+    let auxv: Vec<(usize, usize)> = words[5..]
+        .chunks(2)
+        .map(|pair| (pair[0], pair[1]))
+        .collect();
+    let end = auxv.iter().position(|&(key, _)| key == 0).unwrap();
+    assert!(auxv[..end].contains(&(AT_PAGESZ, PAGE_SIZE)));
+    assert!(auxv[..end].contains(&(AT_ENTRY, 0x401000)));
+    // The program headers are loaded with the first segment, at offset 0x40.
+    assert!(auxv[..end].contains(&(AT_PHDR, 0x400040)));
+    assert!(auxv[..end].contains(&(AT_PHNUM, 5)));
+
+    for (i, arg) in ["args", "echo this"].iter().enumerate() {
+        let mut buf = [0; 16];
+        let len = uaccess::strncpy_from_user(addr_space, &mut buf, words[1 + i]).unwrap();
+        assert_eq!(&buf[..len], arg.as_bytes());
+    }
+
+    // Arguments must fit on the stack.
+    let huge = "x".repeat(USER_STACK_SIZE);
+    assert_eq!(
+        Task::spawn(ARGS_BINARY, &[&huge]).err(),
+        Some(ElfError::ArgumentsTooLarge)
+    );
+
+    printlnk!("Initial stack set up correctly");
+}
+
+fn test_lazy_regions() {
+    // The BSS of this program is a 1 MiB array at 0x403000, of which only one byte is touched.
+    const BSS_ADDR: usize = 0x403000;
+    const BSS_SIZE: usize = 1024 * 1024;
+
+    let parser = ElfParser::parse(BSS_BINARY).unwrap();
+    let before = buddy::allocated_pages();
+    let mut task = Task::create_task_from_elf(&parser).unwrap();
+    let addr_space = &mut task.addr_space;
+
+    // Neither the BSS nor the stack is backed yet.
+    assert_eq!(addr_space.resolve_virt_addr(BSS_ADDR), None);
+    assert_eq!(addr_space.resolve_virt_addr(BSS_ADDR + BSS_SIZE - 1), None);
+    assert_eq!(addr_space.resolve_virt_addr(USER_STACK_VADDR), None);
+    let loaded = buddy::allocated_pages();
+    assert!(loaded - before < BSS_SIZE / PAGE_SIZE);
+
+    // Simulate the write fault the program takes, the same way the page fault handler resolves it.
+    let mapped_pages = addr_space.mapped_pages();
+    assert_eq!(
+        addr_space.handle_lazy_fault(BSS_ADDR + 12345, true),
+        Ok(true)
+    );
+    assert_eq!(buddy::allocated_pages(), loaded + 1);
+    assert_eq!(addr_space.mapped_pages(), mapped_pages + 1);
+    let phys_addr = addr_space.resolve_virt_addr(BSS_ADDR + 12345).unwrap();
+    assert_eq!(unsafe { *(p2v(phys_addr) as *const u8) }, 0);
+
+    // The page is mapped now, so the fault is not resolved again.
+    assert_eq!(
+        addr_space.handle_lazy_fault(BSS_ADDR + 12345, true),
+        Ok(false)
+    );
+    // Guard regions and unmapped addresses are not lazy.
+    assert_eq!(
+        addr_space.handle_lazy_fault(USER_STACK_VADDR - PAGE_SIZE, false),
+        Ok(false)
+    );
+    assert_eq!(addr_space.handle_lazy_fault(0x1000_0000, false), Ok(false));
+    assert_eq!(buddy::allocated_pages(), loaded + 1);
+
+    // Without a free page, the fault fails rather than panicking, and so does a user access that needs the page.
+    let blocks = take_free_pages();
+    assert_eq!(
+        addr_space.handle_lazy_fault(BSS_ADDR, true),
+        Err(MapError::OutOfMemory)
+    );
+    assert!(uaccess::access_ok(addr_space, BSS_ADDR, 1, true).is_err());
+    give_back_pages(blocks);
+    assert_eq!(addr_space.resolve_virt_addr(BSS_ADDR), None);
+
+    drop(task);
+    assert_eq!(buddy::allocated_pages(), before);
+
+    printlnk!("Lazy regions populated on demand");
+}
+
+fn test_bss_zero_fill() {
+    // The data segment of this program is 10000 bytes of 0x5a at 0x403000, then 20000 bytes of BSS.
+    const DATA_ADDR: usize = 0x403000;
+    const DATA_SIZE: usize = 10000;
+    const BSS_SIZE: usize = 20000;
+
+    let mut task = load_task(BSS_FILL_BINARY);
+    let addr_space = &mut task.addr_space;
+
+    // The BSS pages past the file data aren't backed until touched.
+    let lazy_start = (DATA_ADDR + DATA_SIZE).next_multiple_of(PAGE_SIZE);
+    assert_eq!(addr_space.resolve_virt_addr(lazy_start), None);
+
+    let mut segment = vec![0xff; DATA_SIZE + BSS_SIZE];
+    uaccess::copy_from_user(addr_space, &mut segment, DATA_ADDR).unwrap();
+    assert!(segment[..DATA_SIZE].iter().all(|&byte| byte == 0x5a));
+    assert!(segment[DATA_SIZE..].iter().all(|&byte| byte == 0));
+
+    // zero_region zeroes mapped pages whatever they held, across page boundaries.
+    let mut addr_space = AddressSpace::new();
+    addr_space
+        .add_virt_region(0x400000, 3 * PAGE_SIZE, true, false, false)
+        .unwrap();
+    addr_space
+        .copy_into_region(0x400000, &[0xff; 3 * PAGE_SIZE])
+        .unwrap();
+    addr_space
+        .zero_region(0x400000 + 100, 2 * PAGE_SIZE)
+        .unwrap();
+    let mut bytes = vec![0; 3 * PAGE_SIZE];
+    uaccess::copy_from_user(&mut addr_space, &mut bytes, 0x400000).unwrap();
+    assert!(bytes[..100].iter().all(|&byte| byte == 0xff));
+    assert!(
+        bytes[100..100 + 2 * PAGE_SIZE]
+            .iter()
+            .all(|&byte| byte == 0)
+    );
+    assert!(
+        bytes[100 + 2 * PAGE_SIZE..]
+            .iter()
+            .all(|&byte| byte == 0xff)
+    );
+    assert_eq!(
+        addr_space.zero_region(0x400000 + 3 * PAGE_SIZE - 1, 2),
+        Err(MapError::NotMapped)
+    );
+
+    // The BSS of a segment can run into the page shared with the next segment, whose data must survive.
+    let mut elf = synthetic_elf_segments(&[(0x400000, 0x800, 0x6), (0x401a00, 0x100, 0x6)]);
+    elf[SYNTHETIC_DATA_OFFSET..SYNTHETIC_DATA_OFFSET + 0x800].fill(0xaa);
+    elf[SYNTHETIC_DATA_OFFSET + 0x800..].fill(0xbb);
+    program_header_of_type(&mut elf, ElfProgramHeaderType::Load).p_memsz = 0x1900;
+    let parser = ElfParser::parse(&elf).unwrap();
+    let mut addr_space = AddressSpace::new();
+    addr_space.map_elf_segments(&parser).unwrap();
+    let mut bytes = vec![0xff; 0x1b00];
+    uaccess::copy_from_user(&mut addr_space, &mut bytes, 0x400000).unwrap();
+    assert!(bytes[..0x800].iter().all(|&byte| byte == 0xaa));
+    assert!(bytes[0x800..0x1a00].iter().all(|&byte| byte == 0));
+    assert!(bytes[0x1a00..].iter().all(|&byte| byte == 0xbb));
+
+    printlnk!("BSS zero-filled correctly");
+}
+
+fn test_write_xor_execute() {
+    let start = 0x400000;
+    let mut address_space = AddressSpace::new();
+
+    // Writable and executable regions are rejected, and so is making a region both.
+    assert_eq!(
+        address_space.add_virt_region(start, PAGE_SIZE, true, true, false),
+        Err(MapError::WriteExecute)
+    );
+    address_space
+        .add_virt_region(start, PAGE_SIZE, false, true, false)
+        .unwrap();
+    assert_eq!(
+        address_space.protect_region(start, PAGE_SIZE, true, true),
+        Err(MapError::WriteExecute)
+    );
+    assert!(!address_space.virt_regions()[0].writable);
+
+    // So are ELF segments that ask for it.
+    let elf = synthetic_elf(start, PAGE_SIZE, 0x7);
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(
+        AddressSpace::new().map_elf_segments(&parser),
+        Err(ElfError::WriteExecute { index: 0 })
+    );
+
+    // The text segment of a real program is executable but not writable from user mode.
+    let parser = ElfParser::parse(WRITE_TEXT_BINARY).unwrap();
+    let task = Task::create_task_from_elf(&parser).unwrap();
+    let entry = parser.get_header().e_entry as usize;
+    assert!(task.addr_space.check_user_range(entry, 1, false));
+    assert!(!task.addr_space.check_user_range(entry, 1, true));
+
+    printlnk!("W^X enforced");
+}
+
+fn test_unaligned_segment() {
+    // The data segment of this program starts at 0x404123, with "unaligned segment" at 0x404140 and BSS from 0x404180.
+    const MESSAGE_ADDR: usize = 0x404140;
+    const BSS_ADDR: usize = 0x404180;
+
+    let parser = ElfParser::parse(UNALIGNED_BINARY).unwrap();
+    let task = Task::create_task_from_elf(&parser).unwrap();
+    let addr_space = &task.addr_space;
+
+    // The region covers the whole page the segment starts in.
+    assert!(
+        addr_space
+            .virt_regions()
+            .iter()
+            .any(|region| region.start == 0x404000 && region.len == PAGE_SIZE && region.writable)
+    );
+
+    let read =
+        |addr: usize| unsafe { *(p2v(addr_space.resolve_virt_addr(addr).unwrap()) as *const u8) };
+    for (i, &byte) in b"unaligned segment\0".iter().enumerate() {
+        assert_eq!(read(MESSAGE_ADDR + i), byte);
+    }
+    // The part of the page before the segment and the BSS after it are zeroed.
+    assert_eq!(read(0x404000), 0);
+    assert!((BSS_ADDR..BSS_ADDR + 128).all(|addr| read(addr) == 0));
+
+    printlnk!("Unaligned segment loaded correctly");
+}
+
+fn test_segments_sharing_page() {
+    // The headers, text and rodata of this program share the page at 0x400000, its data is at 0x401040.
+    const MESSAGE_ADDR: usize = 0x400320;
+    const NUMBERS_ADDR: usize = 0x401040;
+
+    let parser = ElfParser::parse(SHARED_PAGE_BINARY).unwrap();
+    let task = Task::create_task_from_elf(&parser).unwrap();
+    let addr_space = &task.addr_space;
+
+    // The shared page is mapped once, with the permissions of the text segment.
+    let region = &addr_space.virt_regions()[0];
+    assert_eq!((region.start, region.len), (0x400000, PAGE_SIZE));
+    assert!(region.executable && !region.writable);
+    assert!(addr_space.check_user_range(0x400000, PAGE_SIZE, false));
+    assert!(!addr_space.check_user_range(0x400000, PAGE_SIZE, true));
+
+    let read =
+        |addr: usize| unsafe { *(p2v(addr_space.resolve_virt_addr(addr).unwrap()) as *const u8) };
+    for (i, &byte) in b"segments sharing a page\0".iter().enumerate() {
+        assert_eq!(read(MESSAGE_ADDR + i), byte);
+    }
+    assert_eq!(read(NUMBERS_ADDR), 1);
+    // The ELF header is at the start of the shared page.
+    assert_eq!(read(0x400000 + 1), b'E');
+
+    // A page holding both a writable and an executable segment would be writable and executable.
+    let elf = synthetic_elf_segments(&[(0x400000, 0x800, 0x5), (0x400800, 0x800, 0x6)]);
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(
+        AddressSpace::new().map_elf_segments(&parser),
+        Err(ElfError::WriteExecute { index: 1 })
+    );
+
+    // Overlapping segments are rejected.
+    let elf = synthetic_elf_segments(&[(0x400000, 0x800, 0x4), (0x400400, 0x800, 0x4)]);
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(
+        AddressSpace::new().map_elf_segments(&parser),
+        Err(ElfError::OverlappingSegment { index: 1 })
+    );
+
+    printlnk!("Segments sharing a page loaded correctly");
+}
+
+fn test_position_independent() {
+    // This program is linked at 0. Its pointer at 0x4030 holds the address of the int at 0x4000, after relocation.
+    const VALUE_ADDR: usize = 0x4000;
+    const PTR_ADDR: usize = 0x4030;
+    const RELA_OFFSET: usize = 0x2a0;
+
+    let parser = ElfParser::parse(PIE_BINARY).unwrap();
+    assert!(parser.is_position_independent());
+    let task = Task::create_task_from_elf(&parser).unwrap();
+    let addr_space = &task.addr_space;
+
+    assert_eq!(addr_space.virt_regions()[0].start, PIE_LOAD_BASE);
+    let read = |addr: usize| unsafe {
+        (p2v(addr_space.resolve_virt_addr(addr).unwrap()) as *const usize).read_unaligned()
+    };
+    assert_eq!(read(PIE_LOAD_BASE + PTR_ADDR), PIE_LOAD_BASE + VALUE_ADDR);
+    assert_eq!(read(PIE_LOAD_BASE + VALUE_ADDR) as u32, 42);
+
+    // Executables linked at a fixed address are loaded as they are.
+    let parser = ElfParser::parse(ELF_BINARY).unwrap();
+    assert_eq!(AddressSpace::new().map_elf_segments(&parser), Ok(0));
+
+    // Relocations other than relative ones need symbols, which aren't supported.
+    let mut elf = PIE_BINARY.to_vec();
+    elf[RELA_OFFSET + 8] = 1; // R_X86_64_64
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(
+        AddressSpace::new().map_elf_segments(&parser),
+        Err(ElfError::UnsupportedRelocation { r_type: 1 })
+    );
+
+    // Programs that need a dynamic linker are rejected.
+    let mut elf = PIE_BINARY.to_vec();
+    program_header_of_type(&mut elf, ElfProgramHeaderType::Dynamic).p_type =
+        ElfProgramHeaderType::Interp;
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(
+        AddressSpace::new().map_elf_segments(&parser),
+        Err(ElfError::NeedsInterpreter)
+    );
+
+    printlnk!("Position-independent executable relocated correctly");
+}
+
+// Get the first program header of the given type in an ELF file, to corrupt it.
+fn program_header_of_type(elf: &mut [u8], p_type: ElfProgramHeaderType) -> &mut ElfProgramHeader {
+    let header = unsafe { (elf.as_ptr() as *const ElfHeader).read_unaligned() };
+    let index = (0..header.e_phnum as usize)
+        .find(|&i| {
+            let offset = header.e_phoff as usize + i * header.e_phentsize as usize;
+            let ph =
+                unsafe { (elf.as_ptr().add(offset) as *const ElfProgramHeader).read_unaligned() };
+            ph.p_type == p_type
+        })
+        .unwrap();
+    let offset = header.e_phoff as usize + index * header.e_phentsize as usize;
+    unsafe { &mut *(elf.as_mut_ptr().add(offset) as *mut ElfProgramHeader) }
+}
+
+fn test_gnu_stack() {
+    let stack_region = |task: &Task| {
+        let region = task
+            .addr_space
+            .virt_regions()
+            .iter()
+            .find(|region| region.name == Some("stack"))
+            .unwrap();
+        (region.start, region.len, region.executable)
+    };
+
+    // The test binaries ask for a non-executable stack with no particular size.
+    let task = load_task(ELF_BINARY);
+    assert_eq!(
+        stack_region(&task),
+        (USER_STACK_VADDR, USER_STACK_SIZE, false)
+    );
+
+    // A larger stack can be asked for, the guard page moves down with it.
+    let mut elf = ELF_BINARY.to_vec();
+    program_header_of_type(&mut elf, ElfProgramHeaderType::GnuStack).p_memsz = 0x10000;
+    let task = load_task(&elf);
+    assert_eq!(
+        stack_region(&task),
+        (USER_STACK_TOP - 0x10000, 0x10000, false)
+    );
+    assert!(
+        task.addr_space
+            .guard_region_at(USER_STACK_TOP - 0x10000 - PAGE_SIZE)
+            .is_some()
+    );
+
+    // An executable stack is rejected, unless explicitly allowed.
+    let mut elf = ELF_BINARY.to_vec();
+    program_header_of_type(&mut elf, ElfProgramHeaderType::GnuStack).p_flags = 0x7;
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(
+        Task::create_task_from_elf(&parser).err(),
+        Some(ElfError::ExecutableStack)
+    );
+    unsafe { ALLOW_EXECUTABLE_STACK = true };
+    let task = Task::create_task_from_elf(&parser).unwrap();
+    unsafe { ALLOW_EXECUTABLE_STACK = false };
+    assert!(stack_region(&task).2);
+
+    printlnk!("PT_GNU_STACK honored");
+}
+
+fn test_tls() {
+    // The TLS template of this program is 8 bytes of tdata (0x1234) then tbss, 0x6c bytes in total aligned to 8.
+    const BLOCK_SIZE: usize = 0x70;
+
+    let mut task = load_task(TLS_BINARY);
+    let tp = task.fs_base;
+    assert_ne!(tp, 0);
+    assert_eq!(tp % 8, 0);
+    assert!(
+        task.addr_space
+            .virt_regions()
+            .iter()
+            .any(|region| region.name == Some("tls") && region.start == tp - BLOCK_SIZE)
+    );
+
+    let mut block = [0xff; BLOCK_SIZE + 8];
+    uaccess::copy_from_user(&mut task.addr_space, &mut block, tp - BLOCK_SIZE).unwrap();
+    assert_eq!(block[..8], 0x1234usize.to_le_bytes());
+    assert!(block[8..BLOCK_SIZE].iter().all(|&byte| byte == 0));
+    // The TCB points to itself.
+    assert_eq!(block[BLOCK_SIZE..], tp.to_le_bytes());
+
+    // Programs without PT_TLS have no TLS block.
+    assert_eq!(load_task(ELF_BINARY).fs_base, 0);
+
+    printlnk!("TLS block set up correctly");
+}
+
+fn test_elf_errors() {
+    // Corrupt a copy of a valid executable, and check which error parsing or loading it produces.
+    fn load_corrupted(corrupt: impl FnOnce(&mut Vec<u8>)) -> Result<Task, ElfError> {
+        let mut elf = ELF_BINARY.to_vec();
+        corrupt(&mut elf);
+        ElfParser::parse(&elf).and_then(|parser| Task::create_task_from_elf(&parser))
+    }
+    fn header(elf: &mut [u8]) -> &mut ElfHeader {
+        unsafe { &mut *(elf.as_mut_ptr() as *mut ElfHeader) }
+    }
+    fn program_header(elf: &mut [u8], index: usize) -> &mut ElfProgramHeader {
+        let offset = header(elf).e_phoff as usize + index * size_of::<ElfProgramHeader>();
+        unsafe { &mut *(elf.as_mut_ptr().add(offset) as *mut ElfProgramHeader) }
+    }
+
+    assert!(load_corrupted(|_| {}).is_ok());
+
+    type Corruption = fn(&mut Vec<u8>);
+    let cases: [(Corruption, ElfError); 19] = [
+        (|elf| elf.truncate(32), ElfError::TooSmall),
+        (|elf| elf[1] = b'X', ElfError::BadMagic),
+        (|elf| elf[4] = 1, ElfError::UnsupportedClass),
+        (|elf| elf[5] = 2, ElfError::UnsupportedEndian),
+        (|elf| elf[6] = 0, ElfError::UnsupportedVersion),
+        (
+            |elf| header(elf).e_type = ElfType::Relocatable,
+            ElfError::NotExecutable,
+        ),
+        (
+            |elf| header(elf).e_machine = ElfMachine::ARM,
+            ElfError::WrongMachine,
+        ),
+        (|elf| header(elf).e_phentsize = 32, ElfError::BadPhentsize),
+        (
+            |elf| header(elf).e_phoff = u64::MAX - 8,
+            ElfError::PhTableOutOfBounds,
+        ),
+        (
+            |elf| header(elf).e_phoff = elf.len() as u64 - 8,
+            ElfError::PhTableOutOfBounds,
+        ),
+        (
+            |elf| {
+                header(elf).e_phnum = 0;
+                header(elf).e_phoff = elf.len() as u64 + 1;
+            },
+            ElfError::PhTableOutOfBounds,
+        ),
+        (
+            |elf| header(elf).e_phnum = u16::MAX,
+            ElfError::PhTableOutOfBounds,
+        ),
+        (
+            |elf| program_header(elf, 1).p_offset = u64::MAX - 8,
+            ElfError::SegmentOutOfBounds { index: 1 },
+        ),
+        (
+            |elf| program_header(elf, 1).p_filesz = program_header(elf, 1).p_memsz + 1,
+            ElfError::SegmentOutOfBounds { index: 1 },
+        ),
+        (
+            |elf| program_header(elf, 2).p_vaddr = program_header(elf, 1).p_vaddr,
+            ElfError::OverlappingSegment { index: 2 },
+        ),
+        (
+            |elf| program_header(elf, 1).p_vaddr += 0x10,
+            ElfError::MisalignedSegment { index: 1 },
+        ),
+        (
+            |elf| program_header(elf, 1).p_align = 0x30,
+            ElfError::MisalignedSegment { index: 1 },
+        ),
+        (
+            |elf| program_header(elf, 2).p_offset = program_header(elf, 1).p_offset,
+            ElfError::OverlappingFileRange { index: 2 },
+        ),
+        (
+            |elf| program_header(elf, 1).p_filesz = 0x1001,
+            ElfError::OverlappingFileRange { index: 2 },
+        ),
+    ];
+    for (corrupt, error) in cases {
+        assert_eq!(load_corrupted(corrupt).err(), Some(error));
     }
+
+    printlnk!("Corrupted ELF files rejected with the right errors");
+}
+
+fn test_sections() {
+    let parser = ElfParser::parse(ELF_BINARY).unwrap();
+    assert!(parser.sections().all(|section| section.is_ok()));
+    let (name, _) = parser.sections().next().unwrap().unwrap();
+    assert_eq!(name, "");
+
+    let text = parser.get_section_by_name(".text").unwrap();
+    assert_eq!(text.sh_type, ElfSectionHeaderType::Progbits);
+    assert_eq!(
+        text.sh_flags & (SHF_ALLOC | SHF_EXECINSTR | SHF_WRITE),
+        SHF_ALLOC | SHF_EXECINSTR
+    );
+    // The entry point is in .text.
+    let entry = parser.get_header().e_entry;
+    assert!(text.sh_addr <= entry && entry < text.sh_addr + text.sh_size);
+    let rodata = parser.get_section_by_name(".rodata").unwrap();
+    assert_eq!(
+        rodata.sh_flags & (SHF_ALLOC | SHF_EXECINSTR | SHF_WRITE),
+        SHF_ALLOC
+    );
+
+    let parser = ElfParser::parse(BSS_FILL_BINARY).unwrap();
+    let data = parser.get_section_by_name(".data").unwrap();
+    assert_eq!(
+        data.sh_flags & (SHF_ALLOC | SHF_EXECINSTR | SHF_WRITE),
+        SHF_ALLOC | SHF_WRITE
+    );
+    assert_eq!(parser.get_section_data(&data).unwrap()[0], 0x5a);
+    let bss = parser.get_section_by_name(".bss").unwrap();
+    assert_eq!(bss.sh_type, ElfSectionHeaderType::Nobits);
+
+    // A bad e_shstrndx gives a typed error instead of names from the wrong section.
+    for (shstrndx, error) in [
+        (SHN_UNDEF, ElfError::BadShstrndx),
+        (SHN_XINDEX, ElfError::BadShstrndx),
+        (1000, ElfError::BadShstrndx),
+    ] {
+        let mut elf = ELF_BINARY.to_vec();
+        unsafe { (*(elf.as_mut_ptr() as *mut ElfHeader)).e_shstrndx = shstrndx };
+        let parser = ElfParser::parse(&elf).unwrap();
+        assert_eq!(parser.sections().last().unwrap().err(), Some(error));
+        assert!(parser.get_section_by_name(".text").is_none());
+    }
+
+    // A name outside of the string table is an error too. Section 2 is .text.
+    let mut elf = ELF_BINARY.to_vec();
+    let header = unsafe { (elf.as_ptr() as *const ElfHeader).read_unaligned() };
+    let offset = header.e_shoff as usize + 2 * header.e_shentsize as usize;
+    unsafe { (*(elf.as_mut_ptr().add(offset) as *mut ElfSectionHeader)).sh_name = u32::MAX };
+    let parser = ElfParser::parse(&elf).unwrap();
+    let sh = parser.get_section_header(2).unwrap();
+    assert_eq!(parser.section_name(&sh), Err(ElfError::BadSectionName));
+    assert!(parser.get_section_by_name(".text").is_none());
+    assert!(parser.get_section_by_name(".rodata").is_some());
+
+    printlnk!("Sections found by name");
+}
+
+fn test_symbols() {
+    // This program faults at 0x401009, in crash_here (0x401000, 18 bytes). _start follows it at 0x401012.
+    const FAULT_ADDR: usize = 0x401009;
+
+    let parser = ElfParser::parse(CRASH_BINARY).unwrap();
+    assert!(parser.get_section_by_name(".symtab").is_some());
+    assert!(parser.get_section_by_name(".nonexistent").is_none());
+    assert!(parser.symbols().any(|(_, name)| name == "crash_here"));
+
+    let task = Task::create_task_from_elf(&parser).unwrap();
+    assert_eq!(
+        task.symbols.resolve_symbol(FAULT_ADDR),
+        Some(("crash_here", 0x9))
+    );
+    assert_eq!(task.symbols.resolve_symbol(0x401012), Some(("_start", 0)));
+    assert_eq!(task.symbols.resolve_symbol(0x400fff), None);
+    assert_eq!(task.symbols.resolve_symbol(0x401012 + 58), None);
+
+    let mut out = String::new();
+    task.symbols.format_addr(FAULT_ADDR, &mut out).unwrap();
+    assert_eq!(out, "0x401009 (<crash_here+0x9>)");
+
+    // Symbols of a position-independent executable are offset by its load base.
+    let parser = ElfParser::parse(PIE_BINARY).unwrap();
+    let symbols = parser.symbol_table(PIE_LOAD_BASE);
+    assert_eq!(
+        symbols.resolve_symbol(PIE_LOAD_BASE + 0x1000),
+        Some(("_start", 0))
+    );
+
+    // A stripped file has no symbols, and addresses are printed as they are.
+    let mut elf = CRASH_BINARY.to_vec();
+    unsafe { (*(elf.as_mut_ptr() as *mut ElfHeader)).e_shnum = 0 };
+    let parser = ElfParser::parse(&elf).unwrap();
+    assert_eq!(parser.symbols().count(), 0);
+    let mut out = String::new();
+    parser
+        .symbol_table(0)
+        .format_addr(FAULT_ADDR, &mut out)
+        .unwrap();
+    assert_eq!(out, "0x401009");
+
+    printlnk!("Symbols resolved correctly");
+}
+
+fn test_elf_misaligned() {
+    // Copy a valid executable to an odd address, so none of its headers are aligned.
+    let mut buf = vec![0u8; ELF_BINARY.len() + 1];
+    let offset = if (buf.as_ptr() as usize).is_multiple_of(2) {
+        1
+    } else {
+        0
+    };
+    let elf = &mut buf[offset..offset + ELF_BINARY.len()];
+    elf.copy_from_slice(ELF_BINARY);
+
+    let parser = ElfParser::parse(elf).unwrap();
+    let aligned_parser = ElfParser::parse(ELF_BINARY).unwrap();
+    assert_eq!(
+        parser.get_header().e_entry,
+        aligned_parser.get_header().e_entry
+    );
+    for i in 0..parser.get_header().e_phnum as usize {
+        let ph = parser.get_program_header(i).unwrap();
+        let aligned_ph = aligned_parser.get_program_header(i).unwrap();
+        assert_eq!(
+            (ph.p_type, ph.p_vaddr, ph.p_filesz),
+            (aligned_ph.p_type, aligned_ph.p_vaddr, aligned_ph.p_filesz)
+        );
+    }
+    assert!(Task::create_task_from_elf(&parser).is_ok());
+
+    printlnk!("Misaligned ELF parsed correctly");
+}
+
+// Allocate every free page, in the largest blocks first, so that the next allocation fails. Returns the blocks with
+// their orders, for give_back_pages().
+fn take_free_pages() -> Vec<(*mut u8, usize)> {
+    // Reserved up front, as the vector can't grow once memory is gone
+    let mut blocks = Vec::with_capacity(4096);
+    for order in (0..=buddy::MAX_ORDER).rev() {
+        loop {
+            let block = unsafe { buddy::alloc_pages_order(order) };
+            if block.is_null() {
+                break;
+            }
+            assert!(blocks.len() < blocks.capacity());
+            blocks.push((block, order));
+        }
+    }
+    blocks
+}
+
+fn give_back_pages(blocks: Vec<(*mut u8, usize)>) {
+    for (block, order) in blocks {
+        unsafe { buddy::free_pages_order(block, order) };
+    }
+}
+
+fn test_check_user_range() {
+    let mut address_space = AddressSpace::new();
+    address_space.map_kernel_pages();
+
+    let start = 0x400000;
+    address_space
+        .add_virt_region(start, 3 * PAGE_SIZE, true, false, false)
+        .unwrap();
+
+    // A range spanning the last mapped page and the unmapped page after it.
+    assert!(address_space.check_user_range(start + 2 * PAGE_SIZE, PAGE_SIZE, false));
+    assert!(!address_space.check_user_range(start + 3 * PAGE_SIZE - 8, 16, false));
+
+    // Only the middle page is read-only.
+    address_space
+        .protect_region(start + PAGE_SIZE, PAGE_SIZE, false, false)
+        .unwrap();
+    assert!(address_space.check_user_range(start, 3 * PAGE_SIZE, false));
+    assert!(!address_space.check_user_range(start, 3 * PAGE_SIZE, true));
+    assert!(address_space.check_user_range(start, PAGE_SIZE, true));
+    assert!(address_space.check_user_range(start + 2 * PAGE_SIZE, PAGE_SIZE, true));
+
+    // Kernel addresses are never accessible from user mode.
+    let kernel_addr = &raw const address_space as usize;
+    assert!(!address_space.check_user_range(kernel_addr, 8, false));
+    assert!(!address_space.check_user_range(usize::MAX - 8, 16, false));
+
+    printlnk!("User ranges checked correctly");
+}
+
+fn test_uaccess() {
+    let start = 0x400000;
+    let end = start + 2 * PAGE_SIZE; // Nothing is mapped from here on
+    let read_only = 0x500000;
+    let lazy = 0x600000;
+
+    let mut address_space = AddressSpace::new();
+    address_space
+        .add_virt_region(start, 2 * PAGE_SIZE, true, false, false)
+        .unwrap();
+    address_space
+        .add_virt_region(read_only, PAGE_SIZE, false, false, false)
+        .unwrap();
+    address_space
+        .add_virt_region(lazy, PAGE_SIZE, true, false, true)
+        .unwrap();
+
+    // Copies across a page boundary, between mapped pages.
+    let data = *b"12345678";
+    let mut buf = [0u8; 8];
+    uaccess::copy_to_user(&mut address_space, start + PAGE_SIZE - 4, &data).unwrap();
+    uaccess::copy_from_user(&mut address_space, &mut buf, start + PAGE_SIZE - 4).unwrap();
+    assert_eq!(buf, data);
+
+    // Ranges straddling the mapped/unmapped boundary are rejected, and nothing is written.
+    assert_eq!(
+        uaccess::copy_to_user(&mut address_space, end - 4, &data),
+        Err(uaccess::Fault)
+    );
+    assert_eq!(
+        uaccess::copy_from_user(&mut address_space, &mut buf, end - 4),
+        Err(uaccess::Fault)
+    );
+    uaccess::copy_from_user(&mut address_space, &mut buf[..4], end - 4).unwrap();
+    assert_eq!(buf[..4], [0; 4]);
+
+    // Read-only pages can be read but not written, and kernel addresses are never accessible.
+    uaccess::copy_from_user(&mut address_space, &mut buf, read_only).unwrap();
+    assert!(uaccess::copy_to_user(&mut address_space, read_only, &data).is_err());
+    assert!(uaccess::copy_from_user(&mut address_space, &mut buf, 0xffff_8000_0000_0000).is_err());
+    assert!(uaccess::copy_from_user(&mut address_space, &mut buf, usize::MAX - 4).is_err());
+
+    // Lazy pages are populated.
+    let mapped_pages = address_space.mapped_pages();
+    uaccess::copy_to_user(&mut address_space, lazy, &data).unwrap();
+    assert_eq!(address_space.mapped_pages(), mapped_pages + 1);
+
+    // Strings are read up to the NUL, which may be right before an unmapped page.
+    let mut string = [0u8; 64];
+    uaccess::copy_to_user(&mut address_space, end - 6, b"hello\0").unwrap();
+    assert_eq!(
+        uaccess::strncpy_from_user(&mut address_space, &mut string, end - 6),
+        Ok(5)
+    );
+    assert_eq!(&string[..6], b"hello\0");
+    assert_eq!(
+        uaccess::strncpy_from_user(&mut address_space, &mut string[..3], end - 6),
+        Ok(3)
+    );
+    // A string running into the unmapped page is rejected.
+    uaccess::copy_to_user(&mut address_space, end - 6, b"hello!").unwrap();
+    assert_eq!(
+        uaccess::strncpy_from_user(&mut address_space, &mut string, end - 6),
+        Err(uaccess::Fault)
+    );
+
+    printlnk!("User memory accessed safely");
+}
+
+fn test_region_maps() {
+    let parser = ElfParser::parse(MAPS_BINARY).unwrap();
+    let mut task = Task::create_task_from_elf(&parser).unwrap();
+    let addr_space = &mut task.addr_space;
+    addr_space
+        .grow_heap(addr_space.heap().0 + PAGE_SIZE)
+        .unwrap();
+    let mmap = addr_space.map_anonymous(PAGE_SIZE, true).unwrap();
+
+    let mut maps = String::new();
+    addr_space.format_maps(&mut maps).unwrap();
+    printlnk!("{}", maps);
+
+    let expected = [
+        (0x400000, "r-- rodata"),
+        (0x401000, "r-x text"),
+        (0x402000, "r-- rodata"),
+        (0x403000, "rw- bss"),
+        (0x404000, "rw- heap"),
+        (mmap, "rw- mmap"),
+        (USER_STACK_VADDR - PAGE_SIZE, "--- guard"),
+        (USER_STACK_VADDR, "rw- stack"),
+    ];
+    let lines: Vec<&str> = maps.lines().collect();
+    assert_eq!(lines.len(), expected.len());
+    for (line, (start, suffix)) in lines.iter().zip(expected) {
+        assert!(line.starts_with(&format!("{:016x}-", start)));
+        assert!(line.ends_with(suffix));
+    }
+
+    printlnk!("Region maps formatted correctly");
+}
+
+fn test_accessed_scan() {
+    let mut address_space = AddressSpace::new();
+    address_space.map_kernel_pages();
+
+    let start = 0x400000;
+    let len = 8 * PAGE_SIZE;
+    address_space
+        .add_virt_region(start, len, true, false, false)
+        .unwrap();
+    address_space.scan_and_clear_accessed(start, len);
+
+    // Write to the even pages and read the first odd page, through the user mappings.
+    unsafe {
+        address_space.switch_to_this();
+
+        for page in (0..8).step_by(2) {
+            *((start + page * PAGE_SIZE) as *mut usize) = 0xCAFEBABE;
+        }
+        let _ = ((start + PAGE_SIZE) as *const usize).read_volatile();
+
+        KERNEL_ADDRESS_SPACE.switch_to_this();
+    }
+
+    let bitmap = address_space.scan_and_clear_accessed(start, len);
+    for page in 0..8 {
+        let addr = start + page * PAGE_SIZE;
+        assert_eq!(bitmap.is_accessed(addr), page % 2 == 0 || page == 1);
+        assert_eq!(bitmap.is_dirty(addr), page % 2 == 0);
+    }
+
+    // The bits were cleared by the previous scan.
+    let bitmap = address_space.scan_and_clear_accessed(start, len);
+    assert!(bitmap.accessed.not_any() && bitmap.dirty.not_any());
+
+    // An unaligned start still reaches the page its last byte lands on.
+    let last_page = start + 7 * PAGE_SIZE;
+    unsafe {
+        address_space.switch_to_this();
+        *(last_page as *mut usize) = 0xCAFEBABE;
+        KERNEL_ADDRESS_SPACE.switch_to_this();
+    }
+    let bitmap = address_space.scan_and_clear_accessed(start + 0x123, len - PAGE_SIZE);
+    assert_eq!(bitmap.accessed.len(), 8);
+    assert!(bitmap.is_accessed(last_page) && bitmap.is_dirty(last_page));
+
+    printlnk!("Accessed and dirty pages scanned correctly");
+}
+
+// Create two address spaces sharing one region. Returns the first backing page of the region.
+fn create_shared_pair(start: usize, len: usize) -> (AddressSpace, AddressSpace, *mut u8) {
+    let mut a = AddressSpace::new();
+    a.add_virt_region(start, len, true, false, false).unwrap();
+    let pages = p2v(a.resolve_virt_addr(start).unwrap()) as *mut u8;
+
+    let mut b = AddressSpace::new();
+    b.map_shared(&a, start).unwrap();
+
+    (a, b, pages)
+}
+
+fn test_shared_pages() {
+    let start = 0x400000;
+    let len = 4 * PAGE_SIZE;
+
+    // Warm up the slab allocator, so the page counts below only reflect the address spaces.
+    drop(create_shared_pair(start, len));
+
+    let before = buddy::allocated_pages();
+
+    let (a, b, pages) = create_shared_pair(start, len);
+    assert_eq!(b.resolve_virt_addr(start), Some(v2p(pages as usize)));
+    assert!(!b.virt_regions()[0].writable);
+    assert_eq!(unsafe { page_meta::page_refcount(pages) }, 2);
+    drop(a);
+    assert_eq!(unsafe { page_meta::page_refcount(pages) }, 1);
+    drop(b);
+    assert_eq!(buddy::allocated_pages(), before);
+
+    // The other way around.
+    let (a, b, pages) = create_shared_pair(start, len);
+    drop(b);
+    assert_eq!(unsafe { page_meta::page_refcount(pages) }, 1);
+    drop(a);
+    assert_eq!(buddy::allocated_pages(), before);
+
+    printlnk!("Shared pages freed exactly once");
+}
+
+fn test_shared_fork() {
+    let mut parent = AddressSpace::new();
+    parent.map_kernel_pages();
+    let start = parent.map_anonymous_shared(2 * PAGE_SIZE, true).unwrap();
+
+    // Shared pages stay writable on both sides after fork, and are never copied
+    let mut child = parent.fork().unwrap();
+    let page = p2v(parent.resolve_virt_addr(start).unwrap()) as *mut u8;
+    assert_eq!(child.resolve_virt_addr(start), Some(v2p(page as usize)));
+    assert_eq!(unsafe { page_meta::page_refcount(page) }, 2);
+    assert!(parent.check_user_range(start, 2 * PAGE_SIZE, true));
+    assert!(child.check_user_range(start, 2 * PAGE_SIZE, true));
+    let region = &child.virt_regions()[0];
+    assert!(region.shared && !region.cow);
+    assert_eq!(parent.handle_cow_fault(start), Ok(false));
+    assert_eq!(child.handle_cow_fault(start), Ok(false));
+
+    drop(parent);
+    assert_eq!(unsafe { page_meta::page_refcount(page) }, 1);
+    drop(child);
+
+    printlnk!("Shared fork test passed");
+}
+
+fn test_cow_fork() {
+    let start = 0x400000;
+
+    let mut parent = AddressSpace::new();
+    parent.map_kernel_pages();
+    parent
+        .add_virt_region(start, 2 * PAGE_SIZE, true, false, false)
+        .unwrap();
+    parent
+        .copy_into_region(start, &1usize.to_ne_bytes())
+        .unwrap();
+
+    // After fork, both address spaces map the same pages read-only.
+    let mut child = parent.fork().unwrap();
+    let page = p2v(parent.resolve_virt_addr(start).unwrap()) as *mut u8;
+    assert_eq!(child.resolve_virt_addr(start), Some(v2p(page as usize)));
+    assert_eq!(unsafe { page_meta::page_refcount(page) }, 2);
+    assert!(!parent.check_user_range(start, PAGE_SIZE, true));
+    assert!(!child.check_user_range(start, PAGE_SIZE, true));
+    assert!(child.virt_regions()[0].writable && child.virt_regions()[0].cow);
+
+    // Without a free page to copy to, the fault fails and the page stays shared.
+    let blocks = take_free_pages();
+    assert_eq!(parent.handle_cow_fault(start), Err(MapError::OutOfMemory));
+    // So do a fork and the kernel stack of the forked task, which sys_fork() reports as ENOMEM.
+    assert_eq!(parent.fork().err(), Some(MapError::OutOfMemory));
+    assert_eq!(KernelStack::new().err(), Some(MapError::OutOfMemory));
+    give_back_pages(blocks);
+    assert_eq!(unsafe { page_meta::page_refcount(page) }, 2);
+
+    // Simulate the write faults the page fault handler resolves. The first writer gets a copy.
+    let before = buddy::allocated_pages();
+    assert_eq!(parent.handle_cow_fault(start), Ok(true));
+    assert_eq!(buddy::allocated_pages(), before + 1);
+    assert_ne!(
+        parent.resolve_virt_addr(start),
+        child.resolve_virt_addr(start)
+    );
+    assert_eq!(unsafe { page_meta::page_refcount(page) }, 1);
+
+    // The other side is the only user of the original page now, so it is made writable without copying.
+    assert_eq!(child.handle_cow_fault(start), Ok(true));
+    assert_eq!(buddy::allocated_pages(), before + 1);
+    assert_eq!(child.resolve_virt_addr(start), Some(v2p(page as usize)));
+    assert_eq!(child.handle_cow_fault(start), Ok(false));
+
+    // Both write the same variable, and each sees its own value.
+    unsafe {
+        let var = start as *mut usize;
+        parent.switch_to_this();
+        assert_eq!(var.read_volatile(), 1);
+        var.write_volatile(2);
+        child.switch_to_this();
+        assert_eq!(var.read_volatile(), 1);
+        var.write_volatile(3);
+        parent.switch_to_this();
+        assert_eq!(var.read_volatile(), 2);
+        child.switch_to_this();
+        assert_eq!(var.read_volatile(), 3);
+        KERNEL_ADDRESS_SPACE.switch_to_this();
+    }
+
+    // The page that was never written is still shared.
+    assert_eq!(
+        parent.resolve_virt_addr(start + PAGE_SIZE),
+        child.resolve_virt_addr(start + PAGE_SIZE)
+    );
+
+    // A read-only region is COW too, so making it writable after fork doesn't let writes through to the child.
+    let read_only = 0x600000;
+    parent
+        .add_virt_region(read_only, PAGE_SIZE, false, false, false)
+        .unwrap();
+    parent
+        .copy_into_region(read_only, &4usize.to_ne_bytes())
+        .unwrap();
+    let child = parent.fork().unwrap();
+    assert!(child.virt_regions().iter().all(|region| region.cow));
+
+    parent
+        .protect_region(read_only, PAGE_SIZE, true, false)
+        .unwrap();
+    assert!(!parent.check_user_range(read_only, PAGE_SIZE, true));
+    assert_eq!(parent.handle_cow_fault(read_only), Ok(true));
+    assert_ne!(
+        parent.resolve_virt_addr(read_only),
+        child.resolve_virt_addr(read_only)
+    );
+    unsafe {
+        let var = read_only as *mut usize;
+        parent.switch_to_this();
+        var.write_volatile(5);
+        child.switch_to_this();
+        assert_eq!(var.read_volatile(), 4);
+        KERNEL_ADDRESS_SPACE.switch_to_this();
+    }
+
+    printlnk!("Forked address space copied on write");
+}
+
+fn test_kernel_mappings_shared() {
+    // Address spaces created before the mapping exists.
+    let mut address_space = AddressSpace::new();
+    address_space.map_kernel_pages();
+
+    // An unused address in the upper half, far away from the direct mapping and the MMIO window.
+    let virt_addr = 0xffff_e000_0000_0000;
+    let page = unsafe { buddy::alloc_pages_panic(1) };
+
+    unsafe {
+        KERNEL_ADDRESS_SPACE.map_kernel_page(virt_addr, v2p(page as usize), MapFlags::DATA);
+        *(virt_addr as *mut usize) = 0xCAFEBABE;
+    }
+
+    // The new mapping is visible in the existing address space.
+    assert_eq!(
+        address_space.resolve_virt_addr(virt_addr),
+        Some(v2p(page as usize))
+    );
+    unsafe {
+        address_space.switch_to_this();
+        assert_eq!(*(virt_addr as *const usize), 0xCAFEBABE);
+        KERNEL_ADDRESS_SPACE.switch_to_this();
+
+        KERNEL_ADDRESS_SPACE.unmap_kernel_page(virt_addr);
+        buddy::free_pages(page, 1);
+    }
+    assert_eq!(address_space.resolve_virt_addr(virt_addr), None);
+
+    printlnk!("Kernel mappings are shared by every address space");
+}
+
+fn test_mmio() {
+    const LAPIC_BASE: usize = 0xFEE00000;
+    const LAPIC_VERSION: usize = 0x30;
+
+    unsafe {
+        let lapic = mem::map_mmio(LAPIC_BASE, PAGE_SIZE);
+        assert!(!lapic.is_null());
+
+        let resolved = KERNEL_ADDRESS_SPACE.resolve(lapic as usize).unwrap();
+        assert_eq!(resolved.phys, LAPIC_BASE);
+
+        // Integrated local APICs report a version between 0x10 and 0x15.
+        let version = (lapic.add(LAPIC_VERSION) as *const u32).read_volatile();
+        printlnk!("LAPIC version register: {:#x}", version);
+        assert!((0x10..=0x15).contains(&(version & 0xff)));
+
+        mem::unmap_mmio(lapic, PAGE_SIZE);
+        assert!(KERNEL_ADDRESS_SPACE.resolve(lapic as usize).is_none());
+    }
+
+    printlnk!("MMIO mapped correctly");
+}
+
+// Load a task from an ELF binary, printing why if it can't be loaded.
+fn load_task(binary: &[u8]) -> Task {
+    match ElfParser::parse(binary).and_then(|parser| Task::create_task_from_elf(&parser)) {
+        Ok(task) => task,
+        Err(err) => panic!("Failed to load task: {}", err),
+    }
+}
+
+fn test_task_reaping() {
+    let run_tasks = || unsafe {
+        for _ in 0..10 {
+            sched::add_new_task(TaskRef::new(load_task(ELF_BINARY)));
+        }
+        sched::begin_scheduler();
+    };
+
+    // Run once first, so the scheduler's queues and the slab caches have already grown
+    run_tasks();
+
+    // Every exited task should be reaped, together with its address space and kernel stack
+    let before = buddy::allocated_pages();
+    run_tasks();
+    assert!(unsafe { sched::ZOMBIE_TASKS.is_empty() });
+    assert_eq!(buddy::allocated_pages(), before);
+
+    printlnk!("Task reaping test passed");
+}
+
+// Which kernel task ran, in order.
+static mut PING_PONG: Vec<&str> = Vec::new();
+
+fn ping() -> ! {
+    for _ in 0..3 {
+        printlnk!("ping");
+        unsafe { PING_PONG.push("ping") };
+        unsafe { sched::yield_task() };
+    }
+    unsafe { sched::exit_current(0) }
+}
+
+fn pong() -> ! {
+    for _ in 0..3 {
+        printlnk!("pong");
+        unsafe { PING_PONG.push("pong") };
+        unsafe { sched::yield_task() };
+    }
+    unsafe { sched::exit_current(0) }
+}
+
+fn test_kernel_tasks() {
+    let task = Task::create_kernel_task(ping, "ping");
+    assert_eq!(task.name(), "ping");
+    assert_eq!(task.addr_space.p4_table(), unsafe {
+        KERNEL_ADDRESS_SPACE.p4_table()
+    });
+
+    // The two tasks yield to each other, so their output alternates
+    unsafe {
+        sched::add_new_task(TaskRef::new(task));
+        sched::add_new_task(TaskRef::new(Task::create_kernel_task(pong, "pong")));
+        sched::begin_scheduler();
+
+        assert_eq!(PING_PONG, ["ping", "pong", "ping", "pong", "ping", "pong"]);
+    }
+
+    // A kernel task can borrow itself as the current task
+    let task = Task::create_kernel_task(record_current_task, "current");
+    let id = task.id;
+    unsafe {
+        sched::add_new_task(TaskRef::new(task));
+        sched::begin_scheduler();
+
+        assert_eq!(CURRENT_TASK_SEEN, Some((id, String::from("current"))));
+    }
+
+    printlnk!("Kernel task test passed");
+}
+
+static mut CURRENT_TASK_SEEN: Option<(usize, String)> = None;
+
+fn record_current_task() -> ! {
+    let seen = sched::with_current_task(|task| (task.id, String::from(task.name())));
+    assert_eq!(seen.0, sched::current_pid());
+    unsafe {
+        CURRENT_TASK_SEEN = Some(seen);
+        sched::exit_current(0)
+    }
+}
+
+fn exit_right_away() -> ! {
+    unsafe { sched::exit_current(0) }
+}
+
+fn test_task_refs() {
+    let live = unsafe { task_ref::LIVE_TASKS };
+
+    let task = TaskRef::new(Task::create_kernel_task(exit_right_away, "exit"));
+    let other = task.clone_ref();
+    assert!(other.ptr_eq(&task));
+    assert_eq!(task.ref_count(), 2);
+    other.drop_ref();
+    assert_eq!(task.ref_count(), 1);
+    assert_eq!(unsafe { task_ref::LIVE_TASKS }, live + 1);
+    task.drop_ref();
+    assert_eq!(unsafe { task_ref::LIVE_TASKS }, live);
+
+    // Rapidly create, run and exit tasks. The debug assertions in TaskRef catch a task freed too early.
+    for round in 0..20 {
+        unsafe {
+            for _ in 0..5 {
+                sched::add_new_task(TaskRef::new(Task::create_kernel_task(
+                    exit_right_away,
+                    "exit",
+                )));
+            }
+            if round % 5 == 0 {
+                sched::add_new_task(TaskRef::new(load_task(ELF_BINARY)));
+            }
+            sched::begin_scheduler();
+        }
+        assert_eq!(unsafe { task_ref::LIVE_TASKS }, live);
+    }
+
+    printlnk!("Task ref test passed");
+}
+
+fn test_task_names() {
+    // Kernel tasks are named by their creator, and the name is seen from inside the task
+    let task = Task::create_kernel_task(record_current_task, "stress");
+    let id = task.id;
+    unsafe {
+        sched::add_new_task(TaskRef::new(task));
+        sched::begin_scheduler();
+
+        assert_eq!(CURRENT_TASK_SEEN, Some((id, String::from("stress"))));
+    }
+
+    // User tasks are named after their program
+    let mut task = Task::spawn(ARGS_BINARY, &["args", "echo this"]).unwrap();
+    assert_eq!(task.name(), "args");
+    task.set_name("renamed");
+    assert_eq!(task.name(), "renamed");
+
+    // Long names are truncated to TASK_NAME_LEN bytes, without splitting a character
+    assert_eq!(task_name("a-task-with-a-long-name"), *b"a-task-with-a-lo");
+    task.set_name("a\u{3b1}\u{3b1}\u{3b1}\u{3b1}\u{3b1}\u{3b1}\u{3b1}\u{3b1}");
+    assert_eq!(
+        task.name(),
+        "a\u{3b1}\u{3b1}\u{3b1}\u{3b1}\u{3b1}\u{3b1}\u{3b1}"
+    );
+    task.set_name("");
+    assert_eq!(task.name(), "");
+
+    printlnk!("Task name test passed");
+}
+
+fn test_cpu_placement() {
+    // Only the BSP runs tasks, so every task is placed on it, even if its mask doesn't allow it
+    assert_eq!(cpu::online_cpus(), 1);
+    assert_eq!(cpu::select_cpu(ALL_CPUS), 0);
+    assert_eq!(cpu::select_cpu(0b10), 0);
+    assert!(!unsafe { cpu::steal_work(0) });
+
+    let pinned_task = |affinity| {
+        let mut task = Task::create_kernel_task(exit_right_away, "pinned");
+        task.cpu_affinity = affinity;
+        TaskRef::new(task)
+    };
+
+    unsafe {
+        // Pretend a second CPU is online. Nothing runs on it, so its queues are only looked at.
+        cpu::set_online_cpus(2);
+
+        // Tasks go to the least loaded CPU they are allowed to run on
+        let any = pinned_task(ALL_CPUS);
+        assert_eq!(cpu::select_cpu(ALL_CPUS), 0);
+        CPUS[0].ready_tasks[1].push_back(any.clone_ref());
+        assert_eq!(cpu::select_cpu(ALL_CPUS), 1);
+        assert_eq!(cpu::select_cpu(0b01), 0);
+        assert_eq!(cpu::select_cpu(0b10), 1);
+
+        // The idle CPU only steals tasks that are allowed to run on it
+        for _ in 0..3 {
+            let task = pinned_task(0b01);
+            assert_eq!(cpu::select_cpu((*task.get()).cpu_affinity), 0);
+            CPUS[0].ready_tasks[1].push_back(task);
+        }
+        assert!(cpu::steal_work(1));
+        assert!(CPUS[1].ready_tasks[1][0].ptr_eq(&any));
+        assert_eq!(CPUS[0].load(), 3);
+        assert!(!cpu::steal_work(1));
+
+        for state in &mut CPUS[..2] {
+            for queue in state.ready_tasks.iter_mut() {
+                while let Some(task) = queue.pop_front() {
+                    task.drop_ref();
+                }
+            }
+        }
+        cpu::set_online_cpus(1);
+        any.drop_ref();
+    }
+
+    printlnk!("CPU placement test passed");
+}
+
+fn test_preemption() {
+    // Both tasks spin without ever yielding, so their rounds only interleave if the timer preempts them
+    let before = unsafe { sched::PREEMPTIONS };
+    unsafe {
+        for label in ["A", "B"] {
+            let task = Task::spawn(SPIN_BINARY, &["spin", label]).unwrap();
+            sched::add_new_task(TaskRef::new(task));
+        }
+        sched::begin_scheduler();
+    }
+    assert!(unsafe { sched::PREEMPTIONS } > before);
+
+    printlnk!("Preemption test passed");
+}
+
+fn test_time_slices() {
+    assert_eq!(
+        cmdline::find_option("quiet timeslice=10", "timeslice"),
+        Some("10")
+    );
+    assert_eq!(
+        cmdline::find_option("quiet timeslice=10", "quiet"),
+        Some("")
+    );
+    assert_eq!(
+        cmdline::find_option("timeslice=1 timeslice=2", "timeslice"),
+        Some("2")
+    );
+    assert_eq!(cmdline::find_option("timeslices=1", "timeslice"), None);
+
+    assert_eq!(
+        sched::parse_time_slices("10"),
+        Some([10; sched::PRIORITY_LEVELS])
+    );
+    assert_eq!(sched::parse_time_slices("8,4,2,1"), Some([8, 4, 2, 1]));
+    assert_eq!(sched::parse_time_slices("0"), None);
+    assert_eq!(sched::parse_time_slices("8,4,2"), None);
+    assert_eq!(sched::parse_time_slices("8,4,2,1,1"), None);
+    assert_eq!(sched::parse_time_slices("8,x,2,1"), None);
+
+    // With 50 tick slices, the spinners run in long stretches, and are preempted at most once per slice
+    let old_slices = core::array::from_fn(sched::time_slice);
+    sched::set_time_slices([50; sched::PRIORITY_LEVELS]);
+    let before = unsafe { sched::PREEMPTIONS };
+    let start = time::ticks();
+    unsafe {
+        for label in ["A", "B"] {
+            let task = Task::spawn(SPIN_BINARY, &["spin", label]).unwrap();
+            sched::add_new_task(TaskRef::new(task));
+        }
+        sched::begin_scheduler();
+    }
+    let preemptions = unsafe { sched::PREEMPTIONS } - before;
+    assert!(preemptions <= (time::ticks() - start) / 50 + 1);
+    sched::set_time_slices(old_slices);
+
+    printlnk!("Time slice test passed");
+}
+
+fn test_sleep() {
+    // The current tick doesn't count, and the deadline saturates instead of overflowing
+    assert_eq!(sched::wake_tick_after(100, 0), 101);
+    assert_eq!(sched::wake_tick_after(100, 1), 102);
+    assert_eq!(sched::wake_tick_after(100, 10 * time::MS_PER_TICK), 111);
+    assert_eq!(
+        sched::wake_tick_after(usize::MAX - 1, usize::MAX),
+        usize::MAX
+    );
+
+    // Both tasks sleep for 100 ms at the same time, while the scheduler waits for them
+    let start = time::ticks();
+    unsafe {
+        for _ in 0..2 {
+            sched::add_new_task(TaskRef::new(load_task(SLEEP_BINARY)));
+        }
+        sched::begin_scheduler();
+        assert!(sched::SLEEPING_TASKS.is_empty());
+    }
+    assert!(time::ticks() - start >= 100 / time::MS_PER_TICK);
+
+    printlnk!("Sleep test passed");
+}
+
+fn test_idle_task() {
+    // While all tasks sleep at the same time, the idle task halts the CPU until the timer wakes them up
+    let halts = unsafe { sched::IDLE_HALTS };
+    unsafe {
+        for _ in 0..3 {
+            sched::add_new_task(TaskRef::new(load_task(SLEEP_BINARY)));
+        }
+        sched::begin_scheduler();
+        assert!(sched::CURRENT_TASK.is_none());
+        assert!(sched::SLEEPING_TASKS.is_empty());
+        assert!(sched::IDLE_HALTS > halts);
+    }
+
+    printlnk!("Idle task test passed");
+}
+
+fn test_priorities() {
+    assert!(sched::is_valid_priority(0));
+    assert!(sched::is_valid_priority(sched::PRIORITY_LEVELS - 1));
+    assert!(!sched::is_valid_priority(sched::PRIORITY_LEVELS));
+
+    // The latency task raises itself to the highest priority, and prints how long its short sleeps take while a
+    // low priority task spins. It exits with code 1 if any of them took much longer than the others.
+    let mut spinner = Task::spawn(SPIN_BINARY, &["spin", "L"]).unwrap();
+    spinner.priority = 0;
+    let latency = load_task(LATENCY_BINARY);
+    assert_eq!(latency.priority, sched::DEFAULT_PRIORITY);
+
+    let before = unsafe { sched::PREEMPTIONS };
+    unsafe {
+        sched::add_new_task(TaskRef::new(spinner));
+        sched::add_new_task(TaskRef::new(latency));
+        sched::begin_scheduler();
+    }
+    // The spinner is preempted when the latency task wakes up
+    assert!(unsafe { sched::PREEMPTIONS } > before);
+
+    printlnk!("Priority test passed");
+}
+
+fn test_wait_queue() {
+    let queue = WaitQueue::new();
+    assert!(queue.is_empty());
+    assert!(!queue.wake_one());
+    assert_eq!(queue.wake_all(), 0);
+
+    // Two tasks block on the tick queue ten times each, and are woken up by the timer interrupt
+    let start = time::ticks();
+    unsafe {
+        for _ in 0..2 {
+            sched::add_new_task(TaskRef::new(load_task(TICK_BINARY)));
+        }
+        sched::begin_scheduler();
+
+        assert!(time::TICK_WAITERS.is_empty());
+        assert_eq!(sched::BLOCKED_TASKS, 0);
+    }
+    assert!(time::ticks() - start >= 11);
+
+    printlnk!("Wait queue test passed");
+}
+
+static mut CHILD_PROGRAM: &str = "";
+static mut CHILD_SETUP: fn(&mut Task) = |_| {};
+static mut CHILD_EXIT_CODE: Option<i32> = None;
+
+// Spawn CHILD_PROGRAM as a child of this kernel task, pass it to CHILD_SETUP, and keep its exit code.
+fn run_child_program() -> ! {
+    unsafe {
+        let name = CHILD_PROGRAM;
+        let mut task = Task::spawn(programs::find(name).unwrap(), &[name]).unwrap();
+        task.parent = sched::current_pid();
+        CHILD_SETUP(&mut task);
+        let id = task.id;
+        sched::add_new_task(TaskRef::new(task));
+
+        CHILD_EXIT_CODE = sched::wait_child(id).ok();
+        // The exit code is only taken once
+        assert_eq!(sched::wait_child(id), Err(Errno::ECHILD));
+
+        sched::exit_current(0)
+    }
+}
+
+// Run the registered program as a child of a kernel task, and return its exit code.
+fn run_as_child(name: &'static str) -> Option<i32> {
+    run_as_child_with(name, |_| {})
+}
+
+// Same, but let setup change the task before it first runs.
+fn run_as_child_with(name: &'static str, setup: fn(&mut Task)) -> Option<i32> {
+    unsafe {
+        CHILD_PROGRAM = name;
+        CHILD_SETUP = setup;
+        CHILD_EXIT_CODE = None;
+        sched::add_new_task(TaskRef::new(Task::create_kernel_task(
+            run_child_program,
+            "run_child",
+        )));
+        sched::begin_scheduler();
+
+        CHILD_EXIT_CODE
+    }
+}
+
+fn test_wait() {
+    programs::register("exit42", EXIT42_BINARY);
+    programs::register("wait", WAIT_BINARY);
+    assert!(programs::find("exit42").is_some());
+    assert!(programs::find("missing").is_none());
+
+    assert_eq!(run_as_child("wait"), Some(0));
+    unsafe {
+        // Every exit code was taken or dropped with its parent
+        assert!(sched::EXIT_STATUSES.is_empty());
+        assert!(sched::TASK_TABLE.is_empty());
+    }
+
+    printlnk!("Wait test passed");
+}
+
+fn test_fork() {
+    programs::register("fork", FORK_BINARY);
+
+    // The parent and the child both check that they see only their own write after the fork
+    assert_eq!(run_as_child("fork"), Some(0));
+    unsafe {
+        assert!(sched::EXIT_STATUSES.is_empty());
+        assert!(sched::TASK_TABLE.is_empty());
+    }
+
+    // Run again now that the scheduler's queues and the slab caches have grown. Every page the two address spaces
+    // shared or copied should be freed.
+    let before = buddy::allocated_pages();
+    assert_eq!(run_as_child("fork"), Some(0));
+    assert_eq!(buddy::allocated_pages(), before);
+
+    printlnk!("Fork test passed");
+}
+
+fn test_exec() {
+    programs::register("exec", EXEC_BINARY);
+    programs::register("args", ARGS_BINARY);
+
+    // The launcher execs args, which exits with 0 under the id the launcher was spawned with. The launcher itself
+    // exits with 1 if a failed exec did not return, or the last one did.
+    assert_eq!(run_as_child("exec"), Some(0));
+    unsafe {
+        assert!(sched::EXIT_STATUSES.is_empty());
+        assert!(sched::TASK_TABLE.is_empty());
+    }
+
+    printlnk!("Exec test passed");
+}
+
+fn test_fpu() {
+    let state = FpuState::new();
+    assert_eq!(state.fcw(), fpu::DEFAULT_FCW);
+    assert_eq!(state.mxcsr(), fpu::DEFAULT_MXCSR);
+    assert_eq!(align_of::<FpuState>(), 16);
+
+    // A saved state loads back unchanged
+    state.restore();
+    assert_eq!(FpuState::current().mxcsr(), fpu::DEFAULT_MXCSR);
+
+    // Two tasks keep accumulators in the same xmm register while they preempt each other
+    programs::register("fpu", FPU_BINARY);
+    let before = unsafe { sched::PREEMPTIONS };
+    assert_eq!(run_as_child("fpu"), Some(0));
+    assert!(unsafe { sched::PREEMPTIONS } > before);
+
+    printlnk!("FPU test passed");
+}
+
+static mut TASK_TABLE_DUMP: String = String::new();
+
+fn dump_own_stats() -> ! {
+    unsafe {
+        stats::format_tasks(&mut TASK_TABLE_DUMP).unwrap();
+        sched::dump_tasks();
+        sched::exit_current(0)
+    }
+}
+
+fn test_task_stats() {
+    let mut task_stats = TaskStats::default();
+    task_stats.switch_in(100);
+    task_stats.switch_out(250);
+    task_stats.switch_in(300);
+    task_stats.preemptions += 1;
+    task_stats.switch_out(310);
+    assert_eq!(task_stats.runtime_cycles, 160);
+    assert_eq!(task_stats.switches, 2);
+    assert_eq!(task_stats.voluntary_switches(), 1);
+    assert_eq!(size_of::<stats::TaskInfo>(), 56);
+
+    // A task sees itself running in the task table
+    unsafe {
+        sched::add_new_task(TaskRef::new(Task::create_kernel_task(
+            dump_own_stats,
+            "dump_own_stats",
+        )));
+        sched::begin_scheduler();
+
+        let line = TASK_TABLE_DUMP
+            .lines()
+            .find(|line| line.contains("dump_own_stats"))
+            .unwrap();
+        assert!(line.contains("running"));
+    }
+
+    // Same for a user task, through sys_task_stats
+    programs::register("ps", PS_BINARY);
+    assert_eq!(run_as_child("ps"), Some(0));
+
+    printlnk!("Task stats test passed");
+}
+
+fn test_syscall_dispatch() {
+    // Every syscall number has a handler, and anything past them fails with ENOSYS
+    assert!(dispatch::SYSCALLS.iter().all(Option::is_some));
+    let mut args = SyscallArgs {
+        num: nr::SYSCALL_COUNT,
+        ..Default::default()
+    };
+    assert_eq!(dispatch::dispatch(&mut args), -38);
+    args.num = usize::MAX;
+    assert_eq!(dispatch::dispatch(&mut args), -38);
+
+    // The same from user mode, where the error comes back in rax
+    programs::register("nosys", NOSYS_BINARY);
+    assert_eq!(run_as_child("nosys"), Some(0));
+
+    printlnk!("Syscall dispatch test passed");
+}
+
+fn test_errno() {
+    // Errors are negated error numbers, and results are passed through
+    assert_eq!(errno::encode(Err(Errno::EFAULT)), -14);
+    assert_eq!(errno::encode(Err(Errno::ENOSYS)), -38);
+    assert_eq!(errno::encode(Ok(5)), 5);
+    assert_eq!(errno::encode(Ok(0)), 0);
+
+    // Decoding gives back what was encoded, and nothing for numbers that aren't errors of ours
+    assert_eq!(errno::decode(-4), Some(Err(Errno::EINTR)));
+    assert_eq!(errno::decode(-110), Some(Err(Errno::ETIMEDOUT)));
+    assert_eq!(errno::decode(5), Some(Ok(5)));
+    assert_eq!(errno::decode(-4000), None);
+
+    // getpid and getppid across a fork, and -EFAULT from a bad write
+    programs::register("ids", IDS_BINARY);
+    assert_eq!(run_as_child("ids"), Some(0));
+
+    printlnk!("Errno test passed");
+}
+
+fn test_strace() {
+    // strace runs hello traced, then traces a few of its own syscalls
+    programs::register("hello", HELLO_BINARY);
+    programs::register("strace", STRACE_BINARY);
+    unsafe { trace::TRACE_LOG = Some(Vec::new()) };
+    let code = run_as_child("strace");
+    let log = unsafe { trace::TRACE_LOG.take().unwrap() };
+    assert_eq!(code, Some(0));
+
+    let expected = [
+        "(hello): write(1, \"hello from ring 3\\n\", 18) = 18",
+        "(hello): write(0, \"hello from ring 3\\n\", 18) = -EBADF",
+        "(hello): write(1, 0x10, 4) = -EFAULT",
+        "(hello): write(1, 0xffff800000000000, 4) = -EFAULT",
+        "(hello): write(2, 0x800000000000, 4) = -EFAULT",
+        "(hello): write(1, \"\", 0) = 0",
+        "(hello): exit(0) = ?",
+        "(strace): trace_me(1) = 0",
+        "(strace): getpid() = ",
+        "(strace): trace_me(0) = 0",
+    ];
+    assert_eq!(log.len(), expected.len(), "{:#?}", log);
+    for (line, expected) in log.iter().zip(expected) {
+        assert!(line.starts_with("task "), "{}", line);
+        assert!(
+            line.contains(expected),
+            "{:?} doesn't contain {:?}",
+            line,
+            expected
+        );
+    }
+
+    printlnk!("Strace test passed");
+}
+
+fn test_syscall_storm() {
+    // syscall_entry() finds its scratch space through the kernel GS base
+    assert_eq!(
+        read_msr(IA32_KERNEL_GS_BASE),
+        syscall::this_scratch() as u64
+    );
+
+    // Four tasks make syscalls as fast as they can, while the timer preempts them
+    programs::register("storm", STORM_BINARY);
+    let start = time::ticks();
+    assert_eq!(run_as_child("storm"), Some(0));
+    assert!(time::ticks() - start >= 2);
+
+    printlnk!("Syscall storm test passed");
+}
+
+fn test_segment_base() {
+    assert!(segment_base::is_canonical(0x0000_7fff_ffff_f000));
+    assert!(segment_base::is_canonical(0xffff_8000_0000_0000));
+    assert!(!segment_base::is_canonical(0x0000_8000_0000_0000));
+    assert!(!segment_base::is_user_base(0xffff_8000_0000_0000));
+
+    // The kernel doesn't use FS or GS, so their bases can be changed and put back
+    without_interrupt(|| {
+        let (fs_base, gs_base) = (segment_base::read_fs_base(), segment_base::read_gs_base());
+        segment_base::write_fs_base(0x1234_5000);
+        segment_base::write_gs_base(0x5678_9000);
+        assert_eq!(segment_base::read_fs_base(), 0x1234_5000);
+        assert_eq!(segment_base::read_gs_base(), 0x5678_9000);
+        assert_eq!(read_msr(IA32_FS_BASE), 0x1234_5000);
+        segment_base::write_fs_base(fs_base);
+        segment_base::write_gs_base(gs_base);
+    });
+
+    // swapgs comes in pairs, so after all the syscalls so far the scratch space is still the kernel GS base
+    let scratch = syscall::this_scratch() as u64;
+    assert_eq!(segment_base::read_kernel_gs_base(), scratch);
+    assert_ne!(segment_base::read_gs_base(), scratch);
+
+    // Two tasks with their own fs base keep it through many preemptions
+    programs::register("fsbase", FSBASE_BINARY);
+    let start = time::ticks();
+    assert_eq!(run_as_child("fsbase"), Some(0));
+    assert!(time::ticks() - start >= 10);
+    assert_eq!(segment_base::read_kernel_gs_base(), scratch);
+
+    printlnk!(
+        "Segment base test passed (FSGSBASE {})",
+        if cpu_features().fsgsbase { "on" } else { "off" }
+    );
+}
+
+fn test_clock() {
+    // Sleeps in nanoseconds round up to whole ticks like sleeps in milliseconds
+    assert_eq!(sched::wake_tick_after_ns(100, 0), 101);
+    assert_eq!(sched::wake_tick_after_ns(100, 1), 102);
+    assert_eq!(sched::wake_tick_after_ns(100, 10 * time::NS_PER_TICK), 111);
+    assert_eq!(
+        sched::wake_tick_after_ns(100, 10 * time::NS_PER_TICK + 1),
+        112
+    );
+    assert_eq!(
+        sched::wake_tick_after_ns(usize::MAX - 1, u64::MAX),
+        usize::MAX
+    );
+
+    let time = Timespec::from_nanos(3 * Timespec::NANOS_PER_SEC + 5);
+    assert_eq!((time.secs, time.nanos), (3, 5));
+    assert_eq!(time.as_nanos(), 3 * Timespec::NANOS_PER_SEC + 5);
+
+    // The monotonic clock follows the ticks, and never goes back
+    let start = time::monotonic_ns();
+    let start_tick = time::ticks();
+    while time::ticks() < start_tick + 3 {
+        let now = time::monotonic_ns();
+        assert!(now >= start);
+    }
+    let elapsed = time::monotonic_ns() - start;
+    assert!((2 * time::NS_PER_TICK..=4 * time::NS_PER_TICK).contains(&elapsed));
+
+    // A user task measures a 100 ms sleep
+    programs::register("clock", CLOCK_BINARY);
+    assert_eq!(run_as_child("clock"), Some(0));
+
+    printlnk!("Clock test passed");
+}
+
+fn test_delay() {
+    // The ticks keep up with a busy-wait on the PIT count
+    let start_tick = time::ticks();
+    let cycles = tsc::measure_cycles(|| pit::delay_ms(100));
+    let ticks = time::ticks() - start_tick;
+    let expected = 100 / time::MS_PER_TICK;
+    assert!((expected - 1..=expected + 1).contains(&ticks));
+
+    // The TSC agrees, and keeps counting with interrupts disabled, when the ticks stop
+    let ms = time::cycles_to_ms(cycles);
+    assert!((90..=110).contains(&ms), "delay_ms(100) took {} ms", ms);
+    let start_tick = time::ticks();
+    let cycles = tsc::measure_cycles(|| without_interrupt(|| pit::delay_ms(50)));
+    let ms = time::cycles_to_ms(cycles);
+    assert!((45..=55).contains(&ms), "delay_ms(50) took {} ms", ms);
+    assert!(time::ticks() <= start_tick + 1);
+
+    printlnk!("Delay test passed");
+}
+
+static mut TEST_TICK_CALLBACKS: u64 = 0;
+
+unsafe fn test_tick_callback() {
+    unsafe { TEST_TICK_CALLBACKS += 1 };
+}
+
+fn test_pit() {
+    // The PIT ticks at TIMER_HZ, and only raises IRQ 0 while it drives the tick
+    assert_eq!(pit::frequency(), time::TIMER_HZ);
+    let irq_0_masked = unsafe { idt::PICS.read_masks() }[0] & 1 != 0;
+    assert_eq!(irq_0_masked, lapic::enabled());
+
+    // Over 100 ms measured with the TSC, the tick count goes up by about 100 ms worth, and a registered callback runs
+    // once a tick
+    pit::register_tick_callback(test_tick_callback).unwrap();
+    let per_ms = time::tsc_per_ms().unwrap();
+    let (start_tick, start_calls) = (pit::ticks(), unsafe { TEST_TICK_CALLBACKS });
+    let end = tsc::rdtsc() + 100 * per_ms;
+    while tsc::rdtsc() < end {
+        core::hint::spin_loop();
+    }
+    let (ticks, calls) = without_interrupt(|| {
+        (
+            pit::ticks() - start_tick,
+            unsafe { TEST_TICK_CALLBACKS } - start_calls,
+        )
+    });
+    let expected = (100 / time::MS_PER_TICK) as u64;
+    assert!(
+        (expected - 1..=expected + 1).contains(&ticks),
+        "{} ticks in 100 ms",
+        ticks
+    );
+    assert_eq!(calls, ticks);
+
+    // Once unregistered, it stops running
+    pit::unregister_tick_callback(test_tick_callback).unwrap();
+    assert_eq!(
+        pit::unregister_tick_callback(test_tick_callback),
+        Err(pit::PitError::NotRegistered)
+    );
+    let calls = unsafe { TEST_TICK_CALLBACKS };
+    pit::delay_ms(3 * time::MS_PER_TICK);
+    assert_eq!(unsafe { TEST_TICK_CALLBACKS }, calls);
+
+    // The table of callbacks fills up
+    let registered = (0..pit::MAX_TICK_CALLBACKS)
+        .take_while(|_| pit::register_tick_callback(test_tick_callback).is_ok())
+        .count();
+    assert!(registered < pit::MAX_TICK_CALLBACKS);
+    assert_eq!(
+        pit::register_tick_callback(test_tick_callback),
+        Err(pit::PitError::TooManyCallbacks)
+    );
+    for _ in 0..registered {
+        pit::unregister_tick_callback(test_tick_callback).unwrap();
+    }
+
+    printlnk!("PIT test passed");
+}
+
+fn test_tsc() {
+    // Back-to-back reads never go back, with or without fences
+    let mut last = tsc::rdtsc();
+    for _ in 0..4096 {
+        let now = tsc::rdtsc();
+        assert!(now >= last, "TSC went back from {} to {}", last, now);
+        last = now;
+    }
+    for _ in 0..4096 {
+        let now = tsc::serialized_rdtsc();
+        assert!(now >= last, "TSC went back from {} to {}", last, now);
+        last = now;
+    }
+
+    // RDTSCP counts with the others, and tells which CPU it ran on
+    let features = cpu_features();
+    if features.rdtscp {
+        for _ in 0..4096 {
+            let (now, aux) = tsc::rdtscp();
+            assert!(now >= last, "TSC went back from {} to {}", last, now);
+            assert_eq!(aux as usize, cpu::this_cpu_id());
+            last = now;
+        }
+    }
+
+    // A measurement counts what it measures
+    let cycles = tsc::measure_cycles(|| pit::delay_ms(10));
+    assert!((5..=15).contains(&time::cycles_to_ms(cycles)));
+    assert!(tsc::measure_cycles(|| {}) < cycles);
+
+    printlnk!(
+        "TSC test passed (RDTSCP {}, invariant TSC {})",
+        if features.rdtscp { "on" } else { "off" },
+        if features.invariant_tsc { "yes" } else { "no" }
+    );
+}
+
+fn test_init() {
+    // init spawns exit42, args (with arguments) and hello, and waits for all of them
+    programs::register("init", INIT_BINARY);
+    programs::register("exit42", EXIT42_BINARY);
+    programs::register("args", ARGS_BINARY);
+    programs::register("hello", HELLO_BINARY);
+    programs::register("notelf", b"not an executable");
+    assert_eq!(run_as_child("init"), Some(0));
+    unsafe {
+        assert!(sched::EXIT_STATUSES.is_empty());
+        assert!(sched::TASK_TABLE.is_empty());
+    }
+
+    printlnk!("Init test passed");
+}
+
+fn test_framebuffer() {
+    // Draws a gradient over the whole framebuffer, if there is one
+    programs::register("gradient", GRADIENT_BINARY);
+    assert_eq!(run_as_child("gradient"), Some(0));
+
+    if let Some(info) = output::with_framebuffer(|framebuffer| framebuffer.info()) {
+        printlnk!(
+            "Framebuffer: {}x{}, {} bytes per pixel",
+            info.width,
+            info.height,
+            info.bytes_per_pixel
+        );
+    }
+
+    printlnk!("Framebuffer test passed");
+}
+
+// Pixels of the framebuffer test_framebuffer_console() draws to, 4 bytes each.
+const TEST_FRAMEBUFFER_WIDTH: usize = 160;
+const TEST_FRAMEBUFFER_HEIGHT: usize = 200;
+static mut TEST_FRAMEBUFFER: [u8; TEST_FRAMEBUFFER_WIDTH * TEST_FRAMEBUFFER_HEIGHT * 4] =
+    [0; TEST_FRAMEBUFFER_WIDTH * TEST_FRAMEBUFFER_HEIGHT * 4];
+
+// Numbered lines, written at once so the console only copies the back buffer to the screen once.
+fn numbered_lines(prefix: &str, count: usize) -> String {
+    (0..count).map(|i| format!("{} {}\n", prefix, i)).collect()
+}
+
+fn test_framebuffer_console() {
+    let info = FrameBufferInfo {
+        byte_len: TEST_FRAMEBUFFER_WIDTH * TEST_FRAMEBUFFER_HEIGHT * 4,
+        width: TEST_FRAMEBUFFER_WIDTH,
+        height: TEST_FRAMEBUFFER_HEIGHT,
+        pixel_format: PixelFormat::Bgr,
+        bytes_per_pixel: 4,
+        stride: TEST_FRAMEBUFFER_WIDTH,
+    };
+    let pixels = &raw mut TEST_FRAMEBUFFER;
+    let mut console = FrameBufferWriter::new(unsafe { &mut *pixels }, info, Font::Noto);
+    assert_eq!(console.row_text(0), None);
+    assert!(console.enable_back_buffer());
+    let (rows, columns) = (console.rows(), console.columns());
+    assert!(rows >= 4 && columns > 8);
+
+    // After a few hundred lines, the last ones fill the screen, above the empty row of the cursor
+    fmt::Write::write_str(&mut console, &numbered_lines("line", 300)).unwrap();
+    assert_eq!(console.cursor(), (rows - 1, 0));
+    for row in 0..rows - 1 {
+        let expected = format!("line {}", 300 - (rows - 1) + row);
+        assert_eq!(console.row_text(row).unwrap(), expected);
+    }
+    assert_eq!(console.row_text(rows - 1).unwrap(), "");
+    // and every changed row reached the framebuffer
+    assert!(console.back_buffer().unwrap() == unsafe { &*pixels });
+
+    // Carriage returns overwrite, tabs stop every 8 columns, and backspaces erase
+    console.clear();
+    fmt::Write::write_str(&mut console, "abc\rX\na\tb\nab\x08c\n\x08").unwrap();
+    assert_eq!(console.row_text(0).unwrap(), "Xbc");
+    assert_eq!(console.row_text(1).unwrap(), "a       b");
+    assert_eq!(console.row_text(2).unwrap(), "ac");
+    assert_eq!(console.cursor(), (3, 0));
+
+    // Long lines wrap, but a line that fills the row exactly doesn't leave an empty one
+    console.clear();
+    let long = "x".repeat(columns + 3);
+    fmt::Write::write_str(&mut console, &long).unwrap();
+    assert_eq!(console.row_text(0).unwrap(), long[..columns]);
+    assert_eq!(console.row_text(1).unwrap(), "xxx");
+    assert_eq!(console.cursor(), (1, 3));
+    console.clear();
+    fmt::Write::write_str(&mut console, &format!("{}\nnext", "y".repeat(columns))).unwrap();
+    assert_eq!(console.row_text(1).unwrap(), "next");
+
+    // The same on the screen, to look at
+    output::with_framebuffer(|framebuffer| {
+        fmt::Write::write_str(framebuffer, &numbered_lines("Console line", 300)).unwrap();
+        if let Some(text) = framebuffer.row_text(framebuffer.rows() - 2) {
+            assert_eq!(text, "Console line 299");
+        }
+    });
+
+    printlnk!("Framebuffer console test passed");
+}
+
+// A PSF2 font of four 10x4 glyphs, for '?', 'A', 'é' and 'λ'. The glyph of 'é' also draws "e\u{301}", an e and a
+// combining acute accent, which can't be drawn alone.
+const TEST_PSF2_GLYPHS: [[u16; 4]; 4] = [
+    [0b0111111110, 0b0000001100, 0b0000110000, 0b0000110000],
+    [0b0001111000, 0b0110000110, 0b0111111110, 0b0110000110],
+    [0b0000011000, 0b0111111110, 0b0111000000, 0b0011111110],
+    [0b0110000000, 0b0001100000, 0b0011011000, 0b0110000110],
+];
+const TEST_PSF2_TABLE: &[u8] = b"?\xffA\xff\xc3\xa9\xfee\xcc\x81\xff\xce\xbb\xff";
+
+fn test_psf2_font() -> Vec<u8> {
+    let mut font = Vec::new();
+    for field in [
+        0x864a_b572,
+        0,
+        32,
+        1,
+        TEST_PSF2_GLYPHS.len() as u32,
+        8,
+        4,
+        10,
+    ] {
+        font.extend_from_slice(&field.to_le_bytes());
+    }
+    for row in TEST_PSF2_GLYPHS.iter().flatten() {
+        font.extend_from_slice(&(row << 6).to_be_bytes());
+    }
+    font.extend_from_slice(TEST_PSF2_TABLE);
+    font
+}
+
+// Pixels of the framebuffer test_psf_font() draws to, 4 bytes each: two rows of four chars of the PSF2 font.
+const TEST_FONT_FRAMEBUFFER_WIDTH: usize = 48;
+const TEST_FONT_FRAMEBUFFER_HEIGHT: usize = 16;
+static mut TEST_FONT_FRAMEBUFFER: [u8; TEST_FONT_FRAMEBUFFER_WIDTH
+    * TEST_FONT_FRAMEBUFFER_HEIGHT
+    * 4] = [0; TEST_FONT_FRAMEBUFFER_WIDTH * TEST_FONT_FRAMEBUFFER_HEIGHT * 4];
+// FNV-1a of the framebuffer once test_psf_font() drew its text, worked out by hand from the glyphs
+const TEST_FONT_CHECKSUM: u32 = 0xae34_75d5;
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn test_psf_font() {
+    // Whatever ELYTRA_FONT picked is a font
+    assert!(Font::embedded().is_ok());
+
+    let data = test_psf2_font();
+    let font = PsfFont::parse(&data).unwrap();
+    assert_eq!(
+        (font.width(), font.height(), font.glyph_count()),
+        (10, 4, 4)
+    );
+    assert!(font.has_unicode_table());
+    assert_eq!(font.glyph_index('A'), Some(1));
+    assert_eq!(font.glyph_index('é'), Some(2));
+    assert_eq!(font.glyph_index('λ'), Some(3));
+    // Chars that are only drawn as part of a sequence, or not at all
+    assert_eq!(font.glyph_index('e'), None);
+    assert_eq!(font.glyph_index('\u{301}'), None);
+    assert_eq!(font.glyph_index('B'), None);
+    // Which fall back to '?'
+    assert_eq!(font.glyph_or_fallback('B'), font.glyph(0).unwrap());
+    let glyph = font.glyph(2).unwrap();
+    assert!(glyph.is_set(5, 0) && !glyph.is_set(4, 0) && glyph.is_set(8, 1) && !glyph.is_set(8, 2));
+    assert_eq!(font.glyph(4), None);
+
+    assert_eq!(PsfFont::parse(b"hello").unwrap_err(), PsfError::BadMagic);
+    assert_eq!(
+        PsfFont::parse(&data[..20]).unwrap_err(),
+        PsfError::Truncated
+    );
+    assert_eq!(
+        PsfFont::parse(&data[..40]).unwrap_err(),
+        PsfError::Truncated
+    );
+    let mut bad = data.clone();
+    bad[20] = 7; // Bytes of a glyph that don't fit its size
+    assert_eq!(PsfFont::parse(&bad).unwrap_err(), PsfError::BadHeader);
+
+    // A PSF1 font without a table draws the chars with the number of each glyph, here 256 glyphs of 2 rows
+    let mut psf1 = vec![0x36, 0x04, 0, 2];
+    psf1.extend((0..=255u8).flat_map(|i| [i, !i]));
+    let font = PsfFont::parse(&psf1).unwrap();
+    assert_eq!(
+        (font.width(), font.height(), font.glyph_count()),
+        (8, 2, 256)
+    );
+    assert!(!font.has_unicode_table());
+    assert_eq!(font.glyph_index('A'), Some(0x41));
+    assert_eq!(font.glyph_index('é'), Some(0xe9));
+    assert_eq!(font.glyph_index('λ'), None);
+    assert_eq!(
+        font.glyph_or_fallback('λ'),
+        font.glyph(b'?' as usize).unwrap()
+    );
+    let glyph = font.glyph(0x41).unwrap();
+    assert!(!glyph.is_set(0, 0) && glyph.is_set(1, 0) && glyph.is_set(7, 0) && glyph.is_set(0, 1));
+
+    // With a table, glyph 1 draws 'λ', and the sequence "e\u{301}"
+    psf1[2] = 0x02;
+    for i in 0..256 {
+        let values: &[u16] = if i == 1 {
+            &[0x3bb, 0xfffe, 0x65, 0x301, 0xffff]
+        } else {
+            &[0xffff]
+        };
+        psf1.extend(values.iter().flat_map(|value| value.to_le_bytes()));
+    }
+    let font = PsfFont::parse(&psf1).unwrap();
+    assert!(font.has_unicode_table());
+    assert_eq!(font.glyph_index('λ'), Some(1));
+    assert_eq!(font.glyph_index('A'), None);
+    assert_eq!(font.glyph_index('e'), None);
+
+    // Text with chars outside ASCII, and one the font lacks, comes out the same drawn a pixel at a time, and from the
+    // atlas, the second time each char is drawn too
+    let font = Font::Psf(PsfFont::parse(Box::leak(data.into_boxed_slice())).unwrap());
+    let info = FrameBufferInfo {
+        byte_len: TEST_FONT_FRAMEBUFFER_WIDTH * TEST_FONT_FRAMEBUFFER_HEIGHT * 4,
+        width: TEST_FONT_FRAMEBUFFER_WIDTH,
+        height: TEST_FONT_FRAMEBUFFER_HEIGHT,
+        pixel_format: PixelFormat::Bgr,
+        bytes_per_pixel: 4,
+        stride: TEST_FONT_FRAMEBUFFER_WIDTH,
+    };
+    let pixels = &raw mut TEST_FONT_FRAMEBUFFER;
+    let text = "éλ→A\nAé";
+    let mut console = FrameBufferWriter::new(unsafe { &mut *pixels }, info, font);
+    assert_eq!((console.rows(), console.columns()), (2, 4));
+    fmt::Write::write_str(&mut console, text).unwrap();
+    let direct = unsafe { (*pixels).to_vec() };
+    assert_eq!(fnv1a(&direct), TEST_FONT_CHECKSUM);
+
+    let mut console = FrameBufferWriter::new(unsafe { &mut *pixels }, info, font);
+    assert!(console.enable_back_buffer());
+    fmt::Write::write_str(&mut console, text).unwrap();
+    assert_eq!(console.row_text(0).unwrap(), "éλ→A");
+    assert_eq!(console.row_text(1).unwrap(), "Aé");
+    assert_eq!(console.cursor(), (1, 2));
+    assert!(console.back_buffer().unwrap() == direct);
+    assert_eq!(fnv1a(console.back_buffer().unwrap()), TEST_FONT_CHECKSUM);
+    assert!(unsafe { &*pixels } == direct.as_slice());
+
+    printlnk!("PSF font test passed");
+}
+
+fn test_write() {
+    // Prints "hello from ring 3" once, and checks the errors of bad writes
+    programs::register("hello", HELLO_BINARY);
+    assert_eq!(run_as_child("hello"), Some(0));
+
+    printlnk!("Write test passed");
+}
+
+fn test_serial_ports() {
+    // The divisor must divide the 115200 baud clock evenly
+    assert_eq!(serial::divisor(115200), Ok(1));
+    assert_eq!(serial::divisor(38400), Ok(3));
+    assert_eq!(serial::divisor(9600), Ok(12));
+    assert_eq!(serial::divisor(7), Err(SerialError::InvalidBaud));
+    assert_eq!(serial::divisor(0), Err(SerialError::InvalidBaud));
+    assert_eq!(
+        Serial::new(serial::COM2, 0).unwrap_err(),
+        SerialError::InvalidBaud
+    );
+
+    assert_eq!(serial::parse_console("com1"), Some(serial::COM1));
+    assert_eq!(serial::parse_console("com2"), Some(serial::COM2));
+    assert_eq!(serial::parse_console("com3"), None);
+    let expected = match cmdline::option("console") {
+        Some("com2") => serial::COM2,
+        _ => serial::COM1,
+    };
+    assert_eq!(serial::console_port(), expected);
+
+    // QEMU only has COM2 when the runner passes a second -serial (--com2). Its output then reaches the terminal.
+    match Serial::new(serial::COM2, serial::DEFAULT_BAUD) {
+        Ok(mut com2) => {
+            assert_eq!(com2.port(), serial::COM2);
+            fmt::Write::write_str(&mut com2, "Hello from COM2\n").unwrap();
+            com2.flush();
+            assert!(com2.can_write());
+        }
+        Err(err) => {
+            assert_eq!(err, SerialError::Faulty);
+            printlnk!("No COM2, skipping its output");
+        }
+    }
+
+    printlnk!("Serial ports test passed");
+}
+
+fn test_debugcon() {
+    // The probe reads the port the same way, and writing never blocks, whether the device is there or not
+    assert_eq!(debugcon::present(), unsafe { inb(debugcon::PORT) } == 0xE9);
+    debugcon::print(format_args!("Debug console test\n"));
+    assert!(startup::boot_stage() != "none");
+
+    printlnk!("Debugcon test passed");
+}
+
+fn test_port_io() {
+    // The scratch register of COM1 holds whatever byte was written to it, and the driver never touches it
+    let scratch: Port<u8> = unsafe { Port::new(serial::COM1 + 7) };
+    assert_eq!(scratch.port(), 0x3FF);
+    let saved = scratch.read();
+    for value in [0x5a, 0xa5, saved] {
+        scratch.write(value);
+        io_wait();
+        assert_eq!(scratch.read(), value);
+        assert_eq!(unsafe { inb(serial::COM1 + 7) }, value);
+    }
+    unsafe { outb(serial::COM1 + 7, saved) };
+
+    // The PCI configuration address keeps the 32 bits written to it, and the data port then reads the register, here
+    // the vendor and device of the host bridge at 00:00.0, which QEMU makes an Intel one
+    let config_address: Port<u32> = unsafe { Port::new(0xCF8) };
+    let config_data: PortReadOnly<u32> = unsafe { PortReadOnly::new(0xCFC) };
+    let vendor: PortReadOnly<u16> = unsafe { PortReadOnly::new(0xCFC) };
+    without_interrupt(|| {
+        config_address.write(0x8000_0000);
+        assert_eq!(config_address.read(), 0x8000_0000);
+        assert_eq!(unsafe { inl(0xCF8) }, 0x8000_0000);
+        let id = config_data.read();
+        assert_eq!(id as u16, 0x8086);
+        assert_ne!(id >> 16, 0xFFFF);
+        assert_eq!(vendor.read(), 0x8086);
+        assert_eq!(unsafe { inw(0xCFC) }, 0x8086);
+        unsafe { outl(0xCF8, 0) };
+    });
+
+    // Writing the debug-exit port would end the run, so only its wrapper is checked
+    let exit: PortWriteOnly<u8> = unsafe { PortWriteOnly::new(0xf4) };
+    assert_eq!(exit.port(), 0xf4);
+
+    printlnk!("Port I/O test passed");
+}
+
+// Type a line into the serial console in two parts, once the echo task is waiting for it.
+fn type_serial_input() -> ! {
+    unsafe {
+        sched::sleep_current(50);
+        serial::receive(b"typed ");
+        sched::sleep_current(50);
+        serial::receive(b"line\n");
+        sched::exit_current(0)
+    }
+}
+
+fn test_read() {
+    programs::register("echo", ECHO_BINARY);
+
+    // The echo task blocks until the line is typed
+    unsafe {
+        sched::add_new_task(TaskRef::new(Task::create_kernel_task(
+            type_serial_input,
+            "type",
+        )));
+    }
+    assert_eq!(run_as_child("echo"), Some(0));
+    assert_eq!(serial::received_len(), 0);
+
+    // Input that is already there is read without blocking
+    serial::receive(b"already typed\n");
+    assert_eq!(run_as_child("echo"), Some(0));
+    assert_eq!(serial::received_len(), 0);
+
+    printlnk!("Read test passed");
+}
+
+// Id of the spinning task of test_interruptible_read(), and whether it was still running when echo exited.
+static mut SPINNER_ID: usize = 0;
+static mut SPINNER_ALIVE: bool = false;
+
+// Type a line once echo is blocked in read, then wait for echo to exit and check that the spinner is still around.
+fn type_while_spinning() -> ! {
+    unsafe {
+        while serial::RX_WAITERS.is_empty() {
+            sched::sleep_current(0);
+        }
+        serial::receive(b"typed while spinning\n");
+
+        while CHILD_EXIT_CODE.is_none() {
+            sched::sleep_current(0);
+        }
+        SPINNER_ALIVE = sched::TASK_TABLE.contains_key(&SPINNER_ID);
+        sched::exit_current(0)
+    }
+}
+
+fn test_interruptible_read() {
+    // echo sits in a blocking read while a task spins next to it. The spinner never makes a syscall that blocks, so the
+    // typing task only runs, and echo only wakes up, if the timer preempts the spinner.
+    let before = unsafe { sched::PREEMPTIONS };
+    unsafe {
+        let spinner = TaskRef::new(Task::spawn(SPIN_BINARY, &["spin", "S"]).unwrap());
+        SPINNER_ID = spinner.id();
+        SPINNER_ALIVE = false;
+        sched::add_new_task(spinner);
+        sched::add_new_task(TaskRef::new(Task::create_kernel_task(
+            type_while_spinning,
+            "type",
+        )));
+    }
+    assert_eq!(run_as_child("echo"), Some(0));
+    assert!(unsafe { SPINNER_ALIVE });
+    assert!(unsafe { sched::PREEMPTIONS } > before);
+    assert_eq!(serial::received_len(), 0);
+
+    printlnk!("Interruptible read test passed");
+}
+
+fn test_pipe() {
+    // Descriptors are handed out lowest first, and closed ones are reused
+    let mut files = FdTable::console();
+    assert_eq!(files.len(), 3);
+    assert!(matches!(files.get(0), Ok(File::ConsoleIn)));
+    assert!(matches!(files.get(3), Err(Errno::EBADF)));
+    assert_eq!(files.insert(File::ConsoleOut), Ok(3));
+    assert_eq!(files.close(1), Ok(()));
+    assert_eq!(files.close(1), Err(Errno::EBADF));
+    assert_eq!(files.insert(File::ConsoleOut), Ok(1));
+    while files.len() < fd::MAX_FDS {
+        files.insert(File::ConsoleOut).unwrap();
+    }
+    assert_eq!(files.insert(File::ConsoleOut), Err(Errno::EMFILE));
+
+    // A pipe lives as long as any descriptor refers to either of its ends
+    let pipes = unsafe { fd::LIVE_PIPES };
+    let (reader, writer) = fd::pipe();
+    let mut files = FdTable::new();
+    files.insert(File::PipeReader(reader)).unwrap();
+    files.insert(File::PipeWriter(writer)).unwrap();
+    let copy = files.clone();
+    files.close_all();
+    assert_eq!(unsafe { fd::LIVE_PIPES }, pipes + 1);
+    drop(copy);
+    assert_eq!(unsafe { fd::LIVE_PIPES }, pipes);
+
+    // A forked child sends a megabyte to its parent
+    programs::register("pipe", PIPE_BINARY);
+    assert_eq!(run_as_child("pipe"), Some(0));
+    assert_eq!(unsafe { fd::LIVE_PIPES }, pipes);
+
+    printlnk!("Pipe test passed");
+}
+
+static mut TIMED_QUEUE: WaitQueue = WaitQueue::new();
+static mut TIMED_OUT: usize = 0;
+static mut WOKEN: usize = 0;
+
+// Wait on TIMED_QUEUE for ticks timer ticks, and count how the wait ended.
+fn wait_timed(ticks: usize) {
+    unsafe {
+        if TIMED_QUEUE.wait_until(time::ticks() + ticks) {
+            WOKEN += 1;
+        } else {
+            TIMED_OUT += 1;
+        }
+    }
+}
+
+fn wait_short() -> ! {
+    wait_timed(3);
+    unsafe { sched::exit_current(0) }
+}
+
+fn wait_long() -> ! {
+    wait_timed(1000);
+    unsafe { sched::exit_current(0) }
+}
+
+// Wake a waiter once the short wait is over, which leaves only the long one in the queue.
+fn wake_timed_waiter() -> ! {
+    unsafe {
+        sched::sleep_current(100);
+        assert_eq!(TIMED_QUEUE.len(), 1);
+        assert!(TIMED_QUEUE.wake_one());
+        sched::exit_current(0)
+    }
+}
+
+fn test_timed_wait() {
+    let start = time::ticks();
+    unsafe {
+        TIMED_OUT = 0;
+        WOKEN = 0;
+        for (entry, name) in [
+            (wait_short as fn() -> !, "short"),
+            (wait_long, "long"),
+            (wake_timed_waiter, "wake"),
+        ] {
+            sched::add_new_task(TaskRef::new(Task::create_kernel_task(entry, name)));
+        }
+        sched::begin_scheduler();
+
+        // Whichever side ended the wait took the task out of the other one
+        assert_eq!((TIMED_OUT, WOKEN), (1, 1));
+        assert!(TIMED_QUEUE.is_empty());
+        assert!(sched::SLEEPING_TASKS.is_empty());
+        assert_eq!(sched::BLOCKED_TASKS, 0);
+    }
+    assert!(time::ticks() - start < 1000);
+
+    printlnk!("Timed wait test passed");
+}
+
+fn test_futex() {
+    // Two tasks count under a mutex built on futexes, in a page they share after fork
+    programs::register("mutex", MUTEX_BINARY);
+    assert_eq!(run_as_child("mutex"), Some(0));
+    unsafe {
+        assert!(sched::SLEEPING_TASKS.is_empty());
+        assert_eq!(sched::BLOCKED_TASKS, 0);
+    }
+
+    printlnk!("Futex test passed");
+}
+
+fn test_signals() {
+    // Only tasks in the task table can be signalled
+    assert_eq!(signal::send(usize::MAX, 0), Err(Errno::ESRCH));
+    assert_eq!(signal::send(1, 32), Err(Errno::EINVAL));
+
+    // Handlers, EINTR, and terminating spinning, sleeping and blocked children
+    programs::register("signal", SIGNAL_BINARY);
+    assert_eq!(run_as_child("signal"), Some(0));
+    unsafe {
+        assert!(sched::SLEEPING_TASKS.is_empty());
+        assert_eq!(sched::BLOCKED_TASKS, 0);
+        assert_eq!(fd::LIVE_PIPES, 0);
+    }
+
+    printlnk!("Signal test passed");
+}
+
+fn test_rand() {
+    // The test vector of RFC 8439, section 2.3.2
+    let key: [u32; 8] =
+        core::array::from_fn(|i| u32::from_le_bytes(core::array::from_fn(|j| (i * 4 + j) as u8)));
+    let block = rand::chacha20_block(&key, 1, &[0x09000000, 0x4a000000, 0]);
+    assert_eq!(block[..8], [0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15]);
+    assert_eq!(
+        block[56..],
+        [0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e]
+    );
+
+    // Consecutive blocks are never all zero, and never the same
+    let mut first = [0u8; rand::CHACHA_BLOCK_SIZE];
+    let mut second = [0u8; rand::CHACHA_BLOCK_SIZE];
+    rand::fill(&mut first);
+    rand::fill(&mut second);
+    assert!(first.iter().any(|&byte| byte != 0));
+    assert_ne!(first, second);
+    assert_ne!(rand::next_u64(), rand::next_u64());
+
+    // Exactly the bytes asked for are filled, and about half of the bits are set
+    let mut buf = vec![0u8; 4097];
+    rand::fill(&mut buf[..4096]);
+    assert_eq!(buf[4096], 0);
+    let ones: u32 = buf[..4096].iter().map(|byte| byte.count_ones()).sum();
+    assert!((4096 * 8 * 45 / 100..4096 * 8 * 55 / 100).contains(&ones));
+    printlnk!(
+        "Random bytes from {:?}: {:02x?}",
+        rand::source(),
+        &first[..8]
+    );
+
+    // getrandom from user mode
+    programs::register("random", RANDOM_BINARY);
+    assert_eq!(run_as_child("random"), Some(0));
+
+    printlnk!("Random test passed");
+}
+
+fn test_sysinfo() {
+    let before = stats::system_info();
+    assert!(before.free_pages <= before.total_pages);
+    assert!(before.total_pages >= buddy::allocated_pages() as u64);
+    assert_eq!(before.num_tasks, 0);
+
+    // An allocated page and a slab object show up in the counts
+    let page = unsafe { buddy::alloc_pages_order_panic(0) };
+    let object = Box::new([0u8; 100]);
+    let during = stats::system_info();
+    assert_eq!(during.total_pages, before.total_pages);
+    assert!(during.free_pages < before.free_pages);
+    assert!(during.slab_bytes >= before.slab_bytes + 100);
+    assert!(during.uptime_ns >= before.uptime_ns);
+    drop(object);
+    unsafe { buddy::free_pages_order(page, 0) };
+
+    // The same from user mode, where switching to the task counts as a context switch
+    programs::register("sysinfo", SYSINFO_BINARY);
+    assert_eq!(run_as_child("sysinfo"), Some(0));
+    let after = stats::system_info();
+    assert!(after.context_switches > before.context_switches);
+    assert_eq!(after.num_tasks, 0);
+
+    printlnk!("Sysinfo test passed");
+}
+
+fn test_klog() {
+    // Levels by name or number
+    assert_eq!(Level::parse("warn"), Some(Level::Warn));
+    assert_eq!(Level::parse("3"), Some(Level::Debug));
+    assert_eq!(Level::parse("4"), None);
+    assert_eq!(Level::parse("verbose"), None);
+
+    // A line per record, with its time and, unless it is info, its level
+    let record = Record {
+        level: Level::Warn,
+        timestamp_ns: 1_234_567_891,
+        text: "disk on fire",
+    };
+    assert_eq!(format!("{}", record), "[    1.234567] warn: disk on fire\n");
+    let record = Record {
+        level: Level::Info,
+        ..record
+    };
+    assert_eq!(format!("{}", record), "[    1.234567] disk on fire\n");
+
+    // Filling a ring past its capacity drops the oldest records, whole, and keeps the rest in order
+    let mut ring = Ring::<1024>::new();
+    for i in 0..200 {
+        ring.push(Level::Info, i, &format!("record {}", i));
+    }
+    assert!(ring.dropped() > 0 && ring.len() <= 1024);
+    let mut next = ring.dropped() as u64;
+    ring.for_each(|record| {
+        assert_eq!(record.timestamp_ns, next);
+        assert_eq!(record.text, format!("record {}", next));
+        next += 1;
+    });
+    assert_eq!(next, 200);
+
+    // Long lines are cut off between characters
+    ring.push(Level::Error, 0, &"€".repeat(MAX_LINE));
+    let mut last = String::new();
+    ring.for_each(|record| last = record.text.into());
+    assert_eq!(last, "€".repeat(MAX_LINE / 3));
+
+    // printk goes to the log, where a line printed in pieces is one record. Debug lines are logged too.
+    printk!("Klog test: ");
+    printlnk!("one line");
+    log_debug!("Klog test: debug");
+    let text = klog::read(usize::MAX);
+    let lines: Vec<&str> = text.lines().rev().take(2).collect();
+    assert!(lines[1].ends_with("] Klog test: one line"));
+    assert!(lines[0].ends_with("] debug: Klog test: debug"));
+
+    // read() gives the newest lines that fit whole
+    assert_eq!(klog::read(lines[0].len() + 1), format!("{}\n", lines[0]));
+    assert_eq!(klog::read(lines[0].len()), "");
+
+    // The same from user mode, with sys_dmesg
+    programs::register("dmesg", DMESG_BINARY);
+    assert_eq!(run_as_child("dmesg"), Some(0));
+
+    printlnk!("Klog test passed");
+}
+
+fn test_shell() {
+    // Commands, with their arguments in decimal or hexadecimal
+    assert_eq!(shell::parse_command("  "), Ok(None));
+    assert_eq!(shell::parse_command("ps"), Ok(Some(Command::Ps)));
+    assert_eq!(
+        shell::parse_command("poweroff"),
+        Ok(Some(Command::PowerOff))
+    );
+    assert_eq!(
+        shell::parse_command(" peek 0x1000  16 "),
+        Ok(Some(Command::Peek {
+            addr: 0x1000,
+            len: 16
+        }))
+    );
+    assert_eq!(
+        shell::parse_command("poke 4096 0xff"),
+        Ok(Some(Command::Poke {
+            addr: 4096,
+            value: 0xff
+        }))
+    );
+    assert_eq!(
+        shell::parse_command("poke 4096 256"),
+        Err(ShellError::BadNumber)
+    );
+    assert_eq!(
+        shell::parse_command("peek 0x 16"),
+        Err(ShellError::BadNumber)
+    );
+    assert_eq!(
+        shell::parse_command("maps 99999999999999999999999"),
+        Err(ShellError::BadNumber)
+    );
+    assert_eq!(
+        shell::parse_command("peek 0x10"),
+        Err(ShellError::MissingArgument)
+    );
+    assert_eq!(
+        shell::parse_command("maps 1 2"),
+        Err(ShellError::ExtraArgument)
+    );
+    assert_eq!(
+        shell::parse_command("format c:"),
+        Err(ShellError::UnknownCommand)
+    );
+
+    // peek dumps RAM through the direct map, and poke writes it
+    let bytes = Box::new(*b"Hello, shell!\x00\x01\x02");
+    let addr = v2p(bytes.as_ptr() as usize);
+    let mut out = String::new();
+    shell::execute(Command::Peek { addr, len: 16 }, &mut out).unwrap();
+    assert_eq!(
+        out,
+        format!(
+            "{:#010x}: 48 65 6c 6c 6f 2c 20 73 68 65 6c 6c 21 00 01 02 |Hello, shell!...|\n",
+            addr
+        )
+    );
+    let poke = Command::Poke {
+        addr: addr + 1,
+        value: b'a',
+    };
+    shell::execute(poke, &mut out).unwrap();
+    assert_eq!(unsafe { ptr::read_volatile(&bytes[1]) }, b'a');
+
+    // A short last line is padded, so its text lines up
+    let mut out = String::new();
+    shell::hex_dump(0x10, b"ab", &mut out).unwrap();
+    assert_eq!(out, format!("0x00000010: 61 62{:42} |ab|\n", ""));
+
+    // Device memory, ranges that wrap around, long peeks and missing tasks are refused
+    let mut out = String::new();
+    let mut run = |command| shell::execute(command, &mut out);
+    let lapic = Command::Peek {
+        addr: 0xFEE0_0000,
+        len: 16,
+    };
+    assert_eq!(run(lapic), Err(ShellError::NotRam));
+    let wrapping = Command::Poke {
+        addr: usize::MAX,
+        value: 0,
+    };
+    assert_eq!(run(wrapping), Err(ShellError::NotRam));
+    let long = Command::Peek {
+        addr,
+        len: shell::MAX_PEEK + 1,
+    };
+    assert_eq!(run(long), Err(ShellError::TooLong));
+    assert_eq!(
+        run(Command::Maps { pid: usize::MAX }),
+        Err(ShellError::NoSuchTask)
+    );
+    assert!(out.is_empty());
+
+    // The other commands write their output too
+    let output = |command| {
+        let mut out = String::new();
+        shell::execute(command, &mut out).unwrap();
+        out
+    };
+    assert!(output(Command::Help).contains("peek <addr> <len>"));
+    assert!(output(Command::Mem).starts_with("pages: "));
+    assert!(output(Command::Ps).trim_start().starts_with("PID"));
+    assert!(output(Command::IrqStats).starts_with("Interrupts per vector:"));
+    assert!(output(Command::Dmesg).ends_with("\n"));
+
+    printlnk!("Shell test passed");
+}
+
+fn test_page_fault() {
+    // The error code is decoded into a cause
+    let error =
+        PageFaultError(PageFaultError::USER | PageFaultError::WRITE | PageFaultError::PRESENT);
+    assert!(error.present() && error.write() && error.user());
+    assert!(!error.reserved() && !error.instruction_fetch());
+    assert_eq!(format!("{}", error), "user write to a present page");
+    assert_eq!(
+        format!("{}", PageFaultError(PageFaultError::INSTRUCTION_FETCH)),
+        "kernel instruction fetch from a page that is not present"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            PageFaultError(PageFaultError::USER | PageFaultError::RESERVED | 1)
+        ),
+        "user read from a present page, with a reserved bit set in its paging entry"
+    );
+
+    // Null pointer accesses kill the children, and the kernel goes on to let the parent reap them
+    programs::register("null", NULL_BINARY);
+    assert_eq!(run_as_child("null"), Some(0));
+    assert!(unsafe { sched::TASK_TABLE.is_empty() });
+
+    printlnk!("Page fault test passed");
+}
+
+fn test_user_exceptions() {
+    // ud2, a division by zero and a jump to the stack each kill a child, instead of halting the kernel
+    programs::register("exceptions", EXCEPTIONS_BINARY);
+    assert_eq!(run_as_child("exceptions"), Some(0));
+    assert!(unsafe { sched::TASK_TABLE.is_empty() });
+
+    printlnk!("User exception test passed");
+}
+
+fn test_safe_msr() {
+    // Each of read_msr_safe() and write_msr_safe() has an instruction in the exception table
+    assert!(extable::len() >= 2);
+
+    let efer = read_msr(IA32_EFER);
+    assert_eq!(read_msr_safe(IA32_EFER), Ok(efer));
+
+    // An MSR no CPU has raises #GP, which comes back as an error rather than halting
+    const BOGUS_MSR: u32 = 0xdead_beef;
+    assert_eq!(read_msr_safe(BOGUS_MSR), Err(MsrError::GeneralProtection));
+    assert_eq!(
+        write_msr_safe(BOGUS_MSR, 0),
+        Err(MsrError::GeneralProtection)
+    );
+
+    // So does a reserved bit of an MSR that exists, which is left as it was
+    assert_eq!(
+        write_msr_safe(IA32_EFER, efer | 1 << 63),
+        Err(MsrError::GeneralProtection)
+    );
+    assert_eq!(read_msr(IA32_EFER), efer);
+    assert_eq!(write_msr_safe(IA32_EFER, efer), Ok(()));
+
+    printlnk!("Safe MSR test passed");
+}
+
+fn test_gpf() {
+    // Error codes name the GDT entry or IDT vector of the selector that caused the fault
+    assert_eq!(format!("{}", SelectorError(0)), "no selector");
+    assert_eq!(
+        format!("{}", SelectorError(0x28)),
+        "GDT entry 5 (TSS, lower half)"
+    );
+    assert_eq!(SelectorError(0x6b).table(), DescriptorTable::Idt);
+    assert_eq!(
+        format!("{}", SelectorError(0x6b)),
+        "IDT vector 13 (General Protection), from an external event"
+    );
+    assert_eq!(
+        format!("{}", SelectorError(0x3c)),
+        "LDT entry 7, but there is no LDT"
+    );
+
+    // Instruction bytes are read as far as they are mapped
+    let code = test_gpf as *const () as usize;
+    let instruction = InstructionBytes::from_kernel(code);
+    assert_eq!(instruction.as_bytes(), unsafe {
+        core::slice::from_raw_parts(code as *const u8, InstructionBytes::MAX_LEN)
+    });
+    assert_eq!(InstructionBytes::from_kernel(0x1000).as_bytes(), &[]);
+
+    // Each faulting child is killed, after wrmsr is named by its bytes, and the parent goes on
+    programs::register("gpf", GPF_BINARY);
+    assert_eq!(run_as_child("gpf"), Some(0));
+    let gpf = isr::last_gpf().unwrap();
+    assert_eq!(gpf.error, SelectorError(0));
+    assert_eq!(&gpf.instruction.as_bytes()[..2], &[0x0f, 0x30]);
+    assert_eq!(gpf.cs, USER_CODE_SELECTOR as usize);
+    assert!(unsafe { sched::TASK_TABLE.is_empty() });
+
+    printlnk!("GPF test passed");
+}
+
+fn test_invalid_opcode() {
+    // The classifier looks past prefixes, and its hints depend on the enabled extensions
+    let none = Extensions {
+        sse: false,
+        avx: false,
+        avx512: false,
+    };
+    let all = Extensions {
+        sse: true,
+        avx: true,
+        avx512: true,
+    };
+    let classify = invalid_opcode::classify;
+    assert_eq!(classify(&[0x0f, 0x0b], all), Cause::Ud2);
+    assert_eq!(classify(&[0xc5, 0xf8, 0x77], none), Cause::AvxNotEnabled);
+    assert_eq!(classify(&[0xc5, 0xf8, 0x77], all), Cause::Unsupported);
+    assert_eq!(
+        classify(&[0x62, 0xf1, 0x7c, 0x48, 0x58, 0xc0], none),
+        Cause::Avx512NotEnabled
+    );
+    assert_eq!(classify(&[0x0f, 0x58, 0xc0], none), Cause::SseNotEnabled);
+    assert_eq!(classify(&[0x66, 0x0f, 0xef, 0xc0], all), Cause::Unsupported);
+    assert_eq!(classify(&[0xf0, 0x01, 0xc0], all), Cause::LockOnRegister);
+    assert_eq!(
+        classify(&[0xf0, 0x48, 0x0f, 0xb1, 0xc8], all),
+        Cause::LockOnRegister
+    );
+    assert_eq!(classify(&[0xf0, 0x90], all), Cause::LockNotAllowed);
+    assert_eq!(classify(&[0xf0, 0xf7, 0xd0], all), Cause::LockOnRegister);
+    assert_eq!(classify(&[0xf0, 0xf7, 0xe0], all), Cause::LockNotAllowed);
+    assert_eq!(classify(&[0x06], all), Cause::InvalidIn64BitMode);
+    assert_eq!(classify(&[], all), Cause::Unknown);
+
+    // The kernel enables SSE, but not AVX
+    let extensions = Extensions::current();
+    assert!(extensions.sse && !extensions.avx);
+
+    // Each faulting child is killed, after the AVX instruction is recognized, and the parent goes on
+    programs::register("avx", AVX_BINARY);
+    assert_eq!(run_as_child("avx"), Some(0));
+    assert_eq!(invalid_opcode::last_cause(), Some(Cause::AvxNotEnabled));
+    assert!(format!("{}", Cause::AvxNotEnabled).contains("not enabled"));
+    assert!(unsafe { sched::TASK_TABLE.is_empty() });
+
+    printlnk!("Invalid opcode test passed");
+}
+
+fn test_machine_check() {
+    // MCA error codes: simple ones, then the classes of compound ones, with or without the filter bit
+    assert_eq!(mce::error_kind(0x0000), "no error");
+    assert_eq!(mce::error_kind(0x0005), "internal parity error");
+    assert_eq!(mce::error_kind(0x0401), "internal error");
+    assert_eq!(mce::error_kind(0x000d), "generic cache hierarchy error");
+    assert_eq!(mce::error_kind(0x0011), "TLB error");
+    assert_eq!(mce::error_kind(0x009f), "memory controller error");
+    assert_eq!(mce::error_kind(0x1135), "cache hierarchy error");
+    assert_eq!(mce::error_kind(0x0e0b), "bus or interconnect error");
+    assert_eq!(mce::error_kind(0x0008), "unknown error");
+
+    let status = BankStatus(BankStatus::VAL | BankStatus::UC | BankStatus::PCC | 0x0002_009f);
+    assert_eq!(
+        format!("{}", status),
+        "uncorrected memory controller error (0x009f), model specific code 0x0002, processor context corrupt"
+    );
+    let record = ErrorRecord {
+        bank: 4,
+        status: BankStatus(BankStatus::VAL | BankStatus::ADDRV | 0x0135),
+        addr: Some(0x1234),
+        misc: None,
+    };
+    assert_eq!(
+        format!("{}", record),
+        "Bank 4: corrected cache hierarchy error (0x0135), model specific code 0x0000, address 0x1234"
+    );
+
+    // QEMU has MCA, with banks that never log an error
+    match mce::bank_count() {
+        Some(banks) => {
+            assert!(banks > 0);
+            assert_eq!(mce::for_each_error(|record| panic!("{}", record)), 0);
+            assert!(!mce::mcg_status().unwrap().in_progress());
+        }
+        None => assert_eq!(mce::mcg_status(), None),
+    }
+
+    // Control protection errors name the check that failed
+    assert_eq!(
+        format!(
+            "{}",
+            ControlProtectionError(ControlProtectionError::NEAR_RET)
+        ),
+        "a near return address doesn't match the shadow stack"
+    );
+    let error =
+        ControlProtectionError(ControlProtectionError::ENDBRANCH | ControlProtectionError::ENCLAVE);
+    assert_eq!(error.kind(), ControlProtectionError::ENDBRANCH);
+    assert_eq!(
+        format!("{}", error),
+        "an indirect call or jump didn't land on an ENDBRANCH, in an enclave"
+    );
+    assert_eq!(
+        format!("{}", ControlProtectionError(9)),
+        "unknown violation 9"
+    );
+
+    printlnk!("Machine check test passed");
+}
+
+// Interrupts the test handler of test_irq() has received, and the vector of the last one.
+static mut TEST_IRQS: usize = 0;
+static mut TEST_IRQ_VECTOR: usize = 0;
+
+fn test_gdt() {
+    assert_eq!(
+        SegmentDescriptor::kernel_code().raw(),
+        0x0020_9b00_0000_0000
+    );
+    assert_eq!(SegmentDescriptor::user_data().raw(), 0x0000_f300_0000_0000);
+    assert_eq!(
+        SystemDescriptor::tss(0xffff_8000_1234_5678, 0x67).raw(),
+        [0x1200_8934_5678_0067, 0xffff_8000]
+    );
+
+    // The selectors the CPU has loaded are the ones derived from the entries
+    let cs: u16;
+    let tr: u16;
+    unsafe {
+        asm!("mov {0:x}, cs", "str {1:x}", out(reg) cs, out(reg) tr, options(nomem, nostack, preserves_flags));
+    }
+    assert_eq!(cs, KERNEL_CODE_SELECTOR);
+    assert_eq!(tr, TSS_SELECTOR);
+    assert_eq!(
+        gdt::entry_name(TSS_SELECTOR as usize >> 3),
+        Some("TSS, lower half")
+    );
+}
+
+fn test_irq_handler(context: &mut irq::IrqContext) {
+    unsafe {
+        TEST_IRQS += 1;
+        TEST_IRQ_VECTOR = context.vector;
+    }
+}
+
+fn test_irq() {
+    const TEST_VECTOR: usize = 0x80;
+
+    // The PIT and keyboard drivers are registered, and the timer handler keeps running, on the timer of the local
+    // APIC when it is enabled
+    assert!(irq::handler(idt::TIMER_VECTOR).is_some());
+    assert!(irq::handler(idt::KEYBOARD_VECTOR).is_some());
+    let timer_vector = if lapic::enabled() {
+        lapic::TIMER_VECTOR
+    } else {
+        idt::TIMER_VECTOR
+    };
+    let timer_irqs = irq::interrupt_count(timer_vector);
+    let start = time::ticks();
+    while time::ticks() < start + 2 {
+        core::hint::spin_loop();
+    }
+    assert!(irq::interrupt_count(timer_vector) >= timer_irqs + 2);
+
+    // Exceptions and vectors past the IDT can't be registered, and a vector has one handler at most
+    assert_eq!(
+        irq::register_handler(14, test_irq_handler),
+        Err(irq::IrqError::InvalidVector)
+    );
+    assert_eq!(
+        irq::register_handler(256, test_irq_handler),
+        Err(irq::IrqError::InvalidVector)
+    );
+    assert_eq!(
+        irq::register_handler(idt::TIMER_VECTOR, test_irq_handler),
+        Err(irq::IrqError::AlreadyRegistered)
+    );
+
+    // A registered handler runs, with its vector in the context
+    irq::register_handler(TEST_VECTOR, test_irq_handler).unwrap();
+    let spurious = irq::spurious_count();
+    unsafe { asm!("int 0x80") };
+    assert_eq!(unsafe { (TEST_IRQS, TEST_IRQ_VECTOR) }, (1, TEST_VECTOR));
+    assert_eq!(irq::interrupt_count(TEST_VECTOR), 1);
+    assert_eq!(irq::spurious_count(), spurious);
+
+    // Once unregistered, the vector is spurious
+    irq::unregister_handler(TEST_VECTOR).unwrap();
+    assert_eq!(
+        irq::unregister_handler(TEST_VECTOR),
+        Err(irq::IrqError::NotRegistered)
+    );
+    unsafe { asm!("int 0x80") };
+    assert_eq!(unsafe { TEST_IRQS }, 1);
+    assert_eq!(irq::spurious_count(), spurious + 1);
+
+    // Vectors without a handler, reserved exceptions included, are counted, and execution goes on
+    let (stray, reserved) = (irq::interrupt_count(0x81), irq::interrupt_count(22));
+    for _ in 0..5 {
+        unsafe { asm!("int 0x81", "int 22") };
+    }
+    assert_eq!(irq::interrupt_count(0x81), stray + 5);
+    assert_eq!(irq::interrupt_count(22), reserved + 5);
+    assert_eq!(irq::interrupt_count(TEST_VECTOR), 2);
+    assert_eq!(irq::spurious_count(), spurious + 11);
+
+    printlnk!("IRQ test passed");
+}
+
+fn test_lapic() {
+    // The PIC gets the EOIs of its vectors, the local APIC those of its timer, and nothing else needs one
+    assert_eq!(irq::eoi_kind(idt::TIMER_VECTOR), Eoi::Pic);
+    assert_eq!(irq::eoi_kind(idt::KEYBOARD_VECTOR), Eoi::Pic);
+    assert_eq!(irq::eoi_kind(0x80), Eoi::None);
+    assert_eq!(irq::eoi_kind(lapic::SPURIOUS_VECTOR), Eoi::None);
+    assert_eq!(irq::set_lapic_eoi(14), Err(irq::IrqError::InvalidVector));
+
+    if cmdline::option("nolapic").is_some() || !lapic::supported() {
+        // The PIT drives the tick
+        assert!(!lapic::enabled());
+        assert_eq!(irq::eoi_kind(lapic::TIMER_VECTOR), Eoi::None);
+        let pit_irqs = irq::interrupt_count(idt::TIMER_VECTOR);
+        pit::delay_ms(50);
+        assert!(irq::interrupt_count(idt::TIMER_VECTOR) > pit_irqs);
+        printlnk!("Local APIC test passed (PIT tick)");
+        return;
+    }
+
+    assert!(lapic::enabled() && lapic::id().is_some());
+    assert!(lapic::timer_per_ms().unwrap() > 0);
+    assert_eq!(irq::eoi_kind(lapic::TIMER_VECTOR), Eoi::Lapic);
+
+    // Only the timer of the local APIC fires, as IRQ 0 is masked at the PIC, and it ticks at the rate of the PIT
+    assert!(unsafe { idt::PICS.read_masks() }[0] & 1 != 0);
+    let pit_irqs = irq::interrupt_count(idt::TIMER_VECTOR);
+    let lapic_irqs = irq::interrupt_count(lapic::TIMER_VECTOR);
+    let start_tick = time::ticks();
+    pit::delay_ms(100);
+    let ticks = time::ticks() - start_tick;
+    let expected = 100 / time::MS_PER_TICK;
+    assert!((expected - 1..=expected + 1).contains(&ticks));
+    assert_eq!(irq::interrupt_count(idt::TIMER_VECTOR), pit_irqs);
+    assert!(irq::interrupt_count(lapic::TIMER_VECTOR) >= lapic_irqs + ticks);
+
+    printlnk!("Local APIC test passed");
+}
+
+// Build a MADT with a local APIC entry, an I/O APIC and the overrides QEMU has for the PIT and IRQ 9, and fix up its
+// checksum.
+fn test_madt() -> Vec<u8> {
+    let mut table = vec![0u8; 36];
+    table[..4].copy_from_slice(b"APIC");
+    table.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    table.extend_from_slice(&1u32.to_le_bytes()); // Flags: there are 8259 PICs
+    table.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]); // Local APIC 0
+    table.extend_from_slice(&[1, 12, 3, 0]); // I/O APIC 3
+    table.extend_from_slice(&0xFEC0_0000u32.to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    table.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]); // IRQ 0 is GSI 2
+    table.extend_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0x0d, 0]); // IRQ 9 is level triggered, active high
+    let len = table.len() as u32;
+    table[4..8].copy_from_slice(&len.to_le_bytes());
+    let sum = table.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    table[9] = 0u8.wrapping_sub(sum);
+    table
+}
+
+// Have the keyboard controller raise IRQ 1, as if a key was released.
+fn inject_key_release() {
+    const KBC_DATA: u16 = 0x60;
+    const KBC_STATUS: u16 = 0x64;
+    const KBC_INPUT_FULL: u8 = 1 << 1;
+    const KBC_WRITE_OUTPUT: u8 = 0xd2; // The next data byte comes back as if the keyboard sent it
+
+    unsafe {
+        while inb(KBC_STATUS) & KBC_INPUT_FULL != 0 {}
+        outb(KBC_STATUS, KBC_WRITE_OUTPUT);
+        while inb(KBC_STATUS) & KBC_INPUT_FULL != 0 {}
+        outb(KBC_DATA, 0x9e); // A released
+    }
+}
+
+fn test_ioapic() {
+    // MADT parsing, with the overrides
+    let table = test_madt();
+    let madt = acpi::parse_madt(&table).unwrap();
+    assert_eq!(madt.local_apic_addr, 0xFEE0_0000);
+    assert_eq!(
+        madt.io_apics,
+        [IoApicEntry {
+            id: 3,
+            addr: 0xFEC0_0000,
+            gsi_base: 0
+        }]
+    );
+    assert_eq!(madt.overrides.len(), 2);
+    assert_eq!(
+        ioapic::isa_irq(Some(&madt), 0),
+        (2, Trigger::Edge, Polarity::ActiveHigh)
+    );
+    assert_eq!(
+        ioapic::isa_irq(Some(&madt), 9),
+        (9, Trigger::Level, Polarity::ActiveHigh)
+    );
+    assert_eq!(
+        ioapic::isa_irq(Some(&madt), 1),
+        (1, Trigger::Edge, Polarity::ActiveHigh)
+    );
+    assert_eq!(
+        ioapic::isa_irq(None, 0),
+        (0, Trigger::Edge, Polarity::ActiveHigh)
+    );
+
+    let mut bad = table.clone();
+    bad[40] ^= 1;
+    assert_eq!(acpi::parse_madt(&bad), Err(AcpiError::BadChecksum));
+    assert_eq!(acpi::parse_madt(&table[..40]), Err(AcpiError::Truncated));
+    let mut truncated = table[..table.len() - 4].to_vec();
+    let len = truncated.len() as u32;
+    truncated[4..8].copy_from_slice(&len.to_le_bytes());
+    let sum = truncated
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    truncated[9] = truncated[9].wrapping_sub(sum);
+    assert_eq!(acpi::parse_madt(&truncated), Err(AcpiError::Truncated));
+
+    assert_eq!(
+        ioapic::redirection_entry(0x21, 2, Trigger::Level, Polarity::ActiveLow),
+        0x21 | 2 << 56 | ioapic::ENTRY_LEVEL | ioapic::ENTRY_ACTIVE_LOW
+    );
+
+    // A key release reaches the keyboard handler, through whichever controller routes it
+    let keyboard_irqs = irq::interrupt_count(idt::KEYBOARD_VECTOR);
+    inject_key_release();
+    let start = time::ticks();
+    while irq::interrupt_count(idt::KEYBOARD_VECTOR) == keyboard_irqs {
+        assert!(time::ticks() < start + 10, "No keyboard interrupt");
+        core::hint::spin_loop();
+    }
+
+    if !ioapic::enabled() {
+        printlnk!("I/O APIC test passed (PIC routing)");
+        return;
+    }
+
+    // QEMU has one I/O APIC, and moves the PIT to GSI 2. The PIC is disabled, and the keyboard comes through GSI 1.
+    let madt = acpi::madt().unwrap();
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_eq!(ioapic::isa_irq(Some(madt), 0).0, 2);
+    assert_eq!(unsafe { idt::PICS.read_masks() }, [0xff, 0xff]);
+    assert_eq!(irq::eoi_kind(idt::KEYBOARD_VECTOR), Eoi::Lapic);
+    let entry = ioapic::entry(1).unwrap();
+    assert_eq!(entry & 0xff, idt::KEYBOARD_VECTOR as u64);
+    assert_eq!(entry & ioapic::ENTRY_MASKED, 0);
+    assert_eq!(ioapic::entry(1000), Err(IoApicError::NoIoApic));
+
+    // Masking only sets the mask bit
+    ioapic::mask(1).unwrap();
+    assert_eq!(ioapic::entry(1).unwrap(), entry | ioapic::ENTRY_MASKED);
+    ioapic::unmask(1).unwrap();
+    assert_eq!(ioapic::entry(1).unwrap(), entry);
+
+    // The keyboard still works after the round trip
+    let keyboard_irqs = irq::interrupt_count(idt::KEYBOARD_VECTOR);
+    inject_key_release();
+    let start = time::ticks();
+    while irq::interrupt_count(idt::KEYBOARD_VECTOR) == keyboard_irqs {
+        assert!(
+            time::ticks() < start + 10,
+            "No keyboard interrupt after unmasking"
+        );
+        core::hint::spin_loop();
+    }
+
+    printlnk!("I/O APIC test passed");
+}
+
+fn test_rtc() {
+    // BCD and 24-hour, with the century from the FADT
+    let raw = RawTime {
+        second: 0x09,
+        minute: 0x07,
+        hour: 0x14,
+        day: 0x05,
+        month: 0x03,
+        year: 0x24,
+        century: Some(0x20),
+    };
+    let decoded = rtc::decode(raw, rtc::STATUS_B_24_HOUR);
+    assert_eq!(
+        decoded,
+        DateTime {
+            year: 2024,
+            month: 3,
+            day: 5,
+            hour: 14,
+            minute: 7,
+            second: 9,
+        }
+    );
+    assert_eq!(format!("{}", decoded), "2024-03-05 14:07:09 UTC");
+
+    // Binary and 12-hour, where 12 AM is midnight and the PM bit adds 12
+    let binary = rtc::STATUS_B_BINARY;
+    let raw = RawTime {
+        second: 59,
+        minute: 30,
+        hour: 12,
+        day: 31,
+        month: 12,
+        year: 99,
+        century: Some(19),
+    };
+    let decoded = rtc::decode(raw, binary);
+    assert_eq!((decoded.year, decoded.hour), (1999, 0));
+    assert_eq!(
+        rtc::decode(
+            RawTime {
+                hour: 0x80 | 12,
+                ..raw
+            },
+            binary
+        )
+        .hour,
+        12
+    );
+    assert_eq!(
+        rtc::decode(
+            RawTime {
+                hour: 0x80 | 1,
+                ..raw
+            },
+            binary
+        )
+        .hour,
+        13
+    );
+    assert_eq!(
+        rtc::decode(
+            RawTime {
+                century: None,
+                ..raw
+            },
+            binary
+        )
+        .year,
+        2099
+    );
+
+    // Unix time, across leap days and centuries
+    let unix = |year, month, day, hour, minute, second| {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+        .to_unix_secs()
+    };
+    assert_eq!(unix(1970, 1, 1, 0, 0, 0), 0);
+    assert_eq!(unix(2000, 2, 29, 0, 0, 0), 951782400);
+    assert_eq!(unix(2000, 3, 1, 0, 0, 0), 951868800);
+    assert_eq!(unix(2024, 3, 5, 14, 7, 9), 1709647629);
+    assert_eq!(unix(2100, 3, 1, 0, 0, 0), 4107542400);
+
+    // The century register from the FADT, which old FADTs end before
+    let mut fadt = [0u8; 116];
+    fadt[..4].copy_from_slice(b"FACP");
+    fadt[108] = 0x32;
+    assert_eq!(acpi::parse_fadt_century(&fadt), Ok(Some(0x32)));
+    fadt[108] = 0;
+    assert_eq!(acpi::parse_fadt_century(&fadt), Ok(None));
+    assert_eq!(acpi::parse_fadt_century(&fadt[..100]), Ok(None));
+    assert_eq!(
+        acpi::parse_fadt_century(&fadt[..20]),
+        Err(AcpiError::Truncated)
+    );
+    fadt[0] = b'X';
+    assert_eq!(
+        acpi::parse_fadt_century(&fadt),
+        Err(AcpiError::BadSignature)
+    );
+
+    // Two reads a second apart differ by a second. Start halfway between updates, so neither read is near one.
+    let start = rtc::read();
+    while rtc::read() == start {
+        core::hint::spin_loop();
+    }
+    pit::delay_ms(500);
+    let first = rtc::read();
+    pit::delay_ms(1000);
+    let second = rtc::read();
+    assert_eq!(second.to_unix_secs(), first.to_unix_secs() + 1);
+
+    // The wall clock counts on from the boot time with the monotonic clock, and agrees with the RTC
+    let boot = rtc::boot_time().unwrap();
+    assert!(boot <= second && boot.year >= 2024);
+    let realtime = rtc::realtime_ns() / time::NS_PER_MS / 1000;
+    assert!(realtime.abs_diff(rtc::read().to_unix_secs()) <= 1);
+
+    printlnk!("RTC test passed");
+}
+
+// Whether interrupts were enabled in the test handler of test_interrupt_guard(): on entry, under two guards, and after.
+static mut GUARD_IN_HANDLER: [Option<bool>; 3] = [None; 3];
+
+fn guard_test_handler(_: &mut irq::IrqContext) {
+    let entry = irq::are_enabled();
+    let nested = {
+        let _outer = InterruptGuard::disable();
+        let _inner = InterruptGuard::disable();
+        irq::are_enabled()
+    };
+    unsafe { GUARD_IN_HANDLER = [Some(entry), Some(nested), Some(irq::are_enabled())] };
+}
+
+fn test_pci() {
+    let address = pci::Address::new(0x12, 0x1f, 3);
+    assert_eq!(address.config_address(0x3e), 0x8012_fb3c);
+    assert_eq!(format!("{}", address), "12:1f.3");
+
+    // Unused, I/O, 32-bit memory and 64-bit prefetchable memory BARs
+    assert_eq!(pci::decode_bar(0, 0), None);
+    assert_eq!(
+        pci::decode_bar(0xc041, 0xffff_ffe1),
+        Some(Bar::Io {
+            port: 0xc040,
+            size: 32
+        })
+    );
+    assert_eq!(
+        pci::decode_bar(0xc041, 0xffe1),
+        Some(Bar::Io {
+            port: 0xc040,
+            size: 32
+        })
+    );
+    assert_eq!(
+        pci::decode_bar(0xfebf_0000, 0xffff_f000),
+        Some(Bar::Memory {
+            addr: 0xfebf_0000,
+            size: 0x1000,
+            prefetchable: false,
+            is_64: false
+        })
+    );
+    assert_eq!(
+        pci::decode_bar(0x0000_0008_0000_000c, 0xffff_fffc_0000_000c),
+        Some(Bar::Memory {
+            addr: 0x8_0000_0000,
+            size: 0x4_0000_0000,
+            prefetchable: true,
+            is_64: true
+        })
+    );
+    assert_eq!(
+        format!("{}", pci::decode_bar(0xc041, 0xffe1).unwrap()),
+        "I/O 0xc040, 32 bytes"
+    );
+
+    // QEMU's pc machine: the i440FX host bridge and the PIIX3 ISA bridge, on bus 0
+    let host = pci::find_by_class(CLASS_BRIDGE, SUBCLASS_HOST_BRIDGE)
+        .next()
+        .unwrap();
+    assert_eq!((host.vendor, host.device), (0x8086, 0x1237));
+    assert_eq!(host.address, pci::Address::new(0, 0, 0));
+    let isa = pci::find_by_id(0x8086, 0x7000).next().unwrap();
+    assert_eq!(
+        (isa.class, isa.subclass),
+        (CLASS_BRIDGE, SUBCLASS_ISA_BRIDGE)
+    );
+    assert_eq!(isa.address.bus, 0);
+    assert!(pci::find_by_id(0x8086, 0xffff).next().is_none());
+
+    for device in pci::devices() {
+        assert_eq!(
+            pci::read_config(device.address, 0),
+            device.vendor as u32 | (device.device as u32) << 16
+        );
+        // Probing left every BAR as it was, and each is aligned to its size
+        for bar in device.bars.iter().flatten() {
+            match *bar {
+                Bar::Memory { addr, size, .. } => {
+                    assert!(size.is_power_of_two() && addr.is_multiple_of(size))
+                }
+                Bar::Io { port, size } => {
+                    assert!(size.is_power_of_two() && port.is_multiple_of(size))
+                }
+            }
+        }
+    }
+    printlnk!("{} PCI functions", pci::devices().len());
+
+    printlnk!("PCI test passed");
+}
+
+fn test_power() {
+    // S5 is entered with its sleep type and SLP_EN; the type has 3 bits
+    assert_eq!(power::pm1_control(0), 0x2000);
+    assert_eq!(power::pm1_control(5), 0x3400);
+    assert_eq!(power::pm1_control(0xff), 0x3c00);
+
+    // The PM1 control blocks and the DSDT, from the FADT
+    let mut fadt = [0u8; 116];
+    fadt[..4].copy_from_slice(b"FACP");
+    fadt[40..44].copy_from_slice(&0x7fe1234u32.to_le_bytes());
+    fadt[64..68].copy_from_slice(&0x604u32.to_le_bytes());
+    assert_eq!(acpi::parse_fadt_pm1(&fadt), Ok((0x7fe1234, 0x604, 0)));
+    assert_eq!(acpi::parse_fadt_pm1(&fadt[..70]), Err(AcpiError::Truncated));
+
+    // The sleep types of S5, as Zero, One or a BytePrefix constant, after a root prefix or not
+    let s5 = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(acpi::parse_s5(&s5), Some((0, 0)));
+    let s5 = [
+        0xff, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x02, 0x0a, 0x05, 0x01,
+    ];
+    assert_eq!(acpi::parse_s5(&s5), Some((5, 1)));
+    // A package length of two bytes
+    let s5 = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x40, 0x01, 0x02, 0x0a, 0x07, 0x0a, 0x07,
+    ];
+    assert_eq!(acpi::parse_s5(&s5), Some((7, 7)));
+    // Not a definition, too few elements, or cut short
+    assert_eq!(acpi::parse_s5(b"_S5_\x12\x04\x02\x00\x00"), None);
+    let s5 = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x01, 0x00];
+    assert_eq!(acpi::parse_s5(&s5), None);
+    assert_eq!(acpi::parse_s5(&s5[..8]), None);
+
+    // QEMU's i440FX has its PM1a control block at 0x604, and S5 of type 0
+    let soft_off = acpi::soft_off().unwrap();
+    assert_eq!(soft_off.pm1a_control, 0x604);
+    assert_eq!(soft_off.pm1b_control, None);
+    assert_eq!(soft_off.sleep_type_a, 0);
+
+    // sys_reboot only acts on the magic number and a known command
+    let mut args = SyscallArgs {
+        num: nr::SYS_REBOOT,
+        arg1: 0x1234,
+        arg2: REBOOT_CMD_POWER_OFF,
+        ..Default::default()
+    };
+    assert_eq!(
+        dispatch::dispatch(&mut args),
+        errno::encode(Err(Errno::EINVAL))
+    );
+    args.arg1 = REBOOT_MAGIC;
+    args.arg2 = 0;
+    assert_eq!(
+        dispatch::dispatch(&mut args),
+        errno::encode(Err(Errno::EINVAL))
+    );
+
+    printlnk!("Power test passed");
+}
+
+// Byte n of the disk image the runner creates.
+fn disk_pattern(offset: usize) -> u8 {
+    (offset % 251) as u8
+}
+
+fn matches_disk_pattern(lba: u64, bytes: &[u8]) -> bool {
+    let start = lba as usize * SECTOR_SIZE;
+    bytes
+        .iter()
+        .enumerate()
+        .all(|(i, &byte)| byte == disk_pattern(start + i))
+}
+
+// The file the runner always passes through fw_cfg, from the build script: FW_CFG_TEST_SIZE bytes where byte n is
+// n % 253
+const FW_CFG_TEST: &str = "opt/elytra/test";
+const FW_CFG_TEST_SIZE: usize = 5000;
+
+fn test_fw_cfg() {
+    // A directory that claims three files, but only has entries for two
+    let mut directory = 3u32.to_be_bytes().to_vec();
+    for (name, size, key) in [("opt/a", 5u32, 0x20u16), ("etc/b", 70000, 0x21)] {
+        let mut entry = [0; 64];
+        entry[0..4].copy_from_slice(&size.to_be_bytes());
+        entry[4..6].copy_from_slice(&key.to_be_bytes());
+        entry[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        directory.extend_from_slice(&entry);
+    }
+    let files = fwcfg::parse_directory(&directory);
+    assert_eq!(
+        files,
+        [
+            FwCfgFile {
+                name: "opt/a".into(),
+                size: 5,
+                key: 0x20
+            },
+            FwCfgFile {
+                name: "etc/b".into(),
+                size: 70000,
+                key: 0x21
+            },
+        ]
+    );
+    assert!(fwcfg::parse_directory(&[0, 0]).is_empty());
+
+    if !fwcfg::present() {
+        assert_eq!(fwcfg::read_file(FW_CFG_TEST), None);
+        printlnk!("No fw_cfg, skipping the fw_cfg test");
+        return;
+    }
+    assert_eq!(fwcfg::read_file("opt/elytra/missing"), None);
+    let Some(blob) = fwcfg::read_file(FW_CFG_TEST) else {
+        printlnk!("No fw_cfg test file, skipping its contents (run with the runner)");
+        printlnk!("fw_cfg test passed");
+        return;
+    };
+
+    // More than a page, so DMA takes a few requests, with every byte value
+    let expected: Vec<u8> = (0..FW_CFG_TEST_SIZE).map(|n| (n % 253) as u8).collect();
+    assert!(blob == expected);
+    assert!(fwcfg::read_file_ports(FW_CFG_TEST).unwrap() == expected);
+
+    // What the boot loaded from the runner's --init and --cmdline
+    if let Some(elf) = fwcfg::read_file(startup::FW_CFG_INIT) {
+        assert_eq!(
+            programs::find(startup::FW_CFG_INIT_PROGRAM),
+            Some(elf.as_slice())
+        );
+    }
+    if let Some(options) = fwcfg::read_file(cmdline::FW_CFG_CMDLINE) {
+        assert_eq!(cmdline::fw_cfg_options().as_bytes(), options);
+    }
+
+    printlnk!("fw_cfg test passed (DMA: {})", fwcfg::has_dma());
+}
+
+fn test_block() {
+    let mut buf = vec![0u8; 20 * SECTOR_SIZE];
+    let Some(capacity) = block::capacity() else {
+        assert_eq!(block::read_sectors(0, 1, &mut buf), Err(BlockError::NoDisk));
+        printlnk!("No disk, skipping the disk test (run with --disk)");
+        return;
+    };
+
+    assert_eq!(
+        block::read_sectors(capacity, 1, &mut buf),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        block::read_sectors(u64::MAX, 2, &mut buf),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        block::read_sectors(0, 21, &mut buf),
+        Err(BlockError::SmallBuffer)
+    );
+    block::read_sectors(capacity - 1, 1, &mut buf).unwrap();
+
+    block::read_sectors(0, 1, &mut buf).unwrap();
+    if !matches_disk_pattern(0, &buf[..SECTOR_SIZE]) {
+        printlnk!("The disk isn't the test image, skipping its contents");
+        printlnk!("Block test passed");
+        return;
+    }
+
+    // Read before the scheduler runs, in requests of up to a page, and from the end of the buffer on untouched
+    buf.fill(0xaa);
+    block::read_sectors(3, 19, &mut buf).unwrap();
+    assert!(matches_disk_pattern(3, &buf[..19 * SECTOR_SIZE]));
+    assert!(buf[19 * SECTOR_SIZE..].iter().all(|&byte| byte == 0xaa));
+    block::read_sectors(capacity - 2, 2, &mut buf).unwrap();
+    assert!(matches_disk_pattern(capacity - 2, &buf[..2 * SECTOR_SIZE]));
+
+    // Two tasks reading at once take turns with the disk, and block until their requests complete
+    unsafe {
+        DISK_READS_OK = 0;
+        for name in ["disk reader 1", "disk reader 2"] {
+            sched::add_new_task(TaskRef::new(Task::create_kernel_task(read_disk, name)));
+        }
+        sched::begin_scheduler();
+        assert_eq!(DISK_READS_OK, 2);
+    }
+
+    printlnk!("Block test passed");
+}
+
+// Number of read_disk() tasks whose reads matched the test image.
+static mut DISK_READS_OK: usize = 0;
+
+fn read_disk() -> ! {
+    let mut buf = vec![0u8; 64 * SECTOR_SIZE];
+    let ok = (0..4).all(|i| {
+        let lba = 64 * i + sched::current_pid() as u64 % 8;
+        block::read_sectors(lba, 64, &mut buf).is_ok() && matches_disk_pattern(lba, &buf)
+    });
+    unsafe {
+        if ok {
+            DISK_READS_OK += 1;
+        }
+        sched::exit_current(0)
+    }
+}
+
+fn test_interrupt_guard() {
+    const TEST_VECTOR: usize = 0x82;
+
+    // Three nested guards: interrupts stay disabled until the outermost one is dropped
+    assert!(irq::are_enabled());
+    {
+        let _first = InterruptGuard::disable();
+        assert!(!irq::are_enabled());
+        {
+            let _second = InterruptGuard::disable();
+            {
+                let _third = InterruptGuard::disable();
+                assert!(!irq::are_enabled());
+            }
+            assert!(!irq::are_enabled());
+        }
+        assert!(!irq::are_enabled());
+    }
+    assert!(irq::are_enabled());
+
+    // Guards taken with interrupts disabled leave them disabled
+    disable_interrupt();
+    {
+        let _guard = InterruptGuard::disable();
+        without_interrupt(|| assert!(!irq::are_enabled()));
+    }
+    assert!(!irq::are_enabled());
+    enable_interrupt();
+
+    // without_interrupt() nests the same way, and passes the result through
+    let result = without_interrupt(|| without_interrupt(|| without_interrupt(|| 42)));
+    assert_eq!(result, 42);
+    assert!(irq::are_enabled());
+
+    // In an interrupt handler, interrupts are disabled, and guards don't enable them on the way out
+    irq::register_handler(TEST_VECTOR, guard_test_handler).unwrap();
+    unsafe { asm!("int 0x82") };
+    irq::unregister_handler(TEST_VECTOR).unwrap();
+    assert_eq!(
+        unsafe { GUARD_IN_HANDLER },
+        [Some(false), Some(false), Some(false)]
+    );
+    assert!(irq::are_enabled());
+
+    printlnk!("Interrupt guard test passed");
+}
+
+// Interrupt nesting seen by the handlers of test_interrupt_context(), the outer one first.
+static mut NESTING_SEEN: [usize; 2] = [0; 2];
+
+fn outer_nesting_handler(_: &mut irq::IrqContext) {
+    unsafe {
+        NESTING_SEEN[0] = irq::interrupt_nesting();
+        asm!("int 0x84");
+    }
+}
+
+fn inner_nesting_handler(_: &mut irq::IrqContext) {
+    unsafe { NESTING_SEEN[1] = irq::interrupt_nesting() };
+}
+
+fn test_interrupt_context() {
+    const OUTER_VECTOR: usize = 0x83;
+    const INNER_VECTOR: usize = 0x84;
+
+    // Each handler enters interrupt context, and nested ones nest
+    assert!(!irq::in_interrupt());
+    irq::register_handler(OUTER_VECTOR, outer_nesting_handler).unwrap();
+    irq::register_handler(INNER_VECTOR, inner_nesting_handler).unwrap();
+    unsafe { asm!("int 0x83") };
+    irq::unregister_handler(OUTER_VECTOR).unwrap();
+    irq::unregister_handler(INNER_VECTOR).unwrap();
+    assert_eq!(unsafe { NESTING_SEEN }, [1, 2]);
+    assert!(!irq::in_interrupt());
+
+    // Exceptions too, and the ones a user task causes kill it after their handler has left interrupt context
+    let violations = irq::violations();
+    unsafe { asm!("int3") };
+    assert_eq!(run_as_child("exceptions"), Some(0));
+    assert!(!irq::in_interrupt());
+    assert_eq!(irq::violations(), violations);
+
+    #[cfg(feature = "test-interrupt-context")]
+    test_interrupt_context_violations();
+
+    printlnk!("Interrupt context test passed");
+}
+
+// Violations reported for each call of violating_handler(): an allocation, and a yield.
+#[cfg(feature = "test-interrupt-context")]
+static mut VIOLATIONS_SEEN: [usize; 2] = [0; 2];
+
+// Allocates and yields in interrupt context. Its task is the only one, so the yield returns right away.
+#[cfg(feature = "test-interrupt-context")]
+fn violating_handler(_: &mut irq::IrqContext) {
+    let before = irq::violations();
+    drop(core::hint::black_box(Box::new(42)));
+    let after_alloc = irq::violations();
+    unsafe {
+        sched::yield_task();
+        VIOLATIONS_SEEN = [after_alloc - before, irq::violations() - after_alloc];
+    }
+}
+
+#[cfg(feature = "test-interrupt-context")]
+fn violating_task() -> ! {
+    unsafe {
+        asm!("int 0x85");
+        sched::exit_current(0)
+    }
+}
+
+// Break the rules of interrupt context on purpose, from a kernel task, and check that each call is reported. Only
+// kernels built with the test-interrupt-context feature go on after a report.
+#[cfg(feature = "test-interrupt-context")]
+fn test_interrupt_context_violations() {
+    const TEST_VECTOR: usize = 0x85;
+
+    irq::register_handler(TEST_VECTOR, violating_handler).unwrap();
+    unsafe {
+        sched::add_new_task(TaskRef::new(Task::create_kernel_task(
+            violating_task,
+            "violator",
+        )));
+        sched::begin_scheduler();
+    }
+    irq::unregister_handler(TEST_VECTOR).unwrap();
+    assert_eq!(unsafe { VIOLATIONS_SEEN }, [1, 1]);
+}
+
+fn test_nmi() {
+    // nmi_panic would turn the NMI into a panic
+    if cmdline::option("nmi_panic").is_some() {
+        return;
+    }
+
+    // int 2 runs the NMI handler as an NMI would, and execution goes on with every register as it was
+    let count = nmi::nmi_count();
+    let (mut r12, mut r13) = (0x1234usize, 0x5678usize);
+    unsafe { asm!("int 2", inout("r12") r12, inout("r13") r13) };
+    assert_eq!((r12, r13), (0x1234, 0x5678));
+    assert_eq!(nmi::nmi_count(), count + 1);
+    assert_eq!(startup::boot_stage(), "interrupts");
+
+    // An NMI in the middle of a print breaks the lock of the console rather than waiting for it forever, and says so
+    assert!(!output::poisoned());
+    output::with_serial(|_| unsafe { asm!("int 2") });
+    assert!(output::poisoned());
+    let log = klog::read(usize::MAX);
+    let received = format!("[lock busted] NMI {} received", count + 2);
+    assert!(log.lines().any(|line| line.ends_with(&received)));
+
+    printlnk!("NMI test passed");
+}
+
+// Three nested calls, the innermost of which takes a backtrace, as the panic handler would.
+#[inline(never)]
+fn backtrace_depth_one() -> Vec<String> {
+    // Used after the call, so it can't become a tail call, which would leave no frame
+    core::hint::black_box(backtrace_depth_two())
+}
+
+#[inline(never)]
+fn backtrace_depth_two() -> Vec<String> {
+    core::hint::black_box(backtrace_depth_three())
+}
+
+#[inline(never)]
+fn backtrace_depth_three() -> Vec<String> {
+    let mut frames = Vec::new();
+    backtrace::walk(|ret| frames.push(format!("{}", backtrace::Symbolized(ret))));
+    frames
+}
+
+fn test_backtrace() {
+    // The three functions are named, innermost first
+    let frames = backtrace_depth_one();
+    let position = |name: &str| {
+        let name = format!("<kernel::test::{}+", name);
+        frames.iter().position(|frame| frame.contains(&name))
+    };
+    let three = position("backtrace_depth_three").unwrap();
+    let two = position("backtrace_depth_two").unwrap();
+    let one = position("backtrace_depth_one").unwrap();
+    assert!(three < two && two < one);
+    assert!(position("test_backtrace").unwrap() > one);
+
+    // A chain that doesn't go up, leaves the kernel stack or isn't aligned ends the walk
+    let mut stack = vec![0usize; 8];
+    let base = stack.as_ptr() as usize;
+    let ret = test_backtrace as *const () as usize;
+    let count = |stack: &[usize], offset: usize| {
+        let mut count = 0;
+        backtrace::walk_from(stack.as_ptr() as usize + offset, |addr| {
+            assert_eq!(addr, ret);
+            count += 1;
+        });
+        count
+    };
+    stack[..4].copy_from_slice(&[base + 16, ret, base, ret]); // Loops back down
+    assert_eq!(count(&stack, 0), 2);
+    stack[2] = 0x1000; // User space
+    assert_eq!(count(&stack, 0), 2);
+    stack[2] = base + 16 + 0x100000; // Too far up to be on the same stack
+    assert_eq!(count(&stack, 0), 2);
+    assert_eq!(count(&stack, 4), 0);
+
+    // Plain paths are demangled, anything else is left as it is
+    let demangle = |name| format!("{}", backtrace::Demangled(name));
+    assert_eq!(
+        demangle("_RNvNtCs1a2b3c_6kernel4test4test"),
+        "kernel::test::test"
+    );
+    assert_eq!(
+        demangle("_RNCNvCsXYZ_6kernel5panic0B3_"),
+        "kernel::panic::{closure}"
+    );
+    assert_eq!(demangle("_RNvCs1_6kernel6__start"), "kernel::_start");
+    assert_eq!(
+        demangle("_RINvCs1_4core4dropB2_E"),
+        "_RINvCs1_4core4dropB2_E"
+    );
+    assert_eq!(demangle("memcpy"), "memcpy");
+
+    printlnk!("Backtrace test passed");
+}
+
+// Written to under a hardware watchpoint.
+static mut WATCHED: u64 = 0;
+
+// Run under a hardware instruction breakpoint.
+#[inline(never)]
+fn breakpoint_target(value: usize) -> usize {
+    value + 1
+}
+
+fn test_breakpoints() {
+    // int3 is reported, and execution goes on after it
+    let breakpoints = debug::breakpoint_count();
+    let resumed: usize;
+    unsafe { asm!("int3", "mov {}, 1", out(reg) resumed) };
+    assert_eq!(resumed, 1);
+    assert_eq!(debug::breakpoint_count(), breakpoints + 1);
+
+    // A write watchpoint fires on writes, and not on reads
+    let watched = &raw mut WATCHED;
+    let slot = debug::set_hw_breakpoint(watched as usize, BreakpointKind::Write(8)).unwrap();
+    let hits = debug::hits(slot);
+    unsafe { watched.write_volatile(42) };
+    assert_eq!(debug::hits(slot), hits + 1);
+    assert_eq!(unsafe { watched.read_volatile() }, 42);
+    assert_eq!(debug::hits(slot), hits + 1);
+
+    // Once cleared, it doesn't
+    debug::clear_hw_breakpoint(slot).unwrap();
+    unsafe { watched.write_volatile(43) };
+    assert_eq!(debug::hits(slot), hits + 1);
+    assert_eq!(
+        debug::clear_hw_breakpoint(slot),
+        Err(BreakpointError::NotSet)
+    );
+
+    // An instruction breakpoint fires once per call, and the function still runs
+    let target = breakpoint_target as *const () as usize;
+    let slot = debug::set_hw_breakpoint(target, BreakpointKind::Execute).unwrap();
+    let hits = debug::hits(slot);
+    let call = core::hint::black_box(breakpoint_target as fn(usize) -> usize);
+    assert_eq!(call(1), 2);
+    assert_eq!(call(2), 3);
+    assert_eq!(debug::hits(slot), hits + 2);
+    debug::clear_hw_breakpoint(slot).unwrap();
+
+    // Lengths other than 1, 2, 4 and 8, misaligned addresses, and a fifth breakpoint are rejected
+    assert_eq!(
+        debug::set_hw_breakpoint(watched as usize, BreakpointKind::ReadWrite(3)),
+        Err(BreakpointError::InvalidLength)
+    );
+    assert_eq!(
+        debug::set_hw_breakpoint(watched as usize + 2, BreakpointKind::Write(4)),
+        Err(BreakpointError::Misaligned)
+    );
+    let slots: Vec<usize> = (0..debug::HW_BREAKPOINTS)
+        .map(|_| debug::set_hw_breakpoint(watched as usize, BreakpointKind::Write(8)).unwrap())
+        .collect();
+    assert_eq!(
+        debug::set_hw_breakpoint(watched as usize, BreakpointKind::Write(8)),
+        Err(BreakpointError::NoFreeSlot)
+    );
+    for slot in slots {
+        debug::clear_hw_breakpoint(slot).unwrap();
+    }
+
+    printlnk!("Breakpoint test passed");
+}
+
+// Overflow the boot stack into its guard page. The page fault can't push its frame there either, which escalates to a
+// double fault, and its handler exits QEMU with QEMU_EXIT_DOUBLE_FAULT. Without an IST stack, that would be a triple
+// fault, and QEMU would reboot.
+#[cfg(feature = "test-double-fault")]
+fn test_double_fault() {
+    fn recurse(depth: usize) -> usize {
+        let frame = core::hint::black_box([depth; 64]);
+        if depth == usize::MAX {
+            return 0;
+        }
+        recurse(depth + 1) + frame[0]
+    }
+
+    printlnk!("Overflowing the kernel stack");
+    recurse(0);
+    panic!("The kernel stack never overflowed");
+}
+
+// Panic while holding the serial port of the console, as a panic in the middle of a print would. The panic handler
+// breaks the lock to print the panic, then exits QEMU with QEMU_EXIT_PANIC.
+#[cfg(feature = "test-panic-locked")]
+fn test_panic_locked() {
+    printlnk!("Panicking with the console locked");
+    output::with_serial(|_| panic!("Panicked with the console locked"));
+}
+
+// Yields and sleeps while a wave of user tasks is spawned and exits around it.
+fn churn_companion() -> ! {
+    unsafe {
+        for _ in 0..3 {
+            sched::yield_task();
+        }
+        sched::sleep_current(0);
+        sched::yield_task();
+        sched::exit_current(0)
+    }
+}
+
+fn test_spawn_churn() {
+    const WAVES: usize = 200;
+    const TASKS_PER_WAVE: usize = 8;
+
+    let run_wave = || unsafe {
+        for _ in 0..TASKS_PER_WAVE {
+            sched::add_new_task(TaskRef::new(Task::spawn(CHURN_BINARY, &["churn"]).unwrap()));
+        }
+        sched::add_new_task(TaskRef::new(Task::create_kernel_task(
+            churn_companion,
+            "companion",
+        )));
+        sched::begin_scheduler();
+    };
+
+    // Run once first, so the scheduler's queues and the slab caches have already grown
+    run_wave();
+
+    // Every wave must leave nothing behind: a leaked reference or kernel stack shows up in the counts
+    let live = unsafe { task_ref::LIVE_TASKS };
+    let pages = buddy::allocated_pages();
+    let objects = slab::allocated_objects();
+    for wave in 0..WAVES {
+        run_wave();
+        unsafe {
+            assert!(
+                sched::TASK_TABLE.is_empty(),
+                "wave {}: task table not empty",
+                wave
+            );
+            assert!(
+                sched::ZOMBIE_TASKS.is_empty(),
+                "wave {}: zombies not reaped",
+                wave
+            );
+            assert!(
+                sched::EXIT_STATUSES.is_empty(),
+                "wave {}: exit statuses kept",
+                wave
+            );
+            assert_eq!(task_ref::LIVE_TASKS, live, "wave {}: live tasks", wave);
+        }
+        assert_eq!(
+            buddy::allocated_pages(),
+            pages,
+            "wave {}: allocated pages",
+            wave
+        );
+        assert_eq!(
+            slab::allocated_objects(),
+            objects,
+            "wave {}: slab objects",
+            wave
+        );
+    }
+
+    printlnk!("Spawn churn test passed");
+}
+
+fn test_scheduler() {
+    // Create tasks
+    let tasks = [
+        load_task(ELF_BINARY),
+        load_task(ELF_BINARY),
+        // This one recurses forever, and should be killed with a stack overflow message
+        load_task(STACK_OVERFLOW_BINARY),
+        // This one writes to its BSS, which is only mapped when the write faults
+        load_task(BSS_BINARY),
+        // This one writes to its own text segment, and should be killed by the page fault
+        load_task(WRITE_TEXT_BINARY),
+        // This one checks its own unaligned data segment, and crashes if it was loaded wrong
+        load_task(UNALIGNED_BINARY),
+        // Same, for a program whose segments share pages
+        load_task(SHARED_PAGE_BINARY),
+        // This one prints its own memory map
+        load_task(MAPS_BINARY),
+        // This one is position-independent, and prints where it was loaded
+        load_task(PIE_BINARY),
+        // This one faults in crash_here, which should be named in the kill message
+        load_task(CRASH_BINARY),
+        // This one echoes its argv[1]
+        Task::spawn(ARGS_BINARY, &["args", "Hello from argv!"]).unwrap(),
+        // This one checks its thread-local variables
+        load_task(TLS_BINARY),
+        // This one checks its initialized data and its BSS
+        load_task(BSS_FILL_BINARY),
+    ];
+
+    unsafe {
+        // Move tasks to heap and add them to scheduler
+        for task in tasks {
+            sched::add_new_task(TaskRef::new(task));
+        }
+
+        // Begin scheduler (the first task should run first)
+        sched::begin_scheduler();
+    }
+}
+
+// Number of times each task yields in the context switch benchmark. Must match tests/yield.c.
+const BENCH_YIELDS: usize = 1_000_000;
+
+fn bench_yield_loop() -> ! {
+    for _ in 0..BENCH_YIELDS {
+        unsafe { sched::yield_task() };
+    }
+    unsafe { sched::exit_current(0) }
+}
+
+// Run the tasks until they all exit, and print the average cycles per switch as one line, like
+// "bench context_switch_kernel: 2000000 switches, 1234 cycles/switch".
+fn bench_switches(name: &str, tasks: [Task; 2]) {
+    let switches = BENCH_YIELDS * tasks.len();
+
+    let cycles = tsc::measure_cycles(|| unsafe {
+        for task in tasks {
+            sched::add_new_task(TaskRef::new(task));
+        }
+        sched::begin_scheduler();
+    });
+
+    printlnk!(
+        "bench {}: {} switches, {} cycles/switch",
+        name,
+        switches,
+        cycles / switches as u64
+    );
+}
+
+fn bench_context_switch() {
+    // Two kernel tasks ping-pong with yield_task(), which is the cost of switch_task() alone
+    bench_switches(
+        "context_switch_kernel",
+        [
+            Task::create_kernel_task(bench_yield_loop, "ping"),
+            Task::create_kernel_task(bench_yield_loop, "pong"),
+        ],
+    );
+
+    // Two user tasks bounce with sys_yield, which adds the syscall and the CR3 switch
+    bench_switches(
+        "context_switch_user",
+        [load_task(YIELD_BINARY), load_task(YIELD_BINARY)],
+    );
 }
@@ -0,0 +1,87 @@
+//! Timekeeping on the system tick, which the PIT keeps (see pit), and the time stamp counter.
+//!
+//! init() starts the PIT at TIMER_HZ, and registers what runs on every tick: the calibration of the TSC, the tasks
+//! waiting for the tick, then the scheduler.
+
+use crate::{
+    cpu::tsc::rdtsc,
+    idt::without_interrupt,
+    io::pit,
+    user::sched::{self, wait_queue::WaitQueue},
+};
+
+pub const TIMER_HZ: usize = 100; // Ticks per second
+pub const MS_PER_TICK: usize = 1000 / TIMER_HZ;
+pub const NS_PER_MS: u64 = 1_000_000;
+pub const NS_PER_TICK: u64 = MS_PER_TICK as u64 * NS_PER_MS;
+
+// Time stamp counter at the first tick, to measure the TSC frequency against the PIT.
+static mut TSC_AT_FIRST_TICK: u64 = 0;
+
+// Largest value monotonic_ns() has returned, so it never goes back when the TSC frequency estimate changes.
+static mut LAST_MONOTONIC_NS: u64 = 0;
+
+/// Tasks waiting for the next tick. The timer interrupt wakes all of them.
+pub static mut TICK_WAITERS: WaitQueue = WaitQueue::new();
+
+/// Start the PIT, firing TIMER_HZ times per second, and register the tick callbacks.
+///
+/// # Safety
+/// Must be called once, with interrupts disabled, after the IDT is set up.
+pub unsafe fn init() {
+    unsafe { pit::init(TIMER_HZ) };
+    for callback in [on_tick, sched::wake_sleepers, sched::timer_tick] {
+        pit::register_tick_callback(callback).unwrap();
+    }
+}
+
+// Tick callback: notes the TSC at the first tick, and wakes the tasks waiting for a tick.
+unsafe fn on_tick() {
+    unsafe {
+        if pit::ticks() == 1 {
+            TSC_AT_FIRST_TICK = rdtsc();
+        }
+        TICK_WAITERS.wake_all();
+    }
+}
+
+/// Number of ticks since the timer was started, see pit::ticks().
+pub fn ticks() -> usize {
+    pit::ticks() as usize
+}
+
+/// Milliseconds since the timer was started.
+pub fn uptime_ms() -> usize {
+    ticks() * MS_PER_TICK
+}
+
+/// Time stamp counter cycles per millisecond, measured against the timer. None until two ticks have passed.
+pub fn tsc_per_ms() -> Option<u64> {
+    let ticks = ticks();
+    if ticks < 2 {
+        return None;
+    }
+    let elapsed_ms = ((ticks - 1) * MS_PER_TICK) as u64;
+    Some((rdtsc() - unsafe { TSC_AT_FIRST_TICK }) / elapsed_ms)
+}
+
+/// Nanoseconds since the timer was started, interpolated between ticks with the time stamp counter once its frequency
+/// is known. Never goes back.
+pub fn monotonic_ns() -> u64 {
+    without_interrupt(|| unsafe {
+        let now = match tsc_per_ms() {
+            Some(per_ms) => {
+                let cycles = rdtsc().saturating_sub(TSC_AT_FIRST_TICK) as u128;
+                NS_PER_TICK + (cycles * NS_PER_MS as u128 / per_ms.max(1) as u128) as u64
+            }
+            None => ticks() as u64 * NS_PER_TICK,
+        };
+        LAST_MONOTONIC_NS = LAST_MONOTONIC_NS.max(now);
+        LAST_MONOTONIC_NS
+    })
+}
+
+/// Convert time stamp counter cycles to milliseconds. Returns 0 until the TSC frequency is known.
+pub fn cycles_to_ms(cycles: u64) -> u64 {
+    tsc_per_ms().map_or(0, |per_ms| cycles / per_ms.max(1))
+}
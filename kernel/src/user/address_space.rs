@@ -1,33 +1,96 @@
-use core::ptr::{copy_nonoverlapping, null_mut};
+use core::{
+    fmt,
+    ptr::{copy_nonoverlapping, write_bytes},
+};
 
 use alloc::vec::Vec;
 use arbitrary_int::traits::Integer;
+use bitvec::vec::BitVec;
 
 use crate::{
-    consts::{PAGE_SIZE, USERSPACE_LIMIT},
-    helper::{add_within_bounds, align_down, align_up, log2_ceil, p2v, v2p},
+    consts::{HUGE_PAGE_SIZE, PAGE_SIZE, USERSPACE_LIMIT},
+    helper::{add_within_bounds, align_down, align_up, log2_floor, p2v, v2p},
     mem::{
-        buddy::{alloc_pages_order_panic, alloc_pages_panic, free_pages, free_pages_order},
-        page_table::{
-            PageDirectory, PageDirectoryEntry, VirtAddr, resolve_virt_addr,
+        buddy::{self, alloc_pages, alloc_pages_order, free_pages, free_pages_order},
+        page_meta::{get_page, page_refcount, put_page},
+        paging::{
+            KERNEL_ADDRESS_SPACE, PageDirectory, PageDirectoryEntry, VirtAddr, dump_range,
+            flush_tlb_page, get_active_page_directory, resolve, resolve_virt_addr,
             set_active_page_directory,
         },
     },
-    user::{elf_parser::ElfParser, elf_structure::ElfProgramHeaderType},
+    printlnk,
+    user::{
+        elf_parser::{ElfError, ElfParser},
+        elf_structure::{ElfProgramHeaderType, ElfRelocationType},
+    },
 };
 
-pub static mut KERNEL_P4_TABLE: *mut PageDirectory = null_mut();
+/// Errors returned when changing the mappings of an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The range is empty, overflows or lies outside of userspace.
+    InvalidRange,
+    /// The range overlaps an existing region.
+    Overlap,
+    /// The range is not fully covered by existing regions.
+    NotMapped,
+    /// There is no free range large enough.
+    NoSpace,
+    /// The region would be both writable and executable (see `AddressSpace::set_allow_write_execute`).
+    WriteExecute,
+    /// There aren't enough free pages to back the region.
+    OutOfMemory,
+}
+
+/// Address where position-independent executables are loaded (their segments are offset by it).
+pub const PIE_LOAD_BASE: usize = 0x0000555555554000;
+
+/// Start of the window where the kernel places anonymous mappings.
+pub const MMAP_WINDOW_START: usize = 0x0000100000000000;
+/// End of the window where the kernel places anonymous mappings. Mappings are placed from the top down.
+pub const MMAP_WINDOW_END: usize = 0x00007f0000000000;
 
+// Regions only hold metadata. The physical pages backing a region are found by walking the page tables,
+// so a region can be split freely (e.g. when changing the permissions of part of it).
 #[derive(Debug)]
 pub struct VirtRegion {
     pub start: usize,
     pub len: usize,
     pub writable: bool,
     pub executable: bool,
+    pub guard: bool, // Guard regions are reserved but never mapped (e.g. below the user stack)
+    pub lazy: bool,  // Lazy regions are mapped a page at a time when first accessed
+    pub cow: bool,   // Pages of COW regions may be shared read-only with a forked address space
+    pub shared: bool, // Pages of shared regions stay shared, and writable, in a forked address space
+    pub name: Option<&'static str>, // What the region holds (e.g. "text", "stack"), shown by format_maps
+}
+
+impl VirtRegion {
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+}
+
+/// Accessed and dirty bits of a range of pages, one bit per page (see `AddressSpace::scan_and_clear_accessed`).
+/// Pages inside a huge page all report the bits of the huge page.
+#[derive(Debug)]
+pub struct AccessedBitmap {
+    pub start: usize,
+    pub accessed: BitVec<u64>,
+    pub dirty: BitVec<u64>,
+}
+
+impl AccessedBitmap {
+    /// Check if the page containing addr was accessed.
+    pub fn is_accessed(&self, addr: usize) -> bool {
+        self.accessed[(addr - self.start) / PAGE_SIZE]
+    }
 
-    // From buddy allocator
-    backing_pages: *mut u8,
-    backing_order: usize,
+    /// Check if the page containing addr was written to.
+    pub fn is_dirty(&self, addr: usize) -> bool {
+        self.dirty[(addr - self.start) / PAGE_SIZE]
+    }
 }
 
 // A userspace address space.
@@ -36,30 +99,69 @@ pub struct AddressSpace {
     pub(crate) p4_table: *mut PageDirectory,
     virt_regions: Vec<VirtRegion>,
     allocated_tables: Vec<*mut u8>,
+
+    mapped_pages: usize, // Number of (4 KiB) pages mapped by regions, shared pages included
+
+    // The heap starts right after the highest ELF segment, and grows with brk.
+    heap_base: usize,
+    heap_end: usize,
+
+    allow_write_execute: bool, // W^X is enforced unless this is set
 }
 
 impl AddressSpace {
     /// Create a new AddressSpace with a new P4 page table.
     pub fn new() -> Self {
+        Self::try_new().expect("Out of memory for a P4 table")
+    }
+
+    /// Create a new AddressSpace with a new P4 page table, or fail with OutOfMemory if there is no page for it.
+    pub fn try_new() -> Result<Self, MapError> {
         unsafe {
-            let p4_table = alloc_pages_panic(1) as *mut PageDirectory;
+            let p4_table = alloc_pages(1) as *mut PageDirectory;
+            if p4_table.is_null() {
+                return Err(MapError::OutOfMemory);
+            }
             p4_table.write_bytes(0, 1);
 
-            Self {
+            Ok(Self {
                 p4_table,
                 virt_regions: vec![],
                 allocated_tables: vec![p4_table as *mut u8],
-            }
+                mapped_pages: 0,
+                heap_base: 0,
+                heap_end: 0,
+                allow_write_execute: false,
+            })
+        }
+    }
+
+    /// Create an AddressSpace on the page tables of the kernel, for tasks that only run in kernel mode.
+    /// It has no user space regions, and doesn't own (or free) any page table.
+    pub fn kernel() -> Self {
+        Self {
+            p4_table: unsafe { KERNEL_ADDRESS_SPACE.p4_table() },
+            virt_regions: vec![],
+            allocated_tables: vec![],
+            mapped_pages: 0,
+            heap_base: 0,
+            heap_end: 0,
+            allow_write_execute: false,
         }
     }
 
+    /// Check if this is the address space of a kernel task (see kernel()).
+    pub fn is_kernel(&self) -> bool {
+        self.p4_table == unsafe { KERNEL_ADDRESS_SPACE.p4_table() }
+    }
+
     /// Map all kernel space pages.
     pub fn map_kernel_pages(&mut self) {
         unsafe {
-            assert!(!KERNEL_P4_TABLE.is_null());
+            let kernel_p4_table = KERNEL_ADDRESS_SPACE.p4_table();
 
             for i in 256..512 {
-                (*self.p4_table).0[i] = (*KERNEL_P4_TABLE).0[i];
+                (*self.p4_table).0[i] = (*kernel_p4_table).0[i];
             }
         }
     }
@@ -75,80 +177,896 @@ impl AddressSpace {
         unsafe { resolve_virt_addr(self.p4_table, virt_addr) }
     }
 
-    /// Test if a region does not overlap with existing regions and is within userspace bounds.
-    pub fn check_region_no_overlap(&self, start: usize, len: usize) -> bool {
-        // Forbid addresses not within USERSPACE_LIMIT. We block the first and last page in the userspace too.
-        if start == 0 {
+    /// Check if the range [start, start + len) is accessible from user mode, and writable if write is set.
+    /// Every page covered by the range is checked against the page tables.
+    pub fn check_user_range(&self, start: usize, len: usize, write: bool) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let Some(end) = add_within_bounds(start, len, USERSPACE_LIMIT) else {
             return false;
+        };
+
+        (align_down(start, PAGE_SIZE)..end)
+            .step_by(PAGE_SIZE)
+            .all(|page| match unsafe { resolve(self.p4_table, page) } {
+                Some(resolved) => resolved.user && (!write || resolved.writable),
+                None => false,
+            })
+    }
+
+    /// Print the user mappings of this address space (see `paging::dump_range`).
+    pub fn dump(&self) {
+        printlnk!("Address space {:p}:", self.p4_table);
+        unsafe { dump_range(self.p4_table, 0, USERSPACE_LIMIT) };
+    }
+
+    /// Get the number of pages mapped by regions of this address space.
+    pub fn mapped_pages(&self) -> usize {
+        self.mapped_pages
+    }
+
+    /// Get the heap range [heap_base, heap_end).
+    pub fn heap(&self) -> (usize, usize) {
+        (self.heap_base, self.heap_end)
+    }
+
+    /// Allow regions that are both writable and executable. By default such regions are rejected with
+    /// `MapError::WriteExecute`, this is only meant for the rare test that needs them.
+    pub fn set_allow_write_execute(&mut self, allow: bool) {
+        self.allow_write_execute = allow;
+    }
+
+    // Reject writable and executable regions, unless explicitly allowed.
+    fn check_write_execute(&self, writable: bool, executable: bool) -> Result<(), MapError> {
+        if writable && executable && !self.allow_write_execute {
+            return Err(MapError::WriteExecute);
         }
-        let Some(end) = add_within_bounds(start, len, USERSPACE_LIMIT - PAGE_SIZE) else {
+        Ok(())
+    }
+
+    /// Get the regions of this address space.
+    pub fn virt_regions(&self) -> &[VirtRegion] {
+        &self.virt_regions
+    }
+
+    /// Check if this address space is the active one.
+    pub fn is_active(&self) -> bool {
+        unsafe { get_active_page_directory() == self.p4_table }
+    }
+
+    /// Test if a region does not overlap with existing regions and is within userspace bounds.
+    pub fn check_region_no_overlap(&self, start: usize, len: usize) -> bool {
+        let Ok(end) = check_user_bounds(start, len) else {
             return false;
         };
 
         for region in &self.virt_regions {
-            let region_end = region.start + region.len;
-            if !(end <= region.start || start >= region_end) {
+            if !(end <= region.start || start >= region.end()) {
                 return false;
             }
         }
         true
     }
 
-    /// Add a virtual region. Returns the page pointer if successful. The pages will be zeroed.
+    /// Add a virtual region. The pages will be zeroed.
+    /// Backing pages are allocated separately, so the region doesn't need physically contiguous memory.
+    /// If lazy is set, nothing is mapped now and pages are allocated on first access (see `handle_lazy_fault`).
+    /// Otherwise, the region fails with OutOfMemory if there are too few free pages to back it, and nothing is left
+    /// mapped.
     pub fn add_virt_region(
         &mut self,
         start: usize,
         len: usize,
         writable: bool,
         executable: bool,
-    ) -> Result<*mut u8, ()> {
+        lazy: bool,
+    ) -> Result<(), MapError> {
         let start = align_down(start, PAGE_SIZE);
         let len = align_up(len, PAGE_SIZE);
 
+        check_user_bounds(start, len)?;
+        self.check_write_execute(writable, executable)?;
         if !self.check_region_no_overlap(start, len) {
-            return Err(());
+            return Err(MapError::Overlap);
         }
 
-        // Allocate some pages.
-        let num_order = log2_ceil(len / PAGE_SIZE);
-        let pages = unsafe { alloc_pages_order_panic(num_order) };
-        unsafe { pages.write_bytes(0, len) };
-
-        // Map pages.
-        for offset in (0..len).step_by(PAGE_SIZE) {
-            self.map_virt_addr(
-                start + offset,
-                v2p(unsafe { pages.add(offset) } as usize),
+        if lazy {
+            self.insert_region(VirtRegion {
+                start,
+                len,
                 writable,
                 executable,
-            );
+                guard: false,
+                lazy: true,
+                cow: false,
+                shared: false,
+                name: None,
+            });
+            return Ok(());
+        }
+
+        if pages_to_back(len) > buddy::page_counts().1 {
+            return Err(MapError::OutOfMemory);
+        }
+
+        // Allocate and map pages. Use 2 MiB pages wherever the virtual address allows it and a 2 MiB block is
+        // available, otherwise fall back to 4 KiB pages.
+        let mut offset = 0;
+        while offset < len {
+            let virt_addr = start + offset;
+
+            if len - offset >= HUGE_PAGE_SIZE
+                && virt_addr.is_multiple_of(HUGE_PAGE_SIZE)
+                && let Some(page) = self.try_map_huge_page(virt_addr, writable, executable)
+            {
+                for page_offset in (0..HUGE_PAGE_SIZE).step_by(PAGE_SIZE) {
+                    unsafe { get_page(page.add(page_offset)) };
+                }
+                self.mapped_pages += HUGE_PAGE_SIZE / PAGE_SIZE;
+                offset += HUGE_PAGE_SIZE;
+                continue;
+            }
+
+            let page = unsafe { alloc_pages_order(0) };
+            let mapped = if page.is_null() {
+                Err(MapError::OutOfMemory)
+            } else {
+                unsafe { page.write_bytes(0, PAGE_SIZE) };
+                self.map_virt_addr(virt_addr, v2p(page as usize), writable, executable)
+                    .inspect_err(|_| unsafe { free_pages_order(page, 0) })
+            };
+            if let Err(err) = mapped {
+                // Something else took the pages since the check. There is no region yet, so removing the range only
+                // releases the pages mapped so far, if there are any.
+                if offset > 0 {
+                    self.remove_range(start, offset)?;
+                }
+                return Err(err);
+            }
+            unsafe { get_page(page) };
+            self.mapped_pages += 1;
+            offset += PAGE_SIZE;
         }
 
         // Record region.
-        self.virt_regions.push(VirtRegion {
+        self.insert_region(VirtRegion {
             start,
             len,
             writable,
             executable,
+            guard: false,
+            lazy: false,
+            cow: false,
+            shared: false,
+            name: None,
+        });
+
+        Ok(())
+    }
+
+    // Allocate a zeroed 2 MiB block and map it at virt_addr (aligned to HUGE_PAGE_SIZE) with a huge page.
+    // Returns None (and allocates nothing) if there is no free 2 MiB block, no page for a page table, or the P2 entry
+    // is in use.
+    fn try_map_huge_page(
+        &mut self,
+        virt_addr: usize,
+        writable: bool,
+        executable: bool,
+    ) -> Option<*mut u8> {
+        let order = log2_floor(HUGE_PAGE_SIZE / PAGE_SIZE);
+        let page = unsafe { alloc_pages_order(order) };
+        if page.is_null() {
+            return None;
+        }
+
+        // Buddy blocks are aligned to their size, so the physical address is suitable for a huge page.
+        if self.map_huge_virt_addr(virt_addr, v2p(page as usize), writable, executable) != Ok(true)
+        {
+            unsafe { free_pages_order(page, order) };
+            return None;
+        }
+
+        unsafe { page.write_bytes(0, HUGE_PAGE_SIZE) };
+        Some(page)
+    }
+
+    /// Copy data into mapped memory starting at addr, ignoring page permissions.
+    /// The data is copied page by page through the direct mapping. Pages of lazy regions are populated as needed.
+    pub fn copy_into_region(&mut self, addr: usize, data: &[u8]) -> Result<(), MapError> {
+        let mut copied = 0;
+        while copied < data.len() {
+            let virt_addr = addr + copied;
+            if self.resolve_virt_addr(virt_addr).is_none() {
+                self.populate_lazy_page(virt_addr)?;
+            }
+            let phys_addr = self
+                .resolve_virt_addr(virt_addr)
+                .ok_or(MapError::NotMapped)?;
+            let count = (PAGE_SIZE - virt_addr % PAGE_SIZE).min(data.len() - copied);
+
+            unsafe {
+                copy_nonoverlapping(data.as_ptr().add(copied), p2v(phys_addr) as *mut u8, count)
+            };
+            copied += count;
+        }
+
+        Ok(())
+    }
+
+    /// Zero [addr, addr + len) in mapped memory, ignoring page permissions.
+    /// Pages of lazy regions that are not populated yet are skipped, they are zeroed when populated.
+    pub fn zero_region(&mut self, addr: usize, len: usize) -> Result<(), MapError> {
+        let end = add_within_bounds(addr, len, USERSPACE_LIMIT).ok_or(MapError::InvalidRange)?;
 
-            backing_pages: pages,
-            backing_order: num_order,
+        let mut virt_addr = addr;
+        while virt_addr < end {
+            let count = (PAGE_SIZE - virt_addr % PAGE_SIZE).min(end - virt_addr);
+            match self.resolve_virt_addr(virt_addr) {
+                Some(phys_addr) => unsafe { write_bytes(p2v(phys_addr) as *mut u8, 0, count) },
+                None if self.lazy_region_at(virt_addr).is_some() => {}
+                None => return Err(MapError::NotMapped),
+            }
+            virt_addr += count;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a page fault at addr caused by a not-present page, with write set if the access was a write.
+    /// If addr lies in a lazy region that allows the access, a zeroed page is mapped there and true is returned.
+    /// Returns false if the fault must be handled some other way, and OutOfMemory if there is no page to map.
+    pub fn handle_lazy_fault(&mut self, addr: usize, write: bool) -> Result<bool, MapError> {
+        let Some(region) = self.lazy_region_at(addr) else {
+            return Ok(false);
+        };
+        if write && !region.writable {
+            return Ok(false);
+        }
+        self.populate_lazy_page(addr)
+    }
+
+    // Get the lazy region containing addr, if any.
+    fn lazy_region_at(&self, addr: usize) -> Option<&VirtRegion> {
+        self.virt_regions
+            .iter()
+            .find(|region| region.lazy && region.start <= addr && addr < region.end())
+    }
+
+    // Map a zeroed page at addr if it lies in a lazy region and isn't mapped yet. Returns whether a page was mapped.
+    fn populate_lazy_page(&mut self, addr: usize) -> Result<bool, MapError> {
+        let Some(region) = self.lazy_region_at(addr) else {
+            return Ok(false);
+        };
+        let (writable, executable) = (region.writable, region.executable);
+        let virt_addr = align_down(addr, PAGE_SIZE);
+        if self.resolve_virt_addr(virt_addr).is_some() {
+            return Ok(false);
+        }
+
+        unsafe {
+            let page = alloc_pages_order(0);
+            if page.is_null() {
+                return Err(MapError::OutOfMemory);
+            }
+            page.write_bytes(0, PAGE_SIZE);
+            if let Err(err) =
+                self.map_virt_addr(virt_addr, v2p(page as usize), writable, executable)
+            {
+                free_pages_order(page, 0);
+                return Err(err);
+            }
+            get_page(page);
+        }
+        self.mapped_pages += 1;
+        Ok(true)
+    }
+
+    /// Handle a write fault at addr on a present page. If addr lies in a writable COW region, the page is made
+    /// writable again, copying it first if it is still shared, and true is returned.
+    /// Returns false if the fault must be handled some other way, and OutOfMemory if there is no page to copy to.
+    pub fn handle_cow_fault(&mut self, addr: usize) -> Result<bool, MapError> {
+        if !self.virt_regions.iter().any(|region| {
+            region.cow && region.writable && region.start <= addr && addr < region.end()
+        }) {
+            return Ok(false);
+        }
+        let Some((mut entry, page_size)) = (unsafe { self.leaf_entry(addr) }) else {
+            return Ok(false);
+        };
+        if unsafe { (*entry).writable() } {
+            return Ok(false);
+        }
+
+        unsafe {
+            // Only the faulting page is copied, so split a huge page first.
+            if page_size == HUGE_PAGE_SIZE {
+                self.split_huge_page(entry, align_down(addr, HUGE_PAGE_SIZE))?;
+                (entry, _) = self.leaf_entry(addr).unwrap();
+            }
+
+            // If no one else references the page, it can simply be made writable.
+            let page = p2v((*entry).addr() as usize) as *mut u8;
+            if page_refcount(page) > 1 {
+                let new_page = alloc_pages_order(0);
+                if new_page.is_null() {
+                    return Err(MapError::OutOfMemory);
+                }
+                copy_nonoverlapping(page, new_page, PAGE_SIZE);
+                get_page(new_page);
+                put_page(page);
+                (*entry).set_addr(v2p(new_page as usize) as u64);
+            }
+            (*entry).set_writable(true);
+
+            if self.is_active() {
+                flush_tlb_page(align_down(addr, PAGE_SIZE));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Duplicate this address space for fork. User pages are shared copy-on-write: both address spaces map them
+    /// read-only, and private regions are marked COW so the first write from either side copies the page. Read-only
+    /// regions are marked too, in case protect_region() makes them writable later. Pages of shared regions are mapped
+    /// as they are, so both sides keep seeing each other's writes.
+    /// Kernel pages are mapped as usual. Fails with OutOfMemory if there are no pages for the page tables of the child,
+    /// which is then dropped with everything it mapped.
+    pub fn fork(&mut self) -> Result<AddressSpace, MapError> {
+        let mut child = AddressSpace::try_new()?;
+        child.map_kernel_pages();
+
+        let is_active = self.is_active();
+        for region in &mut self.virt_regions {
+            region.cow |= !region.shared;
+        }
+
+        for region in &self.virt_regions {
+            // Recorded first, so that a child dropped halfway releases the pages it mapped
+            child.insert_region(VirtRegion {
+                start: region.start,
+                len: region.len,
+                writable: region.writable,
+                executable: region.executable,
+                guard: region.guard,
+                lazy: region.lazy,
+                cow: region.cow,
+                shared: region.shared,
+                name: region.name,
+            });
+
+            let mut addr = region.start;
+            while addr < region.end() {
+                let Some((entry, page_size)) = (unsafe { self.leaf_entry(addr) }) else {
+                    addr += PAGE_SIZE;
+                    continue;
+                };
+                let phys_addr = unsafe { (*entry).addr() } as usize & !(page_size - 1);
+
+                // Write protect the parent's mapping, unless the region is shared.
+                let writable = region.shared && region.writable;
+                if !writable {
+                    unsafe {
+                        (*entry).set_writable(false);
+                        if is_active {
+                            flush_tlb_page(addr);
+                        }
+                    }
+                }
+
+                child.map_existing_page(addr, phys_addr, page_size, writable, region.executable)?;
+                addr += page_size;
+            }
+        }
+
+        child.heap_base = self.heap_base;
+        child.heap_end = self.heap_end;
+        child.allow_write_execute = self.allow_write_execute;
+
+        Ok(child)
+    }
+
+    /// Map the backing pages of the region starting at start in other into this address space, at the same address.
+    /// The pages are shared (their reference counts are bumped) and mapped read-only.
+    pub fn map_shared(&mut self, other: &AddressSpace, start: usize) -> Result<(), MapError> {
+        let Some(region) = other
+            .virt_regions
+            .iter()
+            .find(|region| region.start == start)
+        else {
+            return Err(MapError::NotMapped);
+        };
+        if !self.check_region_no_overlap(region.start, region.len) {
+            return Err(MapError::Overlap);
+        }
+
+        // Recorded first, so that a failure can remove what was mapped with remove_range()
+        self.insert_region(VirtRegion {
+            start: region.start,
+            len: region.len,
+            writable: false,
+            executable: region.executable,
+            guard: region.guard,
+            lazy: false,
+            cow: false,
+            shared: false,
+            name: region.name,
         });
 
-        Ok(pages)
+        let mut addr = region.start;
+        while addr < region.end() {
+            let Some((entry, page_size)) = (unsafe { other.leaf_entry(addr) }) else {
+                addr += PAGE_SIZE;
+                continue;
+            };
+            let phys_addr = unsafe { (*entry).addr() } as usize & !(page_size - 1);
+
+            if let Err(err) =
+                self.map_existing_page(addr, phys_addr, page_size, false, region.executable)
+            {
+                self.remove_range(region.start, region.len)?;
+                return Err(err);
+            }
+            addr += page_size;
+        }
+
+        Ok(())
+    }
+
+    /// Reserve a guard region. Nothing is mapped there, and no other region can be added on top of it.
+    pub fn add_guard_region(&mut self, start: usize, len: usize) -> Result<(), MapError> {
+        let start = align_down(start, PAGE_SIZE);
+        let len = align_up(len, PAGE_SIZE);
+
+        check_user_bounds(start, len)?;
+        if !self.check_region_no_overlap(start, len) {
+            return Err(MapError::Overlap);
+        }
+
+        self.insert_region(VirtRegion {
+            start,
+            len,
+            writable: false,
+            executable: false,
+            guard: true,
+            lazy: false,
+            cow: false,
+            shared: false,
+            name: Some("guard"),
+        });
+        Ok(())
+    }
+
+    /// Name the regions inside [start, start + len), e.g. "text" or "stack".
+    pub fn set_region_name(&mut self, start: usize, len: usize, name: &'static str) {
+        let end = start.saturating_add(len);
+        for region in &mut self.virt_regions {
+            if region.start >= start && region.end() <= end {
+                region.name = Some(name);
+            }
+        }
+    }
+
+    /// Write the regions of this address space, one line per region, in the style of /proc/self/maps:
+    /// the range, the permissions and the name of the region. Guard regions have no permissions.
+    pub fn format_maps(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        // Regions are kept sorted by start address, so the lines are too.
+        for region in &self.virt_regions {
+            let readable = !region.guard;
+            writeln!(
+                out,
+                "{:016x}-{:016x} {}{}{} {}",
+                region.start,
+                region.end(),
+                if readable { 'r' } else { '-' },
+                if region.writable { 'w' } else { '-' },
+                if region.executable { 'x' } else { '-' },
+                region.name.unwrap_or(""),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Get the guard region containing addr, if any.
+    pub fn guard_region_at(&self, addr: usize) -> Option<&VirtRegion> {
+        self.virt_regions
+            .iter()
+            .find(|region| region.guard && region.start <= addr && addr < region.end())
+    }
+
+    /// Collect the accessed and dirty bits of the pages in a range, and clear them.
+    /// Unmapped pages are reported as neither accessed nor dirty. Every page the range touches is scanned.
+    pub fn scan_and_clear_accessed(&mut self, start: usize, len: usize) -> AccessedBitmap {
+        let end = align_up(start.saturating_add(len), PAGE_SIZE);
+        let start = align_down(start, PAGE_SIZE);
+        let num_pages = (end - start) / PAGE_SIZE;
+        let mut bitmap = AccessedBitmap {
+            start,
+            accessed: BitVec::repeat(false, num_pages),
+            dirty: BitVec::repeat(false, num_pages),
+        };
+
+        // If the address space isn't active, its TLB entries are flushed by the CR3 switch anyway.
+        let is_active = self.is_active();
+        let mut i = 0;
+        while i < num_pages {
+            let addr = start + i * PAGE_SIZE;
+            let Some((entry, page_size)) = (unsafe { self.leaf_entry(addr) }) else {
+                i += 1;
+                continue;
+            };
+
+            unsafe {
+                let (accessed, dirty) = ((*entry).accessed(), (*entry).dirty());
+                (*entry).set_accessed(false);
+                (*entry).set_dirty(false);
+
+                if is_active {
+                    flush_tlb_page(addr);
+                }
+
+                // A huge page may start before the range, only count the pages inside it.
+                let page_end = align_down(addr, page_size) + page_size;
+                let count = ((page_end - addr) / PAGE_SIZE).min(num_pages - i);
+                bitmap.accessed[i..i + count].fill(accessed);
+                bitmap.dirty[i..i + count].fill(dirty);
+                i += count;
+            }
+        }
+
+        bitmap
+    }
+
+    /// Change the permissions of an existing range. The range must be fully covered by regions.
+    /// Regions that are only partially covered are split, and so are huge pages.
+    pub fn protect_region(
+        &mut self,
+        start: usize,
+        len: usize,
+        writable: bool,
+        executable: bool,
+    ) -> Result<(), MapError> {
+        let end = align_up(
+            start.checked_add(len).ok_or(MapError::InvalidRange)?,
+            PAGE_SIZE,
+        );
+        let start = align_down(start, PAGE_SIZE);
+        if start == end {
+            return Err(MapError::InvalidRange);
+        }
+        self.check_write_execute(writable, executable)?;
+
+        // The whole range must be covered by regions (regions never overlap each other).
+        let covered: usize = self
+            .virt_regions
+            .iter()
+            .map(|region| {
+                region
+                    .end()
+                    .min(end)
+                    .saturating_sub(region.start.max(start))
+            })
+            .sum();
+        if covered != end - start {
+            return Err(MapError::NotMapped);
+        }
+
+        // Update region bookkeeping.
+        self.split_region_at(start);
+        self.split_region_at(end);
+        for region in &mut self.virt_regions {
+            if region.start >= start && region.end() <= end {
+                region.writable = writable;
+                region.executable = executable;
+            }
+        }
+
+        // Update page table entries.
+        let is_active = self.is_active();
+        let mut addr = start;
+        while addr < end {
+            let Some((entry, page_size)) = (unsafe { self.leaf_entry(addr) }) else {
+                addr += PAGE_SIZE;
+                continue;
+            };
+
+            if page_size == HUGE_PAGE_SIZE
+                && (!addr.is_multiple_of(HUGE_PAGE_SIZE) || end - addr < HUGE_PAGE_SIZE)
+            {
+                // Only part of the huge page is changed, split it and look again.
+                unsafe { self.split_huge_page(entry, align_down(addr, HUGE_PAGE_SIZE)) }?;
+                continue;
+            }
+
+            // Pages of COW regions stay read-only, they are made writable again when written to.
+            let cow = self
+                .virt_regions
+                .iter()
+                .any(|region| region.cow && region.start <= addr && addr < region.end());
+
+            unsafe {
+                (*entry).set_writable(writable && !cow);
+                (*entry).set_execute_disable(!executable);
+
+                if is_active {
+                    flush_tlb_page(addr);
+                }
+            }
+            addr += page_size;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the region starting exactly at start, unmapping it and releasing its backing pages.
+    pub fn remove_virt_region(&mut self, start: usize) -> Result<(), MapError> {
+        let Some(region) = self
+            .virt_regions
+            .iter()
+            .find(|region| region.start == start)
+        else {
+            return Err(MapError::NotMapped);
+        };
+        self.remove_range(region.start, region.len)
+    }
+
+    /// Unmap a range and release its backing pages. Regions that are only partially covered are split,
+    /// and so are huge pages. Parts of the range that are not mapped are ignored.
+    /// Page tables are kept until the address space is dropped.
+    pub fn remove_range(&mut self, start: usize, len: usize) -> Result<(), MapError> {
+        let end = align_up(
+            start.checked_add(len).ok_or(MapError::InvalidRange)?,
+            PAGE_SIZE,
+        );
+        let start = align_down(start, PAGE_SIZE);
+        check_user_bounds(start, end - start)?;
+
+        // Update region bookkeeping.
+        self.split_region_at(start);
+        self.split_region_at(end);
+        self.virt_regions
+            .retain(|region| region.end() <= start || region.start >= end);
+
+        // Clear page table entries and release the pages.
+        let is_active = self.is_active();
+        let mut addr = start;
+        while addr < end {
+            let Some((entry, page_size)) = (unsafe { self.leaf_entry(addr) }) else {
+                addr += PAGE_SIZE;
+                continue;
+            };
+
+            if page_size == HUGE_PAGE_SIZE
+                && (!addr.is_multiple_of(HUGE_PAGE_SIZE) || end - addr < HUGE_PAGE_SIZE)
+            {
+                // Only part of the huge page is removed, split it and look again.
+                unsafe { self.split_huge_page(entry, align_down(addr, HUGE_PAGE_SIZE)) }?;
+                continue;
+            }
+
+            unsafe {
+                let page = p2v((*entry).addr() as usize & !(page_size - 1)) as *mut u8;
+                *entry = PageDirectoryEntry::ZERO;
+
+                if is_active {
+                    flush_tlb_page(addr);
+                }
+
+                for offset in (0..page_size).step_by(PAGE_SIZE) {
+                    put_page(page.add(offset));
+                }
+            }
+            self.mapped_pages -= page_size / PAGE_SIZE;
+            addr += page_size;
+        }
+
+        Ok(())
+    }
+
+    /// Move the end of the heap to new_end, mapping zeroed pages or unmapping pages as needed.
+    /// Returns the new end of the heap. On failure, e.g. OutOfMemory, the heap is left as it was.
+    pub fn grow_heap(&mut self, new_end: usize) -> Result<usize, MapError> {
+        if self.heap_base == 0 || new_end < self.heap_base {
+            return Err(MapError::InvalidRange);
+        }
+
+        let old_top = align_up(self.heap_end, PAGE_SIZE);
+        let new_top = align_up(new_end, PAGE_SIZE);
+        if new_top > old_top {
+            // This fails if the heap would run into another region (e.g. the stack) or out of userspace.
+            self.add_virt_region(old_top, new_top - old_top, true, false, false)?;
+            self.set_region_name(old_top, new_top - old_top, "heap");
+        } else if new_top < old_top {
+            self.remove_range(new_top, old_top - new_top)?;
+        }
+
+        self.heap_end = new_end;
+        Ok(new_end)
+    }
+
+    /// Find a free range of len bytes aligned to align (a power of two) inside the mmap window.
+    /// The highest suitable range is returned.
+    pub fn find_free_region(&self, len: usize, align: usize) -> Option<usize> {
+        if len == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let len = len.checked_next_multiple_of(PAGE_SIZE)?;
+        let align = align.max(PAGE_SIZE);
+
+        // Place the range as high as possible inside the gap [gap_start, gap_end).
+        let fit = |gap_start: usize, gap_end: usize| {
+            let gap_start = gap_start.max(MMAP_WINDOW_START);
+            let addr = align_down(gap_end.checked_sub(len)?, align);
+            (addr >= gap_start).then_some(addr)
+        };
+
+        // Regions are sorted, so walk the gaps between them from the top down.
+        let mut gap_end = MMAP_WINDOW_END;
+        for region in self.virt_regions.iter().rev() {
+            if region.end() <= gap_end
+                && let Some(addr) = fit(region.end(), gap_end)
+            {
+                return Some(addr);
+            }
+            gap_end = gap_end.min(region.start);
+            if gap_end <= MMAP_WINDOW_START {
+                return None;
+            }
+        }
+        fit(MMAP_WINDOW_START, gap_end)
+    }
+
+    /// Map an anonymous (zeroed) region at an address chosen by the kernel. Returns the start of the region.
+    pub fn map_anonymous(&mut self, len: usize, writable: bool) -> Result<usize, MapError> {
+        if len == 0 {
+            return Err(MapError::InvalidRange);
+        }
+        let start = self
+            .find_free_region(len, PAGE_SIZE)
+            .ok_or(MapError::NoSpace)?;
+        self.add_virt_region(start, len, writable, false, false)?;
+        self.set_region_name(start, len, "mmap");
+        Ok(start)
+    }
+
+    /// Map an anonymous region like map_anonymous(), which stays shared with forked address spaces instead of being
+    /// copied on write.
+    pub fn map_anonymous_shared(&mut self, len: usize, writable: bool) -> Result<usize, MapError> {
+        let start = self.map_anonymous(len, writable)?;
+        for region in &mut self.virt_regions {
+            if region.start >= start && region.end() <= start + align_up(len, PAGE_SIZE) {
+                region.shared = true;
+            }
+        }
+        Ok(start)
+    }
+
+    // Insert a region, keeping the regions sorted by start address.
+    fn insert_region(&mut self, region: VirtRegion) {
+        let index = self
+            .virt_regions
+            .partition_point(|other| other.start < region.start);
+        self.virt_regions.insert(index, region);
+    }
+
+    // Split the region containing addr (if any) into two regions, so that a region starts at addr.
+    fn split_region_at(&mut self, addr: usize) {
+        let Some(index) = self
+            .virt_regions
+            .iter()
+            .position(|region| region.start < addr && addr < region.end())
+        else {
+            return;
+        };
+
+        let region = &mut self.virt_regions[index];
+        let tail = VirtRegion {
+            start: addr,
+            len: region.end() - addr,
+            writable: region.writable,
+            executable: region.executable,
+            guard: region.guard,
+            lazy: region.lazy,
+            cow: region.cow,
+            shared: region.shared,
+            name: region.name,
+        };
+        region.len = addr - region.start;
+        self.virt_regions.insert(index + 1, tail);
+    }
+
+    // Find the leaf page table entry mapping a virtual address, along with the size of the page it maps.
+    // Returns None if the address is not mapped.
+    pub(crate) unsafe fn leaf_entry(
+        &self,
+        virt_addr: usize,
+    ) -> Option<(*mut PageDirectoryEntry, usize)> {
+        let virt_addr = VirtAddr::new_with_raw_value(virt_addr as u64);
+
+        unsafe {
+            let p4_entry = (*self.p4_table).0[virt_addr.p4_index().as_usize()];
+            if !p4_entry.present() {
+                return None;
+            }
+
+            let p3_table = p2v(p4_entry.addr() as usize) as *mut PageDirectory;
+            let p3_entry = (*p3_table).0[virt_addr.p3_index().as_usize()];
+            if !p3_entry.present() {
+                return None;
+            }
+
+            let p2_table = p2v(p3_entry.addr() as usize) as *mut PageDirectory;
+            let p2_entry = &raw mut (*p2_table).0[virt_addr.p2_index().as_usize()];
+            if !(*p2_entry).present() {
+                return None;
+            }
+            if (*p2_entry).page_size() {
+                return Some((p2_entry, HUGE_PAGE_SIZE));
+            }
+
+            let p1_table = p2v((*p2_entry).addr() as usize) as *mut PageDirectory;
+            let p1_entry = &raw mut (*p1_table).0[virt_addr.p1_index().as_usize()];
+            if !(*p1_entry).present() {
+                return None;
+            }
+            Some((p1_entry, PAGE_SIZE))
+        }
+    }
+
+    // Split the huge page mapped by a P2 entry into 512 pages with the same permissions.
+    // virt_addr is the (2 MiB aligned) virtual address that the huge page maps. Fails if there is no page for the P1
+    // table, leaving the huge page as it was.
+    unsafe fn split_huge_page(
+        &mut self,
+        p2_entry: *mut PageDirectoryEntry,
+        virt_addr: usize,
+    ) -> Result<(), MapError> {
+        unsafe {
+            let huge_entry = *p2_entry;
+            let phys_addr = huge_entry.addr() as usize & !(HUGE_PAGE_SIZE - 1);
+
+            let p1_table = alloc_pages(1) as *mut PageDirectory;
+            if p1_table.is_null() {
+                return Err(MapError::OutOfMemory);
+            }
+            self.allocated_tables.push(p1_table as *mut u8);
+
+            for (i, p1_entry) in (*p1_table).0.iter_mut().enumerate() {
+                *p1_entry = huge_entry
+                    .with_page_size(false)
+                    .with_addr((phys_addr + i * PAGE_SIZE) as u64);
+            }
+
+            *p2_entry = PageDirectoryEntry::ZERO
+                .with_present(true)
+                .with_writable(true)
+                .with_user_accessible(true)
+                .with_addr(v2p(p1_table as usize) as u64);
+
+            if self.is_active() {
+                flush_tlb_page(virt_addr);
+            }
+        }
+        Ok(())
     }
 
+    // Get the table the entry at index points to, creating it if there is none. Fails if there is no page for it.
     unsafe fn get_or_create_page_table(
         &mut self,
         page_table: *mut PageDirectory,
         index: usize,
-    ) -> *mut PageDirectory {
+    ) -> Result<*mut PageDirectory, MapError> {
         unsafe {
             let entry = (*page_table).0[index];
             if entry.present() {
-                p2v(entry.addr() as usize) as *mut PageDirectory
+                // Never descend into a huge page
+                debug_assert!(!entry.page_size());
+                Ok(p2v(entry.addr() as usize) as *mut PageDirectory)
             } else {
-                let new_table = alloc_pages_panic(1) as *mut PageDirectory;
+                let new_table = alloc_pages(1) as *mut PageDirectory;
+                if new_table.is_null() {
+                    return Err(MapError::OutOfMemory);
+                }
                 self.allocated_tables.push(new_table as *mut u8);
                 new_table.write_bytes(0, 1);
 
@@ -159,26 +1077,29 @@ impl AddressSpace {
                     .with_addr(v2p(new_table as usize) as u64);
                 (*page_table).0[index] = new_entry;
 
-                new_table
+                Ok(new_table)
             }
         }
     }
 
-    // Map a virtual address (aligned to PAGE_SIZE) to a physical address.
+    // Map a virtual address (aligned to PAGE_SIZE) to a physical address. Fails if there is no page for a page table,
+    // in which case nothing is mapped.
     fn map_virt_addr(
         &mut self,
         virt_addr: usize,
         phys_addr: usize,
         writable: bool,
         executable: bool,
-    ) {
+    ) -> Result<(), MapError> {
         let virt_addr = VirtAddr::new_with_raw_value(virt_addr as u64);
 
         unsafe {
             let p3_table =
-                self.get_or_create_page_table(self.p4_table, virt_addr.p4_index().as_usize());
-            let p2_table = self.get_or_create_page_table(p3_table, virt_addr.p3_index().as_usize());
-            let p1_table = self.get_or_create_page_table(p2_table, virt_addr.p2_index().as_usize());
+                self.get_or_create_page_table(self.p4_table, virt_addr.p4_index().as_usize())?;
+            let p2_table =
+                self.get_or_create_page_table(p3_table, virt_addr.p3_index().as_usize())?;
+            let p1_table =
+                self.get_or_create_page_table(p2_table, virt_addr.p2_index().as_usize())?;
 
             let p1_entry = PageDirectoryEntry::ZERO
                 .with_present(true)
@@ -188,6 +1109,72 @@ impl AddressSpace {
                 .with_addr(phys_addr as u64);
             (*p1_table).0[virt_addr.p1_index().as_usize()] = p1_entry;
         }
+        Ok(())
+    }
+
+    // Map a virtual address (aligned to HUGE_PAGE_SIZE) to a physical address (aligned to HUGE_PAGE_SIZE) using a 2 MiB page.
+    // Returns false if the P2 entry is already in use (e.g. it points to a page table), in which case nothing is mapped.
+    // Fails if there is no page for a page table.
+    fn map_huge_virt_addr(
+        &mut self,
+        virt_addr: usize,
+        phys_addr: usize,
+        writable: bool,
+        executable: bool,
+    ) -> Result<bool, MapError> {
+        let virt_addr = VirtAddr::new_with_raw_value(virt_addr as u64);
+
+        unsafe {
+            let p3_table =
+                self.get_or_create_page_table(self.p4_table, virt_addr.p4_index().as_usize())?;
+            let p2_table =
+                self.get_or_create_page_table(p3_table, virt_addr.p3_index().as_usize())?;
+
+            let p2_entry = &mut (*p2_table).0[virt_addr.p2_index().as_usize()];
+            if p2_entry.present() {
+                return Ok(false);
+            }
+
+            *p2_entry = PageDirectoryEntry::ZERO
+                .with_present(true)
+                .with_writable(writable)
+                .with_user_accessible(true)
+                .with_page_size(true)
+                .with_execute_disable(!executable)
+                .with_addr(phys_addr as u64);
+        }
+
+        Ok(true)
+    }
+
+    // Map page_size bytes at phys_addr, a page another address space maps too, at addr, and take a reference to each
+    // 4 KiB page of it. A huge page falls back to small pages if the P2 entry is already in use. Each page is only
+    // referenced once it is mapped, so dropping the address space after a failure releases exactly what it mapped.
+    fn map_existing_page(
+        &mut self,
+        addr: usize,
+        phys_addr: usize,
+        page_size: usize,
+        writable: bool,
+        executable: bool,
+    ) -> Result<(), MapError> {
+        let page = p2v(phys_addr) as *mut u8;
+        if page_size == HUGE_PAGE_SIZE
+            && self.map_huge_virt_addr(addr, phys_addr, writable, executable)?
+        {
+            for offset in (0..page_size).step_by(PAGE_SIZE) {
+                unsafe { get_page(page.add(offset)) };
+            }
+            self.mapped_pages += page_size / PAGE_SIZE;
+            return Ok(());
+        }
+
+        for offset in (0..page_size).step_by(PAGE_SIZE) {
+            self.map_virt_addr(addr + offset, phys_addr + offset, writable, executable)?;
+            unsafe { get_page(page.add(offset)) };
+            self.mapped_pages += 1;
+        }
+        Ok(())
     }
 
     /// Switch to this address space.
@@ -195,8 +1182,21 @@ impl AddressSpace {
         unsafe { set_active_page_directory(self.p4_table) };
     }
 
-    /// Map ELF segments into the address space.
-    pub fn map_elf_segments(&mut self, parser: &ElfParser) -> Result<(), ()> {
+    /// Map the loadable segments of the ELF file and apply its relocations. Returns the load base, which is added to
+    /// every address of the file (0 unless it is position-independent). Consecutive segments may share a page, which
+    /// is mapped once with the union of the permissions of the segments in it, so a page holding both writable and
+    /// executable segments is rejected by W^X.
+    pub fn map_elf_segments(&mut self, parser: &ElfParser) -> Result<usize, ElfError> {
+        let base = if parser.is_position_independent() {
+            PIE_LOAD_BASE
+        } else {
+            0
+        };
+
+        // End of the previous segment, and of its last page (segments are sorted by address).
+        let mut prev_data_end = 0;
+        let mut prev_end = 0;
+
         for i in 0..parser.get_header().e_phnum as usize {
             let ph = parser.get_program_header(i)?;
 
@@ -206,47 +1206,156 @@ impl AddressSpace {
 
             let mem_size = ph.p_memsz as usize;
             let file_size = ph.p_filesz as usize;
-            let vaddr = ph.p_vaddr as usize;
             let offset = ph.p_offset as usize;
 
             let writable = (ph.p_flags & 0x2) != 0;
             let executable = (ph.p_flags & 0x1) != 0;
 
             // We check safety first
-            add_within_bounds(offset, file_size, parser.get_buf().len()).ok_or(())?;
+            let out_of_bounds = ElfError::SegmentOutOfBounds { index: i };
+            add_within_bounds(offset, file_size, parser.get_buf().len()).ok_or(out_of_bounds)?;
             if file_size > mem_size {
-                return Err(());
+                return Err(out_of_bounds);
             }
+            let vaddr = (ph.p_vaddr as usize)
+                .checked_add(base)
+                .ok_or(out_of_bounds)?;
 
-            // Create the virtual region
-            let region = self.add_virt_region(vaddr, mem_size, writable, executable)?;
+            // Create the virtual regions, then copy the segment from the ELF file to memory.
+            // add_virt_region rejects segments that are both writable and executable.
+            // Pages holding file data are mapped now. The rest of the segment (BSS) is zero-filled on demand.
+            // A segment may start in the middle of a page: the regions start at the page boundary below vaddr, and
+            // the data is copied to vaddr itself, so it keeps its offset within the page whatever p_offset is.
+            let mut start = align_down(vaddr, PAGE_SIZE);
+            let mem_end = align_up(
+                add_within_bounds(vaddr, mem_size, USERSPACE_LIMIT).ok_or(out_of_bounds)?,
+                PAGE_SIZE,
+            );
 
-            unsafe {
-                // Copy the segment from the ELF file to memory
-                // Additional memory are already zeroed by add_virt_region
-                // Safety is checked above, so this *should* be safe
-                copy_nonoverlapping(
-                    parser.get_buf().as_ptr().add(offset),
-                    region as *mut u8,
-                    file_size,
-                );
+            // The first page is shared with the previous segment. It is already mapped (or lazy) and zeroed apart
+            // from the previous segment's data, so only widen its permissions.
+            if start < prev_end {
+                if vaddr < prev_data_end {
+                    return Err(ElfError::OverlappingSegment { index: i });
+                }
+                let region = self
+                    .virt_regions
+                    .iter()
+                    .find(|region| region.start <= start && start < region.end())
+                    .ok_or(ElfError::OverlappingSegment { index: i })?;
+                let shared_writable = region.writable || writable;
+                let shared_executable = region.executable || executable;
+                if (shared_writable, shared_executable) != (region.writable, region.executable) {
+                    self.protect_region(start, PAGE_SIZE, shared_writable, shared_executable)
+                        .map_err(|err| segment_error(err, i))?;
+                }
+                start = prev_end;
             }
+
+            let file_end = align_up(vaddr + file_size, PAGE_SIZE).max(start);
+            let name = if executable {
+                "text"
+            } else if writable {
+                "data"
+            } else {
+                "rodata"
+            };
+            if file_end > start {
+                self.add_virt_region(start, file_end - start, writable, executable, false)
+                    .map_err(|err| segment_error(err, i))?;
+                self.set_region_name(start, file_end - start, name);
+            }
+            if mem_end > file_end {
+                self.add_virt_region(file_end, mem_end - file_end, writable, executable, true)
+                    .map_err(|err| segment_error(err, i))?;
+                self.set_region_name(file_end, mem_end - file_end, "bss");
+            }
+            self.copy_into_region(vaddr, &parser.get_buf()[offset..offset + file_size])
+                .map_err(|err| segment_error(err, i))?;
+            // Zero the rest of the segment explicitly rather than relying on how its pages were obtained. If it ends
+            // in the first page of the next segment, that segment is copied afterwards, so its data is kept.
+            self.zero_region(vaddr + file_size, mem_size - file_size)
+                .map_err(|err| segment_error(err, i))?;
+
+            prev_data_end = vaddr + mem_size;
+            prev_end = prev_end.max(mem_end);
+            self.heap_base = self.heap_base.max(mem_end);
         }
+        self.heap_end = self.heap_base;
 
-        Ok(())
+        // Only relative relocations are supported, which is all a static PIE has: the load base plus an addend.
+        for rela in parser.relocations()? {
+            let r_type = rela.r_type();
+            let unsupported = ElfError::UnsupportedRelocation { r_type: r_type.0 };
+            match r_type {
+                ElfRelocationType::None => {}
+                ElfRelocationType::Relative => {
+                    let value = base.wrapping_add_signed(rela.r_addend as isize);
+                    // Kernel pages are mapped too, so keep the write inside userspace.
+                    let addr = (rela.r_offset as usize)
+                        .checked_add(base)
+                        .filter(|&addr| add_within_bounds(addr, 8, USERSPACE_LIMIT).is_some())
+                        .ok_or(unsupported)?;
+                    self.copy_into_region(addr, &value.to_le_bytes())
+                        .map_err(|_| unsupported)?;
+                }
+                _ => return Err(unsupported),
+            }
+        }
+
+        Ok(base)
     }
 }
 
 impl Drop for AddressSpace {
     fn drop(&mut self) {
+        // Release backing pages. This walks the page tables, so it must happen before freeing them.
+        for region in &self.virt_regions {
+            let mut addr = region.start;
+            while addr < region.end() {
+                let Some((entry, page_size)) = (unsafe { self.leaf_entry(addr) }) else {
+                    addr += PAGE_SIZE;
+                    continue;
+                };
+
+                // Pages may be shared with other address spaces, so only drop our references.
+                let page = p2v(unsafe { (*entry).addr() } as usize & !(page_size - 1)) as *mut u8;
+                for offset in (0..page_size).step_by(PAGE_SIZE) {
+                    unsafe { put_page(page.add(offset)) };
+                }
+                addr += page_size;
+            }
+        }
+
         // Deallocate page tables.
         for &table in &self.allocated_tables {
             unsafe { free_pages(table, 1) };
         }
+    }
+}
 
-        // Deallocate backing pages.
-        for region in &self.virt_regions {
-            unsafe { free_pages_order(region.backing_pages, region.backing_order) };
-        }
+// Describe a failure to map the segment with the given index.
+fn segment_error(err: MapError, index: usize) -> ElfError {
+    match err {
+        MapError::InvalidRange => ElfError::SegmentOutOfBounds { index },
+        MapError::Overlap => ElfError::OverlappingSegment { index },
+        MapError::WriteExecute => ElfError::WriteExecute { index },
+        MapError::NotMapped | MapError::NoSpace | MapError::OutOfMemory => ElfError::OomMapping,
+    }
+}
+
+// Most free pages it can take to back len bytes: a page for every 4 KiB, and the page tables to map them, which are a
+// P1 table for every 2 MiB and a P2 and P3 table.
+fn pages_to_back(len: usize) -> usize {
+    let pages = len.div_ceil(PAGE_SIZE);
+    pages + pages.div_ceil(HUGE_PAGE_SIZE / PAGE_SIZE) + 2
+}
+
+// Check a range lies within userspace bounds, returning the end of the range.
+fn check_user_bounds(start: usize, len: usize) -> Result<usize, MapError> {
+    // Forbid addresses not within USERSPACE_LIMIT. We block the first and last page in the userspace too.
+    if start == 0 || len == 0 {
+        return Err(MapError::InvalidRange);
     }
+    add_within_bounds(start, len, USERSPACE_LIMIT - PAGE_SIZE).ok_or(MapError::InvalidRange)
 }
@@ -0,0 +1,272 @@
+//! File descriptors, and the pipes they can refer to.
+//!
+//! Every task has an FdTable, which maps its file descriptors to open files. Fork copies the table, so the parent and
+//! the child share the files, and spawned tasks get a copy of the table of their parent.
+
+use core::cell::UnsafeCell;
+
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+
+use crate::{
+    idt::without_interrupt,
+    user::{sched::wait_queue::WaitQueue, signal, syscall::errno::Errno},
+};
+
+/// Most file descriptors a task can have open at once.
+pub const MAX_FDS: usize = 64;
+
+/// Bytes a pipe holds before writers block.
+pub const PIPE_SIZE: usize = 4096;
+
+/// Number of pipes that are not freed yet, for statistics.
+pub static mut LIVE_PIPES: usize = 0;
+
+/// An open file. Cloning it opens the same file again.
+#[derive(Debug, Clone)]
+pub enum File {
+    ConsoleIn,  // The serial console, for reading
+    ConsoleOut, // The kernel console, for writing
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+}
+
+/// The file descriptors of a task. A descriptor is an index into the table.
+#[derive(Debug, Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<File>>,
+}
+
+impl FdTable {
+    /// A table without any open file.
+    pub const fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    /// A table with the console open as stdin (0), stdout (1) and stderr (2).
+    pub fn console() -> Self {
+        FdTable {
+            files: vec![
+                Some(File::ConsoleIn),
+                Some(File::ConsoleOut),
+                Some(File::ConsoleOut),
+            ],
+        }
+    }
+
+    /// Open the file at the lowest free descriptor, and return the descriptor. Fails with EMFILE if MAX_FDS files are
+    /// open already.
+    pub fn insert(&mut self, file: File) -> Result<usize, Errno> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() == MAX_FDS {
+            return Err(Errno::EMFILE);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    /// Open the file behind the descriptor again, so it stays open while a syscall uses it without the table.
+    pub fn get(&self, fd: usize) -> Result<File, Errno> {
+        self.files.get(fd).cloned().flatten().ok_or(Errno::EBADF)
+    }
+
+    /// Close the descriptor. The file is closed once no descriptor refers to it anymore.
+    pub fn close(&mut self, fd: usize) -> Result<(), Errno> {
+        let file = self
+            .files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(Errno::EBADF)?;
+        drop(file);
+
+        while self.files.last().is_some_and(Option::is_none) {
+            self.files.pop();
+        }
+        Ok(())
+    }
+
+    /// Close every descriptor.
+    pub fn close_all(&mut self) {
+        self.files.clear();
+    }
+
+    /// Number of open descriptors.
+    pub fn len(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A pipe: a ring buffer of bytes that flow from its write ends to its read ends.
+#[derive(Debug)]
+pub struct Pipe {
+    state: UnsafeCell<PipeState>,
+    readable: WaitQueue, // Readers waiting for data, or for the last writer to go away
+    writable: WaitQueue, // Writers waiting for room, or for the last reader to go away
+}
+
+// Only touched with interrupts disabled.
+#[derive(Debug)]
+struct PipeState {
+    buffer: Box<[u8; PIPE_SIZE]>,
+    head: usize, // Index of the oldest byte
+    len: usize,
+    readers: usize, // Number of open read ends
+    writers: usize, // Number of open write ends
+}
+
+/// The read end of a pipe. Dropping the last read end makes writes fail with EPIPE.
+#[derive(Debug)]
+pub struct PipeReader(Rc<Pipe>);
+
+/// The write end of a pipe. Dropping the last write end makes reads return 0 once the pipe is empty.
+#[derive(Debug)]
+pub struct PipeWriter(Rc<Pipe>);
+
+/// Create an empty pipe, and return its read end and its write end.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    unsafe { LIVE_PIPES += 1 };
+    let pipe = Rc::new(Pipe {
+        state: UnsafeCell::new(PipeState {
+            buffer: Box::new([0; PIPE_SIZE]),
+            head: 0,
+            len: 0,
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe { LIVE_PIPES -= 1 };
+    }
+}
+
+impl PipeReader {
+    /// Move up to buf.len() bytes out of the pipe into buf, blocking while the pipe is empty. Returns the number of
+    /// bytes read, or 0 if the pipe is empty and every write end is closed. Fails with EINTR if a signal arrives while
+    /// the pipe is empty.
+    ///
+    /// # Safety
+    /// CURRENT_TASK must be Some, and this must not be called from an interrupt handler.
+    pub unsafe fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let pipe = &self.0;
+        without_interrupt(|| unsafe {
+            loop {
+                // Interrupts stay disabled between the check and wait(), so no write or close is missed in between
+                let state = &mut *pipe.state.get();
+                if state.len != 0 || buf.is_empty() {
+                    let count = buf.len().min(state.len);
+                    for byte in &mut buf[..count] {
+                        *byte = state.buffer[state.head];
+                        state.head = (state.head + 1) % PIPE_SIZE;
+                    }
+                    state.len -= count;
+                    pipe.writable.wake_all();
+                    return Ok(count);
+                }
+                if state.writers == 0 {
+                    return Ok(0);
+                }
+                if signal::pending() {
+                    return Err(Errno::EINTR);
+                }
+                pipe.readable.wait();
+            }
+        })
+    }
+}
+
+impl PipeWriter {
+    /// Move all of buf into the pipe, blocking while it is full. Returns the number of bytes written, which is less
+    /// than buf.len() if every read end was closed or a signal arrived in the meantime. Fails with EPIPE if no read end
+    /// is open, and with EINTR if a signal arrives before anything is written.
+    ///
+    /// # Safety
+    /// CURRENT_TASK must be Some, and this must not be called from an interrupt handler.
+    pub unsafe fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let pipe = &self.0;
+        let mut written = 0;
+        without_interrupt(|| unsafe {
+            loop {
+                let state = &mut *pipe.state.get();
+                if state.readers == 0 {
+                    return if written == 0 {
+                        Err(Errno::EPIPE)
+                    } else {
+                        Ok(written)
+                    };
+                }
+
+                let count = (buf.len() - written).min(PIPE_SIZE - state.len);
+                for &byte in &buf[written..written + count] {
+                    state.buffer[(state.head + state.len) % PIPE_SIZE] = byte;
+                    state.len += 1;
+                }
+                written += count;
+                if count != 0 {
+                    pipe.readable.wake_all();
+                }
+
+                if written == buf.len() {
+                    return Ok(written);
+                }
+                if signal::pending() {
+                    return if written == 0 {
+                        Err(Errno::EINTR)
+                    } else {
+                        Ok(written)
+                    };
+                }
+                pipe.writable.wait();
+            }
+        })
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> Self {
+        without_interrupt(|| unsafe { (*self.0.state.get()).readers += 1 });
+        PipeReader(self.0.clone())
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        without_interrupt(|| unsafe {
+            let state = &mut *self.0.state.get();
+            state.readers -= 1;
+            if state.readers == 0 {
+                self.0.writable.wake_all();
+            }
+        });
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        without_interrupt(|| unsafe { (*self.0.state.get()).writers += 1 });
+        PipeWriter(self.0.clone())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        without_interrupt(|| unsafe {
+            let state = &mut *self.0.state.get();
+            state.writers -= 1;
+            if state.writers == 0 {
+                self.0.readable.wake_all();
+            }
+        });
+    }
+}
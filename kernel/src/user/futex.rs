@@ -0,0 +1,83 @@
+//! Futexes: wait queues keyed by the address of a 32-bit word in user memory, for user-space locks.
+//!
+//! A task waits only if the word still holds the value it expects, and is woken up by a task that changed the word.
+//! Waiters are keyed by the physical address of the word, so tasks that map the same page at different addresses, or
+//! share it after fork, meet in the same queue.
+//!
+//! The check of the word and blocking happen with interrupts disabled. Only one CPU runs tasks, so no wake can come in
+//! between, and wakeups are never lost. Waiters may still wake up spuriously, e.g. when the timeout runs out right as
+//! they are woken, so user space must check the word again.
+
+use crate::{
+    helper::p2v,
+    idt::without_interrupt,
+    time,
+    user::{
+        sched::{self, wait_queue::WaitQueue},
+        signal,
+        syscall::errno::Errno,
+        uaccess,
+    },
+};
+
+/// Number of wait queues the futex keys are hashed into.
+pub const FUTEX_BUCKETS: usize = 64;
+
+static mut FUTEX_QUEUES: [WaitQueue; FUTEX_BUCKETS] = [const { WaitQueue::new() }; FUTEX_BUCKETS];
+
+// Check that addr is an aligned futex word the current task may write, and return its physical address. Copy-on-write
+// pages are copied first, so the address stays the same as long as the page is mapped.
+fn futex_key(addr: usize) -> Result<usize, Errno> {
+    if !addr.is_multiple_of(size_of::<u32>()) {
+        return Err(Errno::EINVAL);
+    }
+    sched::with_current_task(|task| {
+        uaccess::access_ok(&mut task.addr_space, addr, size_of::<u32>(), true)?;
+        task.addr_space.resolve_virt_addr(addr).ok_or(Errno::EFAULT)
+    })
+}
+
+fn queue_of(key: usize) -> &'static WaitQueue {
+    unsafe { &FUTEX_QUEUES[(key / size_of::<u32>()) % FUTEX_BUCKETS] }
+}
+
+/// Block the current task while the word at addr holds expected, until futex_wake() wakes it up, or for at most
+/// timeout_ns nanoseconds if it isn't 0. Fails with EAGAIN if the word holds another value, with ETIMEDOUT if the
+/// timeout ran out, and with EINTR if a signal arrived.
+///
+/// # Safety
+/// CURRENT_TASK must be Some, and this must not be called from an interrupt handler.
+pub unsafe fn futex_wait(addr: usize, expected: u32, timeout_ns: u64) -> Result<(), Errno> {
+    let key = futex_key(addr)?;
+    let queue = queue_of(key);
+    let wake_tick = (timeout_ns != 0).then(|| sched::wake_tick_after_ns(time::ticks(), timeout_ns));
+
+    without_interrupt(|| unsafe {
+        // No other task runs until we block, so the page is still mapped. The word is read through the direct mapping.
+        if (p2v(key) as *const u32).read_volatile() != expected {
+            return Err(Errno::EAGAIN);
+        }
+
+        sched::with_current_task(|task| task.futex_key = key);
+        let woken = match wake_tick {
+            Some(wake_tick) => queue.wait_until(wake_tick),
+            None => {
+                queue.wait();
+                true
+            }
+        };
+        if !woken {
+            Err(Errno::ETIMEDOUT)
+        } else if signal::pending() {
+            Err(Errno::EINTR)
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Wake up to count tasks waiting on the word at addr. Returns the number of tasks woken up.
+pub fn futex_wake(addr: usize, count: usize) -> Result<usize, Errno> {
+    let key = futex_key(addr)?;
+    Ok(queue_of(key).wake_matching(count, |task| task.futex_key == key))
+}
@@ -1,6 +1,11 @@
 pub mod address_space;
 pub mod elf_parser;
 pub mod elf_structure;
+pub mod fd;
+pub mod futex;
+pub mod programs;
 pub mod sched;
+pub mod signal;
 pub mod syscall;
 pub mod task;
+pub mod uaccess;
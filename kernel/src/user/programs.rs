@@ -0,0 +1,23 @@
+//! Programs embedded in the kernel image, which user tasks can start by name.
+
+use alloc::vec::Vec;
+
+static mut PROGRAMS: Vec<(&'static str, &'static [u8])> = Vec::new();
+
+/// Make the executable elf available under name. A program registered again replaces the old one.
+pub fn register(name: &'static str, elf: &'static [u8]) {
+    let programs = unsafe { &mut PROGRAMS };
+    match programs.iter_mut().find(|(other, _)| *other == name) {
+        Some(program) => program.1 = elf,
+        None => programs.push((name, elf)),
+    }
+}
+
+/// Find the executable registered under name.
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    let programs = unsafe { &PROGRAMS };
+    programs
+        .iter()
+        .find(|(other, _)| *other == name)
+        .map(|&(_, elf)| elf)
+}
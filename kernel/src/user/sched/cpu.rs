@@ -0,0 +1,136 @@
+//! Per-CPU scheduler state.
+//!
+//! Every CPU has its own ready queues. A task is placed on the least loaded CPU it is allowed to run on when it is
+//! added or woken up, and an idle or lightly loaded CPU steals ready tasks from the busiest one now and then.
+//!
+//! Only the BSP runs tasks for now, so there is one online CPU, and placement and stealing always pick CPU 0.
+
+use alloc::collections::vec_deque::VecDeque;
+
+use crate::user::sched::{BLOCKED_TASKS, PRIORITY_LEVELS, SLEEPING_TASKS, TaskRef};
+
+/// Largest number of CPUs. A CPU affinity mask has one bit per CPU.
+pub const MAX_CPUS: usize = 64;
+
+/// Affinity mask of a task that may run on any CPU.
+pub const ALL_CPUS: u64 = u64::MAX;
+
+/// Number of timer ticks between two work stealing passes of a CPU that still has ready tasks.
+pub const BALANCE_TICKS: usize = 10;
+
+/// Scheduler state of one CPU.
+#[derive(Debug)]
+pub struct Cpu {
+    /// One ready queue per priority level. Tasks run round-robin within a level, and the highest non-empty level runs
+    /// first.
+    pub ready_tasks: [VecDeque<TaskRef>; PRIORITY_LEVELS],
+    // The tick each priority level last ran at, or was last found empty at. Used to boost starving levels.
+    pub(super) last_run_tick: [usize; PRIORITY_LEVELS],
+    // The tick of the last work stealing pass.
+    pub(super) last_balance_tick: usize,
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Cpu {
+            ready_tasks: [const { VecDeque::new() }; PRIORITY_LEVELS],
+            last_run_tick: [0; PRIORITY_LEVELS],
+            last_balance_tick: 0,
+        }
+    }
+
+    /// Number of tasks in the ready queues.
+    pub fn load(&self) -> usize {
+        self.ready_tasks.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// The scheduler state of every CPU, indexed by CPU id. Only the first online_cpus() are in use.
+pub static mut CPUS: [Cpu; MAX_CPUS] = [const { Cpu::new() }; MAX_CPUS];
+
+// Number of CPUs that run tasks.
+static mut ONLINE_CPUS: usize = 1;
+
+/// Number of CPUs that run tasks.
+pub fn online_cpus() -> usize {
+    unsafe { ONLINE_CPUS }
+}
+
+/// Set the number of CPUs that run tasks, once the APs are up. Tests also use it to place tasks on CPUs that don't
+/// run anything.
+///
+/// # Safety
+/// The ready queues of CPUs that go offline must be empty.
+pub unsafe fn set_online_cpus(count: usize) {
+    assert!((1..=MAX_CPUS).contains(&count));
+    unsafe {
+        debug_assert!(CPUS[count..].iter().all(|cpu| cpu.load() == 0));
+        ONLINE_CPUS = count;
+    }
+}
+
+/// Id of the CPU we are running on. Only the BSP runs tasks for now.
+pub fn this_cpu_id() -> usize {
+    0
+}
+
+/// The scheduler state of the CPU we are running on.
+///
+/// # Safety
+/// Interrupts must be disabled while the reference is used.
+pub unsafe fn this_cpu() -> &'static mut Cpu {
+    unsafe { &mut CPUS[this_cpu_id()] }
+}
+
+/// Check if the affinity mask allows running on the CPU.
+pub fn allows(affinity: u64, cpu: usize) -> bool {
+    affinity & (1 << cpu) != 0
+}
+
+/// Choose the CPU to place a task with the affinity mask on: the least loaded online CPU it is allowed to run on, and
+/// the lowest id among equally loaded ones. If the mask allows no online CPU, the task stays on this CPU.
+pub fn select_cpu(affinity: u64) -> usize {
+    unsafe {
+        (0..ONLINE_CPUS)
+            .filter(|&cpu| allows(affinity, cpu))
+            .min_by_key(|&cpu| CPUS[cpu].load())
+            .unwrap_or(this_cpu_id())
+    }
+}
+
+/// Move one ready task that is allowed to run on cpu from the busiest other CPU, if that one has at least two more
+/// ready tasks. Lower priority tasks are taken first, from the back of their queue. Returns true if a task was moved.
+///
+/// # Safety
+/// Interrupts must be disabled, and this must not be called from an interrupt handler, as it may allocate.
+pub unsafe fn steal_work(cpu: usize) -> bool {
+    unsafe {
+        let Some(busiest) = (0..ONLINE_CPUS)
+            .filter(|&other| other != cpu)
+            .max_by_key(|&other| CPUS[other].load())
+        else {
+            return false;
+        };
+        if CPUS[busiest].load() < CPUS[cpu].load() + 2 {
+            return false;
+        }
+
+        for priority in 0..PRIORITY_LEVELS {
+            let queue = &mut CPUS[busiest].ready_tasks[priority];
+            let Some(index) = queue
+                .iter()
+                .rposition(|task| allows((*task.get()).cpu_affinity, cpu))
+            else {
+                continue;
+            };
+
+            let task = queue.remove(index).unwrap();
+            let queue = &mut CPUS[cpu].ready_tasks[priority];
+            queue.reserve(SLEEPING_TASKS.len() + BLOCKED_TASKS + 1);
+            queue.push_back(task);
+            return true;
+        }
+
+        false
+    }
+}
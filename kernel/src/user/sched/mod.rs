@@ -1,58 +1,351 @@
+pub mod cpu;
+pub mod stats;
+pub mod task_ref;
+pub mod wait_queue;
+
+pub use stats::dump_tasks;
+pub use task_ref::TaskRef;
+
 use core::{
-    arch::naked_asm,
-    cell::UnsafeCell,
+    arch::{asm, naked_asm},
     hint::unreachable_unchecked,
-    mem::{forget, offset_of},
-    ptr::null_mut,
+    mem::offset_of,
+    ptr::{null, null_mut},
 };
 
-use alloc::{collections::vec_deque::VecDeque, rc::Rc};
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
 
 use crate::{
     consts,
+    cpu::{features as cpu_features, segment_base, tsc},
     gdt::{TSS, Tss},
-    helper::hcf,
-    printlnk,
+    idt::{InterruptGuard, disable_interrupt, without_interrupt},
+    irq, printlnk, time,
     user::{
-        syscall,
+        sched::cpu::{
+            BALANCE_TICKS, CPUS, online_cpus, select_cpu, steal_work, this_cpu, this_cpu_id,
+        },
+        signal,
+        syscall::{self, errno::Errno},
         task::{KERNEL_STACK_SIZE, Task, TaskState},
     },
 };
 
-pub static mut CURRENT_TASK: Option<Rc<UnsafeCell<Task>>> = None;
+pub static mut CURRENT_TASK: Option<TaskRef> = None;
+
+// Set while with_current_task() lends out the current task, to catch a second borrow.
+static mut CURRENT_TASK_BORROWED: bool = false;
+
+/// Call f with the current task, and return its result. Interrupts are disabled while f runs, so the scheduler can't
+/// touch the task in the meantime.
+///
+/// f must not switch tasks, and must not call with_current_task() again. Panics if there is no current task.
+pub fn with_current_task<R>(f: impl FnOnce(&mut Task) -> R) -> R {
+    without_interrupt(|| unsafe {
+        let task = CURRENT_TASK.as_ref().expect("no current task");
+
+        debug_assert!(!CURRENT_TASK_BORROWED, "current task borrowed twice");
+        CURRENT_TASK_BORROWED = true;
+        let result = f(&mut *task.get());
+        CURRENT_TASK_BORROWED = false;
+
+        result
+    })
+}
+
+/// Id of the current task. Panics if there is no current task.
+pub fn current_pid() -> usize {
+    without_interrupt(|| unsafe { CURRENT_TASK.as_ref().expect("no current task").id() })
+}
+
+/// Number of priority levels. Priority 0 is the lowest, and PRIORITY_LEVELS - 1 is the highest.
+pub const PRIORITY_LEVELS: usize = 4;
+
+/// Priority of new tasks.
+pub const DEFAULT_PRIORITY: usize = 1;
+
+/// Number of timer ticks a waiting priority level can be passed over for, before it is boosted and gets to run.
+pub const STARVATION_TICKS: usize = 50;
+
+// The ready queues are per CPU, see cpu::Cpu.
+
+/// Sleeping tasks, sorted by the tick they wake up at. The timer interrupt moves them back to the ready queue.
+pub static mut SLEEPING_TASKS: Vec<TaskRef> = Vec::new();
+
+/// Number of tasks blocked on a wait queue.
+pub static mut BLOCKED_TASKS: usize = 0;
+
+// The ready, sleeping and wait queues are only touched with interrupts disabled, because interrupt handlers move tasks
+// between them. Interrupt handlers must not allocate either, so the ready queues always have room for every sleeping
+// and blocked task (see reserve_for_waiting_task()).
+
+/// Every task added to the scheduler that hasn't exited yet, by id.
+pub static mut TASK_TABLE: BTreeMap<usize, TaskRef> = BTreeMap::new();
+
+/// The exit code of a task, kept until its parent waits for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub id: usize,
+    pub parent: usize,
+    pub code: i32,
+}
+
+/// Exit codes of tasks whose parents haven't waited for them yet.
+pub static mut EXIT_STATUSES: Vec<ExitStatus> = Vec::new();
+
+/// Tasks that have exited, but are not freed yet.
+/// An exiting task is still running on its own kernel stack and page tables, so it is freed by the next context instead.
+pub static mut ZOMBIE_TASKS: Vec<TaskRef> = Vec::new();
+
+/// Number of timer ticks a task of each priority level runs for before it is preempted. Low priority tasks are
+/// mostly busy, so they get longer slices. High priority tasks mostly wait, and shouldn't hold the CPU for long.
+pub const DEFAULT_TIME_SLICES: [usize; PRIORITY_LEVELS] = [10, 5, 3, 2];
+
+// The time slice of each priority level, in timer ticks. Set with the timeslice option of the kernel command line.
+static mut TIME_SLICES: [usize; PRIORITY_LEVELS] = DEFAULT_TIME_SLICES;
+
+/// Set by the timer interrupt when the time slice of the current task has run out.
+pub static mut NEED_RESCHED: bool = false;
+
+// Set by an exception handler that kills the current task, once it leaves interrupt context (see kill_on_return()).
+static mut KILL_ON_RETURN: bool = false;
+
+/// Number of times a task has been preempted, for statistics.
+pub static mut PREEMPTIONS: usize = 0;
+
+/// Number of times a task has been switched to, for statistics.
+pub static mut CONTEXT_SWITCHES: usize = 0;
+
+/// Number of times the idle task has halted the CPU to wait for an interrupt, for statistics.
+pub static mut IDLE_HALTS: usize = 0;
 
-pub static mut READY_TASKS: VecDeque<Rc<UnsafeCell<Task>>> = VecDeque::new();
+// The idle task, a kernel task that runs whenever no other task is ready.
+// It is never in the ready queues, and is never CURRENT_TASK, so CURRENT_TASK is None while it runs.
+static mut IDLE_TASK: Option<Task> = None;
 
-// To use Rc<UnsafeCell<Task>> safely:
-// We have to be very careful to not clone or drop any Rc ptr.
-// Cloning Rc may prevent the task from being freed when it should be, and dropping Rc may free the task too early.
+// The context begin_scheduler() was called from. The idle task switches back to it once every task has exited.
+static mut BOOT_CONTEXT: Option<Task> = None;
 
-/// Begin the task scheduler. There must be at least one ready task in the ready queue.
-pub unsafe fn begin_scheduler() -> ! {
+// Every task the scheduler knows about is held by exactly one of CURRENT_TASK, the ready queues of a CPU, the queues
+// above, or a wait queue.
+// Handles are moved between them, and only cloned or released explicitly (see TaskRef).
+
+/// Run the task scheduler, and return once every task has exited.
+pub unsafe fn begin_scheduler() {
     unsafe {
-        yield_task_must_swap();
+        let boot_context: *mut Task = BOOT_CONTEXT.insert(Task::boot_context());
+        let idle_task: *mut Task = IDLE_TASK.insert(Task::create_kernel_task(idle_loop, "idle"));
 
-        // Kernel is not a task, so context switching into the kernel is impossible.
-        unreachable_unchecked();
+        {
+            let _guard = InterruptGuard::disable();
+            inner_context_switch(boot_context, idle_task);
+        }
+
+        // Both run on the kernel page tables, so they can be freed right away
+        IDLE_TASK = None;
+        BOOT_CONTEXT = None;
+
+        printlnk!("All tasks terminated.");
+    }
+}
+
+// The body of the idle task. Runs the next ready task, or halts until an interrupt makes one ready.
+fn idle_loop() -> ! {
+    unsafe {
+        loop {
+            disable_interrupt();
+
+            // No task is running, so none of the zombies is in use
+            reap_zombies();
+
+            if let Some(next_task) = pop_ready_task(0) {
+                let next_task_ptr = next_task.get();
+                CURRENT_TASK = Some(next_task);
+
+                prepare_to_run(next_task_ptr);
+                inner_context_switch(IDLE_TASK.as_mut().unwrap_unchecked(), next_task_ptr);
+                continue;
+            }
+
+            if SLEEPING_TASKS.is_empty() && BLOCKED_TASKS == 0 {
+                // Every task has exited, so return from begin_scheduler(). The idle task is freed there.
+                inner_context_switch(
+                    IDLE_TASK.as_mut().unwrap_unchecked(),
+                    BOOT_CONTEXT.as_mut().unwrap_unchecked(),
+                );
+                unreachable_unchecked();
+            }
+
+            // Wait for an interrupt to wake a sleeping or blocked task
+            IDLE_HALTS += 1;
+            asm!("sti", "hlt", options(nomem, nostack));
+        }
     }
 }
 
 /// Add a new task to the scheduler.
 ///
 /// The task must be new, and this function must only be called once per task.
-pub unsafe fn add_new_task(task: Rc<UnsafeCell<Task>>) {
+pub unsafe fn add_new_task(task: TaskRef) {
+    assert!(unsafe { (*task.get()).priority } < PRIORITY_LEVELS);
+
+    without_interrupt(|| unsafe {
+        TASK_TABLE.insert(task.id(), task.clone_ref());
+        push_ready_task(task);
+    });
+}
+
+// Push a task to the back of the ready queue of its priority, on the least loaded CPU it may run on. Keeps room for
+// every sleeping and blocked task to be woken up without allocating. Must not be called from an interrupt handler that
+// may have interrupted the kernel.
+unsafe fn push_ready_task(task: TaskRef) {
+    unsafe {
+        let cpu = select_cpu((*task.get()).cpu_affinity);
+        let queue = &mut CPUS[cpu].ready_tasks[(*task.get()).priority];
+        queue.reserve(SLEEPING_TASKS.len() + BLOCKED_TASKS + 1);
+        queue.push_back(task);
+    }
+}
+
+// Make room in every ready queue for one more sleeping or blocked task. Call this before a task goes to sleep or blocks.
+unsafe fn reserve_for_waiting_task() {
+    unsafe {
+        let waiting = SLEEPING_TASKS.len() + BLOCKED_TASKS;
+        for cpu in &mut CPUS[..online_cpus()] {
+            for queue in cpu.ready_tasks.iter_mut() {
+                queue.reserve(waiting + 1);
+            }
+        }
+    }
+}
+
+// Make a sleeping or blocked task ready again, and ask for a reschedule if it should run before the current task.
+// This never allocates, so it can be called from interrupt handlers.
+unsafe fn wake_task(task: TaskRef) {
     unsafe {
-        READY_TASKS.push_back(task);
+        let task_ref = &mut *task.get();
+        task_ref.state = TaskState::Ready;
+
+        let cpu = select_cpu(task_ref.cpu_affinity);
+        let current_priority = CURRENT_TASK
+            .as_ref()
+            .map(|current_task| (*current_task.get()).priority);
+        if cpu == this_cpu_id()
+            && current_priority.is_some_and(|priority| task_ref.priority > priority)
+        {
+            NEED_RESCHED = true;
+        }
+
+        CPUS[cpu].ready_tasks[task_ref.priority].push_back(task);
     }
 }
 
+// Take the next task to run on this CPU, from the highest non-empty level of at least min_priority.
+// A level that has been passed over for STARVATION_TICKS is boosted, and runs first regardless of its priority.
+unsafe fn pop_ready_task(min_priority: usize) -> Option<TaskRef> {
+    unsafe {
+        let now = time::ticks();
+
+        // Steal from other CPUs every BALANCE_TICKS, or right away if we have nothing to run
+        if this_cpu().load() == 0 || now - this_cpu().last_balance_tick >= BALANCE_TICKS {
+            this_cpu().last_balance_tick = now;
+            steal_work(this_cpu_id());
+        }
+
+        let cpu = this_cpu();
+
+        for priority in 0..PRIORITY_LEVELS {
+            if cpu.ready_tasks[priority].is_empty() {
+                cpu.last_run_tick[priority] = now;
+            }
+        }
+
+        let starving = (0..PRIORITY_LEVELS)
+            .find(|&priority| now - cpu.last_run_tick[priority] >= STARVATION_TICKS);
+        let priority = starving.or_else(|| {
+            (min_priority..PRIORITY_LEVELS)
+                .rev()
+                .find(|&priority| !cpu.ready_tasks[priority].is_empty())
+        })?;
+
+        cpu.last_run_tick[priority] = now;
+        cpu.ready_tasks[priority].pop_front()
+    }
+}
+
+/// Check that priority is a valid priority level.
+pub fn is_valid_priority(priority: usize) -> bool {
+    priority < PRIORITY_LEVELS
+}
+
 /// Kill the current task.
-/// This function simply marks the current task as terminated and doesn't put it back to the ready queue.
 pub unsafe fn kill_task() -> ! {
+    unsafe { exit_current(-1) }
+}
+
+/// Kill the current task once the exception handler that calls this leaves interrupt context, as killing it switches
+/// to another task. The handler still returns, but not to the task.
+pub fn kill_on_return() {
+    unsafe { KILL_ON_RETURN = true };
+}
+
+/// Do the work interrupt and exception handlers deferred: kill the current task after an exception, switch to another
+/// task if the timer asked for it, and terminate the task for a fatal signal.
+///
+/// # Safety
+/// Must only be called by InterruptContext::exit(), once the CPU has left interrupt context on its way back to user
+/// mode.
+pub unsafe fn return_to_user() {
     unsafe {
-        let current_task = CURRENT_TASK.as_ref().unwrap_unchecked();
+        if KILL_ON_RETURN {
+            KILL_ON_RETURN = false;
+            kill_task();
+        }
+        preempt_if_needed();
+        // A task that never makes a syscall is killed here
+        signal::deliver_fatal();
+    }
+}
+
+/// Exit the current task with the given exit code.
+/// The task is marked as terminated and moved to the zombie list, which the next context frees after the switch.
+///
+/// # Safety
+/// CURRENT_TASK must be Some.
+pub unsafe fn exit_current(code: i32) -> ! {
+    unsafe {
+        // The task never comes back, so interrupts are restored by the task we switch to
+        disable_interrupt();
+
+        let current_task = &mut *CURRENT_TASK.as_ref().unwrap_unchecked().get();
+
+        current_task.state = TaskState::Terminated;
+        current_task.exit_code = Some(code);
 
-        (*current_task.get()).state = TaskState::Terminated;
+        // Close the files now rather than when the task is freed, so readers of our pipes see the end right away
+        current_task.files.close_all();
+
+        if let Some(task) = TASK_TABLE.remove(&current_task.id) {
+            task.drop_ref();
+        }
+
+        // Nobody can wait for our children anymore, so their exit codes are not kept
+        for task in TASK_TABLE.values() {
+            if (*task.get()).parent == current_task.id {
+                (*task.get()).parent = 0;
+            }
+        }
+        EXIT_STATUSES.retain(|status| status.parent != current_task.id);
+
+        // Keep the exit code until the parent waits for it, if the parent is still around
+        if TASK_TABLE.contains_key(&current_task.parent) {
+            EXIT_STATUSES.push(ExitStatus {
+                id: current_task.id,
+                parent: current_task.parent,
+                code,
+            });
+        }
+        current_task.exit_waiters.wake_all();
 
         yield_task_must_swap();
 
@@ -61,6 +354,48 @@ pub unsafe fn kill_task() -> ! {
     }
 }
 
+/// Wait for the child task with the given id to exit, and return its exit code. The exit code is released afterwards.
+/// Fails with ECHILD if the task is not a child of the current task, or its exit code was already taken, and with
+/// EINTR if a signal arrived first.
+///
+/// # Safety
+/// CURRENT_TASK must be Some, and this must not be called from an interrupt handler.
+pub unsafe fn wait_child(id: usize) -> Result<i32, Errno> {
+    unsafe {
+        let current_id = CURRENT_TASK.as_ref().unwrap_unchecked().id();
+
+        loop {
+            let exited = EXIT_STATUSES
+                .iter()
+                .position(|status| status.id == id && status.parent == current_id);
+            if let Some(index) = exited {
+                return Ok(EXIT_STATUSES.swap_remove(index).code);
+            }
+
+            let child = TASK_TABLE.get(&id).ok_or(Errno::ECHILD)?;
+            if (*child.get()).parent != current_id {
+                return Err(Errno::ECHILD);
+            }
+            if signal::pending() {
+                return Err(Errno::EINTR);
+            }
+
+            // Hold on to the child, so its wait queue stays around until we are woken up
+            let child = child.clone_ref();
+            (*child.get()).exit_waiters.wait();
+            child.drop_ref();
+        }
+    }
+}
+
+/// Free every task in the zombie list.
+/// Must not be called on the kernel stack or page tables of any of them.
+unsafe fn reap_zombies() {
+    unsafe {
+        ZOMBIE_TASKS.clear();
+    }
+}
+
 /// Yield the current task.
 /// If there is any ready task, this function will push the current task back to the ready queue and switch to another task.
 /// Otherwise, continues the current task.
@@ -68,30 +403,250 @@ pub unsafe fn kill_task() -> ! {
 /// The following assumptions must hold:
 /// 1. CURRENT_TASK must be Some.
 /// 2. The current task is not in the terminated state.
+/// 3. This is not called in interrupt context.
 pub unsafe fn yield_task() {
-    unsafe {
-        let Some(next_task) = READY_TASKS.pop_front() else {
-            // No other ready task, continue the current task
+    irq::assert_not_in_interrupt("yield_task()");
+
+    without_interrupt(|| unsafe {
+        let priority = (*CURRENT_TASK.as_ref().unwrap_unchecked().get()).priority;
+
+        let Some(next_task) = pop_ready_task(priority) else {
+            // No other ready task of the same or a higher priority, continue the current task
             return;
         };
 
         switch_task(next_task);
+    });
+}
+
+/// Put the current task to sleep for at least ms milliseconds, and switch to another task.
+/// A sleep of 0 ms just yields.
+///
+/// # Safety
+/// CURRENT_TASK must be Some.
+pub unsafe fn sleep_current(ms: usize) {
+    if ms == 0 {
+        unsafe { yield_task() };
+        return;
+    }
+
+    without_interrupt(|| unsafe { sleep_until(wake_tick_after(time::ticks(), ms)) });
+}
+
+/// Put the current task to sleep for at least ns nanoseconds, and switch to another task.
+/// A sleep of 0 ns just yields.
+///
+/// # Safety
+/// CURRENT_TASK must be Some.
+pub unsafe fn sleep_current_ns(ns: u64) {
+    if ns == 0 {
+        unsafe { yield_task() };
+        return;
+    }
+
+    without_interrupt(|| unsafe { sleep_until(wake_tick_after_ns(time::ticks(), ns)) });
+}
+
+// Put the current task to sleep until wake_tick. Interrupts must be disabled. A pending signal cuts the sleep short,
+// like one that arrives during it (see interrupt_wait()).
+unsafe fn sleep_until(wake_tick: usize) {
+    unsafe {
+        let current_task = &mut *CURRENT_TASK.as_ref().unwrap_unchecked().get();
+        if current_task.signals.deliverable() != 0 {
+            return;
+        }
+        current_task.state = TaskState::Sleeping;
+        current_task.wake_tick = wake_tick;
+
+        yield_task_must_swap();
+    }
+}
+
+/// The tick a task sleeping for ms milliseconds from now should wake up at.
+/// The current tick is already partly over, so it doesn't count.
+pub fn wake_tick_after(now: usize, ms: usize) -> usize {
+    now.saturating_add(ms.div_ceil(time::MS_PER_TICK))
+        .saturating_add(1)
+}
+
+/// The tick a task sleeping for ns nanoseconds from now should wake up at. Rounded up like wake_tick_after().
+pub fn wake_tick_after_ns(now: usize, ns: u64) -> usize {
+    let ticks = ns.div_ceil(time::NS_PER_TICK);
+    now.saturating_add(usize::try_from(ticks).unwrap_or(usize::MAX))
+        .saturating_add(1)
+}
+
+// Add a task to SLEEPING_TASKS, after the tasks that wake up at the same tick or earlier. Interrupts must be disabled.
+unsafe fn insert_sleeping_task(task: TaskRef) {
+    unsafe {
+        let wake_tick = (*task.get()).wake_tick;
+        let index = SLEEPING_TASKS.partition_point(|other| (*other.get()).wake_tick <= wake_tick);
+        SLEEPING_TASKS.insert(index, task);
+    }
+}
+
+// Forget the wait queue a blocked task was taken out of, and take the task out of SLEEPING_TASKS if it waited with a
+// timeout. Interrupts must be disabled.
+unsafe fn end_wait(task: &TaskRef) {
+    unsafe {
+        (*task.get()).waiting_on = null();
+        if let Some(index) = SLEEPING_TASKS.iter().position(|other| other.ptr_eq(task)) {
+            SLEEPING_TASKS.remove(index).drop_ref();
+        }
+    }
+}
+
+/// Wake up a blocked or sleeping task early, because a signal arrived for it. Its wait or sleep returns as if it
+/// was over, and the task sees the signal with signal::pending(). Does nothing to tasks that are not waiting.
+///
+/// # Safety
+/// Interrupts must be disabled.
+pub unsafe fn interrupt_wait(task: &TaskRef) {
+    unsafe {
+        match (*task.get()).state {
+            TaskState::Blocked => {
+                // The handle the wait queue held goes to the ready queue
+                let task = (*(*task.get()).waiting_on).remove(task).unwrap();
+                end_wait(&task);
+                BLOCKED_TASKS -= 1;
+                wake_task(task);
+            }
+            TaskState::Sleeping => {
+                if let Some(index) = SLEEPING_TASKS.iter().position(|other| other.ptr_eq(task)) {
+                    wake_task(SLEEPING_TASKS.remove(index));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Move every sleeping task whose wake tick has come to the ready queue. Tasks blocked with a timeout are taken out of
+/// their wait queue, and see that they timed out.
+///
+/// # Safety
+/// Must only be called from the timer interrupt.
+pub unsafe fn wake_sleepers() {
+    unsafe {
+        let now = time::ticks();
+        let expired = SLEEPING_TASKS.partition_point(|task| (*task.get()).wake_tick <= now);
+
+        for task in SLEEPING_TASKS.drain(..expired) {
+            let task_ref = &mut *task.get();
+            if task_ref.state == TaskState::Blocked {
+                // The wait queue holds a handle too, which is released here, as ours goes to the ready queue
+                (*task_ref.waiting_on).remove(&task).unwrap().drop_ref();
+                task_ref.waiting_on = null();
+                task_ref.timed_out = true;
+                BLOCKED_TASKS -= 1;
+            }
+            wake_task(task);
+        }
+    }
+}
+
+/// Called by the timer interrupt on every tick.
+/// Uses up the time slice of the current task, and asks for a reschedule once it runs out.
+///
+/// # Safety
+/// Must only be called from the timer interrupt.
+pub unsafe fn timer_tick() {
+    unsafe {
+        let Some(current_task) = CURRENT_TASK.as_ref() else {
+            return;
+        };
+
+        let current_task = &mut *current_task.get();
+        current_task.time_slice = current_task.time_slice.saturating_sub(1);
+        if current_task.time_slice == 0 {
+            NEED_RESCHED = true;
+        }
+    }
+}
+
+/// Number of timer ticks a task of the priority runs for before it is preempted.
+pub fn time_slice(priority: usize) -> usize {
+    unsafe { TIME_SLICES[priority] }
+}
+
+/// Set the time slice of every priority level, in timer ticks. Slices are at least one tick long.
+/// Tasks that are already running keep their current slice.
+pub fn set_time_slices(slices: [usize; PRIORITY_LEVELS]) {
+    without_interrupt(|| unsafe { TIME_SLICES = slices.map(|ticks| ticks.max(1)) });
+}
+
+/// Parse the value of the timeslice option: either one length in ticks for every priority level, like timeslice=10,
+/// or one per level from the lowest, like timeslice=10,5,3,2.
+pub fn parse_time_slices(value: &str) -> Option<[usize; PRIORITY_LEVELS]> {
+    if let Ok(ticks) = value.parse::<usize>() {
+        return (ticks != 0).then_some([ticks; PRIORITY_LEVELS]);
     }
+
+    let mut slices = [0; PRIORITY_LEVELS];
+    let mut values = value.split(',');
+    for slice in &mut slices {
+        *slice = values.next()?.parse().ok().filter(|&ticks| ticks != 0)?;
+    }
+    values.next().is_none().then_some(slices)
+}
+
+/// Yield the current task if the timer asked for a reschedule.
+///
+/// # Safety
+/// Must only be called on the way back to user mode, where the kernel is not in the middle of anything.
+pub unsafe fn preempt_if_needed() {
+    without_interrupt(|| unsafe {
+        if !NEED_RESCHED {
+            return;
+        }
+        NEED_RESCHED = false;
+
+        let current_task = &mut *CURRENT_TASK.as_ref().unwrap_unchecked().get();
+
+        // If nothing else should run, the current task gets another time slice
+        current_task.time_slice = time_slice(current_task.priority);
+
+        let Some(next_task) = pop_ready_task(current_task.priority) else {
+            return;
+        };
+
+        PREEMPTIONS += 1;
+        current_task.stats.preemptions += 1;
+        switch_task(next_task);
+    });
 }
 
 /// Yield the current task, and must switch to another task.
 unsafe fn yield_task_must_swap() {
     unsafe {
-        let Some(next_task) = READY_TASKS.pop_front() else {
-            // No other ready task, continue the current task
-            printlnk!("All tasks terminated. Halting CPU...");
-            hcf();
+        let Some(next_task) = pop_ready_task(0) else {
+            // No other ready task, go back to begin_scheduler()
+            switch_to_idle();
+            return;
         };
 
         switch_task(next_task);
     }
 }
 
+/// Switch to the idle task.
+/// The current task is retired like in switch_task(), and CURRENT_TASK becomes None.
+unsafe fn switch_to_idle() {
+    unsafe {
+        let old_task = CURRENT_TASK.take().unwrap_unchecked();
+        let old_task_ptr = old_task.get();
+
+        retire_task(old_task);
+
+        // The task switched to after idle restores its own state, so ours must be saved here
+        (*old_task_ptr).fpu_state.save();
+        (*old_task_ptr).stats.switch_out(tsc::rdtsc());
+
+        let idle_task: *mut Task = IDLE_TASK.as_mut().unwrap_unchecked();
+        inner_context_switch(old_task_ptr, idle_task);
+    }
+}
+
 /// Switch to the given task.
 /// This function will push the current task back to the ready queue and update CURRENT_TASK, then perform the context switch.
 /// This function will return in the future when the task is switched back to this task.
@@ -99,8 +654,16 @@ unsafe fn yield_task_must_swap() {
 /// The following assumptions must hold:
 /// 1. CURRENT_TASK must be Some.
 /// 2. Neither the current task nor the new task is in the terminated state.
-pub unsafe fn switch_task(new_task: Rc<UnsafeCell<Task>>) {
+pub unsafe fn switch_task(new_task: TaskRef) {
     unsafe {
+        debug_assert!(
+            !CURRENT_TASK_BORROWED,
+            "switching tasks while the current task is borrowed"
+        );
+
+        // We are running on the current task, so none of the zombies is in use
+        reap_zombies();
+
         let new_task_ptr = new_task.get();
 
         // Take the current task and replace it with the new task
@@ -108,22 +671,62 @@ pub unsafe fn switch_task(new_task: Rc<UnsafeCell<Task>>) {
 
         let old_task_ptr = old_task.as_ref().map_or(null_mut(), |v| v.get());
 
-        // Put the current task back to the ready queue
         if let Some(old_task) = old_task {
-            if (*old_task.get()).state != TaskState::Terminated {
-                READY_TASKS.push_back(old_task);
-            } else {
-                // TODO: Free task properly
-                // We can't free the task here because we are still using its stack. For now, just leak the task.
-                forget(old_task);
+            retire_task(old_task);
+        }
+
+        // The kernel doesn't use the FPU, so the registers still hold the state of the old task
+        let now = tsc::rdtsc();
+        if !old_task_ptr.is_null() {
+            (*old_task_ptr).fpu_state.save();
+            // With FSGSBASE, user mode can set its fs base without asking the kernel
+            if cpu_features().fsgsbase {
+                (*old_task_ptr).fs_base = segment_base::read_fs_base() as usize;
             }
+            (*old_task_ptr).stats.switch_out(now);
         }
+        (*new_task_ptr).stats.switch_in(now);
+
+        prepare_to_run(new_task_ptr);
 
         // Perform the actual context switch
         inner_context_switch(old_task_ptr, new_task_ptr);
     }
 }
 
+// Get the CPU ready for the task that is about to be switched to.
+unsafe fn prepare_to_run(task: *mut Task) {
+    unsafe {
+        // The task starts a new time slice. Whatever was left of the slice of the old task is given up.
+        (*task).time_slice = time_slice((*task).priority);
+        NEED_RESCHED = false;
+        CONTEXT_SWITCHES += 1;
+
+        segment_base::write_fs_base((*task).fs_base as u64);
+
+        (*task).fpu_state.restore();
+    }
+}
+
+/// Put a task that is being switched away from back to the ready queue, to the sleeping queue if it is asleep, or to the
+/// zombie list if it has exited. A blocked task is already in its wait queue.
+/// A terminated task can't be freed here, because we are still using its stack and page tables.
+unsafe fn retire_task(task: TaskRef) {
+    unsafe {
+        match (*task.get()).state {
+            TaskState::Terminated => ZOMBIE_TASKS.push(task),
+            TaskState::Sleeping => {
+                // Keep room in the ready queues for the task to be woken up
+                reserve_for_waiting_task();
+                insert_sleeping_task(task);
+            }
+            // The wait queue the task is blocked on holds its own handle, see WaitQueue::wait()
+            TaskState::Blocked => task.drop_ref(),
+            _ => push_ready_task(task),
+        }
+    }
+}
+
 /// The actual context switch.
 /// This function will save the context of the old task and restore the context of the new task.
 /// The caller must ensure that both tasks are not terminated (and not null).
@@ -161,8 +764,10 @@ unsafe extern "C" fn inner_context_switch(old_task: *mut Task, new_task: *mut Ta
         "add rax, {kernel_stack_size}",
         "mov [rip + {tss} + {tss_rsp0}], rax",
 
-        // Set syscall stack pointer
-        "mov [rip + {syscall_stack_addr}], rax",
+        // Set syscall stack pointer, in the SyscallScratch of this CPU (interrupts are disabled)
+        "swapgs",
+        "mov gs:[{scratch_kernel_stack}], rax",
+        "swapgs",
 
         // Switch page tables
         "mov rax, -{phys_mem_offset}",
@@ -175,6 +780,7 @@ unsafe extern "C" fn inner_context_switch(old_task: *mut Task, new_task: *mut Ta
         // Two cases:
         // 1. If the task is NEW, we need to clear all registers and return to user space using iretq.
         // 2. If the task is not NEW, we just switch the kernel stack.
+        //    New kernel tasks are not NEW either: their context switch structure returns into a trampoline.
 
         // Compare task.state with TaskState::New
         // If equal, jump to new task handling
@@ -232,7 +838,7 @@ unsafe extern "C" fn inner_context_switch(old_task: *mut Task, new_task: *mut Ta
 
         phys_mem_offset = const consts::PHYS_MEM_OFFSET,
 
-        syscall_stack_addr = sym syscall::KERNEL_STACK_ADDR,
+        scratch_kernel_stack = const offset_of!(syscall::SyscallScratch, kernel_stack),
 
         task_state = const offset_of!(Task, state),
         new_state = const TaskState::New as usize,
@@ -247,9 +853,6 @@ unsafe extern "C" fn inner_context_switch(old_task: *mut Task, new_task: *mut Ta
 //         // Set TSS rsp0 to the top of the kernel stack
 //         TSS.rsp0 = task.kernel_stack.top() as u64;
 
-//         // Set syscall stack pointer
-//         syscall::KERNEL_STACK_ADDR = task.kernel_stack.top();
-
 //         // Switch address space
 //         task.addr_space.switch_to_this();
 
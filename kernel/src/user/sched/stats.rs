@@ -0,0 +1,136 @@
+//! Per-task CPU time accounting.
+//!
+//! The time stamp counter is read once per context switch. The time between switching a task in and out is charged to
+//! it, whether it was spent in user mode, in a syscall or in an interrupt handler that interrupted it.
+
+use core::fmt::{self, Write};
+
+pub use elytra_abi::{
+    sysinfo::SysInfo,
+    task::{STATE_BLOCKED, STATE_EXITED, STATE_READY, STATE_RUNNING, STATE_SLEEPING, TaskInfo},
+};
+
+use crate::{
+    cpu::tsc,
+    idt::without_interrupt,
+    io::output::Console,
+    mem::{buddy, slab},
+    time::{self, cycles_to_ms},
+    user::{
+        sched::{CONTEXT_SWITCHES, CURRENT_TASK, TASK_TABLE},
+        task::{Task, TaskState},
+    },
+};
+
+/// CPU time and switch counts of a task.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TaskStats {
+    pub runtime_cycles: u64, // TSC cycles spent running, up to the last switch out
+    pub switched_in_at: u64, // TSC at the last switch in
+    pub switches: usize,     // Times the task was switched out
+    pub preemptions: usize, // Times the task was switched out by the timer, instead of yielding or blocking
+}
+
+impl TaskStats {
+    /// Called when the task is switched in at TSC now.
+    pub fn switch_in(&mut self, now: u64) {
+        self.switched_in_at = now;
+    }
+
+    /// Called when the task is switched out at TSC now.
+    pub fn switch_out(&mut self, now: u64) {
+        self.runtime_cycles += now.saturating_sub(self.switched_in_at);
+        self.switches += 1;
+    }
+
+    pub fn voluntary_switches(&self) -> usize {
+        self.switches - self.preemptions
+    }
+}
+
+/// Take a snapshot of the task. The current task is charged for the time since it was switched in.
+pub fn task_info(task: &Task, is_current: bool) -> TaskInfo {
+    let mut runtime_cycles = task.stats.runtime_cycles;
+    let state = if is_current {
+        runtime_cycles += tsc::rdtsc().saturating_sub(task.stats.switched_in_at);
+        STATE_RUNNING
+    } else {
+        match task.state {
+            TaskState::New | TaskState::Ready => STATE_READY,
+            TaskState::Sleeping => STATE_SLEEPING,
+            TaskState::Blocked => STATE_BLOCKED,
+            TaskState::Terminated => STATE_EXITED,
+        }
+    };
+
+    TaskInfo {
+        id: task.id as u64,
+        state,
+        priority: task.priority as u32,
+        runtime_ms: cycles_to_ms(runtime_cycles),
+        voluntary_switches: task.stats.voluntary_switches() as u64,
+        preemptions: task.stats.preemptions as u64,
+        name: task.name,
+    }
+}
+
+/// Call f with a snapshot of every task in the task table, in order of id. This doesn't allocate, so it can be used
+/// from interrupt handlers.
+pub fn for_each_task(mut f: impl FnMut(&TaskInfo)) {
+    unsafe {
+        let current = CURRENT_TASK.as_ref().map(|task| task.get());
+        for task in TASK_TABLE.values() {
+            f(&task_info(&*task.get(), current == Some(task.get())));
+        }
+    }
+}
+
+/// Take a snapshot of the memory and tasks of the system. Each allocator reports under its own lock, so its numbers
+/// agree with each other, and the scheduler counters are read together with interrupts disabled.
+pub fn system_info() -> SysInfo {
+    let (total_pages, free_pages) = buddy::page_counts();
+    let slab_bytes = slab::allocated_bytes();
+    let (num_tasks, context_switches) =
+        without_interrupt(|| unsafe { (TASK_TABLE.len(), CONTEXT_SWITCHES) });
+
+    SysInfo {
+        total_pages: total_pages as u64,
+        free_pages: free_pages as u64,
+        slab_bytes: slab_bytes as u64,
+        num_tasks: num_tasks as u64,
+        uptime_ns: time::monotonic_ns(),
+        context_switches: context_switches as u64,
+    }
+}
+
+/// Write a table of every task: id, name, state, priority, CPU time and switches (voluntary and preempted).
+pub fn format_tasks(out: &mut impl Write) -> fmt::Result {
+    writeln!(
+        out,
+        "{:>5} {:<16} {:<8} {:>4} {:>10} {:>8} {:>8}",
+        "PID", "NAME", "STATE", "PRIO", "TIME(ms)", "VOLUN", "PREEMPT"
+    )?;
+
+    let mut result = Ok(());
+    for_each_task(|info| {
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "{:>5} {:<16} {:<8} {:>4} {:>10} {:>8} {:>8}",
+                info.id,
+                info.name(),
+                info.state_name(),
+                info.priority,
+                info.runtime_ms,
+                info.voluntary_switches,
+                info.preemptions
+            );
+        }
+    });
+    result
+}
+
+/// Print the task table to the console.
+pub fn dump_tasks() {
+    format_tasks(&mut Console).unwrap();
+}
@@ -0,0 +1,140 @@
+//! Reference-counted handles to tasks.
+//!
+//! Every place that keeps a task alive (CURRENT_TASK, the task table, the ready, sleeping and zombie queues, and wait
+//! queues) holds exactly one TaskRef to it. Handles are only copied with clone_ref(), and the task is freed when the
+//! last handle is released, either with drop_ref() or by dropping it.
+
+use core::{
+    arch::asm,
+    ptr::{self, NonNull},
+};
+
+use alloc::boxed::Box;
+
+use crate::user::{
+    sched::{
+        CURRENT_TASK, SLEEPING_TASKS,
+        cpu::{CPUS, online_cpus},
+    },
+    task::{Task, TaskState},
+};
+
+/// Number of tasks that have a TaskRef and are not freed yet, for statistics.
+pub static mut LIVE_TASKS: usize = 0;
+
+// A task on the heap, with the number of handles to it.
+struct TaskBox {
+    ref_count: usize,
+    task: Task,
+}
+
+/// A handle to a task on the heap.
+#[derive(Debug)]
+pub struct TaskRef {
+    ptr: NonNull<TaskBox>,
+}
+
+impl TaskRef {
+    /// Move the task to the heap, and return the first handle to it.
+    pub fn new(task: Task) -> Self {
+        unsafe { LIVE_TASKS += 1 };
+
+        let task_box = Box::new(TaskBox { ref_count: 1, task });
+        TaskRef {
+            ptr: NonNull::from(Box::leak(task_box)),
+        }
+    }
+
+    /// Create another handle to the same task.
+    pub fn clone_ref(&self) -> Self {
+        unsafe { (*self.ptr.as_ptr()).ref_count += 1 };
+        TaskRef { ptr: self.ptr }
+    }
+
+    /// Release this handle. The task is freed with its last handle.
+    pub fn drop_ref(self) {
+        drop(self);
+    }
+
+    /// Get a pointer to the task. It is valid for as long as this handle is.
+    ///
+    /// The scheduler mutates tasks through this pointer, so references made from it must not be held across a switch.
+    pub fn get(&self) -> *mut Task {
+        unsafe { &raw mut (*self.ptr.as_ptr()).task }
+    }
+
+    /// Check if both handles are to the same task.
+    pub fn ptr_eq(&self, other: &TaskRef) -> bool {
+        self.ptr == other.ptr
+    }
+
+    /// Number of handles to the task.
+    pub fn ref_count(&self) -> usize {
+        unsafe { (*self.ptr.as_ptr()).ref_count }
+    }
+
+    pub fn id(&self) -> usize {
+        unsafe { (*self.get()).id }
+    }
+
+    pub fn state(&self) -> TaskState {
+        unsafe { (*self.get()).state }
+    }
+
+    pub fn priority(&self) -> usize {
+        unsafe { (*self.get()).priority }
+    }
+}
+
+impl Drop for TaskRef {
+    fn drop(&mut self) {
+        unsafe {
+            let task_box = self.ptr.as_ptr();
+            (*task_box).ref_count -= 1;
+            if (*task_box).ref_count != 0 {
+                return;
+            }
+
+            let task = &raw const (*task_box).task;
+            debug_assert!(
+                !is_running_on(&*task),
+                "task {} freed while running on its kernel stack",
+                (*task).id
+            );
+            debug_assert!(
+                CURRENT_TASK
+                    .as_ref()
+                    .is_none_or(|current| !ptr::eq(current.get(), task)),
+                "task {} freed while it is the current task",
+                (*task).id
+            );
+            debug_assert!(
+                !is_queued(task),
+                "task {} freed while it is in a scheduler queue",
+                (*task).id
+            );
+
+            drop(Box::from_raw(task_box));
+            LIVE_TASKS -= 1;
+        }
+    }
+}
+
+// Check if we are running on the kernel stack of the task.
+fn is_running_on(task: &Task) -> bool {
+    let rsp: usize;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+
+    (task.kernel_stack.ptr as usize..task.kernel_stack.top()).contains(&rsp)
+}
+
+// Check if the task is in the ready queues of any CPU, or in the sleeping queue.
+unsafe fn is_queued(task: *const Task) -> bool {
+    unsafe {
+        CPUS[..online_cpus()]
+            .iter()
+            .flat_map(|cpu| cpu.ready_tasks.iter().flatten())
+            .chain(SLEEPING_TASKS.iter())
+            .any(|other| ptr::eq(other.get(), task))
+    }
+}
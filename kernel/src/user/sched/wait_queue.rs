@@ -0,0 +1,153 @@
+//! Wait queues, for blocking tasks until an event happens.
+
+use core::cell::UnsafeCell;
+
+use alloc::collections::vec_deque::VecDeque;
+
+use crate::{
+    idt::without_interrupt,
+    irq,
+    user::{
+        sched::{
+            BLOCKED_TASKS, CURRENT_TASK, TaskRef, end_wait, insert_sleeping_task,
+            reserve_for_waiting_task, wake_task, yield_task_must_swap,
+        },
+        task::{Task, TaskState},
+    },
+};
+
+/// A list of tasks blocked on an event.
+///
+/// Tasks block with wait(), and are woken up in order with wake_one() or wake_all(). Waking up only moves tasks to the
+/// ready queue, so it can be done from interrupt handlers.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    tasks: UnsafeCell<VecDeque<TaskRef>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            tasks: UnsafeCell::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the current task on this queue, and switch to another task.
+    /// Returns once the task is woken up and scheduled again, or right away if a signal is pending for the task.
+    /// Callers that wait in a loop must check signal::pending() and fail with EINTR, or they would never block.
+    ///
+    /// # Safety
+    /// CURRENT_TASK must be Some, and this must not be called from an interrupt handler.
+    pub unsafe fn wait(&self) {
+        irq::assert_not_in_interrupt("WaitQueue::wait()");
+
+        without_interrupt(|| unsafe {
+            let current_task = CURRENT_TASK.as_ref().unwrap_unchecked();
+            let task = &mut *current_task.get();
+            if task.signals.deliverable() != 0 {
+                return;
+            }
+
+            // Keep room in the ready queues for the task to be woken up
+            reserve_for_waiting_task();
+            BLOCKED_TASKS += 1;
+
+            task.state = TaskState::Blocked;
+            task.waiting_on = self;
+
+            // The queue holds its own handle of the task, because switching away drops the current one
+            (*self.tasks.get()).push_back(current_task.clone_ref());
+
+            yield_task_must_swap();
+        });
+    }
+
+    /// Block the current task on this queue like wait(), but only until the timer reaches wake_tick. Returns true if
+    /// the task was woken up (or a signal is pending), or false if it timed out.
+    ///
+    /// # Safety
+    /// CURRENT_TASK must be Some, and this must not be called from an interrupt handler. The queue must not move or
+    /// be dropped while the task waits on it.
+    pub unsafe fn wait_until(&self, wake_tick: usize) -> bool {
+        irq::assert_not_in_interrupt("WaitQueue::wait_until()");
+
+        without_interrupt(|| unsafe {
+            let current_task = CURRENT_TASK.as_ref().unwrap_unchecked();
+            let task = &mut *current_task.get();
+            if task.signals.deliverable() != 0 {
+                return true;
+            }
+
+            reserve_for_waiting_task();
+            BLOCKED_TASKS += 1;
+
+            task.state = TaskState::Blocked;
+            task.wake_tick = wake_tick;
+            task.waiting_on = self;
+            task.timed_out = false;
+
+            // Both the queue and the sleeping tasks hold a handle. Whichever wakes the task up first takes it out of
+            // the other one.
+            (*self.tasks.get()).push_back(current_task.clone_ref());
+            insert_sleeping_task(current_task.clone_ref());
+
+            yield_task_must_swap();
+
+            !(*CURRENT_TASK.as_ref().unwrap_unchecked().get()).timed_out
+        })
+    }
+
+    /// Wake up the task that has waited the longest. Returns false if no task was waiting.
+    pub fn wake_one(&self) -> bool {
+        self.wake_matching(1, |_| true) == 1
+    }
+
+    /// Wake up to max tasks for which f returns true, the ones that have waited the longest first. Returns the number
+    /// of tasks woken up.
+    pub fn wake_matching(&self, max: usize, mut f: impl FnMut(&Task) -> bool) -> usize {
+        without_interrupt(|| unsafe {
+            let tasks = &mut *self.tasks.get();
+            let mut count = 0;
+            let mut index = 0;
+            while count < max && index < tasks.len() {
+                if !f(&*tasks[index].get()) {
+                    index += 1;
+                    continue;
+                }
+
+                let task = tasks.remove(index).unwrap();
+                end_wait(&task);
+                BLOCKED_TASKS -= 1;
+                wake_task(task);
+                count += 1;
+            }
+            count
+        })
+    }
+
+    // Take the task out of the queue without waking it up, and return the handle the queue held. Interrupts must be
+    // disabled.
+    pub(super) unsafe fn remove(&self, task: &TaskRef) -> Option<TaskRef> {
+        let tasks = unsafe { &mut *self.tasks.get() };
+        let index = tasks.iter().position(|other| other.ptr_eq(task))?;
+        tasks.remove(index)
+    }
+
+    /// Wake up every waiting task. Returns the number of tasks woken up.
+    pub fn wake_all(&self) -> usize {
+        let mut count = 0;
+        while self.wake_one() {
+            count += 1;
+        }
+        count
+    }
+
+    /// Number of tasks waiting on this queue.
+    pub fn len(&self) -> usize {
+        without_interrupt(|| unsafe { (*self.tasks.get()).len() })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,263 @@
+//! Signals: sending them to tasks, and running their handlers (see elytra_abi::signal).
+//!
+//! Sending a signal marks it pending, and wakes up the task if it is blocked or sleeping, so its syscall fails with
+//! EINTR. Pending signals are delivered on the way back to user mode from a syscall, where the registers of the task
+//! are in its SyscallFrame and can be swapped for the ones of the handler. Interrupts from user mode only deliver
+//! signals without a handler, which terminate the task, so a task that never makes a syscall can still be killed.
+
+use core::{mem, slice};
+
+use elytra_abi::signal::{NSIG, RED_ZONE_SIZE, SIGKILL, SIGNAL_EXIT_BASE, SigContext};
+
+use crate::{
+    consts::USERSPACE_LIMIT,
+    fpu::FpuState,
+    helper::align_down,
+    idt::without_interrupt,
+    printlnk,
+    user::{
+        sched::{self, TASK_TABLE},
+        syscall::{
+            SyscallFrame,
+            errno::{self, Errno},
+        },
+        uaccess,
+    },
+};
+
+// Flags user mode may set in rflags: CF, PF, AF, ZF, SF, TF, DF and OF.
+const USER_RFLAGS: u64 = 0xdd5;
+const RFLAGS_IF: u64 = 0x200;
+const RFLAGS_DF: u64 = 0x400;
+
+/// What a task does when a signal arrives.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignalAction {
+    pub handler: usize,  // Address of the handler, or 0 to terminate the task
+    pub restorer: usize, // Address the handler returns to, which calls sys_sigreturn()
+}
+
+/// The signal state of a task. Signal sets have one bit per signal number.
+#[derive(Debug, Clone, Default)]
+pub struct Signals {
+    pub pending: u64, // Sent, but not delivered yet
+    pub blocked: u64, // Not delivered until unblocked, while their handler runs
+    pub actions: [SignalAction; NSIG],
+}
+
+impl Signals {
+    /// Pending signals that are not blocked.
+    pub fn deliverable(&self) -> u64 {
+        self.pending & !self.blocked
+    }
+
+    /// The signal state of a child after fork: the same handlers and blocked signals, but nothing pending.
+    pub fn fork(&self) -> Self {
+        Signals {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Terminate on every signal again, as after exec the handlers are gone.
+    pub fn reset_handlers(&mut self) {
+        self.actions = Default::default();
+    }
+
+    // Take the lowest deliverable signal out of the pending ones, and return it with its action.
+    fn take(&mut self) -> Option<(usize, SignalAction)> {
+        let deliverable = self.deliverable();
+        if deliverable == 0 {
+            return None;
+        }
+        let sig = deliverable.trailing_zeros() as usize;
+        self.pending &= !(1 << sig);
+        Some((sig, self.actions[sig]))
+    }
+}
+
+/// Check if the current task has a signal to deliver. Blocking syscalls fail with EINTR then.
+pub fn pending() -> bool {
+    sched::with_current_task(|task| task.signals.deliverable() != 0)
+}
+
+/// Send the signal to the task with the id, and interrupt its wait if it is blocked or sleeping. Signal 0 only checks
+/// that the task exists. Fails with ESRCH if there is no such task, and with EPERM for kernel tasks, which never
+/// return to user mode to be signalled.
+pub fn send(id: usize, sig: usize) -> Result<(), Errno> {
+    if sig >= NSIG {
+        return Err(Errno::EINVAL);
+    }
+
+    without_interrupt(|| unsafe {
+        let task = TASK_TABLE.get(&id).ok_or(Errno::ESRCH)?;
+        if (*task.get()).addr_space.is_kernel() {
+            return Err(Errno::EPERM);
+        }
+        if sig == 0 {
+            return Ok(());
+        }
+
+        let signals = &mut (*task.get()).signals;
+        signals.pending |= 1 << sig;
+        if signals.deliverable() & (1 << sig) != 0 {
+            sched::interrupt_wait(task);
+        }
+        Ok(())
+    })
+}
+
+/// Set the handler of the signal for the current task, or terminate on it if handler is 0. Handlers return into
+/// restorer. SIGKILL always terminates.
+pub fn set_action(sig: usize, handler: usize, restorer: usize) -> Result<(), Errno> {
+    if sig == 0 || sig >= NSIG || sig == SIGKILL {
+        return Err(Errno::EINVAL);
+    }
+    // The handler becomes the rip of sysret, which faults in the kernel if it isn't a user address
+    if handler >= USERSPACE_LIMIT || restorer >= USERSPACE_LIMIT {
+        return Err(Errno::EINVAL);
+    }
+
+    sched::with_current_task(|task| {
+        task.signals.actions[sig] = SignalAction { handler, restorer };
+    });
+    Ok(())
+}
+
+/// Deliver a pending signal of the current task, which is about to return from a syscall with ret in rax. A signal
+/// without a handler terminates the task. Otherwise, the registers in the frame are saved on the user stack, and the
+/// task returns into the handler instead, with the signal number in frame.args.arg1 (see syscall_return()).
+///
+/// # Safety
+/// CURRENT_TASK must be Some, and frame must be the SyscallFrame the task returns to user mode with.
+pub unsafe fn deliver(frame: &mut SyscallFrame, ret: isize) {
+    let Some((sig, action)) = sched::with_current_task(|task| task.signals.take()) else {
+        return;
+    };
+    if action.handler == 0 {
+        unsafe { terminate(sig) };
+    }
+
+    let mut context = SigContext {
+        fpu: [0; 512],
+        rax: ret as u64,
+        rip: frame.rip as u64,
+        rsp: frame.rsp as u64,
+        rflags: frame.rflags as u64,
+        rbx: frame.rbx as u64,
+        rbp: frame.rbp as u64,
+        r12: frame.r12 as u64,
+        r13: frame.r13 as u64,
+        r14: frame.r14 as u64,
+        r15: frame.r15 as u64,
+        blocked: 0,
+    };
+    // The kernel doesn't use the FPU, so the registers still hold the state of the task
+    context.fpu = *FpuState::current().as_bytes();
+
+    // The context goes below the red zone, 16-byte aligned, and the return address below it. So the handler is entered
+    // like a called function, with rsp 8 bytes off of 16-byte alignment.
+    let context_addr =
+        align_down(frame.rsp.wrapping_sub(RED_ZONE_SIZE), 16).wrapping_sub(size_of::<SigContext>());
+    let return_addr = context_addr.wrapping_sub(size_of::<usize>());
+
+    let saved = sched::with_current_task(|task| {
+        context.blocked = task.signals.blocked;
+        let bytes = unsafe {
+            slice::from_raw_parts(&raw const context as *const u8, size_of::<SigContext>())
+        };
+        uaccess::copy_to_user(&mut task.addr_space, context_addr, bytes)?;
+        uaccess::copy_to_user(
+            &mut task.addr_space,
+            return_addr,
+            &action.restorer.to_ne_bytes(),
+        )?;
+
+        // The signal stays blocked until the handler returns, so it doesn't interrupt its own handler
+        task.signals.blocked |= 1 << sig;
+        Ok::<_, Errno>(())
+    });
+    if saved.is_err() {
+        sched::with_current_task(|task| {
+            printlnk!(
+                "Killing task {} ({}): no room on its stack for the handler of signal {}",
+                task.id,
+                task.name(),
+                sig
+            );
+        });
+        unsafe { sched::kill_task() };
+    }
+
+    frame.args.arg1 = sig;
+    frame.rip = action.handler;
+    frame.rsp = return_addr;
+    // Functions expect the direction flag to be clear
+    frame.rflags &= !RFLAGS_DF as usize;
+}
+
+/// Terminate the current task if it has a deliverable signal without a handler. Called when an interrupt returns to
+/// user mode, where handlers can't be run, as the registers of the task are not in a SyscallFrame.
+///
+/// # Safety
+/// CURRENT_TASK must be Some, and the interrupt must have come from user mode.
+pub unsafe fn deliver_fatal() {
+    let fatal = sched::with_current_task(|task| {
+        let signals = &task.signals;
+        (0..NSIG).find(|&sig| {
+            signals.deliverable() & (1 << sig) != 0 && signals.actions[sig].handler == 0
+        })
+    });
+    if let Some(sig) = fatal {
+        unsafe { terminate(sig) };
+    }
+}
+
+/// Return from a signal handler: restore the registers and blocked signals from the SigContext at the stack pointer
+/// of the handler, into the frame the task returns to user mode with. Returns what the interrupted syscall returned.
+///
+/// # Safety
+/// CURRENT_TASK must be Some, and frame must be the SyscallFrame of the current syscall.
+pub unsafe fn sigreturn(frame: &mut SyscallFrame) -> Result<usize, Errno> {
+    // Plain integers, for which all zeroes is valid
+    let mut context: SigContext = unsafe { mem::zeroed() };
+    let bytes =
+        unsafe { slice::from_raw_parts_mut(&raw mut context as *mut u8, size_of::<SigContext>()) };
+    sched::with_current_task(|task| {
+        uaccess::copy_from_user(&mut task.addr_space, bytes, frame.rsp)
+    })?;
+
+    // Everything in the context may have been changed by user mode, so it is checked before anything is restored
+    let result = errno::decode(context.rax as isize).ok_or(Errno::EINVAL)?;
+    if context.rip as usize >= USERSPACE_LIMIT {
+        return Err(Errno::EINVAL);
+    }
+
+    FpuState::from_user(&context.fpu).restore();
+    sched::with_current_task(|task| task.signals.blocked = context.blocked & !(1 << SIGKILL));
+
+    frame.rip = context.rip as usize;
+    frame.rsp = context.rsp as usize;
+    frame.rflags = ((context.rflags & USER_RFLAGS) | RFLAGS_IF | 0x2) as usize;
+    frame.rbx = context.rbx as usize;
+    frame.rbp = context.rbp as usize;
+    frame.r12 = context.r12 as usize;
+    frame.r13 = context.r13 as usize;
+    frame.r14 = context.r14 as usize;
+    frame.r15 = context.r15 as usize;
+
+    result
+}
+
+// Terminate the current task because of the signal.
+unsafe fn terminate(sig: usize) -> ! {
+    sched::with_current_task(|task| {
+        printlnk!(
+            "task {} ({}) killed by signal {}",
+            task.id,
+            task.name(),
+            sig
+        );
+    });
+    unsafe { sched::exit_current(SIGNAL_EXIT_BASE + sig as i32) }
+}
@@ -0,0 +1,155 @@
+//! The syscall table, which maps syscall numbers to their handlers.
+//!
+//! Handlers access the current task with sched::with_current_task(), as many of them switch tasks, and the task
+//! can't stay borrowed across a switch.
+
+use elytra_abi::nr::*;
+
+use crate::user::{
+    sched,
+    syscall::{
+        SyscallArgs,
+        errno::{self, Errno, SyscallResult},
+        sys_brk, sys_clock_gettime, sys_close, sys_dmesg, sys_exec, sys_exit, sys_fb_blit,
+        sys_fb_info, sys_fork, sys_futex_wait, sys_futex_wake, sys_get_fs_base, sys_getpid,
+        sys_getppid, sys_getrandom, sys_kill, sys_maps, sys_mmap, sys_pipe, sys_read, sys_reboot,
+        sys_set_fs_base, sys_set_priority, sys_sigaction, sys_sigreturn, sys_sleep_ms,
+        sys_sleep_ns, sys_spawn, sys_sysinfo, sys_task_stats, sys_trace_me, sys_wait,
+        sys_wait_tick, sys_write,
+        trace::{self, Arg, Arg::*},
+    },
+};
+
+/// A syscall handler. dispatch() encodes its result for rax.
+pub type SyscallHandler = fn(&mut SyscallArgs) -> SyscallResult;
+
+/// A syscall in the table.
+#[derive(Debug, Clone, Copy)]
+pub struct Syscall {
+    pub name: &'static str,
+    pub args: &'static [Arg], // How the tracer prints each argument
+    pub ret: Arg,             // How the tracer prints the result
+    pub handler: SyscallHandler,
+}
+
+/// Every syscall, indexed by its number.
+pub static SYSCALLS: [Option<Syscall>; SYSCALL_COUNT] = {
+    const fn syscall(
+        name: &'static str,
+        args: &'static [Arg],
+        ret: Arg,
+        handler: SyscallHandler,
+    ) -> Option<Syscall> {
+        Some(Syscall {
+            name,
+            args,
+            ret,
+            handler,
+        })
+    }
+
+    let mut table = [None; SYSCALL_COUNT];
+    table[SYS_EXIT] = syscall("exit", &[Int], Dec, |args| sys_exit(args.arg1 as i32));
+    table[SYS_YIELD] = syscall("yield", &[], Dec, |_| {
+        unsafe { sched::yield_task() };
+        Ok(0)
+    });
+    table[SYS_BRK] = syscall("brk", &[Hex], Hex, |args| sys_brk(args.arg1));
+    table[SYS_MMAP] = syscall("mmap", &[Dec, Hex, Hex], Hex, |args| {
+        sys_mmap(args.arg1, args.arg2, args.arg3)
+    });
+    table[SYS_WRITE] = syscall("write", &[Dec, Str, Dec], Dec, |args| {
+        sys_write(args.arg1, args.arg2, args.arg3)
+    });
+    table[SYS_MAPS] = syscall("maps", &[Hex, Dec], Dec, |args| {
+        sys_maps(args.arg1, args.arg2)
+    });
+    table[SYS_SLEEP_MS] = syscall("sleep_ms", &[Dec], Dec, |args| sys_sleep_ms(args.arg1));
+    table[SYS_SET_PRIORITY] = syscall("set_priority", &[Dec], Dec, |args| {
+        sys_set_priority(args.arg1)
+    });
+    table[SYS_WAIT_TICK] = syscall("wait_tick", &[], Dec, |_| sys_wait_tick());
+    table[SYS_SPAWN] = syscall("spawn", &[Str, Dec, Hex, Hex], Dec, |args| {
+        sys_spawn(args.arg1, args.arg2, args.arg3, args.arg4)
+    });
+    table[SYS_WAIT] = syscall("wait", &[Dec, Hex], Dec, |args| {
+        sys_wait(args.arg1, args.arg2)
+    });
+    table[SYS_FORK] = syscall("fork", &[], Dec, |_| sys_fork());
+    table[SYS_EXEC] = syscall("exec", &[Str, Dec, Hex], Dec, |args| {
+        sys_exec(args.arg1, args.arg2, args.arg3)
+    });
+    table[SYS_TASK_STATS] = syscall("task_stats", &[Hex, Dec], Dec, |args| {
+        sys_task_stats(args.arg1, args.arg2)
+    });
+    table[SYS_GETPID] = syscall("getpid", &[], Dec, |_| sys_getpid());
+    table[SYS_READ] = syscall("read", &[Dec, Str, Dec], Dec, |args| {
+        sys_read(args.arg1, args.arg2, args.arg3)
+    });
+    table[SYS_GETPPID] = syscall("getppid", &[], Dec, |_| sys_getppid());
+    table[SYS_TRACE_ME] = syscall("trace_me", &[Dec], Dec, |args| sys_trace_me(args.arg1));
+    table[SYS_CLOCK_GETTIME] = syscall("clock_gettime", &[Dec, Hex], Dec, |args| {
+        sys_clock_gettime(args.arg1, args.arg2)
+    });
+    table[SYS_SLEEP_NS] = syscall("sleep_ns", &[Dec], Dec, |args| sys_sleep_ns(args.arg1));
+    table[SYS_FB_INFO] = syscall("fb_info", &[Hex], Dec, |args| sys_fb_info(args.arg1));
+    table[SYS_FB_BLIT] = syscall("fb_blit", &[Dec, Dec, Dec, Dec, Hex], Dec, |args| {
+        sys_fb_blit(args.arg1, args.arg2, args.arg3, args.arg4, args.arg5)
+    });
+    table[SYS_PIPE] = syscall("pipe", &[Hex], Dec, |args| sys_pipe(args.arg1));
+    table[SYS_CLOSE] = syscall("close", &[Dec], Dec, |args| sys_close(args.arg1));
+    table[SYS_FUTEX_WAIT] = syscall("futex_wait", &[Hex, Dec, Dec], Dec, |args| {
+        sys_futex_wait(args.arg1, args.arg2, args.arg3)
+    });
+    table[SYS_FUTEX_WAKE] = syscall("futex_wake", &[Hex, Dec], Dec, |args| {
+        sys_futex_wake(args.arg1, args.arg2)
+    });
+    table[SYS_KILL] = syscall("kill", &[Dec, Dec], Dec, |args| {
+        sys_kill(args.arg1, args.arg2)
+    });
+    table[SYS_SIGACTION] = syscall("sigaction", &[Dec, Hex, Hex], Dec, |args| {
+        sys_sigaction(args.arg1, args.arg2, args.arg3)
+    });
+    table[SYS_SIGRETURN] = syscall("sigreturn", &[], Dec, sys_sigreturn);
+    table[SYS_GETRANDOM] = syscall("getrandom", &[Hex, Dec], Dec, |args| {
+        sys_getrandom(args.arg1, args.arg2)
+    });
+    table[SYS_SYSINFO] = syscall("sysinfo", &[Hex], Dec, |args| sys_sysinfo(args.arg1));
+    table[SYS_DMESG] = syscall("dmesg", &[Hex, Dec], Dec, |args| {
+        sys_dmesg(args.arg1, args.arg2)
+    });
+    table[SYS_REBOOT] = syscall("reboot", &[Hex, Dec], Dec, |args| {
+        sys_reboot(args.arg1, args.arg2)
+    });
+    table[SYS_SET_FS_BASE] = syscall("set_fs_base", &[Hex], Dec, |args| {
+        sys_set_fs_base(args.arg1)
+    });
+    table[SYS_GET_FS_BASE] = syscall("get_fs_base", &[], Hex, |_| sys_get_fs_base());
+    table
+};
+
+/// Run the handler of the syscall, and return its result. Unknown syscall numbers fail with ENOSYS.
+///
+/// The syscall is traced if tracing is on for the current task either before or after it, so turning tracing on or
+/// off with sys_trace_me is traced too.
+pub fn dispatch(args: &mut SyscallArgs) -> isize {
+    let traced = trace::is_traced();
+
+    let Some(syscall) = SYSCALLS.get(args.num).copied().flatten() else {
+        if traced {
+            trace::trace_unknown(args.num);
+        }
+        return errno::encode(Err(Errno::ENOSYS));
+    };
+
+    if traced && args.num == SYS_EXIT {
+        trace::trace(&syscall, args, None);
+    }
+
+    let result = (syscall.handler)(args);
+
+    if traced || trace::is_traced() {
+        trace::trace(&syscall, args, Some(result));
+    }
+    errno::encode(result)
+}
@@ -0,0 +1,92 @@
+//! Error numbers, and how syscall results are returned to user mode.
+//!
+//! A syscall returns a value in rax: a negated error number in -MAX_ERRNO..=-1 if it failed, or its result otherwise.
+//! Handlers return a SyscallResult, which dispatch() encodes.
+
+use elytra_abi::errno as abi;
+
+use crate::user::uaccess::Fault;
+
+pub use elytra_abi::errno::MAX_ERRNO;
+
+/// An error number, with the values in elytra_abi::errno.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    EPERM = abi::EPERM,
+    ENOENT = abi::ENOENT,
+    ESRCH = abi::ESRCH,
+    EINTR = abi::EINTR,
+    E2BIG = abi::E2BIG,
+    ENOEXEC = abi::ENOEXEC,
+    EBADF = abi::EBADF,
+    ECHILD = abi::ECHILD,
+    EAGAIN = abi::EAGAIN,
+    ENOMEM = abi::ENOMEM,
+    EFAULT = abi::EFAULT,
+    ENODEV = abi::ENODEV,
+    EINVAL = abi::EINVAL,
+    EMFILE = abi::EMFILE,
+    EPIPE = abi::EPIPE,
+    ENOSYS = abi::ENOSYS,
+    ETIMEDOUT = abi::ETIMEDOUT,
+}
+
+impl Errno {
+    // Every error number, for decode()
+    const ALL: [Errno; 17] = [
+        Errno::EPERM,
+        Errno::ENOENT,
+        Errno::ESRCH,
+        Errno::EINTR,
+        Errno::E2BIG,
+        Errno::ENOEXEC,
+        Errno::EBADF,
+        Errno::ECHILD,
+        Errno::EAGAIN,
+        Errno::ENOMEM,
+        Errno::EFAULT,
+        Errno::ENODEV,
+        Errno::EINVAL,
+        Errno::EMFILE,
+        Errno::EPIPE,
+        Errno::ENOSYS,
+        Errno::ETIMEDOUT,
+    ];
+}
+
+/// The result of a syscall handler.
+pub type SyscallResult = Result<usize, Errno>;
+
+/// Encode the result of a syscall as its value in rax.
+pub fn encode(result: SyscallResult) -> isize {
+    match result {
+        Ok(value) => {
+            debug_assert!(
+                value < MAX_ERRNO.wrapping_neg(),
+                "syscall result {:#x} looks like an error",
+                value
+            );
+            value as isize
+        }
+        Err(errno) => -(errno as isize),
+    }
+}
+
+/// Decode a value in rax back into the result it encodes. Returns None for a negated number that isn't an Errno.
+pub fn decode(ret: isize) -> Option<SyscallResult> {
+    if !abi::is_error(ret) {
+        return Some(Ok(ret as usize));
+    }
+    let number = ret.unsigned_abs();
+    Errno::ALL
+        .into_iter()
+        .find(|&errno| errno as usize == number)
+        .map(Err)
+}
+
+impl From<Fault> for Errno {
+    fn from(_: Fault) -> Self {
+        Errno::EFAULT
+    }
+}
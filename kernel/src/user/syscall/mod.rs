@@ -0,0 +1,778 @@
+//! Syscalls using the SYSCALL/SYSRET instructions.
+//!
+//! Calling convention for userspace:
+//!   RAX: syscall number
+//!   RDI, RSI, RDX, R10, R8, R9: arguments
+//!   RAX: return value
+//!   Caller-saved and callee-saved registers are the same as System V AMD64 ABI.
+//!
+//! Handlers run with interrupts enabled, so devices and the timer are served during long syscalls. Interrupt handlers
+//! only wake tasks and set NEED_RESCHED, and the task is preempted on the way back to user mode, so a handler is never
+//! switched away from unless it blocks. Anything an interrupt handler also touches, like the current task, the
+//! scheduler queues and the serial buffer, is only used with interrupts disabled.
+
+pub mod dispatch;
+pub mod errno;
+pub mod trace;
+
+use core::{arch::naked_asm, mem::offset_of, slice};
+
+use alloc::{string::String, vec::Vec};
+use bootloader_api::info::PixelFormat;
+use elytra_abi::{
+    fb::{FB_FORMAT_BGR, FB_FORMAT_GRAY, FB_FORMAT_RGB, FB_FORMAT_UNKNOWN, FbInfo},
+    mm::{MAP_SHARED, PROT_WRITE},
+    power::{REBOOT_CMD_POWER_OFF, REBOOT_CMD_RESTART, REBOOT_MAGIC},
+    task::SPAWN_TRACE,
+    time::{CLOCK_MONOTONIC, CLOCK_REALTIME, Timespec},
+};
+
+use crate::{
+    cpu::segment_base,
+    gdt::{KERNEL_CODE_SELECTOR, SYSRET_BASE_SELECTOR},
+    idt::without_interrupt,
+    io::{klog, output, rtc, serial},
+    msr::{IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR, read_msr, write_msr},
+    power, printk, printlnk, rand, time,
+    user::{
+        address_space::{AddressSpace, MapError},
+        elf_parser::ElfError,
+        fd::{self, File},
+        futex, programs,
+        sched::{self, TaskRef, cpu, cpu::MAX_CPUS, stats},
+        signal,
+        syscall::errno::{Errno, SyscallResult},
+        task::Task,
+        uaccess,
+    },
+};
+
+pub fn init() {
+    // Enable SYSCALL/SYSRET in IA32_EFER
+    write_msr(IA32_EFER, read_msr(IA32_EFER) | 1);
+
+    // Setup segment selectors in IA32_STAR
+    //
+    // For SYSCALL, we set IA32_STAR[47:32] to KERNEL_CODE_SELECTOR (0x8):
+    //   CS: IA32_STAR[47:32]        = 0x8 (Kernel code segment)
+    //   SS: IA32_STAR[47:32] + 8    = 0x10 (Kernel data segment)
+    //
+    // For SYSRET, we set IA32_STAR[63:48] to SYSRET_BASE_SELECTOR (0x10):
+    //   CS: IA32_STAR[63:48] + 16   = 0x20 (User code segment)
+    //   SS: IA32_STAR[63:48] + 8    = 0x18 (User data segment)
+    write_msr(
+        IA32_STAR,
+        (KERNEL_CODE_SELECTOR as u64) << 32 | (SYSRET_BASE_SELECTOR as u64) << 48,
+    );
+
+    // Set syscall entry address in IA32_LSTAR
+    write_msr(IA32_LSTAR, syscall_entry as *const () as u64);
+
+    // Set flags mask in IA32_FMASK (clear trap flag, disable interrupt, clear direction flag)
+    // syscall_entry() relies on interrupts being disabled until it is on the kernel stack. User mode may leave the
+    // direction flag set, which would make string instructions in the kernel copy backwards.
+    write_msr(IA32_FMASK, 0x700);
+
+    // syscall_entry() swaps IA32_KERNEL_GS_BASE into GS base to find the scratch space of this CPU
+    unsafe { segment_base::write_kernel_gs_base(this_scratch() as u64) };
+}
+
+/// Per-CPU scratch space of syscall_entry(), which IA32_KERNEL_GS_BASE points to.
+///
+/// The kernel runs with the GS base of user mode, which it doesn't use. syscall_entry() swaps this in with swapgs just
+/// long enough to switch to the kernel stack, and the context switch does the same to set kernel_stack.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallScratch {
+    pub kernel_stack: usize, // Top of the kernel stack of the task running on this CPU
+    pub user_rsp: usize,     // User rsp, while syscall_entry() moves it to the kernel stack
+}
+
+static mut SYSCALL_SCRATCH: [SyscallScratch; MAX_CPUS] = [const {
+    SyscallScratch {
+        kernel_stack: 0,
+        user_rsp: 0,
+    }
+}; MAX_CPUS];
+
+/// The syscall scratch space of the CPU we are running on.
+pub fn this_scratch() -> *mut SyscallScratch {
+    unsafe { &raw mut SYSCALL_SCRATCH[cpu::this_cpu_id()] }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone)]
+pub struct SyscallArgs {
+    pub num: usize,  // rax
+    pub arg1: usize, // rdi
+    pub arg2: usize, // rsi
+    pub arg3: usize, // rdx
+    pub arg4: usize, // r10
+    pub arg5: usize, // r8
+    pub arg6: usize, // r9
+}
+
+/// Everything syscall_entry() saves on the kernel stack, from the lowest address up. The frame ends at the top of the
+/// kernel stack of the task.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct SyscallFrame {
+    pub args: SyscallArgs,
+    pub rbp: usize,
+    pub rbx: usize,
+    pub r12: usize,
+    pub r13: usize,
+    pub r14: usize,
+    pub r15: usize,
+    pub rip: usize,    // rcx
+    pub rflags: usize, // r11
+    pub rsp: usize,
+}
+
+impl SyscallFrame {
+    /// The frame that starts with args, as syscall_entry() passes them to the handler.
+    ///
+    /// # Safety
+    /// args must be the SyscallArgs of the current syscall, and must not be used while the frame is.
+    pub unsafe fn from_args(args: &mut SyscallArgs) -> &mut SyscallFrame {
+        unsafe { &mut *(args as *mut SyscallArgs as *mut SyscallFrame) }
+    }
+}
+
+#[unsafe(naked)]
+pub extern "C" fn syscall_entry() {
+    naked_asm!(
+        // Interrupts are disabled by IA32_FMASK, so nothing else can run on this CPU until sti.
+        "swapgs",                    // GS base is now the SyscallScratch of this CPU
+        "mov gs:[{user_rsp}], rsp",  // Save user rsp temporarily
+        "mov rsp, gs:[{stack}]",     // Load kernel stack rsp
+
+        "push gs:[{user_rsp}]",      // Save user rsp
+        "swapgs",                    // Back to the GS base of user mode
+
+        "sti",                       // Enable interrupts, now that everything is on the kernel stack
+
+        "push r11",                  // Save r11 (user rflags)
+        "push rcx",                  // Save rcx (user rip)
+
+        // syscall_handler follows System V, so callee-saved registers are preserved anyway.
+        // They are saved in the frame so fork can give the child the same values.
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push rbx",
+        "push rbp",
+
+        "push r9",                   // Save syscall arguments in SyscallArgs struct
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+
+        "mov rdi, rsp",              // First argument: pointer to SyscallArgs
+
+        // Caller-saved registers are not saved. If the execution messes up, we might leak data to user mode or mess
+        // up user mode. We might need to assess if such a risk is acceptable in the future.
+        "call {handler}",            // Call syscall handler
+
+        "jmp {syscall_return}",
+
+        user_rsp = const offset_of!(SyscallScratch, user_rsp),
+        stack = const offset_of!(SyscallScratch, kernel_stack),
+        handler = sym syscall_handler,
+        syscall_return = sym syscall_return,
+    )
+}
+
+/// Return to user mode from a syscall, with the SyscallFrame on top of the stack and the return value in rax.
+///
+/// # Safety
+/// Must be jumped to, with a SyscallFrame of a syscall made from user mode at the top of the stack.
+#[unsafe(naked)]
+pub unsafe extern "C" fn syscall_return() -> ! {
+    naked_asm!(
+        // rdi is args.arg1, which syscall_handler() clears unless it enters a signal handler (see signal)
+        "mov rdi, [rsp + 8]",
+        "add rsp, 56",  // Clean up SyscallArgs
+        "xor esi, esi", // Clear registers to prevent leaking data to user mode
+        "xor edx, edx", // (caller-saved registers, rax, rcx and r11 are ignored)
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "pop rbp", // Restore callee-saved registers
+        "pop rbx",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+        "pop rcx", // Restore rcx (user rip)
+        "pop r11", // Restore r11 (user rflags)
+        "cli",     // Disable interrupts
+        "pop rsp", // Restore user rsp
+        "sysretq", // Return to user mode
+    )
+}
+
+pub extern "C" fn syscall_handler(args: &mut SyscallArgs) -> usize {
+    let ret = dispatch::dispatch(args);
+
+    // The timer may have asked for a reschedule while we were in the kernel
+    unsafe { sched::preempt_if_needed() };
+
+    // syscall_return() passes arg1 back in rdi, which only a signal handler gets
+    args.arg1 = 0;
+    unsafe { signal::deliver(SyscallFrame::from_args(args), ret) };
+
+    ret as usize
+}
+
+// Exit the current task with the given exit code. This never returns to the task.
+// The syscall frame is on the kernel stack of the task, so the task is freed by the next context after the switch.
+fn sys_exit(code: i32) -> ! {
+    sched::with_current_task(|task| {
+        printlnk!(
+            "task {} ({}) exited with code {}",
+            task.id,
+            task.name(),
+            code
+        );
+    });
+
+    unsafe { sched::exit_current(code) }
+}
+
+// Return the id of the current task.
+fn sys_getpid() -> SyscallResult {
+    Ok(sched::current_pid())
+}
+
+// Return the id of the parent of the current task, or 0 if it has none (anymore).
+fn sys_getppid() -> SyscallResult {
+    Ok(sched::with_current_task(|task| task.parent))
+}
+
+// Set the end of the heap of the current task to addr. Returns the new end of the heap. brk(0) can be used to query
+// the current end of the heap. Fails with EINVAL if addr is below the start of the heap, and with ENOMEM if the heap
+// can't grow that far, because it would run into another region or there isn't enough free memory. The heap is left
+// as it was on failure.
+fn sys_brk(addr: usize) -> SyscallResult {
+    sched::with_current_task(|task| {
+        if addr == 0 {
+            return Ok(task.addr_space.heap().1);
+        }
+        task.addr_space.grow_heap(addr).map_err(|err| match err {
+            MapError::InvalidRange if addr < task.addr_space.heap().0 => Errno::EINVAL,
+            _ => Errno::ENOMEM,
+        })
+    })
+}
+
+// Map an anonymous region of len bytes for the current task, with PROT_* flags in prot and MAP_* flags in flags.
+// Returns the address of the region. Fails with ENOMEM if len is more than the free memory can back.
+fn sys_mmap(len: usize, prot: usize, flags: usize) -> SyscallResult {
+    if flags & !MAP_SHARED != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let writable = prot & PROT_WRITE != 0;
+    let mapped = sched::with_current_task(|task| {
+        if flags & MAP_SHARED != 0 {
+            task.addr_space.map_anonymous_shared(len, writable)
+        } else {
+            task.addr_space.map_anonymous(len, writable)
+        }
+    });
+
+    mapped.map_err(|err| match err {
+        MapError::NoSpace | MapError::OutOfMemory => Errno::ENOMEM,
+        _ => Errno::EINVAL,
+    })
+}
+
+// Most bytes written by one write. Longer writes are partial, so a task can't hold the console for long.
+const MAX_WRITE_LEN: usize = 1024;
+
+// Write up to len bytes from buf to fd, which must be the console or the write end of a pipe. Writes to a pipe block
+// until all the bytes fit. Returns the number of bytes written, which is less than len if it is over MAX_WRITE_LEN, or
+// if the last reader of the pipe went away in the middle.
+fn sys_write(fd: usize, buf: usize, len: usize) -> SyscallResult {
+    let file = sched::with_current_task(|task| task.files.get(fd))?;
+    if !matches!(file, File::ConsoleOut | File::PipeWriter(_)) {
+        return Err(Errno::EBADF);
+    }
+    let len = len.min(MAX_WRITE_LEN);
+    // Check the whole buffer first, so nothing is written if part of it is bad.
+    sched::with_current_task(|task| uaccess::access_ok(&mut task.addr_space, buf, len, false))?;
+
+    let mut bytes = vec![0u8; len];
+    sched::with_current_task(|task| {
+        uaccess::copy_from_user(&mut task.addr_space, &mut bytes, buf)
+    })?;
+
+    if let File::PipeWriter(writer) = file {
+        return unsafe { writer.write(&bytes) };
+    }
+
+    // Printed at once, so the output isn't interleaved with kernel messages
+    printk!("{}", String::from_utf8_lossy(&bytes));
+    Ok(len)
+}
+
+// Most bytes returned by one read.
+const MAX_READ_LEN: usize = 256;
+
+// Read up to len bytes from fd into buf. fd must be the serial console or the read end of a pipe. Blocks until at least
+// one byte is there, as the serial console never ends. Returns the number of bytes read, or 0 at the end of a pipe.
+fn sys_read(fd: usize, buf: usize, len: usize) -> SyscallResult {
+    let file = sched::with_current_task(|task| task.files.get(fd))?;
+    if !matches!(file, File::ConsoleIn | File::PipeReader(_)) {
+        return Err(Errno::EBADF);
+    }
+    let len = len.min(MAX_READ_LEN);
+    sched::with_current_task(|task| uaccess::access_ok(&mut task.addr_space, buf, len, true))?;
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let mut bytes = [0u8; MAX_READ_LEN];
+    let count = if let File::PipeReader(reader) = file {
+        unsafe { reader.read(&mut bytes[..len]) }?
+    } else {
+        read_console(&mut bytes[..len])?
+    };
+
+    sched::with_current_task(|task| {
+        uaccess::copy_to_user(&mut task.addr_space, buf, &bytes[..count])
+    })?;
+    Ok(count)
+}
+
+// Move at least one received byte of the serial console into buf, blocking until there is one. Returns the number of
+// bytes moved. Fails with EINTR if a signal arrives first.
+fn read_console(buf: &mut [u8]) -> Result<usize, Errno> {
+    without_interrupt(|| {
+        loop {
+            // Interrupts stay disabled between the check and wait(), so no input is missed in between
+            let count = serial::read_received(buf);
+            if count != 0 {
+                break Ok(count);
+            }
+            if signal::pending() {
+                break Err(Errno::EINTR);
+            }
+            unsafe { serial::RX_WAITERS.wait() };
+        }
+    })
+}
+
+// Most bytes one getrandom returns.
+const MAX_GETRANDOM_LEN: usize = 256;
+
+// Fill buf with up to len random bytes (see rand). Returns the number of bytes, which is less than len if it is over
+// MAX_GETRANDOM_LEN.
+fn sys_getrandom(buf: usize, len: usize) -> SyscallResult {
+    let len = len.min(MAX_GETRANDOM_LEN);
+    let mut bytes = [0u8; MAX_GETRANDOM_LEN];
+    rand::fill(&mut bytes[..len]);
+
+    sched::with_current_task(|task| {
+        uaccess::copy_to_user(&mut task.addr_space, buf, &bytes[..len])
+    })?;
+    Ok(len)
+}
+
+// Create a pipe, and store the descriptors of its read end and its write end in the two i32 at fds. Fails with EMFILE
+// if the task has no two free descriptors. Returns 0.
+fn sys_pipe(fds: usize) -> SyscallResult {
+    sched::with_current_task(|task| {
+        uaccess::access_ok(&mut task.addr_space, fds, 2 * size_of::<i32>(), true)?;
+
+        let (reader, writer) = fd::pipe();
+        let read_fd = task.files.insert(File::PipeReader(reader))?;
+        let write_fd = match task.files.insert(File::PipeWriter(writer)) {
+            Ok(fd) => fd,
+            Err(errno) => {
+                task.files.close(read_fd).unwrap();
+                return Err(errno);
+            }
+        };
+
+        let pair = [read_fd as i32, write_fd as i32];
+        let bytes =
+            unsafe { slice::from_raw_parts(pair.as_ptr() as *const u8, size_of_val(&pair)) };
+        if let Err(fault) = uaccess::copy_to_user(&mut task.addr_space, fds, bytes) {
+            task.files.close(read_fd).unwrap();
+            task.files.close(write_fd).unwrap();
+            return Err(fault.into());
+        }
+        Ok(0)
+    })
+}
+
+// Block while the u32 at addr holds expected, for at most timeout_ns nanoseconds unless it is 0. Returns 0 once woken
+// up (see futex).
+fn sys_futex_wait(addr: usize, expected: usize, timeout_ns: usize) -> SyscallResult {
+    unsafe { futex::futex_wait(addr, expected as u32, timeout_ns as u64) }?;
+    Ok(0)
+}
+
+// Wake up to count tasks waiting on the u32 at addr. Returns the number of tasks woken up.
+fn sys_futex_wake(addr: usize, count: usize) -> SyscallResult {
+    futex::futex_wake(addr, count)
+}
+
+// Send the signal to the task with the id. Signal 0 only checks that the task exists. Returns 0.
+fn sys_kill(id: usize, sig: usize) -> SyscallResult {
+    signal::send(id, sig)?;
+    Ok(0)
+}
+
+// Run the handler at handler when the signal arrives, or terminate the task if it is 0. The handler returns into
+// restorer. Returns 0.
+fn sys_sigaction(sig: usize, handler: usize, restorer: usize) -> SyscallResult {
+    signal::set_action(sig, handler, restorer)?;
+    Ok(0)
+}
+
+// Return from a signal handler to where the signal interrupted the task, with the SigContext at the stack pointer.
+// Returns what the interrupted syscall returned.
+fn sys_sigreturn(args: &mut SyscallArgs) -> SyscallResult {
+    unsafe { signal::sigreturn(SyscallFrame::from_args(args)) }
+}
+
+// Close the file descriptor. Returns 0.
+fn sys_close(fd: usize) -> SyscallResult {
+    sched::with_current_task(|task| task.files.close(fd))?;
+    Ok(0)
+}
+
+// Copy the memory map of the current task (see AddressSpace::format_maps) into buf, truncated to len bytes.
+// Returns the number of bytes copied.
+fn sys_maps(buf: usize, len: usize) -> SyscallResult {
+    sched::with_current_task(|task| {
+        let mut maps = String::new();
+        task.addr_space.format_maps(&mut maps).unwrap();
+
+        let count = maps.len().min(len);
+        uaccess::copy_to_user(&mut task.addr_space, buf, &maps.as_bytes()[..count])?;
+        Ok(count)
+    })
+}
+
+// Sleep for at least ms milliseconds. A sleep of 0 ms just yields. Returns 0, or fails with EINTR if a signal cut the
+// sleep short.
+fn sys_sleep_ms(ms: usize) -> SyscallResult {
+    unsafe { sched::sleep_current(ms) };
+
+    if signal::pending() {
+        return Err(Errno::EINTR);
+    }
+    Ok(0)
+}
+
+// Sleep for at least ns nanoseconds. It is rounded up to whole timer ticks, so it is never shorter. A sleep of 0 ns just
+// yields. Returns 0, or fails with EINTR if a signal cut the sleep short.
+fn sys_sleep_ns(ns: usize) -> SyscallResult {
+    unsafe { sched::sleep_current_ns(ns as u64) };
+
+    if signal::pending() {
+        return Err(Errno::EINTR);
+    }
+    Ok(0)
+}
+
+// Store the time of the clock in the Timespec at buf. CLOCK_MONOTONIC counts from when the timer was started, and
+// CLOCK_REALTIME from 1970-01-01 UTC, as the RTC gave it at boot. Returns 0.
+fn sys_clock_gettime(clock: usize, buf: usize) -> SyscallResult {
+    let nanos = match clock {
+        CLOCK_REALTIME => rtc::realtime_ns(),
+        CLOCK_MONOTONIC => time::monotonic_ns(),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let time = Timespec::from_nanos(nanos);
+    let bytes = unsafe { slice::from_raw_parts(&raw const time as *const u8, size_of_val(&time)) };
+    sched::with_current_task(|task| uaccess::copy_to_user(&mut task.addr_space, buf, bytes))?;
+    Ok(0)
+}
+
+// Store the geometry and pixel format of the framebuffer in the FbInfo at buf. Fails with ENODEV if there is no
+// framebuffer. Returns 0.
+fn sys_fb_info(buf: usize) -> SyscallResult {
+    let info = output::with_framebuffer(|framebuffer| framebuffer.info()).ok_or(Errno::ENODEV)?;
+    let info = FbInfo {
+        width: info.width as u32,
+        height: info.height as u32,
+        stride: info.stride as u32,
+        bytes_per_pixel: info.bytes_per_pixel as u32,
+        format: match info.pixel_format {
+            PixelFormat::Rgb => FB_FORMAT_RGB,
+            PixelFormat::Bgr => FB_FORMAT_BGR,
+            PixelFormat::U8 => FB_FORMAT_GRAY,
+            _ => FB_FORMAT_UNKNOWN,
+        },
+    };
+
+    let bytes = unsafe { slice::from_raw_parts(&raw const info as *const u8, size_of_val(&info)) };
+    sched::with_current_task(|task| uaccess::copy_to_user(&mut task.addr_space, buf, bytes))?;
+    Ok(0)
+}
+
+// Draw the w x h rectangle of pixels at buf to (x, y) on the framebuffer. buf holds the rows one after another, in the
+// pixel format of the framebuffer. Rectangles that don't fit on the framebuffer fail with EINVAL, and nothing is drawn
+// if buf is bad. Returns 0.
+fn sys_fb_blit(x: usize, y: usize, w: usize, h: usize, buf: usize) -> SyscallResult {
+    let info = output::with_framebuffer(|framebuffer| framebuffer.info()).ok_or(Errno::ENODEV)?;
+    if x.checked_add(w).is_none_or(|right| right > info.width)
+        || y.checked_add(h).is_none_or(|bottom| bottom > info.height)
+    {
+        return Err(Errno::EINVAL);
+    }
+
+    let row_len = w * info.bytes_per_pixel;
+    // Check the whole buffer first, so nothing is drawn if part of it is bad
+    sched::with_current_task(|task| {
+        uaccess::access_ok(&mut task.addr_space, buf, row_len * h, false)
+    })?;
+
+    // One row at a time, so kernel messages aren't held up for long
+    let mut row = vec![0u8; row_len];
+    for i in 0..h {
+        sched::with_current_task(|task| {
+            uaccess::copy_from_user(&mut task.addr_space, &mut row, buf + i * row_len)
+        })?;
+        output::with_framebuffer(|framebuffer| framebuffer.write_row(x, y + i, &row));
+    }
+    Ok(0)
+}
+
+// Set the scheduling priority of the current task, then yield in case a task of a higher priority should run now.
+// Returns 0.
+fn sys_set_priority(priority: usize) -> SyscallResult {
+    if !sched::is_valid_priority(priority) {
+        return Err(Errno::EINVAL);
+    }
+
+    sched::with_current_task(|task| task.priority = priority);
+    unsafe { sched::yield_task() };
+
+    Ok(0)
+}
+
+// Block until the next timer tick. Returns the number of ticks since the timer was started, or fails with EINTR if a
+// signal arrived first.
+fn sys_wait_tick() -> SyscallResult {
+    unsafe { time::TICK_WAITERS.wait() };
+
+    if signal::pending() {
+        return Err(Errno::EINTR);
+    }
+    Ok(time::ticks())
+}
+
+// Longest program name that can be spawned.
+const MAX_PROGRAM_NAME: usize = 64;
+
+// Start the embedded program with the name in name[..len] as a child of the current task, with SPAWN_* flags. argv is
+// as for exec: 0 gives the program just its name as its argument. Returns the id of the new task.
+fn sys_spawn(name: usize, len: usize, flags: usize, argv: usize) -> SyscallResult {
+    if flags & !SPAWN_TRACE != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let (args, elf, files) = sched::with_current_task(|task| -> Result<_, Errno> {
+        let mut buf = [0u8; MAX_PROGRAM_NAME];
+        let (name, elf) = find_program(&mut task.addr_space, &mut buf, name, len)?;
+        let args = copy_args(&mut task.addr_space, name, argv)?;
+        Ok((args, elf, task.files.clone()))
+    })?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    // The child gets the descriptors of the parent, like after fork
+    let mut task = Task::spawn(elf, &args).map_err(elf_errno)?;
+    task.files = files;
+    task.parent = sched::current_pid();
+    task.trace_syscalls = flags & SPAWN_TRACE != 0;
+    let id = task.id;
+
+    unsafe { sched::add_new_task(TaskRef::new(task)) };
+
+    Ok(id)
+}
+
+// Turn tracing of the syscalls of the current task on (enable != 0) or off.
+fn sys_trace_me(enable: usize) -> SyscallResult {
+    sched::with_current_task(|task| task.trace_syscalls = enable != 0);
+    Ok(0)
+}
+
+// Copy the program name in name[..len] from user memory into buf, and find the embedded program with that name.
+// Returns the name and the executable.
+fn find_program<'a>(
+    addr_space: &mut AddressSpace,
+    buf: &'a mut [u8; MAX_PROGRAM_NAME],
+    name: usize,
+    len: usize,
+) -> Result<(&'a str, &'static [u8]), Errno> {
+    if len > buf.len() {
+        return Err(Errno::ENOENT);
+    }
+    uaccess::copy_from_user(addr_space, &mut buf[..len], name)?;
+    let name = str::from_utf8(&buf[..len]).map_err(|_| Errno::ENOENT)?;
+    match programs::find(name) {
+        Some(elf) => Ok((name, elf)),
+        None => Err(Errno::ENOENT),
+    }
+}
+
+// Wait for the child task with the given id to exit, and store its exit code (an i32) at status, unless status is 0.
+// Returns the id of the child.
+fn sys_wait(id: usize, status: usize) -> SyscallResult {
+    let code = unsafe { sched::wait_child(id) }?;
+
+    if status != 0 {
+        sched::with_current_task(|task| {
+            uaccess::copy_to_user(&mut task.addr_space, status, &code.to_ne_bytes())
+        })?;
+    }
+
+    Ok(id)
+}
+
+// Create a copy of the current task, which returns from this syscall with 0. Returns the id of the child to the
+// parent, or ENOMEM if there is no memory for its page tables or kernel stack.
+fn sys_fork() -> SyscallResult {
+    let child =
+        sched::with_current_task(|task| unsafe { task.fork() }).map_err(|_| Errno::ENOMEM)?;
+    let id = child.id;
+
+    unsafe { sched::add_new_task(TaskRef::new(child)) };
+
+    Ok(id)
+}
+
+// Most arguments exec and spawn accept, and the longest argument.
+const MAX_EXEC_ARGS: usize = 16;
+const MAX_EXEC_ARG_LEN: usize = 256;
+
+// Copy the arguments at argv from user memory. argv points to a NULL-terminated array of NUL-terminated strings, or is
+// 0 to pass just the name.
+fn copy_args(addr_space: &mut AddressSpace, name: &str, argv: usize) -> Result<Vec<String>, Errno> {
+    if argv == 0 {
+        return Ok(vec![String::from(name)]);
+    }
+
+    let mut args = Vec::new();
+    for index in 0..=MAX_EXEC_ARGS {
+        let mut ptr = [0u8; 8];
+        let addr = argv.checked_add(index * 8).ok_or(Errno::EFAULT)?;
+        uaccess::copy_from_user(addr_space, &mut ptr, addr)?;
+        let ptr = usize::from_ne_bytes(ptr);
+        if ptr == 0 {
+            break;
+        }
+        if index == MAX_EXEC_ARGS {
+            return Err(Errno::E2BIG);
+        }
+
+        let mut arg = [0u8; MAX_EXEC_ARG_LEN];
+        let len = uaccess::strncpy_from_user(addr_space, &mut arg, ptr)?;
+        if len == arg.len() {
+            return Err(Errno::E2BIG);
+        }
+        args.push(String::from_utf8_lossy(&arg[..len]).into_owned());
+    }
+    Ok(args)
+}
+
+// The error of a syscall that failed to load an executable.
+fn elf_errno(err: ElfError) -> Errno {
+    match err {
+        ElfError::ArgumentsTooLarge => Errno::E2BIG,
+        ElfError::OomMapping => Errno::ENOMEM,
+        _ => Errno::ENOEXEC,
+    }
+}
+
+// Replace the image of the current task with the embedded program with the name in name[..len]. argv points to a
+// NULL-terminated array of NUL-terminated strings, or is 0 to pass just the name. Never returns to the old image on
+// success, and returns an error with the old image intact on failure.
+fn sys_exec(name: usize, len: usize, argv: usize) -> SyscallResult {
+    sched::with_current_task(|current_task| {
+        let addr_space = &mut current_task.addr_space;
+
+        let mut buf = [0u8; MAX_PROGRAM_NAME];
+        let (name, elf) = find_program(addr_space, &mut buf, name, len)?;
+
+        // The arguments must be copied out before the old address space goes away
+        let args = copy_args(addr_space, name, argv)?;
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        unsafe { current_task.exec(elf, &args) }.map_err(elf_errno)?;
+        Ok(0)
+    })
+}
+
+// Copy a snapshot of up to count tasks into buf, as an array of elytra_abi::task::TaskInfo.
+// Returns the number of tasks, which may be more than count.
+fn sys_task_stats(buf: usize, count: usize) -> SyscallResult {
+    let mut tasks = Vec::new();
+    stats::for_each_task(|info| tasks.push(*info));
+
+    let copied = &tasks[..tasks.len().min(count)];
+    let bytes = unsafe { slice::from_raw_parts(copied.as_ptr() as *const u8, size_of_val(copied)) };
+    sched::with_current_task(|task| uaccess::copy_to_user(&mut task.addr_space, buf, bytes))?;
+    Ok(tasks.len())
+}
+
+// Store a snapshot of the memory and tasks of the system in the SysInfo at buf. Returns 0.
+fn sys_sysinfo(buf: usize) -> SyscallResult {
+    let info = stats::system_info();
+    let bytes = unsafe { slice::from_raw_parts(&raw const info as *const u8, size_of_val(&info)) };
+    sched::with_current_task(|task| uaccess::copy_to_user(&mut task.addr_space, buf, bytes))?;
+    Ok(0)
+}
+
+// Copy the newest whole lines of the kernel log that fit in len bytes into buf, as klog::read() gives them. Returns
+// the number of bytes.
+fn sys_dmesg(buf: usize, len: usize) -> SyscallResult {
+    let text = klog::read(len);
+    sched::with_current_task(|task| {
+        uaccess::copy_to_user(&mut task.addr_space, buf, text.as_bytes())
+    })?;
+    Ok(text.len())
+}
+
+// Reset the machine, or turn it off, as cmd says. Fails with EINVAL unless magic is REBOOT_MAGIC and cmd is one of
+// the commands, and never returns otherwise.
+fn sys_reboot(magic: usize, cmd: usize) -> SyscallResult {
+    if magic != REBOOT_MAGIC {
+        return Err(Errno::EINVAL);
+    }
+    match cmd {
+        REBOOT_CMD_RESTART => power::reboot(),
+        REBOOT_CMD_POWER_OFF => power::poweroff(),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+// Set the fs base of the current task, which its thread-local storage is reached through. Fails with EINVAL unless
+// base is a user address. Returns 0.
+fn sys_set_fs_base(base: usize) -> SyscallResult {
+    if !segment_base::is_user_base(base as u64) {
+        return Err(Errno::EINVAL);
+    }
+    // The context switch restores the fs base of the task, so both change together
+    without_interrupt(|| {
+        sched::with_current_task(|task| task.fs_base = base);
+        segment_base::write_fs_base(base as u64);
+    });
+    Ok(0)
+}
+
+// Returns the fs base of the current task.
+fn sys_get_fs_base() -> SyscallResult {
+    Ok(segment_base::read_fs_base() as usize)
+}
@@ -0,0 +1,114 @@
+//! Syscall tracing.
+//!
+//! A traced syscall prints one line once it returns, with the task, the arguments and the result:
+//!   task 5 (hello): write(1, "hello from ring 3\n", 18) = 18
+//! exit never returns, so it is printed before it runs, with ? as its result.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    idt::without_interrupt,
+    printlnk,
+    user::{
+        sched::CURRENT_TASK,
+        syscall::{SyscallArgs, dispatch::Syscall, errno::SyscallResult},
+        task::Task,
+        uaccess,
+    },
+};
+
+/// Trace the syscalls of every task. Set with the strace option of the kernel command line. Tasks can also be traced
+/// on their own, with sys_trace_me or the SPAWN_TRACE flag of sys_spawn.
+pub static mut TRACE_SYSCALLS: bool = false;
+
+/// Every trace line is also kept here while it is Some, so tests can check them.
+pub static mut TRACE_LOG: Option<Vec<String>> = None;
+
+// Longest string argument printed. Longer ones are truncated, and end with "...".
+const MAX_TRACE_STR: usize = 32;
+
+/// How an argument or result of a syscall is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg {
+    Dec, // A number, e.g. a length or an id
+    Int, // A signed number, e.g. an exit code
+    Hex, // A pointer, or flags
+    Str, // A pointer to a string, with its length in the next argument. Printed quoted, or as Hex if it is bad.
+}
+
+/// Check if the syscalls of the current task are traced.
+pub fn is_traced() -> bool {
+    let everything = unsafe { TRACE_SYSCALLS };
+    everything || with_task(|task| task.trace_syscalls).unwrap_or(false)
+}
+
+// Call f with the current task, if there is one. Tests also call dispatch() without a current task.
+fn with_task<R>(f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+    without_interrupt(|| unsafe { CURRENT_TASK.as_ref().map(|task| f(&mut *task.get())) })
+}
+
+/// Print the trace line of a syscall that returned result, or that never returns if result is None.
+pub fn trace(syscall: &Syscall, args: &SyscallArgs, result: Option<SyscallResult>) {
+    let values = [
+        args.arg1, args.arg2, args.arg3, args.arg4, args.arg5, args.arg6,
+    ];
+
+    let mut line = String::new();
+    write!(line, "{}(", syscall.name).unwrap();
+    for (i, (&kind, &value)) in syscall.args.iter().zip(&values).enumerate() {
+        if i > 0 {
+            line.push_str(", ");
+        }
+        let len = values.get(i + 1).copied().unwrap_or(0);
+        write_arg(&mut line, kind, value, len);
+    }
+    line.push_str(") = ");
+
+    match result {
+        Some(Ok(value)) => write_arg(&mut line, syscall.ret, value, 0),
+        Some(Err(errno)) => write!(line, "-{:?}", errno).unwrap(),
+        None => line.push('?'),
+    }
+    log(line);
+}
+
+/// Print the trace line of a syscall with an unknown number.
+pub fn trace_unknown(num: usize) {
+    log(format!("unknown syscall {} = -ENOSYS", num));
+}
+
+fn write_arg(line: &mut String, kind: Arg, value: usize, len: usize) {
+    match kind {
+        Arg::Dec => write!(line, "{}", value).unwrap(),
+        Arg::Int => write!(line, "{}", value as i32).unwrap(),
+        Arg::Hex => write!(line, "{:#x}", value).unwrap(),
+        Arg::Str => {
+            let mut bytes = [0u8; MAX_TRACE_STR];
+            let bytes = &mut bytes[..len.min(MAX_TRACE_STR)];
+            let copied =
+                with_task(|task| uaccess::copy_from_user(&mut task.addr_space, bytes, value));
+            match copied {
+                Some(Ok(())) => {
+                    write!(line, "{:?}", String::from_utf8_lossy(bytes)).unwrap();
+                    if len > MAX_TRACE_STR {
+                        line.push_str("...");
+                    }
+                }
+                _ => write!(line, "{:#x}", value).unwrap(),
+            }
+        }
+    }
+}
+
+// Print the line with the task it is for, and keep it in TRACE_LOG.
+fn log(line: String) {
+    let task = with_task(|task| format!("task {} ({})", task.id, task.name()));
+    let line = format!("{}: {}", task.as_deref().unwrap_or("no task"), line);
+    printlnk!("{}", line);
+
+    if let Some(log) = unsafe { TRACE_LOG.as_mut() } {
+        log.push(line);
+    }
+}
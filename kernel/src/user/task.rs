@@ -14,6 +14,38 @@
 //! |      for iretq      |
 //! |---------------------| High Address
 //!
+//! Kernel stack - New kernel task, not executing:
+//!
+//! |---------------------| Low Address
+//! |                     |
+//! |        Empty        |
+//! |                     |
+//! |---------------------|
+//! |                     | <- rsp
+//! |    Context switch   |
+//! |      structure      |
+//! |  (returns into the  |
+//! |     trampoline)     |
+//! |---------------------| High Address
+//!
+//! Kernel stack - New forked task, not executing:
+//!
+//! |---------------------| Low Address
+//! |                     |
+//! |        Empty        |
+//! |                     |
+//! |---------------------|
+//! |                     | <- rsp
+//! |    Context switch   |
+//! |      structure      |
+//! |  (returns into the  |
+//! |     trampoline)     |
+//! |---------------------|
+//! |                     |
+//! |    Syscall frame    |
+//! | (copied from parent)|
+//! |---------------------| High Address
+//!
 //! Kernel stack - Normal task, context switched out, not executing:
 //!
 //! |---------------------| Low Address
@@ -30,31 +62,89 @@
 //! |      for iretq      |
 //! |---------------------| High Address
 
+use core::{
+    arch::naked_asm,
+    mem::{self, transmute},
+    ptr::null,
+};
+
+use alloc::vec::Vec;
+
 use crate::{
     consts::PAGE_SIZE,
+    cpu::segment_base,
+    fpu::FpuState,
     gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR},
+    helper::{add_within_bounds, align_down, align_up},
+    idt::without_interrupt,
     isr::InterruptStackFrame,
-    mem::buddy::{alloc_pages_panic, free_pages},
-    user::{address_space::AddressSpace, elf_parser::ElfParser},
+    mem::buddy::{alloc_pages, free_pages},
+    user::{
+        address_space::{AddressSpace, MapError},
+        elf_parser::{ElfError, ElfParser, SymbolTable},
+        elf_structure::{ElfProgramHeader, ElfProgramHeaderType},
+        fd::FdTable,
+        sched::{DEFAULT_PRIORITY, cpu::ALL_CPUS, stats::TaskStats, wait_queue::WaitQueue},
+        signal::Signals,
+        syscall::{SyscallArgs, SyscallFrame, syscall_return},
+    },
 };
 
 pub const USER_STACK_SIZE: usize = 4 * PAGE_SIZE; // 16 KiB
 pub const USER_STACK_VADDR: usize = 0x00007ffffff00000; // Bottom of user stack
+pub const USER_STACK_TOP: usize = USER_STACK_VADDR + USER_STACK_SIZE; // Top of user stack
+pub const USER_STACK_MAX_SIZE: usize = 8 * 1024 * 1024; // 8 MiB, the largest stack PT_GNU_STACK can ask for
+
+/// Allow executables to ask for an executable stack with PT_GNU_STACK. Otherwise, loading them fails.
+pub static mut ALLOW_EXECUTABLE_STACK: bool = false;
 
 pub const KERNEL_STACK_SIZE: usize = 4 * PAGE_SIZE; // 16 KiB
 
+pub use elytra_abi::task::TASK_NAME_LEN;
+
+// Auxiliary vector entry types, passed to the task on its initial stack
+pub const AT_NULL: usize = 0; // End of the vector
+pub const AT_PHDR: usize = 3; // Address of the program headers
+pub const AT_PHENT: usize = 4; // Size of a program header
+pub const AT_PHNUM: usize = 5; // Number of program headers
+pub const AT_PAGESZ: usize = 6; // Page size
+pub const AT_ENTRY: usize = 9; // Entry point
+
+static mut NEXT_TASK_ID: usize = 1;
+
 /// Represents a task (i.e. thread) in the OS.
 #[derive(Debug)]
 pub struct Task {
-    pub state: TaskState,          // Current state of the task
-    pub addr_space: AddressSpace,  // Address space of the task
-    pub kernel_stack: KernelStack, // Kernel stack information
+    pub id: usize,                    // Unique id of the task
+    pub state: TaskState,             // Current state of the task
+    pub addr_space: AddressSpace,     // Address space of the task
+    pub kernel_stack: KernelStack,    // Kernel stack information
+    pub fs_base: usize, // Thread pointer (the end of the TLS block), or 0 if there is no TLS
+    pub symbols: SymbolTable, // Function symbols of the executable, to symbolize crash addresses
+    pub exit_code: Option<i32>, // Exit code, set when the task exits
+    pub time_slice: usize, // Timer ticks left before the task is preempted
+    pub wake_tick: usize, // Tick to wake up at, while the task is sleeping
+    pub priority: usize, // Scheduling priority, higher runs first (see sched::PRIORITY_LEVELS)
+    pub name: [u8; TASK_NAME_LEN], // Name of the task for messages, NUL-padded (see name())
+    pub parent: usize,  // Id of the task that spawned this one, or 0 if there is none (anymore)
+    pub exit_waiters: WaitQueue, // Tasks waiting for this task to exit
+    pub fpu_state: FpuState, // FPU and SSE registers, saved while the task is switched out
+    pub stats: TaskStats, // CPU time and switch counts
+    pub cpu_affinity: u64, // CPUs the task may run on, one bit per CPU (see sched::cpu)
+    pub trace_syscalls: bool, // Print every syscall of the task (see syscall::trace)
+    pub files: FdTable, // Open file descriptors
+    pub waiting_on: *const WaitQueue, // Queue the task is blocked on, or null (see WaitQueue::wait)
+    pub timed_out: bool, // Set if the last wait with a timeout ran out instead of being woken up
+    pub futex_key: usize, // Physical address of the futex word the task waits on (see futex)
+    pub signals: Signals, // Pending and blocked signals, and their handlers (see signal)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TaskState {
     New,
     Ready,
+    Sleeping,
+    Blocked,
     Terminated,
 }
 
@@ -65,11 +155,15 @@ pub struct KernelStack {
 }
 
 impl KernelStack {
-    pub fn new() -> Self {
-        let ptr = unsafe { alloc_pages_panic(KERNEL_STACK_SIZE / PAGE_SIZE) };
+    /// Allocate an empty kernel stack, or fail with OutOfMemory if there are no free pages for it.
+    pub fn new() -> Result<Self, MapError> {
+        let ptr = unsafe { alloc_pages(KERNEL_STACK_SIZE / PAGE_SIZE) };
+        if ptr.is_null() {
+            return Err(MapError::OutOfMemory);
+        }
         let krsp = ptr as usize + KERNEL_STACK_SIZE;
 
-        KernelStack { ptr, krsp }
+        Ok(KernelStack { ptr, krsp })
     }
 
     pub fn top(&self) -> usize {
@@ -104,37 +198,485 @@ impl Drop for KernelStack {
 }
 
 impl Task {
-    pub fn create_task_from_elf(parser: &ElfParser) -> Result<Self, ()> {
-        // Address space
-
-        let mut addr_space = AddressSpace::new();
-
-        // Map kernel pages into the new address space
-        addr_space.map_kernel_pages();
+    /// Load the executable elf into a new task, which starts with args as its argv and an empty environment.
+    pub fn spawn(elf: &[u8], args: &[&str]) -> Result<Self, ElfError> {
+        let parser = ElfParser::parse(elf)?;
+        Self::create_task_with_args(&parser, args, &[])
+    }
 
-        // Map ELF segments
-        addr_space.map_elf_segments(parser)?;
+    /// Create a task from the executable, which starts with no arguments and an empty environment.
+    pub fn create_task_from_elf(parser: &ElfParser) -> Result<Self, ElfError> {
+        Self::create_task_with_args(parser, &[], &[])
+    }
 
-        // Map user stack
-        let _ = addr_space.add_virt_region(USER_STACK_VADDR, USER_STACK_SIZE, true, false)?;
+    fn create_task_with_args(
+        parser: &ElfParser,
+        args: &[&str],
+        env: &[&str],
+    ) -> Result<Self, ElfError> {
+        let image = load_image(parser, args, env)?;
 
         // Kernel stack
 
-        let mut kernel_stack = KernelStack::new();
+        let mut kernel_stack = KernelStack::new().map_err(|_| ElfError::OomMapping)?;
         unsafe {
             kernel_stack.push(InterruptStackFrame {
-                ip: parser.get_header().e_entry as usize,
+                ip: image.entry,
                 cs: USER_CODE_SELECTOR as usize,
                 flags: 0x202,
-                sp: USER_STACK_VADDR + USER_STACK_SIZE,
+                sp: image.sp,
                 ss: USER_DATA_SELECTOR as usize,
             });
         }
 
         Ok(Task {
+            id: next_task_id(),
             state: TaskState::New,
+            addr_space: image.addr_space,
+            kernel_stack,
+            fs_base: image.fs_base,
+            symbols: image.symbols,
+            exit_code: None,
+            time_slice: 0,
+            wake_tick: 0,
+            priority: DEFAULT_PRIORITY,
+            name: task_name(args.first().copied().unwrap_or("")),
+            parent: 0,
+            exit_waiters: WaitQueue::new(),
+            fpu_state: FpuState::new(),
+            stats: TaskStats::default(),
+            cpu_affinity: ALL_CPUS,
+            trace_syscalls: false,
+            files: FdTable::console(),
+            waiting_on: null(),
+            timed_out: false,
+            futex_key: 0,
+            signals: Signals::default(),
+        })
+    }
+
+    /// Replace the image of this task with the executable elf, which starts with args as its argv and an empty
+    /// environment. The task keeps its id and kernel stack, so TSS rsp0 and the syscall stack stay the same.
+    /// The current syscall returns into the entry point of the new image. On failure, the old image is left intact.
+    ///
+    /// # Safety
+    /// This task must be the current task, in a syscall made from user mode.
+    pub unsafe fn exec(&mut self, elf: &[u8], args: &[&str]) -> Result<(), ElfError> {
+        let parser = ElfParser::parse(elf)?;
+        let image = load_image(&parser, args, &[])?;
+
+        without_interrupt(|| unsafe {
+            // The old page tables are in use until CR3 points at the new ones
+            image.addr_space.switch_to_this();
+            let old_addr_space = mem::replace(&mut self.addr_space, image.addr_space);
+            drop(old_addr_space);
+
+            self.fs_base = image.fs_base;
+            segment_base::write_fs_base(image.fs_base as u64);
+        });
+        self.symbols = image.symbols;
+        self.name = task_name(args.first().copied().unwrap_or(""));
+
+        // The handlers were in the old image
+        self.signals.reset_handlers();
+
+        // The new image starts with clean FPU and SSE registers
+        self.fpu_state = FpuState::new();
+        self.fpu_state.restore();
+
+        // Return from the syscall into the new image, with the registers a new task starts with
+        unsafe {
+            let frame = (self.kernel_stack.top() - size_of::<SyscallFrame>()) as *mut SyscallFrame;
+            frame.write(SyscallFrame {
+                args: SyscallArgs::default(),
+                rbp: 0,
+                rbx: 0,
+                r12: 0,
+                r13: 0,
+                r14: 0,
+                r15: 0,
+                rip: image.entry,
+                rflags: 0x202,
+                rsp: image.sp,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Create a kernel task (kernel thread), which runs entry in kernel mode with interrupts enabled.
+    /// It runs on the kernel page tables, and must end by calling sched::exit_current().
+    pub fn create_kernel_task(entry: fn() -> !, name: &str) -> Self {
+        // Fake the context switch structure, so the first switch to the task returns into kernel_task_trampoline
+        let mut kernel_stack = KernelStack::new().expect("Out of memory for a kernel stack");
+        unsafe {
+            kernel_stack.push(kernel_task_trampoline as *const () as usize); // Return address
+            kernel_stack.push(0x2usize); // rflags (interrupts are enabled by the trampoline)
+            kernel_stack.push(0usize); // r15
+            kernel_stack.push(0usize); // r14
+            kernel_stack.push(0usize); // r13
+            kernel_stack.push(entry as usize); // r12, the entry for the trampoline
+            kernel_stack.push(0usize); // rbx
+            kernel_stack.push(0usize); // rbp
+        }
+
+        Task {
+            id: next_task_id(),
+            // Not New, as the task doesn't start with iretq
+            state: TaskState::Ready,
+            addr_space: AddressSpace::kernel(),
+            kernel_stack,
+            fs_base: 0,
+            symbols: SymbolTable::default(),
+            exit_code: None,
+            time_slice: 0,
+            wake_tick: 0,
+            priority: DEFAULT_PRIORITY,
+            name: task_name(name),
+            parent: 0,
+            exit_waiters: WaitQueue::new(),
+            fpu_state: FpuState::new(),
+            stats: TaskStats::default(),
+            cpu_affinity: ALL_CPUS,
+            trace_syscalls: false,
+            files: FdTable::new(),
+            waiting_on: null(),
+            timed_out: false,
+            futex_key: 0,
+            signals: Signals::default(),
+        }
+    }
+
+    /// Create a copy of this task for fork. The child gets a copy-on-write copy of the address space, and returns from
+    /// the current syscall with 0 when it first runs. Fails with OutOfMemory if there are no pages for its page tables
+    /// or its kernel stack.
+    ///
+    /// # Safety
+    /// This task must be the current task, in a syscall made from user mode.
+    pub unsafe fn fork(&mut self) -> Result<Self, MapError> {
+        let addr_space = self.addr_space.fork()?;
+
+        let mut kernel_stack = KernelStack::new()?;
+        unsafe {
+            // The child returns to user mode through the same syscall frame as ours, without the arguments
+            let frame =
+                (self.kernel_stack.top() - size_of::<SyscallFrame>()) as *const SyscallFrame;
+            kernel_stack.push(SyscallFrame {
+                args: SyscallArgs::default(),
+                ..(*frame).clone()
+            });
+
+            // Fake context switch structure, which inner_context_switch returns from into the trampoline
+            kernel_stack.push(fork_return_trampoline as *const () as usize); // Return address
+            kernel_stack.push(0x2usize); // rflags (interrupts stay disabled until sysretq)
+            kernel_stack.push(0usize); // r15
+            kernel_stack.push(0usize); // r14
+            kernel_stack.push(0usize); // r13
+            kernel_stack.push(0usize); // r12
+            kernel_stack.push(0usize); // rbx
+            kernel_stack.push(0usize); // rbp
+        }
+
+        Ok(Task {
+            id: next_task_id(),
+            // Not New, as the task doesn't start with iretq
+            state: TaskState::Ready,
             addr_space,
             kernel_stack,
+            // User mode may have changed it since the last context switch
+            fs_base: segment_base::read_fs_base() as usize,
+            symbols: self.symbols.clone(),
+            exit_code: None,
+            time_slice: 0,
+            wake_tick: 0,
+            priority: self.priority,
+            name: self.name,
+            parent: self.id,
+            exit_waiters: WaitQueue::new(),
+            fpu_state: FpuState::current(),
+            stats: TaskStats::default(),
+            cpu_affinity: self.cpu_affinity,
+            trace_syscalls: self.trace_syscalls,
+            files: self.files.clone(),
+            waiting_on: null(),
+            timed_out: false,
+            futex_key: 0,
+            signals: self.signals.fork(),
         })
     }
+
+    /// Create a task that only holds the context sched::begin_scheduler() was called from.
+    /// It never enters user mode, and its own kernel stack is never used.
+    pub fn boot_context() -> Self {
+        Task {
+            id: 0,
+            state: TaskState::Ready,
+            addr_space: AddressSpace::kernel(),
+            kernel_stack: KernelStack::new().expect("Out of memory for a kernel stack"),
+            fs_base: 0,
+            symbols: SymbolTable::default(),
+            exit_code: None,
+            time_slice: 0,
+            wake_tick: 0,
+            priority: 0,
+            name: task_name("boot"),
+            parent: 0,
+            exit_waiters: WaitQueue::new(),
+            fpu_state: FpuState::new(),
+            stats: TaskStats::default(),
+            cpu_affinity: ALL_CPUS,
+            trace_syscalls: false,
+            files: FdTable::new(),
+            waiting_on: null(),
+            timed_out: false,
+            futex_key: 0,
+            signals: Signals::default(),
+        }
+    }
+
+    /// Name of the task, usually the name of its program.
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(TASK_NAME_LEN);
+        str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    /// Rename the task. Names longer than TASK_NAME_LEN bytes are truncated.
+    pub fn set_name(&mut self, name: &str) {
+        self.name = task_name(name);
+    }
+}
+
+/// Make a task name buffer from name, truncated to TASK_NAME_LEN bytes without splitting a character.
+pub fn task_name(name: &str) -> [u8; TASK_NAME_LEN] {
+    let mut len = name.len().min(TASK_NAME_LEN);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    let mut buf = [0u8; TASK_NAME_LEN];
+    buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+    buf
+}
+
+// A loaded executable, ready to be run by a task.
+struct Image {
+    addr_space: AddressSpace,
+    entry: usize,
+    sp: usize,
+    fs_base: usize,
+    symbols: SymbolTable,
+}
+
+// Load the executable into a new address space, with its initial user stack holding args and env.
+fn load_image(parser: &ElfParser, args: &[&str], env: &[&str]) -> Result<Image, ElfError> {
+    // Address space
+
+    let mut addr_space = AddressSpace::new();
+
+    // Map kernel pages into the new address space
+    addr_space.map_kernel_pages();
+
+    // Map ELF segments
+    let load_base = addr_space.map_elf_segments(parser)?;
+
+    // Map user stack lazily, with a guard page below it to catch stack overflows.
+    // PT_GNU_STACK gives the permissions of the stack, and may ask for a larger one.
+    let (stack_size, stack_executable) = stack_request(parser)?;
+    let stack_bottom = USER_STACK_TOP - stack_size;
+    addr_space.set_allow_write_execute(stack_executable);
+    let stack = addr_space.add_virt_region(stack_bottom, stack_size, true, stack_executable, true);
+    addr_space.set_allow_write_execute(false);
+    stack.map_err(|_| ElfError::OomMapping)?;
+    addr_space.set_region_name(stack_bottom, stack_size, "stack");
+    addr_space
+        .add_guard_region(stack_bottom - PAGE_SIZE, PAGE_SIZE)
+        .map_err(|_| ElfError::OomMapping)?;
+
+    // Thread-local storage, set up from the PT_TLS template
+    let fs_base = match parser.find_program_header(ElfProgramHeaderType::Tls)? {
+        Some(ph) => map_tls(&mut addr_space, parser, &ph)?,
+        None => 0,
+    };
+
+    // Initial user stack (arguments, environment and auxiliary vector)
+
+    let header = parser.get_header();
+    let entry = load_base + header.e_entry as usize;
+    let mut auxv = Vec::new();
+    if let Some(phdr) = parser.phdr_vaddr() {
+        auxv.push((AT_PHDR, load_base + phdr));
+        auxv.push((AT_PHENT, header.e_phentsize as usize));
+        auxv.push((AT_PHNUM, header.e_phnum as usize));
+    }
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, entry));
+    let sp = write_initial_stack(&mut addr_space, stack_bottom, args, env, &auxv)?;
+
+    Ok(Image {
+        addr_space,
+        entry,
+        sp,
+        fs_base,
+        symbols: parser.symbol_table(load_base),
+    })
+}
+
+fn next_task_id() -> usize {
+    unsafe {
+        let id = NEXT_TASK_ID;
+        NEXT_TASK_ID += 1;
+        id
+    }
+}
+
+// The first code a kernel task runs, with the entry of the task in r12.
+#[unsafe(naked)]
+unsafe extern "C" fn kernel_task_trampoline() -> ! {
+    naked_asm!(
+        "sti",
+        "mov rdi, r12",
+        "call {run}",
+        "ud2",
+        run = sym run_kernel_task,
+    )
+}
+
+extern "C" fn run_kernel_task(entry: usize) -> ! {
+    let entry: fn() -> ! = unsafe { transmute(entry) };
+    entry()
+}
+
+// The first code a forked task runs. It returns from the syscall it was forked in, with 0 as the return value.
+#[unsafe(naked)]
+unsafe extern "C" fn fork_return_trampoline() -> ! {
+    naked_asm!(
+        "xor eax, eax",
+        "jmp {syscall_return}",
+        syscall_return = sym syscall_return,
+    )
+}
+
+// Get the size of the user stack and whether it is executable, as asked by PT_GNU_STACK.
+fn stack_request(parser: &ElfParser) -> Result<(usize, bool), ElfError> {
+    let Some(ph) = parser.find_program_header(ElfProgramHeaderType::GnuStack)? else {
+        return Ok((USER_STACK_SIZE, false));
+    };
+
+    let executable = ph.p_flags & 0x1 != 0;
+    if executable && !unsafe { ALLOW_EXECUTABLE_STACK } {
+        return Err(ElfError::ExecutableStack);
+    }
+
+    // The size is only a hint: it never makes the stack smaller than the default.
+    let size = (ph.p_memsz as usize).clamp(USER_STACK_SIZE, USER_STACK_MAX_SIZE);
+    Ok((align_up(size, PAGE_SIZE), executable))
+}
+
+// Allocate the TLS block of the task and initialize it from the template, then return the thread pointer.
+//
+// x86_64 uses TLS variant II: the block ends at the thread pointer, and variables are accessed at negative
+// offsets from the fs base. The thread pointer points to the TCB, whose first word is the thread pointer itself.
+fn map_tls(
+    addr_space: &mut AddressSpace,
+    parser: &ElfParser,
+    ph: &ElfProgramHeader,
+) -> Result<usize, ElfError> {
+    let offset = ph.p_offset as usize;
+    let file_size = ph.p_filesz as usize;
+    let mem_size = ph.p_memsz as usize;
+    let align = (ph.p_align as usize).max(1);
+
+    // We check safety first
+    add_within_bounds(offset, file_size, parser.get_buf().len()).ok_or(ElfError::BadTls)?;
+    if file_size > mem_size || !align.is_power_of_two() || align > PAGE_SIZE {
+        return Err(ElfError::BadTls);
+    }
+
+    // The region is page aligned, so the thread pointer is aligned to the template.
+    let block_size = mem_size
+        .checked_add(align - 1)
+        .map(|size| align_down(size, align))
+        .ok_or(ElfError::BadTls)?;
+    let len = block_size
+        .checked_add(size_of::<usize>() + PAGE_SIZE - 1)
+        .map(|len| align_down(len, PAGE_SIZE))
+        .ok_or(ElfError::BadTls)?;
+    let start = addr_space
+        .map_anonymous(len, true)
+        .map_err(|_| ElfError::OomMapping)?;
+    addr_space.set_region_name(start, len, "tls");
+
+    // tdata is copied from the template, tbss is left zeroed.
+    let tp = start + block_size;
+    addr_space
+        .copy_into_region(start, &parser.get_buf()[offset..offset + file_size])
+        .and_then(|_| addr_space.copy_into_region(tp, &tp.to_le_bytes()))
+        .map_err(|_| ElfError::OomMapping)?;
+
+    Ok(tp)
+}
+
+// Write the System V initial stack at the top of the user stack, and return the stack pointer to start with:
+//
+// |---------------------| Low Address
+// |        argc         | <- sp (16-byte aligned)
+// |  argv[0..argc], 0   |
+// |  envp[0..envc], 0   |
+// | auxv pairs, AT_NULL |
+// |---------------------|
+// |    Padding, then    |
+// |  argument and env   |
+// |       strings       |
+// |---------------------| High Address
+fn write_initial_stack(
+    addr_space: &mut AddressSpace,
+    stack_bottom: usize,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(usize, usize)],
+) -> Result<usize, ElfError> {
+    let too_large = ElfError::ArgumentsTooLarge;
+    // Copy the strings (NUL-terminated) to the top of the stack, and remember where each one went.
+    let mut pos = USER_STACK_TOP;
+    let mut pointers = Vec::with_capacity(args.len() + env.len());
+    for string in args.iter().chain(env) {
+        pos = pos
+            .checked_sub(string.len() + 1)
+            .filter(|&pos| pos >= stack_bottom)
+            .ok_or(too_large)?;
+        addr_space
+            .copy_into_region(pos, string.as_bytes())
+            .and_then(|_| addr_space.copy_into_region(pos + string.len(), &[0]))
+            .map_err(|_| too_large)?;
+        pointers.push(pos);
+    }
+    let (arg_pointers, env_pointers) = pointers.split_at(args.len());
+
+    let mut words = Vec::new();
+    words.push(args.len());
+    words.extend_from_slice(arg_pointers);
+    words.push(0);
+    words.extend_from_slice(env_pointers);
+    words.push(0);
+    for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        words.push(key);
+        words.push(value);
+    }
+
+    let sp = align_down(pos, 16)
+        .checked_sub(words.len() * size_of::<usize>())
+        .map(|sp| align_down(sp, 16))
+        .filter(|&sp| sp >= stack_bottom)
+        .ok_or(too_large)?;
+    for (i, word) in words.iter().enumerate() {
+        addr_space
+            .copy_into_region(sp + i * size_of::<usize>(), &word.to_le_bytes())
+            .map_err(|_| too_large)?;
+    }
+
+    Ok(sp)
 }
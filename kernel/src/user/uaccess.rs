@@ -0,0 +1,116 @@
+//! Access to user memory from the kernel.
+//!
+//! User pointers are never dereferenced directly. Ranges are checked against the page tables of the address space
+//! (`AddressSpace::check_user_range`), then copied page by page through the direct mapping, so these work whether or
+//! not the address space is active. Lazy and copy-on-write pages are faulted in first, as a user access would.
+
+use core::ptr::copy_nonoverlapping;
+
+use crate::{
+    consts::{PAGE_SIZE, USERSPACE_LIMIT},
+    helper::{add_within_bounds, align_down, p2v},
+    user::address_space::AddressSpace,
+};
+
+/// A user range that can't be accessed: it lies outside userspace, isn't fully mapped, doesn't allow the access, or
+/// has a lazy or copy-on-write page there was no free page for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+/// Check that [start, start + len) can be read from user mode, and written if write is set.
+/// Lazy and copy-on-write pages in the range are faulted in.
+pub fn access_ok(
+    addr_space: &mut AddressSpace,
+    start: usize,
+    len: usize,
+    write: bool,
+) -> Result<(), Fault> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = add_within_bounds(start, len, USERSPACE_LIMIT).ok_or(Fault)?;
+
+    // Running out of pages to fault in fails the access too
+    for page in (align_down(start, PAGE_SIZE)..end).step_by(PAGE_SIZE) {
+        if addr_space.resolve_virt_addr(page).is_none()
+            && !addr_space
+                .handle_lazy_fault(page, write)
+                .map_err(|_| Fault)?
+        {
+            // Not mapped, no need to look further.
+            return Err(Fault);
+        }
+        if write {
+            addr_space.handle_cow_fault(page).map_err(|_| Fault)?;
+        }
+    }
+
+    if addr_space.check_user_range(start, len, write) {
+        Ok(())
+    } else {
+        Err(Fault)
+    }
+}
+
+/// Copy dst.len() bytes from user memory at user_ptr into dst.
+pub fn copy_from_user(
+    addr_space: &mut AddressSpace,
+    dst: &mut [u8],
+    user_ptr: usize,
+) -> Result<(), Fault> {
+    access_ok(addr_space, user_ptr, dst.len(), false)?;
+
+    let mut copied = 0;
+    while copied < dst.len() {
+        let addr = user_ptr + copied;
+        let phys_addr = addr_space.resolve_virt_addr(addr).ok_or(Fault)?;
+        let count = (PAGE_SIZE - addr % PAGE_SIZE).min(dst.len() - copied);
+
+        unsafe {
+            copy_nonoverlapping(
+                p2v(phys_addr) as *const u8,
+                dst.as_mut_ptr().add(copied),
+                count,
+            )
+        };
+        copied += count;
+    }
+
+    Ok(())
+}
+
+/// Copy src into user memory at user_ptr.
+pub fn copy_to_user(
+    addr_space: &mut AddressSpace,
+    user_ptr: usize,
+    src: &[u8],
+) -> Result<(), Fault> {
+    access_ok(addr_space, user_ptr, src.len(), true)?;
+    addr_space
+        .copy_into_region(user_ptr, src)
+        .map_err(|_| Fault)
+}
+
+/// Copy a NUL-terminated string from user memory at user_ptr into dst, and return its length (without the NUL).
+/// If there is no NUL within dst.len() bytes, dst is filled and dst.len() is returned.
+/// The string is read page by page, so it may end right before an unmapped page.
+pub fn strncpy_from_user(
+    addr_space: &mut AddressSpace,
+    dst: &mut [u8],
+    user_ptr: usize,
+) -> Result<usize, Fault> {
+    let mut copied = 0;
+    while copied < dst.len() {
+        let addr = user_ptr.checked_add(copied).ok_or(Fault)?;
+        let count = (PAGE_SIZE - addr % PAGE_SIZE).min(dst.len() - copied);
+
+        let chunk = &mut dst[copied..copied + count];
+        copy_from_user(addr_space, chunk, addr)?;
+        if let Some(len) = chunk.iter().position(|&byte| byte == 0) {
+            return Ok(copied + len);
+        }
+        copied += count;
+    }
+
+    Ok(copied)
+}
@@ -1,8 +1,13 @@
 use clap::Parser;
 use pathdiff::diff_paths;
 use std::env;
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
 use std::path::Path;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
 
 // NOTE: Use Ctrl+A x to exit QEMU!
 
@@ -20,6 +25,67 @@ struct Args {
     /// Disable graphical output in QEMU
     #[arg(long)]
     nographic: bool,
+
+    /// Show the framebuffer in the QEMU window, and print the serial output to the terminal instead
+    #[arg(long, conflicts_with = "nographic")]
+    display: bool,
+
+    /// Add a second serial port, COM2, and print its output to the terminal instead of COM1's. Use with the
+    /// console=com2 kernel option
+    #[arg(long, conflicts_with = "nographic")]
+    com2: bool,
+
+    /// Add the QEMU debug console (port 0xE9), writing to this file, or to the terminal with "stdio"
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "debugcon.log")]
+    debugcon: Option<String>,
+
+    /// Attach a virtio-blk disk backed by this file, created with the test pattern if it doesn't exist
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "disk.img")]
+    disk: Option<String>,
+
+    /// Pass this user program to the kernel through fw_cfg, which makes it available as "fwcfg-init"
+    #[arg(long, value_name = "FILE")]
+    init: Option<String>,
+
+    /// Pass these options to the kernel through fw_cfg, after those of KERNEL_CMDLINE
+    #[arg(long, value_name = "OPTIONS")]
+    cmdline: Option<String>,
+
+    /// Inject an NMI through the QEMU monitor after this many seconds, to test the NMI handler
+    #[arg(long, value_name = "SECONDS")]
+    nmi_after: Option<u64>,
+}
+
+/// Local port of the QEMU monitor, when it is exposed
+const MONITOR_PORT: u16 = 4445;
+
+/// Size of the disk image the runner creates
+const DISK_IMAGE_SIZE: usize = 1024 * 1024;
+
+/// Create the disk image the kernel tests read, unless it exists. Byte n of it is n % 251, so no two sectors match
+fn create_disk_image(path: &str) {
+    if Path::new(path).exists() {
+        return;
+    }
+    let image: Vec<u8> = (0..DISK_IMAGE_SIZE).map(|n| (n % 251) as u8).collect();
+    fs::write(path, image).expect("failed to create the disk image");
+    println!("Created the disk image {}", path);
+}
+
+/// Wait, then send the nmi command to the QEMU monitor
+fn inject_nmi(seconds: u64) {
+    thread::sleep(Duration::from_secs(seconds));
+    match TcpStream::connect(("127.0.0.1", MONITOR_PORT)) {
+        Ok(mut monitor) => {
+            monitor
+                .write_all(b"nmi\n")
+                .expect("failed to write to the QEMU monitor");
+            // Give QEMU time to run the command before the connection is closed
+            thread::sleep(Duration::from_millis(100));
+            println!("Injected an NMI");
+        }
+        Err(err) => eprintln!("Failed to connect to the QEMU monitor: {}", err),
+    }
 }
 
 /// Convert Windows path to relative path (that can be used in WSL)
@@ -31,17 +97,11 @@ fn fix_wsl_path(path: &str) -> String {
         .replace("\\", "/")
 }
 
-fn main() {
-    let args = Args::parse();
-
-    // Read env variables that were set in build script
-    let kernel_path = env!("KERNEL_PATH");
+/// Build the QEMU command that boots the kernel, with the options the flags ask for
+fn qemu_command(args: &Args) -> Command {
     let bios_path = env!("BIOS_PATH");
+    let fw_cfg_test_path = env!("FW_CFG_TEST_PATH");
 
-    println!("Kernel is located at: {}", kernel_path);
-    println!("Bios image is located at: {}", bios_path);
-
-    // Setup QEMU command
     let mut cmd;
     if !args.wsl {
         cmd = Command::new("qemu-system-x86_64");
@@ -54,6 +114,52 @@ fn main() {
     if args.nographic {
         cmd.arg("-nographic");
     }
+    // Keep the window on the framebuffer, as the serial console would take its place otherwise
+    if args.display && !args.com2 {
+        cmd.arg("-serial").arg("stdio");
+    }
+    // The first -serial is COM1, the second COM2
+    if args.com2 {
+        cmd.arg("-serial").arg("null").arg("-serial").arg("stdio");
+    }
+    // The debug console works before the serial port is up, and after it broke
+    match args.debugcon.as_deref() {
+        Some("stdio") => {
+            cmd.arg("-debugcon").arg("stdio");
+        }
+        Some(file) => {
+            cmd.arg("-debugcon").arg(format!("file:{}", file));
+        }
+        None => {}
+    }
+
+    // The kernel reads the disk through its legacy virtio interface, which virtio-blk-pci has on the PCI bus
+    if let Some(file) = &args.disk {
+        create_disk_image(file);
+        cmd.arg("-drive")
+            .arg(format!("if=none,id=disk,format=raw,file={}", file));
+        cmd.arg("-device").arg("virtio-blk-pci,drive=disk");
+    }
+
+    // Files for the kernel, through fw_cfg: the test file the build script wrote, and what the flags give. Commas in
+    // an option value are escaped by doubling them
+    cmd.arg("-fw_cfg").arg(format!(
+        "name=opt/elytra/test,file={}",
+        fix_wsl_path(fw_cfg_test_path).replace(',', ",,")
+    ));
+    if let Some(file) = &args.init {
+        cmd.arg("-fw_cfg").arg(format!(
+            "name=opt/elytra/init,file={}",
+            file.replace(',', ",,")
+        ));
+    }
+    if let Some(options) = &args.cmdline {
+        cmd.arg("-fw_cfg").arg(format!(
+            "name=opt/elytra/cmdline,string={}",
+            options.replace(',', ",,")
+        ));
+    }
+
     // Enable the guest to exit qemu
     cmd.arg("-device")
         .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
@@ -63,12 +169,37 @@ fn main() {
         cmd.arg("-s").arg("-S");
     }
 
+    // Expose the QEMU monitor on a local port, so an NMI can be injected through it
+    if args.nmi_after.is_some() {
+        cmd.arg("-monitor")
+            .arg(format!("tcp:127.0.0.1:{},server,nowait", MONITOR_PORT));
+    }
+
     // Pass bios paths
     cmd.arg("-drive")
         .arg(format!("format=raw,file={}", fix_wsl_path(bios_path)));
 
+    cmd
+}
+
+fn main() {
+    let args = Args::parse();
+
+    // Read env variables that were set in build script
+    let kernel_path = env!("KERNEL_PATH");
+    let bios_path = env!("BIOS_PATH");
+
+    println!("Kernel is located at: {}", kernel_path);
+    println!("Bios image is located at: {}", bios_path);
+
     // Start QEMU
-    let mut child = cmd.spawn().expect("failed to start qemu-system-x86_64");
+    let mut child = qemu_command(&args)
+        .spawn()
+        .expect("failed to start qemu-system-x86_64");
+
+    if let Some(seconds) = args.nmi_after {
+        thread::spawn(move || inject_nmi(seconds));
+    }
 
     // Start GDB if enabled
     if args.gdb {
@@ -92,3 +223,133 @@ fn main() {
     let status = child.wait().expect("failed to wait on qemu");
     println!("QEMU exited: {}", status);
 }
+
+// These boot the kernel in QEMU with the serial port on stdout, so they need qemu-system-x86_64, and take as long as
+// the kernel tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, ExitStatus, Stdio};
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Instant;
+
+    /// How long the kernel gets to run its tests and halt
+    const TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// Printed once the RTC is up, on every boot
+    #[cfg(not(feature = "test-panic-locked"))]
+    const BOOT_BANNER: &str = "Elytra OS, booted";
+
+    /// What the kernel writes to the isa-debug-exit device after a panic, with test-panic-locked (see
+    /// kernel/src/helper.rs)
+    #[cfg(feature = "test-panic-locked")]
+    const QEMU_EXIT_PANIC: i32 = 0x11;
+
+    /// Start QEMU with these flags, and send each line of its serial output through the receiver
+    fn start_qemu(flags: &[&str]) -> (Child, Receiver<String>) {
+        let args = Args::parse_from(["os", "--nographic"].iter().chain(flags));
+        let mut child = qemu_command(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start qemu-system-x86_64");
+
+        let stdout = child.stdout.take().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The kernel may print bytes that aren't UTF-8, e.g. while it panics
+            for line in BufReader::new(stdout).split(b'\n') {
+                let Ok(line) = line else { break };
+                let line = String::from_utf8_lossy(&line).into_owned();
+                println!("{}", line);
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        (child, receiver)
+    }
+
+    /// Wait for QEMU to exit, and return its status and everything it printed. Kills it if it is still running after
+    /// TIMEOUT
+    fn wait_for_exit(mut child: Child, output: Receiver<String>) -> (ExitStatus, Vec<String>) {
+        let deadline = Instant::now() + TIMEOUT;
+        while child.try_wait().unwrap().is_none() {
+            if Instant::now() > deadline {
+                child.kill().unwrap();
+                panic!("QEMU didn't exit in {:?}", TIMEOUT);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let status = child.wait().unwrap();
+        // The output ends once QEMU closed its stdout
+        (status, output.iter().collect())
+    }
+
+    /// Wait until QEMU has printed a line that contains text count times, then kill it. Fails if QEMU exits first, or
+    /// after TIMEOUT
+    #[cfg(not(feature = "test-panic-locked"))]
+    fn wait_for_lines(mut child: Child, output: Receiver<String>, text: &str, count: usize) {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut seen = 0;
+        let result = loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match output.recv_timeout(timeout) {
+                Ok(line) if line.contains(text) => {
+                    seen += 1;
+                    if seen == count {
+                        break Ok(());
+                    }
+                }
+                Ok(_) => {}
+                Err(mpsc::RecvTimeoutError::Timeout) => break Err("QEMU timed out"),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break Err("QEMU exited"),
+            }
+        };
+        child.kill().ok();
+        child.wait().unwrap();
+        if let Err(err) = result {
+            panic!(
+                "{} after printing {:?} {} of {} times",
+                err, text, seen, count
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "test-panic-locked"))]
+    fn halt_poweroff_exits_qemu() {
+        let (child, output) = start_qemu(&["--cmdline", "halt=poweroff"]);
+        let (status, output) = wait_for_exit(child, output);
+        assert!(output.iter().any(|line| line.contains("Powering off...")));
+        assert_eq!(status.code(), Some(0));
+    }
+
+    #[test]
+    #[cfg(not(feature = "test-panic-locked"))]
+    fn halt_reboot_boots_again() {
+        let (child, output) = start_qemu(&["--cmdline", "halt=reboot"]);
+        // The machine starts over from the firmware, so the kernel boots again
+        wait_for_lines(child, output, BOOT_BANNER, 2);
+    }
+
+    // The kernel panics with the console locked once its tests are done, and exits QEMU with QEMU_EXIT_PANIC
+    #[test]
+    #[cfg(feature = "test-panic-locked")]
+    fn panic_with_console_locked_reaches_serial() {
+        let (child, output) = start_qemu(&[]);
+        let (status, output) = wait_for_exit(child, output);
+        assert!(
+            output
+                .iter()
+                .any(|line| line.contains("[lock busted] Kernel panic!"))
+        );
+        assert!(
+            output
+                .iter()
+                .any(|line| line.contains("Panicked with the console locked"))
+        );
+        assert_eq!(status.code(), Some((QEMU_EXIT_PANIC << 1) | 1));
+    }
+}
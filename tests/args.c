@@ -0,0 +1,40 @@
+// gcc -masm=intel -static -nostdlib args.c -o args
+
+#include "elytra_abi.h"
+
+// At the entry point, rsp points to argc. Pass it on before anything is pushed.
+__asm__(
+    ".global _start\n"
+    "_start:\n\t"
+    "mov rdi, rsp\n\t"
+    "call start_c\n\t");
+
+void start_c(long *sp)
+{
+    long argc = sp[0];
+    char **argv = (char **)(sp + 1);
+    char **envp = argv + argc + 1;
+    while (*envp)
+        envp++;
+    long *auxv = (long *)(envp + 1);
+
+    // Crash if the auxiliary vector has no page size (AT_PAGESZ) of 4096
+    long page_size = 0;
+    for (; auxv[0] != 0; auxv += 2)
+        if (auxv[0] == 6)
+            page_size = auxv[1];
+    if (page_size != 4096)
+        *(volatile int *)0 = 0;
+
+    // Echo argv[1]
+    if (argc >= 2)
+    {
+        long len = 0;
+        while (argv[1][len])
+            len++;
+        sys_write(1, argv[1], len);
+        sys_write(1, "\n", 1);
+    }
+
+    sys_exit(0);
+}
@@ -0,0 +1,13 @@
+// gcc -masm=intel -static -nostdlib bss.c -o bss
+
+#include "elytra_abi.h"
+
+// 1 MiB of BSS, only one byte of it is ever touched.
+static char big[1 << 20];
+
+void _start()
+{
+    big[12345] = 1;
+
+    sys_exit(0);
+}
@@ -0,0 +1,22 @@
+// gcc -masm=intel -static -nostdlib bss_fill.c -o bss_fill
+
+#include "elytra_abi.h"
+
+// A data segment whose file part ends in the middle of a page, followed by BSS spanning several pages.
+static volatile unsigned char initialized[10000] = {[0 ... 9999] = 0x5a};
+static volatile unsigned char zeroed[20000];
+
+void _start()
+{
+    // Crash if any initialized byte is wrong or any BSS byte isn't zero
+    for (int i = 0; i < sizeof(initialized); i++)
+        if (initialized[i] != 0x5a)
+            *(volatile int *)0 = 0;
+    for (int i = 0; i < sizeof(zeroed); i++)
+        if (zeroed[i] != 0)
+            *(volatile int *)0 = 0;
+
+    sys_write(1, "BSS zero-filled correctly\n", 26);
+
+    sys_exit(0);
+}
@@ -0,0 +1,22 @@
+// gcc -masm=intel -static -nostdlib churn.c -o churn
+
+#include "elytra_abi.h"
+
+// A short-lived task for the spawn and exit stress test: it makes a few syscalls, touches a new heap page, then exits.
+void _start()
+{
+    long code = 0;
+
+    sys_yield();
+
+    // brk(0), then grow the heap by a page and write to it
+    char *heap_end = (char *)sys_brk(0);
+    if (sys_brk(heap_end + 4096) != (long)(heap_end + 4096))
+        code = 1;
+    else
+        *(volatile char *)heap_end = 1;
+
+    sys_yield();
+
+    sys_exit(code);
+}
@@ -0,0 +1,17 @@
+// gcc -masm=intel -static -nostdlib crash.c -o crash
+
+#include "elytra_abi.h"
+
+// Fault in a named function, the kernel should print it next to rip when it kills the task.
+__attribute__((noinline)) void crash_here(void)
+{
+    *(volatile int *)0 = 0;
+}
+
+void _start()
+{
+    crash_here();
+
+    // Never reached
+    sys_exit(0);
+}
@@ -0,0 +1,291 @@
+// The interface between the kernel and user programs, for the test programs written in C.
+//
+// Generated from the elytra-abi crate by elytra-abi/tests/c_header.rs, don't edit it by hand. After changing the
+// crate, regenerate it with: UPDATE_C_HEADER=1 cargo test -p elytra-abi
+
+#ifndef ELYTRA_ABI_H
+#define ELYTRA_ABI_H
+
+// Syscall numbers, passed in rax.
+#define SYS_EXIT 0
+#define SYS_YIELD 1
+#define SYS_BRK 2
+#define SYS_MMAP 3
+#define SYS_WRITE 4
+#define SYS_MAPS 5
+#define SYS_SLEEP_MS 6
+#define SYS_SET_PRIORITY 7
+#define SYS_WAIT_TICK 8
+#define SYS_SPAWN 9
+#define SYS_WAIT 10
+#define SYS_FORK 11
+#define SYS_EXEC 12
+#define SYS_TASK_STATS 13
+#define SYS_GETPID 14
+#define SYS_READ 15
+#define SYS_GETPPID 16
+#define SYS_TRACE_ME 17
+#define SYS_CLOCK_GETTIME 18
+#define SYS_SLEEP_NS 19
+#define SYS_FB_INFO 20
+#define SYS_FB_BLIT 21
+#define SYS_PIPE 22
+#define SYS_CLOSE 23
+#define SYS_FUTEX_WAIT 24
+#define SYS_FUTEX_WAKE 25
+#define SYS_KILL 26
+#define SYS_SIGACTION 27
+#define SYS_SIGRETURN 28
+#define SYS_GETRANDOM 29
+#define SYS_SYSINFO 30
+#define SYS_DMESG 31
+#define SYS_REBOOT 32
+#define SYS_SET_FS_BASE 33
+#define SYS_GET_FS_BASE 34
+#define SYSCALL_COUNT 35
+
+// Error numbers, as in Linux.
+#define MAX_ERRNO 4095
+#define EPERM 1 // Operation not permitted
+#define ENOENT 2 // No such file or directory (or program)
+#define ESRCH 3 // No such task
+#define EINTR 4 // Interrupted by a signal
+#define E2BIG 7 // Argument list too long
+#define ENOEXEC 8 // Exec format error
+#define EBADF 9 // Bad file descriptor
+#define ECHILD 10 // No child to wait for
+#define EAGAIN 11 // Try again (the futex word changed)
+#define ENOMEM 12 // Out of memory
+#define EFAULT 14 // Bad address
+#define ENODEV 19 // No such device
+#define EINVAL 22 // Invalid argument
+#define EMFILE 24 // Too many open files
+#define EPIPE 32 // Broken pipe
+#define ENOSYS 38 // Function not implemented
+#define ETIMEDOUT 110 // Timed out
+
+// Flags for memory syscalls.
+#define PROT_WRITE 0x2
+#define MAP_SHARED 0x1
+
+// Task information, as returned by sys_task_stats, and flags for starting tasks.
+#define TASK_NAME_LEN 16
+#define SPAWN_TRACE 0x1
+#define STATE_RUNNING 0
+#define STATE_READY 1
+#define STATE_SLEEPING 2
+#define STATE_BLOCKED 3
+#define STATE_EXITED 4
+
+// Signals, and the frame the kernel saves on the user stack to run a signal handler.
+#define SIGINT 2 // Interrupt
+#define SIGKILL 9 // Kill, can't have a handler
+#define SIGUSR1 10 // For the program to use
+#define SIGUSR2 12 // For the program to use
+#define SIGTERM 15 // Termination
+#define NSIG 32
+#define SIGNAL_EXIT_BASE 128
+#define RED_ZONE_SIZE 128
+
+// Clocks, and the time structs of time syscalls.
+#define CLOCK_REALTIME 0
+#define CLOCK_MONOTONIC 1
+
+// Arguments of sys_reboot.
+#define REBOOT_MAGIC 0xfee1dead
+#define REBOOT_CMD_RESTART 1
+#define REBOOT_CMD_POWER_OFF 2
+
+// The framebuffer, as user programs see it through sys_fb_info and sys_fb_blit.
+#define FB_FORMAT_RGB 0 // Red, green and blue bytes, then padding up to bytes_per_pixel
+#define FB_FORMAT_BGR 1 // Blue, green and red bytes, then padding up to bytes_per_pixel
+#define FB_FORMAT_GRAY 2 // One intensity byte
+#define FB_FORMAT_UNKNOWN 3
+
+// elytra_abi::task::TaskInfo
+struct task_info
+{
+    unsigned long id;
+    unsigned int state;
+    unsigned int priority;
+    unsigned long runtime_ms;
+    unsigned long voluntary_switches;
+    unsigned long preemptions;
+    char name[TASK_NAME_LEN];
+};
+_Static_assert(sizeof(struct task_info) == 56, "struct task_info changed");
+_Static_assert(__builtin_offsetof(struct task_info, id) == 0, "struct task_info changed");
+_Static_assert(__builtin_offsetof(struct task_info, state) == 8, "struct task_info changed");
+_Static_assert(__builtin_offsetof(struct task_info, priority) == 12, "struct task_info changed");
+_Static_assert(__builtin_offsetof(struct task_info, runtime_ms) == 16, "struct task_info changed");
+_Static_assert(__builtin_offsetof(struct task_info, voluntary_switches) == 24, "struct task_info changed");
+_Static_assert(__builtin_offsetof(struct task_info, preemptions) == 32, "struct task_info changed");
+_Static_assert(__builtin_offsetof(struct task_info, name) == 40, "struct task_info changed");
+
+// Always inlined, so that a program only has the syscall instructions it makes, even without optimizations.
+#define ABI_FN static inline __attribute__((always_inline))
+
+// Call syscall num. The syscall number goes in rax, and the arguments in rdi, rsi, rdx, r10, r8 and r9. The result
+// comes back in rax, as a negated error number if the syscall failed. The kernel clears the argument registers on
+// return, so they are all clobbered, and the SYSCALL instruction itself clobbers rcx and r11.
+ABI_FN long syscall0(long num)
+{
+    long ret;
+    __asm__ volatile(
+        "syscall"
+        : "=a"(ret)
+        : "a"(num)
+        : "rcx", "r11", "rdi", "rsi", "rdx", "r8", "r9", "r10", "memory");
+    return ret;
+}
+
+ABI_FN long syscall1(long num, long arg1)
+{
+    long ret;
+    __asm__ volatile(
+        "syscall"
+        : "=a"(ret), "+D"(arg1)
+        : "a"(num)
+        : "rcx", "r11", "rsi", "rdx", "r8", "r9", "r10", "memory");
+    return ret;
+}
+
+ABI_FN long syscall2(long num, long arg1, long arg2)
+{
+    long ret;
+    __asm__ volatile(
+        "syscall"
+        : "=a"(ret), "+D"(arg1), "+S"(arg2)
+        : "a"(num)
+        : "rcx", "r11", "rdx", "r8", "r9", "r10", "memory");
+    return ret;
+}
+
+ABI_FN long syscall3(long num, long arg1, long arg2, long arg3)
+{
+    long ret;
+    __asm__ volatile(
+        "syscall"
+        : "=a"(ret), "+D"(arg1), "+S"(arg2), "+d"(arg3)
+        : "a"(num)
+        : "rcx", "r11", "r8", "r9", "r10", "memory");
+    return ret;
+}
+
+ABI_FN long syscall4(long num, long arg1, long arg2, long arg3, long arg4)
+{
+    long ret;
+    register long r10 __asm__("r10") = arg4;
+    __asm__ volatile(
+        "syscall"
+        : "=a"(ret), "+D"(arg1), "+S"(arg2), "+d"(arg3), "+r"(r10)
+        : "a"(num)
+        : "rcx", "r11", "r8", "r9", "memory");
+    return ret;
+}
+
+ABI_FN long syscall5(long num, long arg1, long arg2, long arg3, long arg4, long arg5)
+{
+    long ret;
+    register long r10 __asm__("r10") = arg4;
+    register long r8 __asm__("r8") = arg5;
+    __asm__ volatile(
+        "syscall"
+        : "=a"(ret), "+D"(arg1), "+S"(arg2), "+d"(arg3), "+r"(r10), "+r"(r8)
+        : "a"(num)
+        : "rcx", "r11", "r9", "memory");
+    return ret;
+}
+
+ABI_FN long syscall6(long num, long arg1, long arg2, long arg3, long arg4, long arg5, long arg6)
+{
+    long ret;
+    register long r10 __asm__("r10") = arg4;
+    register long r8 __asm__("r8") = arg5;
+    register long r9 __asm__("r9") = arg6;
+    __asm__ volatile(
+        "syscall"
+        : "=a"(ret), "+D"(arg1), "+S"(arg2), "+d"(arg3), "+r"(r10), "+r"(r8), "+r"(r9)
+        : "a"(num)
+        : "rcx", "r11", "memory");
+    return ret;
+}
+
+// Wrappers for the syscalls the C programs make. They return the raw result.
+
+ABI_FN __attribute__((noreturn)) void sys_exit(long code)
+{
+    syscall1(SYS_EXIT, code);
+    __builtin_unreachable();
+}
+
+ABI_FN long sys_yield(void)
+{
+    return syscall0(SYS_YIELD);
+}
+
+// Move the end of the heap to addr, or just return it if addr is 0. Returns the new end.
+ABI_FN long sys_brk(void *addr)
+{
+    return syscall1(SYS_BRK, (long)addr);
+}
+
+ABI_FN long sys_write(long fd, const void *buf, long len)
+{
+    return syscall3(SYS_WRITE, fd, (long)buf, len);
+}
+
+// Write the memory map of the task to buf, truncated to len bytes. Returns its length.
+ABI_FN long sys_maps(char *buf, long len)
+{
+    return syscall2(SYS_MAPS, (long)buf, len);
+}
+
+ABI_FN long sys_sleep_ms(long ms)
+{
+    return syscall1(SYS_SLEEP_MS, ms);
+}
+
+ABI_FN long sys_set_priority(long priority)
+{
+    return syscall1(SYS_SET_PRIORITY, priority);
+}
+
+// Block until the next timer tick. Returns its number.
+ABI_FN long sys_wait_tick(void)
+{
+    return syscall0(SYS_WAIT_TICK);
+}
+
+// Start the program called name as a child, with SPAWN_* flags and a NULL-terminated argv, or none if argv is NULL.
+// Returns its id.
+ABI_FN long sys_spawn(const char *name, long len, long flags, const char *const *argv)
+{
+    return syscall4(SYS_SPAWN, (long)name, len, flags, (long)argv);
+}
+
+// Wait for the child with the id to exit, and store its exit code in status. Returns the id.
+ABI_FN long sys_wait(long id, int *status)
+{
+    return syscall2(SYS_WAIT, id, (long)status);
+}
+
+// Returns the id of the child in the parent, and 0 in the child.
+ABI_FN long sys_fork(void)
+{
+    return syscall0(SYS_FORK);
+}
+
+// Replace the program of the task with the one called name. Only returns if that failed.
+ABI_FN long sys_exec(const char *name, long len, const char *const *argv)
+{
+    return syscall3(SYS_EXEC, (long)name, len, (long)argv);
+}
+
+// Fill tasks with up to count tasks. Returns the number of tasks, even those that didn't fit.
+ABI_FN long sys_task_stats(struct task_info *tasks, long count)
+{
+    return syscall2(SYS_TASK_STATS, (long)tasks, count);
+}
+
+#endif
@@ -0,0 +1,25 @@
+// gcc -masm=intel -static -nostdlib exec.c -o exec
+
+#include "elytra_abi.h"
+
+// Exec the args program, which echoes its argv[1] and exits with 0. Failed execs must return here with the old image
+// intact, and a successful one never returns.
+void _start()
+{
+    static const char missing[] = "missing";
+    if (sys_exec(missing, sizeof(missing) - 1, 0) != -ENOENT)
+        goto fail;
+
+    static const char name[] = "args";
+    if (sys_exec(name, sizeof(name) - 1, (const char *const *)0x10) != -EFAULT)
+        goto fail;
+
+    static const char message[] = "Launcher still here, exec'ing args\n";
+    sys_write(1, message, sizeof(message) - 1);
+
+    const char *argv[] = {"args", "Hello from exec!", 0};
+    sys_exec(name, sizeof(name) - 1, argv);
+
+fail:
+    sys_exit(1);
+}
@@ -0,0 +1,8 @@
+// gcc -masm=intel -static -nostdlib exit42.c -o exit42
+
+#include "elytra_abi.h"
+
+void _start()
+{
+    sys_exit(42);
+}
@@ -0,0 +1,38 @@
+// gcc -masm=intel -static -nostdlib fork.c -o fork
+
+#include "elytra_abi.h"
+
+// Lives in the data segment, which the child gets a copy-on-write copy of.
+static volatile long value = 1;
+
+// Fork, change value on both sides, and check that each side sees only its own write.
+void _start()
+{
+    long pid = sys_fork();
+    if (pid < 0)
+        sys_exit(1);
+
+    if (pid == 0)
+    {
+        value = 2;
+        // Yield, so the parent gets to write its value
+        sys_yield();
+
+        static const char message[] = "Child: value is 2\n";
+        sys_write(1, message, sizeof(message) - 1);
+        sys_exit(value == 2 ? 7 : 1);
+    }
+
+    value = 3;
+    // Yield, so the child gets to write its value
+    sys_yield();
+
+    static const char message[] = "Parent: value is 3\n";
+    sys_write(1, message, sizeof(message) - 1);
+
+    int status = 0;
+    if (sys_wait(pid, &status) != pid || status != 7)
+        sys_exit(1);
+
+    sys_exit(value == 3 ? 0 : 1);
+}
@@ -0,0 +1,65 @@
+// gcc -masm=intel -static -nostdlib fpu.c -o fpu
+
+#include "elytra_abi.h"
+
+#define ROUNDS 10
+#define ITERATIONS 10000000
+
+// Add step to an accumulator that never leaves its xmm register, so any preemption that loses the SSE registers
+// changes the result. Every partial sum is exact, so the final value must be too.
+static int accumulate(char label, double start, double step, unsigned int mxcsr)
+{
+    char message[] = "? round 0\n";
+    message[0] = label;
+
+    __asm__ volatile("ldmxcsr %0" : : "m"(mxcsr));
+
+    double acc = start;
+    for (int round = 0; round < ROUNDS; round++)
+    {
+        long n = ITERATIONS;
+        __asm__ volatile(
+            "1:\n\t"
+            "addsd %0, %2\n\t"
+            "dec %1\n\t"
+            "jnz 1b\n\t"
+            : "+x"(acc), "+r"(n)
+            : "x"(step));
+
+        message[8] = '0' + round;
+        sys_write(1, message, sizeof(message) - 1);
+    }
+
+    unsigned int mxcsr_after;
+    __asm__ volatile("stmxcsr %0" : "=m"(mxcsr_after));
+
+    return acc == start + step * ROUNDS * ITERATIONS && mxcsr_after == mxcsr;
+}
+
+// Fork, then accumulate different values in both tasks, with different SSE rounding modes, while they preempt each
+// other.
+void _start()
+{
+    long pid = sys_fork();
+    if (pid < 0)
+        sys_exit(1);
+
+    if (pid == 0)
+    {
+        // Round toward zero, which doesn't change the exact sums
+        int ok = accumulate('C', 1000000.0, 0.5, 0x7f80);
+        sys_exit(ok ? 7 : 1);
+    }
+
+    int ok = accumulate('P', 3.0, 0.25, 0x1f80);
+
+    int status = 0;
+    if (sys_wait(pid, &status) != pid || status != 7)
+        ok = 0;
+
+    static const char message[] = "FPU state preserved\n";
+    if (ok)
+        sys_write(1, message, sizeof(message) - 1);
+
+    sys_exit(ok ? 0 : 1);
+}
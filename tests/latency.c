@@ -0,0 +1,67 @@
+// gcc -masm=intel -static -nostdlib latency.c -o latency
+
+#include "elytra_abi.h"
+
+static unsigned long rdtsc()
+{
+    unsigned int low, high;
+    __asm__ volatile("rdtsc" : "=a"(low), "=d"(high));
+    return ((unsigned long)high << 32) | low;
+}
+
+static void print(const char *str)
+{
+    long len = 0;
+    while (str[len])
+        len++;
+    sys_write(1, str, len);
+}
+
+static void print_number(unsigned long number)
+{
+    char buf[21];
+    int i = sizeof(buf);
+    buf[--i] = 0;
+    do
+    {
+        buf[--i] = '0' + number % 10;
+        number /= 10;
+    } while (number);
+    print(buf + i);
+}
+
+// Sleep for 10 ms in short bursts, while a lower priority task spins, and measure how long each sleep takes.
+// With priorities, the task runs again right after it wakes up, so no sleep should take much longer than the others.
+void _start()
+{
+    long code = 0;
+
+    // 99 is not a valid priority, 3 is the highest
+    if (sys_set_priority(99) != -EINVAL || sys_set_priority(3) != 0)
+        code = 1;
+
+    unsigned long min = -1, max = 0;
+    for (int i = 0; i < 20; i++)
+    {
+        unsigned long start = rdtsc();
+        sys_sleep_ms(10);
+        unsigned long elapsed = rdtsc() - start;
+
+        if (elapsed < min)
+            min = elapsed;
+        if (elapsed > max)
+            max = elapsed;
+    }
+
+    print("Sleep latency in cycles: min ");
+    print_number(min);
+    print(", max ");
+    print_number(max);
+    print("\n");
+
+    // A sleep of 10 ms takes 1 to 2 ticks, a sleep delayed by a whole time slice of the spinner takes 5 or more
+    if (max > 3 * min)
+        code = 1;
+
+    sys_exit(code);
+}
@@ -0,0 +1,20 @@
+// gcc -masm=intel -static -nostdlib maps.c -o maps
+
+#include "elytra_abi.h"
+
+static char buf[1024];
+
+void _start()
+{
+    // Get our memory map, and print it
+    long len = sys_maps(buf, sizeof(buf));
+    if (len > 0)
+        sys_write(1, buf, len);
+
+    // The map is truncated to a buffer that is too small
+    len = sys_maps(buf, 10);
+    if (len == 10)
+        sys_write(1, "Truncated correctly\n", 20);
+
+    sys_exit(0);
+}
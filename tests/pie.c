@@ -0,0 +1,24 @@
+// gcc -masm=intel -static-pie -nostdlib pie.c -o pie
+
+#include "elytra_abi.h"
+
+static int value = 42;
+// The address of value is only known at load time, so this needs a relative relocation.
+static int *volatile ptr = &value;
+
+static char buf[] = "Loaded at 0x0000000000000000\n";
+
+void _start()
+{
+    // Crash if the relocation wasn't applied
+    if (*ptr != 42 || ptr != &value)
+        *(volatile int *)0 = 0;
+
+    // Print where we were loaded
+    unsigned long addr = (unsigned long)&value;
+    for (int i = 0; i < 16; i++)
+        buf[27 - i] = "0123456789abcdef"[(addr >> (i * 4)) & 0xf];
+    sys_write(1, buf, sizeof(buf) - 1);
+
+    sys_exit(0);
+}
@@ -0,0 +1,42 @@
+// gcc -masm=intel -static -nostdlib ps.c -o ps
+
+#include "elytra_abi.h"
+
+// Use some CPU time and sleep once, then find this task in the task stats.
+void _start()
+{
+    long code = 0;
+
+    for (volatile long i = 0; i < 20000000; i++)
+        ;
+    sys_sleep_ms(10);
+
+    // With no room, only counts the tasks
+    struct task_info tasks[8];
+    long count = sys_task_stats(tasks, 0);
+    if (count < 1 || sys_task_stats(tasks, 8) != count)
+        code = 1;
+
+    int found = 0;
+    for (long i = 0; i < count && i < 8; i++)
+    {
+        struct task_info *task = &tasks[i];
+        if (task->name[0] != 'p' || task->name[1] != 's' || task->name[2] != 0)
+            continue;
+
+        found = 1;
+        if (task->state != STATE_RUNNING || task->runtime_ms == 0 || task->voluntary_switches < 1)
+            code = 1;
+    }
+    if (!found)
+        code = 1;
+
+    // To a bad buffer
+    if (sys_task_stats((struct task_info *)0x10, 8) != -EFAULT)
+        code = 1;
+
+    static const char message[] = "Found this task in the task stats\n";
+    sys_write(1, message, sizeof(message) - 1);
+
+    sys_exit(code);
+}
@@ -0,0 +1,32 @@
+// gcc -masm=intel -static -nostdlib -Wl,-z,max-page-size=16 -Wl,--section-start=.data=0x401040 shared_page.c -o shared_page
+
+#include "elytra_abi.h"
+
+// Without page alignment, the read-only, text and rodata segments all land in the first page. The program checks its
+// rodata, data and BSS, and crashes if any of it is wrong.
+static const char message[] = "segments sharing a page";
+static volatile unsigned long numbers[4] = {1, 2, 3, 0x1122334455667788};
+static volatile unsigned long zeroed[16];
+
+static void check(int ok)
+{
+    if (!ok)
+        *(volatile char *)0 = 0; // Page fault, the task gets killed
+}
+
+void _start()
+{
+    const volatile char *text = message;
+    check(text[0] == 's' && text[9] == 's' && text[22] == 'e' && text[23] == 0);
+
+    check(numbers[0] == 1 && numbers[1] == 2 && numbers[2] == 3);
+    check(numbers[3] == 0x1122334455667788);
+
+    for (int i = 0; i < 16; i++)
+    {
+        check(zeroed[i] == 0);
+        zeroed[i] = i;
+    }
+
+    sys_exit(0);
+}
@@ -0,0 +1,19 @@
+// gcc -masm=intel -static -nostdlib sleep.c -o sleep
+
+#include "elytra_abi.h"
+
+void _start()
+{
+    static const char before[] = "Going to sleep for 100 ms\n";
+    sys_write(1, before, sizeof(before) - 1);
+
+    sys_sleep_ms(100);
+
+    static const char after[] = "Woke up\n";
+    sys_write(1, after, sizeof(after) - 1);
+
+    // A sleep of 0 ms just yields
+    sys_sleep_ms(0);
+
+    sys_exit(0);
+}
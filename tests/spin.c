@@ -0,0 +1,30 @@
+// gcc -masm=intel -static -nostdlib spin.c -o spin
+
+#include "elytra_abi.h"
+
+// At the entry point, rsp points to argc. Pass it on before anything is pushed.
+__asm__(
+    ".global _start\n"
+    "_start:\n\t"
+    "mov rdi, rsp\n\t"
+    "call start_c\n\t");
+
+// Spin on the CPU without ever yielding, printing argv[1] and the round after each round.
+// Without preemption, one instance runs to the end before another one starts.
+void start_c(long *sp)
+{
+    char **argv = (char **)(sp + 1);
+    char message[] = "? round 0\n";
+    message[0] = argv[1][0];
+
+    for (int round = 0; round < 10; round++)
+    {
+        for (volatile long i = 0; i < 20000000; i++)
+            ;
+
+        message[8] = '0' + round;
+        sys_write(1, message, sizeof(message) - 1);
+    }
+
+    sys_exit(0);
+}
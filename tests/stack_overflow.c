@@ -0,0 +1,14 @@
+// gcc -masm=intel -static -nostdlib stack_overflow.c -o stack_overflow
+
+// Recurse forever, the task should be killed when it hits the guard page below its stack.
+void recurse(int depth)
+{
+    volatile char buf[256];
+    buf[0] = depth;
+    recurse(depth + 1);
+}
+
+void _start()
+{
+    recurse(0);
+}
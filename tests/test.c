@@ -1,18 +1,26 @@
 // gcc -masm=intel -static -nostdlib test.c -o test
 
+#include "elytra_abi.h"
+
 void _start()
 {
     int a = 5;
     a += 10;
 
-    __asm__(
-        // yield
-        "mov rax, 1\n\t"
-        "syscall\n\t"
-        // yield
-        "mov rax, 1\n\t"
-        "syscall\n\t"
-        // print exit
-        "mov rax, 0\n\t"
-        "syscall\n\t");
-}
\ No newline at end of file
+    // Print a message to the kernel console
+    static const char message[] = "Hello from user mode!\n";
+    sys_write(1, message, sizeof(message) - 1);
+
+    // Grow the heap by two pages and touch them
+    char *heap = (char *)sys_brk(0);
+    char *heap_end = (char *)sys_brk(heap + 2 * 4096);
+    if (heap_end == heap + 2 * 4096)
+    {
+        for (char *p = heap; p < heap_end; p += 4096)
+            *p = 42;
+    }
+
+    sys_yield();
+    sys_yield();
+    sys_exit(0);
+}
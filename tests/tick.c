@@ -0,0 +1,24 @@
+// gcc -masm=intel -static -nostdlib tick.c -o tick
+
+#include "elytra_abi.h"
+
+// Wait for the next timer tick 10 times. Each wait must end on a later tick than the one before,
+// otherwise the task was resumed more than once for the same wake up.
+void _start()
+{
+    long code = 0;
+
+    long last = sys_wait_tick();
+    for (int i = 0; i < 10; i++)
+    {
+        long tick = sys_wait_tick();
+        if (tick <= last)
+            code = 1;
+        last = tick;
+    }
+
+    static const char message[] = "Waited for 10 ticks\n";
+    sys_write(1, message, sizeof(message) - 1);
+
+    sys_exit(code);
+}
@@ -0,0 +1,28 @@
+// gcc -masm=intel -static -nostdlib tls.c -o tls
+
+#include "elytra_abi.h"
+
+// Thread-local variables, accessed relative to the fs base
+static __thread volatile long initialized = 0x1234;
+static __thread volatile char zeroed[100];
+
+void _start()
+{
+    // The first word of the TCB (at the fs base) points to itself
+    long tp;
+    __asm__ volatile("mov %0, fs:0" : "=r"(tp));
+    if ((long)&initialized >= tp)
+        *(volatile int *)0 = 0;
+
+    // Crash if the TLS block wasn't initialized from the template
+    if (initialized != 0x1234)
+        *(volatile int *)0 = 0;
+    for (int i = 0; i < sizeof(zeroed); i++)
+        if (zeroed[i] != 0)
+            *(volatile int *)0 = 0;
+
+    initialized++;
+    sys_write(1, "TLS initialized correctly\n", 26);
+
+    sys_exit(0);
+}
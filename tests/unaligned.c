@@ -0,0 +1,30 @@
+// gcc -masm=intel -static -nostdlib -Wl,--section-start=.data=0x404123 unaligned.c -o unaligned
+
+#include "elytra_abi.h"
+
+// The data segment starts in the middle of a page (0x404123). The program checks that its initialized data
+// landed at the right addresses and that the BSS following it is zeroed, and crashes if not.
+static volatile char message[] = "unaligned segment";
+static volatile unsigned long numbers[4] = {1, 2, 3, 0x1122334455667788};
+static volatile unsigned long zeroed[16];
+
+static void check(int ok)
+{
+    if (!ok)
+        *(volatile char *)0 = 0; // Page fault, the task gets killed
+}
+
+void _start()
+{
+    const char *expected = "unaligned segment";
+    for (int i = 0; expected[i]; i++)
+        check(message[i] == expected[i]);
+
+    check(numbers[0] == 1 && numbers[1] == 2 && numbers[2] == 3);
+    check(numbers[3] == 0x1122334455667788);
+
+    for (int i = 0; i < 16; i++)
+        check(zeroed[i] == 0);
+
+    sys_exit(0);
+}
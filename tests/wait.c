@@ -0,0 +1,52 @@
+// gcc -masm=intel -static -nostdlib wait.c -o wait
+
+#include "elytra_abi.h"
+
+// Spawn without flags or arguments.
+static long spawn(const char *name, long len)
+{
+    return sys_spawn(name, len, 0, 0);
+}
+
+// Spawn children and wait for them to exit, checking their exit codes and the errors for bad waits.
+void _start()
+{
+    static const char name[] = "exit42";
+    long code = 0;
+    int status = 0;
+
+    // Wait for a child that is still running
+    long pid = spawn(name, sizeof(name) - 1);
+    if (pid <= 0)
+        code = 1;
+    if (sys_wait(pid, &status) != pid || status != 42)
+        code = 1;
+
+    // Its exit code can only be taken once
+    if (sys_wait(pid, &status) != -ECHILD)
+        code = 1;
+
+    // Wait for a child that has already exited
+    pid = spawn(name, sizeof(name) - 1);
+    sys_sleep_ms(50);
+    status = 0;
+    if (sys_wait(pid, &status) != pid || status != 42)
+        code = 1;
+
+    // Tasks that are not our children can't be waited for
+    if (sys_wait(1, &status) != -ECHILD)
+        code = 1;
+
+    // Unknown programs can't be spawned
+    static const char missing[] = "missing";
+    if (spawn(missing, sizeof(missing) - 1) != -ENOENT)
+        code = 1;
+
+    // This child is never waited for, and its exit code is dropped when we exit
+    spawn(name, sizeof(name) - 1);
+
+    static const char message[] = "Waited for children\n";
+    sys_write(1, message, sizeof(message) - 1);
+
+    sys_exit(code);
+}
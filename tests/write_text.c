@@ -0,0 +1,13 @@
+// gcc -masm=intel -static -nostdlib write_text.c -o write_text
+
+#include "elytra_abi.h"
+
+// Write to our own text segment, the task should be killed by the page fault.
+void _start()
+{
+    volatile unsigned char *code = (volatile unsigned char *)_start;
+    *code = 0xCC;
+
+    // Never reached
+    sys_exit(0);
+}
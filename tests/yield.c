@@ -0,0 +1,12 @@
+// gcc -masm=intel -static -nostdlib yield.c -o yield
+
+#include "elytra_abi.h"
+
+// Yield a million times, for the context switch benchmark. Two of these bounce the CPU between each other.
+void _start()
+{
+    for (long i = 0; i < 1000000; i++)
+        sys_yield();
+
+    sys_exit(0);
+}
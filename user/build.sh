@@ -0,0 +1,9 @@
+#!/bin/sh
+# Build the user programs, and copy them to tests/ for the kernel to embed.
+set -e
+cd "$(dirname "$0")"
+cargo build --release
+for src in src/bin/*.rs; do
+    name=$(basename "$src" .rs)
+    cp "target/x86_64-unknown-none/release/$name" "../tests/$name"
+done
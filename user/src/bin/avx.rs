@@ -0,0 +1,36 @@
+// Build with user/build.sh
+
+//! Fork children that run invalid opcodes: one puts a LOCK prefix on an add to a register, the other runs an AVX
+//! instruction, which the kernel doesn't enable. The kernel kills each of them, and the child forked after them still
+//! runs. Exits with 0 if only the faulting children were killed, or the number of the first check that failed.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+use user::{KILLED_EXIT_CODE, in_child};
+
+// lock add eax, eax, which the assembler refuses to write
+fn lock_on_register() {
+    unsafe { asm!(".byte 0xf0, 0x01, 0xc0", out("eax") _) };
+}
+
+// The last fault, so the kernel test finds its likely cause
+fn run_avx() {
+    unsafe { asm!("vzeroupper") };
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    if in_child(lock_on_register) != Some(KILLED_EXIT_CODE) {
+        return 1;
+    }
+    if in_child(run_avx) != Some(KILLED_EXIT_CODE) {
+        return 2;
+    }
+    if in_child(|| {}) != Some(0) {
+        return 3;
+    }
+    0
+}
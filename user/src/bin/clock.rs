@@ -0,0 +1,69 @@
+// Build with user/build.sh
+
+//! Measure a 100 ms sleep with the monotonic clock, check the wall clock is past 2020, and check the errors of
+//! clock_gettime.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{
+    errno::{EFAULT, EINVAL},
+    syscall::{sys_clock_gettime, sys_sleep_ns},
+    time::{CLOCK_MONOTONIC, CLOCK_REALTIME, Timespec},
+};
+use user as _;
+
+const SLEEP_NS: u64 = 100_000_000;
+// How much longer than asked the sleep may take: rounding up to ticks, and waiting to be scheduled
+const TOLERANCE_NS: u64 = 60_000_000;
+// 2020-01-01 00:00:00 UTC, in Unix seconds
+const YEAR_2020: u64 = 1_577_836_800;
+
+fn now() -> Option<u64> {
+    let mut time = Timespec::default();
+    (sys_clock_gettime(CLOCK_MONOTONIC, &mut time) == 0 && time.nanos < Timespec::NANOS_PER_SEC)
+        .then(|| time.as_nanos())
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let mut time = Timespec::default();
+    if sys_clock_gettime(2, &mut time) != -(EINVAL as isize) {
+        return 1;
+    }
+    if sys_clock_gettime(CLOCK_MONOTONIC, 0x10 as *mut Timespec) != -(EFAULT as isize) {
+        return 2;
+    }
+
+    let Some(start) = now() else {
+        return 3;
+    };
+    if sys_sleep_ns(SLEEP_NS) != 0 {
+        return 4;
+    }
+    let Some(end) = now() else {
+        return 5;
+    };
+
+    let elapsed = end.saturating_sub(start);
+    if elapsed < SLEEP_NS {
+        return 6;
+    }
+    if elapsed > SLEEP_NS + TOLERANCE_NS {
+        return 7;
+    }
+
+    // A sleep of 0 just yields, and the clock never goes back
+    if sys_sleep_ns(0) != 0 || now().is_none_or(|later| later < end) {
+        return 8;
+    }
+
+    if sys_clock_gettime(CLOCK_REALTIME, &mut time) != 0 || time.nanos >= Timespec::NANOS_PER_SEC {
+        return 9;
+    }
+    if time.secs < YEAR_2020 {
+        return 10;
+    }
+    0
+}
@@ -0,0 +1,54 @@
+// Build with user/build.sh
+
+//! Print a line, and check that it is the newest line of the kernel log, that a short buffer gets only whole lines,
+//! and that dmesg rejects bad buffers.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{errno::EFAULT, syscall::sys_dmesg};
+use user::println;
+
+const MARKER: &[u8] = b"dmesg: a line for the kernel log";
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    println!("{}", core::str::from_utf8(MARKER).unwrap());
+
+    let mut log = [0u8; 4096];
+    let len = sys_dmesg(log.as_mut_ptr(), log.len());
+    if len <= 0 || len as usize > log.len() {
+        return 1;
+    }
+    let log = &log[..len as usize];
+
+    // Whole lines, each with a timestamp
+    if log.last() != Some(&b'\n') {
+        return 2;
+    }
+    let mut lines = log[..log.len() - 1].split(|&byte| byte == b'\n');
+    if lines.clone().any(|line| line.first() != Some(&b'[')) {
+        return 3;
+    }
+    // The newest is what was just printed, after "[    0.000000] "
+    if !lines.next_back().is_some_and(|line| line.ends_with(MARKER)) {
+        return 4;
+    }
+
+    // A buffer too short for the newest line gets nothing, and one just long enough gets only it
+    let newest_len = MARKER.len() + "[    0.000000] \n".len();
+    let mut short = [0u8; 64];
+    if sys_dmesg(short.as_mut_ptr(), 8) != 0 {
+        return 5;
+    }
+    let len = sys_dmesg(short.as_mut_ptr(), newest_len + 1);
+    if len != newest_len as isize || !short[..newest_len - 1].ends_with(MARKER) {
+        return 6;
+    }
+
+    if sys_dmesg(0x10 as *mut u8, 4096) != -(EFAULT as isize) {
+        return 7;
+    }
+    0
+}
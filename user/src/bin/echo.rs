@@ -0,0 +1,49 @@
+// Build with user/build.sh
+
+//! Read a line from the console and write it back. Bad reads fail right away, without waiting for input.
+//! Exits with 0 if the line was echoed, or the number of the check that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{
+    errno::{EBADF, EFAULT},
+    syscall::{sys_read, sys_write},
+};
+use user as _;
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let mut line = [0u8; 128];
+
+    if sys_read(1, line.as_mut_ptr(), line.len()) != -(EBADF as isize) {
+        return 1;
+    }
+    if sys_read(0, 0x10 as *mut u8, line.len()) != -(EFAULT as isize) {
+        return 2;
+    }
+    if sys_read(0, 0xffff800000000000 as *mut u8, line.len()) != -(EFAULT as isize) {
+        return 3;
+    }
+    if sys_read(0, line.as_mut_ptr(), 0) != 0 {
+        return 4;
+    }
+
+    let mut len = 0;
+    while len == 0 || line[len - 1] != b'\n' {
+        let rest = &mut line[len..];
+        let count = sys_read(0, rest.as_mut_ptr(), rest.len());
+        if count <= 0 {
+            return 5;
+        }
+        len += count as usize;
+        if len == line.len() {
+            return 6;
+        }
+    }
+
+    if sys_write(1, line.as_ptr(), len) != len as isize {
+        return 7;
+    }
+    0
+}
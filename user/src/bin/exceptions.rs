@@ -0,0 +1,46 @@
+// Build with user/build.sh
+
+//! Fork children that cause CPU exceptions: an invalid opcode, a division by zero, and a jump to a page that is not
+//! executable. The kernel kills each of them, and this task goes on to reap them.
+//! Exits with 0 if every child was killed, or the number of the first check that failed.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+use user::{KILLED_EXIT_CODE, in_child};
+
+fn invalid_opcode() {
+    unsafe { asm!("ud2") };
+}
+
+// Not with the / operator, which checks for zero first
+fn divide_by_zero() {
+    unsafe {
+        asm!(
+            "div {divisor}",
+            divisor = in(reg) 0u64,
+            inout("rax") 1u64 => _,
+            inout("rdx") 0u64 => _,
+        )
+    };
+}
+
+// The stack is never executable. The ret would come straight back if it were.
+fn jump_to_stack() {
+    let code = [0xc3u8; 16];
+    let f: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    f();
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let tests: [fn(); 3] = [invalid_opcode, divide_by_zero, jump_to_stack];
+    for (i, test) in tests.into_iter().enumerate() {
+        if in_child(test) != Some(KILLED_EXIT_CODE) {
+            return i as i32 + 1;
+        }
+    }
+    0
+}
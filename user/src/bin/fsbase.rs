@@ -0,0 +1,85 @@
+// Build with user/build.sh
+
+//! Give two tasks different fs bases, and check each keeps its own while the timer preempts them. Each task reaches a
+//! block of its own through fs, like thread-local storage, whose first word points to itself.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+use elytra_abi::{
+    errno::EINVAL,
+    syscall::{sys_clock_gettime, sys_exit, sys_fork, sys_get_fs_base, sys_set_fs_base, sys_wait},
+    time::{CLOCK_MONOTONIC, Timespec},
+};
+use user as _;
+
+// How long each task checks its fs base for, which is many time slices
+const RUN_NS: u64 = 200_000_000;
+
+// The block of each task: a pointer to itself, then the number of the task
+static mut BLOCKS: [[usize; 2]; 2] = [[0; 2]; 2];
+
+fn now() -> u64 {
+    let mut time = Timespec::default();
+    sys_clock_gettime(CLOCK_MONOTONIC, &mut time);
+    time.as_nanos()
+}
+
+// Read the word at the offset from the fs base.
+fn read_fs(offset: usize) -> usize {
+    let value;
+    unsafe { asm!("mov {}, fs:[{}]", out(reg) value, in(reg) offset, options(readonly, nostack)) };
+    value
+}
+
+// Set the fs base to the block of the task, then keep checking it is still there. Returns 0 if it always was, or the
+// number of the check that failed.
+fn check(task: usize) -> i32 {
+    let block = unsafe { &raw mut BLOCKS[task] };
+    unsafe { *block = [block as usize, task] };
+    if sys_set_fs_base(block as usize) != 0 {
+        return 10;
+    }
+
+    let start = now();
+    let mut rounds = 0usize;
+    while now() - start < RUN_NS {
+        if read_fs(0) != block as usize || read_fs(8) != task {
+            return 11;
+        }
+        if rounds.is_multiple_of(64) && sys_get_fs_base() != block as isize {
+            return 12;
+        }
+        rounds += 1;
+    }
+    0
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    // Kernel addresses can't be a user fs base
+    if sys_set_fs_base(0xffff_8000_0000_0000) != -(EINVAL as isize) {
+        return 1;
+    }
+
+    let child = sys_fork();
+    if child < 0 {
+        return 2;
+    }
+    if child == 0 {
+        sys_exit(check(1));
+    }
+
+    let code = check(0);
+    if code != 0 {
+        return code;
+    }
+    let mut status = -1;
+    if sys_wait(child as usize, &mut status) != child {
+        return 3;
+    }
+    status
+}
@@ -0,0 +1,38 @@
+// Build with user/build.sh
+
+//! Fork children that cause general protection faults: one loads the TSS selector into ds, the other runs wrmsr,
+//! which only the kernel may run. The kernel kills each of them, and the child forked after them still runs.
+//! Exits with 0 if only the faulting children were killed, or the number of the first check that failed.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+use user::{KILLED_EXIT_CODE, in_child};
+
+// Selector of the TSS in the GDT, which can't be loaded into a data segment register
+const TSS_SELECTOR: u16 = 0x28;
+
+fn load_tss_selector() {
+    unsafe { asm!("mov ds, {:x}", in(reg) TSS_SELECTOR) };
+}
+
+// The last fault, so the kernel test finds its instruction bytes (0f 30)
+fn write_msr() {
+    unsafe { asm!("wrmsr", in("ecx") 0x10, in("eax") 0, in("edx") 0) };
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    if in_child(load_tss_selector) != Some(KILLED_EXIT_CODE) {
+        return 1;
+    }
+    if in_child(write_msr) != Some(KILLED_EXIT_CODE) {
+        return 2;
+    }
+    if in_child(|| {}) != Some(0) {
+        return 3;
+    }
+    0
+}
@@ -0,0 +1,78 @@
+// Build with user/build.sh
+
+//! Fill the framebuffer with a gradient, after checking that bad blits fail. Does nothing if there is no framebuffer.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{
+    errno::{EFAULT, EINVAL, ENODEV},
+    fb::{FB_FORMAT_BGR, FB_FORMAT_GRAY, FB_FORMAT_RGB, FbInfo},
+    syscall::{sys_fb_blit, sys_fb_info},
+};
+use user::println;
+
+// Pixels drawn by one blit, which fit on the stack.
+const CHUNK: usize = 256;
+
+// The pixel at (x, y) in the format of the framebuffer.
+fn pixel(info: &FbInfo, x: usize, y: usize) -> [u8; 4] {
+    let red = (x * 255 / info.width as usize) as u8;
+    let green = (y * 255 / info.height as usize) as u8;
+    let blue = 255 - red / 2 - green / 2;
+    match info.format {
+        FB_FORMAT_RGB => [red, green, blue, 0],
+        FB_FORMAT_BGR => [blue, green, red, 0],
+        _ => [red / 3 + green / 3 + blue / 3, 0, 0, 0],
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let mut info = FbInfo::default();
+    match sys_fb_info(&mut info) {
+        0 => {}
+        ret if ret == -(ENODEV as isize) => {
+            println!("gradient: no framebuffer");
+            return 0;
+        }
+        _ => return 1,
+    }
+    let (width, height) = (info.width as usize, info.height as usize);
+    let bytes_per_pixel = info.bytes_per_pixel as usize;
+    if width == 0 || height == 0 || !(1..=4).contains(&bytes_per_pixel) {
+        return 2;
+    }
+    if info.format == FB_FORMAT_GRAY && bytes_per_pixel != 1 {
+        return 3;
+    }
+
+    let mut chunk = [0u8; CHUNK * 4];
+
+    // Past the right and bottom edges, and from a bad buffer
+    if sys_fb_blit(width, 0, 1, 1, chunk.as_ptr()) != -(EINVAL as isize) {
+        return 4;
+    }
+    if sys_fb_blit(0, height - 1, 1, 2, chunk.as_ptr()) != -(EINVAL as isize) {
+        return 5;
+    }
+    if sys_fb_blit(0, 0, 1, 1, 0x10 as *const u8) != -(EFAULT as isize) {
+        return 6;
+    }
+
+    for y in 0..height {
+        for start in (0..width).step_by(CHUNK) {
+            let len = CHUNK.min(width - start);
+            let pixels = &mut chunk[..len * bytes_per_pixel];
+            for (i, bytes) in pixels.chunks_exact_mut(bytes_per_pixel).enumerate() {
+                bytes.copy_from_slice(&pixel(&info, start + i, y)[..bytes_per_pixel]);
+            }
+            if sys_fb_blit(start, y, len, 1, pixels.as_ptr()) != 0 {
+                return 7;
+            }
+        }
+    }
+    println!("gradient: drew {}x{}", width, height);
+    0
+}
@@ -0,0 +1,37 @@
+// Build with user/build.sh
+
+//! Print a message once, and check that bad writes fail without printing anything.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{
+    errno::{EBADF, EFAULT},
+    syscall::sys_write,
+};
+use user as _;
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let message = b"hello from ring 3\n";
+    let len = message.len() as isize;
+
+    if sys_write(1, message.as_ptr(), message.len()) != len {
+        1
+    } else if sys_write(0, message.as_ptr(), message.len()) != -(EBADF as isize) {
+        2
+    }
+    // Unmapped, kernel and non-canonical addresses
+    else if sys_write(1, 0x10 as *const u8, 4) != -(EFAULT as isize) {
+        3
+    } else if sys_write(1, 0xffff800000000000 as *const u8, 4) != -(EFAULT as isize) {
+        4
+    } else if sys_write(2, 0x0000800000000000 as *const u8, 4) != -(EFAULT as isize) {
+        5
+    } else if sys_write(1, message.as_ptr(), 0) != 0 {
+        6
+    } else {
+        0
+    }
+}
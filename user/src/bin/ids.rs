@@ -0,0 +1,52 @@
+// Build with user/build.sh
+
+//! Check getpid and getppid across a fork, and that errors come back as exactly -errno.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{
+    errno::EFAULT,
+    syscall::{sys_exit, sys_fork, sys_getpid, sys_getppid, sys_wait, sys_write},
+};
+use user as _;
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let pid = sys_getpid();
+    if pid <= 0 {
+        return 1;
+    }
+
+    // A write from an unmapped address fails with -EFAULT, compared as a signed value
+    if sys_write(1, 0x10 as *const u8, 4) != -(EFAULT as isize) {
+        return 2;
+    }
+
+    let child = sys_fork();
+    if child < 0 {
+        return 3;
+    }
+
+    if child == 0 {
+        // The child has its own id, and its parent is the task that forked it
+        if sys_getpid() == pid {
+            sys_exit(4);
+        }
+        if sys_getppid() != pid {
+            sys_exit(5);
+        }
+        sys_exit(0);
+    }
+
+    if child == pid {
+        return 6;
+    }
+
+    let mut status = -1;
+    if sys_wait(child as usize, &mut status) != child {
+        return 7;
+    }
+    status
+}
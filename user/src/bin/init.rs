@@ -0,0 +1,53 @@
+// Build with user/build.sh
+
+//! Spawn a few other programs, wait for all of them, and print their exit codes. Also checks the errors of spawn.
+//! Exits with 0 if every child exited as expected, or the number of the first check that failed.
+
+#![no_std]
+#![no_main]
+
+use core::ptr::null;
+
+use elytra_abi::{
+    errno::{ENOENT, ENOEXEC},
+    syscall::{sys_spawn, sys_wait},
+};
+use user::println;
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    if sys_spawn("missing", 0, null()) != -(ENOENT as isize) {
+        return 1;
+    }
+    // Registered by the kernel test, but not an executable
+    if sys_spawn("notelf", 0, null()) != -(ENOEXEC as isize) {
+        return 2;
+    }
+
+    let argv = [c"args".as_ptr(), c"spawned with argv".as_ptr(), null()];
+    let children = [
+        ("exit42", sys_spawn("exit42", 0, null()), 42),
+        ("args", sys_spawn("args", 0, argv.as_ptr().cast()), 0),
+        ("hello", sys_spawn("hello", 0, null()), 0),
+    ];
+
+    let mut code = 0;
+    for (name, id, expected) in children {
+        if id <= 0 {
+            println!("init: spawning {} failed with {}", name, id);
+            code = 3;
+            continue;
+        }
+
+        let mut status = -1;
+        if sys_wait(id as usize, &mut status) != id {
+            code = 4;
+            continue;
+        }
+        println!("init: {} (task {}) exited with code {}", name, id, status);
+        if status != expected && code == 0 {
+            code = 5;
+        }
+    }
+    code
+}
@@ -0,0 +1,145 @@
+// Build with user/build.sh
+
+//! Count to a large number from two tasks that share a page, under a mutex built on futexes, and check the errors of
+//! futex_wait and futex_wake.
+//! Exits with 0 if no increment was lost, or the number of the first check that failed.
+
+#![no_std]
+#![no_main]
+
+use core::{
+    hint::black_box,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use elytra_abi::{
+    errno::{EAGAIN, EFAULT, EINVAL, ETIMEDOUT},
+    mm::{MAP_SHARED, PROT_WRITE},
+    syscall::{
+        sys_clock_gettime, sys_exit, sys_fork, sys_futex_wait, sys_futex_wake, sys_mmap, sys_wait,
+        sys_yield,
+    },
+    time::{CLOCK_MONOTONIC, Timespec},
+};
+use user as _;
+
+const ROUNDS: u64 = 100_000;
+// Every so many rounds, a task yields while it holds the lock, so the other one has to wait for it
+const YIELD_EVERY: u64 = 1000;
+const TIMEOUT_NS: u64 = 20_000_000;
+
+// Lives in a shared page, so both tasks see the same one.
+struct Shared {
+    // 0: unlocked, 1: locked, 2: locked, and a task may be waiting
+    lock: AtomicU32,
+    // Only changed under the lock, with a plain read and write that would lose increments without it
+    counter: u64,
+    // Number of times a task blocked on the lock
+    waits: AtomicU64,
+}
+
+// Take the lock. Returns the number of times the task blocked.
+fn lock(lock: &AtomicU32) -> u64 {
+    let mut state = match lock.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed) {
+        Ok(_) => return 0,
+        Err(state) => state,
+    };
+    if state != 2 {
+        state = lock.swap(2, Ordering::Acquire);
+    }
+    let mut waits = 0;
+    while state != 0 {
+        waits += 1;
+        sys_futex_wait(lock.as_ptr(), 2, 0);
+        state = lock.swap(2, Ordering::Acquire);
+    }
+    waits
+}
+
+fn unlock(lock: &AtomicU32) {
+    if lock.fetch_sub(1, Ordering::Release) != 1 {
+        lock.store(0, Ordering::Release);
+        sys_futex_wake(lock.as_ptr(), 1);
+    }
+}
+
+fn count(shared: *mut Shared) {
+    for round in 0..ROUNDS {
+        unsafe {
+            let waits = lock(&(*shared).lock);
+            (*shared).waits.fetch_add(waits, Ordering::Relaxed);
+
+            let value = (&raw const (*shared).counter).read_volatile();
+            if round % YIELD_EVERY == 0 {
+                sys_yield();
+            }
+            (&raw mut (*shared).counter).write_volatile(black_box(value) + 1);
+        }
+        unlock(unsafe { &(*shared).lock });
+    }
+}
+
+fn now() -> u64 {
+    let mut time = Timespec::default();
+    sys_clock_gettime(CLOCK_MONOTONIC, &mut time);
+    time.as_nanos()
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let addr = sys_mmap(size_of::<Shared>(), PROT_WRITE, MAP_SHARED);
+    if addr < 0 {
+        return 1;
+    }
+    let shared = addr as *mut Shared;
+    let word = unsafe { (*shared).lock.as_ptr() };
+
+    // The word must hold the expected value, be aligned and be mapped
+    if sys_futex_wait(word, 1, 0) != -(EAGAIN as isize) {
+        return 2;
+    }
+    if sys_futex_wait(word.wrapping_byte_add(1), 0, 0) != -(EINVAL as isize) {
+        return 3;
+    }
+    if sys_futex_wait(0x10 as *const u32, 0, 0) != -(EFAULT as isize) {
+        return 4;
+    }
+    if sys_futex_wake(word, 1) != 0 {
+        return 5;
+    }
+    if sys_mmap(4096, PROT_WRITE, 0x100) != -(EINVAL as isize) {
+        return 6;
+    }
+
+    // Nobody wakes us up, so the wait runs out
+    let start = now();
+    if sys_futex_wait(word, 0, TIMEOUT_NS) != -(ETIMEDOUT as isize) {
+        return 7;
+    }
+    if now() - start < TIMEOUT_NS {
+        return 8;
+    }
+
+    let child = sys_fork();
+    if child < 0 {
+        return 9;
+    }
+    count(shared);
+    if child == 0 {
+        sys_exit(0);
+    }
+
+    let mut status = -1;
+    if sys_wait(child as usize, &mut status) != child || status != 0 {
+        return 10;
+    }
+    unsafe {
+        if (*shared).counter != 2 * ROUNDS {
+            return 11;
+        }
+        if (*shared).waits.load(Ordering::Relaxed) == 0 {
+            return 12;
+        }
+    }
+    0
+}
@@ -0,0 +1,28 @@
+// Build with user/build.sh
+
+//! Unknown syscall numbers fail with -ENOSYS, instead of succeeding. getpid returns the id of the task.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{
+    errno::ENOSYS,
+    syscall::{sys_getpid, sys_yield, syscall0},
+};
+use user as _;
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    if unsafe { syscall0(1000) } != -(ENOSYS as isize) {
+        1
+    } else if unsafe { syscall0(usize::MAX) } != -(ENOSYS as isize) {
+        2
+    } else if sys_getpid() <= 0 {
+        3
+    } else if sys_yield() != 0 {
+        4
+    } else {
+        0
+    }
+}
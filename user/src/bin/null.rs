@@ -0,0 +1,30 @@
+// Build with user/build.sh
+
+//! Fork a child that reads through a null pointer, and another that writes through one. The kernel kills them, and
+//! this task goes on to reap them.
+//! Exits with 0 if both children were killed, or the number of the first check that failed.
+
+#![no_std]
+#![no_main]
+
+use core::ptr;
+
+use elytra_abi::syscall::sys_exit;
+use user::{KILLED_EXIT_CODE, in_child};
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let read = in_child(|| {
+        let value = unsafe { ptr::read_volatile(ptr::null::<u64>()) };
+        sys_exit(value as i32 + 1);
+    });
+    if read != Some(KILLED_EXIT_CODE) {
+        return 1;
+    }
+
+    let write = in_child(|| unsafe { ptr::write_volatile(ptr::null_mut::<u64>(), 1) });
+    if write != Some(KILLED_EXIT_CODE) {
+        return 2;
+    }
+    0
+}
@@ -0,0 +1,136 @@
+// Build with user/build.sh
+
+//! Send a megabyte of patterned data from a forked child to its parent through a pipe, in small chunks of changing
+//! sizes, and check the errors of bad descriptors and broken pipes.
+//! Exits with 0 if the data arrived intact, or the number of the check that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{
+    errno::{EBADF, EPIPE},
+    syscall::{sys_close, sys_exit, sys_fork, sys_pipe, sys_read, sys_wait, sys_write},
+};
+use user as _;
+
+const TOTAL: usize = 1024 * 1024;
+
+// The byte at offset i of the stream. Changes with the high bits too, so a lost or repeated chunk shows up.
+fn pattern(i: usize) -> u8 {
+    (i ^ (i >> 8) ^ (i >> 16)) as u8
+}
+
+// Write TOTAL bytes of the pattern to fd, in chunks of 1 to 300 bytes. Returns 0, or the number of the failed check.
+fn produce(fd: usize) -> i32 {
+    let mut chunk = [0u8; 300];
+    let mut offset = 0;
+    let mut round = 0;
+    while offset < TOTAL {
+        let len = (round * 37 % chunk.len() + 1).min(TOTAL - offset);
+        for (i, byte) in chunk[..len].iter_mut().enumerate() {
+            *byte = pattern(offset + i);
+        }
+        // Writes to a pipe are never partial while it has a reader
+        if sys_write(fd, chunk.as_ptr(), len) != len as isize {
+            return 20;
+        }
+        offset += len;
+        round += 1;
+    }
+    0
+}
+
+// Read from fd until the end of the pipe, and check every byte. Returns 0, or the number of the failed check.
+fn consume(fd: usize) -> i32 {
+    let mut chunk = [0u8; 97];
+    let mut offset = 0;
+    loop {
+        let count = sys_read(fd, chunk.as_mut_ptr(), chunk.len());
+        if count < 0 {
+            return 10;
+        }
+        if count == 0 {
+            break;
+        }
+        for (i, &byte) in chunk[..count as usize].iter().enumerate() {
+            if byte != pattern(offset + i) {
+                return 11;
+            }
+        }
+        offset += count as usize;
+    }
+    if offset != TOTAL {
+        return 12;
+    }
+    // The end stays the end
+    if sys_read(fd, chunk.as_mut_ptr(), chunk.len()) != 0 {
+        return 13;
+    }
+    0
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let mut fds = [-1i32; 2];
+    if sys_pipe(&mut fds) != 0 {
+        return 1;
+    }
+    let [read_fd, write_fd] = fds.map(|fd| fd as usize);
+    // The console holds 0 to 2, so the pipe gets the next free descriptors
+    if read_fd != 3 || write_fd != 4 {
+        return 2;
+    }
+
+    // Each end only goes one way
+    let mut byte = 0u8;
+    if sys_write(read_fd, &byte, 1) != -(EBADF as isize)
+        || sys_read(write_fd, &mut byte, 1) != -(EBADF as isize)
+    {
+        return 3;
+    }
+
+    let child = sys_fork();
+    if child < 0 {
+        return 4;
+    }
+    if child == 0 {
+        // The child only writes. Closing its read end doesn't break the pipe, as the parent still has one.
+        if sys_close(read_fd) != 0 {
+            sys_exit(21);
+        }
+        sys_exit(produce(write_fd));
+    }
+
+    // Without closing our write end, the pipe would never end
+    if sys_close(write_fd) != 0 || sys_close(write_fd) != -(EBADF as isize) {
+        return 5;
+    }
+    let code = consume(read_fd);
+    if code != 0 {
+        return code;
+    }
+
+    let mut status = -1;
+    if sys_wait(child as usize, &mut status) != child {
+        return 6;
+    }
+    if status != 0 {
+        return status;
+    }
+
+    // Writing to a pipe without readers fails, and closed descriptors are reused
+    if sys_close(read_fd) != 0 {
+        return 7;
+    }
+    if sys_pipe(&mut fds) != 0 || fds != [3, 4] {
+        return 8;
+    }
+    let [read_fd, write_fd] = fds.map(|fd| fd as usize);
+    if sys_close(read_fd) != 0 || sys_write(write_fd, &byte, 1) != -(EPIPE as isize) {
+        return 9;
+    }
+    if sys_close(write_fd) != 0 {
+        return 14;
+    }
+    0
+}
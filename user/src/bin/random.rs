@@ -0,0 +1,68 @@
+// Build with user/build.sh
+
+//! Get random bytes from the kernel, and check that getrandom fills exactly what it is asked for, caps long requests
+//! and rejects bad buffers.
+//! Exits with 0 if the bytes look random, or the number of the first check that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{errno::EFAULT, syscall::sys_getrandom};
+use user as _;
+
+// Most bytes one getrandom returns
+const MAX_LEN: usize = 256;
+// Bytes that are not asked for keep this value
+const SENTINEL: u8 = 0xa5;
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let mut first = [SENTINEL; 300];
+    let mut second = [SENTINEL; 300];
+
+    // Exactly len bytes are written, even for a length that isn't a multiple of anything
+    if sys_getrandom(first.as_mut_ptr(), 100) != 100
+        || sys_getrandom(second.as_mut_ptr(), 100) != 100
+    {
+        return 1;
+    }
+    if first[100..]
+        .iter()
+        .chain(&second[100..])
+        .any(|&byte| byte != SENTINEL)
+    {
+        return 2;
+    }
+    if first[..100].iter().all(|&byte| byte == 0)
+        || first[..100].iter().all(|&byte| byte == SENTINEL)
+    {
+        return 3;
+    }
+    // Two calls never give the same bytes
+    if first[..100] == second[..100] {
+        return 4;
+    }
+
+    // Long requests are capped
+    if sys_getrandom(first.as_mut_ptr(), first.len()) != MAX_LEN as isize {
+        return 5;
+    }
+    if first[MAX_LEN..].iter().any(|&byte| byte != SENTINEL) {
+        return 6;
+    }
+
+    // Roughly half of the bits are set
+    let ones: u32 = first[..MAX_LEN].iter().map(|byte| byte.count_ones()).sum();
+    let bits = MAX_LEN as u32 * 8;
+    if ones < bits * 2 / 5 || ones > bits * 3 / 5 {
+        return 7;
+    }
+
+    if sys_getrandom(first.as_mut_ptr(), 0) != 0 {
+        return 8;
+    }
+    if sys_getrandom(0x10 as *mut u8, 16) != -(EFAULT as isize) {
+        return 9;
+    }
+    0
+}
@@ -0,0 +1,214 @@
+// Build with user/build.sh
+
+//! Send signals to this task and to forked children: run a handler and resume, interrupt blocking syscalls with EINTR,
+//! and terminate a spinning, a sleeping and a blocked task.
+//! Exits with 0 if every signal did what it should, or the number of the first check that failed (or the exit code of a
+//! child that failed).
+
+#![no_std]
+#![no_main]
+
+use core::{
+    hint::{black_box, spin_loop},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use elytra_abi::{
+    errno::{EINTR, EINVAL, EPERM, ESRCH},
+    signal::{NSIG, SIGKILL, SIGNAL_EXIT_BASE, SIGUSR1, SIGUSR2},
+    syscall::{
+        sys_clock_gettime, sys_exit, sys_fork, sys_getpid, sys_getppid, sys_kill, sys_pipe,
+        sys_read, sys_sleep_ms, sys_wait, sys_yield,
+    },
+    time::{CLOCK_MONOTONIC, Timespec},
+};
+use user::set_signal_handler;
+
+// Number of times the handler ran
+static HANDLED: AtomicUsize = AtomicUsize::new(0);
+// Handlers running right now, which is never more than one, as the signal is blocked while its handler runs
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+// Set if the handler ran for the wrong signal, or inside itself
+static BROKEN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_usr1(sig: usize) {
+    if sig != SIGUSR1 || DEPTH.fetch_add(1, Ordering::Relaxed) != 0 {
+        BROKEN.store(1, Ordering::Relaxed);
+    }
+    // Use the SSE registers, which the interrupted code gets back as they were
+    let _ = black_box(black_box(2.5f64) * black_box(4.0f64));
+
+    // The first time, send the signal again. It waits until this handler returns.
+    if HANDLED.fetch_add(1, Ordering::Relaxed) == 0 {
+        sys_kill(sys_getpid() as usize, SIGUSR1);
+    }
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn now_ms() -> u64 {
+    let mut time = Timespec::default();
+    sys_clock_gettime(CLOCK_MONOTONIC, &mut time);
+    time.as_nanos() / 1_000_000
+}
+
+// Wait for the child, and return its exit code, or -1 if the wait failed.
+fn wait_for(child: isize) -> i32 {
+    let mut status = -1;
+    if sys_wait(child as usize, &mut status) != child {
+        return -1;
+    }
+    status
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let pid = sys_getpid() as usize;
+
+    // SIGKILL can't have a handler, and signal numbers must be valid
+    if set_signal_handler(SIGKILL, Some(on_usr1)) != -(EINVAL as isize)
+        || set_signal_handler(0, Some(on_usr1)) != -(EINVAL as isize)
+        || set_signal_handler(NSIG, Some(on_usr1)) != -(EINVAL as isize)
+    {
+        return 1;
+    }
+    if sys_kill(pid, NSIG) != -(EINVAL as isize) || sys_kill(usize::MAX, 0) != -(ESRCH as isize) {
+        return 2;
+    }
+    // Signal 0 only checks the task. Our parent is a kernel task, which can't be signalled.
+    if sys_kill(pid, 0) != 0 || sys_kill(sys_getppid() as usize, 0) != -(EPERM as isize) {
+        return 3;
+    }
+
+    // The handler runs on the way back from kill, then kill returns as usual. The signal it sends itself runs the
+    // handler a second time, right after the first one.
+    if set_signal_handler(SIGUSR1, Some(on_usr1)) != 0 {
+        return 4;
+    }
+    let value = black_box(1.5f64);
+    if sys_kill(pid, SIGUSR1) != 0 {
+        return 5;
+    }
+    if HANDLED.load(Ordering::Relaxed) != 2 || BROKEN.load(Ordering::Relaxed) != 0 {
+        return 6;
+    }
+    if black_box(value) * 2.0 != 3.0 {
+        return 7;
+    }
+
+    // A child in a loop of syscalls runs the handler (inherited over fork), and goes on with the loop
+    let child = sys_fork();
+    if child < 0 {
+        return 8;
+    }
+    if child == 0 {
+        let mut rounds = 0usize;
+        while HANDLED.load(Ordering::Relaxed) < 2 + 3 {
+            if sys_yield() != 0 {
+                sys_exit(30);
+            }
+            rounds += 1;
+        }
+        if rounds == 0 || BROKEN.load(Ordering::Relaxed) != 0 {
+            sys_exit(31);
+        }
+        sys_exit(0);
+    }
+    // Signals that arrive before the last one is handled are merged, so keep sending until the child is gone
+    for _ in 0..1000 {
+        if sys_kill(child as usize, SIGUSR1) != 0 {
+            break;
+        }
+        sys_sleep_ms(5);
+    }
+    let status = wait_for(child);
+    if status != 0 {
+        return status;
+    }
+
+    // A read from an empty pipe fails with EINTR, after the handler ran
+    let mut fds = [-1i32; 2];
+    if sys_pipe(&mut fds) != 0 {
+        return 10;
+    }
+    let child = sys_fork();
+    if child < 0 {
+        return 11;
+    }
+    if child == 0 {
+        let handled = HANDLED.load(Ordering::Relaxed);
+        let mut byte = 0u8;
+        if sys_read(fds[0] as usize, &mut byte, 1) != -(EINTR as isize) {
+            sys_exit(40);
+        }
+        if HANDLED.load(Ordering::Relaxed) == handled {
+            sys_exit(41);
+        }
+        sys_exit(0);
+    }
+    sys_sleep_ms(20);
+    if sys_kill(child as usize, SIGUSR1) != 0 {
+        return 12;
+    }
+    let status = wait_for(child);
+    if status != 0 {
+        return status;
+    }
+
+    // SIGKILL terminates a task that never makes a syscall
+    let child = sys_fork();
+    if child < 0 {
+        return 14;
+    }
+    if child == 0 {
+        loop {
+            spin_loop();
+        }
+    }
+    sys_sleep_ms(20);
+    if sys_kill(child as usize, SIGKILL) != 0
+        || wait_for(child) != SIGNAL_EXIT_BASE + SIGKILL as i32
+    {
+        return 15;
+    }
+
+    // A signal without a handler terminates a sleeping task right away
+    let child = sys_fork();
+    if child < 0 {
+        return 16;
+    }
+    if child == 0 {
+        sys_sleep_ms(10_000);
+        sys_exit(50);
+    }
+    let start = now_ms();
+    sys_sleep_ms(20);
+    if sys_kill(child as usize, SIGUSR2) != 0
+        || wait_for(child) != SIGNAL_EXIT_BASE + SIGUSR2 as i32
+    {
+        return 17;
+    }
+    if now_ms() - start > 1000 {
+        return 18;
+    }
+
+    // Without its handler, SIGUSR1 terminates a task blocked on the pipe too
+    if set_signal_handler(SIGUSR1, None) != 0 {
+        return 19;
+    }
+    let child = sys_fork();
+    if child < 0 {
+        return 20;
+    }
+    if child == 0 {
+        let mut byte = 0u8;
+        sys_read(fds[0] as usize, &mut byte, 1);
+        sys_exit(51);
+    }
+    sys_sleep_ms(20);
+    if sys_kill(child as usize, SIGUSR1) != 0
+        || wait_for(child) != SIGNAL_EXIT_BASE + SIGUSR1 as i32
+    {
+        return 21;
+    }
+    0
+}
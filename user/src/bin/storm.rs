@@ -0,0 +1,74 @@
+// Build with user/build.sh
+
+//! Make syscalls as fast as possible from several tasks at once, so timer interrupts keep landing in and around
+//! syscall entry and exit. Each task checks that its stack and the results survive every syscall.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use core::hint::black_box;
+
+use elytra_abi::syscall::{sys_exit, sys_fork, sys_getpid, sys_getppid, sys_wait};
+use user as _;
+
+const CHILDREN: usize = 3;
+const ROUNDS: usize = 50_000;
+
+// Make syscalls in a loop. Returns 0 if nothing was lost, or the number of the check that failed.
+fn storm(pid: isize, parent: isize) -> i32 {
+    // Lives on the stack, so it is lost if the user rsp isn't restored
+    let mut pattern = [0u64; 16];
+    for (i, value) in pattern.iter_mut().enumerate() {
+        *value = (pid as u64) << 32 | i as u64;
+    }
+
+    for round in 0..ROUNDS {
+        if sys_getpid() != pid {
+            return 10;
+        }
+        if round % 8 == 0 && sys_getppid() != parent {
+            return 11;
+        }
+        let pattern = black_box(&pattern);
+        for (i, &value) in pattern.iter().enumerate() {
+            if value != (pid as u64) << 32 | i as u64 {
+                return 12;
+            }
+        }
+    }
+    0
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let pid = sys_getpid();
+    let parent = sys_getppid();
+
+    let mut children = [0isize; CHILDREN];
+    for child in &mut children {
+        *child = sys_fork();
+        if *child < 0 {
+            return 1;
+        }
+        if *child == 0 {
+            sys_exit(storm(sys_getpid(), pid));
+        }
+    }
+
+    let code = storm(pid, parent);
+    if code != 0 {
+        return code;
+    }
+
+    for child in children {
+        let mut status = -1;
+        if sys_wait(child as usize, &mut status) != child {
+            return 2;
+        }
+        if status != 0 {
+            return status;
+        }
+    }
+    0
+}
@@ -0,0 +1,41 @@
+// Build with user/build.sh
+
+//! Run hello with its syscalls traced, then trace a few syscalls of our own. The kernel test checks the trace lines.
+//! Exits with 0 if every check passed, or the number of the first one that failed.
+
+#![no_std]
+#![no_main]
+
+use core::ptr::null;
+
+use elytra_abi::{
+    errno::EINVAL,
+    syscall::{sys_getpid, sys_spawn, sys_trace_me, sys_wait},
+    task::SPAWN_TRACE,
+};
+use user as _;
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let child = sys_spawn("hello", SPAWN_TRACE, null());
+    if child <= 0 {
+        return 1;
+    }
+    let mut status = -1;
+    if sys_wait(child as usize, &mut status) != child || status != 0 {
+        return 2;
+    }
+
+    if sys_spawn("hello", 0x100, null()) != -(EINVAL as isize) {
+        return 3;
+    }
+
+    // Both trace_me calls are traced, and only the first getpid
+    if sys_trace_me(true) != 0 || sys_getpid() <= 0 || sys_trace_me(false) != 0 {
+        return 4;
+    }
+    if sys_getpid() <= 0 {
+        return 5;
+    }
+    0
+}
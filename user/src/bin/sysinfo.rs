@@ -0,0 +1,63 @@
+// Build with user/build.sh
+
+//! Print what sysinfo reports about the system, like free and top would, and check that the numbers make sense.
+//! Exits with 0 if they do, or the number of the first check that failed.
+
+#![no_std]
+#![no_main]
+
+use elytra_abi::{
+    errno::EFAULT,
+    syscall::{sys_sleep_ms, sys_sysinfo},
+    sysinfo::SysInfo,
+};
+use user::println;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[unsafe(no_mangle)]
+extern "C" fn main() -> i32 {
+    let mut first = SysInfo::default();
+    if sys_sysinfo(&mut first) != 0 {
+        return 1;
+    }
+    println!(
+        "Memory: {} KiB total, {} KiB free, {} KiB in slabs",
+        first.total_pages * PAGE_SIZE / 1024,
+        first.free_pages * PAGE_SIZE / 1024,
+        first.slab_bytes / 1024
+    );
+    println!(
+        "Tasks: {}, up {} ms, {} context switches",
+        first.num_tasks,
+        first.uptime_ns / 1_000_000,
+        first.context_switches
+    );
+
+    if first.total_pages == 0 || first.free_pages > first.total_pages {
+        return 2;
+    }
+    // At least this task exists, and was switched to
+    if first.num_tasks == 0 || first.context_switches == 0 {
+        return 3;
+    }
+
+    // Sleeping switches away from this task and back
+    sys_sleep_ms(10);
+    let mut second = SysInfo::default();
+    if sys_sysinfo(&mut second) != 0 {
+        return 4;
+    }
+    if second.uptime_ns <= first.uptime_ns {
+        return 5;
+    }
+    if second.context_switches <= first.context_switches || second.total_pages != first.total_pages
+    {
+        return 6;
+    }
+
+    if sys_sysinfo(0x10 as *mut SysInfo) != -(EFAULT as isize) {
+        return 7;
+    }
+    0
+}
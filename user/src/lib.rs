@@ -0,0 +1,113 @@
+//! Runtime of the user programs. The entry point calls the main() of the program and exits with what it returns, and
+//! a panic exits the task, as there is no one to unwind to. print! and println! write to stdout.
+//!
+//! set_signal_handler() sets a signal handler, with the restorer the kernel needs to return from it, and in_child()
+//! runs a function in a child task, for checking that the kernel kills it.
+
+#![no_std]
+
+use core::{
+    arch::naked_asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+};
+
+use elytra_abi::{
+    nr::{SYS_EXIT, SYS_SIGRETURN},
+    syscall::{sys_exit, sys_fork, sys_sigaction, sys_wait, sys_write},
+};
+
+/// Exit code of a task that panicked.
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+/// Exit code of a task killed by the kernel, e.g. after an exception.
+pub const KILLED_EXIT_CODE: i32 = -1;
+
+unsafe extern "C" {
+    // Defined by each program, with #[unsafe(no_mangle)]. Returns the exit code.
+    fn main() -> i32;
+}
+
+// The stack pointer is 16-byte aligned at the entry point, so it is 8 bytes off of what a function expects.
+#[unsafe(no_mangle)]
+#[unsafe(naked)]
+extern "C" fn _start() -> ! {
+    naked_asm!(
+        "and rsp, -16",
+        "call {main}",
+        "mov edi, eax",
+        "mov eax, {exit}",
+        "syscall",
+        "ud2",
+        main = sym main,
+        exit = const SYS_EXIT,
+    )
+}
+
+// The handler returns here, with the stack pointer at the SigContext the kernel saved.
+#[unsafe(naked)]
+unsafe extern "C" fn sigreturn() -> ! {
+    naked_asm!(
+        "mov eax, {sigreturn}",
+        "syscall",
+        "ud2",
+        sigreturn = const SYS_SIGRETURN,
+    )
+}
+
+/// Run handler when the signal arrives, or terminate the task if it is None. Returns what sys_sigaction() returned.
+pub fn set_signal_handler(sig: usize, handler: Option<extern "C" fn(usize)>) -> isize {
+    sys_sigaction(sig, handler, sigreturn)
+}
+
+/// Run f in a child, which exits with 0 if f returns, and return the exit code of the child, or None if fork or wait
+/// failed.
+pub fn in_child(f: fn()) -> Option<i32> {
+    let child = sys_fork();
+    if child < 0 {
+        return None;
+    }
+    if child == 0 {
+        f();
+        sys_exit(0);
+    }
+
+    let mut status = 0;
+    if sys_wait(child as usize, &mut status) != child {
+        return None;
+    }
+    Some(status)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    sys_exit(PANIC_EXIT_CODE)
+}
+
+/// Writes to stdout.
+pub struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s.as_bytes();
+        while !rest.is_empty() {
+            let written = sys_write(1, rest.as_ptr(), rest.len());
+            if written <= 0 {
+                return Err(fmt::Error);
+            }
+            rest = &rest[written as usize..];
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => (core::fmt::Write::write_fmt(&mut $crate::Stdout, format_args!($($arg)*)).unwrap());
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}